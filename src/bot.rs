@@ -0,0 +1,309 @@
+//! Running the bot from another Rust program: `KelvinBot::builder()` takes a config, services
+//! and middlewares defined in code, and pipelines for them, and wires them onto a bus the same
+//! way the `kelvin-bot` binary does.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use kelvin_bot::KelvinBot;
+//! use kelvin_bot::middlewares::echo::Echo;
+//! use tokio_util::sync::CancellationToken;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let bot = KelvinBot::builder()
+//!     .config(kelvin_bot::core::config::load_from_env(None)?)
+//!     .middleware("echo", |ctx| Ok(Arc::new(Echo::new(ctx, "!echo".to_string()))))
+//!     .pipeline("matrix", &["echo"])
+//!     .build()
+//!     .await?;
+//! bot.run(CancellationToken::new()).await
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow, bail};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::core::{
+    alerts::{self, OperatorAlerts},
+    audit::AuditLog,
+    bus::{self, Bus, Command, EventTx},
+    config::Config,
+    coordination::Lease,
+    event::Event,
+    http::HttpClient,
+    media::MediaStore,
+    metrics::MetricsRegistry,
+    middleware::{self, Middleware, MiddlewareContext, MiddlewareFactory},
+    outbox::Outbox,
+    roster::Roster,
+    service::{self, Service, ServiceId, ServiceLoader},
+};
+
+// Builds a service defined in code, given the sender for its events
+type ServiceFactory = Box<dyn FnOnce(EventTx) -> Result<Arc<dyn Service>> + Send>;
+
+/// A bot ready to run: its services, middlewares and bus, built by `KelvinBotBuilder`.
+pub struct KelvinBot {
+    bus: Bus,
+    media: MediaStore,
+    cmd_tx: mpsc::Sender<Command>,
+    event_tap: broadcast::Sender<Arc<Event>>,
+}
+
+impl KelvinBot {
+    pub fn builder() -> KelvinBotBuilder {
+        KelvinBotBuilder::default()
+    }
+
+    /// Sends commands to the bus, which dispatches them to services like a middleware's.
+    pub fn command_sender(&self) -> mpsc::Sender<Command> {
+        self.cmd_tx.clone()
+    }
+
+    /// Every event the services emit, as the bus receives them and before any pipeline.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+        self.event_tap.subscribe()
+    }
+
+    /// Runs the services, middlewares and media store until `cancel` is cancelled.
+    pub async fn run(self, cancel: CancellationToken) -> Result<()> {
+        let Self { mut bus, media, .. } = self;
+        let bus_cancel = cancel.child_token();
+        let bus_task = tokio::spawn(async move { bus.run(bus_cancel).await });
+
+        // Prune and serve stored attachments; middlewares open the same directory themselves
+        let media_cancel = cancel.child_token();
+        let media_task = tokio::spawn({
+            let media_cancel = media_cancel.clone();
+            async move {
+                if let Err(e) = media.run(media_cancel).await {
+                    warn!(?e, "media store stopped");
+                }
+            }
+        });
+
+        let result = bus_task.await.map_err(|e| anyhow!("bus task panicked/aborted: {e}"));
+        // The media store goes when the bus does, even if the bus stopped on its own
+        media_cancel.cancel();
+        if let Err(e) = media_task.await {
+            warn!(?e, "media task panicked/aborted");
+        }
+        result?
+    }
+}
+
+/// Builds a `KelvinBot` from a config plus services, middlewares and pipelines defined in code.
+///
+/// Services and middlewares from the config are built as the binary builds them. Ones added
+/// here sit alongside them: a pipeline from the config can name a middleware added here, and
+/// `pipeline` can name configured middlewares. Names must not clash with the config's.
+pub struct KelvinBotBuilder {
+    config: Config,
+    load_config: Box<dyn Fn() -> Result<Config> + Send + Sync>,
+    services: Vec<(ServiceId, ServiceFactory)>,
+    middlewares: Vec<(String, MiddlewareFactory)>,
+    pipelines: Vec<(ServiceId, Vec<String>)>,
+}
+
+impl Default for KelvinBotBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config::empty(),
+            load_config: Box::new(|| bail!("this bot's config can't be reloaded")),
+            services: Vec::new(),
+            middlewares: Vec::new(),
+            pipelines: Vec::new(),
+        }
+    }
+}
+
+impl KelvinBotBuilder {
+    /// The config to build from; an empty one (no services or middlewares) if not given.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Reads the config again for `!reload middleware` and `BusControl::AddService`, e.g. with
+    /// `load_from_env`. Without one, both fail.
+    pub fn config_loader(
+        mut self,
+        load_config: impl Fn() -> Result<Config> + Send + Sync + 'static,
+    ) -> Self {
+        self.load_config = Box::new(load_config);
+        self
+    }
+
+    /// Adds a service under `service_id`, built with the sender it emits its events to.
+    pub fn service(
+        mut self,
+        service_id: impl Into<String>,
+        build: impl FnOnce(EventTx) -> Result<Arc<dyn Service>> + Send + 'static,
+    ) -> Self {
+        self.services.push((ServiceId(service_id.into()), Box::new(build)));
+        self
+    }
+
+    /// Adds a middleware under `name`, built with a context like a configured middleware's,
+    /// including a store file of its own.
+    pub fn middleware(
+        mut self,
+        name: impl Into<String>,
+        build: impl FnOnce(MiddlewareContext) -> Result<Arc<dyn Middleware>> + Send + 'static,
+    ) -> Self {
+        self.middlewares.push((name.into(), Box::new(build)));
+        self
+    }
+
+    /// Sets the pipeline of `service_id` to the `global_middleware` list followed by `names`,
+    /// replacing any pipeline the config gives it.
+    pub fn pipeline(mut self, service_id: impl Into<String>, names: &[&str]) -> Self {
+        let names = names.iter().map(|name| name.to_string()).collect();
+        self.pipelines.push((ServiceId(service_id.into()), names));
+        self
+    }
+
+    pub async fn build(self) -> Result<KelvinBot> {
+        let Self { config: cfg, load_config, services: custom_services, middlewares, pipelines } =
+            self;
+
+        // Command channel: many producers (middleware) -> one consumer (bus)
+        let (cmd_tx, cmd_rx) = bus::create_command_channel(1024);
+        // Event channel: many producers (services) -> one consumer (bus)
+        let (evt_tx, evt_rx) = bus::create_event_channel(1024);
+        // Event tap: one producer (bus) -> many out-of-pipeline observers
+        let event_tap = bus::create_event_tap(1024);
+        // Alert channel: one producer (bus) -> many operator-facing observers
+        let alerts = bus::create_alert_channel(64);
+
+        // Read again by `!reload middleware` and when a service is added at runtime
+        let load_config: Arc<dyn Fn() -> Result<Config> + Send + Sync> = Arc::from(load_config);
+
+        info!("instantiating services...");
+        let metrics = MetricsRegistry::default();
+        // One HTTP client for the services and middlewares alike
+        let http = HttpClient::from_config(&cfg.http)?;
+        let mut services =
+            service::instantiate_services_with_http(&cfg, &evt_tx, &metrics, &http).await?;
+        let custom_service_ids: Vec<ServiceId> =
+            custom_services.iter().map(|(service_id, _)| service_id.clone()).collect();
+        for (service_id, build) in custom_services {
+            if cfg.services.contains_key(&service_id.0) || services.contains_key(&service_id) {
+                bail!("service '{service_id}' is defined both in the config and in code");
+            }
+            let service = build(evt_tx.clone())
+                .with_context(|| format!("failed to build service '{service_id}'"))?;
+            services.insert(service_id, service);
+        }
+
+        if let Some(alerts_cfg) = &cfg.operator_alerts {
+            if !services.contains_key(&ServiceId(alerts_cfg.service_id.clone())) {
+                bail!("operator_alerts names unknown service '{}'", alerts_cfg.service_id);
+            }
+            alerts::install(OperatorAlerts::from_config(alerts_cfg, cmd_tx.clone())?);
+        }
+
+        info!("instantiating middlewares...");
+        let roster = Roster::from_config(&cfg.roster);
+        let (all_middlewares, reloader) = middleware::instantiate_middleware_with_custom(
+            &cfg,
+            &cmd_tx,
+            &evt_tx,
+            &services,
+            &roster,
+            &http,
+            {
+                let load_config = load_config.clone();
+                move || load_config()
+            },
+            middlewares,
+        )?;
+        let service_loader =
+            ServiceLoader::new(evt_tx.clone(), metrics.clone(), http, move || load_config());
+
+        info!("building service middleware pipelines...");
+        let mut service_middlewares = middleware::build_service_pipelines(&cfg, &all_middlewares)?;
+        // Services defined in code get the global middlewares, like configured ones
+        if cfg.global_middleware.as_ref().is_some_and(|names| !names.is_empty()) {
+            for service_id in custom_service_ids {
+                let pipeline =
+                    middleware::build_pipeline(&cfg, &service_id.0, &[], &all_middlewares)?;
+                service_middlewares.insert(service_id, pipeline);
+            }
+        }
+        for (service_id, names) in pipelines {
+            if !services.contains_key(&service_id) {
+                bail!("pipeline given for unknown service '{service_id}'");
+            }
+            let pipeline =
+                middleware::build_pipeline(&cfg, &service_id.0, &names, &all_middlewares)?;
+            service_middlewares.insert(service_id, pipeline);
+        }
+        let room_middlewares = middleware::build_room_pipelines(&cfg, &all_middlewares)?;
+        let room_filters = bus::room_filters_from_config(&cfg);
+        let command_policy = bus::command_policy_from_config(&cfg);
+        let read_only_services = bus::read_only_services_from_config(&cfg);
+        let startup_timeouts = bus::startup_timeouts_from_config(&cfg);
+        let disabled_middlewares = bus::disabled_middlewares_from_config(&cfg);
+        let dry_run_middlewares = bus::dry_run_middlewares_from_config(&cfg);
+        let connection_schedules = bus::connection_schedules_from_config(&cfg)?;
+
+        let mut bus =
+            Bus::new(evt_rx, cmd_rx, services, service_middlewares, cfg.reconnection.clone())
+                .with_room_pipelines(room_middlewares)
+                .with_room_filters(room_filters)
+                .with_connection_schedules(connection_schedules)
+                .with_event_tap(event_tap.clone())
+                .with_middleware_names(all_middlewares)
+                .with_middleware_reloader(reloader)
+                .with_service_loader(service_loader)
+                .with_disabled_middlewares(disabled_middlewares)
+                .with_dry_run_middlewares(dry_run_middlewares)
+                .with_command_dispatch(cfg.command_dispatch)
+                .with_command_policy(command_policy)
+                .with_read_only_services(read_only_services)
+                .with_startup_timeouts(startup_timeouts)
+                .with_lifecycle_announcements(
+                    cfg.lifecycle_announcements.clone().unwrap_or_default(),
+                )
+                .with_panic_limit(cfg.panic_guard.disable_after)
+                .with_alerts(alerts)
+                .with_latency_budget(
+                    cfg.middleware_latency_budget.unwrap_or(bus::DEFAULT_LATENCY_BUDGET),
+                )
+                .with_lag_warning(cfg.event_lag_warning.unwrap_or(bus::DEFAULT_LAG_WARNING))
+                .with_metrics(metrics)
+                .with_roster(roster);
+
+        if let Some(outbox_cfg) = &cfg.outbox {
+            if outbox_cfg.flush_interval.is_zero() {
+                bail!("outbox flush_interval must be greater than zero");
+            }
+            info!("opening outbox...");
+            let outbox = Outbox::open(cfg.data_directory.join("outbox.sqlite3"), outbox_cfg.ttl)?;
+            bus = bus.with_outbox(outbox, outbox_cfg.flush_interval);
+        }
+
+        if let Some(coordination_cfg) = &cfg.coordination {
+            info!("opening leadership lease...");
+            bus = bus.with_lease(Lease::from_config(coordination_cfg)?);
+        }
+
+        if let Some(audit_cfg) = &cfg.audit {
+            info!("opening audit trail...");
+            let audit =
+                AuditLog::open(cfg.data_directory.join("audit.sqlite3"), audit_cfg.retention)?;
+            bus = bus.with_audit(audit);
+        }
+
+        if let Some(watchdog_cfg) = &cfg.watchdog {
+            bus = bus.with_watchdog(watchdog_cfg.clone());
+        }
+
+        let media = MediaStore::from_config(&cfg)?;
+        Ok(KelvinBot { bus, media, cmd_tx, event_tap })
+    }
+}
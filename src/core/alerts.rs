@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use tokio::sync::mpsc::Sender;
+
+use crate::core::{
+    bus::{Command, CommandSender},
+    config::{AlertLevel, OperatorAlertsConfig},
+    format::BodyFormat,
+    redact,
+    service::ServiceId,
+};
+
+/// What alert commands are attributed to in the audit trail, in place of a middleware name.
+pub const ALERTS_ORIGIN: &str = "operator_alerts";
+
+/// Where operator alerts are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlertTarget {
+    Room {
+        service_id: ServiceId,
+        room_id: String,
+    },
+    /// A direct message to the operator.
+    User {
+        service_id: ServiceId,
+        user_id: String,
+    },
+}
+
+/// Sends alerts about the bot's own failures to an operator's room or DMs, through the bus like
+/// any middleware's messages.
+///
+/// Identical alerts are sent once per `repeat_after`, so a failure that recurs on every event
+/// doesn't flood the room.
+pub struct OperatorAlerts {
+    cmd_tx: CommandSender,
+    target: AlertTarget,
+    min_level: AlertLevel,
+    repeat_after: Duration,
+    sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl OperatorAlerts {
+    pub fn new(
+        cmd_tx: CommandSender,
+        target: AlertTarget,
+        min_level: AlertLevel,
+        repeat_after: Duration,
+    ) -> Self {
+        Self { cmd_tx, target, min_level, repeat_after, sent: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn from_config(config: &OperatorAlertsConfig, cmd_tx: Sender<Command>) -> Result<Self> {
+        let service_id = ServiceId(config.service_id.clone());
+        let target = match (&config.room_id, &config.user_id) {
+            (Some(room_id), None) => AlertTarget::Room { service_id, room_id: room_id.clone() },
+            (None, Some(user_id)) => AlertTarget::User { service_id, user_id: user_id.clone() },
+            _ => bail!("operator_alerts needs exactly one of room_id and user_id"),
+        };
+        Ok(Self::new(
+            CommandSender::new(cmd_tx, ALERTS_ORIGIN),
+            target,
+            config.min_level,
+            config.repeat_after,
+        ))
+    }
+
+    /// Sends `message` unless it's below the minimum level or was already sent within
+    /// `repeat_after`. Returns whether it was sent.
+    ///
+    /// Never waits: an alert that doesn't fit in the command channel is dropped with a warning,
+    /// so the bus can raise alerts from its own loop.
+    pub fn notify(&self, level: AlertLevel, message: &str) -> bool {
+        if level < self.min_level {
+            return false;
+        }
+        {
+            let mut sent = self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            sent.retain(|_, at| now.duration_since(*at) < self.repeat_after);
+            if sent.contains_key(message) {
+                return false;
+            }
+            sent.insert(message.to_string(), now);
+        }
+
+        let body = format_alert(level, &redact::redact(message));
+        let command = match &self.target {
+            AlertTarget::Room { service_id, room_id } => Command::SendRoomMessage {
+                service_id: service_id.clone(),
+                room_id: room_id.clone(),
+                body,
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            },
+            AlertTarget::User { service_id, user_id } => Command::SendDirectMessage {
+                service_id: service_id.clone(),
+                user_id: user_id.clone(),
+                body,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            },
+        };
+        match self.cmd_tx.try_send(command) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(error=%e, "failed to send operator alert");
+                false
+            }
+        }
+    }
+}
+
+/// `message` as posted for an alert of `level`, e.g. `🚨 service 'matrix' keeps failing`.
+pub fn format_alert(level: AlertLevel, message: &str) -> String {
+    let marker = match level {
+        AlertLevel::Info => "ℹ️",
+        AlertLevel::Warning => "⚠️",
+        AlertLevel::Critical => "🚨",
+    };
+    format!("{marker} {message}")
+}
+
+// Process-wide, like the redactor: installed from config at startup, used wherever a failure
+// happens, including the watchdog's thread
+static OPERATOR_ALERTS: LazyLock<RwLock<Option<OperatorAlerts>>> = LazyLock::new(RwLock::default);
+
+/// Makes `notify` send alerts through `alerts`, replacing any installed before.
+pub fn install(alerts: OperatorAlerts) {
+    let mut installed = OPERATOR_ALERTS.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *installed = Some(alerts);
+}
+
+/// Tells the operator about an internal failure, e.g. a middleware that panicked or a config
+/// that no longer loads, if operator alerts are configured.
+///
+/// Alerts are a copy for whoever runs the bot, not a replacement for the log: callers still log
+/// the failure with its details as they would otherwise.
+pub fn notify(level: AlertLevel, message: impl AsRef<str>) {
+    let installed = OPERATOR_ALERTS.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(alerts) = installed.as_ref() {
+        alerts.notify(level, message.as_ref());
+    }
+}
@@ -0,0 +1,444 @@
+//! `proptest` generators for events and commands, enabled by the `proptest` feature.
+//!
+//! Message bodies are biased towards `!command`-looking text so generated sequences reach
+//! command handlers instead of only falling through them.
+
+use std::{sync::Arc, time::Duration};
+
+use proptest::{
+    arbitrary::{Arbitrary, any},
+    collection::vec,
+    option,
+    prelude::{BoxedStrategy, Just, Strategy},
+    prop_oneof,
+};
+
+use crate::core::{
+    bus::Command,
+    event::{Event, EventKind, MissedMessage, User, VoiceState},
+    format::BodyFormat,
+    service::ServiceId,
+};
+
+/// Free text, or something shaped like a bot command.
+pub fn message_body() -> BoxedStrategy<String> {
+    prop_oneof![".{0,64}", "![a-z]{1,10}( [^\n]{0,32}){0,3}", Just(String::new())].boxed()
+}
+
+// IDs drawn from a small pool so generated events refer to the same rooms and users
+fn id() -> BoxedStrategy<String> {
+    prop_oneof![4 => "[!@$]?[a-z]{1,3}", 1 => ".{0,16}"].boxed()
+}
+
+fn body_format() -> BoxedStrategy<BodyFormat> {
+    prop_oneof![Just(BodyFormat::Plain), Just(BodyFormat::Markdown)].boxed()
+}
+
+fn image_data() -> BoxedStrategy<Option<Arc<[u8]>>> {
+    option::of(vec(any::<u8>(), 0..64).prop_map(Arc::from)).boxed()
+}
+
+fn missed_message() -> BoxedStrategy<MissedMessage> {
+    (id(), option::of(".{0,16}"), message_body())
+        .prop_map(|(sender_id, sender_display_name, body)| MissedMessage {
+            sender_id,
+            sender_display_name,
+            body,
+            relayed_from: None,
+        })
+        .boxed()
+}
+
+/// An event from one of `service_ids`, for feeding a bus whose services are known.
+pub fn event_from(service_ids: Vec<String>) -> BoxedStrategy<Event> {
+    (proptest::sample::select(service_ids), any::<EventKind>())
+        .prop_map(|(service_id, kind)| Event { service_id: ServiceId(service_id), kind })
+        .boxed()
+}
+
+impl Arbitrary for User {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (id(), id(), ".{0,16}", any::<bool>(), any::<bool>(), option::of(".{0,32}"), image_data())
+            .prop_map(|(id, username, display_name, is_active, is_self, comment, avatar)| User {
+                id,
+                username,
+                display_name,
+                is_active,
+                is_self,
+                comment,
+                avatar,
+            })
+            .boxed()
+    }
+}
+
+// Changes to a user rather than something they posted; kept out of the main list so it stays
+// within what `prop_oneof!` can combine
+fn user_state_event() -> BoxedStrategy<EventKind> {
+    let state = prop_oneof![
+        Just(VoiceState::PrioritySpeaker),
+        Just(VoiceState::Recording),
+        Just(VoiceState::Listening),
+        Just(VoiceState::Talking),
+    ];
+    prop_oneof![
+        any::<User>().prop_map(|user| EventKind::UserProfileChanged { user }),
+        (id(), id(), option::of(".{0,16}"), state, any::<bool>(), any::<bool>()).prop_map(
+            |(room_id, user_id, sender_display_name, state, active, is_self)| {
+                EventKind::VoiceStateChanged {
+                    room_id,
+                    user_id,
+                    sender_display_name,
+                    state,
+                    active,
+                    is_self,
+                }
+            }
+        ),
+    ]
+    .boxed()
+}
+
+impl Arbitrary for EventKind {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            (id(), message_body(), any::<bool>(), id(), option::of(".{0,16}"), any::<bool>())
+                .prop_map(
+                    |(user_id, body, is_local_user, sender_id, sender_display_name, is_self)| {
+                        EventKind::DirectMessage {
+                            user_id,
+                            body,
+                            is_local_user,
+                            sender_id,
+                            sender_display_name,
+                            is_self,
+                        }
+                    }
+                ),
+            (
+                id(),
+                message_body(),
+                any::<bool>(),
+                id(),
+                option::of(".{0,16}"),
+                any::<bool>(),
+                any::<bool>(),
+                option::of(id())
+            )
+                .prop_map(
+                    |(
+                        room_id,
+                        body,
+                        is_local_user,
+                        sender_id,
+                        sender_display_name,
+                        is_self,
+                        mentions_self,
+                        thread_root_id,
+                    )| {
+                        EventKind::RoomMessage {
+                            room_id,
+                            body,
+                            is_local_user,
+                            sender_id,
+                            sender_display_name,
+                            is_self,
+                            mentions_self,
+                            relayed_from: None,
+                            thread_root_id,
+                            message_id: None,
+                        }
+                    }
+                ),
+            vec(any::<User>(), 0..6).prop_map(|users| EventKind::UserListUpdate { users }),
+            (vec(any::<User>(), 0..3), vec(any::<User>(), 0..3), vec(any::<User>(), 0..3))
+                .prop_map(|(joined, left, changed)| EventKind::UserListDiff {
+                    joined,
+                    left,
+                    changed
+                }),
+            (id(), id(), id(), ".{0,4}", id(), option::of(".{0,16}"), any::<bool>()).prop_map(
+                |(
+                    room_id,
+                    event_id,
+                    target_event_id,
+                    key,
+                    sender_id,
+                    sender_display_name,
+                    is_self,
+                )| EventKind::ReactionAdded {
+                    room_id,
+                    event_id,
+                    target_event_id,
+                    key,
+                    sender_id,
+                    sender_display_name,
+                    is_self,
+                }
+            ),
+            (id(), id(), option::of(id()), option::of(".{0,4}"), id(), any::<bool>()).prop_map(
+                |(room_id, event_id, target_event_id, key, sender_id, is_self)| {
+                    EventKind::ReactionRemoved {
+                        room_id,
+                        event_id,
+                        target_event_id,
+                        key,
+                        sender_id,
+                        is_self,
+                    }
+                }
+            ),
+            (
+                (id(), id(), option::of(".{0,16}"), any::<bool>(), any::<bool>()),
+                (message_body(), ".{0,32}", option::of("image/[a-z]{1,5}"), image_data()),
+            )
+                .prop_map(
+                    |(
+                        (room_id, sender_id, sender_display_name, is_self, is_local_user),
+                        (body, source_url, mimetype, image_data),
+                    )| EventKind::RoomImage {
+                        room_id,
+                        sender_id,
+                        sender_display_name,
+                        is_self,
+                        is_local_user,
+                        body,
+                        source_url,
+                        mimetype,
+                        image_data,
+                    }
+                ),
+            (id(), id(), option::of(".{0,16}"), option::of(".{0,32}"), any::<bool>()).prop_map(
+                |(room_id, user_id, sender_display_name, reason, is_self)| EventKind::Knock {
+                    room_id,
+                    user_id,
+                    sender_display_name,
+                    reason,
+                    is_self,
+                }
+            ),
+            (id(), id()).prop_map(|(old_room_id, new_room_id)| EventKind::RoomUpgraded {
+                old_room_id,
+                new_room_id
+            }),
+            (id(), vec(missed_message(), 0..4), any::<bool>()).prop_map(
+                |(room_id, messages, truncated)| EventKind::MissedMessages {
+                    room_id,
+                    messages,
+                    truncated,
+                }
+            ),
+            user_state_event(),
+            (id(), id(), vec(".{1,16}", 7)).prop_map(|(flow_id, other_device_id, emoji)| {
+                EventKind::VerificationRequested { flow_id, other_device_id, emoji }
+            }),
+            any::<bool>().prop_map(|connected| EventKind::ConnectionScheduled { connected }),
+            Just(EventKind::ServiceReady),
+            (option::of(".{0,32}"), any::<u32>(), any::<u64>()).prop_map(
+                |(error, attempt, retry_in)| EventKind::ServiceDegraded {
+                    error,
+                    attempt,
+                    retry_in: Duration::from_secs(retry_in),
+                }
+            ),
+            Just(EventKind::ServiceStopped),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for Event {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (id(), any::<EventKind>())
+            .prop_map(|(service_id, kind)| Event { service_id: ServiceId(service_id), kind })
+            .boxed()
+    }
+}
+
+// Commands about rooms and who's in them rather than messages; kept out of the main list so it
+// stays within what `prop_oneof!` can combine
+fn room_management_command() -> BoxedStrategy<Command> {
+    let service_id = || id().prop_map(ServiceId);
+    prop_oneof![
+        (service_id(), id(), id(), id()).prop_map(
+            |(service_id, room_id, event_type, state_key)| {
+                let (response_tx, _) = tokio::sync::oneshot::channel();
+                Command::GetRoomState {
+                    service_id,
+                    room_id,
+                    event_type,
+                    state_key,
+                    response_tx,
+                    origin: None,
+                }
+            }
+        ),
+        (service_id(), id(), id(), id(), message_body()).prop_map(
+            |(service_id, room_id, event_type, state_key, value)| Command::SetRoomState {
+                service_id,
+                room_id,
+                event_type,
+                state_key,
+                content: serde_json::json!({ "value": value }),
+                response_tx: None,
+                origin: None,
+            }
+        ),
+        (service_id(), id(), message_body()).prop_map(|(service_id, room_id, topic)| {
+            Command::SetRoomTopic { service_id, room_id, topic, response_tx: None, origin: None }
+        }),
+        (service_id(), id(), id()).prop_map(|(service_id, room_id, user_id)| {
+            Command::ApproveKnock { service_id, room_id, user_id, response_tx: None, origin: None }
+        }),
+        (service_id(), id(), id(), option::of(".{0,32}")).prop_map(
+            |(service_id, room_id, user_id, reason)| Command::KickUser {
+                service_id,
+                room_id,
+                user_id,
+                reason,
+                response_tx: None,
+                origin: None,
+            }
+        ),
+        (service_id(), id(), option::of(".{0,32}")).prop_map(|(service_id, room, reason)| {
+            Command::JoinRoom { service_id, room, reason, response_tx: None, origin: None }
+        }),
+        (service_id(), id(), any::<bool>()).prop_map(|(service_id, flow_id, confirm)| {
+            Command::ConfirmVerification {
+                service_id,
+                flow_id,
+                confirm,
+                response_tx: None,
+                origin: None,
+            }
+        }),
+        service_id().prop_map(|service_id| {
+            let (response_tx, _) = tokio::sync::oneshot::channel();
+            Command::GetUserList { service_id, response_tx, origin: None }
+        }),
+    ]
+    .boxed()
+}
+
+/// Generated commands never carry a response channel, except invite token requests, pings and
+/// user lists, which require one; its receiver is already dropped. Bus control commands and
+/// announcements aren't generated.
+impl Arbitrary for Command {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let service_id = || id().prop_map(ServiceId);
+        prop_oneof![
+            (service_id(), id(), message_body()).prop_map(|(service_id, user_id, body)| {
+                Command::SendDirectMessage {
+                    service_id,
+                    user_id,
+                    body,
+                    response_tx: None,
+                    origin: None,
+                    idempotency_key: None,
+                    expires_after: None,
+                    require_encryption: false,
+                }
+            }),
+            (service_id(), id(), message_body(), body_format()).prop_map(
+                |(service_id, room_id, body, format)| Command::SendRoomMessage {
+                    service_id,
+                    room_id,
+                    body,
+                    format,
+                    response_tx: None,
+                    origin: None,
+                    idempotency_key: None,
+                    relayed_from: None,
+                    expires_after: None,
+                }
+            ),
+            (service_id(), id(), id(), message_body(), body_format()).prop_map(
+                |(service_id, room_id, thread_root_id, body, format)| {
+                    Command::SendThreadReply {
+                        service_id,
+                        room_id,
+                        thread_root_id,
+                        body,
+                        format,
+                        response_tx: None,
+                        origin: None,
+                        idempotency_key: None,
+                    }
+                }
+            ),
+            (service_id(), id(), message_body(), body_format()).prop_map(
+                |(service_id, message_id, new_body, format)| Command::EditMessage {
+                    service_id,
+                    message_id,
+                    new_body,
+                    format,
+                    origin: None,
+                }
+            ),
+            (service_id(), id()).prop_map(|(service_id, message_id)| Command::DeleteMessage {
+                service_id,
+                message_id,
+                origin: None,
+            }),
+            (service_id(), id(), option::of(any::<u32>()), option::of(0..86_400u64)).prop_map(
+                |(service_id, user_id, uses_allowed, expiry_secs)| {
+                    let (response_tx, _) = tokio::sync::oneshot::channel();
+                    Command::GenerateInviteToken {
+                        service_id,
+                        user_id,
+                        uses_allowed,
+                        expiry: expiry_secs.map(Duration::from_secs),
+                        response_tx,
+                        origin: None,
+                    }
+                }
+            ),
+            (service_id(), id(), id(), ".{0,4}").prop_map(
+                |(service_id, room_id, event_id, key)| Command::AddReaction {
+                    service_id,
+                    room_id,
+                    event_id,
+                    key,
+                    origin: None,
+                }
+            ),
+            (service_id(), id(), message_body(), ".{0,32}", vec(any::<u8>(), 0..64), ".{0,16}")
+                .prop_map(
+                    |(
+                        service_id,
+                        room_id,
+                        caption,
+                        source_url,
+                        thumbnail_data,
+                        thumbnail_mimetype,
+                    )| Command::SendRoomImage {
+                        service_id,
+                        room_id,
+                        caption,
+                        source_url,
+                        thumbnail_data,
+                        thumbnail_mimetype,
+                        origin: None,
+                    }
+                ),
+            service_id().prop_map(|service_id| {
+                let (response_tx, _) = tokio::sync::oneshot::channel();
+                Command::Ping { service_id, response_tx, origin: None }
+            }),
+            room_management_command(),
+        ]
+        .boxed()
+    }
+}
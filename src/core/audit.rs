@@ -0,0 +1,208 @@
+use std::{path::Path, sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, params};
+
+use crate::core::bus::{BusControl, Command};
+use crate::core::migrations::{self, Migration};
+
+/// One dispatched command, as recorded in the audit trail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// Command type, e.g. `generate_invite_token`.
+    pub command: String,
+    /// Middleware that issued the command, when known.
+    pub origin: Option<String>,
+    /// Service the command was sent to; `bus` for bus control commands.
+    pub service_id: String,
+    /// Room, user, message, service or middleware the command acted on.
+    pub target: String,
+    /// `ok`, `queued`, `denied`, `disabled`, or a description of the failure.
+    pub outcome: String,
+    pub latency: Duration,
+}
+
+impl AuditEntry {
+    /// An entry for `command` with its type, service and target filled in from the command.
+    pub fn for_command(command: &Command, outcome: String, latency: Duration) -> Self {
+        let (service_id, target) = match command {
+            Command::SendDirectMessage { service_id, user_id, .. }
+            | Command::GenerateInviteToken { service_id, user_id, .. } => (service_id, user_id),
+            Command::SendRoomMessage { service_id, room_id, .. }
+            | Command::SendThreadReply { service_id, room_id, .. }
+            | Command::AddReaction { service_id, room_id, .. }
+            | Command::SendRoomImage { service_id, room_id, .. }
+            | Command::GetRoomState { service_id, room_id, .. }
+            | Command::SetRoomState { service_id, room_id, .. }
+            | Command::SetRoomTopic { service_id, room_id, .. }
+            | Command::ApproveKnock { service_id, room_id, .. }
+            | Command::KickUser { service_id, room_id, .. } => (service_id, room_id),
+            Command::JoinRoom { service_id, room, .. } => (service_id, room),
+            Command::ConfirmVerification { service_id, flow_id, .. } => (service_id, flow_id),
+            Command::Ping { service_id, .. } | Command::GetUserList { service_id, .. } => {
+                (service_id, &service_id.0)
+            }
+            Command::EditMessage { service_id, message_id, .. }
+            | Command::DeleteMessage { service_id, message_id, .. } => (service_id, message_id),
+            // Its messages are dispatched, and recorded, one by one
+            Command::Announce { targets, .. } => {
+                let target = format!("{} targets", targets.len());
+                let mut entry = Self::new(command.kind(), "bus", &target, outcome, latency);
+                entry.origin = command.origin().map(str::to_string);
+                return entry;
+            }
+            Command::Control(control) => return Self::for_control(control, outcome),
+        };
+        let mut entry = Self::new(command.kind(), &service_id.0, target, outcome, latency);
+        entry.origin = command.origin().map(str::to_string);
+        entry
+    }
+
+    /// An entry for a bus control command, recorded against the `bus` service.
+    pub fn for_control(control: &BusControl, outcome: String) -> Self {
+        let (kind, target) = match control {
+            BusControl::PauseService { service_id, .. } => ("pause_service", service_id.0.as_str()),
+            BusControl::ResumeService { service_id, .. } => {
+                ("resume_service", service_id.0.as_str())
+            }
+            BusControl::DisableMiddleware { name, .. } => ("disable_middleware", name.as_str()),
+            BusControl::EnableMiddleware { name, .. } => ("enable_middleware", name.as_str()),
+            BusControl::ReloadMiddleware { name, .. } => ("reload_middleware", name.as_str()),
+            BusControl::RecentAudit { .. } => ("recent_audit", ""),
+            BusControl::DescribePipeline { service_id, .. } => {
+                ("describe_pipeline", service_id.0.as_str())
+            }
+            BusControl::InjectFault { service_id, .. } => ("inject_fault", service_id.0.as_str()),
+            BusControl::AddService { service_id, .. } => ("add_service", service_id.0.as_str()),
+        };
+        Self::new(kind, "bus", target, outcome, Duration::ZERO)
+    }
+
+    fn new(kind: &str, service_id: &str, target: &str, outcome: String, latency: Duration) -> Self {
+        Self {
+            at: Utc::now(),
+            command: kind.to_string(),
+            origin: None,
+            service_id: service_id.to_string(),
+            target: target.to_string(),
+            outcome,
+            latency,
+        }
+    }
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "create the audit table",
+    sql: "CREATE TABLE IF NOT EXISTS audit (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            at INTEGER NOT NULL,
+            command TEXT NOT NULL,
+            origin TEXT,
+            service_id TEXT NOT NULL,
+            target TEXT NOT NULL,
+            outcome TEXT NOT NULL,
+            latency_ms INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS audit_at ON audit (at);",
+}];
+
+/// A sqlite-backed record of the commands the bus dispatched and how they went.
+///
+/// Entries older than `retention` are pruned as new ones are recorded.
+pub struct AuditLog {
+    conn: Mutex<Connection>,
+    retention: Duration,
+}
+
+impl AuditLog {
+    /// Open (or create) the audit database at `path`.
+    pub fn open(path: impl AsRef<Path>, retention: Duration) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?, retention)
+    }
+
+    /// Create an audit log that lives only in memory. Useful for testing.
+    pub fn in_memory(retention: Duration) -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, retention)
+    }
+
+    fn init(mut conn: Connection, retention: Duration) -> Result<Self> {
+        migrations::migrate(&mut conn, "audit", MIGRATIONS)?;
+        Ok(Self { conn: Mutex::new(conn), retention })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO audit (at, command, origin, service_id, target, outcome, latency_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.at.timestamp(),
+                entry.command,
+                entry.origin,
+                entry.service_id,
+                entry.target,
+                entry.outcome,
+                entry.latency.as_millis() as i64,
+            ],
+        )?;
+        let cutoff = Utc::now().timestamp() - self.retention.as_secs() as i64;
+        conn.execute("DELETE FROM audit WHERE at < ?1", params![cutoff])?;
+        Ok(())
+    }
+
+    /// The `limit` most recent entries, newest first.
+    pub fn recent(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT at, command, origin, service_id, target, outcome, latency_ms FROM audit
+             ORDER BY id DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok(AuditEntry {
+                at: DateTime::from_timestamp(row.get(0)?, 0).unwrap_or_default(),
+                command: row.get(1)?,
+                origin: row.get(2)?,
+                service_id: row.get(3)?,
+                target: row.get(4)?,
+                outcome: row.get(5)?,
+                latency: Duration::from_millis(row.get::<_, i64>(6)?.max(0) as u64),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+/// One line per entry, e.g.
+/// `2026-01-05 19:02:11 generate_invite_token matrix → @bob:example.com by invite: ok (84ms)`.
+pub fn format_entries(entries: &[AuditEntry]) -> String {
+    if entries.is_empty() {
+        return "No commands recorded".to_string();
+    }
+    entries
+        .iter()
+        .map(|entry| {
+            let origin = entry.origin.as_deref().unwrap_or("unknown");
+            format!(
+                "{} {} {} → {} by {}: {} ({}ms)",
+                entry.at.format("%Y-%m-%d %H:%M:%S"),
+                entry.command,
+                entry.service_id,
+                entry.target,
+                origin,
+                entry.outcome,
+                entry.latency.as_millis()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,203 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// Written first in every backup, so a restore can tell a backup from any other tarball.
+pub const MANIFEST_FILE: &str = "kelvin-backup.json";
+/// Where the Matrix service keeps its store, relative to the data directory.
+pub const MATRIX_STORE_DIRECTORY: &str = "matrix";
+
+const SQLITE_EXTENSION: &str = "sqlite3";
+/// Files SQLite keeps beside a database while it's open. They're folded into the snapshot of
+/// the database, and stale ones are removed on restore so they aren't replayed onto it.
+const SQLITE_SIDECARS: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub created_at: DateTime<Utc>,
+    pub includes_matrix_store: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    /// The Matrix store holds the session and encryption keys. It's left out by default: it's
+    /// large, and restoring an old copy of it over a newer one can break decryption.
+    pub include_matrix_store: bool,
+    /// Paths left out, e.g. the directory scheduled backups are written to.
+    pub exclude: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupSummary {
+    pub manifest: BackupManifest,
+    /// Files archived or restored, not counting the manifest.
+    pub files: usize,
+}
+
+/// Archives the data directory into a gzipped tarball at `archive`: the middleware stores,
+/// outbox, audit trail, logs and media, and with `include_matrix_store` the Matrix store.
+///
+/// SQLite databases are copied with `VACUUM INTO`, so a backup taken while the bot is running
+/// holds a consistent snapshot of each. The archive is written beside `archive` and moved into
+/// place once complete, so a failed backup never leaves a truncated one behind.
+pub fn create_backup(
+    data_directory: &Path,
+    archive: &Path,
+    options: &BackupOptions,
+) -> Result<BackupSummary> {
+    let data_directory = data_directory
+        .canonicalize()
+        .with_context(|| format!("could not open {}", data_directory.display()))?;
+    let partial = sibling(archive, ".partial");
+    let scratch = sibling(archive, ".snapshot");
+    let file = File::create(&partial)
+        .with_context(|| format!("could not create {}", partial.display()))?;
+
+    let mut skipped: Vec<PathBuf> = options.exclude.clone();
+    skipped.extend([archive.to_path_buf(), partial.clone(), scratch.clone()]);
+    let skipped: Vec<PathBuf> = skipped.iter().filter_map(|path| absolute(path)).collect();
+
+    let manifest = BackupManifest {
+        created_at: Utc::now(),
+        includes_matrix_store: options.include_matrix_store,
+    };
+    let result = (|| {
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_json.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created_at.timestamp().max(0) as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, MANIFEST_FILE, manifest_json.as_slice())?;
+
+        let mut files = 0;
+        let mut pending = vec![PathBuf::new()];
+        while let Some(relative) = pending.pop() {
+            let mut entries = fs::read_dir(data_directory.join(&relative))?
+                .collect::<std::io::Result<Vec<_>>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let path = entry.path();
+                let name = relative.join(entry.file_name());
+                if skipped.contains(&path)
+                    || (!options.include_matrix_store && name == Path::new(MATRIX_STORE_DIRECTORY))
+                {
+                    continue;
+                }
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    pending.push(name);
+                } else if !file_type.is_file() || is_sqlite_sidecar(&name) {
+                    continue;
+                } else if name.extension().is_some_and(|ext| ext == SQLITE_EXTENSION) {
+                    snapshot_database(&path, &scratch)?;
+                    builder.append_path_with_name(&scratch, &name)?;
+                    files += 1;
+                } else {
+                    builder.append_path_with_name(&path, &name)?;
+                    files += 1;
+                }
+            }
+        }
+        builder.into_inner()?.finish()?.sync_all()?;
+        anyhow::Ok(files)
+    })();
+    let _ = fs::remove_file(&scratch);
+
+    match result {
+        Ok(files) => {
+            fs::rename(&partial, archive)
+                .with_context(|| format!("could not move the backup to {}", archive.display()))?;
+            Ok(BackupSummary { manifest, files })
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e.context(format!("could not back up {}", data_directory.display())))
+        }
+    }
+}
+
+/// Unpacks a backup made by `create_backup` into `data_directory`, replacing the files it
+/// holds. Files the backup doesn't hold are left alone, so restoring a backup without the
+/// Matrix store keeps the current one. The bot must not be running while this runs.
+pub fn restore_backup(archive: &Path, data_directory: &Path) -> Result<BackupSummary> {
+    let file =
+        File::open(archive).with_context(|| format!("could not open {}", archive.display()))?;
+    let mut tarball = tar::Archive::new(GzDecoder::new(file));
+    let mut entries = tarball.entries()?;
+
+    let not_a_backup = || format!("{} isn't a kelvin-bot backup", archive.display());
+    let mut first = entries.next().with_context(not_a_backup)?.with_context(not_a_backup)?;
+    if first.path()?.as_os_str() != MANIFEST_FILE {
+        bail!(not_a_backup());
+    }
+    let mut manifest_json = Vec::new();
+    first.read_to_end(&mut manifest_json)?;
+    let manifest: BackupManifest =
+        serde_json::from_slice(&manifest_json).with_context(not_a_backup)?;
+
+    fs::create_dir_all(data_directory)
+        .with_context(|| format!("could not create {}", data_directory.display()))?;
+    let mut files = 0;
+    for entry in entries {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if name.extension().is_some_and(|ext| ext == SQLITE_EXTENSION) {
+            for suffix in SQLITE_SIDECARS {
+                let _ = fs::remove_file(sibling(&data_directory.join(&name), suffix));
+            }
+        }
+        if !entry.unpack_in(data_directory)? {
+            bail!(
+                "{} holds a file outside the data directory: {}",
+                archive.display(),
+                name.display()
+            );
+        }
+        files += 1;
+    }
+    Ok(BackupSummary { manifest, files })
+}
+
+/// `path` with `suffix` appended to its file name, e.g. `outbox.sqlite3-wal`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// `path` with its directory resolved, for comparing against paths found walking the data
+/// directory. Unlike `canonicalize`, works for files that don't exist yet.
+fn absolute(path: &Path) -> Option<PathBuf> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    Some(parent.unwrap_or(Path::new(".")).canonicalize().ok()?.join(path.file_name()?))
+}
+
+fn is_sqlite_sidecar(name: &Path) -> bool {
+    let name = name.to_string_lossy();
+    SQLITE_SIDECARS
+        .iter()
+        .any(|suffix| name.strip_suffix(suffix).is_some_and(|db| db.ends_with(SQLITE_EXTENSION)))
+}
+
+fn snapshot_database(path: &Path, scratch: &Path) -> Result<()> {
+    let _ = fs::remove_file(scratch);
+    let connection =
+        Connection::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    connection
+        .execute("VACUUM INTO ?1", [scratch.to_string_lossy()])
+        .with_context(|| format!("could not snapshot {}", path.display()))?;
+    Ok(())
+}
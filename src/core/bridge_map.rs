@@ -0,0 +1,141 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::core::{
+    migrations::{self, Migration},
+    service::ServiceId,
+};
+
+/// File in the data directory holding the map.
+pub const BRIDGE_MAP_FILE: &str = "bridge_map.sqlite3";
+
+/// How long a mapping is kept. Threads and edits rarely reach back further than this.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "create the bridge_map table",
+    sql: "CREATE TABLE IF NOT EXISTS bridge_map (
+            source_service TEXT NOT NULL,
+            source_id TEXT NOT NULL,
+            dest_service TEXT NOT NULL,
+            dest_id TEXT NOT NULL,
+            recorded_at INTEGER NOT NULL,
+            PRIMARY KEY (source_service, source_id, dest_service)
+        );
+        CREATE INDEX IF NOT EXISTS bridge_map_dest ON bridge_map (dest_service, dest_id);
+        CREATE INDEX IF NOT EXISTS bridge_map_recorded_at ON bridge_map (recorded_at);",
+}];
+
+/// Which message a relay posted for which, as (source service, source message ID) →
+/// (destination service, destination message ID), shared by every middleware (through
+/// `MiddlewareContext::bridge_map`).
+///
+/// Kept in sqlite so relays can still find the copy of a message after a restart, e.g. to
+/// post a reply in the right thread. Mappings expire after `ttl`, pruned as new ones are
+/// recorded.
+#[derive(Clone)]
+pub struct BridgeMap {
+    conn: Arc<Mutex<Connection>>,
+    ttl: Duration,
+}
+
+impl BridgeMap {
+    /// Opens (or creates) the map kept in `data_directory`.
+    pub fn load(data_directory: &Path) -> Result<Self> {
+        Self::open(data_directory.join(BRIDGE_MAP_FILE), DEFAULT_TTL)
+    }
+
+    /// Open (or create) the map database at `path`.
+    pub fn open(path: impl AsRef<Path>, ttl: Duration) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?, ttl)
+    }
+
+    /// Create a map that lives only in memory. Useful for testing.
+    pub fn in_memory(ttl: Duration) -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, ttl)
+    }
+
+    fn init(mut conn: Connection, ttl: Duration) -> Result<Self> {
+        migrations::migrate(&mut conn, "bridge_map", MIGRATIONS)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)), ttl })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn cutoff(&self) -> i64 {
+        Utc::now().timestamp() - self.ttl.as_secs() as i64
+    }
+
+    /// Records that message `source_id` on `source` was relayed to `dest` as `dest_id`,
+    /// replacing any earlier copy there.
+    pub fn record(
+        &self,
+        source: &ServiceId,
+        source_id: &str,
+        dest: &ServiceId,
+        dest_id: &str,
+    ) -> Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT OR REPLACE INTO bridge_map
+             (source_service, source_id, dest_service, dest_id, recorded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![source.0, source_id, dest.0, dest_id, Utc::now().timestamp()],
+        )?;
+        conn.execute("DELETE FROM bridge_map WHERE recorded_at < ?1", params![self.cutoff()])?;
+        Ok(())
+    }
+
+    /// Every unexpired copy of message `source_id` on `source`, as (service, message ID).
+    pub fn copies(&self, source: &ServiceId, source_id: &str) -> Result<Vec<(ServiceId, String)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT dest_service, dest_id FROM bridge_map
+             WHERE source_service = ?1 AND source_id = ?2 AND recorded_at >= ?3
+             ORDER BY dest_service",
+        )?;
+        let rows = stmt.query_map(params![source.0, source_id, self.cutoff()], |row| {
+            Ok((ServiceId(row.get(0)?), row.get(1)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// The ID on `other` of the message `message_id` on `service` stands for, whichever way it
+    /// was relayed: its copy there, or the original it's a copy of.
+    pub fn counterpart(
+        &self,
+        service: &ServiceId,
+        message_id: &str,
+        other: &ServiceId,
+    ) -> Result<Option<String>> {
+        let conn = self.conn();
+        let found = conn
+            .query_row(
+                "SELECT dest_id FROM bridge_map
+                 WHERE source_service = ?1 AND source_id = ?2 AND dest_service = ?3
+                   AND recorded_at >= ?4
+                 UNION ALL
+                 SELECT source_id FROM bridge_map
+                 WHERE dest_service = ?1 AND dest_id = ?2 AND source_service = ?3
+                   AND recorded_at >= ?4
+                 LIMIT 1",
+                params![service.0, message_id, other.0, self.cutoff()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(found)
+    }
+}
@@ -1,43 +1,88 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use anyhow::Context;
+use std::collections::{HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::error::{SendError, TryRecvError, TrySendError};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{Instrument, info};
 
-use crate::core::config::{ExponentialBackoff, ReconnectionConfig};
-use crate::core::event::Event;
-use crate::core::middleware::{Middleware, Verdict};
-use crate::core::service::{Service, ServiceId};
+use crate::core::alerts;
+use crate::core::audit::{self, AuditEntry, AuditLog};
+use crate::core::commands::{CommandSpec, command_text};
+use crate::core::config::{
+    AlertLevel, CommandDispatch, Config, ExponentialBackoff, LifecycleAnnouncementsConfig,
+    ReconnectionConfig, WatchdogConfig,
+};
+use crate::core::connection_schedule::ConnectionSchedule;
+use crate::core::coordination::Lease;
+use crate::core::correlation::{self, CorrelationId};
+use crate::core::event::{Event, EventKind, Provenance, User};
+use crate::core::format::BodyFormat;
+use crate::core::metrics::{LagStage, MetricsRegistry};
+use crate::core::middleware::{self, Middleware, MiddlewareReloader, Verdict};
+use crate::core::outbox::{Outbox, QueuedCommand};
+use crate::core::roster::Roster;
+use crate::core::service::{Service, ServiceId, ServiceLoader};
+use crate::core::user_list::UserLists;
+use crate::core::watchdog::{self, Heartbeat};
 
+/// Something for a service (or, for `Control`, the bus) to do.
+///
+/// `origin` names the middleware instance that issued the command and the event it was issued
+/// in response to. Middlewares leave it `None` and the `CommandSender` in their context fills
+/// it in.
+///
+/// Messages may carry an `idempotency_key`: a service that sees the same key twice sends the
+/// message once and answers the repeat with the first result.
 pub enum Command {
     SendDirectMessage {
         service_id: ServiceId,
         user_id: String,
         body: String,
         response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+        idempotency_key: Option<String>,
+        /// Deleted this long after it's sent, on services that can delete messages.
+        expires_after: Option<Duration>,
+        /// Only sent if the conversation is end-to-end encrypted, for secrets. Otherwise the
+        /// service responds with a [`NotEncrypted`] error.
+        require_encryption: bool,
     },
     SendRoomMessage {
         service_id: ServiceId,
         room_id: String,
         body: String,
-        markdown_body: Option<String>,
+        /// How `body` is written. Each service renders it into its own format.
+        format: BodyFormat,
         response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+        idempotency_key: Option<String>,
+        /// Set when relaying someone else's message, so services can mark it as such.
+        relayed_from: Option<Provenance>,
+        /// Deleted this long after it's sent, on services that can delete messages.
+        expires_after: Option<Duration>,
     },
     SendThreadReply {
         service_id: ServiceId,
         room_id: String,
         thread_root_id: String,
         body: String,
-        markdown_body: Option<String>,
+        format: BodyFormat,
         response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+        idempotency_key: Option<String>,
     },
     EditMessage {
         service_id: ServiceId,
         message_id: String,
         new_body: String,
-        new_markdown_body: Option<String>,
+        format: BodyFormat,
+        origin: Option<CommandOrigin>,
     },
     GenerateInviteToken {
         service_id: ServiceId,
@@ -45,12 +90,17 @@ pub enum Command {
         uses_allowed: Option<u32>,
         expiry: Option<Duration>,
         response_tx: tokio::sync::oneshot::Sender<anyhow::Result<String>>,
+        origin: Option<CommandOrigin>,
     },
+    /// Deletes one of the bot's messages, on services that can (`supports_delete`). The bus
+    /// sends these itself for messages with an `expires_after`.
+    DeleteMessage { service_id: ServiceId, message_id: String, origin: Option<CommandOrigin> },
     AddReaction {
         service_id: ServiceId,
         room_id: String,
         event_id: String,
         key: String,
+        origin: Option<CommandOrigin>,
     },
     SendRoomImage {
         service_id: ServiceId,
@@ -59,34 +109,315 @@ pub enum Command {
         source_url: String,
         thumbnail_data: Vec<u8>,
         thumbnail_mimetype: String,
+        origin: Option<CommandOrigin>,
     },
+    /// Reads one of the bot's custom state events (see `room_state`) in a room. Responds with
+    /// the event's content, or `None` if it was never set.
+    GetRoomState {
+        service_id: ServiceId,
+        room_id: String,
+        event_type: String,
+        state_key: String,
+        response_tx: tokio::sync::oneshot::Sender<anyhow::Result<Option<serde_json::Value>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Writes one of the bot's custom state events in a room, replacing its previous content.
+    /// Responds with the ID of the new state event.
+    SetRoomState {
+        service_id: ServiceId,
+        room_id: String,
+        event_type: String,
+        state_key: String,
+        content: serde_json::Value,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Replaces a room's topic. Responds with the ID of the event that changed it, where the
+    /// service has one.
+    SetRoomTopic {
+        service_id: ServiceId,
+        room_id: String,
+        topic: String,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Lets in a user who knocked on a room (see `EventKind::Knock`).
+    ApproveKnock {
+        service_id: ServiceId,
+        room_id: String,
+        user_id: String,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Removes a user from a room, telling them `reason` where the service can. Mumble has no
+    /// per-channel kicks, so it disconnects them from the server.
+    KickUser {
+        service_id: ServiceId,
+        room_id: String,
+        user_id: String,
+        reason: Option<String>,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Joins a room by ID or alias. Rooms that only let people in on request are knocked on
+    /// instead, which the service reports with an `EventKind::Knock` from the bot. Responds
+    /// with the room's ID.
+    JoinRoom {
+        service_id: ServiceId,
+        room: String,
+        /// Shown to the room's moderators if the bot has to knock.
+        reason: Option<String>,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Answers an interactive device verification the service is waiting on (see
+    /// `EventKind::VerificationRequested`): `confirm` if the emoji matched, which completes it,
+    /// otherwise it's cancelled.
+    ConfirmVerification {
+        service_id: ServiceId,
+        flow_id: String,
+        confirm: bool,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Measures a round trip to the service's server, e.g. to tell whether lag is the bot's or
+    /// the server's. Services with no server to ask respond with an error.
+    Ping {
+        service_id: ServiceId,
+        response_tx: tokio::sync::oneshot::Sender<anyhow::Result<Duration>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Lists who's connected to the service right now, on services that keep such a list (who's
+    /// on the Mumble server). Others respond with an error.
+    GetUserList {
+        service_id: ServiceId,
+        response_tx: tokio::sync::oneshot::Sender<anyhow::Result<Vec<ConnectedUser>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Posts `body` to every target, flagged with `priority` the same way wherever it goes.
+    /// The bus expands it into a message per target, dispatched like any other (so
+    /// `allowed_commands` and read-only services apply to those), retries the ones that fail
+    /// with a transient error, and responds with a report of where it was posted. It only fails
+    /// if it was posted nowhere.
+    Announce {
+        targets: Vec<(ServiceId, RoomTarget)>,
+        body: String,
+        priority: AnnouncementPriority,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+        origin: Option<CommandOrigin>,
+    },
+    /// Handled by the bus itself rather than dispatched to a service.
+    Control(BusControl),
+}
+
+/// Where a `Command::Announce` is posted on one of its services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomTarget {
+    Room(String),
+    DirectMessage(String),
+}
+
+impl std::fmt::Display for RoomTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomTarget::Room(room_id) => f.write_str(room_id),
+            RoomTarget::DirectMessage(user_id) => write!(f, "DM {user_id}"),
+        }
+    }
+}
+
+/// How much an announcement matters, shown by the marker it's posted with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnnouncementPriority {
+    /// Good to know, e.g. a new release.
+    Info,
+    /// Affects people, e.g. planned maintenance.
+    #[default]
+    Notice,
+    /// Needs attention now, e.g. an outage.
+    Urgent,
+}
+
+/// `body` as it's posted for an announcement of `priority`, e.g. `📢 Maintenance at 22:00`.
+pub fn format_announcement(priority: AnnouncementPriority, body: &str) -> String {
+    let marker = match priority {
+        AnnouncementPriority::Info => "ℹ️",
+        AnnouncementPriority::Notice => "📢",
+        AnnouncementPriority::Urgent => "🚨",
+    };
+    format!("{marker} {body}")
+}
+
+/// A user listed by `Command::GetUserList`.
+#[derive(Debug, Clone)]
+pub struct ConnectedUser {
+    pub user: User,
+    /// The room they're in, on services where users are always in one (a Mumble channel).
+    pub room_id: Option<String>,
+}
+
+/// Something an operator should look at, published on the bus's alert channel.
+#[derive(Debug, Clone)]
+pub enum BusAlert {
+    /// A middleware panicked while handling an event. The bus skipped it for that event and
+    /// moved on; `disabled` is set once it has panicked often enough to be switched off.
+    MiddlewarePanicked {
+        middleware: String,
+        service_id: ServiceId,
+        /// `EventKind::name` of the event being handled.
+        event_kind: &'static str,
+        message: String,
+        panic_count: u32,
+        disabled: bool,
+    },
+    /// A service refused a moderation command because the bot lacks the power to carry it out
+    /// in the room, e.g. its power level is too low to kick.
+    PermissionDenied {
+        service_id: ServiceId,
+        /// The middleware that sent the command.
+        origin: String,
+        room_id: String,
+        /// What the bot couldn't do, e.g. `kick`.
+        action: &'static str,
+    },
+    /// The bus loop made no progress for `stalled_for`, e.g. because a service's
+    /// `handle_command` never returned. No events or commands are handled until it moves on.
+    BusStalled {
+        stalled_for: Duration,
+        /// What the loop was doing, e.g. `dispatching send_room_message to service 'matrix'`;
+        /// `None` when it was waiting for work.
+        step: Option<String>,
+    },
+}
+
+/// Runtime controls for the bus. Changes last until reverted or the process restarts.
+#[derive(Debug)]
+pub enum BusControl {
+    /// Drop events emitted by a service instead of running them through its pipeline.
+    PauseService {
+        service_id: ServiceId,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    ResumeService {
+        service_id: ServiceId,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    /// Skip a middleware (by config name) in every pipeline that contains it.
+    DisableMiddleware {
+        name: String,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    EnableMiddleware {
+        name: String,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    /// Rebuild a middleware (by config name) from a freshly loaded config and swap it into
+    /// every pipeline that contains it. Fails if the bus has no reloader.
+    ReloadMiddleware {
+        name: String,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    /// Report the `limit` most recent audit trail entries. Fails if auditing is disabled.
+    RecentAudit {
+        limit: usize,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    /// Report the middlewares events from a service run through, in order, and how often each
+    /// stopped the pipeline. With `room_id`, the pipeline for events from that room.
+    DescribePipeline {
+        service_id: ServiceId,
+        room_id: Option<String>,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    /// Simulate a failure of a service, to exercise supervision, backoff and the outbox.
+    InjectFault {
+        service_id: ServiceId,
+        fault: Fault,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+    /// Build a service (by config name) from a freshly loaded config and start it alongside the
+    /// running ones, with its configured pipelines, room filter and schedule. Fails if the bus
+    /// has no service loader or the service is already running.
+    AddService {
+        service_id: ServiceId,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    },
+}
+
+/// A simulated service failure, injected with `BusControl::InjectFault`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// End the service's task as if it had failed, so it's restarted after a backoff.
+    Crash,
+    /// Hold every command to the service for this long before handing it over.
+    DelayCommands(Duration),
+    /// Drop this share of the service's events, from 0.0 to 1.0, before they reach a pipeline.
+    DropEvents(f64),
+    /// Stop delaying commands and dropping events.
+    Clear,
+}
+
+/// The delays and drops injected into one service.
+#[derive(Debug, Clone, Copy, Default)]
+struct InjectedFaults {
+    command_delay: Option<Duration>,
+    event_drop_rate: f64,
 }
 
 // Implement Debug manually since oneshot::Sender doesn't implement Clone
 impl std::fmt::Debug for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Command::SendDirectMessage { service_id, user_id, body, .. } => f
+            Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body,
+                origin,
+                idempotency_key,
+                expires_after,
+                require_encryption,
+                ..
+            } => f
                 .debug_struct("SendDirectMessage")
                 .field("service_id", service_id)
                 .field("user_id", user_id)
                 .field("body", body)
                 .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .field("idempotency_key", idempotency_key)
+                .field("expires_after", expires_after)
+                .field("require_encryption", require_encryption)
                 .finish(),
-            Command::SendRoomMessage { service_id, room_id, body, markdown_body, .. } => f
+            Command::SendRoomMessage {
+                service_id,
+                room_id,
+                body,
+                format,
+                origin,
+                idempotency_key,
+                relayed_from,
+                expires_after,
+                ..
+            } => f
                 .debug_struct("SendRoomMessage")
                 .field("service_id", service_id)
                 .field("room_id", room_id)
                 .field("body", body)
-                .field("markdown_body", markdown_body)
+                .field("format", format)
                 .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .field("idempotency_key", idempotency_key)
+                .field("relayed_from", relayed_from)
+                .field("expires_after", expires_after)
                 .finish(),
             Command::SendThreadReply {
                 service_id,
                 room_id,
                 thread_root_id,
                 body,
-                markdown_body,
+                format,
+                origin,
+                idempotency_key,
                 ..
             } => f
                 .debug_struct("SendThreadReply")
@@ -94,117 +425,2493 @@ impl std::fmt::Debug for Command {
                 .field("room_id", room_id)
                 .field("thread_root_id", thread_root_id)
                 .field("body", body)
-                .field("markdown_body", markdown_body)
+                .field("format", format)
                 .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .field("idempotency_key", idempotency_key)
                 .finish(),
-            Command::EditMessage { service_id, message_id, new_body, new_markdown_body } => f
+            Command::EditMessage { service_id, message_id, new_body, format, origin, .. } => f
                 .debug_struct("EditMessage")
                 .field("service_id", service_id)
                 .field("message_id", message_id)
                 .field("new_body", new_body)
-                .field("new_markdown_body", new_markdown_body)
+                .field("format", format)
+                .field("origin", origin)
+                .finish(),
+            Command::DeleteMessage { service_id, message_id, origin } => f
+                .debug_struct("DeleteMessage")
+                .field("service_id", service_id)
+                .field("message_id", message_id)
+                .field("origin", origin)
                 .finish(),
-            Command::GenerateInviteToken { service_id, user_id, uses_allowed, expiry, .. } => f
+            Command::GenerateInviteToken {
+                service_id,
+                user_id,
+                uses_allowed,
+                expiry,
+                origin,
+                ..
+            } => f
                 .debug_struct("GenerateInviteToken")
                 .field("service_id", service_id)
                 .field("user_id", user_id)
                 .field("uses_allowed", uses_allowed)
                 .field("expiry", expiry)
                 .field("response_tx", &"<oneshot::Sender>")
+                .field("origin", origin)
                 .finish(),
-            Command::AddReaction { service_id, room_id, event_id, key } => f
+            Command::AddReaction { service_id, room_id, event_id, key, origin, .. } => f
                 .debug_struct("AddReaction")
                 .field("service_id", service_id)
                 .field("room_id", room_id)
                 .field("event_id", event_id)
                 .field("key", key)
+                .field("origin", origin)
                 .finish(),
-            Command::SendRoomImage { service_id, room_id, caption, .. } => f
+            Command::SendRoomImage { service_id, room_id, caption, origin, .. } => f
                 .debug_struct("SendRoomImage")
                 .field("service_id", service_id)
                 .field("room_id", room_id)
                 .field("caption", caption)
+                .field("origin", origin)
+                .finish(),
+            Command::GetRoomState {
+                service_id, room_id, event_type, state_key, origin, ..
+            } => f
+                .debug_struct("GetRoomState")
+                .field("service_id", service_id)
+                .field("room_id", room_id)
+                .field("event_type", event_type)
+                .field("state_key", state_key)
+                .field("response_tx", &"<oneshot::Sender>")
+                .field("origin", origin)
+                .finish(),
+            Command::SetRoomState {
+                service_id,
+                room_id,
+                event_type,
+                state_key,
+                content,
+                origin,
+                ..
+            } => f
+                .debug_struct("SetRoomState")
+                .field("service_id", service_id)
+                .field("room_id", room_id)
+                .field("event_type", event_type)
+                .field("state_key", state_key)
+                .field("content", content)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::SetRoomTopic { service_id, room_id, topic, origin, .. } => f
+                .debug_struct("SetRoomTopic")
+                .field("service_id", service_id)
+                .field("room_id", room_id)
+                .field("topic", topic)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::ApproveKnock { service_id, room_id, user_id, origin, .. } => f
+                .debug_struct("ApproveKnock")
+                .field("service_id", service_id)
+                .field("room_id", room_id)
+                .field("user_id", user_id)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::KickUser { service_id, room_id, user_id, reason, origin, .. } => f
+                .debug_struct("KickUser")
+                .field("service_id", service_id)
+                .field("room_id", room_id)
+                .field("user_id", user_id)
+                .field("reason", reason)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::JoinRoom { service_id, room, reason, origin, .. } => f
+                .debug_struct("JoinRoom")
+                .field("service_id", service_id)
+                .field("room", room)
+                .field("reason", reason)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::ConfirmVerification { service_id, flow_id, confirm, origin, .. } => f
+                .debug_struct("ConfirmVerification")
+                .field("service_id", service_id)
+                .field("flow_id", flow_id)
+                .field("confirm", confirm)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::Ping { service_id, origin, .. } => f
+                .debug_struct("Ping")
+                .field("service_id", service_id)
+                .field("response_tx", &"<oneshot::Sender>")
+                .field("origin", origin)
                 .finish(),
+            Command::GetUserList { service_id, origin, .. } => f
+                .debug_struct("GetUserList")
+                .field("service_id", service_id)
+                .field("response_tx", &"<oneshot::Sender>")
+                .field("origin", origin)
+                .finish(),
+            Command::Announce { targets, body, priority, origin, .. } => f
+                .debug_struct("Announce")
+                .field("targets", targets)
+                .field("body", body)
+                .field("priority", priority)
+                .field("response_tx", &"<Option<oneshot::Sender>>")
+                .field("origin", origin)
+                .finish(),
+            Command::Control(control) => f.debug_tuple("Control").field(control).finish(),
+        }
+    }
+}
+
+/// Who issued a command, and the inbound event it was issued in response to, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOrigin {
+    /// The middleware instance, as named in config.
+    pub middleware: String,
+    pub correlation_id: Option<CorrelationId>,
+}
+
+impl CommandOrigin {
+    /// Attributes a command to `middleware`, as part of handling the current task's event.
+    pub fn new(middleware: impl Into<String>) -> Self {
+        Self { middleware: middleware.into(), correlation_id: CorrelationId::current() }
+    }
+}
+
+impl Command {
+    /// The middleware instance that issued the command, if known.
+    pub fn origin(&self) -> Option<&str> {
+        self.origin_metadata().map(|origin| origin.middleware.as_str())
+    }
+
+    /// The inbound event the command was issued in response to, if any.
+    pub fn correlation_id(&self) -> Option<CorrelationId> {
+        self.origin_metadata()?.correlation_id
+    }
+
+    fn origin_metadata(&self) -> Option<&CommandOrigin> {
+        match self {
+            Command::SendDirectMessage { origin, .. }
+            | Command::SendRoomMessage { origin, .. }
+            | Command::SendThreadReply { origin, .. }
+            | Command::EditMessage { origin, .. }
+            | Command::DeleteMessage { origin, .. }
+            | Command::GenerateInviteToken { origin, .. }
+            | Command::AddReaction { origin, .. }
+            | Command::SendRoomImage { origin, .. }
+            | Command::GetRoomState { origin, .. }
+            | Command::SetRoomState { origin, .. }
+            | Command::SetRoomTopic { origin, .. }
+            | Command::ApproveKnock { origin, .. }
+            | Command::KickUser { origin, .. }
+            | Command::JoinRoom { origin, .. }
+            | Command::ConfirmVerification { origin, .. }
+            | Command::Ping { origin, .. }
+            | Command::GetUserList { origin, .. }
+            | Command::Announce { origin, .. } => origin.as_ref(),
+            Command::Control(_) => None,
+        }
+    }
+
+    /// The key services dedupe the command by, if it has one.
+    pub fn idempotency_key(&self) -> Option<&str> {
+        match self {
+            Command::SendDirectMessage { idempotency_key, .. }
+            | Command::SendRoomMessage { idempotency_key, .. }
+            | Command::SendThreadReply { idempotency_key, .. } => idempotency_key.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Attributes the command to `name` and the current task's event, replacing any origin it
+    /// already claims so a middleware can't pass its commands off as another's.
+    pub fn set_origin(&mut self, name: &str) {
+        match self {
+            Command::SendDirectMessage { origin, .. }
+            | Command::SendRoomMessage { origin, .. }
+            | Command::SendThreadReply { origin, .. }
+            | Command::EditMessage { origin, .. }
+            | Command::DeleteMessage { origin, .. }
+            | Command::GenerateInviteToken { origin, .. }
+            | Command::AddReaction { origin, .. }
+            | Command::SendRoomImage { origin, .. }
+            | Command::GetRoomState { origin, .. }
+            | Command::SetRoomState { origin, .. }
+            | Command::SetRoomTopic { origin, .. }
+            | Command::ApproveKnock { origin, .. }
+            | Command::KickUser { origin, .. }
+            | Command::JoinRoom { origin, .. }
+            | Command::ConfirmVerification { origin, .. }
+            | Command::Ping { origin, .. }
+            | Command::GetUserList { origin, .. }
+            | Command::Announce { origin, .. } => *origin = Some(CommandOrigin::new(name)),
+            Command::Control(_) => {}
+        }
+    }
+
+    /// The command's type as named in config and the audit trail, e.g. `send_room_message`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Command::SendDirectMessage { .. } => "send_direct_message",
+            Command::SendRoomMessage { .. } => "send_room_message",
+            Command::SendThreadReply { .. } => "send_thread_reply",
+            Command::EditMessage { .. } => "edit_message",
+            Command::DeleteMessage { .. } => "delete_message",
+            Command::GenerateInviteToken { .. } => "generate_invite_token",
+            Command::AddReaction { .. } => "add_reaction",
+            Command::SendRoomImage { .. } => "send_room_image",
+            Command::GetRoomState { .. } => "get_room_state",
+            Command::SetRoomState { .. } => "set_room_state",
+            Command::SetRoomTopic { .. } => "set_room_topic",
+            Command::ApproveKnock { .. } => "approve_knock",
+            Command::KickUser { .. } => "kick_user",
+            Command::JoinRoom { .. } => "join_room",
+            Command::ConfirmVerification { .. } => "confirm_verification",
+            Command::Ping { .. } => "ping",
+            Command::GetUserList { .. } => "get_user_list",
+            Command::Announce { .. } => "announce",
+            Command::Control(_) => "bus_control",
+        }
+    }
+
+    /// Whether the command only reads state, so sending it changes nothing anyone can see.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::GetRoomState { .. } | Command::Ping { .. } | Command::GetUserList { .. }
+        )
+    }
+
+    /// Resolves the command's response channel, if it has one, with `err`.
+    ///
+    /// Services call this when they can't handle a command at all (e.g. while disconnected),
+    /// so callers waiting on a response learn why instead of seeing a dropped channel.
+    pub fn reject(self, err: anyhow::Error) {
+        match self {
+            Command::SendDirectMessage { response_tx: Some(tx), .. }
+            | Command::SendRoomMessage { response_tx: Some(tx), .. }
+            | Command::SendThreadReply { response_tx: Some(tx), .. }
+            | Command::GenerateInviteToken { response_tx: tx, .. }
+            | Command::SetRoomState { response_tx: Some(tx), .. }
+            | Command::SetRoomTopic { response_tx: Some(tx), .. }
+            | Command::ApproveKnock { response_tx: Some(tx), .. }
+            | Command::KickUser { response_tx: Some(tx), .. }
+            | Command::JoinRoom { response_tx: Some(tx), .. }
+            | Command::ConfirmVerification { response_tx: Some(tx), .. }
+            | Command::Announce { response_tx: Some(tx), .. } => {
+                let _ = tx.send(Err(err));
+            }
+            Command::GetRoomState { response_tx, .. } => {
+                let _ = response_tx.send(Err(err));
+            }
+            Command::Ping { response_tx, .. } => {
+                let _ = response_tx.send(Err(err));
+            }
+            Command::GetUserList { response_tx, .. } => {
+                let _ = response_tx.send(Err(err));
+            }
+            _ => {}
+        }
+    }
+
+    /// Resolves the command's response channel, if it has one, with `result`, without
+    /// carrying the command out. Used to answer a repeat of a command that already was.
+    pub fn resolve(self, result: String) {
+        match self {
+            Command::SendDirectMessage { response_tx: Some(tx), .. }
+            | Command::SendRoomMessage { response_tx: Some(tx), .. }
+            | Command::SendThreadReply { response_tx: Some(tx), .. } => {
+                let _ = tx.send(Ok(result));
+            }
+            _ => {}
+        }
+    }
+
+    /// Copies the command with a fresh response channel so it can be sent again. Messages keep
+    /// their idempotency key, so a retry of one that did go through isn't sent twice.
+    ///
+    /// Returns `None` for commands that don't report an outcome, since there is no
+    /// failure to react to.
+    fn with_fresh_response(
+        &self,
+    ) -> Option<(Command, tokio::sync::oneshot::Receiver<anyhow::Result<String>>)> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let command = match self {
+            Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body,
+                origin,
+                idempotency_key,
+                expires_after,
+                require_encryption,
+                ..
+            } => Command::SendDirectMessage {
+                service_id: service_id.clone(),
+                user_id: user_id.clone(),
+                body: body.clone(),
+                response_tx: Some(tx),
+                origin: origin.clone(),
+                idempotency_key: idempotency_key.clone(),
+                expires_after: *expires_after,
+                require_encryption: *require_encryption,
+            },
+            Command::SendRoomMessage {
+                service_id,
+                room_id,
+                body,
+                format,
+                origin,
+                idempotency_key,
+                relayed_from,
+                expires_after,
+                ..
+            } => Command::SendRoomMessage {
+                service_id: service_id.clone(),
+                room_id: room_id.clone(),
+                body: body.clone(),
+                format: *format,
+                response_tx: Some(tx),
+                origin: origin.clone(),
+                idempotency_key: idempotency_key.clone(),
+                relayed_from: relayed_from.clone(),
+                expires_after: *expires_after,
+            },
+            Command::SendThreadReply {
+                service_id,
+                room_id,
+                thread_root_id,
+                body,
+                format,
+                origin,
+                idempotency_key,
+                ..
+            } => Command::SendThreadReply {
+                service_id: service_id.clone(),
+                room_id: room_id.clone(),
+                thread_root_id: thread_root_id.clone(),
+                body: body.clone(),
+                format: *format,
+                response_tx: Some(tx),
+                origin: origin.clone(),
+                idempotency_key: idempotency_key.clone(),
+            },
+            Command::GenerateInviteToken {
+                service_id,
+                user_id,
+                uses_allowed,
+                expiry,
+                origin,
+                ..
+            } => Command::GenerateInviteToken {
+                service_id: service_id.clone(),
+                user_id: user_id.clone(),
+                uses_allowed: *uses_allowed,
+                expiry: *expiry,
+                response_tx: tx,
+                origin: origin.clone(),
+            },
+            Command::SetRoomState {
+                service_id,
+                room_id,
+                event_type,
+                state_key,
+                content,
+                origin,
+                ..
+            } => Command::SetRoomState {
+                service_id: service_id.clone(),
+                room_id: room_id.clone(),
+                event_type: event_type.clone(),
+                state_key: state_key.clone(),
+                content: content.clone(),
+                response_tx: Some(tx),
+                origin: origin.clone(),
+            },
+            Command::SetRoomTopic { service_id, room_id, topic, origin, .. } => {
+                Command::SetRoomTopic {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    topic: topic.clone(),
+                    response_tx: Some(tx),
+                    origin: origin.clone(),
+                }
+            }
+            Command::ApproveKnock { service_id, room_id, user_id, origin, .. } => {
+                Command::ApproveKnock {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    user_id: user_id.clone(),
+                    response_tx: Some(tx),
+                    origin: origin.clone(),
+                }
+            }
+            Command::KickUser { service_id, room_id, user_id, reason, origin, .. } => {
+                Command::KickUser {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    user_id: user_id.clone(),
+                    reason: reason.clone(),
+                    response_tx: Some(tx),
+                    origin: origin.clone(),
+                }
+            }
+            Command::JoinRoom { service_id, room, reason, origin, .. } => Command::JoinRoom {
+                service_id: service_id.clone(),
+                room: room.clone(),
+                reason: reason.clone(),
+                response_tx: Some(tx),
+                origin: origin.clone(),
+            },
+            Command::ConfirmVerification { service_id, flow_id, confirm, origin, .. } => {
+                Command::ConfirmVerification {
+                    service_id: service_id.clone(),
+                    flow_id: flow_id.clone(),
+                    confirm: *confirm,
+                    response_tx: Some(tx),
+                    origin: origin.clone(),
+                }
+            }
+            // Reads respond with content rather than an ID, so they're sent once
+            Command::EditMessage { .. }
+            | Command::DeleteMessage { .. }
+            | Command::AddReaction { .. }
+            | Command::SendRoomImage { .. }
+            | Command::GetRoomState { .. }
+            | Command::Ping { .. }
+            | Command::GetUserList { .. }
+            | Command::Control(_) => return None,
+            // Announcements retry their own messages, so sending one again would repost them
+            Command::Announce { .. } => return None,
+        };
+        Some((command, rx))
+    }
+}
+
+/// The command channel as handed to a middleware: stamps each command with the middleware's
+/// name before sending it, so the bus can tell who issued what.
+#[derive(Clone)]
+pub struct CommandSender {
+    tx: Sender<Command>,
+    origin: Arc<str>,
+}
+
+impl CommandSender {
+    pub fn new(tx: Sender<Command>, origin: impl Into<Arc<str>>) -> Self {
+        Self { tx, origin: origin.into() }
+    }
+
+    /// The middleware instance name commands are attributed to.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub async fn send(&self, mut command: Command) -> Result<(), SendError<Command>> {
+        command.set_origin(&self.origin);
+        self.tx.send(command).await
+    }
+
+    /// Like `send`, but fails instead of waiting when the channel is full, for callers that
+    /// can't await (or are the bus itself).
+    pub fn try_send(&self, mut command: Command) -> Result<(), TrySendError<Command>> {
+        command.set_origin(&self.origin);
+        self.tx.try_send(command)
+    }
+
+    /// Sends `command` from a background task, for middlewares replying from `on_event`, which
+    /// can't await. A failure is logged rather than returned, with the middleware, the command's
+    /// kind and `what` was being sent (e.g. "faq answer") for context.
+    pub fn spawn_send(&self, command: Command, what: &'static str) {
+        let cmd_tx = self.clone();
+        correlation::spawn(async move {
+            let kind = command.kind();
+            if let Err(e) = cmd_tx.send(command).await {
+                tracing::error!(origin=%cmd_tx.origin, command=%kind, error=%e, "failed to send {what}");
+            }
+        });
+    }
+}
+
+/// Marks a command failure as temporary, e.g. because the service is reconnecting.
+///
+/// Services wrap errors in this (see [`transient_error`]) so [`send_with_retry`] knows the
+/// command is worth sending again. Any other error is treated as permanent.
+#[derive(Debug)]
+pub struct TransientError(pub String);
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+pub fn transient_error(message: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(TransientError(message.into()))
+}
+
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TransientError>().is_some()
+}
+
+/// A DM with `require_encryption` wasn't sent because the conversation isn't end-to-end
+/// encrypted.
+#[derive(Debug)]
+pub struct NotEncrypted;
+
+impl std::fmt::Display for NotEncrypted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the direct message conversation isn't end-to-end encrypted")
+    }
+}
+
+impl std::error::Error for NotEncrypted {}
+
+/// A moderation command wasn't carried out because the bot isn't allowed to in the room, as
+/// found before asking the server.
+#[derive(Debug)]
+pub struct PermissionDenied {
+    pub room_id: String,
+    /// What the bot couldn't do, e.g. `kick`.
+    pub action: &'static str,
+}
+
+impl std::fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the bot's power level in {} is too low to {}", self.room_id, self.action)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+/// How [`send_with_retry`] spaces out and bounds its attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first. Zero is treated as one.
+    pub max_attempts: u32,
+    pub backoff: ReconnectionConfig,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            backoff: ReconnectionConfig {
+                initial_delay: Duration::from_millis(500),
+                max_delay: Duration::from_secs(10),
+                multiplier: 2.0,
+                jitter_factor: 0.1,
+            },
+        }
+    }
+}
+
+/// Sends `command` and waits for the service's response, re-sending it after a backoff
+/// delay whenever the service reports a [`TransientError`].
+///
+/// Returns the service's response (usually a message ID). The command's own `response_tx`
+/// is replaced, so read the result from the return value instead. Commands without a
+/// response channel (edits, reactions, images) are sent once and yield an empty string.
+pub async fn send_with_retry(
+    cmd_tx: &CommandSender,
+    command: Command,
+    policy: &RetryPolicy,
+) -> anyhow::Result<String> {
+    let max_attempts = policy.max_attempts.max(1);
+    let mut backoff = ExponentialBackoff::new(policy.backoff.clone());
+    let mut attempt = 1;
+
+    loop {
+        let Some((attempt_command, response_rx)) = command.with_fresh_response() else {
+            cmd_tx.send(command).await?;
+            return Ok(String::new());
+        };
+        cmd_tx.send(attempt_command).await?;
+
+        let err = match response_rx.await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) if is_retryable(&e) && attempt < max_attempts => e,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => anyhow::bail!("service dropped the command without responding"),
+        };
+
+        let delay = backoff.next_delay();
+        tracing::warn!(
+            error=%err,
+            attempt=%attempt,
+            delay_ms=%delay.as_millis(),
+            "command failed with a transient error, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Waits for the response to the first attempt at `message`, then sends it straight to
+/// `service` again after a backoff delay for as long as it fails with a transient error.
+async fn await_with_retry(
+    service: Option<Arc<dyn Service>>,
+    message: &Command,
+    mut response_rx: tokio::sync::oneshot::Receiver<anyhow::Result<String>>,
+    policy: &RetryPolicy,
+) -> anyhow::Result<String> {
+    let max_attempts = policy.max_attempts.max(1);
+    let mut backoff = ExponentialBackoff::new(policy.backoff.clone());
+    let mut attempt = 1;
+
+    loop {
+        let err = match response_rx.await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) if is_retryable(&e) && attempt < max_attempts => e,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => anyhow::bail!("no response from the service"),
+        };
+        let (Some(service), Some((retry, retry_rx))) = (&service, message.with_fresh_response())
+        else {
+            return Err(err);
+        };
+
+        let delay = backoff.next_delay();
+        tracing::warn!(
+            error=%err,
+            attempt=%attempt,
+            delay_ms=%delay.as_millis(),
+            "announcement failed with a transient error, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        // Failures are reported through the response channel
+        let _ = service.handle_command(retry).await;
+        response_rx = retry_rx;
+        attempt += 1;
+    }
+}
+
+/// Consecutive failures of a service after which the operator is alerted. It's restarted all
+/// the same.
+const ALERT_AFTER_FAILURES: u32 = 3;
+
+struct ServiceState {
+    backoff: ExponentialBackoff,
+    attempt_count: u32,
+    connection_start: Instant,
+}
+
+impl ServiceState {
+    fn new(reconnect_config: ReconnectionConfig) -> Self {
+        Self {
+            backoff: ExponentialBackoff::new(reconnect_config),
+            attempt_count: 0,
+            connection_start: Instant::now(),
+        }
+    }
+}
+
+/// Which rooms of a service the bus passes on to the middleware pipelines.
+///
+/// Events outside any room (direct messages, user list updates) always pass.
+#[derive(Debug, Clone, Default)]
+pub struct RoomFilter {
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>,
+}
+
+impl RoomFilter {
+    /// `allow` of `None` admits every room not in `deny`. A room in both lists is denied.
+    pub fn new(allow: Option<Vec<String>>, deny: Vec<String>) -> Self {
+        Self {
+            allow: allow.map(|rooms| rooms.into_iter().collect()),
+            deny: deny.into_iter().collect(),
+        }
+    }
+
+    pub fn allows(&self, room_id: &str) -> bool {
+        !self.deny.contains(room_id)
+            && self.allow.as_ref().is_none_or(|allow| allow.contains(room_id))
+    }
+}
+
+/// Room filters for every service that configures a `room_allowlist` or `room_denylist`.
+pub fn room_filters_from_config(config: &Config) -> HashMap<ServiceId, RoomFilter> {
+    config
+        .services
+        .iter()
+        .filter(|(_, cfg)| cfg.room_allowlist.is_some() || cfg.room_denylist.is_some())
+        .map(|(id, cfg)| {
+            let filter = RoomFilter::new(
+                cfg.room_allowlist.clone(),
+                cfg.room_denylist.clone().unwrap_or_default(),
+            );
+            (ServiceId(id.clone()), filter)
+        })
+        .collect()
+}
+
+/// Connection schedules for every service that configures a `schedule`.
+pub fn connection_schedules_from_config(
+    config: &Config,
+) -> anyhow::Result<HashMap<ServiceId, ConnectionSchedule>> {
+    config
+        .services
+        .iter()
+        .filter_map(|(id, cfg)| Some((id, cfg.schedule.as_ref()?)))
+        .map(|(id, schedule)| {
+            let schedule = ConnectionSchedule::from_config(schedule)
+                .with_context(|| format!("invalid schedule for service '{id}'"))?;
+            Ok((ServiceId(id.clone()), schedule))
+        })
+        .collect()
+}
+
+/// The services configured with `read_only = true`.
+pub fn read_only_services_from_config(config: &Config) -> HashSet<ServiceId> {
+    config
+        .services
+        .iter()
+        .filter(|(_, cfg)| cfg.read_only)
+        .map(|(id, _)| ServiceId(id.clone()))
+        .collect()
+}
+
+/// The `startup_timeout` of each service that sets one.
+pub fn startup_timeouts_from_config(config: &Config) -> HashMap<ServiceId, Duration> {
+    config
+        .services
+        .iter()
+        .filter_map(|(id, cfg)| Some((ServiceId(id.clone()), cfg.startup_timeout?)))
+        .collect()
+}
+
+/// Every command type a middleware's `allowed_commands` may name.
+pub const COMMAND_KINDS: [&str; 18] = [
+    "send_direct_message",
+    "send_room_message",
+    "send_thread_reply",
+    "edit_message",
+    "delete_message",
+    "generate_invite_token",
+    "add_reaction",
+    "send_room_image",
+    "get_room_state",
+    "set_room_state",
+    "set_room_topic",
+    "approve_knock",
+    "kick_user",
+    "join_room",
+    "confirm_verification",
+    "ping",
+    "get_user_list",
+    "announce",
+];
+
+/// Which command types each middleware may send, keyed by middleware config name.
+///
+/// Middlewares without an entry may send anything, as may commands with no origin (the bus's
+/// own announcements). Bus controls carry no origin, so they aren't restricted either.
+#[derive(Debug, Clone, Default)]
+pub struct CommandPolicy {
+    allowed: HashMap<String, HashSet<String>>,
+}
+
+impl CommandPolicy {
+    pub fn new(allowed: HashMap<String, Vec<String>>) -> Self {
+        Self {
+            allowed: allowed
+                .into_iter()
+                .map(|(name, kinds)| (name, kinds.into_iter().collect()))
+                .collect(),
+        }
+    }
+
+    pub fn permits(&self, command: &Command) -> bool {
+        let Some(origin) = command.origin() else { return true };
+        // Per-service instances are named `middleware@service`
+        let name = origin.split_once('@').map_or(origin, |(name, _)| name);
+        self.allowed.get(name).is_none_or(|kinds| kinds.contains(command.kind()))
+    }
+}
+
+/// The command policy for every middleware that configures `allowed_commands`.
+pub fn command_policy_from_config(config: &Config) -> CommandPolicy {
+    CommandPolicy::new(
+        config
+            .middlewares
+            .iter()
+            .filter_map(|(name, cfg)| Some((name.clone(), cfg.allowed_commands.clone()?)))
+            .collect(),
+    )
+}
+
+/// Names of the middlewares configured with `enabled = false`.
+pub fn disabled_middlewares_from_config(config: &Config) -> Vec<String> {
+    config
+        .middlewares
+        .iter()
+        .filter(|(_, cfg)| !cfg.enabled)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Names of the middlewares configured with `dry_run = true`.
+pub fn dry_run_middlewares_from_config(config: &Config) -> Vec<String> {
+    config.middlewares.iter().filter(|(_, cfg)| cfg.dry_run).map(|(name, _)| name.clone()).collect()
+}
+
+/// How a middleware instance has answered the events it handled since startup.
+#[derive(Default)]
+struct VerdictStats {
+    continued: u64,
+    stopped: u64,
+    // When it last stopped a pipeline, and on what kind of event
+    last_stop: Option<(Instant, &'static str)>,
+}
+
+/// Runtime middleware state shared by the bus and its per-service pipeline tasks.
+#[derive(Default)]
+struct MiddlewareControls {
+    disabled: Vec<Arc<dyn Middleware>>,
+    // Panics caught per middleware instance
+    panic_counts: Vec<(Arc<dyn Middleware>, u32)>,
+    verdicts: Vec<(Arc<dyn Middleware>, VerdictStats)>,
+}
+
+impl MiddlewareControls {
+    fn is_disabled(&self, middleware: &Arc<dyn Middleware>) -> bool {
+        self.disabled.iter().any(|disabled| Arc::ptr_eq(disabled, middleware))
+    }
+
+    fn disable(&mut self, middleware: Arc<dyn Middleware>) {
+        if !self.is_disabled(&middleware) {
+            self.disabled.push(middleware);
+        }
+    }
+
+    fn panic_count(&self, middleware: &Arc<dyn Middleware>) -> u32 {
+        self.panic_counts.iter().find(|(m, _)| Arc::ptr_eq(m, middleware)).map_or(0, |(_, c)| *c)
+    }
+
+    fn verdict_stats(&self, middleware: &Arc<dyn Middleware>) -> Option<&VerdictStats> {
+        self.verdicts.iter().find(|(m, _)| Arc::ptr_eq(m, middleware)).map(|(_, stats)| stats)
+    }
+
+    fn record_verdict(
+        &mut self,
+        middleware: &Arc<dyn Middleware>,
+        verdict: &Verdict,
+        event_kind: &'static str,
+    ) {
+        let stats = match self.verdicts.iter().position(|(m, _)| Arc::ptr_eq(m, middleware)) {
+            Some(index) => &mut self.verdicts[index].1,
+            None => {
+                self.verdicts.push((middleware.clone(), VerdictStats::default()));
+                &mut self.verdicts.last_mut().unwrap().1
+            }
+        };
+        match verdict {
+            Verdict::Continue | Verdict::Rewrite(_) => stats.continued += 1,
+            Verdict::Stop => {
+                stats.stopped += 1;
+                stats.last_stop = Some((Instant::now(), event_kind));
+            }
+        }
+    }
+
+    fn record_panic(&mut self, middleware: &Arc<dyn Middleware>) -> u32 {
+        match self.panic_counts.iter_mut().find(|(m, _)| Arc::ptr_eq(m, middleware)) {
+            Some((_, count)) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                self.panic_counts.push((middleware.clone(), 1));
+                1
+            }
+        }
+    }
+}
+
+/// Every pipeline, and the middleware instances in them by config name. Published as a whole,
+/// so a reload never leaves one pipeline with the old instance and another with the new.
+#[derive(Default)]
+struct Pipelines {
+    service: HashMap<ServiceId, Vec<Arc<dyn Middleware>>>,
+    rooms: HashMap<ServiceId, HashMap<String, Vec<Arc<dyn Middleware>>>>,
+    names: HashMap<String, Arc<dyn Middleware>>,
+}
+
+/// Everything a per-service pipeline task needs to run events through its middlewares.
+struct PipelineContext {
+    pipelines: watch::Receiver<Arc<Pipelines>>,
+    command_dispatch: CommandDispatch,
+    latency_budget: Duration,
+    lag_warning: Duration,
+    panic_limit: Option<u32>,
+    metrics: Option<MetricsRegistry>,
+    alerts: Option<broadcast::Sender<BusAlert>>,
+    controls: Arc<Mutex<MiddlewareControls>>,
+}
+
+impl PipelineContext {
+    fn lock_controls(&self) -> MutexGuard<'_, MiddlewareControls> {
+        self.controls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn middleware_name(&self, middleware: &Arc<dyn Middleware>) -> String {
+        self.pipelines
+            .borrow()
+            .names
+            .iter()
+            .find(|(_, registered)| Arc::ptr_eq(registered, middleware))
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| "<unnamed>".to_string())
+    }
+
+    fn run_pipeline(
+        &self,
+        pipeline: &[Arc<dyn Middleware>],
+        evt: &Arc<Event>,
+    ) -> anyhow::Result<()> {
+        // Replaced when a middleware rewrites the event, for the middlewares after it
+        let mut evt = evt.clone();
+        let mut claimed_commands: Vec<&str> = Vec::new();
+        for mw in pipeline {
+            if self.lock_controls().is_disabled(mw) {
+                continue;
+            }
+            if self.command_dispatch == CommandDispatch::FirstMatch
+                && evt.kind.message_body().is_some()
+            {
+                let invoked: Vec<&str> = mw
+                    .command_strings()
+                    .into_iter()
+                    .filter(|command| command_text(&evt, command).is_some())
+                    .collect();
+                if invoked.iter().any(|command| claimed_commands.contains(command)) {
+                    continue;
+                }
+                claimed_commands.extend(invoked);
+            }
+            // A panicking middleware is skipped for this event rather than unwinding through
+            // the bus and taking every service down with it
+            let started = Instant::now();
+            let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| mw.on_event(&evt)));
+            self.record_latency(mw, &evt.service_id, started.elapsed());
+            let verdict = match outcome {
+                Ok(Ok(verdict)) => verdict,
+                Ok(Err(e)) => {
+                    tracing::error!(
+                        middleware=%self.middleware_name(mw),
+                        service_id=%evt.service_id,
+                        event_kind=evt.kind.name(),
+                        error=%e,
+                        "middleware failed while handling event"
+                    );
+                    return Err(e);
+                }
+                Err(payload) => {
+                    self.record_panic(mw, &evt, panic_message(payload.as_ref()));
+                    continue;
+                }
+            };
+            self.lock_controls().record_verdict(mw, &verdict, evt.kind.name());
+            match verdict {
+                Verdict::Continue => {}
+                Verdict::Stop => break,
+                Verdict::Rewrite(rewritten) => {
+                    tracing::debug!(
+                        middleware=%self.middleware_name(mw),
+                        service_id=%evt.service_id,
+                        event_kind=rewritten.kind.name(),
+                        "middleware rewrote event"
+                    );
+                    evt = rewritten;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record_latency(
+        &self,
+        middleware: &Arc<dyn Middleware>,
+        service_id: &ServiceId,
+        elapsed: Duration,
+    ) {
+        let over_budget = elapsed > self.latency_budget;
+        if over_budget {
+            tracing::warn!(
+                middleware=%self.middleware_name(middleware),
+                service_id=%service_id,
+                elapsed_ms=elapsed.as_millis() as u64,
+                budget_ms=self.latency_budget.as_millis() as u64,
+                "slow middleware: on_event exceeded latency budget"
+            );
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.for_middleware(&self.middleware_name(middleware)).record(elapsed, over_budget);
+        }
+    }
+
+    /// Records how old an event is at `stage`, and once it has been processed, warns if it
+    /// took longer than the lag warning threshold to get through.
+    fn record_lag(&self, evt: &Event, stage: LagStage, lag: Duration) {
+        if stage == LagStage::Processed && lag > self.lag_warning {
+            tracing::warn!(
+                service_id=%evt.service_id,
+                event_kind=evt.kind.name(),
+                lag_ms=lag.as_millis() as u64,
+                threshold_ms=self.lag_warning.as_millis() as u64,
+                "event lag: event took longer than the threshold to get through its pipeline"
+            );
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.for_lag_stage(stage).record(lag);
+        }
+    }
+
+    fn record_panic(&self, middleware: &Arc<dyn Middleware>, evt: &Event, message: String) {
+        let (panic_count, disabled) = {
+            let mut controls = self.lock_controls();
+            let panic_count = controls.record_panic(middleware);
+            let disabled = self.panic_limit.is_some_and(|limit| panic_count >= limit);
+            if disabled {
+                controls.disable(middleware.clone());
+            }
+            (panic_count, disabled)
+        };
+        let name = self.middleware_name(middleware);
+        tracing::error!(
+            middleware=%name,
+            service_id=%evt.service_id,
+            event_kind=evt.kind.name(),
+            panic=%message,
+            panic_count,
+            disabled,
+            "middleware panicked while handling event"
+        );
+        let (level, consequence) = if disabled {
+            (AlertLevel::Critical, "it's been disabled until it's re-enabled")
+        } else {
+            (AlertLevel::Warning, "it was skipped for that event")
+        };
+        alerts::notify(
+            level,
+            format!(
+                "middleware '{name}' panicked handling a {} event on '{}': {message}; \
+                 {consequence}",
+                evt.kind.name(),
+                evt.service_id
+            ),
+        );
+
+        if let Some(alerts) = &self.alerts {
+            let _ = alerts.send(BusAlert::MiddlewarePanicked {
+                middleware: name,
+                service_id: evt.service_id.clone(),
+                event_kind: evt.kind.name(),
+                message,
+                panic_count,
+                disabled,
+            });
+        }
+    }
+}
+
+/// An event waiting in a service's pipeline queue.
+struct PipelineEvent {
+    evt: Arc<Event>,
+    emitted_at: Instant,
+    correlation_id: CorrelationId,
+}
+
+/// Runs one service's events through its pipelines, in the order they arrive.
+async fn run_service_pipeline(
+    ctx: Arc<PipelineContext>,
+    service_id: ServiceId,
+    mut events: Receiver<PipelineEvent>,
+) -> anyhow::Result<()> {
+    while let Some(PipelineEvent { evt, emitted_at, correlation_id }) = events.recv().await {
+        // Everything the middlewares log, spawn or send for this event carries its ID. The span
+        // is at error level so it's kept whatever the log level, and tags warnings too
+        let span =
+            tracing::error_span!("event", correlation_id=%correlation_id, service_id=%service_id);
+        let _entered = span.enter();
+        ctx.record_lag(&evt, LagStage::Queued, emitted_at.elapsed());
+        // Looked up per event, so a reloaded middleware takes over from the next one
+        let pipelines = ctx.pipelines.borrow().clone();
+        // Use the pipeline for this room, falling back to the service's
+        let room_pipeline =
+            evt.kind.room_id().and_then(|room_id| pipelines.rooms.get(&service_id)?.get(room_id));
+        match room_pipeline.or(pipelines.service.get(&service_id)) {
+            Some(pipeline) => correlation_id.scope(|| ctx.run_pipeline(pipeline, &evt))?,
+            None => {
+                tracing::debug!(service_id=%service_id, "no middleware pipeline configured for room")
+            }
+        }
+        ctx.record_lag(&evt, LagStage::Processed, emitted_at.elapsed());
+    }
+    Ok(())
+}
+
+pub struct Bus {
+    // Receive events from services
+    evt_rx: EventRx,
+
+    // Receive commands from middlewares
+    cmd_rx: Receiver<Command>,
+
+    services: HashMap<ServiceId, Arc<dyn Service>>,
+
+    // Per-service middleware pipelines
+    service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>>,
+
+    // Per-room pipelines that replace the service pipeline for events from those rooms
+    room_middlewares: HashMap<ServiceId, HashMap<String, Vec<Arc<dyn Middleware>>>>,
+
+    // Rooms whose events are dropped before reaching any pipeline
+    room_filters: HashMap<ServiceId, RoomFilter>,
+
+    // Per-service state tracking for reconnection
+    service_state: HashMap<ServiceId, ServiceState>,
+
+    // Windows outside which services are kept disconnected
+    connection_schedules: HashMap<ServiceId, ConnectionSchedule>,
+
+    // Service tasks report here each time their service becomes ready
+    ready_tx: UnboundedSender<ServiceId>,
+    ready_rx: UnboundedReceiver<ServiceId>,
+
+    // Expired messages are deleted through here, so the deletions are dispatched like any command
+    expiry_tx: UnboundedSender<(ServiceId, Command)>,
+    expiry_rx: UnboundedReceiver<(ServiceId, Command)>,
+
+    // Optional broadcast tap for observers outside of the middleware pipelines
+    event_tap: Option<broadcast::Sender<Arc<Event>>>,
+
+    // Rooms, members and display names seen so far, shared with middlewares
+    roster: Roster,
+
+    // Each service's latest user list, to turn user list snapshots into diffs and back
+    user_lists: UserLists,
+
+    // Middleware instances by config name, used to resolve runtime enable/disable requests
+    middleware_names: HashMap<String, Arc<dyn Middleware>>,
+
+    // What the pipeline tasks run events through, republished when a middleware is reloaded
+    pipelines_tx: watch::Sender<Arc<Pipelines>>,
+
+    // Rebuilds a middleware from a freshly loaded config for `BusControl::ReloadMiddleware`
+    reloader: Option<MiddlewareReloader>,
+
+    // Builds services from a freshly loaded config for `BusControl::AddService`
+    service_loader: Option<ServiceLoader>,
+    // Services being built for `BusControl::AddService`, so one isn't built twice at once
+    adding_services: HashSet<ServiceId>,
+    // Backoff for services added at runtime
+    reconnect_config: ReconnectionConfig,
+
+    // Each running middleware's token, so a reloaded one can be stopped
+    middleware_cancel: CancellationToken,
+    middleware_runs: Vec<(Arc<dyn Middleware>, CancellationToken)>,
+
+    // Runtime controls toggled through `Command::Control`
+    paused_services: HashSet<ServiceId>,
+    injected_faults: HashMap<ServiceId, InjectedFaults>,
+    // Cancelled to make a running service's task fail, for `Fault::Crash`
+    crash_switches: HashMap<ServiceId, CancellationToken>,
+    middleware_controls: Arc<Mutex<MiddlewareControls>>,
+
+    // Middlewares (by config name) that start out disabled
+    initially_disabled: Vec<String>,
+
+    // Middlewares (by config name) whose commands are logged rather than dispatched
+    dry_run_middlewares: Vec<String>,
+
+    // Durable queue for fire-and-forget commands that hit a temporarily unavailable service
+    outbox: Option<Outbox>,
+    outbox_flush_interval: Duration,
+
+    // Record of every dispatched command and its outcome
+    audit: Option<AuditLog>,
+
+    // Leadership shared with redundant instances. On standby, services stay connected but
+    // events don't reach the pipelines and commands aren't dispatched
+    lease: Option<Arc<Lease>>,
+    leader: bool,
+
+    // Command types each middleware may send
+    command_policy: CommandPolicy,
+
+    // Services the bot only listens on: commands that would change anything there are dropped
+    read_only_services: HashSet<ServiceId>,
+
+    // How long each service may take to become ready before that attempt counts as failed
+    startup_timeouts: HashMap<ServiceId, Duration>,
+
+    // How to route a command registered by more than one middleware in a pipeline
+    command_dispatch: CommandDispatch,
+
+    // Messages posted once every service is ready and before services are shut down
+    lifecycle_announcements: LifecycleAnnouncementsConfig,
+
+    // How many panics a middleware instance is allowed before it's disabled
+    panic_limit: Option<u32>,
+
+    // Optional broadcast channel for operator alerts
+    alerts: Option<broadcast::Sender<BusAlert>>,
+
+    // What the loop is doing, for the watchdog to report if it stops making progress
+    heartbeat: Heartbeat,
+    watchdog: Option<WatchdogConfig>,
+
+    // Time a middleware's on_event may take before it's reported as slow
+    latency_budget: Duration,
+    // Time an event may take from its service to the end of its pipeline before it's reported
+    lag_warning: Duration,
+    metrics: Option<MetricsRegistry>,
+}
+
+/// A service built for `BusControl::AddService`, with what's needed to answer the control.
+struct BuiltService {
+    service_id: ServiceId,
+    built: anyhow::Result<(Arc<dyn Service>, Config)>,
+    response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    audit_entry: AuditEntry,
+}
+
+// Events a service's pipeline task may have queued before further events are shed
+const PIPELINE_QUEUE_CAPACITY: usize = 1024;
+
+// How often the bus checks whether a scheduled service should connect or disconnect
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default time a middleware's `on_event` may take before the bus warns about it.
+pub const DEFAULT_LATENCY_BUDGET: Duration = Duration::from_millis(50);
+
+/// Default time an event may take from its service to the end of its pipeline before the bus
+/// warns about it.
+pub const DEFAULT_LAG_WARNING: Duration = Duration::from_secs(1);
+
+// How long a lifecycle announcement may take per destination before the bus moves on
+const ANNOUNCEMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Bus {
+    pub fn new(
+        evt_rx: EventRx,
+        cmd_rx: Receiver<Command>,
+        services: HashMap<ServiceId, Arc<dyn Service>>,
+        service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>>,
+        reconnect_config: ReconnectionConfig,
+    ) -> Self {
+        // Initialize state for each service
+        let service_state = services
+            .keys()
+            .map(|id| (id.clone(), ServiceState::new(reconnect_config.clone())))
+            .collect();
+        let (ready_tx, ready_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (expiry_tx, expiry_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        Self {
+            evt_rx,
+            cmd_rx,
+            services,
+            service_middlewares,
+            room_middlewares: HashMap::new(),
+            room_filters: HashMap::new(),
+            service_state,
+            connection_schedules: HashMap::new(),
+            ready_tx,
+            ready_rx,
+            expiry_tx,
+            expiry_rx,
+            event_tap: None,
+            roster: Roster::default(),
+            user_lists: UserLists::default(),
+            middleware_names: HashMap::new(),
+            pipelines_tx: watch::channel(Arc::default()).0,
+            reloader: None,
+            service_loader: None,
+            adding_services: HashSet::new(),
+            reconnect_config,
+            middleware_cancel: CancellationToken::new(),
+            middleware_runs: Vec::new(),
+            paused_services: HashSet::new(),
+            injected_faults: HashMap::new(),
+            crash_switches: HashMap::new(),
+            middleware_controls: Arc::default(),
+            initially_disabled: Vec::new(),
+            dry_run_middlewares: Vec::new(),
+            outbox: None,
+            outbox_flush_interval: Duration::from_secs(30),
+            audit: None,
+            lease: None,
+            leader: true,
+            command_policy: CommandPolicy::default(),
+            read_only_services: HashSet::new(),
+            startup_timeouts: HashMap::new(),
+            command_dispatch: CommandDispatch::default(),
+            lifecycle_announcements: LifecycleAnnouncementsConfig::default(),
+            panic_limit: None,
+            alerts: None,
+            heartbeat: Heartbeat::default(),
+            watchdog: None,
+            latency_budget: DEFAULT_LATENCY_BUDGET,
+            lag_warning: DEFAULT_LAG_WARNING,
+            metrics: None,
+        }
+    }
+
+    /// Posts the startup message once every service reports ready, and the shutdown message
+    /// during graceful shutdown before services are told to disconnect.
+    pub fn with_lifecycle_announcements(
+        mut self,
+        announcements: LifecycleAnnouncementsConfig,
+    ) -> Self {
+        self.lifecycle_announcements = announcements;
+        self
+    }
+
+    /// Disables a middleware once it has panicked `limit` times. Panics are always caught and
+    /// reported; without a limit the middleware keeps receiving events.
+    pub fn with_panic_limit(mut self, limit: Option<u32>) -> Self {
+        self.panic_limit = limit;
+        self
+    }
+
+    /// Warns whenever a middleware's `on_event` takes longer than `budget`. Pipelines run
+    /// synchronously, so a slow middleware delays every event behind it.
+    pub fn with_latency_budget(mut self, budget: Duration) -> Self {
+        self.latency_budget = budget;
+        self
+    }
+
+    /// Warns whenever an event takes longer than `threshold` from being emitted by its service
+    /// to getting through its pipeline, e.g. because the bus or the pipeline is backed up.
+    pub fn with_lag_warning(mut self, threshold: Duration) -> Self {
+        self.lag_warning = threshold;
+        self
+    }
+
+    /// Records each middleware's `on_event` latency into `metrics`, keyed by config name, and
+    /// how old events are as they enter and leave their pipelines.
+    pub fn with_metrics(mut self, metrics: MetricsRegistry) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Publishes operator alerts (e.g. a middleware panicking) to `alerts`.
+    pub fn with_alerts(mut self, alerts: broadcast::Sender<BusAlert>) -> Self {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    /// Watches the loop from a thread of its own and reports it once it goes
+    /// `config.stall_after` without progress (see `watchdog::start`), aborting the process
+    /// afterwards if `config.abort` is set.
+    pub fn with_watchdog(mut self, config: WatchdogConfig) -> Self {
+        self.watchdog = Some(config);
+        self
+    }
+
+    /// Persists fire-and-forget commands that fail with a transient error and redelivers them,
+    /// in order, once the service is reachable again.
+    ///
+    /// Delivery is retried every `flush_interval` and whenever the service emits an event.
+    pub fn with_outbox(mut self, outbox: Outbox, flush_interval: Duration) -> Self {
+        self.outbox = Some(outbox);
+        self.outbox_flush_interval = flush_interval;
+        self
+    }
+
+    /// Starts the named middlewares disabled, as if disabled through `BusControl` before the
+    /// first event.
+    pub fn with_disabled_middlewares(mut self, names: Vec<String>) -> Self {
+        self.initially_disabled = names;
+        self
+    }
+
+    /// Logs the commands the named middlewares send, and records them in the audit trail,
+    /// instead of dispatching them, so new rules can be trialled against live traffic. Their
+    /// responses fail; read-only commands (`Ping`, `GetRoomState`, `GetUserList`) are still
+    /// dispatched.
+    pub fn with_dry_run_middlewares(mut self, names: Vec<String>) -> Self {
+        self.dry_run_middlewares = names;
+        self
+    }
+
+    /// Refuses commands from middlewares that `policy` doesn't permit to send them.
+    pub fn with_command_policy(mut self, policy: CommandPolicy) -> Self {
+        self.command_policy = policy;
+        self
+    }
+
+    /// Drops every command sent to the listed services, counted by their `command_blocked`
+    /// metric, unless it only reads state (`Ping`, `GetRoomState`, `GetUserList`). Lifecycle
+    /// announcements skip them, and anything already in their outbox stays queued.
+    pub fn with_read_only_services(mut self, service_ids: HashSet<ServiceId>) -> Self {
+        self.read_only_services = service_ids;
+        self
+    }
+
+    /// Gives each listed service that long to become ready each time it starts. An attempt
+    /// that isn't ready by then is cancelled and restarted after the usual backoff, with an
+    /// operator alert, and no longer holds up the startup announcement.
+    pub fn with_startup_timeouts(mut self, timeouts: HashMap<ServiceId, Duration>) -> Self {
+        self.startup_timeouts = timeouts;
+        self
+    }
+
+    /// Records every command the bus dispatches, including bus controls, in `audit`.
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Only keeps services with a schedule connected while it's open: they're started when it
+    /// opens and stopped when it closes, with a `ConnectionScheduled` event sent through the
+    /// service's pipeline each time. Commands sent to a service while it's disconnected fail
+    /// as they would during an outage.
+    pub fn with_connection_schedules(
+        mut self,
+        connection_schedules: HashMap<ServiceId, ConnectionSchedule>,
+    ) -> Self {
+        self.connection_schedules = connection_schedules;
+        self
+    }
+
+    /// Runs as one of several redundant instances: only the one holding `lease` handles events
+    /// and sends commands, while the others stay connected on standby, ready to take over.
+    pub fn with_lease(mut self, lease: Lease) -> Self {
+        self.lease = Some(Arc::new(lease));
+        self.leader = false;
+        self
+    }
+
+    /// Pipelines for specific rooms, keyed by service then room ID. Events from a listed room
+    /// run through its pipeline instead of the service's; other rooms are unaffected.
+    pub fn with_room_pipelines(
+        mut self,
+        room_middlewares: HashMap<ServiceId, HashMap<String, Vec<Arc<dyn Middleware>>>>,
+    ) -> Self {
+        self.room_middlewares = room_middlewares;
+        self
+    }
+
+    /// Drops events from rooms a service's filter excludes before they reach the event tap or
+    /// any middleware, so busy rooms the bot only lurks in cost nothing downstream. Such
+    /// events still count as a sign of life for outbox delivery.
+    pub fn with_room_filters(mut self, room_filters: HashMap<ServiceId, RoomFilter>) -> Self {
+        self.room_filters = room_filters;
+        self
+    }
+
+    /// With `CommandDispatch::FirstMatch`, a message invoking a command reaches only the first
+    /// middleware in the pipeline that registers it; later ones claiming the same command are
+    /// skipped for that event.
+    pub fn with_command_dispatch(mut self, command_dispatch: CommandDispatch) -> Self {
+        self.command_dispatch = command_dispatch;
+        self
+    }
+
+    /// Registers middleware instances by config name so they can be disabled at runtime.
+    pub fn with_middleware_names(mut self, names: HashMap<String, Arc<dyn Middleware>>) -> Self {
+        self.middleware_names = names;
+        self
+    }
+
+    /// Lets `BusControl::ReloadMiddleware` rebuild middlewares with `reloader`.
+    pub fn with_middleware_reloader(mut self, reloader: MiddlewareReloader) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Lets `BusControl::AddService` build services with `loader`. Middlewares a new service's
+    /// pipelines name must be running already, apart from `shared = false` ones, which are
+    /// built for it if the bus also has a middleware reloader.
+    pub fn with_service_loader(mut self, loader: ServiceLoader) -> Self {
+        self.service_loader = Some(loader);
+        self
+    }
+
+    /// Names of all instances registered under `name`, including per-service instances of a
+    /// non-shared middleware (registered as `name@service`), sorted.
+    fn middleware_keys(&self, name: &str) -> Vec<String> {
+        let per_service_prefix = format!("{name}@");
+        let mut keys: Vec<String> = self
+            .middleware_names
+            .keys()
+            .filter(|key| key.as_str() == name || key.starts_with(&per_service_prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// All instances registered under `name`, as for `middleware_keys`.
+    fn middleware_instances(&self, name: &str) -> Vec<Arc<dyn Middleware>> {
+        self.middleware_keys(name).iter().map(|key| self.middleware_names[key].clone()).collect()
+    }
+
+    /// Rebuilds the instances of middleware `name` from a freshly loaded config and swaps them
+    /// into every pipeline at once. The new instances take over the old ones' runtime state
+    /// (disabled or not); the old ones are stopped.
+    fn reload_middleware(&mut self, name: &str) -> anyhow::Result<String> {
+        let Some(reloader) = &self.reloader else {
+            anyhow::bail!("reloading middlewares isn't available");
+        };
+        let keys = self.middleware_keys(name);
+        if keys.is_empty() {
+            anyhow::bail!("unknown middleware '{name}'");
+        }
+        let mut reloaded = reloader.reload(name).inspect_err(|e| {
+            alerts::notify(
+                AlertLevel::Warning,
+                format!("reloading middleware '{name}' failed, so it keeps its old config: {e:#}"),
+            );
+        })?;
+        let mut reloaded_keys: Vec<&String> = reloaded.keys().collect();
+        reloaded_keys.sort();
+        if reloaded_keys != keys.iter().collect::<Vec<_>>() {
+            anyhow::bail!(
+                "middleware '{name}' now runs in different pipelines, so needs a restart to change"
+            );
+        }
+        for key in keys {
+            let new = reloaded.remove(&key).expect("reloaded keys were checked");
+            let old = self.middleware_names.insert(key, new.clone()).expect("keys are registered");
+            self.replace_middleware(&old, new);
+        }
+        self.publish_pipelines();
+        info!(middleware=%name, "middleware reloaded");
+        Ok(format!("middleware '{name}' reloaded"))
+    }
+
+    /// Puts `new` wherever `old` is: in the pipelines, runtime controls and running middlewares.
+    fn replace_middleware(&mut self, old: &Arc<dyn Middleware>, new: Arc<dyn Middleware>) {
+        let pipelines = self
+            .service_middlewares
+            .values_mut()
+            .chain(self.room_middlewares.values_mut().flat_map(HashMap::values_mut));
+        for middleware in pipelines.flatten() {
+            if Arc::ptr_eq(middleware, old) {
+                *middleware = new.clone();
+            }
+        }
+
+        {
+            let mut controls = self.lock_controls();
+            for disabled in &mut controls.disabled {
+                if Arc::ptr_eq(disabled, old) {
+                    *disabled = new.clone();
+                }
+            }
+            controls.panic_counts.retain(|(middleware, _)| !Arc::ptr_eq(middleware, old));
+            controls.verdicts.retain(|(middleware, _)| !Arc::ptr_eq(middleware, old));
+        }
+
+        if let Some(index) = self.middleware_runs.iter().position(|(m, _)| Arc::ptr_eq(m, old)) {
+            let (_, token) = self.middleware_runs.remove(index);
+            token.cancel();
+            self.start_middleware(new);
+        }
+    }
+
+    /// Runs `middleware` until the bus stops or the middleware is reloaded.
+    fn start_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        let token = self.middleware_cancel.child_token();
+        self.middleware_runs.push((middleware.clone(), token.clone()));
+        tokio::spawn(async move { middleware.run(token).await });
+    }
+
+    /// Hands the pipeline tasks the current pipelines.
+    fn publish_pipelines(&self) {
+        self.pipelines_tx.send_replace(Arc::new(Pipelines {
+            service: self.service_middlewares.clone(),
+            rooms: self.room_middlewares.clone(),
+            names: self.middleware_names.clone(),
+        }));
+    }
+
+    /// Commands of every middleware in `service_id`'s pipelines, room pipelines included, for
+    /// registering with the service. A command in several pipelines is listed once.
+    fn service_commands(&self, service_id: &ServiceId) -> Vec<CommandSpec> {
+        let pipelines = self
+            .service_middlewares
+            .get(service_id)
+            .into_iter()
+            .chain(self.room_middlewares.get(service_id).into_iter().flat_map(HashMap::values));
+        let mut commands: Vec<CommandSpec> = Vec::new();
+        for middleware in pipelines.flatten() {
+            for command in middleware.commands() {
+                if !commands.iter().any(|c| c.prefix == command.prefix) {
+                    commands.push(command);
+                }
+            }
+        }
+        commands
+    }
+
+    /// Registers the commands of `service_id`'s pipelines with the service once it's ready,
+    /// unless `cancel` is cancelled first.
+    fn register_commands_when_ready(&self, service_id: &ServiceId, cancel: &CancellationToken) {
+        let commands = self.service_commands(service_id);
+        let Some(service) = self.services.get(service_id).cloned() else { return };
+        if commands.is_empty() {
+            return;
+        }
+        let service_id = service_id.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = service.ready() => {
+                    if let Err(e) = service.register_commands(commands).await {
+                        tracing::warn!(service_id=%service_id, error=%e, "failed to register commands");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Whether the middleware instance that sent a command (by its registered name) is
+    /// disabled, so the command should be dropped rather than delivered.
+    fn is_origin_disabled(&self, origin: &str) -> bool {
+        self.middleware_names
+            .get(origin)
+            .is_some_and(|middleware| self.lock_controls().is_disabled(middleware))
+    }
+
+    /// Whether the middleware instance that sent a command (by its registered name) is in
+    /// dry-run mode, including per-service instances of a non-shared middleware.
+    fn is_origin_dry_run(&self, origin: &str) -> bool {
+        self.dry_run_middlewares.iter().any(|name| {
+            origin
+                .strip_prefix(name.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('@'))
+        })
+    }
+
+    fn lock_controls(&self) -> MutexGuard<'_, MiddlewareControls> {
+        self.middleware_controls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn apply_control(&mut self, control: BusControl) {
+        // Reading the audit trail or a pipeline isn't itself worth recording
+        let audited = !matches!(
+            control,
+            BusControl::RecentAudit { .. } | BusControl::DescribePipeline { .. }
+        );
+        let mut entry = AuditEntry::for_control(&control, String::new());
+        let (result, response_tx) = match control {
+            BusControl::PauseService { service_id, response_tx } => {
+                let result = if self.services.contains_key(&service_id) {
+                    self.paused_services.insert(service_id.clone());
+                    info!(service_id=%service_id, "service paused");
+                    Ok(format!("service '{service_id}' paused"))
+                } else {
+                    Err(anyhow::anyhow!("unknown service '{service_id}'"))
+                };
+                (result, response_tx)
+            }
+            BusControl::ResumeService { service_id, response_tx } => {
+                let result = if self.paused_services.remove(&service_id) {
+                    info!(service_id=%service_id, "service resumed");
+                    Ok(format!("service '{service_id}' resumed"))
+                } else {
+                    Err(anyhow::anyhow!("service '{service_id}' is not paused"))
+                };
+                (result, response_tx)
+            }
+            BusControl::DisableMiddleware { name, response_tx } => {
+                let instances = self.middleware_instances(&name);
+                let result = if instances.is_empty() {
+                    Err(anyhow::anyhow!("unknown middleware '{name}'"))
+                } else {
+                    let mut controls = self.lock_controls();
+                    for middleware in instances {
+                        controls.disable(middleware);
+                    }
+                    info!(middleware=%name, "middleware disabled");
+                    Ok(format!("middleware '{name}' disabled"))
+                };
+                (result, response_tx)
+            }
+            BusControl::EnableMiddleware { name, response_tx } => {
+                let instances = self.middleware_instances(&name);
+                let result = if instances.is_empty() {
+                    Err(anyhow::anyhow!("unknown middleware '{name}'"))
+                } else {
+                    let mut controls = self.lock_controls();
+                    controls.disabled.retain(|disabled| {
+                        !instances.iter().any(|instance| Arc::ptr_eq(disabled, instance))
+                    });
+                    controls.panic_counts.retain(|(panicked, _)| {
+                        !instances.iter().any(|instance| Arc::ptr_eq(panicked, instance))
+                    });
+                    info!(middleware=%name, "middleware enabled");
+                    Ok(format!("middleware '{name}' enabled"))
+                };
+                (result, response_tx)
+            }
+            BusControl::ReloadMiddleware { name, response_tx } => {
+                (self.reload_middleware(&name), response_tx)
+            }
+            BusControl::RecentAudit { limit, response_tx } => {
+                let result = match &self.audit {
+                    Some(log) => log.recent(limit).map(|entries| audit::format_entries(&entries)),
+                    None => Err(anyhow::anyhow!("the audit trail is disabled")),
+                };
+                (result, response_tx)
+            }
+            BusControl::DescribePipeline { service_id, room_id, response_tx } => {
+                (self.describe_pipeline(&service_id, room_id.as_deref()), response_tx)
+            }
+            BusControl::InjectFault { service_id, fault, response_tx } => {
+                (self.inject_fault(&service_id, fault), response_tx)
+            }
+            BusControl::AddService { .. } => {
+                unreachable!("services are added by the run loop, which builds them first")
+            }
+        };
+        self.finish_control(audited.then_some(entry), result, response_tx);
+    }
+
+    /// Records the outcome of a control command in the audit trail, unless `entry` is `None`,
+    /// and reports it to whoever sent the command.
+    fn finish_control(
+        &self,
+        entry: Option<AuditEntry>,
+        result: anyhow::Result<String>,
+        response_tx: Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>,
+    ) {
+        if let Some(mut entry) = entry {
+            entry.outcome = match &result {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("failed: {e}"),
+            };
+            self.audit(&entry);
+        }
+
+        if let Err(e) = &result {
+            tracing::warn!(error=%e, "bus control command failed");
+        }
+        if let Some(tx) = response_tx {
+            let _ = tx.send(result);
+        }
+    }
+
+    /// Builds the service a `BusControl::AddService` names on a task of `building`, so that
+    /// connecting it (e.g. a Matrix login) doesn't hold up the bus. `add_service` takes over
+    /// once it's built.
+    fn start_building_service(
+        &mut self,
+        building: &mut JoinSet<BuiltService>,
+        control: BusControl,
+    ) {
+        let audit_entry = AuditEntry::for_control(&control, String::new());
+        let BusControl::AddService { service_id, response_tx } = control else {
+            unreachable!("only `BusControl::AddService` builds a service")
+        };
+        let Some(loader) = self.service_loader.clone() else {
+            let result = Err(anyhow::anyhow!("adding services isn't available"));
+            self.finish_control(Some(audit_entry), result, response_tx);
+            return;
+        };
+        if self.services.contains_key(&service_id) {
+            let result = Err(anyhow::anyhow!("service '{service_id}' is already running"));
+            self.finish_control(Some(audit_entry), result, response_tx);
+            return;
+        }
+        if !self.adding_services.insert(service_id.clone()) {
+            let result = Err(anyhow::anyhow!("service '{service_id}' is already being added"));
+            self.finish_control(Some(audit_entry), result, response_tx);
+            return;
+        }
+
+        info!(service_id=%service_id, "building service");
+        building.spawn(async move {
+            let built = loader.load(&service_id).await;
+            BuiltService { service_id, built, response_tx, audit_entry }
+        });
+    }
+
+    /// Registers `service`, built from `config` for `BusControl::AddService`, with its pipelines,
+    /// room filter, schedule and the `shared = false` middleware instances it needs, and starts
+    /// those instances. Nothing changes if any of that fails. Returns whether the service should
+    /// connect now, which it shouldn't while outside its connection schedule.
+    ///
+    /// Middlewares' `ServiceDirectory` is taken at startup, so it doesn't know the new service's
+    /// capabilities and leaves the final say on commands to the service.
+    fn add_service(
+        &mut self,
+        service_id: &ServiceId,
+        service: Arc<dyn Service>,
+        config: &Config,
+    ) -> anyhow::Result<bool> {
+        if self.services.contains_key(service_id) {
+            anyhow::bail!("service '{service_id}' is already running");
+        }
+        let instances = match &self.reloader {
+            Some(reloader) => reloader.instantiate_for_service(config, &service_id.0)?,
+            None => HashMap::new(),
+        };
+        let mut all_middlewares = self.middleware_names.clone();
+        all_middlewares.extend(instances.clone());
+        let pipelines =
+            middleware::build_pipelines_for_service(config, &service_id.0, &all_middlewares)?;
+        let schedule = connection_schedules_from_config(config)?.remove(service_id);
+
+        if let Some(filter) = room_filters_from_config(config).remove(service_id) {
+            self.room_filters.insert(service_id.clone(), filter);
+        }
+        if read_only_services_from_config(config).contains(service_id) {
+            self.read_only_services.insert(service_id.clone());
+        }
+        if let Some(timeout) = startup_timeouts_from_config(config).remove(service_id) {
+            self.startup_timeouts.insert(service_id.clone(), timeout);
+        }
+        let connect = schedule.as_ref().is_none_or(ConnectionSchedule::is_open_now);
+        if let Some(schedule) = schedule {
+            self.connection_schedules.insert(service_id.clone(), schedule);
+        }
+        if let Some(pipeline) = pipelines.service {
+            self.service_middlewares.insert(service_id.clone(), pipeline);
+        }
+        if !pipelines.rooms.is_empty() {
+            self.room_middlewares.insert(service_id.clone(), pipelines.rooms);
+        }
+        self.service_state
+            .insert(service_id.clone(), ServiceState::new(self.reconnect_config.clone()));
+        self.services.insert(service_id.clone(), service);
+
+        let suffix = format!("@{service_id}");
+        for (key, instance) in instances {
+            // A middleware switched off stays off on the new service too
+            let name = key.strip_suffix(&suffix).unwrap_or(&key);
+            let disabled = {
+                let controls = self.lock_controls();
+                self.middleware_instances(name).iter().any(|other| controls.is_disabled(other))
+            };
+            if disabled {
+                self.lock_controls().disable(instance.clone());
+            }
+            self.middleware_names.insert(key, instance.clone());
+            self.start_middleware(instance);
+        }
+        self.publish_pipelines();
+        info!(service_id=%service_id, "service added");
+        Ok(connect)
+    }
+
+    fn inject_fault(&mut self, service_id: &ServiceId, fault: Fault) -> anyhow::Result<String> {
+        if !self.services.contains_key(service_id) {
+            anyhow::bail!("unknown service '{service_id}'");
+        }
+        let message = match fault {
+            Fault::Crash => {
+                let Some(switch) = self.crash_switches.get(service_id) else {
+                    anyhow::bail!("service '{service_id}' isn't running");
+                };
+                switch.cancel();
+                format!("service '{service_id}' crashed; it's restarted after the usual backoff")
+            }
+            Fault::DelayCommands(delay) => {
+                self.injected_faults.entry(service_id.clone()).or_default().command_delay =
+                    Some(delay);
+                let delay = humantime::format_duration(delay);
+                format!("commands to service '{service_id}' are delayed by {delay}")
+            }
+            Fault::DropEvents(rate) => {
+                self.injected_faults.entry(service_id.clone()).or_default().event_drop_rate = rate;
+                let percent = rate * 100.0;
+                format!("{percent:.0}% of events from service '{service_id}' are dropped")
+            }
+            Fault::Clear => {
+                self.injected_faults.remove(service_id);
+                format!("injected faults on service '{service_id}' cleared")
+            }
+        };
+        tracing::warn!(service_id=%service_id, fault=?fault, "fault injected");
+        Ok(message)
+    }
+
+    /// Runs a service on `tasks` until `token` is cancelled or it's crashed with `Fault::Crash`.
+    fn spawn_service(
+        &mut self,
+        tasks: &mut JoinSet<(ServiceId, anyhow::Result<()>)>,
+        service_id: &ServiceId,
+        token: CancellationToken,
+    ) {
+        let Some(service) = self.services.get(service_id).cloned() else { return };
+        let crash_switch = CancellationToken::new();
+        self.crash_switches.insert(service_id.clone(), crash_switch.clone());
+        let ready_tx = self.ready_tx.clone();
+        let id = service_id.clone();
+        let startup_timeout = self.startup_timeouts.get(service_id).copied();
+        // Cancelled if the attempt times out, so tasks the service spawned stop with it
+        let attempt = token.child_token();
+        tasks.spawn(async move {
+            // Biased so `run` starts, and clears the service's readiness, before `ready` is
+            // first polled; a restarted service would otherwise look ready from its last run
+            let result = tokio::select! {
+                biased;
+                result = service.run(attempt.clone()) => result,
+                _ = crash_switch.cancelled() => Err(anyhow::anyhow!("simulated crash")),
+                timed_out = report_ready(service.as_ref(), &id, &ready_tx, startup_timeout) => {
+                    attempt.cancel();
+                    Err(timed_out)
+                }
+            };
+            (id, result)
+        });
+
+        // Track connection start time
+        if let Some(state) = self.service_state.get_mut(service_id) {
+            state.connection_start = Instant::now();
+        }
+    }
+
+    /// The middlewares events from `service_id` (in `room_id`, if given) run through, one per
+    /// line in order, with whatever would explain one of them not seeing an event: being
+    /// disabled, in dry-run mode, panicking, or a middleware ahead of it returning `Stop`.
+    fn describe_pipeline(
+        &self,
+        service_id: &ServiceId,
+        room_id: Option<&str>,
+    ) -> anyhow::Result<String> {
+        if !self.services.contains_key(service_id) {
+            anyhow::bail!("unknown service '{service_id}'");
+        }
+        let room_pipelines = self.room_middlewares.get(service_id);
+        let room_pipeline = room_id.and_then(|room_id| room_pipelines?.get(room_id));
+        let (heading, pipeline) = match (room_id, room_pipeline) {
+            (Some(room_id), Some(pipeline)) => {
+                (format!("Pipeline for {service_id} in {room_id}:"), Some(pipeline))
+            }
+            _ => (format!("Pipeline for {service_id}:"), self.service_middlewares.get(service_id)),
+        };
+
+        let mut lines = vec![heading];
+        let pipeline = pipeline.map(Vec::as_slice).unwrap_or_default();
+        if pipeline.is_empty() {
+            lines.push("(no middlewares)".to_string());
+        }
+        if self.paused_services.contains(service_id) {
+            lines.push("(service paused: its events are dropped)".to_string());
+        }
+        if let Some(room_id) = room_id
+            && self.room_filters.get(service_id).is_some_and(|filter| !filter.allows(room_id))
+        {
+            lines.push(format!("({room_id} is filtered out: its events never reach a pipeline)"));
+        }
+        let controls = self.lock_controls();
+        for (position, middleware) in pipeline.iter().enumerate() {
+            let name = self
+                .middleware_names
+                .iter()
+                .find(|(_, registered)| Arc::ptr_eq(registered, middleware))
+                .map_or("<unnamed>", |(name, _)| name.as_str());
+            let mut line = format!("{}. {name}", position + 1);
+            if controls.is_disabled(middleware) {
+                line.push_str(" [disabled]");
+            }
+            if self.is_origin_dry_run(name) {
+                line.push_str(" [dry run]");
+            }
+            match controls.panic_count(middleware) {
+                0 => {}
+                panics => line.push_str(&format!(" [panicked {panics}x]")),
+            }
+            let (continued, stopped, last_stop) = controls
+                .verdict_stats(middleware)
+                .map_or((0, 0, None), |stats| (stats.continued, stats.stopped, stats.last_stop));
+            line.push_str(&format!(": {continued} continued, {stopped} stopped"));
+            if let Some((at, event_kind)) = last_stop {
+                let ago = Duration::from_secs(at.elapsed().as_secs());
+                line.push_str(&format!(
+                    " (last stop {} ago on {event_kind})",
+                    humantime::format_duration(ago)
+                ));
+            }
+            lines.push(line);
+        }
+
+        if room_pipeline.is_none()
+            && let Some(room_pipelines) = room_pipelines.filter(|rooms| !rooms.is_empty())
+        {
+            let mut rooms: Vec<&str> = room_pipelines.keys().map(String::as_str).collect();
+            rooms.sort_unstable();
+            lines.push(format!("Rooms with their own pipeline: {}", rooms.join(", ")));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Sends a command to its service, diverting it to the outbox if the service can't take
+    /// it right now (or still has earlier commands queued).
+    async fn dispatch_command(&self, service_id: &ServiceId, cmd: Command) {
+        let started = Instant::now();
+        let mut entry = self
+            .audit
+            .as_ref()
+            .map(|_| AuditEntry::for_command(&cmd, String::new(), Duration::ZERO));
+        // The service's logs for the command carry the ID of the event that prompted it
+        let span = match cmd.correlation_id() {
+            Some(correlation_id) => tracing::error_span!("command", correlation_id=%correlation_id),
+            None => tracing::Span::none(),
+        };
+        let outcome = self.deliver_command(service_id, cmd).instrument(span).await;
+        if let Some(entry) = &mut entry {
+            entry.outcome = outcome;
+            entry.latency = started.elapsed();
+            self.audit(entry);
         }
     }
-}
 
-struct ServiceState {
-    backoff: ExponentialBackoff,
-    attempt_count: u32,
-    connection_start: Instant,
-}
+    /// Does the work of `dispatch_command`, returning the outcome for the audit trail.
+    async fn deliver_command(&self, service_id: &ServiceId, cmd: Command) -> String {
+        let origin = cmd.origin().unwrap_or("unknown").to_string();
+        let cmd = match self.screen_origin(&service_id.0, cmd) {
+            Ok(cmd) => cmd,
+            Err(outcome) => return outcome,
+        };
+        if !cmd.is_read_only() && !self.leader {
+            tracing::debug!(service_id=%service_id, origin=%origin, "standing by, not dispatching command");
+            cmd.reject(anyhow::anyhow!("this instance is on standby"));
+            return "standby".to_string();
+        }
+        if !cmd.is_read_only() && self.read_only_services.contains(service_id) {
+            tracing::warn!(service_id=%service_id, origin=%origin, command=%cmd.kind(), "dropping command for read-only service");
+            if let Some(metrics) = &self.metrics {
+                metrics.for_service(service_id).command_blocked();
+            }
+            cmd.reject(anyhow::anyhow!("service '{service_id}' is read-only"));
+            return "read_only".to_string();
+        }
 
-impl ServiceState {
-    fn new(reconnect_config: ReconnectionConfig) -> Self {
-        Self {
-            backoff: ExponentialBackoff::new(reconnect_config),
-            attempt_count: 0,
-            connection_start: Instant::now(),
+        let Some(service) = self.services.get(service_id) else {
+            tracing::warn!(service_id=%service_id, origin=%origin, "command sent to unknown service");
+            cmd.reject(anyhow::anyhow!("unknown service '{service_id}'"));
+            return "failed: unknown service".to_string();
+        };
+        let cmd = schedule_expiry(service, service_id, &self.expiry_tx, cmd);
+        let cmd = watch_permission_denied(self.alerts.clone(), service_id, cmd);
+
+        let queued = self.outbox.as_ref().and_then(|_| QueuedCommand::from_command(&cmd));
+        if let (Some(outbox), Some(queued)) = (&self.outbox, &queued) {
+            match outbox.has_pending(service_id) {
+                Ok(true) => {
+                    self.enqueue(outbox, service_id, queued);
+                    self.flush_outbox(service_id).await;
+                    return "queued".to_string();
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::error!(service_id=%service_id, error=%e, "failed to read outbox")
+                }
+            }
+        }
+
+        if let Some(delay) = self.injected_faults.get(service_id).and_then(|f| f.command_delay) {
+            tracing::debug!(service_id=%service_id, delay=?delay, "delaying command (injected fault)");
+            tokio::time::sleep(delay).await;
+        }
+        match service.handle_command(cmd).await {
+            Ok(()) => "ok".to_string(),
+            Err(e) if is_retryable(&e) && queued.is_some() && self.outbox.is_some() => {
+                tracing::warn!(service_id=%service_id, origin=%origin, error=%e, "service unavailable, queueing command");
+                if let (Some(outbox), Some(queued)) = (&self.outbox, &queued) {
+                    self.enqueue(outbox, service_id, queued);
+                }
+                format!("queued: {e}")
+            }
+            Err(e) => {
+                tracing::error!(service_id=%service_id, origin=%origin, error=%e, "failed to handle command");
+                alert_permission_denied(self.alerts.as_ref(), service_id, &origin, &e);
+                format!("failed: {e}")
+            }
         }
     }
-}
 
-pub struct Bus {
-    // Receive events from services
-    evt_rx: Receiver<Event>,
+    /// Expands an announcement into a message per target and dispatches each one, then reports
+    /// back from its own task once they've all been posted or given up on.
+    async fn dispatch_announcement(&self, cmd: Command) {
+        // Only announcements turned away are recorded; the messages of the rest are, one by one
+        let mut entry = self
+            .audit
+            .as_ref()
+            .map(|_| AuditEntry::for_command(&cmd, String::new(), Duration::ZERO));
+        let cmd = match self.screen_origin("bus", cmd) {
+            Ok(cmd) => cmd,
+            Err(outcome) => {
+                if let Some(entry) = &mut entry {
+                    entry.outcome = outcome;
+                    self.audit(entry);
+                }
+                return;
+            }
+        };
+        let Command::Announce { targets, body, priority, response_tx, origin } = cmd else {
+            return;
+        };
+        let body = format_announcement(priority, &body);
+        // Keys the messages, so an outbox redelivery racing a retry doesn't post twice
+        let key = format!(
+            "announce-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        );
 
-    // Receive commands from middlewares
-    cmd_rx: Receiver<Command>,
+        let mut sends = Vec::with_capacity(targets.len());
+        for (index, (service_id, target)) in targets.into_iter().enumerate() {
+            let idempotency_key = Some(format!("{key}-{index}"));
+            let message = match &target {
+                RoomTarget::Room(room_id) => Command::SendRoomMessage {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    body: body.clone(),
+                    format: BodyFormat::Plain,
+                    response_tx: None,
+                    origin: origin.clone(),
+                    idempotency_key,
+                    relayed_from: None,
+                    expires_after: None,
+                },
+                RoomTarget::DirectMessage(user_id) => Command::SendDirectMessage {
+                    service_id: service_id.clone(),
+                    user_id: user_id.clone(),
+                    body: body.clone(),
+                    response_tx: None,
+                    origin: origin.clone(),
+                    idempotency_key,
+                    expires_after: None,
+                    require_encryption: false,
+                },
+            };
+            let Some((first, response_rx)) = message.with_fresh_response() else { continue };
+            self.dispatch_command(&service_id, first).await;
+            let service = self.services.get(&service_id).cloned();
+            sends.push((service_id, target, service, message, response_rx));
+        }
 
-    services: HashMap<ServiceId, Arc<dyn Service>>,
+        correlation::spawn(async move {
+            let total = sends.len();
+            let mut failures = Vec::new();
+            for (service_id, target, service, message, response_rx) in sends {
+                let result =
+                    await_with_retry(service, &message, response_rx, &RetryPolicy::default()).await;
+                if let Err(e) = result {
+                    tracing::warn!(service_id=%service_id, target=%target, error=%e, "failed to post announcement");
+                    failures.push(format!("{service_id} {target}: {e}"));
+                }
+            }
+            let posted = total - failures.len();
+            let report = std::iter::once(format!("Posted to {posted} of {total} targets"))
+                .chain(failures)
+                .collect::<Vec<_>>()
+                .join("\n");
+            info!(posted, total, "announcement done");
+            if let Some(tx) = response_tx {
+                let _ = tx.send(if posted > 0 { Ok(report) } else { Err(anyhow::anyhow!(report)) });
+            }
+        });
+    }
 
-    // Per-service middleware pipelines
-    service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>>,
+    /// Rejects a command its origin may not send: one the command policy doesn't allow, one from
+    /// a disabled middleware, or one that changes something from a middleware in dry-run mode.
+    /// Returns the outcome to record for a rejected command.
+    fn screen_origin(&self, service_id: &str, cmd: Command) -> Result<Command, String> {
+        let origin = cmd.origin().unwrap_or("unknown").to_string();
+        if !self.command_policy.permits(&cmd) {
+            let kind = cmd.kind();
+            tracing::warn!(service_id=%service_id, origin=%origin, command=%kind, "command denied by policy");
+            cmd.reject(anyhow::anyhow!("middleware '{origin}' may not send {kind}"));
+            return Err("denied".to_string());
+        }
+        if self.is_origin_disabled(&origin) {
+            tracing::info!(service_id=%service_id, origin=%origin, "dropping command from disabled middleware");
+            cmd.reject(anyhow::anyhow!("middleware '{origin}' is disabled"));
+            return Err("disabled".to_string());
+        }
+        if !cmd.is_read_only() && self.is_origin_dry_run(&origin) {
+            tracing::info!(service_id=%service_id, origin=%origin, command=?cmd, "dry run, not dispatching command");
+            cmd.reject(anyhow::anyhow!("middleware '{origin}' is in dry-run mode"));
+            return Err("dry_run".to_string());
+        }
+        Ok(cmd)
+    }
 
-    // Per-service state tracking for reconnection
-    service_state: HashMap<ServiceId, ServiceState>,
-}
+    /// Takes or renews the leadership lease, switching between leading and standing by when it
+    /// changes hands.
+    ///
+    /// SQLite can wait out its busy timeout while another instance renews, so the lease is
+    /// queried on a blocking thread rather than holding up the runtime.
+    async fn renew_lease(&mut self) {
+        let Some(lease) = self.lease.clone() else { return };
+        let was_leader = self.leader;
+        let renewing = lease.clone();
+        let renewal = tokio::task::spawn_blocking(move || {
+            let now = chrono::Utc::now();
+            let leader = renewing.try_acquire(now).unwrap_or_else(|e| {
+                // Better nobody leading until the lease expires than two instances at once
+                tracing::error!(error=%e, "failed to renew leadership lease, standing by");
+                false
+            });
+            // Only looked up to log who took over
+            let holder =
+                if was_leader && !leader { renewing.holder(now).ok().flatten() } else { None };
+            (leader, holder)
+        });
+        let (leader, holder) = renewal.await.unwrap_or_else(|e| {
+            tracing::error!(error=%e, "leadership lease renewal panicked, standing by");
+            (false, None)
+        });
+        if leader == self.leader {
+            return;
+        }
+        if leader {
+            info!(instance_id=%lease.instance_id(), "leadership lease acquired, leading");
+        } else {
+            tracing::warn!(instance_id=%lease.instance_id(), holder=?holder, "leadership lease held elsewhere, standing by");
+        }
+        self.leader = leader;
+    }
 
-impl Bus {
-    pub fn new(
-        evt_rx: Receiver<Event>,
-        cmd_rx: Receiver<Command>,
-        services: HashMap<ServiceId, Arc<dyn Service>>,
-        service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>>,
-        reconnect_config: ReconnectionConfig,
-    ) -> Self {
-        // Initialize state for each service
-        let service_state = services
-            .keys()
-            .map(|id| (id.clone(), ServiceState::new(reconnect_config.clone())))
+    fn audit(&self, entry: &AuditEntry) {
+        if let Some(log) = &self.audit
+            && let Err(e) = log.record(entry)
+        {
+            tracing::error!(error=%e, "failed to record command in audit trail");
+        }
+    }
+
+    fn enqueue(&self, outbox: &Outbox, service_id: &ServiceId, queued: &QueuedCommand) {
+        match outbox.enqueue(service_id, queued) {
+            Ok(()) => tracing::info!(service_id=%service_id, "command queued in outbox"),
+            Err(e) => {
+                tracing::error!(service_id=%service_id, error=%e, "failed to queue command, dropping it")
+            }
+        }
+    }
+
+    /// Sends an event the bus raised about `service_id` itself, e.g. it becoming ready, to the
+    /// tap and through the service's pipeline as if the service had emitted it.
+    fn publish_service_event(
+        &self,
+        pipeline_queues: &HashMap<ServiceId, Sender<PipelineEvent>>,
+        service_id: &ServiceId,
+        kind: EventKind,
+    ) {
+        let evt = Arc::new(Event { service_id: service_id.clone(), kind });
+        if let Some(tap) = &self.event_tap {
+            let _ = tap.send(evt.clone());
+        }
+
+        let queued = PipelineEvent {
+            evt,
+            emitted_at: Instant::now(),
+            correlation_id: CorrelationId::next(),
+        };
+        if let Some(queue) = pipeline_queues.get(service_id)
+            && let Err(TrySendError::Full(queued)) = queue.try_send(queued)
+        {
+            tracing::warn!(service_id=%queued.evt.service_id, "pipeline queue full, dropping event");
+        }
+    }
+
+    /// Posts `message` to the lifecycle announcement destinations on `service`, or to all of them
+    /// if `None`. Sends go straight to the services rather than through the outbox, so a stale
+    /// announcement is never delivered late.
+    async fn announce(&self, message: &str, service: Option<&ServiceId>) {
+        if !self.leader {
+            info!("standing by, not posting lifecycle announcement");
+            return;
+        }
+        let mut destinations: Vec<_> = self
+            .lifecycle_announcements
+            .destinations
+            .iter()
+            .filter(|(_, dest)| service.is_none_or(|service| service.0 == dest.service_id))
             .collect();
+        destinations.sort_by_key(|(name, _)| *name);
+        for (_, dest) in destinations {
+            let service_id = ServiceId(dest.service_id.clone());
+            if self.read_only_services.contains(&service_id) {
+                tracing::warn!(service_id=%service_id, "not posting lifecycle announcement to read-only service");
+                continue;
+            }
+            let Some(service) = self.services.get(&service_id) else {
+                tracing::warn!(service_id=%service_id, "lifecycle announcement for unknown service");
+                continue;
+            };
+            let command = Command::SendRoomMessage {
+                service_id: service_id.clone(),
+                room_id: dest.room_id.clone(),
+                body: message.to_string(),
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            };
+            match tokio::time::timeout(ANNOUNCEMENT_TIMEOUT, service.handle_command(command)).await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tracing::warn!(service_id=%service_id, error=%e, "failed to post lifecycle announcement")
+                }
+                Err(_) => {
+                    tracing::warn!(service_id=%service_id, "timed out posting lifecycle announcement")
+                }
+            }
+        }
+    }
+
+    /// Redelivers queued commands for `service_id` in order, stopping at the first one the
+    /// service still can't take. Entries older than the outbox TTL are discarded.
+    async fn flush_outbox(&self, service_id: &ServiceId) {
+        let (Some(outbox), Some(service)) = (&self.outbox, self.services.get(service_id)) else {
+            return;
+        };
+        if !self.leader || self.read_only_services.contains(service_id) {
+            return;
+        }
+
+        loop {
+            let entry = match outbox.next(service_id) {
+                Ok((entry, expired)) => {
+                    if expired > 0 {
+                        tracing::warn!(service_id=%service_id, count=%expired, "discarded expired outbox entries");
+                    }
+                    match entry {
+                        Some(entry) => entry,
+                        None => return,
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(service_id=%service_id, error=%e, "failed to read outbox");
+                    return;
+                }
+            };
+
+            let cmd = entry.command.into_command(service_id.clone());
+            match service.handle_command(cmd).await {
+                Ok(()) => {
+                    tracing::info!(service_id=%service_id, enqueued_at=%entry.enqueued_at, "delivered queued command");
+                }
+                Err(e) if is_retryable(&e) => {
+                    tracing::debug!(service_id=%service_id, error=%e, "service still unavailable, keeping outbox");
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!(service_id=%service_id, error=%e, "dropping undeliverable queued command");
+                }
+            }
 
-        Self { evt_rx, cmd_rx, services, service_middlewares, service_state }
+            if let Err(e) = outbox.remove(entry.id) {
+                tracing::error!(service_id=%service_id, error=%e, "failed to remove outbox entry");
+                return;
+            }
+        }
+    }
+
+    async fn flush_all_outboxes(&self) {
+        let Some(outbox) = &self.outbox else { return };
+        if !self.leader {
+            return;
+        }
+        match outbox.pending_services() {
+            Ok(service_ids) => {
+                for service_id in service_ids {
+                    self.flush_outbox(&service_id).await;
+                }
+            }
+            Err(e) => tracing::error!(error=%e, "failed to read outbox"),
+        }
+    }
+
+    /// Publishes every inbound event to `tap` before it enters the service's pipeline.
+    ///
+    /// Subscribers (metrics, admin API, event log, ...) observe all traffic without being
+    /// registered as middlewares. Lagging subscribers miss events rather than slowing the bus.
+    pub fn with_event_tap(mut self, tap: broadcast::Sender<Arc<Event>>) -> Self {
+        self.event_tap = Some(tap);
+        self
+    }
+
+    /// Keeps `roster` up to date from every event that passes the room filters, before the
+    /// event reaches any middleware.
+    pub fn with_roster(mut self, roster: Roster) -> Self {
+        self.roster = roster;
+        self
     }
 
     pub async fn run(&mut self, cancel: CancellationToken) -> anyhow::Result<()> {
+        // Settle who leads before anything is connected, so a standby never acts on its own
+        self.renew_lease().await;
+        if let Some(lease) = &self.lease
+            && !self.leader
+        {
+            info!(instance_id=%lease.instance_id(), "another instance is leading, standing by");
+        }
+
         // Start all services with supervision
         info!("starting services with supervision...");
         let mut service_tasks: JoinSet<(ServiceId, anyhow::Result<()>)> = JoinSet::new();
 
-        for (service_id, service) in &self.services {
-            let child_token = cancel.child_token();
-            let service_clone = service.clone();
-            let id = service_id.clone();
+        // Services get their own token so the shutdown announcement can go out before they
+        // disconnect; the guard still stops them if the bus exits any other way
+        let service_cancel = CancellationToken::new();
+        let _service_guard = service_cancel.clone().drop_guard();
 
-            service_tasks.spawn(async move {
-                let result = service_clone.run(child_token).await;
-                (id, result)
-            });
+        // Tokens of the services started so far. A scheduled service's token is cancelled when
+        // its window closes and removed once it has exited, so it isn't restarted meanwhile
+        let mut service_tokens: HashMap<ServiceId, CancellationToken> = HashMap::new();
+        let service_ids: Vec<ServiceId> = self.services.keys().cloned().collect();
+        for service_id in service_ids {
+            if self.connection_schedules.get(&service_id).is_some_and(|s| !s.is_open_now()) {
+                info!(service_id=%service_id, "outside connection schedule, not connecting");
+                continue;
+            }
+            let child_token = service_cancel.child_token();
+            service_tokens.insert(service_id.clone(), child_token.clone());
+            self.spawn_service(&mut service_tasks, &service_id, child_token);
+        }
+
+        // Each service registers its commands natively once it first connects; registrations
+        // outlive reconnects, so restarts don't repeat it
+        for service_id in self.services.keys() {
+            self.register_commands_when_ready(service_id, &service_cancel);
+        }
 
-            // Track connection start time
-            if let Some(state) = self.service_state.get_mut(service_id) {
-                state.connection_start = Instant::now();
+        for name in &self.initially_disabled {
+            let instances = self.middleware_instances(name);
+            let mut controls = self.lock_controls();
+            for middleware in instances {
+                controls.disable(middleware);
             }
+            info!(middleware=%name, "middleware disabled in config");
         }
 
-        // Start all middlewares (collect unique instances across all services)
+        // Start all middlewares (collect unique instances across all service and room pipelines)
         info!("starting middlewares...");
-        let mut middleware_handles = Vec::new();
+        self.middleware_cancel = cancel.child_token();
         let mut started_middlewares: Vec<Arc<dyn Middleware>> = Vec::new();
 
-        for pipeline in self.service_middlewares.values() {
+        let pipelines = self
+            .service_middlewares
+            .values()
+            .chain(self.room_middlewares.values().flat_map(HashMap::values));
+        for pipeline in pipelines {
             for middleware in pipeline {
                 // Use Arc::ptr_eq to track unique instances
                 let already_started =
@@ -212,24 +2919,126 @@ impl Bus {
 
                 if !already_started {
                     started_middlewares.push(middleware.clone());
-                    let child_token = cancel.child_token();
-                    let middleware_clone = middleware.clone();
-                    middleware_handles
-                        .push(tokio::spawn(async move { middleware_clone.run(child_token).await }));
                 }
             }
         }
+        for middleware in started_middlewares {
+            self.start_middleware(middleware);
+        }
 
         // Begin command/event processing with service supervision
         info!("starting event bus...");
 
+        // Services are still connecting at startup, so hold off on the first flush
+        let mut outbox_flush = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.outbox_flush_interval,
+            self.outbox_flush_interval,
+        );
+        outbox_flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut schedule_check = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+        schedule_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let renew_interval =
+            self.lease.as_ref().map_or(SCHEDULE_CHECK_INTERVAL, |lease| lease.renew_interval());
+        let mut lease_renewal =
+            tokio::time::interval_at(tokio::time::Instant::now() + renew_interval, renew_interval);
+        lease_renewal.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        // The loop beats each time round, so an idle one still ticks over for the watchdog
+        let watchdog = self
+            .watchdog
+            .as_ref()
+            .map(|config| watchdog::start(self.heartbeat.clone(), config, self.alerts.clone()));
+        let beat_interval =
+            self.watchdog.as_ref().map_or(SCHEDULE_CHECK_INTERVAL, watchdog::beat_interval);
+        let mut heartbeat_tick = tokio::time::interval(beat_interval);
+        heartbeat_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        // Each service's events run through its pipelines on a task of its own, so a burst or a
+        // slow middleware on one service doesn't hold up the others
+        self.publish_pipelines();
+        let pipeline_ctx = Arc::new(PipelineContext {
+            pipelines: self.pipelines_tx.subscribe(),
+            command_dispatch: self.command_dispatch,
+            latency_budget: self.latency_budget,
+            lag_warning: self.lag_warning,
+            panic_limit: self.panic_limit,
+            metrics: self.metrics.clone(),
+            alerts: self.alerts.clone(),
+            controls: self.middleware_controls.clone(),
+        });
+        let mut pipeline_tasks: JoinSet<anyhow::Result<()>> = JoinSet::new();
+        let mut pipeline_queues: HashMap<ServiceId, Sender<PipelineEvent>> = HashMap::new();
+        let piped_services: HashSet<&ServiceId> =
+            self.service_middlewares.keys().chain(self.room_middlewares.keys()).collect();
+        for service_id in piped_services {
+            let (queue_tx, queue_rx) = tokio::sync::mpsc::channel(PIPELINE_QUEUE_CAPACITY);
+            pipeline_queues.insert(service_id.clone(), queue_tx);
+            pipeline_tasks.spawn(run_service_pipeline(
+                pipeline_ctx.clone(),
+                service_id.clone(),
+                queue_rx,
+            ));
+        }
+
+        // Services being built for `BusControl::AddService`
+        let mut building_services: JoinSet<BuiltService> = JoinSet::new();
+
+        // The startup announcement goes out on each service as soon as it's ready (or past its
+        // startup timeout), so one that never connects only misses out on its own rooms.
+        // Services outside their schedule at startup aren't announced on at all
+        let started = tokio::time::Instant::now();
+        let mut startup_ready: JoinSet<ServiceId> = JoinSet::new();
+        if self.lifecycle_announcements.startup_message.is_some() {
+            let service_ids: HashSet<ServiceId> = self
+                .lifecycle_announcements
+                .destinations
+                .values()
+                .map(|dest| ServiceId(dest.service_id.clone()))
+                .collect();
+            for service_id in service_ids {
+                let Some(service) = self.services.get(&service_id).cloned() else {
+                    // Announced on right away, which warns that it's unknown
+                    startup_ready.spawn(async move { service_id });
+                    continue;
+                };
+                if !service_tokens.contains_key(&service_id) {
+                    continue;
+                }
+                let startup_timeout = self.startup_timeouts.get(&service_id).copied();
+                startup_ready.spawn(async move {
+                    match startup_timeout {
+                        Some(timeout) => {
+                            let _ =
+                                tokio::time::timeout_at(started + timeout, service.ready()).await;
+                        }
+                        None => service.ready().await,
+                    }
+                    service_id
+                });
+            }
+        }
+
         loop {
+            self.heartbeat.idle();
             tokio::select! {
                 // Wait for any service task to complete
-                Some(Ok((completed_service_id, _result))) = service_tasks.join_next() => {
+                Some(Ok((completed_service_id, result))) = service_tasks.join_next() => {
+                    self.crash_switches.remove(&completed_service_id);
                     if cancel.is_cancelled() {
                         // Graceful shutdown - don't restart
                         tracing::info!(service_id=%completed_service_id, "service exited during shutdown");
+                    } else if service_tokens
+                        .get(&completed_service_id)
+                        .is_some_and(CancellationToken::is_cancelled)
+                    {
+                        service_tokens.remove(&completed_service_id);
+                        tracing::info!(service_id=%completed_service_id, "service disconnected on schedule");
+                        let stopped = EventKind::ServiceStopped;
+                        self.publish_service_event(
+                            &pipeline_queues,
+                            &completed_service_id,
+                            stopped,
+                        );
                     } else {
                         // Service exited unexpectedly - apply backoff and restart
                         let state = self.service_state.get_mut(&completed_service_id);
@@ -252,11 +3061,34 @@ impl Bus {
                             // Increment attempt counter
                             state.attempt_count += 1;
 
-                            tracing::warn!(
-                                service_id=%completed_service_id,
-                                attempt=%state.attempt_count,
-                                "service exited unexpectedly, will reconnect"
-                            );
+                            match &result {
+                                Ok(()) => tracing::warn!(
+                                    service_id=%completed_service_id,
+                                    attempt=%state.attempt_count,
+                                    "service exited unexpectedly, will reconnect"
+                                ),
+                                Err(e) => tracing::error!(
+                                    service_id=%completed_service_id,
+                                    attempt=%state.attempt_count,
+                                    error=%e,
+                                    "service failed, will reconnect"
+                                ),
+                            }
+                            if state.attempt_count == ALERT_AFTER_FAILURES {
+                                let error = result
+                                    .as_ref()
+                                    .err()
+                                    .map(|e| format!(" ({e:#})"))
+                                    .unwrap_or_default();
+                                alerts::notify(
+                                    AlertLevel::Critical,
+                                    format!(
+                                        "service '{completed_service_id}' has failed {} times \
+                                         in a row{error}; still trying to reconnect",
+                                        state.attempt_count
+                                    ),
+                                );
+                            }
 
                             // Calculate backoff delay
                             let delay = state.backoff.next_delay();
@@ -267,31 +3099,31 @@ impl Bus {
                                 delay_secs=%delay.as_secs(),
                                 "waiting before restart"
                             );
+                            let degraded = EventKind::ServiceDegraded {
+                                error: result.as_ref().err().map(|e| format!("{e:#}")),
+                                attempt: state.attempt_count,
+                                retry_in: delay,
+                            };
+                            self.publish_service_event(
+                                &pipeline_queues,
+                                &completed_service_id,
+                                degraded,
+                            );
 
                             // Sleep with cancellation support
+                            let waiting =
+                                format!("waiting to restart service '{completed_service_id}'");
+                            self.heartbeat.wait(waiting, delay);
                             tokio::select! {
                                 _ = cancel.cancelled() => {
                                     tracing::info!(service_id=%completed_service_id, "cancellation during backoff, not restarting");
                                 }
                                 _ = tokio::time::sleep(delay) => {
                                     // Restart the service
-                                    if let Some(service) = self.services.get(&completed_service_id) {
-                                        let child_token = cancel.child_token();
-                                        let service_clone = service.clone();
-                                        let id = completed_service_id.clone();
-
-                                        service_tasks.spawn(async move {
-                                            let result = service_clone.run(child_token).await;
-                                            (id, result)
-                                        });
-
-                                        // Update connection start time
-                                        if let Some(state) = self.service_state.get_mut(&completed_service_id) {
-                                            state.connection_start = Instant::now();
-                                        }
-
-                                        tracing::info!(service_id=%completed_service_id, "service restarted");
-                                    }
+                                    let child_token = service_cancel.child_token();
+                                    service_tokens.insert(completed_service_id.clone(), child_token.clone());
+                                    self.spawn_service(&mut service_tasks, &completed_service_id, child_token);
+                                    tracing::info!(service_id=%completed_service_id, "service restarted");
                                 }
                             }
                         }
@@ -301,25 +3133,205 @@ impl Bus {
                     info!("shutdown signal received");
                     break;
                 }
-                maybe_evt = self.evt_rx.recv() => {
-                    info!("event received");
-                    let Some(evt) = maybe_evt else { break };
+                // Pipeline tasks only end early when a middleware returns an error
+                Some(joined) = pipeline_tasks.join_next() => {
+                    joined??;
+                }
+                Some(joined) = building_services.join_next() => {
+                    let built = match joined {
+                        Ok(built) => built,
+                        Err(e) => {
+                            tracing::error!(error=%e, "service building task failed");
+                            continue;
+                        }
+                    };
+                    let BuiltService { service_id, built, response_tx, audit_entry } = built;
+                    self.adding_services.remove(&service_id);
+                    let added = built.and_then(|(service, config)| {
+                        self.add_service(&service_id, service, &config)
+                    });
+                    let connect = match added {
+                        Ok(connect) => connect,
+                        Err(e) => {
+                            self.finish_control(Some(audit_entry), Err(e), response_tx);
+                            continue;
+                        }
+                    };
 
-                    // Get the middleware pipeline for this service
-                    if let Some(pipeline) = self.service_middlewares.get(&evt.service_id) {
-                        for mw in pipeline {
-                            match mw.on_event(&evt)? {
-                                Verdict::Continue => {},
-                                Verdict::Stop => { break; }
+                    if self.service_middlewares.contains_key(&service_id)
+                        || self.room_middlewares.contains_key(&service_id)
+                    {
+                        let (queue_tx, queue_rx) =
+                            tokio::sync::mpsc::channel(PIPELINE_QUEUE_CAPACITY);
+                        pipeline_queues.insert(service_id.clone(), queue_tx);
+                        pipeline_tasks.spawn(run_service_pipeline(
+                            pipeline_ctx.clone(),
+                            service_id.clone(),
+                            queue_rx,
+                        ));
+                    }
+                    self.register_commands_when_ready(&service_id, &service_cancel);
+                    let message = if connect {
+                        let child_token = service_cancel.child_token();
+                        service_tokens.insert(service_id.clone(), child_token.clone());
+                        self.spawn_service(&mut service_tasks, &service_id, child_token);
+                        format!("service '{service_id}' added")
+                    } else {
+                        info!(service_id=%service_id, "outside connection schedule, not connecting");
+                        format!("service '{service_id}' added; it connects when its schedule opens")
+                    };
+                    self.finish_control(Some(audit_entry), Ok(message), response_tx);
+                }
+                Some(service_id) = self.ready_rx.recv() => {
+                    info!(service_id=%service_id, "service ready");
+                    let ready = EventKind::ServiceReady;
+                    self.publish_service_event(&pipeline_queues, &service_id, ready);
+                }
+                Some((service_id, delete)) = self.expiry_rx.recv() => {
+                    self.heartbeat.step(format!("deleting an expired message on '{service_id}'"));
+                    self.dispatch_command(&service_id, delete).await;
+                }
+                Some(Ok(service_id)) = startup_ready.join_next(), if !startup_ready.is_empty() => {
+                    if let Some(message) = &self.lifecycle_announcements.startup_message {
+                        info!(service_id=%service_id, "service ready; posting startup announcement");
+                        let step = format!("posting the startup announcement on '{service_id}'");
+                        self.heartbeat.step(step);
+                        self.announce(message, Some(&service_id)).await;
+                    }
+                }
+                _ = outbox_flush.tick(), if self.outbox.is_some() => {
+                    self.heartbeat.step("flushing outboxes");
+                    self.flush_all_outboxes().await;
+                }
+                _ = heartbeat_tick.tick(), if watchdog.is_some() => {}
+                _ = lease_renewal.tick(), if self.lease.is_some() => {
+                    self.renew_lease().await;
+                }
+                _ = schedule_check.tick(), if !self.connection_schedules.is_empty() => {
+                    let changes: Vec<(ServiceId, bool)> = self
+                        .connection_schedules
+                        .iter()
+                        .map(|(service_id, schedule)| (service_id.clone(), schedule.is_open_now()))
+                        .filter(|(service_id, open)| match service_tokens.get(service_id) {
+                            None => *open,
+                            // Still shutting down; it can connect again once it has exited
+                            Some(token) if token.is_cancelled() => false,
+                            Some(_) => !*open,
+                        })
+                        .collect();
+                    for (service_id, connected) in changes {
+                        if connected {
+                            let child_token = service_cancel.child_token();
+                            service_tokens.insert(service_id.clone(), child_token.clone());
+                            self.spawn_service(&mut service_tasks, &service_id, child_token);
+                            info!(service_id=%service_id, "connection schedule opened, connecting");
+                        } else {
+                            if let Some(token) = service_tokens.get(&service_id) {
+                                token.cancel();
+                            }
+                            info!(service_id=%service_id, "connection schedule closed, disconnecting");
+                        }
+
+                        let scheduled = EventKind::ConnectionScheduled { connected };
+                        self.publish_service_event(&pipeline_queues, &service_id, scheduled);
+                    }
+                }
+                maybe_evt = self.evt_rx.recv_emitted() => {
+                    let Some(EmittedEvent { event: evt, emitted_at }) = maybe_evt else { break };
+                    let correlation_id = CorrelationId::next();
+                    info!(correlation_id=%correlation_id, service_id=%evt.service_id, "event received");
+
+                    // A user list change goes out both as a snapshot and as a diff, right after
+                    // whichever the service sent, under the same correlation ID. The service's
+                    // list is kept up to date even from events dropped below, so one paused for
+                    // a while doesn't come back with a stale list to diff against
+                    let counterpart = self.user_lists.counterpart(&evt);
+
+                    if self.paused_services.contains(&evt.service_id) {
+                        tracing::debug!(service_id=%evt.service_id, "dropping event from paused service");
+                        continue;
+                    }
+                    if self
+                        .injected_faults
+                        .get(&evt.service_id)
+                        .is_some_and(|faults| rand::random::<f64>() < faults.event_drop_rate)
+                    {
+                        tracing::debug!(service_id=%evt.service_id, "dropping event (injected fault)");
+                        continue;
+                    }
+
+                    // An event means the service is up again; deliver anything it missed first
+                    if let Some(outbox) = &self.outbox
+                        && outbox.has_pending(&evt.service_id).unwrap_or(false)
+                    {
+                        let flushing =
+                            format!("flushing the outbox of service '{}'", evt.service_id);
+                        self.heartbeat.step(flushing);
+                        self.flush_outbox(&evt.service_id).await;
+                    }
+
+                    if let Some(room_id) = evt.kind.room_id()
+                        && let Some(filter) = self.room_filters.get(&evt.service_id)
+                        && !filter.allows(room_id)
+                    {
+                        tracing::trace!(service_id=%evt.service_id, room_id=%room_id, "dropping event from filtered room");
+                        continue;
+                    }
+
+                    for evt in std::iter::once(evt).chain(counterpart) {
+                        self.roster.record(&evt);
+
+                        // From here on the event is shared, not copied, by observers and the
+                        // pipeline
+                        let evt = Arc::new(evt);
+
+                        // Publish to out-of-pipeline observers
+                        if let Some(tap) = &self.event_tap {
+                            let _ = tap.send(evt.clone());
+                        }
+
+                        if !self.leader {
+                            tracing::trace!(service_id=%evt.service_id, "standing by, not running event through pipelines");
+                            continue;
+                        }
+
+                        match pipeline_queues.get(&evt.service_id) {
+                            Some(queue) => {
+                                let queued = PipelineEvent { evt, emitted_at, correlation_id };
+                                if let Err(TrySendError::Full(queued)) = queue.try_send(queued) {
+                                    tracing::warn!(correlation_id=%correlation_id, service_id=%queued.evt.service_id, "pipeline queue full, dropping event");
+                                }
+                            }
+                            None => {
+                                tracing::debug!(service_id=%evt.service_id, "no middleware pipeline configured for service");
                             }
                         }
-                    } else {
-                        tracing::debug!(service_id=%evt.service_id, "no middleware pipeline configured for service");
                     }
                 }
                 maybe_cmd = self.cmd_rx.recv() => {
-                    info!("command received");
                     let Some(cmd) = maybe_cmd else { break };
+                    info!(
+                        origin=%cmd.origin().unwrap_or("unknown"),
+                        correlation_id=cmd.correlation_id().map(tracing::field::display),
+                        "command received"
+                    );
+
+                    let cmd = match cmd {
+                        Command::Control(control @ BusControl::AddService { .. }) => {
+                            self.start_building_service(&mut building_services, control);
+                            continue;
+                        }
+                        Command::Control(control) => {
+                            self.apply_control(control);
+                            continue;
+                        }
+                        cmd @ Command::Announce { .. } => {
+                            self.heartbeat.step("posting an announcement");
+                            self.dispatch_announcement(cmd).await;
+                            continue;
+                        }
+                        cmd => cmd,
+                    };
 
                     // Extract service_id from command
                     let service_id = match &cmd {
@@ -327,33 +3339,366 @@ impl Bus {
                         Command::SendRoomMessage { service_id, .. } => service_id.clone(),
                         Command::SendThreadReply { service_id, .. } => service_id.clone(),
                         Command::EditMessage { service_id, .. } => service_id.clone(),
+                        Command::DeleteMessage { service_id, .. } => service_id.clone(),
                         Command::GenerateInviteToken { service_id, .. } => service_id.clone(),
                         Command::AddReaction { service_id, .. } => service_id.clone(),
                         Command::SendRoomImage { service_id, .. } => service_id.clone(),
+                        Command::GetRoomState { service_id, .. } => service_id.clone(),
+                        Command::SetRoomState { service_id, .. } => service_id.clone(),
+                        Command::SetRoomTopic { service_id, .. } => service_id.clone(),
+                        Command::ApproveKnock { service_id, .. } => service_id.clone(),
+                        Command::KickUser { service_id, .. } => service_id.clone(),
+                        Command::JoinRoom { service_id, .. } => service_id.clone(),
+                        Command::ConfirmVerification { service_id, .. } => service_id.clone(),
+                        Command::Ping { service_id, .. } => service_id.clone(),
+                        Command::GetUserList { service_id, .. } => service_id.clone(),
+                        Command::Announce { .. } | Command::Control(_) => {
+                            unreachable!("announcements and control commands are handled above")
+                        }
                     };
 
                     // Dispatch command to appropriate service
-                    if let Some(service) = self.services.get(&service_id) {
-                        if let Err(e) = service.handle_command(cmd).await {
-                            tracing::error!(service_id=%service_id, error=%e, "failed to handle command");
-                        }
-                    } else {
-                        tracing::warn!(service_id=%service_id, "command sent to unknown service");
-                    }
+                    let dispatching =
+                        format!("dispatching {} to service '{service_id}'", cmd.kind());
+                    self.heartbeat.step(dispatching);
+                    self.dispatch_command(&service_id, cmd).await;
+                }
+            }
+        }
+
+        // Shutdown takes as long as it takes; a slow one isn't a stall
+        drop(watchdog);
+
+        // Let pipelines finish the events already handed to them
+        drop(pipeline_queues);
+        while let Some(joined) = pipeline_tasks.join_next().await {
+            match joined {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tracing::error!(error=%e, "middleware pipeline failed during shutdown")
                 }
+                Err(e) => tracing::error!(error=%e, "middleware pipeline task failed"),
+            }
+        }
+
+        if let Some(message) = &self.lifecycle_announcements.shutdown_message {
+            info!("posting shutdown announcement");
+            self.announce(message, None).await;
+        }
+        if let Some(lease) = self.lease.clone()
+            && self.leader
+        {
+            info!("handing over leadership");
+            match tokio::task::spawn_blocking(move || lease.release()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::warn!(error=%e, "failed to release leadership lease"),
+                Err(e) => tracing::warn!(error=%e, "leadership lease release panicked"),
             }
         }
+        service_cancel.cancel();
+
         info!("exited event bus");
         Ok(())
     }
 }
 
+/// Reports `id` to the bus once `service` is ready, then never finishes, so it can run
+/// alongside the service's `run` without ending the service task. Finishes with an error
+/// instead, alerting the operator, if the service isn't ready within `startup_timeout`.
+async fn report_ready(
+    service: &dyn Service,
+    id: &ServiceId,
+    ready_tx: &UnboundedSender<ServiceId>,
+    startup_timeout: Option<Duration>,
+) -> anyhow::Error {
+    if let Some(timeout) = startup_timeout {
+        if tokio::time::timeout(timeout, service.ready()).await.is_err() {
+            let timeout = humantime::format_duration(timeout);
+            tracing::error!(service_id=%id, timeout=%timeout, "service not ready in time, restarting it");
+            alerts::notify(
+                AlertLevel::Critical,
+                format!(
+                    "service '{id}' wasn't ready within {timeout}, so it's being restarted; the \
+                     other services carry on without it"
+                ),
+            );
+            return anyhow::anyhow!("not ready within {timeout}");
+        }
+    } else {
+        service.ready().await;
+    }
+    let _ = ready_tx.send(id.clone());
+    std::future::pending().await
+}
+
+/// Arranges for a message with an `expires_after` to be deleted once it expires: swaps in a
+/// response channel of the bus's own, which passes the message's ID on to the sender and then
+/// waits out the expiry. Messages to services that can't delete are sent as they are.
+///
+/// The deletion goes back through `expiry_tx` to the run loop, so it's audited and checked
+/// against the command policy, standby and read-only mode like any other command.
+///
+/// Having a response channel keeps the message out of the outbox, since one delivered late
+/// could outlive its expiry. Deletions still pending when the bot stops are dropped.
+fn schedule_expiry(
+    service: &Arc<dyn Service>,
+    service_id: &ServiceId,
+    expiry_tx: &UnboundedSender<(ServiceId, Command)>,
+    mut cmd: Command,
+) -> Command {
+    let (Command::SendDirectMessage { response_tx, origin, expires_after, .. }
+    | Command::SendRoomMessage { response_tx, origin, expires_after, .. }) = &mut cmd
+    else {
+        return cmd;
+    };
+    let Some(expires_after) = expires_after.take() else {
+        return cmd;
+    };
+    if !service.capabilities().supports_delete {
+        tracing::debug!(service_id=%service_id, "service can't delete messages, not expiring message");
+        return cmd;
+    }
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let sender_tx = response_tx.replace(tx);
+    let origin = origin.clone();
+    let expiry_tx = expiry_tx.clone();
+    let service_id = service_id.clone();
+    correlation::spawn(async move {
+        let message_id = match rx.await {
+            Ok(Ok(message_id)) => {
+                if let Some(sender_tx) = sender_tx {
+                    let _ = sender_tx.send(Ok(message_id.clone()));
+                }
+                message_id
+            }
+            Ok(Err(e)) => {
+                if let Some(sender_tx) = sender_tx {
+                    let _ = sender_tx.send(Err(e));
+                }
+                return;
+            }
+            Err(_) => return,
+        };
+        tokio::time::sleep(expires_after).await;
+        tracing::info!(service_id=%service_id, message_id=%message_id, "deleting expired message");
+        let delete = Command::DeleteMessage { service_id: service_id.clone(), message_id, origin };
+        if expiry_tx.send((service_id.clone(), delete)).is_err() {
+            tracing::debug!(service_id=%service_id, "bus stopped, not deleting expired message");
+        }
+    });
+    cmd
+}
+
+/// Publishes a [`BusAlert::PermissionDenied`] if `err` is a [`PermissionDenied`], so an
+/// operator hears the bot needs a higher power level rather than only the middleware.
+fn alert_permission_denied(
+    alerts: Option<&broadcast::Sender<BusAlert>>,
+    service_id: &ServiceId,
+    origin: &str,
+    err: &anyhow::Error,
+) {
+    let Some(denied) = err.downcast_ref::<PermissionDenied>() else {
+        return;
+    };
+    tracing::warn!(service_id=%service_id, origin=%origin, room_id=%denied.room_id, action=denied.action, "bot lacks permission for moderation command");
+    alerts::notify(
+        AlertLevel::Warning,
+        format!(
+            "the bot lacks permission to {} in room {} on '{service_id}' (for '{origin}')",
+            denied.action, denied.room_id
+        ),
+    );
+    let Some(alerts) = alerts else {
+        return;
+    };
+    let _ = alerts.send(BusAlert::PermissionDenied {
+        service_id: service_id.clone(),
+        origin: origin.to_string(),
+        room_id: denied.room_id.clone(),
+        action: denied.action,
+    });
+}
+
+/// Sees the result of a moderation command answered through its response channel, which
+/// bypasses the bus, by swapping in one of the bus's own that passes the result on, raising an
+/// alert first if the bot wasn't allowed to carry it out.
+fn watch_permission_denied(
+    alerts: Option<broadcast::Sender<BusAlert>>,
+    service_id: &ServiceId,
+    mut cmd: Command,
+) -> Command {
+    let Command::KickUser { response_tx, origin, .. } = &mut cmd else {
+        return cmd;
+    };
+    let (Some(alerts), Some(sender_tx)) = (alerts, response_tx.take()) else {
+        return cmd;
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    *response_tx = Some(tx);
+    let origin = origin.as_ref().map_or("unknown", |origin| origin.middleware.as_str()).to_string();
+    let service_id = service_id.clone();
+    correlation::spawn(async move {
+        let Ok(result) = rx.await else {
+            return;
+        };
+        if let Err(e) = &result {
+            alert_permission_denied(Some(&alerts), &service_id, &origin, e);
+        }
+        let _ = sender_tx.send(result);
+    });
+    cmd
+}
+
 // A small helper to make a Command channel pair available to middlewares.
 pub fn create_command_channel(cap: usize) -> (Sender<Command>, Receiver<Command>) {
     tokio::sync::mpsc::channel(cap)
 }
 
 // A small helper to make an Event channel pair available to services.
-pub fn create_event_channel(cap: usize) -> (Sender<Event>, Receiver<Event>) {
-    tokio::sync::mpsc::channel(cap)
+pub fn create_event_channel(cap: usize) -> (EventTx, EventRx) {
+    let (tx, rx) = tokio::sync::mpsc::channel(cap);
+    (EventTx { tx }, EventRx { rx })
+}
+
+/// An event on its way to the bus, with when its service emitted it.
+struct EmittedEvent {
+    event: Event,
+    emitted_at: Instant,
+}
+
+impl EmittedEvent {
+    fn now(event: Event) -> Self {
+        Self { event, emitted_at: Instant::now() }
+    }
+}
+
+/// The services' end of the bus's event channel. Stamps each event as it's sent, so the bus
+/// can tell how long it waited on the way through.
+#[derive(Clone)]
+pub struct EventTx {
+    tx: Sender<EmittedEvent>,
+}
+
+impl EventTx {
+    pub async fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        self.tx
+            .send(EmittedEvent::now(event))
+            .await
+            .map_err(|SendError(emitted)| SendError(emitted.event))
+    }
+
+    pub fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        self.tx.try_send(EmittedEvent::now(event)).map_err(|e| match e {
+            TrySendError::Full(emitted) => TrySendError::Full(emitted.event),
+            TrySendError::Closed(emitted) => TrySendError::Closed(emitted.event),
+        })
+    }
+}
+
+/// The bus's end of the event channel.
+pub struct EventRx {
+    rx: Receiver<EmittedEvent>,
+}
+
+impl EventRx {
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.rx.recv().await.map(|emitted| emitted.event)
+    }
+
+    pub fn try_recv(&mut self) -> Result<Event, TryRecvError> {
+        self.rx.try_recv().map(|emitted| emitted.event)
+    }
+
+    async fn recv_emitted(&mut self) -> Option<EmittedEvent> {
+        self.rx.recv().await
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// What an `EventSender` does with an event when the bus's event channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Shed the event immediately.
+    DropNewest,
+    /// Wait up to the given duration for room, then shed the event.
+    WaitFor(Duration),
+}
+
+/// Sends events to the bus without letting a full channel stall the caller indefinitely.
+///
+/// Services should use this from callbacks that must return promptly (e.g. Matrix sync
+/// handlers). Shed events are counted and logged; clones share the same counter.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: EventTx,
+    policy: OverflowPolicy,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSender {
+    pub fn new(tx: EventTx, policy: OverflowPolicy) -> Self {
+        Self { tx, policy, dropped: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Delivers `event` to the bus, applying the overflow policy if the channel is full.
+    ///
+    /// Returns `Ok(true)` if delivered, `Ok(false)` if shed, and an error once the bus
+    /// has shut down.
+    pub async fn send(&self, event: Event) -> anyhow::Result<bool> {
+        let event = match self.tx.try_send(event) {
+            Ok(()) => return Ok(true),
+            Err(TrySendError::Closed(_)) => anyhow::bail!("bus event receiver dropped"),
+            Err(TrySendError::Full(event)) => event,
+        };
+
+        if let OverflowPolicy::WaitFor(wait) = self.policy {
+            let service_id = event.service_id.clone();
+            match tokio::time::timeout(wait, self.tx.send(event)).await {
+                Ok(Ok(())) => return Ok(true),
+                Ok(Err(_)) => anyhow::bail!("bus event receiver dropped"),
+                Err(_) => self.record_drop(&service_id),
+            }
+        } else {
+            self.record_drop(&event.service_id);
+        }
+        Ok(false)
+    }
+
+    /// Number of events shed so far.
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record_drop(&self, service_id: &ServiceId) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        // Warn on the first drop and periodically after, so a sustained overload doesn't
+        // flood the log it's trying to report
+        if dropped == 1 || dropped.is_multiple_of(100) {
+            tracing::warn!(
+                service_id=%service_id,
+                total_dropped=%dropped,
+                "bus event channel full, shedding event"
+            );
+        }
+    }
+}
+
+// A small helper to make a broadcast channel for operator alerts.
+pub fn create_alert_channel(cap: usize) -> broadcast::Sender<BusAlert> {
+    broadcast::channel(cap).0
+}
+
+// A small helper to make a broadcast tap for out-of-pipeline event subscribers.
+// Call `subscribe()` on the returned sender to obtain a receiver.
+pub fn create_event_tap(cap: usize) -> broadcast::Sender<Arc<Event>> {
+    broadcast::channel(cap).0
 }
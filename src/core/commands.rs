@@ -0,0 +1,596 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use chrono::{NaiveTime, Weekday};
+
+use crate::core::{
+    bus::{Command, CommandSender},
+    config::Config,
+    correlation,
+    event::{Event, EventKind},
+    format::BodyFormat,
+    middleware::matches_command,
+    preferences::{PreferenceStore, ReplyMode},
+    service::ServiceId,
+};
+
+// Process-wide, like the redactor: installed from config at startup, read by every router
+static COMMAND_PREFIXES: LazyLock<RwLock<HashMap<ServiceId, String>>> =
+    LazyLock::new(RwLock::default);
+
+/// Makes commands on `service_id` start with `prefix` instead of the sigil they're configured
+/// with, e.g. `.` where `!` collides with another bot: `!echo` is then typed `.echo` there, and
+/// `!echo` no longer invokes it. Commands configured without a sigil are left alone.
+pub fn set_command_prefix(service_id: ServiceId, prefix: impl Into<String>) {
+    let mut prefixes = COMMAND_PREFIXES.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    prefixes.insert(service_id, prefix.into());
+}
+
+/// Installs the `command_prefix` of every service in `config` that sets one.
+pub fn install_command_prefixes(config: &Config) -> Result<()> {
+    for (id, service) in &config.services {
+        let Some(prefix) = &service.command_prefix else {
+            continue;
+        };
+        if prefix.is_empty() || prefix.contains(char::is_whitespace) {
+            bail!("service '{id}': command_prefix must be non-empty and contain no spaces");
+        }
+        set_command_prefix(ServiceId(id.clone()), prefix.clone());
+    }
+    Ok(())
+}
+
+/// `command` as it's typed on `service_id`: with the service's command prefix in place of its
+/// sigil if the service sets one, e.g. `.echo` for `!echo`.
+pub fn command_on(service_id: &ServiceId, command: &str) -> String {
+    let prefixes = COMMAND_PREFIXES.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let bare = command.trim_start_matches(|c: char| !c.is_alphanumeric());
+    match prefixes.get(service_id) {
+        Some(prefix) if !bare.is_empty() && bare != command => format!("{prefix}{bare}"),
+        _ => command.to_string(),
+    }
+}
+
+// Installed and read like the prefixes. Keyed by the alias with its sigil, e.g. `!gn`
+static COMMAND_ALIASES: LazyLock<RwLock<HashMap<String, String>>> = LazyLock::new(RwLock::default);
+
+/// Makes `alias` stand for `expansion` wherever a command router reads a message, e.g. `!gn` for
+/// `!event create friday 19:00 Game Night`. Anything typed after the alias is appended to the
+/// expansion. An alias given without a sigil (`gn`) takes the expansion's, and like commands,
+/// aliases are typed with the service's command prefix where it has one.
+///
+/// Expansions aren't expanded again, so aliases can't stand for other aliases.
+pub fn set_command_alias(alias: &str, expansion: impl Into<String>) {
+    let expansion = expansion.into();
+    let alias = if alias.starts_with(char::is_alphanumeric) {
+        let bare = expansion.trim_start_matches(|c: char| !c.is_alphanumeric());
+        format!("{}{alias}", &expansion[..expansion.len() - bare.len()])
+    } else {
+        alias.to_string()
+    };
+    let mut aliases = COMMAND_ALIASES.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    aliases.insert(alias, expansion);
+}
+
+/// Installs the `aliases` in `config`.
+pub fn install_command_aliases(config: &Config) -> Result<()> {
+    for (alias, expansion) in &config.aliases {
+        if alias.is_empty() || alias.contains(char::is_whitespace) {
+            bail!("alias '{alias}' must be non-empty and contain no spaces");
+        }
+        if expansion.trim().is_empty() {
+            bail!("alias '{alias}' must stand for a command");
+        }
+        set_command_alias(alias, expansion.trim());
+    }
+    Ok(())
+}
+
+/// `text` with the alias it starts with replaced by what the alias stands for, or `None` if it
+/// doesn't start with one. The alias is matched as typed on `service_id` if one is given.
+pub fn expand_alias(service_id: Option<&ServiceId>, text: &str) -> Option<String> {
+    let aliases = COMMAND_ALIASES.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    aliases.iter().find_map(|(alias, expansion)| {
+        let typed = service_id.map_or_else(|| alias.clone(), |id| command_on(id, alias));
+        matches_command(text, &typed).then(|| format!("{expansion}{}", &text.trim()[typed.len()..]))
+    })
+}
+
+/// How a command argument consumes input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// One word, quoted if it contains spaces, that must be present.
+    Required,
+    /// One word that may be omitted. Only valid after required args.
+    Optional,
+    /// Everything left on the line, with inner whitespace preserved. Must be non-empty and last.
+    Rest,
+    /// Like `Rest`, but may be empty, in which case the argument is absent.
+    OptionalRest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+}
+
+impl ArgSpec {
+    pub fn required(name: &'static str) -> Self {
+        Self { name, kind: ArgKind::Required }
+    }
+
+    pub fn optional(name: &'static str) -> Self {
+        Self { name, kind: ArgKind::Optional }
+    }
+
+    pub fn rest(name: &'static str) -> Self {
+        Self { name, kind: ArgKind::Rest }
+    }
+
+    pub fn optional_rest(name: &'static str) -> Self {
+        Self { name, kind: ArgKind::OptionalRest }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subcommand {
+    pub name: &'static str,
+    pub args: Vec<ArgSpec>,
+}
+
+/// A command a middleware responds to, declared once so services with their own command system
+/// (e.g. Discord slash commands) can register it natively.
+///
+/// Services hand native invocations on as ordinary messages (see `message_text`), so
+/// middlewares parse them with the same prefix parsing as typed commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    /// What invokes the command in a message, e.g. `!bus`.
+    pub prefix: String,
+    pub description: Option<String>,
+    /// Arguments of a command without subcommands.
+    pub args: Vec<ArgSpec>,
+    pub subcommands: Vec<Subcommand>,
+}
+
+impl CommandSpec {
+    /// A command known only by its prefix, taking any text after it.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            description: None,
+            args: vec![ArgSpec::optional_rest("text")],
+            subcommands: Vec::new(),
+        }
+    }
+
+    /// The prefix without its sigil, as native command systems name commands, e.g. `bus`.
+    pub fn name(&self) -> &str {
+        self.prefix.trim_start_matches(|c: char| !c.is_alphanumeric())
+    }
+
+    /// The message a native invocation stands for, e.g. `!bus pause mumble` for the `pause`
+    /// subcommand with `["mumble"]`. Single-word arguments containing spaces are quoted so
+    /// they parse back as one word.
+    pub fn message_text(&self, subcommand: Option<&str>, args: &[&str]) -> String {
+        let specs = match subcommand {
+            Some(name) => {
+                self.subcommands.iter().find(|s| s.name == name).map_or(&[][..], |s| &s.args)
+            }
+            None => &self.args,
+        };
+        let mut text = self.prefix.clone();
+        if let Some(name) = subcommand {
+            text.push(' ');
+            text.push_str(name);
+        }
+        for (index, arg) in args.iter().enumerate().filter(|(_, arg)| !arg.is_empty()) {
+            let rest = specs
+                .get(index)
+                .is_some_and(|spec| matches!(spec.kind, ArgKind::Rest | ArgKind::OptionalRest));
+            text.push(' ');
+            if rest || !arg.contains(char::is_whitespace) {
+                text.push_str(arg);
+            } else {
+                text.push_str(&format!("\"{arg}\""));
+            }
+        }
+        text
+    }
+}
+
+/// A successfully parsed command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The subcommand that matched, or `None` for a router without subcommands.
+    pub subcommand: Option<&'static str>,
+    args: HashMap<&'static str, String>,
+}
+
+impl Invocation {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.args.get(name).map(String::as_str)
+    }
+
+    /// Value of a required or rest argument, which parsing guarantees is present.
+    /// Returns an empty string for names that aren't part of the spec.
+    pub fn arg(&self, name: &str) -> &str {
+        self.get(name).unwrap_or_default()
+    }
+}
+
+/// Parses `<prefix> [subcommand] <args...>` messages for a middleware.
+///
+/// A router either takes arguments directly (`!echo <text...>`) or dispatches to named
+/// subcommands (`!bus pause <service>`). Single-word arguments can be quoted to include spaces
+/// (`"movie night"`). Malformed invocations produce a usage message built from the registered
+/// specs, so every command reports errors the same way.
+#[derive(Debug, Clone)]
+pub struct CommandRouter {
+    prefix: String,
+    description: Option<String>,
+    args: Vec<ArgSpec>,
+    subcommands: Vec<Subcommand>,
+}
+
+impl CommandRouter {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into(), description: None, args: Vec::new(), subcommands: Vec::new() }
+    }
+
+    /// One line on what the command does, shown by services that register commands natively.
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Arguments taken by the command itself. Ignored once subcommands are registered.
+    pub fn with_args(mut self, args: Vec<ArgSpec>) -> Self {
+        self.args = args;
+        self
+    }
+
+    pub fn with_subcommand(mut self, name: &'static str, args: Vec<ArgSpec>) -> Self {
+        self.subcommands.push(Subcommand { name, args });
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The prefix as it's typed on `service_id`, for replies that tell users what to type.
+    pub fn prefix_on(&self, service_id: &ServiceId) -> String {
+        command_on(service_id, &self.prefix)
+    }
+
+    /// The command as declared, for `Middleware::commands`.
+    pub fn spec(&self) -> CommandSpec {
+        CommandSpec {
+            prefix: self.prefix.clone(),
+            description: self.description.clone(),
+            args: self.args.clone(),
+            subcommands: self.subcommands.clone(),
+        }
+    }
+
+    /// Whether `body` invokes this command at all, regardless of whether its arguments parse,
+    /// either directly or through an alias (see `set_command_alias`).
+    pub fn matches(&self, body: &str) -> bool {
+        matches_command(body, &self.prefix)
+            || expand_alias(None, body)
+                .is_some_and(|expanded| matches_command(&expanded, &self.prefix))
+    }
+
+    /// Parses `body`. Returns `None` if it doesn't invoke this command, or `Some(Err(usage))`
+    /// if it does but the subcommand or arguments don't fit the spec. Aliases aren't expanded;
+    /// `route` does that.
+    pub fn parse(&self, body: &str) -> Option<Result<Invocation, String>> {
+        if !matches_command(body, &self.prefix) {
+            return None;
+        }
+        let input = body.trim()[self.prefix.len()..].trim();
+
+        if self.subcommands.is_empty() {
+            return Some(
+                parse_args(&self.args, input)
+                    .map(|args| Invocation { subcommand: None, args })
+                    .ok_or_else(|| self.usage()),
+            );
+        }
+
+        let (name, rest) = next_token(input).unwrap_or_default();
+        let Some(subcommand) = self.subcommands.iter().find(|s| s.name == name) else {
+            return Some(Err(self.usage()));
+        };
+        Some(
+            parse_args(&subcommand.args, rest)
+                .map(|args| Invocation { subcommand: Some(subcommand.name), args })
+                .ok_or_else(|| self.usage()),
+        )
+    }
+
+    /// e.g. `Usage: !bus pause <service> | !bus resume <service>`
+    pub fn usage(&self) -> String {
+        self.usage_with(&self.prefix)
+    }
+
+    /// The usage message as it's typed on `service_id`.
+    pub fn usage_on(&self, service_id: &ServiceId) -> String {
+        self.usage_with(&self.prefix_on(service_id))
+    }
+
+    fn usage_with(&self, prefix: &str) -> String {
+        let forms: Vec<String> = if self.subcommands.is_empty() {
+            vec![format_form(prefix, &self.args)]
+        } else {
+            self.subcommands
+                .iter()
+                .map(|s| format_form(&format!("{prefix} {}", s.name), &s.args))
+                .collect()
+        };
+        format!("Usage: {}", forms.join(" | "))
+    }
+
+    /// Parses the message carried by `evt`. Returns the invocation if it's well-formed; a
+    /// malformed invocation gets the usage message sent back to where it came from.
+    ///
+    /// In a room message that mentions the bot, the command may follow the mention, with or
+    /// without its `!` (`@kelvin echo hi`). On a service with its own command prefix, the
+    /// command is typed with that prefix instead (see `set_command_prefix`). Aliases are
+    /// expanded first (see `set_command_alias`).
+    pub fn route(&self, evt: &Event, cmd_tx: &CommandSender) -> Option<Invocation> {
+        match self.parse(&command_text(evt, &self.prefix)?)? {
+            Ok(invocation) => Some(invocation),
+            Err(_) => {
+                send_reply(evt, self.usage_on(&evt.service_id), cmd_tx);
+                None
+            }
+        }
+    }
+}
+
+// Longest leading "Name:" treated as a mention rather than part of the message
+const MAX_MENTION_LEN: usize = 64;
+
+/// The message `evt` invokes `command` with, starting with `command`, or `None` if it doesn't.
+///
+/// That's the message itself, or for a room message that mentions the bot (`mentions_self`),
+/// the text after the mention, where the command's leading sigil may be left off: both
+/// `@kelvin !echo hi` and `Kelvin: echo hi` read as `!echo hi`. On a service with its own
+/// command prefix, `.echo hi` reads as `!echo hi` too, and `!echo hi` doesn't invoke it.
+///
+/// A message starting with an alias reads as what the alias stands for, e.g. `!gn` as
+/// `!event create friday 19:00 Game Night`.
+pub fn command_text(evt: &Event, command: &str) -> Option<String> {
+    let body = evt.kind.message_body()?;
+    if let Some(expanded) = expand_alias(Some(&evt.service_id), body) {
+        return matches_command(&expanded, command).then_some(expanded);
+    }
+    let typed = command_on(&evt.service_id, command);
+    if matches_command(body, &typed) {
+        return Some(format!("{command}{}", &body.trim()[typed.len()..]));
+    }
+    let EventKind::RoomMessage { mentions_self: true, .. } = &evt.kind else {
+        return None;
+    };
+    let text = strip_leading_mention(body);
+    if let Some(expanded) = expand_alias(Some(&evt.service_id), text) {
+        return matches_command(&expanded, command).then_some(expanded);
+    }
+    if matches_command(text, &typed) {
+        return Some(format!("{command}{}", &text.trim()[typed.len()..]));
+    }
+    let bare = command.trim_start_matches(|c: char| !c.is_alphanumeric());
+    if bare.is_empty() || bare == command || !matches_command(text, bare) {
+        return None;
+    }
+    Some(format!("{command}{}", &text.trim()[bare.len()..]))
+}
+
+/// `body` without a leading mention: an `@name` word, or a name followed by `:` or `,` (how
+/// clients render a mention at the start of a message, e.g. `Kelvin Bot: echo hi`).
+pub fn strip_leading_mention(body: &str) -> &str {
+    let body = body.trim_start();
+    if body.starts_with('@') {
+        let rest = body.split_once(char::is_whitespace).map_or("", |(_, rest)| rest);
+        return rest.trim_start();
+    }
+    match body.find([':', ',']) {
+        Some(end) if end > 0 && end <= MAX_MENTION_LEN && !body[..end].starts_with('!') => {
+            body[end + 1..].trim_start()
+        }
+        _ => body,
+    }
+}
+
+/// A fire-and-forget reply to `evt`: a DM back to the sender or a message in the same room.
+pub fn reply_command(evt: &Event, body: String) -> Option<Command> {
+    match &evt.kind {
+        EventKind::DirectMessage { user_id, .. } => Some(Command::SendDirectMessage {
+            service_id: evt.service_id.clone(),
+            user_id: user_id.clone(),
+            body,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            expires_after: None,
+            require_encryption: false,
+        }),
+        EventKind::RoomMessage { room_id, .. } => Some(Command::SendRoomMessage {
+            service_id: evt.service_id.clone(),
+            room_id: room_id.clone(),
+            body,
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        }),
+        _ => None,
+    }
+}
+
+/// Sends `reply_command(evt, body)` in the background.
+pub fn send_reply(evt: &Event, body: String, cmd_tx: &CommandSender) {
+    let Some(command) = reply_command(evt, body) else {
+        return;
+    };
+    cmd_tx.spawn_send(command, "command reply");
+}
+
+/// Like `send_reply`, but a reply to a room message goes to the sender by DM instead if they
+/// asked for replies that way (`!prefs set replies dm`).
+pub fn send_preferred_reply(
+    evt: &Event,
+    body: String,
+    cmd_tx: &CommandSender,
+    preferences: &PreferenceStore,
+) {
+    let EventKind::RoomMessage { sender_id, .. } = &evt.kind else {
+        send_reply(evt, body, cmd_tx);
+        return;
+    };
+    let evt = evt.clone();
+    let sender_id = sender_id.clone();
+    let cmd_tx = cmd_tx.clone();
+    let preferences = preferences.clone();
+    correlation::spawn(async move {
+        let command = match preferences.get(&evt.service_id, &sender_id).await.reply_mode {
+            ReplyMode::Dm => Command::SendDirectMessage {
+                service_id: evt.service_id.clone(),
+                user_id: sender_id,
+                body,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            },
+            ReplyMode::Room => {
+                let Some(command) = reply_command(&evt, body) else {
+                    return;
+                };
+                command
+            }
+        };
+        cmd_tx.spawn_send(command, "command reply");
+    });
+}
+
+fn format_form(command: &str, args: &[ArgSpec]) -> String {
+    let mut form = command.to_string();
+    for arg in args {
+        let formatted = match arg.kind {
+            ArgKind::Required => format!(" <{}>", arg.name),
+            ArgKind::Optional => format!(" [{}]", arg.name),
+            ArgKind::Rest => format!(" <{}...>", arg.name),
+            ArgKind::OptionalRest => format!(" [{}...]", arg.name),
+        };
+        form.push_str(&formatted);
+    }
+    form
+}
+
+// Opening and closing quote pairs; curly quotes are what many phone keyboards produce
+const QUOTES: [(char, char); 2] = [('"', '"'), ('\u{201C}', '\u{201D}')];
+
+/// Splits off the first word, returning it and the trimmed remainder. A word starting with a
+/// quote runs to the matching closing quote, which is stripped. An unterminated quote is
+/// treated as an ordinary character.
+fn next_token(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    let first = input.chars().next()?;
+    if let Some(&(open, close)) = QUOTES.iter().find(|(open, _)| *open == first) {
+        let quoted = &input[open.len_utf8()..];
+        if let Some(end) = quoted.find(close) {
+            return Some((&quoted[..end], quoted[end + close.len_utf8()..].trim_start()));
+        }
+    }
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    Some((&input[..end], input[end..].trim_start()))
+}
+
+/// Splits `input` into words the same way single-word arguments are parsed, honoring quotes.
+/// Useful for interpreting a rest argument that holds free-form options.
+pub fn split_words(input: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = input;
+    while let Some((word, remainder)) = next_token(rest) {
+        words.push(word);
+        rest = remainder;
+    }
+    words
+}
+
+/// Parses a duration such as `90s`, `24h`, `2h30m` or `1week`.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    humantime::parse_duration(input.trim())
+        .map_err(|_| format!("'{input}' isn't a duration (try something like 2h30m)"))
+}
+
+/// Parses a time of day: `19:30`, `7pm`, `7:30pm` or `7:30 PM`.
+pub fn parse_time_of_day(input: &str) -> Result<NaiveTime, String> {
+    let invalid = || format!("'{input}' isn't a time of day (try 7pm or 19:30)");
+    let lower: String = input.split_whitespace().collect::<String>().to_ascii_lowercase();
+    let (clock, pm) = match (lower.strip_suffix("am"), lower.strip_suffix("pm")) {
+        (Some(clock), _) => (clock, Some(false)),
+        (_, Some(clock)) => (clock, Some(true)),
+        _ => (lower.as_str(), None),
+    };
+    let (hour, minute) = match clock.split_once(':') {
+        Some((hour, minute)) => (hour.parse::<u32>(), minute.parse::<u32>()),
+        None => (clock.parse::<u32>(), Ok(0)),
+    };
+    let (Ok(hour), Ok(minute)) = (hour, minute) else {
+        return Err(invalid());
+    };
+    let hour = match pm {
+        Some(_) if !(1..=12).contains(&hour) => return Err(invalid()),
+        Some(pm) => hour % 12 + if pm { 12 } else { 0 },
+        None => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0).ok_or_else(invalid)
+}
+
+/// Parses a weekday followed by a time of day, e.g. `friday 7pm`, `Fri 19:30` or `sat 9:15 am`.
+pub fn parse_weekday_time(input: &str) -> Result<(Weekday, NaiveTime), String> {
+    let input = input.trim();
+    let (day, time) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let weekday = day
+        .parse::<Weekday>()
+        .map_err(|_| format!("'{day}' isn't a day of the week (try friday or fri)"))?;
+    Ok((weekday, parse_time_of_day(time)?))
+}
+
+fn parse_args(specs: &[ArgSpec], input: &str) -> Option<HashMap<&'static str, String>> {
+    let mut args = HashMap::new();
+    let mut rest = input.trim();
+    for spec in specs {
+        match spec.kind {
+            ArgKind::Rest | ArgKind::OptionalRest => {
+                if !rest.is_empty() {
+                    args.insert(spec.name, rest.to_string());
+                } else if spec.kind == ArgKind::Rest {
+                    return None;
+                }
+                rest = "";
+            }
+            ArgKind::Required | ArgKind::Optional => match next_token(rest) {
+                Some((value, remainder)) => {
+                    args.insert(spec.name, value.to_string());
+                    rest = remainder;
+                }
+                None if spec.kind == ArgKind::Optional => {}
+                None => return None,
+            },
+        }
+    }
+    rest.is_empty().then_some(args)
+}
@@ -1,23 +1,41 @@
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::{Context, anyhow, bail};
+use schemars::JsonSchema;
 use secrecy::SecretString;
 use serde::Deserialize;
 use serde_with::{DisplayFromStr, serde_as};
 use url::Url;
 
-use crate::middlewares::movie_showtimes::LatLng;
-
 pub const ENV_PREFIX: &str = "KELVIN";
 pub const ENV_SEPARATOR: &str = "__";
+/// Environment variable naming an optional TOML config file.
+pub const CONFIG_FILE_ENV: &str = "KELVIN_CONFIG_FILE";
+/// Environment variable naming the config file profile to apply, if `--profile` isn't given.
+pub const PROFILE_ENV: &str = "KELVIN_PROFILE";
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub struct AnnouncementDestination {
     pub service_id: String,
     pub room_id: String,
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+pub struct LatLng {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lat: f64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub lng: f64,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "kind", rename_all = "lowercase")]
 pub enum ServiceKind {
     Dummy {
@@ -25,28 +43,125 @@ pub enum ServiceKind {
         interval_ms: Option<u64>,
     },
     Matrix {
+        #[schemars(with = "String")]
         homeserver_url: Url,
         user_id: String,
+        #[schemars(with = "String")]
         password: SecretString,
         device_id: String,
+        #[schemars(with = "String")]
         db_passphrase: SecretString,
         verification_device_id: Option<String>,
+        /// Wait for the emoji to be confirmed over chat, e.g. through a `verification_approval`
+        /// middleware, instead of only on the other device.
+        #[serde(default)]
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        verification_approval: Option<bool>,
+        /// Directory name under `<data_directory>/matrix/` for the sqlite store.
+        /// Defaults to the service ID.
+        store_subdir: Option<String>,
     },
     Mumble {
         hostname: String,
         #[serde_as(as = "DisplayFromStr")]
         port: u16,
         username: String,
+        #[schemars(with = "String")]
         password: SecretString,
         #[serde(default)]
         #[serde_as(as = "Option<DisplayFromStr>")]
         accept_invalid_certs: Option<bool>,
     },
+    /// An in-process service for demos and end-to-end tests; see `LoopbackService`.
+    Loopback {
+        /// Users present from the start. Comma-separated or a list.
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        users: Option<Vec<String>>,
+        /// Rooms injected messages may name; the first is used when a message names none.
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        room_ids: Option<Vec<String>>,
+        /// File polled for JSON lines to inject as events.
+        inject_file: Option<PathBuf>,
+        /// File the messages the bot sends are appended to as JSON lines.
+        transcript_file: Option<PathBuf>,
+        #[serde(default, with = "humantime_serde")]
+        #[schemars(with = "Option<String>")]
+        poll_interval: Option<Duration>,
+    },
     #[serde(other)]
+    #[schemars(skip)]
     Unknown,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_update_repository() -> String {
+    "haydenmc/KelvinBot".to_string()
+}
+
+fn default_update_check_interval() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_game_poll_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_stream_poll_interval() -> Duration {
+    Duration::from_secs(2 * 60)
+}
+
+fn default_stream_renotify_after() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn default_release_poll_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn default_trivia_questions_per_round() -> usize {
+    5
+}
+
+fn default_trivia_answer_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_impersonation_similarity() -> f64 {
+    0.85
+}
+
+fn default_inactive_after() -> Duration {
+    Duration::from_secs(30 * 24 * 60 * 60)
+}
+
+fn default_inactivity_check_interval() -> Duration {
+    Duration::from_secs(6 * 60 * 60)
+}
+
+fn default_backups_kept() -> usize {
+    7
+}
+
+fn default_vote_kick_threshold() -> usize {
+    3
+}
+
+fn default_vote_kick_duration() -> Duration {
+    Duration::from_secs(2 * 60)
+}
+
+fn default_vote_kick_cooldown() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
+fn default_status_refresh_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_status_min_interval() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub struct HouseholdCfg {
     pub name: String,
     /// Comma-separated list of member user IDs.
@@ -54,8 +169,163 @@ pub struct HouseholdCfg {
     pub members: String,
 }
 
+/// One rule of a `router` middleware. An event matching every condition that is set gets a
+/// message sent to the destination: a room (`room_id`), a user by DM (`user_id`), everyone
+/// subscribed to a topic by DM (`topic`), or a room mentioning them (`room_id` and `topic`).
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct RouteRuleCfg {
+    /// Event kind to match, e.g. `room_message` or `reaction_added`.
+    #[serde(default)]
+    pub event_kind: Option<String>,
+    #[serde(default)]
+    pub source_service_id: Option<String>,
+    #[serde(default)]
+    pub source_room_id: Option<String>,
+    #[serde(default)]
+    pub sender_id: Option<String>,
+    /// Text the message must contain, ignoring case.
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// Regular expression the message must match.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    pub service_id: String,
+    #[serde(default)]
+    pub room_id: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Subscription topic whose subscribers on `service_id` are notified.
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Message to send. `{{body}}`, `{{sender}}`, `{{sender_id}}`, `{{room}}`, `{{service}}`
+    /// and `{{event_kind}}` are filled in from the event.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// How a `gamestatus` middleware polls a game server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GameProtocolCfg {
+    /// Valve's A2S_INFO query, for Source engine games and most Steam dedicated servers.
+    A2s,
+    /// The Minecraft Java Edition server list ping.
+    Minecraft,
+}
+
+/// One server polled by a `gamestatus` middleware.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct GameServerCfg {
+    pub protocol: GameProtocolCfg,
+    /// `host` or `host:port`; the port defaults to the protocol's usual one.
+    pub address: String,
+    /// Post an alert when the server goes down and when it's back.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub watch: bool,
+}
+
+/// Where a `streamannounce` channel streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamPlatformCfg {
+    Twitch,
+    Youtube,
+}
+
+/// One channel watched by a `streamannounce` middleware.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct StreamChannelCfg {
+    pub platform: StreamPlatformCfg,
+    /// The Twitch login, or the YouTube channel ID (`UC...`).
+    pub channel: String,
+}
+
+/// The forge a `ticketbridge` repository is hosted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ForgeCfg {
+    Github,
+    /// Gitea or Forgejo; needs a `base_url`.
+    Gitea,
+}
+
+/// A room a `trivia` middleware plays in.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct TriviaRoomCfg {
+    pub service_id: String,
+    pub room_id: String,
+}
+
+/// What an `impersonationguard` middleware does about a name that looks like a protected one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImpersonationActionCfg {
+    /// Post to the alert room.
+    #[default]
+    Alert,
+    /// Kick the user, and post to the alert room if one is set.
+    Kick,
+}
+
+/// A room an `inactivitypruner` middleware prunes.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct PrunedRoomCfg {
+    pub service_id: String,
+    pub room_id: String,
+}
+
+/// What an `inactivitypruner` middleware does about inactive members.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneActionCfg {
+    /// List them in the report room, as a dry run.
+    #[default]
+    Report,
+    /// Kick them, and list who was kicked in the report room if one is set.
+    Kick,
+}
+
+/// A repository a `ticketbridge` middleware files issues in, and the room they come from.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct TicketRepoCfg {
+    pub forge: ForgeCfg,
+    /// `owner/name`.
+    pub repository: String,
+    /// Where the Gitea instance is, e.g. `https://git.example.com`.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    pub token: String,
+    pub room_id: String,
+}
+
+/// Where a `releasetracker` package is published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageSourceCfg {
+    CratesIo,
+    DockerHub,
+    Github,
+}
+
+/// One package watched by a `releasetracker` middleware.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct PackageCfg {
+    pub source: PackageSourceCfg,
+    /// The crate name, Docker Hub repository (`nginx` or `owner/image`) or GitHub `owner/repo`.
+    pub package: String,
+    /// Announce prereleases (e.g. `2.0.0-rc.1`) too.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub prereleases: bool,
+}
+
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "kind", rename_all = "lowercase")]
 pub enum MiddlewareKind {
     Echo {
@@ -65,9 +335,96 @@ pub enum MiddlewareKind {
         command_string: String,
         uses_allowed: Option<u32>,
         #[serde(default, with = "humantime_serde")]
+        #[schemars(with = "Option<String>")]
         expiry: Option<Duration>,
+        // How long the DM carrying a token is kept before it's deleted
+        #[serde(default, with = "humantime_serde")]
+        #[schemars(with = "Option<String>")]
+        message_expiry: Option<Duration>,
     },
     Logger {},
+    BusAdmin {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    Audit {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    Pipeline {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    Reload {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    // Simulated service failures; debug builds only
+    Chaos {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    Tap {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    FeatureFlags {
+        command_string: String,
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    Prefs {
+        command_string: String,
+    },
+    Settings {
+        command_string: String,
+        // Who may change a room's settings; anyone may view them
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        moderator_user_ids: Option<Vec<String>>,
+    },
+    Ping {
+        command_string: String,
+    },
+    Who {
+        command_string: String,
+        // The voice service to list, e.g. a Mumble service
+        service_id: String,
+    },
+    VerificationApproval {
+        // The admin the emoji of a device verification are sent to, and who answers them
+        service_id: String,
+        user_id: String,
+    },
+    DmRelay {
+        // Where the DMs to the bot that are relayed come from, e.g. a Mumble service
+        source_service_id: String,
+        // Who they're relayed to, and whose replies are relayed back
+        dest_service_id: String,
+        dest_user_id: String,
+    },
+    StatusTopic {
+        // The room whose topic shows the status
+        service_id: String,
+        room_id: String,
+        // The voice service whose users are counted, e.g. a Mumble service
+        voice_service_id: String,
+        // Topic with {{voice_count}} and {{last_relay}} placeholders
+        #[serde(default)]
+        template: Option<String>,
+        #[serde(default = "default_status_refresh_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        refresh_interval: Duration,
+        // Least time between topic changes, since each one shows in the room's timeline
+        #[serde(default = "default_status_min_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        min_interval: Duration,
+    },
     MovieShowtimes {
         service_id: String,
         room_id: String,
@@ -81,6 +438,9 @@ pub enum MiddlewareKind {
         theater_id_filter: Option<Vec<String>>,
         #[serde(default)]
         command_string: Option<String>,
+        // IANA time zone the schedule is evaluated in; defaults to the host's
+        #[serde(default)]
+        timezone: Option<String>,
     },
     AttendanceRelay {
         source_service_id: String,
@@ -91,6 +451,17 @@ pub enum MiddlewareKind {
         session_end_message: String,
         session_ended_edit_message: String,
     },
+    VoiceSessions {
+        source_service_id: String,
+        report_service_id: String,
+        report_room_id: String,
+        // When the monthly summary is posted on the first, e.g. "09:00"; defaults to 09:00
+        #[serde(default)]
+        summary_time: Option<String>,
+        // IANA time zone the schedule is evaluated in; defaults to the host's
+        #[serde(default)]
+        timezone: Option<String>,
+    },
     ChatRelay {
         source_service_id: String,
         source_room_id: Option<String>,
@@ -103,6 +474,21 @@ pub enum MiddlewareKind {
         thumbnail_max_height: u32,
         #[serde(default = "default_thumbnail_jpeg_quality")]
         thumbnail_jpeg_quality: u8,
+        // Collect text messages and post them as one digest per window, e.g. "60s"
+        #[serde(default, with = "humantime_serde")]
+        #[schemars(with = "Option<String>")]
+        digest_window: Option<Duration>,
+        // What to do with messages sent while the bot was offline
+        #[serde(default)]
+        catch_up: CatchUpMode,
+        // Most missed messages relayed with `catch_up = relay`; defaults to 20
+        #[serde(default)]
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        catch_up_limit: Option<usize>,
+        // Drop messages whose content was relayed to the destination this recently, e.g. "30s"
+        #[serde(default, with = "humantime_serde")]
+        #[schemars(with = "Option<String>")]
+        loop_window: Option<Duration>,
     },
     EzStreamAnnounce {
         websocket_url: String,
@@ -130,20 +516,328 @@ pub enum MiddlewareKind {
         finalization_no_votes_message: String,
         #[serde(default)]
         households: HashMap<String, HouseholdCfg>,
+        // IANA time zone the schedule is evaluated in; defaults to the host's
+        #[serde(default)]
+        timezone: Option<String>,
+    },
+    UpdateNotifier {
+        service_id: String,
+        // Exactly one of room_id or admin_user_id says where notifications go
+        #[serde(default)]
+        room_id: Option<String>,
+        #[serde(default)]
+        admin_user_id: Option<String>,
+        #[serde(default = "default_update_repository")]
+        repository: String,
+        #[serde(default = "default_update_check_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        check_interval: Duration,
+    },
+    ScheduledBackup {
+        #[serde(with = "humantime_serde")]
+        #[schemars(with = "String")]
+        interval: Duration,
+        // Where backups are written; defaults to <data_directory>/backups
+        #[serde(default)]
+        directory: Option<PathBuf>,
+        // How many backups are kept in the directory
+        #[serde_as(as = "DisplayFromStr")]
+        #[serde(default = "default_backups_kept")]
+        #[schemars(with = "String")]
+        keep: usize,
+        #[serde_as(as = "DisplayFromStr")]
+        #[serde(default)]
+        #[schemars(with = "String")]
+        include_matrix_store: bool,
+        // Each backup is also PUT to this URL joined with its file name, so end it with /
+        #[serde(default)]
+        #[schemars(with = "Option<String>")]
+        upload_url: Option<Url>,
+        // Sent as a bearer token with uploads
+        #[serde(default)]
+        upload_token: Option<String>,
+    },
+    Router {
+        #[serde(default)]
+        rules: HashMap<String, RouteRuleCfg>,
+    },
+    GameStatus {
+        command_string: String,
+        #[serde(default)]
+        servers: HashMap<String, GameServerCfg>,
+        #[serde(default = "default_game_poll_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        poll_interval: Duration,
+        // Where alerts about watched servers go
+        #[serde(default)]
+        alert_service_id: Option<String>,
+        #[serde(default)]
+        alert_room_id: Option<String>,
+    },
+    StreamAnnounce {
+        service_id: String,
+        room_id: String,
+        #[serde(default)]
+        channels: HashMap<String, StreamChannelCfg>,
+        // App credentials, needed for Twitch channels
+        #[serde(default)]
+        twitch_client_id: Option<String>,
+        #[serde(default)]
+        twitch_client_secret: Option<String>,
+        // Data API key, needed for YouTube channels
+        #[serde(default)]
+        youtube_api_key: Option<String>,
+        #[serde(default = "default_stream_poll_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        poll_interval: Duration,
+        // How long a channel must have been offline before a new stream is announced
+        #[serde(default = "default_stream_renotify_after", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        renotify_after: Duration,
+    },
+    TicketBridge {
+        command_string: String,
+        #[serde(default)]
+        repositories: HashMap<String, TicketRepoCfg>,
+        // Who may file issues and comment; everyone when unset
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        allowed_user_ids: Option<Vec<String>>,
+    },
+    ReleaseTracker {
+        service_id: String,
+        room_id: String,
+        #[serde(default)]
+        packages: HashMap<String, PackageCfg>,
+        #[serde(default = "default_release_poll_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        poll_interval: Duration,
+    },
+    VoteKick {
+        command_string: String,
+        // Votes a kick needs, counting whoever started the vote
+        #[serde_as(as = "DisplayFromStr")]
+        #[serde(default = "default_vote_kick_threshold")]
+        #[schemars(with = "String")]
+        threshold: usize,
+        #[serde(default = "default_vote_kick_duration", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        vote_duration: Duration,
+        // How long after a vote on someone ends before they can be voted on again
+        #[serde(default = "default_vote_kick_cooldown", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        cooldown: Duration,
+        // Who may change the settings and cancel votes; they can't be vote-kicked
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        admin_user_ids: Option<Vec<String>>,
+    },
+    ImpersonationGuard {
+        // Names nobody else may take, on top of the bot's own
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        protected_names: Option<Vec<String>>,
+        // Users who really go by a protected name, e.g. the admins
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        exempt_user_ids: Option<Vec<String>>,
+        // How similar, from 0 to 1, a name must be to a protected one to be flagged
+        #[serde_as(as = "DisplayFromStr")]
+        #[serde(default = "default_impersonation_similarity")]
+        #[schemars(with = "String")]
+        similarity: f64,
+        #[serde(default)]
+        action: ImpersonationActionCfg,
+        // Where alerts go; needed unless action is kick
+        #[serde(default)]
+        alert_service_id: Option<String>,
+        #[serde(default)]
+        alert_room_id: Option<String>,
+    },
+    AutoResponder {
+        command_string: String,
+        // IANA time zone the once-a-day replies follow; defaults to the host's
+        #[serde(default)]
+        timezone: Option<String>,
+    },
+    Faq {
+        command_string: String,
+        // Who may set and delete entries
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        editor_user_ids: Option<Vec<String>>,
+    },
+    Picker {
+        // Picks one of the options given, e.g. !pick
+        command_string: String,
+        // Picks from the room's saved list, e.g. !lunch
+        list_command_string: String,
+    },
+    InactivityPruner {
+        // Rooms the bot administers
+        #[serde(default)]
+        rooms: HashMap<String, PrunedRoomCfg>,
+        // How long without posting or reacting before a member is pruned
+        #[serde(default = "default_inactive_after", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        inactive_after: Duration,
+        #[serde(default = "default_inactivity_check_interval", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        check_interval: Duration,
+        #[serde(default)]
+        action: PruneActionCfg,
+        // Where reports go; needed unless action is kick
+        #[serde(default)]
+        report_service_id: Option<String>,
+        #[serde(default)]
+        report_room_id: Option<String>,
+        // Members never pruned, e.g. the admins
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        exempt_user_ids: Option<Vec<String>>,
+    },
+    Subscriptions {
+        // Subscribes to a topic, or lists your subscriptions, e.g. !subscribe
+        command_string: String,
+        // e.g. !unsubscribe
+        unsubscribe_command_string: String,
+        // Topics users may subscribe to; any topic if unset
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        topics: Option<Vec<String>>,
+    },
+    Trivia {
+        command_string: String,
+        // Rooms that play together, e.g. both sides of a chat relay
+        #[serde(default)]
+        rooms: HashMap<String, TriviaRoomCfg>,
+        // TOML file of questions; they come from Open Trivia DB when unset
+        #[serde(default)]
+        questions_file: Option<PathBuf>,
+        // Open Trivia DB category ID, e.g. 9 for general knowledge; any when unset
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        #[serde(default)]
+        #[schemars(with = "Option<String>")]
+        category: Option<u32>,
+        #[serde_as(as = "DisplayFromStr")]
+        #[serde(default = "default_trivia_questions_per_round")]
+        #[schemars(with = "String")]
+        questions_per_round: usize,
+        #[serde(default = "default_trivia_answer_time", with = "humantime_serde")]
+        #[schemars(with = "String")]
+        answer_time: Duration,
+        // A round every week, e.g. "friday 8pm"
+        #[serde(default)]
+        schedule: Option<String>,
+        // IANA time zone the schedule is in; defaults to the host's
+        #[serde(default)]
+        timezone: Option<String>,
+    },
+    WordFilter {
+        // Masked in message bodies before later middlewares in the pipeline see them
+        #[serde(default, deserialize_with = "deserialize_string_list")]
+        words: Option<Vec<String>>,
     },
     #[serde(other)]
+    #[schemars(skip)]
     Unknown,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct Config {
     pub services: HashMap<String, ServiceCfg>, // key = service name
     #[serde(default)]
     pub middlewares: HashMap<String, MiddlewareCfg>, // key = middleware name
+    // Middlewares applied to every service's events ahead of its own pipeline
+    #[serde(default, deserialize_with = "deserialize_middleware_list")]
+    pub global_middleware: Option<Vec<String>>,
     #[serde(default = "default_data_directory")]
     pub data_directory: PathBuf,
     #[serde(default)]
     pub reconnection: ReconnectionConfig,
+    // Durable queue for sends that hit an unavailable service; disabled when absent
+    #[serde(default)]
+    pub outbox: Option<OutboxConfig>,
+    // Leader election between redundant instances; every instance leads when absent
+    #[serde(default)]
+    pub coordination: Option<CoordinationConfig>,
+    // Record of every command the bus dispatches; disabled when absent
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    // Reports, and optionally aborts on, a bus loop that stops making progress; off when absent
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+    #[serde(default)]
+    pub command_dispatch: CommandDispatch,
+    // Window during which scheduled posts and non-urgent relays are deferred
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHoursConfig>,
+    // Messages the bus posts once every service is ready and before shutting down
+    #[serde(default)]
+    pub lifecycle_announcements: Option<LifecycleAnnouncementsConfig>,
+    #[serde(default)]
+    pub panic_guard: PanicGuardConfig,
+    // How long a middleware's on_event may take before the bus warns about it; defaults to 50ms
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub middleware_latency_budget: Option<Duration>,
+    // How long an event may take from its service to the end of its pipeline before the bus
+    // warns about it; defaults to 1s
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub event_lag_warning: Option<Duration>,
+    // Extra patterns masked in logs and transcripts, on top of configured credentials
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+    // Log levels, format and optional rotated log files
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    // Report errors and panics to Sentry
+    #[serde(default)]
+    pub error_reporting: Option<ErrorReportingConfig>,
+    // Room or user told about the bot's own failures, e.g. a service that keeps crashing
+    #[serde(default)]
+    pub operator_alerts: Option<OperatorAlertsConfig>,
+    // Rooms, members and display names the bus tracks for middlewares
+    #[serde(default)]
+    pub roster: RosterConfig,
+    // Relayed attachments kept on disk and optionally served; disabled when absent
+    #[serde(default)]
+    pub media: Option<MediaConfig>,
+    // Where messages too long for their destination are uploaded; the media server when absent
+    #[serde(default)]
+    pub paste: Option<PasteConfig>,
+    // Proxy, TLS, rate limits and caching for the HTTP requests middlewares make
+    #[serde(default)]
+    pub http: HttpConfig,
+    // Short commands standing for longer ones, e.g. gn = "!event create friday 19:00 Game Night"
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
+impl Config {
+    /// No services or middlewares and every setting at its default, as read from a file with
+    /// only an empty `[services]` table.
+    pub fn empty() -> Self {
+        toml::from_str("[services]").expect("an empty config always parses")
+    }
+}
+
+/// How a pipeline treats two middlewares that register the same command string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandDispatch {
+    /// Conflicting command strings in one pipeline are a startup error.
+    #[default]
+    Unique,
+    /// Conflicts are allowed; only the first middleware in the pipeline that registers a
+    /// command receives messages invoking it.
+    FirstMatch,
+}
+
+/// What a chat relay does with messages sent while the bot was offline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUpMode {
+    #[default]
+    Skip,
+    /// Relay the most recent ones, up to `catch_up_limit`.
+    Relay,
+    /// Post how many there were.
+    Summarize,
 }
 
 fn default_data_directory() -> PathBuf {
@@ -163,11 +857,13 @@ fn default_thumbnail_jpeg_quality() -> u8 {
 }
 
 // Reconnection configuration with exponential backoff
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub struct ReconnectionConfig {
     #[serde(default = "default_initial_delay", with = "humantime_serde")]
+    #[schemars(with = "String")]
     pub initial_delay: Duration,
     #[serde(default = "default_max_delay", with = "humantime_serde")]
+    #[schemars(with = "String")]
     pub max_delay: Duration,
     #[serde(default = "default_multiplier")]
     pub multiplier: f64,
@@ -202,6 +898,289 @@ fn default_jitter_factor() -> f64 {
     0.1
 }
 
+// How the bus reacts to a middleware panicking while handling an event
+#[serde_as]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct PanicGuardConfig {
+    /// Disable a middleware after it has panicked this many times; never when unset.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub disable_after: Option<u32>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct RosterConfig {
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct LifecycleAnnouncementsConfig {
+    /// Posted once every service reports ready, e.g. "KelvinBot is back online".
+    #[serde(default)]
+    pub startup_message: Option<String>,
+    /// Posted during graceful shutdown, before services disconnect.
+    #[serde(default)]
+    pub shutdown_message: Option<String>,
+    #[serde(default)]
+    pub destinations: HashMap<String, AnnouncementDestination>,
+}
+
+// Daily quiet hours, as HH:MM local times; the window may wrap past midnight
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct QuietHoursConfig {
+    pub start: String,
+    pub end: String,
+    /// IANA time zone `start` and `end` are in. Defaults to the middleware's `timezone`, or the
+    /// host's zone for middlewares without one.
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+// Daily window, as HH:MM times, during which a service is connected; may wrap past midnight
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct ConnectionScheduleConfig {
+    /// Days the window opens on (e.g. `Friday`). Every day when unset.
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub days: Option<Vec<String>>,
+    pub start: String,
+    pub end: String,
+    /// IANA time zone `start` and `end` are in. Defaults to the host's.
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+// Outbound message queue configuration
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct OutboxConfig {
+    /// How long a queued command stays deliverable before it's discarded.
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub ttl: Duration,
+    #[serde(default = "default_outbox_flush_interval", with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub flush_interval: Duration,
+}
+
+fn default_outbox_flush_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+// Leader election between redundant instances
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct CoordinationConfig {
+    /// SQLite file holding the lease, somewhere every instance can reach, e.g. a shared volume.
+    pub lease_file: PathBuf,
+    /// Names this instance in the lease. Defaults to the host name and process ID.
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    /// How long the leader's lease lasts without being renewed, so how long a standby waits
+    /// before taking over from a leader that stopped.
+    #[serde(default = "default_lease_ttl", with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub lease_ttl: Duration,
+}
+
+fn default_lease_ttl() -> Duration {
+    Duration::from_secs(30)
+}
+
+// Command audit trail configuration
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct AuditConfig {
+    /// How long entries are kept before they're pruned.
+    #[serde(default = "default_audit_retention", with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub retention: Duration,
+}
+
+fn default_audit_retention() -> Duration {
+    Duration::from_secs(90 * 24 * 60 * 60)
+}
+
+// Bus loop watchdog configuration
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct WatchdogConfig {
+    /// How long the bus loop may go without making progress before it's reported as stalled.
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub stall_after: Duration,
+    /// Abort the process once the loop stalls, so a supervisor (systemd, Docker) restarts it.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub abort: bool,
+}
+
+// Attachment storage configuration
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct MediaConfig {
+    /// How long attachments, and links to them, last before they're deleted.
+    #[serde(default = "default_media_ttl", with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub ttl: Duration,
+    /// Address the HTTP server serving attachments listens on, e.g. `0.0.0.0:8080`.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub listen_address: Option<SocketAddr>,
+    /// URL the HTTP server is reachable at from outside, which links start with.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub public_url: Option<Url>,
+}
+
+fn default_media_ttl() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+// Outbound HTTP configuration
+#[serde_as]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct HttpConfig {
+    /// Proxy every request goes through, e.g. `http://proxy.internal:3128`.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// PEM certificate trusted on top of the system's, e.g. a private CA.
+    #[serde(default)]
+    pub ca_certificate: Option<PathBuf>,
+    /// Skip certificate checks. Only for testing against self-signed servers.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub accept_invalid_certs: bool,
+    /// How long a request may take before it fails. No limit when unset.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Per-host limits, as `host=requests per minute` (e.g. `api.github.com=30`).
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub rate_limits: Option<Vec<String>>,
+    /// How long cacheable responses are reused. Nothing is cached when unset.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub cache_ttl: Option<Duration>,
+}
+
+// Paste endpoint configuration
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct PasteConfig {
+    /// Endpoint long messages are `POST`ed to as plain text, answering with the URL of the paste.
+    #[schemars(with = "String")]
+    pub url: Url,
+}
+
+// Secrets redaction configuration
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct RedactionConfig {
+    /// Regexes whose matches are masked. When a pattern has a capture group, only the first
+    /// group is masked, e.g. `password=(\S+)` keeps the `password=` prefix.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+// Log output configuration
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct LoggingConfig {
+    /// Level for targets without an entry in `targets`. Defaults to `warn`.
+    #[serde(default)]
+    pub level: Option<String>,
+    /// Levels for specific targets, e.g. `kelvin_bot = "info"`. `RUST_LOG` directives are
+    /// applied on top of these.
+    #[serde(default)]
+    pub targets: HashMap<String, String>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Also write logs to daily rotated files.
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct LogFileConfig {
+    /// Defaults to `<data_directory>/logs`.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// File names are this prefix followed by the date, e.g. `kelvin.log.2024-03-04`.
+    #[serde(default = "default_log_file_prefix")]
+    pub prefix: String,
+    /// Files older than this are deleted as new ones are started.
+    #[serde(default = "default_log_retention", with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub retention: Duration,
+    /// Defaults to the stdout format.
+    #[serde(default)]
+    pub format: Option<LogFormat>,
+}
+
+fn default_log_file_prefix() -> String {
+    "kelvin.log".to_string()
+}
+
+fn default_log_retention() -> Duration {
+    Duration::from_secs(14 * 24 * 60 * 60)
+}
+
+// Error reporting configuration
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct ErrorReportingConfig {
+    /// Sentry DSN of the project errors are reported to.
+    #[schemars(with = "String")]
+    pub dsn: SecretString,
+    /// Reported as the Sentry environment, e.g. `production`.
+    #[serde(default)]
+    pub environment: Option<String>,
+}
+
+// Operator alert configuration; alerts go to exactly one of `room_id` and `user_id`
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct OperatorAlertsConfig {
+    pub service_id: String,
+    #[serde(default)]
+    pub room_id: Option<String>,
+    /// Messaged directly, instead of posting in a room.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Alerts below this level are only logged. Defaults to `warning`.
+    #[serde(default)]
+    pub min_level: AlertLevel,
+    /// How long an identical alert is held back after it was sent, so a recurring failure
+    /// doesn't flood the room.
+    #[serde(default = "default_alert_repeat_after", with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub repeat_after: Duration,
+}
+
+fn default_alert_repeat_after() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+/// How urgent an operator alert is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertLevel {
+    /// Worth knowing, e.g. a service came back after failing.
+    Info,
+    /// Something failed, but the bot carried on, e.g. a middleware panicked.
+    #[default]
+    Warning,
+    /// Part of the bot stopped working, e.g. a service keeps failing to reconnect.
+    Critical,
+}
+
 // Helper for calculating exponential backoff delays
 pub struct ExponentialBackoff {
     config: ReconnectionConfig,
@@ -235,12 +1214,53 @@ impl ExponentialBackoff {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[serde_as]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct ServiceCfg {
     #[serde(flatten)]
     pub kind: ServiceKind,
     #[serde(default, deserialize_with = "deserialize_middleware_list")]
     pub middleware: Option<Vec<String>>, // List of middleware names
+    // Pipelines for specific rooms, replacing `middleware` for events from those rooms
+    #[serde(default)]
+    pub rooms: HashMap<String, RoomCfg>, // key = room ID
+    /// When set, only events from these rooms reach the middleware pipelines.
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub room_allowlist: Option<Vec<String>>,
+    /// Events from these rooms are dropped before the middleware pipelines. Takes precedence
+    /// over `room_allowlist`.
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub room_denylist: Option<Vec<String>>,
+    /// When set, the bus only keeps the service connected during this window.
+    #[serde(default)]
+    pub schedule: Option<ConnectionScheduleConfig>,
+    /// When false, the service isn't started. Defaults to true.
+    #[serde(default = "default_enabled")]
+    #[serde_as(as = "DisplayFromStr")]
+    pub enabled: bool,
+    /// Replaces the `!` commands start with on this service, e.g. `.` where `!` collides with
+    /// another bot.
+    #[serde(default)]
+    pub command_prefix: Option<String>,
+    /// When true, the bus drops every command that would post to or change anything on the
+    /// service, so the bot can lurk in a room without being able to write back. Defaults to
+    /// false.
+    #[serde(default)]
+    #[serde_as(as = "DisplayFromStr")]
+    pub read_only: bool,
+    /// How long the service may take to become ready each time it starts, e.g. while a Matrix
+    /// login waits on interactive verification, before the bus gives up on that attempt and
+    /// restarts it like a failed one. The other services carry on meanwhile. Unlimited when
+    /// unset.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub startup_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct RoomCfg {
+    #[serde(default, deserialize_with = "deserialize_middleware_list")]
+    pub middleware: Option<Vec<String>>,
 }
 
 fn deserialize_middleware_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
@@ -278,16 +1298,261 @@ where
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[serde_as]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct MiddlewareCfg {
     #[serde(flatten)]
     pub kind: MiddlewareKind,
+    /// When false, each service referencing this middleware gets its own instance.
+    /// Defaults to true (one instance shared by every pipeline).
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub shared: Option<bool>,
+    /// Overrides the global `quiet_hours` for this middleware.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHoursConfig>,
+    /// Command types (e.g. `send_room_message`) this middleware may send. The bus refuses
+    /// any other command it sends. Unrestricted when unset.
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub allowed_commands: Option<Vec<String>>,
+    /// When false, the middleware starts disabled: pipelines skip it and the bus drops its
+    /// commands until it is turned on at runtime. Defaults to true.
+    #[serde(default = "default_enabled")]
+    #[serde_as(as = "DisplayFromStr")]
+    pub enabled: bool,
+    /// When true, the bus logs the commands this middleware sends and records them in the
+    /// audit trail instead of dispatching them. Commands that only read state still go
+    /// through. Defaults to false.
+    #[serde(default)]
+    #[serde_as(as = "DisplayFromStr")]
+    pub dry_run: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl MiddlewareCfg {
+    pub fn is_shared(&self) -> bool {
+        self.shared.unwrap_or(true)
+    }
 }
 
-pub fn load_from_env() -> anyhow::Result<Config> {
+/// Loads config from the optional TOML file named by `KELVIN_CONFIG_FILE`, then from
+/// `KELVIN__...` environment variables, which override values from the file.
+///
+/// `profile` (or `KELVIN_PROFILE` when `None`) selects a `[profiles.<name>]` table from the
+/// file to layer over its base values.
+pub fn load_from_env(profile: Option<&str>) -> anyhow::Result<Config> {
     dotenvy::dotenv().ok(); // Load from .env file first
-    let cfg = config::Config::builder()
+    let profile = profile.map(str::to_string).or_else(|| std::env::var(PROFILE_ENV).ok());
+    let mut builder = config::Config::builder();
+    if let Ok(path) = std::env::var(CONFIG_FILE_ENV) {
+        let mut table = read_config_file(Path::new(&path), profile.as_deref())?;
+        interpolate_table(&mut table, &|name: &str| std::env::var(name).ok())
+            .with_context(|| format!("failed to interpolate config file {path}"))?;
+        builder = builder.add_source(config::File::from_str(
+            &toml::to_string(&table)?,
+            config::FileFormat::Toml,
+        ));
+    } else if let Some(profile) = &profile {
+        bail!("profile {profile} was requested but {CONFIG_FILE_ENV} is not set");
+    }
+    let cfg = builder
         .add_source(config::Environment::with_prefix(ENV_PREFIX).separator(ENV_SEPARATOR))
         .build()?;
     Ok(cfg.try_deserialize()?)
 }
+
+/// Reads the TOML config file at `path`, resolving its `include` list and applying `profile`.
+///
+/// Included files are layered beneath the file that includes them, so the including file's
+/// own values win. Include paths are relative to the including file and may use `*` in the
+/// file name (e.g. `middlewares/*.toml`); matches are read in name order. The selected
+/// `[profiles.<name>]` table is merged over everything else, and the `profiles` table itself
+/// is removed from the result.
+pub fn read_config_file(path: &Path, profile: Option<&str>) -> anyhow::Result<toml::Table> {
+    let mut table = read_with_includes(path, &mut Vec::new())?;
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => bail!("`profiles` in {} must be a table", path.display()),
+        None => toml::Table::new(),
+    };
+    if let Some(name) = profile {
+        match profiles.get(name) {
+            Some(toml::Value::Table(overlay)) => merge_tables(&mut table, overlay.clone()),
+            Some(_) => bail!("profile {name} must be a table"),
+            None => bail!("profile {name} is not defined in {}", path.display()),
+        }
+    }
+    Ok(table)
+}
+
+fn read_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("config file {} includes itself", path.display());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let mut own: toml::Table = contents
+        .parse()
+        .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+    let includes = match own.remove("include") {
+        None => Vec::new(),
+        Some(toml::Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                toml::Value::String(s) => Ok(s),
+                other => Err(anyhow!("include entries must be strings, got {other}")),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        Some(_) => bail!("`include` in {} must be an array of paths", path.display()),
+    };
+
+    stack.push(canonical);
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut table = toml::Table::new();
+    for pattern in &includes {
+        for included in expand_include(base_dir, pattern)? {
+            merge_tables(&mut table, read_with_includes(&included, stack)?);
+        }
+    }
+    stack.pop();
+
+    merge_tables(&mut table, own);
+    Ok(table)
+}
+
+fn expand_include(base_dir: &Path, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let full = base_dir.join(pattern);
+    let file_pattern = full.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if !file_pattern.contains('*') {
+        return Ok(vec![full]);
+    }
+
+    let dir = full.parent().unwrap_or(base_dir);
+    let mut matches = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read include directory {}", dir.display()))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file()
+            && name.to_str().is_some_and(|name| wildcard_match(file_pattern, name))
+        {
+            matches.push(entry.path());
+        }
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+/// Matches `name` against `pattern`, where `*` stands for any run of characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(remaining) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=remaining.len())
+                .filter(|&i| remaining.is_char_boundary(i))
+                .any(|i| wildcard_match(rest, &remaining[i..]))
+        }
+    }
+}
+
+/// Recursively merges `overlay` into `base`. Nested tables are merged key by key; any other
+/// value in `overlay` replaces the one in `base`.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(incoming)) => {
+                merge_tables(existing, incoming)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Replaces `${NAME}` references in every string value of a TOML document with the value
+/// returned by `lookup`. Keys are left alone, `$$` escapes a literal `$`, and an unset
+/// variable is an error rather than silently becoming an empty string.
+pub fn interpolate_env_in_toml(
+    contents: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    let mut table: toml::Table = contents.parse()?;
+    interpolate_table(&mut table, &lookup)?;
+    Ok(toml::to_string(&table)?)
+}
+
+fn interpolate_table(
+    table: &mut toml::Table,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    for value in table.values_mut() {
+        interpolate_value(value, lookup)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(
+    value: &mut toml::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(s) => *s = interpolate_env(s, lookup)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                interpolate_value(item, lookup)?;
+            }
+        }
+        toml::Value::Table(table) => interpolate_table(table, lookup)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_env(
+    input: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        output.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            output.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| anyhow!("unterminated ${{ in \"{input}\""))?;
+            let name = &after[..end];
+            let resolved =
+                lookup(name).ok_or_else(|| anyhow!("environment variable {name} is not set"))?;
+            output.push_str(&resolved);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// JSON schema describing `Config`, including every service and middleware kind.
+///
+/// Generated from the same serde definitions used to load config, so it can't drift from
+/// what the bot actually accepts.
+pub fn config_schema() -> serde_json::Value {
+    schemars::schema_for!(Config).to_value()
+}
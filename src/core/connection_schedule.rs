@@ -0,0 +1,68 @@
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+use crate::core::{config::ConnectionScheduleConfig, time_zone::resolve_time_zone};
+
+/// When a service should be connected: a daily window, on every day or only some.
+///
+/// The window may wrap past midnight (`22:00`–`02:00`), in which case it belongs to the day it
+/// opens on. A window whose start equals its end spans the whole day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSchedule {
+    // Days the window opens on; every day when empty
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Tz,
+}
+
+impl ConnectionSchedule {
+    pub fn new(days: Vec<Weekday>, start: NaiveTime, end: NaiveTime, timezone: Tz) -> Self {
+        Self { days, start, end, timezone }
+    }
+
+    pub fn from_config(cfg: &ConnectionScheduleConfig) -> Result<Self> {
+        let parse = |value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| {
+                anyhow!("invalid schedule time '{value}'. Expected format: HH:MM (e.g., 18:00)")
+            })
+        };
+        let days = cfg
+            .days
+            .iter()
+            .flatten()
+            .map(|day| {
+                day.parse::<Weekday>().map_err(|_| {
+                    anyhow!(
+                        "invalid schedule day '{day}'. Valid values: Monday, Tuesday, Wednesday, Thursday, Friday, Saturday, Sunday"
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        let timezone =
+            resolve_time_zone(cfg.timezone.as_deref()).context("invalid schedule timezone")?;
+        Ok(Self::new(days, parse(&cfg.start)?, parse(&cfg.end)?, timezone))
+    }
+
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn is_open_at<Z: TimeZone>(&self, at: &DateTime<Z>) -> bool {
+        let local = at.with_timezone(&self.timezone).naive_local();
+        let (day, time) = (local.weekday(), local.time());
+        if self.start == self.end {
+            self.opens_on(day)
+        } else if self.start < self.end {
+            self.opens_on(day) && self.start <= time && time < self.end
+        } else {
+            (time >= self.start && self.opens_on(day))
+                || (time < self.end && self.opens_on(day.pred()))
+        }
+    }
+
+    pub fn is_open_now(&self) -> bool {
+        self.is_open_at(&Utc::now())
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+
+use crate::core::{
+    bus::{Command, CommandSender},
+    event::{Event, EventKind},
+    service::ServiceId,
+};
+
+/// How long a conversation waits for each reply unless configured otherwise.
+pub const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A reply that ends the conversation early, compared case-insensitively.
+pub const CANCEL_WORD: &str = "cancel";
+
+// Replies received before the flow asks for them; more than this and the user is talking past it
+const PENDING_REPLIES: usize = 8;
+
+type SessionKey = (ServiceId, String);
+
+/// Why a conversation stopped before the flow finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationEnded {
+    /// The user didn't reply within the timeout.
+    TimedOut,
+    /// The user replied with `CANCEL_WORD`.
+    Cancelled,
+    /// The command channel closed, i.e. the bot is shutting down.
+    Closed,
+}
+
+impl fmt::Display for ConversationEnded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversationEnded::TimedOut => write!(f, "timed out waiting for a reply"),
+            ConversationEnded::Cancelled => write!(f, "cancelled by the user"),
+            ConversationEnded::Closed => write!(f, "command channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for ConversationEnded {}
+
+/// The multi-step DM dialogues a middleware has in progress, one per user per service.
+///
+/// A middleware keeps one of these, starts a `Conversation` from the DM that kicks off a flow
+/// and runs the flow on a task of its own. Its `on_event` hands every DM to `deliver` first, so
+/// replies reach the waiting flow instead of being treated as new commands:
+///
+/// ```ignore
+/// fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+///     if self.conversations.deliver(evt) {
+///         return Ok(Verdict::Continue);
+///     }
+///     if !is_start_command(evt) {
+///         return Ok(Verdict::Continue);
+///     }
+///     if let Some(mut conversation) = self.conversations.start(evt, &self.cmd_tx) {
+///         correlation::spawn(async move {
+///             let name = conversation.ask("What should the event be called?").await?;
+///             // ...
+///         });
+///     }
+///     Ok(Verdict::Continue)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Conversations {
+    sessions: Arc<Mutex<HashMap<SessionKey, mpsc::Sender<String>>>>,
+    reply_timeout: Duration,
+}
+
+impl Default for Conversations {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLY_TIMEOUT)
+    }
+}
+
+impl Conversations {
+    pub fn new(reply_timeout: Duration) -> Self {
+        Self { sessions: Arc::default(), reply_timeout }
+    }
+
+    /// Starts a conversation with the user `evt` is a DM from. `None` if `evt` isn't a DM or
+    /// that user already has a conversation with this middleware in progress.
+    pub fn start(&self, evt: &Event, cmd_tx: &CommandSender) -> Option<Conversation> {
+        let EventKind::DirectMessage { user_id, is_self: false, .. } = &evt.kind else {
+            return None;
+        };
+        let key = (evt.service_id.clone(), user_id.clone());
+        let mut sessions = self.lock_sessions();
+        if sessions.get(&key).is_some_and(|session| !session.is_closed()) {
+            return None;
+        }
+        let (reply_tx, replies) = mpsc::channel(PENDING_REPLIES);
+        sessions.insert(key.clone(), reply_tx);
+        Some(Conversation {
+            key,
+            replies,
+            cmd_tx: cmd_tx.clone(),
+            sessions: self.sessions.clone(),
+            reply_timeout: self.reply_timeout,
+        })
+    }
+
+    /// Hands `evt` to the conversation waiting on its sender. Returns whether it was consumed,
+    /// in which case the middleware shouldn't handle it any further.
+    pub fn deliver(&self, evt: &Event) -> bool {
+        let EventKind::DirectMessage { user_id, body, is_self: false, .. } = &evt.kind else {
+            return false;
+        };
+        let key = (evt.service_id.clone(), user_id.clone());
+        let sessions = self.lock_sessions();
+        let Some(session) = sessions.get(&key) else {
+            return false;
+        };
+        if let Err(e) = session.try_send(body.clone()) {
+            tracing::warn!(service_id=%evt.service_id, user_id=%user_id, error=%e, "dropping conversation reply");
+        }
+        true
+    }
+
+    /// Whether `user_id` on `service_id` has a conversation in progress.
+    pub fn is_active(&self, service_id: &ServiceId, user_id: &str) -> bool {
+        self.lock_sessions()
+            .get(&(service_id.clone(), user_id.to_string()))
+            .is_some_and(|session| !session.is_closed())
+    }
+
+    fn lock_sessions(&self) -> MutexGuard<'_, HashMap<SessionKey, mpsc::Sender<String>>> {
+        self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// One user's side of a dialogue. The conversation ends when this is dropped.
+pub struct Conversation {
+    key: SessionKey,
+    replies: mpsc::Receiver<String>,
+    cmd_tx: CommandSender,
+    sessions: Arc<Mutex<HashMap<SessionKey, mpsc::Sender<String>>>>,
+    reply_timeout: Duration,
+}
+
+impl Conversation {
+    pub fn service_id(&self) -> &ServiceId {
+        &self.key.0
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.key.1
+    }
+
+    /// Sends the user a DM without waiting for a reply.
+    pub async fn say(&self, body: impl Into<String>) -> Result<(), ConversationEnded> {
+        let command = Command::SendDirectMessage {
+            service_id: self.key.0.clone(),
+            user_id: self.key.1.clone(),
+            body: body.into(),
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            expires_after: None,
+            require_encryption: false,
+        };
+        self.cmd_tx.send(command).await.map_err(|_| ConversationEnded::Closed)
+    }
+
+    /// Waits for the user's next DM, trimmed.
+    pub async fn reply(&mut self) -> Result<String, ConversationEnded> {
+        let reply = match tokio::time::timeout(self.reply_timeout, self.replies.recv()).await {
+            Ok(Some(reply)) => reply.trim().to_string(),
+            Ok(None) => return Err(ConversationEnded::Closed),
+            Err(_) => return Err(ConversationEnded::TimedOut),
+        };
+        if reply.eq_ignore_ascii_case(CANCEL_WORD) {
+            return Err(ConversationEnded::Cancelled);
+        }
+        Ok(reply)
+    }
+
+    /// Sends `prompt` and waits for the answer.
+    pub async fn ask(&mut self, prompt: impl Into<String>) -> Result<String, ConversationEnded> {
+        self.say(prompt).await?;
+        self.reply().await
+    }
+
+    /// Asks the user to pick one of `options`, by number or by name (case-insensitively), and
+    /// returns its index. Asks again until the answer matches an option.
+    pub async fn choose(
+        &mut self,
+        prompt: &str,
+        options: &[&str],
+    ) -> Result<usize, ConversationEnded> {
+        let listed: Vec<String> =
+            options.iter().enumerate().map(|(i, option)| format!("{}. {option}", i + 1)).collect();
+        let mut message = format!("{prompt}\n{}", listed.join("\n"));
+        loop {
+            let answer = self.ask(message).await?;
+            let by_number =
+                answer.parse::<usize>().ok().filter(|n| (1..=options.len()).contains(n));
+            if let Some(n) = by_number {
+                return Ok(n - 1);
+            }
+            if let Some(index) =
+                options.iter().position(|option| option.eq_ignore_ascii_case(&answer))
+            {
+                return Ok(index);
+            }
+            message = format!(
+                "Please answer with a number from 1 to {} (or '{CANCEL_WORD}').",
+                options.len()
+            );
+        }
+    }
+}
+
+impl Drop for Conversation {
+    fn drop(&mut self) {
+        // No other conversation can have started for this user while this one was alive
+        self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&self.key);
+    }
+}
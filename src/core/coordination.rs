@@ -0,0 +1,105 @@
+use std::{path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::core::config::CoordinationConfig;
+use crate::core::migrations::{self, Migration};
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "create the lease table",
+    sql: "CREATE TABLE IF NOT EXISTS lease (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            holder TEXT NOT NULL,
+            expires_at INTEGER NOT NULL
+        );",
+}];
+
+/// A lease on leadership shared by redundant instances of the bot through one SQLite file.
+///
+/// Whoever holds an unexpired lease is the leader and renews it well before it runs out; the
+/// others stay on standby and take it over once it expires, e.g. because the leader crashed.
+pub struct Lease {
+    conn: Mutex<Connection>,
+    instance_id: String,
+    ttl: Duration,
+}
+
+impl Lease {
+    /// Open (or create) the lease database at `path`.
+    pub fn open(path: impl AsRef<Path>, instance_id: String, ttl: Duration) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut conn = Connection::open(path)?;
+        // The other instance may be renewing at the same moment
+        conn.busy_timeout(Duration::from_secs(5))?;
+        migrations::migrate(&mut conn, "lease", MIGRATIONS)?;
+        Ok(Self { conn: Mutex::new(conn), instance_id, ttl })
+    }
+
+    pub fn from_config(config: &CoordinationConfig) -> Result<Self> {
+        if config.lease_ttl < Duration::from_secs(1) {
+            bail!("coordination lease_ttl must be at least 1s");
+        }
+        let instance_id = config.instance_id.clone().unwrap_or_else(default_instance_id);
+        Self::open(&config.lease_file, instance_id, config.lease_ttl)
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// How often the lease should be renewed: a few times per TTL, so one slow renewal doesn't
+    /// let it lapse.
+    pub fn renew_interval(&self) -> Duration {
+        self.ttl / 3
+    }
+
+    /// Takes the lease as of `now` if it's free or expired, or renews it if this instance
+    /// already holds it. Returns whether this instance is the leader.
+    pub fn try_acquire(&self, now: DateTime<Utc>) -> Result<bool> {
+        let expires_at = now + chrono::Duration::from_std(self.ttl)?;
+        let changed = self.conn().execute(
+            "INSERT INTO lease (id, holder, expires_at) VALUES (1, ?1, ?2)
+             ON CONFLICT (id) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+             WHERE lease.holder = excluded.holder OR lease.expires_at <= ?3",
+            params![self.instance_id, expires_at.timestamp_millis(), now.timestamp_millis()],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Who holds an unexpired lease as of `now`, if anyone.
+    pub fn holder(&self, now: DateTime<Utc>) -> Result<Option<String>> {
+        let holder = self
+            .conn()
+            .query_row(
+                "SELECT holder FROM lease WHERE id = 1 AND expires_at > ?1",
+                params![now.timestamp_millis()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(holder)
+    }
+
+    /// Gives up the lease if this instance holds it, so a standby can take over right away
+    /// instead of waiting for it to expire.
+    pub fn release(&self) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM lease WHERE id = 1 AND holder = ?1", params![self.instance_id])?;
+        Ok(())
+    }
+}
+
+/// The host name (e.g. the container's, from `HOSTNAME`) and process ID, which tells apart
+/// instances sharing a host as well as ones on different hosts.
+pub fn default_instance_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "kelvin-bot".to_string());
+    format!("{host}-{}", std::process::id())
+}
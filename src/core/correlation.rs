@@ -0,0 +1,58 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+tokio::task_local! {
+    static CURRENT: CorrelationId;
+}
+
+/// Identifies one inbound event and everything done in response to it: the middlewares that
+/// handled it, the tasks they spawned and the commands they sent. Unique within a run of the
+/// bot, and logged as `correlation_id` so a relay can be followed across the async hops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    /// A fresh ID, for an event that just arrived.
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The ID of the event the current task is handling, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|id| *id).ok()
+    }
+
+    /// Runs `f` (e.g. a middleware's `on_event`) as part of handling this event.
+    pub fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self, f)
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06x}", self.0)
+    }
+}
+
+/// Like `tokio::spawn`, but the task carries on the caller's correlation ID and tracing span,
+/// so its logs and the commands it sends are tied to the event that prompted them.
+///
+/// Middlewares should spawn with this rather than `tokio::spawn`.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = future.in_current_span();
+    match CorrelationId::current() {
+        Some(id) => tokio::spawn(CURRENT.scope(id, future)),
+        None => tokio::spawn(future),
+    }
+}
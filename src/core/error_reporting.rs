@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use secrecy::ExposeSecret;
+use sentry::{
+    ClientInitGuard, ClientOptions,
+    integrations::tracing::EventFilter,
+    protocol::{Breadcrumb, Context as EventContext, Event, Value},
+};
+use tracing::Level;
+use tracing_subscriber::Layer;
+
+use crate::core::{config::ErrorReportingConfig, redact};
+
+/// Starts the Sentry client. Panics are reported by its panic hook, and errors logged through
+/// `layer` become events carrying the log line's fields (service_id, middleware, event_kind).
+///
+/// Reporting stops when the returned guard is dropped, after pending events are flushed.
+pub fn init(cfg: &ErrorReportingConfig) -> Result<ClientInitGuard> {
+    let dsn = cfg.dsn.expose_secret().parse().context("invalid error reporting DSN")?;
+    Ok(sentry::init(ClientOptions {
+        dsn: Some(dsn),
+        environment: cfg.environment.clone().map(Into::into),
+        release: sentry::release_name!(),
+        before_send: Some(Arc::new(|event| Some(redact_event(event)))),
+        before_breadcrumb: Some(Arc::new(|breadcrumb| Some(redact_breadcrumb(breadcrumb)))),
+        ..Default::default()
+    }))
+}
+
+/// A tracing layer that reports `error` events to Sentry and keeps `warn` and `info` events as
+/// breadcrumbs leading up to them.
+pub fn layer<S>() -> impl Layer<S> + Send + Sync
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    sentry::integrations::tracing::layer().event_filter(|metadata| match *metadata.level() {
+        Level::ERROR => EventFilter::Event,
+        Level::WARN | Level::INFO => EventFilter::Breadcrumb,
+        _ => EventFilter::Ignore,
+    })
+}
+
+/// Masks secrets in the text an event carries before it leaves the process.
+pub fn redact_event(mut event: Event<'static>) -> Event<'static> {
+    event.message = event.message.map(|message| redact::redact(&message));
+    if let Some(logentry) = &mut event.logentry {
+        logentry.message = redact::redact(&logentry.message);
+    }
+    for exception in event.exception.values.iter_mut() {
+        exception.value = exception.value.as_deref().map(redact::redact);
+    }
+    event.extra.values_mut().for_each(redact_value);
+    for context in event.contexts.values_mut() {
+        if let EventContext::Other(fields) = context {
+            fields.values_mut().for_each(redact_value);
+        }
+    }
+    event.breadcrumbs.values =
+        std::mem::take(&mut event.breadcrumbs.values).into_iter().map(redact_breadcrumb).collect();
+    event
+}
+
+fn redact_breadcrumb(mut breadcrumb: Breadcrumb) -> Breadcrumb {
+    breadcrumb.message = breadcrumb.message.map(|message| redact::redact(&message));
+    breadcrumb.data.values_mut().for_each(redact_value);
+    breadcrumb
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = redact::redact(text),
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        Value::Object(fields) => fields.values_mut().for_each(redact_value),
+        _ => {}
+    }
+}
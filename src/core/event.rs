@@ -1,24 +1,96 @@
-use std::{fmt, sync::Arc};
+use std::{fmt, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
 use crate::core::service::ServiceId;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
     pub username: String,
     pub display_name: String,
     pub is_active: bool,
     pub is_self: bool,
+    /// Free text the user shows on their profile, on services that have it (a Mumble comment).
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// The user's avatar image as the service stores it (a Mumble texture), usually a PNG or
+    /// JPEG.
+    #[serde(default)]
+    pub avatar: Option<Arc<[u8]>>,
+}
+
+/// Who originally wrote a message that a bridge (this bot or another) posted on their behalf.
+/// Carried as hidden metadata on relayed messages where the service supports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Service the message was first posted on, as named in the relaying bot's config.
+    pub service_id: String,
+    pub sender_id: String,
+    pub sender_display_name: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A room message posted while the bot was offline, reported in `EventKind::MissedMessages`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissedMessage {
+    pub sender_id: String,
+    pub sender_display_name: Option<String>,
+    pub body: String,
+    #[serde(default)]
+    pub relayed_from: Option<Provenance>,
+}
+
+/// Voice states reported by `EventKind::VoiceStateChanged`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoiceState {
+    /// The user is heard over everyone else in the room.
+    PrioritySpeaker,
+    /// The user is recording the room.
+    Recording,
+    /// The user hears the room without being in it (a Mumble channel listener).
+    Listening,
+    /// The user is transmitting voice in the room.
+    Talking,
+}
+
+impl VoiceState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VoiceState::PrioritySpeaker => "priority_speaker",
+            VoiceState::Recording => "recording",
+            VoiceState::Listening => "listening",
+            VoiceState::Talking => "talking",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub service_id: ServiceId,
     pub kind: EventKind,
 }
 
+/// Every value `EventKind::name` can return.
+pub const EVENT_KIND_NAMES: [&str; 17] = [
+    "direct_message",
+    "room_message",
+    "user_list_update",
+    "user_list_diff",
+    "reaction_added",
+    "reaction_removed",
+    "room_image",
+    "knock",
+    "room_upgraded",
+    "missed_messages",
+    "user_profile_changed",
+    "voice_state_changed",
+    "verification_requested",
+    "connection_scheduled",
+    "service_ready",
+    "service_degraded",
+    "service_stopped",
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EventKind {
     DirectMessage {
@@ -36,10 +108,36 @@ pub enum EventKind {
         sender_id: String,
         sender_display_name: Option<String>,
         is_self: bool,
+        /// Whether the message mentions the bot, e.g. `@kelvin echo hi`. Only set by services
+        /// that can tell; missing from older event logs.
+        #[serde(default)]
+        mentions_self: bool,
+        /// Set when the message carries a relay marker, i.e. a bridge posted it for someone
+        /// else. `is_self` is still true when that bridge was this bot.
+        #[serde(default)]
+        relayed_from: Option<Provenance>,
+        /// ID of the message starting the thread this one was posted in, for services with
+        /// threads. Replies go there with `Command::SendThreadReply`.
+        #[serde(default)]
+        thread_root_id: Option<String>,
+        /// The service's ID for the message (a Matrix event ID), for services that have them.
+        /// Relays map it to the IDs of their copies in `bridge_map`.
+        #[serde(default)]
+        message_id: Option<String>,
     },
     UserListUpdate {
         users: Vec<User>,
     },
+    /// How the user list changed since the service's last one, by user ID. The bus sends one
+    /// after every `UserListUpdate` that changed something, and a `UserListUpdate` after every
+    /// diff a service emits, so middlewares can follow whichever suits them.
+    UserListDiff {
+        joined: Vec<User>,
+        left: Vec<User>,
+        /// Users listed before and after whose details (e.g. `is_active`) changed, as they are
+        /// now.
+        changed: Vec<User>,
+    },
     ReactionAdded {
         room_id: String,
         event_id: String,
@@ -71,6 +169,197 @@ pub enum EventKind {
         /// the relay uses these directly instead of re-fetching via source_url.
         image_data: Option<Arc<[u8]>>,
     },
+    /// Someone asked to join a room that only lets people in on request, e.g. a Matrix room
+    /// with a knock join rule. Approve it with `Command::ApproveKnock`.
+    Knock {
+        room_id: String,
+        /// Who wants in. The bot itself when it had to ask to join a room (`is_self`).
+        user_id: String,
+        sender_display_name: Option<String>,
+        reason: Option<String>,
+        is_self: bool,
+    },
+    /// A room the bot is in was replaced by a new one (e.g. a Matrix room upgrade). The bot has
+    /// already moved over, and commands addressed to the old room go to the new one.
+    RoomUpgraded {
+        old_room_id: String,
+        new_room_id: String,
+    },
+    /// Room messages posted since the last one the bot processed in a room, reported once when
+    /// it comes back online so relays can catch up. Oldest first; the bot's own messages are
+    /// left out.
+    MissedMessages {
+        room_id: String,
+        messages: Vec<MissedMessage>,
+        /// Set when the service gave up looking before it found where it left off, so more
+        /// messages were missed than are listed.
+        truncated: bool,
+    },
+    /// A user's comment or avatar changed, or arrived after the user list that first listed
+    /// them (services may fetch large ones separately).
+    UserProfileChanged {
+        user: User,
+    },
+    /// A user switched on or off a voice state others in the room may want to know about, e.g.
+    /// started recording it, listening in from another channel or talking. Reported by Mumble.
+    VoiceStateChanged {
+        /// The room the state applies to: the one listened to for `Listening`, otherwise the
+        /// one the user is in.
+        room_id: String,
+        user_id: String,
+        sender_display_name: Option<String>,
+        state: VoiceState,
+        active: bool,
+        is_self: bool,
+    },
+    /// The service is verifying its device against another of the bot account's devices and
+    /// waits for someone to compare the emoji shown on both. Answer with
+    /// `Command::ConfirmVerification`. Reported by Matrix when set up to wait for approval.
+    VerificationRequested {
+        flow_id: String,
+        /// The device the bot is verifying against.
+        other_device_id: String,
+        /// The emoji to compare, each with its name, e.g. `🐶 Dog`.
+        emoji: Vec<String>,
+    },
+    /// The bus connected or disconnected the service because its connection schedule opened
+    /// or closed. Sent through the service's pipeline by the bus, not the service.
+    ConnectionScheduled {
+        connected: bool,
+    },
+    /// The service connected and can deliver commands, at startup or after reconnecting. Sent
+    /// through the service's pipeline by the bus, like the two below.
+    ServiceReady,
+    /// The service dropped out unexpectedly and the bus will reconnect it after `retry_in`.
+    ServiceDegraded {
+        /// Why it dropped out; `None` if it just exited.
+        error: Option<String>,
+        /// Reconnection attempts so far, counting this one.
+        attempt: u32,
+        retry_in: Duration,
+    },
+    /// The service was disconnected on purpose and won't reconnect on its own, e.g. because its
+    /// connection schedule closed. Not sent at shutdown, once middlewares have stopped.
+    ServiceStopped,
+}
+
+impl EventKind {
+    /// Text of a direct or room message; `None` for every other event.
+    pub fn message_body(&self) -> Option<&str> {
+        match self {
+            EventKind::DirectMessage { body, .. } | EventKind::RoomMessage { body, .. } => {
+                Some(body)
+            }
+            _ => None,
+        }
+    }
+
+    /// Text of a direct or room message to change, e.g. before returning `Verdict::Rewrite`;
+    /// `None` for every other event.
+    pub fn message_body_mut(&mut self) -> Option<&mut String> {
+        match self {
+            EventKind::DirectMessage { body, .. } | EventKind::RoomMessage { body, .. } => {
+                Some(body)
+            }
+            _ => None,
+        }
+    }
+
+    /// Room the event happened in; `None` for direct messages and user list updates.
+    pub fn room_id(&self) -> Option<&str> {
+        match self {
+            EventKind::RoomMessage { room_id, .. }
+            | EventKind::ReactionAdded { room_id, .. }
+            | EventKind::ReactionRemoved { room_id, .. }
+            | EventKind::RoomImage { room_id, .. }
+            | EventKind::Knock { room_id, .. }
+            | EventKind::VoiceStateChanged { room_id, .. } => Some(room_id),
+            EventKind::RoomUpgraded { old_room_id, .. } => Some(old_room_id),
+            EventKind::MissedMessages { room_id, .. } => Some(room_id),
+            EventKind::DirectMessage { .. }
+            | EventKind::UserListUpdate { .. }
+            | EventKind::UserListDiff { .. }
+            | EventKind::UserProfileChanged { .. }
+            | EventKind::VerificationRequested { .. }
+            | EventKind::ConnectionScheduled { .. }
+            | EventKind::ServiceReady
+            | EventKind::ServiceDegraded { .. }
+            | EventKind::ServiceStopped => None,
+        }
+    }
+
+    /// Whether the bot wrote the event itself. Unlike `is_self`, false for messages the bot
+    /// relayed on someone else's behalf, which are really theirs.
+    pub fn is_own(&self) -> bool {
+        match self {
+            EventKind::RoomMessage { is_self, relayed_from, .. } => {
+                *is_self && relayed_from.is_none()
+            }
+            EventKind::DirectMessage { is_self, .. }
+            | EventKind::ReactionAdded { is_self, .. }
+            | EventKind::ReactionRemoved { is_self, .. }
+            | EventKind::RoomImage { is_self, .. }
+            | EventKind::Knock { is_self, .. }
+            | EventKind::VoiceStateChanged { is_self, .. } => *is_self,
+            EventKind::UserProfileChanged { user } => user.is_self,
+            EventKind::UserListUpdate { .. }
+            | EventKind::UserListDiff { .. }
+            | EventKind::RoomUpgraded { .. }
+            | EventKind::MissedMessages { .. }
+            | EventKind::VerificationRequested { .. }
+            | EventKind::ConnectionScheduled { .. }
+            | EventKind::ServiceReady
+            | EventKind::ServiceDegraded { .. }
+            | EventKind::ServiceStopped => false,
+        }
+    }
+
+    /// Where a relayed room message was first posted; `None` for everything else.
+    pub fn relayed_from(&self) -> Option<&Provenance> {
+        match self {
+            EventKind::RoomMessage { relayed_from, .. } => relayed_from.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Short snake_case name of the variant, for logs and error reports.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::DirectMessage { .. } => "direct_message",
+            EventKind::RoomMessage { .. } => "room_message",
+            EventKind::UserListUpdate { .. } => "user_list_update",
+            EventKind::UserListDiff { .. } => "user_list_diff",
+            EventKind::ReactionAdded { .. } => "reaction_added",
+            EventKind::ReactionRemoved { .. } => "reaction_removed",
+            EventKind::RoomImage { .. } => "room_image",
+            EventKind::Knock { .. } => "knock",
+            EventKind::RoomUpgraded { .. } => "room_upgraded",
+            EventKind::MissedMessages { .. } => "missed_messages",
+            EventKind::UserProfileChanged { .. } => "user_profile_changed",
+            EventKind::VoiceStateChanged { .. } => "voice_state_changed",
+            EventKind::VerificationRequested { .. } => "verification_requested",
+            EventKind::ConnectionScheduled { .. } => "connection_scheduled",
+            EventKind::ServiceReady => "service_ready",
+            EventKind::ServiceDegraded { .. } => "service_degraded",
+            EventKind::ServiceStopped => "service_stopped",
+        }
+    }
+}
+
+/// Whether `body` addresses `name` the way people mention someone in plain text: `@name`
+/// anywhere, or `name:` / `name,` at the start. Case-insensitive.
+pub fn mentions_name(body: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let body = body.trim_start().to_lowercase();
+    let name = name.to_lowercase();
+    let leading =
+        body.strip_prefix(&name).is_some_and(|rest| rest.starts_with(':') || rest.starts_with(','));
+    let at_mention = body.match_indices(&format!("@{name}")).any(|(index, mention)| {
+        body[index + mention.len()..].chars().next().is_none_or(|c| !c.is_alphanumeric())
+    });
+    leading || at_mention
 }
 
 impl fmt::Display for Event {
@@ -86,6 +375,9 @@ impl fmt::Display for Event {
             EventKind::UserListUpdate { users } => {
                 write!(f, "[UserList] {} users", users.len())
             }
+            EventKind::UserListDiff { joined, left, changed } => {
+                write!(f, "[UserList] +{} -{} ~{} users", joined.len(), left.len(), changed.len())
+            }
             EventKind::ReactionAdded { room_id, key, sender_id, target_event_id, .. } => {
                 write!(f, "[React+] {room_id}: {sender_id} reacted {key} to {target_event_id}")
             }
@@ -95,6 +387,38 @@ impl fmt::Display for Event {
             EventKind::RoomImage { room_id, body, .. } => {
                 write!(f, "[IMG] {room_id}: {body}")
             }
+            EventKind::Knock { room_id, user_id, .. } => {
+                write!(f, "[Knock] {room_id}: {user_id}")
+            }
+            EventKind::RoomUpgraded { old_room_id, new_room_id } => {
+                write!(f, "[Upgrade] {old_room_id} -> {new_room_id}")
+            }
+            EventKind::MissedMessages { room_id, messages, truncated } => {
+                let more = if *truncated { "+" } else { "" };
+                write!(f, "[Missed] {room_id}: {}{more} messages", messages.len())
+            }
+            EventKind::UserProfileChanged { user } => {
+                write!(f, "[Profile] {}", user.display_name)
+            }
+            EventKind::VoiceStateChanged { room_id, user_id, state, active, .. } => {
+                let sign = if *active { "+" } else { "-" };
+                write!(f, "[Voice{sign}] {room_id}: {user_id} {}", state.name())
+            }
+            EventKind::VerificationRequested { other_device_id, emoji, .. } => {
+                write!(f, "[Verify] {other_device_id}: {}", emoji.join(", "))
+            }
+            EventKind::ConnectionScheduled { connected } => {
+                write!(f, "[Schedule] {}", if *connected { "connected" } else { "disconnected" })
+            }
+            EventKind::ServiceReady => write!(f, "[Ready]"),
+            EventKind::ServiceDegraded { error, attempt, retry_in } => {
+                write!(f, "[Degraded] attempt {attempt}, retrying in {}s", retry_in.as_secs())?;
+                match error {
+                    Some(error) => write!(f, ": {error}"),
+                    None => Ok(()),
+                }
+            }
+            EventKind::ServiceStopped => write!(f, "[Stopped]"),
         }
     }
 }
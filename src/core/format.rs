@@ -0,0 +1,265 @@
+//! Message bodies are written once, as plain text or Markdown, and each service renders them
+//! into its own format (`FormatProfile`) when it sends them.
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd, html};
+use serde::{Deserialize, Serialize};
+
+/// How a message body is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyFormat {
+    /// Shown as is, e.g. text someone else wrote.
+    #[default]
+    Plain,
+    /// CommonMark, plus strikethrough and tables.
+    Markdown,
+}
+
+/// The format a service writes its messages in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FormatProfile {
+    #[default]
+    Plain,
+    /// The HTML Matrix clients render from `formatted_body`.
+    MatrixHtml,
+    /// The HTML subset Mumble clients render in text messages. Raw HTML in the body is shown
+    /// as text rather than passed through.
+    MumbleHtml,
+    /// mIRC formatting codes.
+    Irc,
+}
+
+/// `body`, written in `format`, rendered for `profile`.
+pub fn render(body: &str, format: BodyFormat, profile: FormatProfile) -> String {
+    match (format, profile) {
+        (BodyFormat::Plain, FormatProfile::Plain | FormatProfile::Irc) => body.to_string(),
+        (BodyFormat::Plain, FormatProfile::MatrixHtml | FormatProfile::MumbleHtml) => {
+            escape_html(body).replace('\n', "<br>")
+        }
+        (BodyFormat::Markdown, FormatProfile::MatrixHtml) => {
+            let mut out = String::new();
+            // Chat messages break lines where they're written, so soft breaks are kept too
+            let events = parse(body).map(|event| match event {
+                Event::SoftBreak => Event::HardBreak,
+                event => event,
+            });
+            html::push_html(&mut out, events);
+            out.truncate(out.trim_end().len());
+            out
+        }
+        (BodyFormat::Markdown, profile) => {
+            let mut renderer = Renderer { profile, ..Renderer::default() };
+            for event in parse(body) {
+                renderer.event(event);
+            }
+            renderer.out
+        }
+    }
+}
+
+fn parse(body: &str) -> Parser<'_> {
+    Parser::new_ext(body, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Renders Markdown for the profiles `pulldown_cmark::html` doesn't cover.
+#[derive(Default)]
+struct Renderer {
+    profile: FormatProfile,
+    out: String,
+    // Next number of each open list, innermost last; `None` for bulleted lists
+    lists: Vec<Option<u64>>,
+    // Where each open link points and where its text starts in `out`
+    links: Vec<(String, usize)>,
+    // Set right after a list item or quote marker, so the block inside it isn't separated from it
+    at_block_start: bool,
+    cells_in_row: usize,
+}
+
+impl Renderer {
+    fn event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text)
+            | Event::Html(text)
+            | Event::InlineHtml(text)
+            | Event::InlineMath(text)
+            | Event::DisplayMath(text) => self.text(&text),
+            Event::Code(code) => {
+                self.markup("<code>", "\x11", "");
+                self.text(&code);
+                self.markup("</code>", "\x11", "");
+            }
+            Event::SoftBreak | Event::HardBreak => self.line_break(),
+            Event::Rule => {
+                self.block();
+                self.markup("<hr>", "---", "---");
+            }
+            Event::TaskListMarker(done) => self.text(if done { "[x] " } else { "[ ] " }),
+            Event::FootnoteReference(name) => self.text(&format!("[{name}]")),
+        }
+    }
+
+    fn start(&mut self, tag: Tag<'_>) {
+        match tag {
+            Tag::Paragraph | Tag::HtmlBlock | Tag::FootnoteDefinition(_) => self.block(),
+            Tag::Heading { .. } => {
+                self.block();
+                self.bold(true);
+            }
+            Tag::BlockQuote(_) => {
+                self.block();
+                self.markup("<blockquote>", "> ", "> ");
+                self.at_block_start = true;
+            }
+            Tag::CodeBlock(_) => {
+                self.block();
+                self.markup("<pre>", "\x11", "");
+            }
+            Tag::List(start) => {
+                if self.profile == FormatProfile::MumbleHtml {
+                    self.out.push_str(&match start {
+                        None => "<ul>".to_string(),
+                        Some(1) => "<ol>".to_string(),
+                        Some(n) => format!("<ol start=\"{n}\">"),
+                    });
+                } else if self.lists.is_empty() {
+                    self.block();
+                    self.at_block_start = true;
+                }
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                if self.profile == FormatProfile::MumbleHtml {
+                    self.out.push_str("<li>");
+                } else {
+                    if !std::mem::take(&mut self.at_block_start) && !self.out.is_empty() {
+                        self.out.push('\n');
+                    }
+                    let depth = self.lists.len().saturating_sub(1);
+                    self.out.push_str(&"  ".repeat(depth));
+                    match self.lists.last_mut() {
+                        Some(Some(n)) => {
+                            self.out.push_str(&format!("{n}. "));
+                            *n += 1;
+                        }
+                        _ => self.out.push_str("- "),
+                    }
+                }
+                self.at_block_start = true;
+            }
+            Tag::Table(_) => {
+                self.block();
+                self.at_block_start = true;
+            }
+            Tag::TableHead | Tag::TableRow => {
+                if self.cells_in_row == 0 && !self.at_block_start && !self.out.is_empty() {
+                    self.line_break();
+                }
+                self.at_block_start = false;
+            }
+            Tag::TableCell => {
+                if self.cells_in_row > 0 {
+                    self.text(" | ");
+                }
+                self.cells_in_row += 1;
+            }
+            Tag::Emphasis => self.markup("<i>", "\x1d", ""),
+            Tag::Strong => self.bold(true),
+            Tag::Strikethrough => self.markup("<s>", "\x1e", ""),
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                if self.profile == FormatProfile::MumbleHtml {
+                    self.out.push_str(&format!("<a href=\"{}\">", escape_html(&dest_url)));
+                }
+                self.links.push((dest_url.to_string(), self.out.len()));
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Heading(_) => self.bold(false),
+            TagEnd::BlockQuote(_) => self.end_html_block("</blockquote>"),
+            TagEnd::CodeBlock => {
+                self.out.truncate(self.out.trim_end_matches('\n').len());
+                if self.profile == FormatProfile::MumbleHtml {
+                    self.end_html_block("</pre>");
+                } else {
+                    self.markup("", "\x11", "");
+                }
+            }
+            TagEnd::List(ordered) => {
+                self.lists.pop();
+                self.end_html_block(if ordered { "</ol>" } else { "</ul>" });
+            }
+            TagEnd::Item => self.markup("</li>", "", ""),
+            TagEnd::TableHead | TagEnd::TableRow => self.cells_in_row = 0,
+            TagEnd::Emphasis => self.markup("</i>", "\x1d", ""),
+            TagEnd::Strong => self.bold(false),
+            TagEnd::Strikethrough => self.markup("</s>", "\x1e", ""),
+            TagEnd::Link | TagEnd::Image => {
+                let Some((url, text_start)) = self.links.pop() else {
+                    return;
+                };
+                if self.profile == FormatProfile::MumbleHtml {
+                    self.out.push_str("</a>");
+                } else if self.out[text_start..] != url {
+                    // No links in plain text, so show where it goes unless the text already does
+                    self.out.push_str(&format!(" ({url})"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Separates a new block from whatever came before it.
+    fn block(&mut self) {
+        if std::mem::take(&mut self.at_block_start) || self.out.is_empty() {
+            return;
+        }
+        self.line_break();
+        if self.lists.is_empty() {
+            self.line_break();
+        }
+    }
+
+    /// Closes a block-level HTML element on Mumble, which already ends its own line.
+    fn end_html_block(&mut self, tag: &str) {
+        if self.profile == FormatProfile::MumbleHtml {
+            self.out.push_str(tag);
+            self.at_block_start = true;
+        }
+    }
+
+    fn line_break(&mut self) {
+        self.markup("<br>", "\n", "\n");
+    }
+
+    fn bold(&mut self, open: bool) {
+        if open { self.markup("<b>", "\x02", "") } else { self.markup("</b>", "\x02", "") }
+    }
+
+    /// Appends whichever of `mumble`, `irc` and `plain` matches the profile.
+    fn markup(&mut self, mumble: &str, irc: &str, plain: &str) {
+        self.out.push_str(match self.profile {
+            FormatProfile::MumbleHtml | FormatProfile::MatrixHtml => mumble,
+            FormatProfile::Irc => irc,
+            FormatProfile::Plain => plain,
+        });
+    }
+
+    fn text(&mut self, text: &str) {
+        self.at_block_start = false;
+        match self.profile {
+            FormatProfile::MumbleHtml | FormatProfile::MatrixHtml => {
+                self.out.push_str(&escape_html(text))
+            }
+            FormatProfile::Irc | FormatProfile::Plain => self.out.push_str(text),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::{IntoUrl, Method, StatusCode};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::time::Instant;
+
+use crate::core::config::HttpConfig;
+
+/// Sent with every request unless the request sets its own.
+pub const USER_AGENT: &str = "kelvin-bot";
+
+/// Outbound HTTP shared by every middleware (through `MiddlewareContext::http`), so they share
+/// one connection pool and the operator's proxy and TLS settings instead of each building its
+/// own `reqwest::Client`.
+///
+/// Requests to a host with a rate limit are spaced out evenly to stay under it, waiting for
+/// their turn rather than failing. Responses read with `send_cached` are kept for the configured
+/// cache TTL and shared by every middleware asking for the same URL.
+#[derive(Clone, Default)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    // Host -> least time between two requests to it
+    spacing: Arc<HashMap<String, Duration>>,
+    // Host -> when the next request to it may go out
+    next_slots: Arc<Mutex<HashMap<String, Instant>>>,
+    cache: Option<Arc<ResponseCache>>,
+}
+
+impl HttpClient {
+    pub fn from_config(cfg: &HttpConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .danger_accept_invalid_certs(cfg.accept_invalid_certs);
+        if let Some(proxy) = &cfg.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .with_context(|| format!("invalid http.proxy '{proxy}'"))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &cfg.ca_certificate {
+            let pem = std::fs::read(path).with_context(|| {
+                format!("failed to read http.ca_certificate {}", path.display())
+            })?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("invalid http.ca_certificate {}", path.display()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(timeout) = cfg.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let client = builder.build().context("failed to build HTTP client")?;
+
+        let mut http = Self { client, ..Self::default() };
+        for limit in cfg.rate_limits.iter().flatten() {
+            let (host, per_minute) = parse_rate_limit(limit)?;
+            http = http.with_rate_limit(&host, per_minute);
+        }
+        if let Some(ttl) = cfg.cache_ttl {
+            http = http.with_cache(ttl);
+        }
+        Ok(http)
+    }
+
+    /// Sends at most `per_minute` requests a minute to `host`.
+    pub fn with_rate_limit(mut self, host: &str, per_minute: u32) -> Self {
+        let spacing = Duration::from_secs(60) / per_minute.max(1);
+        Arc::make_mut(&mut self.spacing).insert(host.to_ascii_lowercase(), spacing);
+        self
+    }
+
+    /// Keeps responses read with `send_cached` for `ttl`.
+    pub fn with_cache(mut self, ttl: Duration) -> Self {
+        self.cache = Some(Arc::new(ResponseCache { ttl, entries: Mutex::default() }));
+        self
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> HttpRequest {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> HttpRequest {
+        self.request(Method::POST, url)
+    }
+
+    pub fn put(&self, url: impl IntoUrl) -> HttpRequest {
+        self.request(Method::PUT, url)
+    }
+
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> HttpRequest {
+        HttpRequest { http: self.clone(), builder: self.client.request(method, url) }
+    }
+
+    /// Waits until a request to `host` fits within its rate limit, if it has one.
+    async fn wait_for_turn(&self, host: &str) {
+        let Some(spacing) = self.spacing.get(host) else { return };
+        let slot = {
+            let mut next_slots = self.next_slots.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let slot = next_slots.get(host).map_or(now, |next| (*next).max(now));
+            next_slots.insert(host.to_string(), slot + *spacing);
+            slot
+        };
+        if slot > Instant::now() {
+            tracing::debug!(host=%host, wait=?slot - Instant::now(), "waiting for HTTP rate limit");
+            tokio::time::sleep_until(slot).await;
+        }
+    }
+
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        if let Some(host) = request.url().host_str() {
+            self.wait_for_turn(&host.to_ascii_lowercase()).await;
+        }
+        self.client.execute(request).await
+    }
+}
+
+/// A request being built, sent through its `HttpClient`'s rate limits and cache.
+pub struct HttpRequest {
+    http: HttpClient,
+    builder: reqwest::RequestBuilder,
+}
+
+impl HttpRequest {
+    pub fn header(mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.builder = self.builder.header(name.as_ref(), value.as_ref());
+        self
+    }
+
+    pub fn bearer_auth(mut self, token: impl std::fmt::Display) -> Self {
+        self.builder = self.builder.bearer_auth(token);
+        self
+    }
+
+    pub fn query<T: Serialize + ?Sized>(mut self, query: &T) -> Self {
+        self.builder = self.builder.query(query);
+        self
+    }
+
+    pub fn form<T: Serialize + ?Sized>(mut self, form: &T) -> Self {
+        self.builder = self.builder.form(form);
+        self
+    }
+
+    pub fn json<T: Serialize + ?Sized>(mut self, json: &T) -> Self {
+        self.builder = self.builder.json(json);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<reqwest::Body>) -> Self {
+        self.builder = self.builder.body(body);
+        self
+    }
+
+    /// Overrides the client's timeout for this request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.builder = self.builder.timeout(timeout);
+        self
+    }
+
+    pub async fn send(self) -> reqwest::Result<reqwest::Response> {
+        let (_, request) = self.builder.build_split();
+        self.http.execute(request?).await
+    }
+
+    /// Sends the request and reads the whole response, answering from the cache instead when
+    /// it's a `GET` for a URL fetched successfully within the cache TTL.
+    ///
+    /// The cache is keyed by URL alone, so requests with an `Authorization` header always go
+    /// out and their responses aren't kept: what one caller is allowed to see mustn't be
+    /// answered to another asking for the same URL with different credentials, or none.
+    pub async fn send_cached(self) -> Result<CachedResponse> {
+        let (_, request) = self.builder.build_split();
+        let request = request?;
+        let cacheable = request.method() == Method::GET
+            && !request.headers().contains_key(reqwest::header::AUTHORIZATION);
+        let cache = self.http.cache.as_ref().filter(|_| cacheable);
+        let key = request.url().to_string();
+        if let Some(cached) = cache.and_then(|cache| cache.get(&key)) {
+            tracing::debug!(url=%key, "answering HTTP request from cache");
+            return Ok(cached);
+        }
+
+        let response = self.http.execute(request).await?;
+        let status = response.status();
+        let body = response.text().await?;
+        let response = CachedResponse { status, body };
+        if let Some(cache) = cache.filter(|_| status.is_success()) {
+            cache.insert(key, response.clone());
+        }
+        Ok(response)
+    }
+}
+
+/// A response read in full, as `send_cached` keeps it.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl CachedResponse {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_str(&self.body)?)
+    }
+}
+
+struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, CachedResponse)>>,
+}
+
+impl ResponseCache {
+    fn get(&self, url: &str) -> Option<CachedResponse> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let (fetched_at, response) = entries.get(url)?;
+        (fetched_at.elapsed() < self.ttl).then(|| response.clone())
+    }
+
+    fn insert(&self, url: String, response: CachedResponse) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < self.ttl);
+        entries.insert(url, (Instant::now(), response));
+    }
+}
+
+/// Parses a rate limit written `host=requests per minute`, e.g. `api.github.com=30`.
+pub fn parse_rate_limit(limit: &str) -> Result<(String, u32)> {
+    let invalid = || {
+        anyhow!(
+            "invalid rate limit '{limit}'. Expected host=requests per minute (e.g., api.github.com=30)"
+        )
+    };
+    let (host, per_minute) = limit.split_once('=').ok_or_else(invalid)?;
+    let host = host.trim().to_ascii_lowercase();
+    let per_minute: u32 = per_minute.trim().parse().map_err(|_| invalid())?;
+    if host.is_empty() || per_minute == 0 {
+        return Err(invalid());
+    }
+    Ok((host, per_minute))
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::core::bus::Command;
+
+/// How long services remember the messages they sent, unless told otherwise. Long enough to
+/// cover a retry racing the original send, short enough not to grow without bound.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// A new random idempotency key, for a message that may be retried.
+pub fn fresh_key() -> String {
+    format!("{:032x}", rand::thread_rng().r#gen::<u128>())
+}
+
+/// The results of recently sent messages by idempotency key, for services whose servers can't
+/// dedupe sends themselves. (Matrix passes the key on as the transaction ID instead.)
+pub struct SeenKeys {
+    ttl: Duration,
+    // Key -> when it was sent and what sending it returned
+    sent: Mutex<HashMap<String, (Instant, String)>>,
+}
+
+impl SeenKeys {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, sent: Mutex::new(HashMap::new()) }
+    }
+
+    /// `command`, unless its key was sent within the TTL. Repeats are answered with the first
+    /// send's result and `None` is returned, so the caller has nothing left to do.
+    pub fn screen(&self, command: Command) -> Option<Command> {
+        let Some(result) = command.idempotency_key().and_then(|key| self.result(key)) else {
+            return Some(command);
+        };
+        tracing::debug!(key = command.idempotency_key(), "skipping already sent message");
+        command.resolve(result);
+        None
+    }
+
+    /// Remembers what sending the message with `key` returned, if it has a key and succeeded.
+    pub fn record(&self, key: Option<&str>, result: &anyhow::Result<String>) {
+        let (Some(key), Ok(result)) = (key, result) else {
+            return;
+        };
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        sent.insert(key.to_string(), (Instant::now(), result.clone()));
+    }
+
+    fn result(&self, key: &str) -> Option<String> {
+        let sent = self.sent.lock().unwrap();
+        sent.get(key).filter(|(at, _)| at.elapsed() < self.ttl).map(|(_, result)| result.clone())
+    }
+}
+
+impl Default for SeenKeys {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry,
+    filter::LevelFilter,
+    fmt::{self, MakeWriter},
+    layer::{Layered, SubscriberExt},
+    util::SubscriberInitExt,
+};
+
+use crate::core::{
+    config::{LogFormat, LoggingConfig},
+    error_reporting,
+    redact::RedactingMakeWriter,
+};
+
+type FilteredRegistry = Layered<EnvFilter, Registry>;
+type BoxedLayer = Box<dyn Layer<FilteredRegistry> + Send + Sync>;
+
+/// Installs the global tracing subscriber: stdout, plus daily rotated files if configured.
+/// Both outputs go through the secrets redactor. With `report_errors`, logged errors are also
+/// sent to the error reporting client, which must already be initialized.
+///
+/// Log files default to `<data_directory>/logs`.
+pub fn init(cfg: &LoggingConfig, data_directory: &Path, report_errors: bool) -> Result<()> {
+    let directives = filter_directives(cfg, std::env::var("RUST_LOG").ok().as_deref())?;
+    let filter = EnvFilter::builder().parse_lossy(directives);
+
+    let mut layers =
+        vec![format_layer(cfg.format, RedactingMakeWriter::new(std::io::stdout), true)];
+    if let Some(file) = &cfg.file {
+        let directory = file.directory.clone().unwrap_or_else(|| data_directory.join("logs"));
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix(&file.prefix)
+            .max_log_files(retained_files(file.retention))
+            .build(&directory)
+            .with_context(|| format!("could not open log directory {}", directory.display()))?;
+        layers.push(format_layer(
+            file.format.unwrap_or(cfg.format),
+            RedactingMakeWriter::new(appender),
+            false,
+        ));
+    }
+
+    if report_errors {
+        layers.push(error_reporting::layer().boxed());
+    }
+
+    tracing_subscriber::registry().with(filter).with(layers).try_init()?;
+    Ok(())
+}
+
+/// The `EnvFilter` directives for `cfg`: the default level, then per-target levels, then any
+/// directives from `rust_log` (the `RUST_LOG` variable).
+pub fn filter_directives(cfg: &LoggingConfig, rust_log: Option<&str>) -> Result<String> {
+    let mut directives = vec![parse_level(cfg.level.as_deref().unwrap_or("warn"))?];
+
+    let mut targets: Vec<_> = cfg.targets.iter().collect();
+    targets.sort();
+    for (target, level) in targets {
+        let level = parse_level(level)
+            .with_context(|| format!("invalid log level for target '{target}'"))?;
+        directives.push(format!("{target}={level}"));
+    }
+
+    if let Some(rust_log) = rust_log.map(str::trim).filter(|rust_log| !rust_log.is_empty()) {
+        directives.push(rust_log.to_string());
+    }
+    Ok(directives.join(","))
+}
+
+fn parse_level(level: &str) -> Result<String> {
+    let level = level.trim().to_ascii_lowercase();
+    level.parse::<LevelFilter>().map_err(|_| {
+        anyhow!("invalid log level '{level}'. Expected off, error, warn, info, debug or trace")
+    })?;
+    Ok(level)
+}
+
+// Files are rotated daily, so keep one per (started) day of retention
+fn retained_files(retention: Duration) -> usize {
+    retention.as_secs().div_ceil(24 * 60 * 60).max(1) as usize
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::core::service::ServiceId;
+
+/// Leading words of a message skipped when looking for content relayed before, enough for the
+/// prefixes a few bridges add on the way (`[Discord] Bob: [Mumble] Alice: ...`).
+pub const MAX_PREFIX_WORDS: usize = 8;
+
+/// Messages shorter than this, once normalized, are never treated as looped back: short replies
+/// ("ok", "lol") repeat too often to tell apart.
+pub const MIN_CONTENT_CHARS: usize = 8;
+
+/// Hashes of message contents recently relayed into each room, shared by every relay (through
+/// `MiddlewareContext::recent_relays`), so a message another bridge carries back around isn't
+/// relayed into the same room a second time.
+///
+/// Contents are compared normalized: lowercased, punctuation dropped and whitespace collapsed.
+/// A message counts as a repeat when what's left after skipping up to `MAX_PREFIX_WORDS`
+/// leading words matches something relayed to the room within the window, so the relay
+/// prefixes it picked up on the way don't hide it.
+#[derive(Clone, Default)]
+pub struct RecentRelays {
+    rooms: Arc<Mutex<HashMap<(ServiceId, String), VecDeque<(Instant, u64)>>>>,
+}
+
+impl RecentRelays {
+    /// Records that `body` is being relayed into `room_id` on `service_id`, unless its content
+    /// was relayed there within `window`. Returns whether it's new.
+    pub fn check_and_record(
+        &self,
+        service_id: &ServiceId,
+        room_id: &str,
+        body: &str,
+        window: Duration,
+    ) -> bool {
+        let words = normalize(body);
+        let mut rooms = self.rooms.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let recent = rooms.entry((service_id.clone(), room_id.to_string())).or_default();
+        while recent.front().is_some_and(|(at, _)| at.elapsed() > window) {
+            recent.pop_front();
+        }
+
+        let seen = (0..=MAX_PREFIX_WORDS.min(words.len()))
+            .filter_map(|skip| content_hash(&words[skip..]))
+            .any(|hash| recent.iter().any(|(_, seen)| *seen == hash));
+        if seen {
+            return false;
+        }
+        if let Some(hash) = content_hash(&words) {
+            recent.push_back((Instant::now(), hash));
+        }
+        true
+    }
+}
+
+/// `body` as lowercase words, without punctuation.
+fn normalize(body: &str) -> Vec<String> {
+    body.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Hash of `words`, or `None` when they're too short to compare.
+fn content_hash(words: &[String]) -> Option<u64> {
+    let chars: usize = words.iter().map(|word| word.chars().count()).sum();
+    if chars + words.len().saturating_sub(1) < MIN_CONTENT_CHARS {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    words.hash(&mut hasher);
+    Some(hasher.finish())
+}
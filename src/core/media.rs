@@ -0,0 +1,355 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Result, bail};
+use chrono::Utc;
+use rand::{Rng, distributions::Alphanumeric};
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::core::config::{Config, MediaConfig};
+use crate::core::migrations::{self, Migration};
+
+// MIME types of the attachments the store keeps, by the extension they're saved with
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("bin", "application/octet-stream"),
+];
+
+// Where short links are kept, alongside the attachments
+const LINKS_FILE: &str = "links.sqlite3";
+const LINKS_MIGRATIONS: &[Migration] = &[Migration {
+    description: "create the links table",
+    sql: "CREATE TABLE IF NOT EXISTS links (
+            code TEXT PRIMARY KEY,
+            url TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS links_url ON links (url);",
+}];
+const SHORT_CODE_LENGTH: usize = 8;
+
+// How often expired attachments and short links are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Longest request the server reads, and how long it waits for one
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const NOT_FOUND: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// An attachment the store has saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMedia {
+    /// The file name it's saved under, which is also what links to it end with.
+    pub id: String,
+    pub path: PathBuf,
+    /// Where it's served, when the store has an HTTP server.
+    pub url: Option<Url>,
+}
+
+struct MediaDirectory {
+    directory: PathBuf,
+    ttl: Duration,
+    listen_address: Option<SocketAddr>,
+    public_url: Option<Url>,
+    links: Mutex<Connection>,
+}
+
+/// Attachments relayed between services, kept under `<data_directory>/media` for a while so
+/// services that can't take uploads (e.g. Mumble, IRC) can be sent a link instead. The same
+/// server hands out short links redirecting to long URLs, for destinations that limit how
+/// long messages can be.
+///
+/// Storage is opt-in (`media` in the config); a store that isn't enabled refuses to save
+/// anything, which middlewares can detect with `is_enabled`. Attachments and short links are
+/// deleted once they're older than the configured TTL, and links to them stop working at the
+/// same time. Links need the embedded HTTP server (`listen_address`) and the URL it's
+/// reachable at (`public_url`); names are random, so a link can't be guessed from another.
+/// Short links are kept in sqlite, so every `MediaStore` opened on a directory sees them.
+#[derive(Clone, Default)]
+pub struct MediaStore {
+    inner: Option<Arc<MediaDirectory>>,
+}
+
+impl MediaStore {
+    /// A store keeping attachments in `directory`, created if needed.
+    pub fn open(directory: impl AsRef<Path>, cfg: &MediaConfig) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+        if cfg.listen_address.is_some() != cfg.public_url.is_some() {
+            bail!("media.listen_address and media.public_url must be set together");
+        }
+        let mut links = Connection::open(directory.join(LINKS_FILE))?;
+        // The server and middlewares each open the directory, so wait out each other's writes
+        links.busy_timeout(Duration::from_secs(5))?;
+        migrations::migrate(&mut links, "links", LINKS_MIGRATIONS)?;
+        Ok(Self {
+            inner: Some(Arc::new(MediaDirectory {
+                directory,
+                ttl: cfg.ttl,
+                listen_address: cfg.listen_address,
+                public_url: cfg.public_url.clone(),
+                links: Mutex::new(links),
+            })),
+        })
+    }
+
+    /// The store `config` enables under `<data_directory>/media`, or one that's disabled.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        match &config.media {
+            Some(cfg) => Self::open(config.data_directory.join("media"), cfg),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Whether `store` hands out links to what it saves, and `shorten` works.
+    pub fn hosts_links(&self) -> bool {
+        self.inner.as_ref().is_some_and(|inner| inner.public_url.is_some())
+    }
+
+    /// Saves `data`, an attachment of type `mimetype`.
+    pub fn store(&self, data: &[u8], mimetype: Option<&str>) -> Result<StoredMedia> {
+        let Some(inner) = &self.inner else {
+            bail!("media storage is not enabled");
+        };
+        let extension = EXTENSIONS
+            .iter()
+            .find(|(_, mime)| Some(*mime) == mimetype)
+            .map_or("bin", |(extension, _)| extension);
+        let id = format!("{:032x}.{extension}", rand::thread_rng().r#gen::<u128>());
+        let path = inner.directory.join(&id);
+        std::fs::write(&path, data)?;
+        let url =
+            inner.public_url.as_ref().map(|base| link(base, &format!("media/{id}"))).transpose()?;
+        debug!(id=%id, bytes=%data.len(), "stored attachment");
+        Ok(StoredMedia { id, path, url })
+    }
+
+    /// Path of the attachment saved as `id`, unless it doesn't exist or has expired.
+    pub fn path(&self, id: &str) -> Option<PathBuf> {
+        let inner = self.inner.as_ref()?;
+        if !is_media_id(id) {
+            return None;
+        }
+        let path = inner.directory.join(id);
+        let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+        (!is_expired(modified, inner.ttl)).then_some(path)
+    }
+
+    /// A short link redirecting to `url`. Shortening the same URL again gives the same link
+    /// until it expires.
+    pub fn shorten(&self, url: &str) -> Result<Url> {
+        let Some((inner, base)) =
+            self.inner.as_ref().and_then(|inner| Some((inner, inner.public_url.as_ref()?)))
+        else {
+            bail!("media storage isn't serving links");
+        };
+        // Stored as parsed, so redirects can't smuggle anything into the response headers
+        let url = Url::parse(url)?;
+        let links = inner.links();
+        let existing = links
+            .query_row(
+                "SELECT code FROM links WHERE url = ?1 AND created_at >= ?2 LIMIT 1",
+                params![url.as_str(), inner.cutoff()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        let code = match existing {
+            Some(code) => code,
+            None => {
+                let code: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(SHORT_CODE_LENGTH)
+                    .map(char::from)
+                    .collect();
+                links.execute(
+                    "INSERT INTO links (code, url, created_at) VALUES (?1, ?2, ?3)",
+                    params![code, url.as_str(), Utc::now().timestamp()],
+                )?;
+                debug!(code=%code, url=%url, "shortened link");
+                code
+            }
+        };
+        link(base, &format!("s/{code}"))
+    }
+
+    /// Where short link `code` redirects to, unless it doesn't exist or has expired.
+    pub fn expand(&self, code: &str) -> Option<String> {
+        let inner = self.inner.as_ref()?;
+        inner
+            .links()
+            .query_row(
+                "SELECT url FROM links WHERE code = ?1 AND created_at >= ?2",
+                params![code, inner.cutoff()],
+                |row| row.get(0),
+            )
+            .optional()
+            .ok()?
+    }
+
+    /// Deletes expired attachments and short links, returning how many there were.
+    pub fn prune(&self) -> Result<usize> {
+        let Some(inner) = &self.inner else {
+            return Ok(0);
+        };
+        let mut pruned = 0;
+        for entry in std::fs::read_dir(&inner.directory)? {
+            let entry = entry?;
+            if !entry.file_name().to_str().is_some_and(is_media_id) {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            if is_expired(modified, inner.ttl) {
+                std::fs::remove_file(entry.path())?;
+                pruned += 1;
+            }
+        }
+        pruned += inner
+            .links()
+            .execute("DELETE FROM links WHERE created_at < ?1", params![inner.cutoff()])?;
+        Ok(pruned)
+    }
+
+    /// Prunes expired attachments periodically and, if configured, serves the rest over HTTP
+    /// until cancelled. Returns immediately if the store isn't enabled.
+    pub async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        let Some(inner) = &self.inner else {
+            return Ok(());
+        };
+        let listener = match inner.listen_address {
+            Some(address) => {
+                let listener = TcpListener::bind(address).await?;
+                info!(address=%address, "serving stored attachments");
+                Some(listener)
+            }
+            None => None,
+        };
+
+        let mut prune_interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                _ = prune_interval.tick() => match self.prune() {
+                    Ok(0) => {}
+                    Ok(pruned) => debug!(pruned, "pruned expired attachments and links"),
+                    Err(e) => warn!(error=%e, "failed to prune expired attachments and links"),
+                },
+                accepted = async { listener.as_ref()?.accept().await.ok() }, if listener.is_some() => {
+                    let Some((stream, peer)) = accepted else { continue };
+                    let store = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = store.respond(stream).await {
+                            debug!(peer=%peer, error=%e, "failed to answer media request");
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Answers one HTTP request on `stream` with the attachment it asks for, or a redirect
+    /// for a short link.
+    async fn respond(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await??;
+            if read == 0 || request.len() + read > MAX_REQUEST_BYTES {
+                bail!("incomplete or oversized request");
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+
+        let request_line = String::from_utf8_lossy(&request);
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        if method != "GET" && method != "HEAD" {
+            stream.write_all(NOT_FOUND).await?;
+            return Ok(());
+        }
+        if let Some(url) = target.strip_prefix("/s/").and_then(|code| self.expand(code)) {
+            let headers = format!(
+                "HTTP/1.1 302 Found\r\nLocation: {url}\r\nContent-Length: 0\r\n\
+                 Connection: close\r\n\r\n"
+            );
+            stream.write_all(headers.as_bytes()).await?;
+            return Ok(());
+        }
+        let Some(path) = target.strip_prefix("/media/").and_then(|id| self.path(id)) else {
+            stream.write_all(NOT_FOUND).await?;
+            return Ok(());
+        };
+
+        let body = tokio::fs::read(&path).await?;
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let content_type = EXTENSIONS
+            .iter()
+            .find(|(known, _)| Some(*known) == extension)
+            .map_or("application/octet-stream", |(_, mime)| mime);
+        let headers = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+             Cache-Control: private, max-age=3600\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(headers.as_bytes()).await?;
+        if method == "GET" {
+            stream.write_all(&body).await?;
+        }
+        Ok(())
+    }
+}
+
+impl MediaDirectory {
+    fn links(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.links.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Creation time, in Unix seconds, of the oldest short link that hasn't expired.
+    fn cutoff(&self) -> i64 {
+        Utc::now().timestamp() - self.ttl.as_secs() as i64
+    }
+}
+
+/// The link to `path` on the server at `base`.
+fn link(base: &Url, path: &str) -> Result<Url> {
+    // Keep any path the server sits behind, e.g. a reverse proxy's prefix
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    Ok(base.join(path)?)
+}
+
+/// Whether `id` is a name `store` could have made, so requests can't reach outside the
+/// directory and pruning leaves other files alone.
+fn is_media_id(id: &str) -> bool {
+    id.split_once('.').is_some_and(|(token, extension)| {
+        !token.is_empty()
+            && token.chars().all(|c| c.is_ascii_hexdigit())
+            && EXTENSIONS.iter().any(|(known, _)| *known == extension)
+    })
+}
+
+fn is_expired(modified: SystemTime, ttl: Duration) -> bool {
+    modified.elapsed().is_ok_and(|age| age > ttl)
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::core::service::ServiceId;
+
+/// Instrumentation hooks a service reports into.
+///
+/// Each service receives one of these at creation and calls it from its event handlers and
+/// `handle_command`, so every service is measured the same way regardless of protocol.
+pub trait ServiceMetrics: Send + Sync {
+    /// A chat message (DM, room message, or image) arrived from the service.
+    fn message_received(&self);
+    /// A message was delivered; `latency` covers the send call itself.
+    fn message_sent(&self, latency: Duration);
+    fn send_failed(&self);
+    /// The service started running again after a previous run ended.
+    fn reconnected(&self);
+    /// A ping to the service's server came back after `latency`.
+    fn round_trip(&self, latency: Duration);
+    /// The bus dropped a command sent to the service because it's read-only.
+    fn command_blocked(&self);
+}
+
+/// Discards everything. Used where no metrics subsystem is wired up, e.g. in tests.
+pub struct NoopMetrics;
+
+impl ServiceMetrics for NoopMetrics {
+    fn message_received(&self) {}
+    fn message_sent(&self, _latency: Duration) {}
+    fn send_failed(&self) {}
+    fn reconnected(&self) {}
+    fn round_trip(&self, _latency: Duration) {}
+    fn command_blocked(&self) {}
+}
+
+/// Point-in-time copy of a service's counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServiceMetricsSnapshot {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub send_failures: u64,
+    pub reconnects: u64,
+    /// Mean latency of successful sends, or `None` before the first one.
+    pub mean_send_latency: Option<Duration>,
+    /// Latest round trip to the server, or `None` on services that don't measure it.
+    pub last_round_trip: Option<Duration>,
+    pub mean_round_trip: Option<Duration>,
+    pub commands_blocked: u64,
+}
+
+/// Lock-free counters backing one service's `ServiceMetrics`.
+#[derive(Default)]
+pub struct ServiceCounters {
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    send_failures: AtomicU64,
+    reconnects: AtomicU64,
+    send_latency_micros: AtomicU64,
+    round_trips: AtomicU64,
+    round_trip_micros: AtomicU64,
+    last_round_trip_micros: AtomicU64,
+    commands_blocked: AtomicU64,
+}
+
+impl ServiceCounters {
+    pub fn snapshot(&self) -> ServiceMetricsSnapshot {
+        let messages_sent = self.messages_sent.load(Ordering::Relaxed);
+        let latency_micros = self.send_latency_micros.load(Ordering::Relaxed);
+        let round_trips = self.round_trips.load(Ordering::Relaxed);
+        let round_trip_micros = self.round_trip_micros.load(Ordering::Relaxed);
+        ServiceMetricsSnapshot {
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_sent,
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            mean_send_latency: (messages_sent > 0)
+                .then(|| Duration::from_micros(latency_micros / messages_sent)),
+            last_round_trip: (round_trips > 0).then(|| {
+                Duration::from_micros(self.last_round_trip_micros.load(Ordering::Relaxed))
+            }),
+            mean_round_trip: (round_trips > 0)
+                .then(|| Duration::from_micros(round_trip_micros / round_trips)),
+            commands_blocked: self.commands_blocked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ServiceMetrics for ServiceCounters {
+    fn message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_sent(&self, latency: Duration) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.send_latency_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn round_trip(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        self.last_round_trip_micros.store(micros, Ordering::Relaxed);
+        self.round_trip_micros.fetch_add(micros, Ordering::Relaxed);
+        self.round_trips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn command_blocked(&self) {
+        self.commands_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Upper bounds of the `on_event` latency histogram buckets. Slower calls land in a final
+/// overflow bucket.
+pub const LATENCY_BUCKETS: [Duration; 9] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Point-in-time copy of a middleware's `on_event` latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MiddlewareLatencySnapshot {
+    pub calls: u64,
+    /// Calls that took longer than the bus's latency budget.
+    pub over_budget: u64,
+    pub max: Duration,
+    /// Call counts per `LATENCY_BUCKETS` entry, followed by the overflow bucket.
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// Lock-free latency histogram for one middleware's `on_event`.
+#[derive(Default)]
+pub struct MiddlewareLatency {
+    calls: AtomicU64,
+    over_budget: AtomicU64,
+    max_micros: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl MiddlewareLatency {
+    pub fn record(&self, elapsed: Duration, over_budget: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if over_budget {
+            self.over_budget.fetch_add(1, Ordering::Relaxed);
+        }
+        self.max_micros.fetch_max(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MiddlewareLatencySnapshot {
+        MiddlewareLatencySnapshot {
+            calls: self.calls.load(Ordering::Relaxed),
+            over_budget: self.over_budget.load(Ordering::Relaxed),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Upper bounds of the event lag histogram buckets. Events that waited longer land in a final
+/// overflow bucket.
+pub const LAG_BUCKETS: [Duration; 10] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+];
+
+/// How far through the bus an event had got when its age was measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LagStage {
+    /// The event reached the front of its service's pipeline queue.
+    Queued,
+    /// Every middleware in the event's pipeline has handled it.
+    Processed,
+}
+
+/// Point-in-time copy of how old events were at one stage, measured from when their service
+/// emitted them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventLagSnapshot {
+    pub events: u64,
+    pub max: Duration,
+    /// Event counts per `LAG_BUCKETS` entry, followed by the overflow bucket.
+    pub buckets: [u64; LAG_BUCKETS.len() + 1],
+}
+
+impl EventLagSnapshot {
+    /// An upper bound on the lag of the given share of events (e.g. 0.99 for p99): the bound of
+    /// the bucket the percentile falls in, capped at the largest lag seen. `None` before the
+    /// first event.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        if self.events == 0 {
+            return None;
+        }
+        let rank = ((self.events as f64 * quantile).ceil() as u64).clamp(1, self.events);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = LAG_BUCKETS.get(bucket).copied().unwrap_or(self.max);
+                return Some(bound.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(0.5)
+    }
+
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(0.99)
+    }
+}
+
+/// Lock-free histogram of event ages at one stage.
+#[derive(Default)]
+pub struct EventLag {
+    events: AtomicU64,
+    max_micros: AtomicU64,
+    buckets: [AtomicU64; LAG_BUCKETS.len() + 1],
+}
+
+impl EventLag {
+    pub fn record(&self, lag: Duration) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+        self.max_micros.fetch_max(lag.as_micros() as u64, Ordering::Relaxed);
+        let bucket =
+            LAG_BUCKETS.iter().position(|bound| lag <= *bound).unwrap_or(LAG_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> EventLagSnapshot {
+        EventLagSnapshot {
+            events: self.events.load(Ordering::Relaxed),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Hands each service its counters and collects them for reporting.
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    services: Arc<Mutex<HashMap<ServiceId, Arc<ServiceCounters>>>>,
+    // Keyed by middleware config name
+    middlewares: Arc<Mutex<HashMap<String, Arc<MiddlewareLatency>>>>,
+    lag: Arc<Mutex<HashMap<LagStage, Arc<EventLag>>>>,
+}
+
+impl MetricsRegistry {
+    /// Counters for `service_id`, created on first use. Repeated calls share the same counters.
+    pub fn for_service(&self, service_id: &ServiceId) -> Arc<dyn ServiceMetrics> {
+        let mut services = self.services.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        services.entry(service_id.clone()).or_default().clone()
+    }
+
+    pub fn snapshot(&self) -> HashMap<ServiceId, ServiceMetricsSnapshot> {
+        let services = self.services.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        services.iter().map(|(id, counters)| (id.clone(), counters.snapshot())).collect()
+    }
+
+    /// Latency histogram for the middleware named `name`, created on first use.
+    pub fn for_middleware(&self, name: &str) -> Arc<MiddlewareLatency> {
+        let mut middlewares =
+            self.middlewares.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        middlewares.entry(name.to_string()).or_default().clone()
+    }
+
+    pub fn middleware_snapshot(&self) -> HashMap<String, MiddlewareLatencySnapshot> {
+        let middlewares = self.middlewares.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        middlewares.iter().map(|(name, latency)| (name.clone(), latency.snapshot())).collect()
+    }
+
+    /// Event lag histogram for `stage`, created on first use.
+    pub fn for_lag_stage(&self, stage: LagStage) -> Arc<EventLag> {
+        let mut lag = self.lag.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        lag.entry(stage).or_default().clone()
+    }
+
+    pub fn lag_snapshot(&self) -> HashMap<LagStage, EventLagSnapshot> {
+        let lag = self.lag.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        lag.iter().map(|(stage, lag)| (*stage, lag.snapshot())).collect()
+    }
+}
@@ -1,142 +1,890 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use crate::core::bus::Command;
-use crate::core::config::{Config, HouseholdCfg, MiddlewareKind};
-use crate::core::event::Event;
+use crate::core::bridge_map::BridgeMap;
+use crate::core::bus::{
+    COMMAND_KINDS, Command, CommandSender, EventSender, EventTx, OverflowPolicy,
+};
+use crate::core::commands::CommandSpec;
+use crate::core::config::{
+    CatchUpMode, CommandDispatch, Config, GameProtocolCfg, HouseholdCfg, ImpersonationActionCfg,
+    MiddlewareCfg, MiddlewareKind, PruneActionCfg, RouteRuleCfg, ServiceCfg, ServiceKind,
+};
+use crate::core::event::{EVENT_KIND_NAMES, Event};
+use crate::core::http::HttpClient;
+use crate::core::loop_detection::RecentRelays;
+use crate::core::media::MediaStore;
+use crate::core::paste::Paster;
+use crate::core::preferences::PreferenceStore;
+use crate::core::quiet_hours::QuietHours;
+use crate::core::room_settings::RoomSettingsStore;
+use crate::core::roster::Roster;
+use crate::core::scheduler::SchedulerHandle;
+use crate::core::service::{Service, ServiceDirectory, ServiceId};
+use crate::core::subscriptions::{SubscriptionStore, parse_topic};
+use crate::core::time_zone::resolve_time_zone;
 use crate::middlewares::{
     attendance_relay::{AttendanceRelay, AttendanceRelayConfig},
-    chat_relay::{ChatRelay, ChatRelayConfig},
+    audit::Audit,
+    auto_responder::AutoResponder,
+    bus_admin::BusAdmin,
+    chaos::Chaos,
+    chat_relay::{CatchUp, ChatRelay, ChatRelayConfig, DEFAULT_CATCH_UP_LIMIT},
+    dm_relay::DmRelay,
     echo::Echo,
-    ezstream_announce::EzStreamAnnounce,
+    faq::Faq,
+    feature_flags::FeatureFlags,
+    game_status::{AlertDestination, GameProtocol, GameServer, GameStatus},
+    impersonation_guard::{ImpersonationAction, ImpersonationGuard, ImpersonationGuardConfig},
+    inactivity_pruner::{InactivityPruner, InactivityPrunerConfig, PruneAction, PrunedRoom},
     invite::Invite,
     logger::Logger,
-    movie_showtimes::MovieShowtimes,
+    picker::Picker,
+    ping::Ping,
+    pipeline::Pipeline,
+    prefs::Prefs,
+    reload::Reload,
+    router::{DEFAULT_TEMPLATE, RouteDestination, RouteRule, Router},
+    scheduled_backup::{ScheduledBackup, ScheduledBackupConfig},
+    settings::Settings,
+    status_topic::{DEFAULT_TOPIC_TEMPLATE, StatusTopic, StatusTopicConfig},
+    subscriptions::Subscriptions,
+    tap::Tap,
+    verification_approval::VerificationApproval,
+    voice_sessions::{SessionLog, VoiceSessions, VoiceSessionsConfig},
+    vote_kick::{VoteKick, VoteKickConfig, VoteKickSettings},
     weekly_gathering::{Household, WeeklyGathering, WeeklyGatheringConfig},
+    who::Who,
+    word_filter::WordFilter,
 };
 use crate::store::PersistentStore;
-use anyhow::{Result, bail};
+#[cfg(feature = "web-middlewares")]
+use crate::{
+    core::{
+        commands::parse_weekday_time,
+        config::{ForgeCfg, PackageSourceCfg, StreamPlatformCfg},
+    },
+    middlewares::{
+        ezstream_announce::EzStreamAnnounce,
+        movie_showtimes::MovieShowtimes,
+        release_tracker::{PackageSource, ReleaseTracker, ReleaseTrackerConfig, TrackedPackage},
+        stream_announce::{
+            StreamAnnounce, StreamAnnounceConfig, StreamChannel, StreamPlatform, TwitchCredentials,
+        },
+        ticket_bridge::{Forge, TicketBridge, TicketRepo},
+        trivia::{
+            MAX_ROUND_QUESTIONS, QuestionSource, Trivia, TriviaConfig, TriviaRoom,
+            parse_question_file,
+        },
+        update_notifier::{
+            RUNNING_VERSION, UpdateDestination, UpdateNotifier, UpdateNotifierConfig,
+        },
+    },
+};
+use anyhow::{Context, Result, bail};
 use async_trait::async_trait;
+use regex::Regex;
 use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 use tracing::warn;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Verdict {
+    /// Passes the event on to the next middleware.
     Continue,
-    #[allow(dead_code)]
-    Stop, // This will be used eventually.
+    /// Drops the event: the middlewares after this one in the pipeline don't see it.
+    Stop,
+    /// Passes this event on to the next middlewares in place of the one handled, e.g. with its
+    /// body translated or censored. Earlier middlewares and event tap observers keep the
+    /// original.
+    Rewrite(Arc<Event>),
+}
+
+impl Verdict {
+    /// Passes `event` on with its message body replaced by `body`, or unchanged if it isn't a
+    /// message.
+    pub fn rewrite_body(event: &Event, body: String) -> Self {
+        let mut rewritten = event.clone();
+        match rewritten.kind.message_body_mut() {
+            Some(old_body) => {
+                *old_body = body;
+                Verdict::Rewrite(Arc::new(rewritten))
+            }
+            None => Verdict::Continue,
+        }
+    }
 }
 
 /// Per-middleware context passed to every middleware constructor.
 ///
-/// Bundles the shared command sender and a dedicated persistent store so that
-/// any middleware can opt into storage simply by using `ctx.store` — no
-/// changes to `instantiate_middleware_from_config` required.
+/// Bundles a command sender that attributes commands to the middleware instance and a
+/// dedicated persistent store so that any middleware can opt into storage simply by using
+/// `ctx.store` — no changes to `instantiate_middleware_from_config` required.
 #[derive(Clone)]
 pub struct MiddlewareContext {
-    pub cmd_tx: Sender<Command>,
+    pub cmd_tx: CommandSender,
+    /// Feeds events into the bus as though a service had sent them; `None` unless the
+    /// middlewares were built alongside the bot's event channel.
+    pub evt_tx: Option<EventSender>,
     pub store: Arc<PersistentStore>,
+    pub services: ServiceDirectory,
+    /// Per-user preferences, shared by every middleware.
+    pub preferences: PreferenceStore,
+    /// Who's subscribed to which topics, shared by every middleware.
+    pub subscriptions: SubscriptionStore,
+    /// Per-room settings changed with `!set`, shared by every middleware.
+    pub room_settings: RoomSettingsStore,
+    /// Which relayed message is the copy of which, shared by every relay.
+    pub bridge_map: BridgeMap,
+    /// What was recently relayed into each room, for relays to spot messages looping back.
+    pub recent_relays: RecentRelays,
+    /// Rooms, members and display names seen so far; empty unless roster tracking is enabled.
+    pub roster: Roster,
+    /// Where relayed attachments can be kept and linked to; disabled unless configured.
+    pub media: MediaStore,
+    /// Uploads messages too long for their destination; falls back to truncating them.
+    pub paste: Paster,
+    /// Outbound HTTP with the configured proxy, TLS, rate limits and cache.
+    pub http: HttpClient,
+    /// Makes the schedulers scheduled middlewares wait for wall-clock times with.
+    pub scheduler: SchedulerHandle,
+    /// The middleware's own quiet hours, falling back to the global ones.
+    pub quiet_hours: Option<QuietHours>,
 }
 
 #[async_trait]
 pub trait Middleware: Send + Sync {
     async fn run(&self, cancel: CancellationToken) -> Result<()>;
-    fn on_event(&self, event: &Event) -> Result<Verdict>;
+    /// Events are shared between pipelines and observers; clone the `Arc` to keep one around
+    /// (e.g. in a spawned task) without copying it.
+    fn on_event(&self, event: &Arc<Event>) -> Result<Verdict>;
+
+    /// Command strings this middleware responds to (e.g. `!echo`). Used to detect two
+    /// middlewares in one pipeline claiming the same command.
+    fn command_strings(&self) -> Vec<&str> {
+        Vec::new()
+    }
+
+    /// The commands in `command_strings` with their arguments and descriptions, registered
+    /// with services that have a native command system. Middlewares parsing with a
+    /// `CommandRouter` return its `spec()`; the default knows only the prefixes.
+    fn commands(&self) -> Vec<CommandSpec> {
+        self.command_strings().into_iter().map(CommandSpec::new).collect()
+    }
 }
 
-/// Instantiates middleware instances from config as a HashMap keyed by middleware name
+/// Whether `body` invokes `command`: the command alone, or followed by a space and arguments.
+pub fn matches_command(body: &str, command: &str) -> bool {
+    body.trim().strip_prefix(command).is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+/// Instantiates middleware instances from config as a HashMap keyed by instance name.
+///
+/// Shared middlewares (the default) get a single instance keyed by the middleware name, and
+/// that one instance receives events from every pipeline that references it. Middlewares with
+/// `shared = false` get a separate instance for each service that references them, keyed by
+/// `per_service_instance_name`, each with its own store file.
 pub fn instantiate_middleware_from_config(
     config: &Config,
     cmd_tx: &Sender<Command>,
+    services: &HashMap<ServiceId, Arc<dyn Service>>,
 ) -> Result<HashMap<String, Arc<dyn Middleware>>> {
-    let mut middlewares = HashMap::new();
+    instantiate_middleware_with_roster(config, cmd_tx, services, &Roster::default())
+}
 
-    for (name, cfg) in &config.middlewares {
-        // Lazily build a MiddlewareContext for this middleware. Calling make_ctx()
-        // opens (or creates) the middleware's dedicated store file on disk. Only
-        // middlewares that actually need the context call this.
-        let make_ctx = || -> Result<MiddlewareContext> {
-            let store_path = config.data_directory.join(format!("{name}.store.json"));
-            let store = Arc::new(PersistentStore::load(store_path)?);
-            Ok(MiddlewareContext { cmd_tx: cmd_tx.clone(), store })
+/// Like `instantiate_middleware_from_config`, but middlewares can look up `roster`, which the
+/// caller should also give the bus to keep up to date.
+pub fn instantiate_middleware_with_roster(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    services: &HashMap<ServiceId, Arc<dyn Service>>,
+    roster: &Roster,
+) -> Result<HashMap<String, Arc<dyn Middleware>>> {
+    validate_middleware_references(config)?;
+    let http = HttpClient::from_config(&config.http)?;
+    let shared = SharedState::new(config, services, roster, http, None)?;
+    instantiate_all(config, cmd_tx, &shared)
+}
+
+/// Like `instantiate_middleware_with_roster`, but also returns a reloader that rebuilds a
+/// middleware from the config `load_config` returns, for the bus to swap in at runtime.
+pub fn instantiate_middleware_with_reloader(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    services: &HashMap<ServiceId, Arc<dyn Service>>,
+    roster: &Roster,
+    load_config: impl Fn() -> Result<Config> + Send + Sync + 'static,
+) -> Result<(HashMap<String, Arc<dyn Middleware>>, MiddlewareReloader)> {
+    validate_middleware_references(config)?;
+    let http = HttpClient::from_config(&config.http)?;
+    let shared = SharedState::new(config, services, roster, http, None)?;
+    instantiate_with_reloader(config, cmd_tx, shared, load_config)
+}
+
+/// Does the work of `instantiate_middleware_with_reloader`, with the shared state given.
+fn instantiate_with_reloader(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    shared: SharedState,
+    load_config: impl Fn() -> Result<Config> + Send + Sync + 'static,
+) -> Result<(HashMap<String, Arc<dyn Middleware>>, MiddlewareReloader)> {
+    let middlewares = instantiate_all(config, cmd_tx, &shared)?;
+    let reloader =
+        MiddlewareReloader { cmd_tx: cmd_tx.clone(), shared, load_config: Box::new(load_config) };
+    Ok((middlewares, reloader))
+}
+
+/// Builds a middleware defined in code rather than in the config, given a context like a
+/// configured middleware's. See `KelvinBotBuilder::middleware`.
+pub type MiddlewareFactory =
+    Box<dyn FnOnce(MiddlewareContext) -> Result<Arc<dyn Middleware>> + Send>;
+
+/// Like `instantiate_middleware_with_reloader`, but also builds the `custom` middlewares under
+/// the names pipelines refer to them by. Each gets its own store file and the global quiet
+/// hours, and shares everything else in its context with the configured middlewares. Custom
+/// middlewares can't be reloaded, since they aren't in the config.
+///
+/// Every middleware makes its HTTP requests through `http`, shared with the services, and can
+/// send events into the bus through `evt_tx`.
+#[allow(clippy::too_many_arguments)]
+pub fn instantiate_middleware_with_custom(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    evt_tx: &EventTx,
+    services: &HashMap<ServiceId, Arc<dyn Service>>,
+    roster: &Roster,
+    http: &HttpClient,
+    load_config: impl Fn() -> Result<Config> + Send + Sync + 'static,
+    custom: Vec<(String, MiddlewareFactory)>,
+) -> Result<(HashMap<String, Arc<dyn Middleware>>, MiddlewareReloader)> {
+    validate_middleware_references(config)?;
+    let evt_tx = EventSender::new(evt_tx.clone(), EVENT_OVERFLOW_POLICY);
+    let shared = SharedState::new(config, services, roster, http.clone(), Some(evt_tx))?;
+    let (mut middlewares, reloader) =
+        instantiate_with_reloader(config, cmd_tx, shared, load_config)?;
+    let quiet_hours = config
+        .quiet_hours
+        .as_ref()
+        .map(QuietHours::from_config)
+        .transpose()
+        .context("invalid quiet_hours")?;
+    for (name, build) in custom {
+        if config.middlewares.contains_key(&name) || middlewares.contains_key(&name) {
+            bail!("middleware '{name}' is defined both in the config and in code");
+        }
+        let ctx = reloader.shared.context(config, cmd_tx, &name, quiet_hours)?;
+        let middleware =
+            build(ctx).with_context(|| format!("failed to build middleware '{name}'"))?;
+        middlewares.insert(name, middleware);
+    }
+    Ok((middlewares, reloader))
+}
+
+/// Rebuilds one middleware at a time from a freshly loaded config, so its settings can change
+/// without a restart. New instances share the preferences, subscriptions, roster and media of
+/// the ones built at startup, and reopen their own store file.
+pub struct MiddlewareReloader {
+    cmd_tx: Sender<Command>,
+    shared: SharedState,
+    load_config: Box<dyn Fn() -> Result<Config> + Send + Sync>,
+}
+
+impl MiddlewareReloader {
+    /// New instances of middleware `name`, keyed like `instantiate_middleware_from_config`'s.
+    pub fn reload(&self, name: &str) -> Result<HashMap<String, Arc<dyn Middleware>>> {
+        let config = (self.load_config)().context("failed to load config")?;
+        let Some(cfg) = config.middlewares.get(name) else {
+            bail!("middleware '{name}' is no longer in the config");
         };
+        validate_middleware_references(&config)?;
+        let mut middlewares = HashMap::new();
+        instantiate_named(&config, &self.cmd_tx, &self.shared, name, cfg, &mut middlewares)?;
+        Ok(middlewares)
+    }
 
-        let middleware: Arc<dyn Middleware> = match &cfg.kind {
-            MiddlewareKind::Echo { command_string } => {
-                Arc::new(Echo::new(make_ctx()?, command_string.clone()))
+    /// Instances of the `shared = false` middlewares that `service_name` references in `config`,
+    /// for a service added at runtime. Shared ones are already running, so aren't rebuilt.
+    pub fn instantiate_for_service(
+        &self,
+        config: &Config,
+        service_name: &str,
+    ) -> Result<HashMap<String, Arc<dyn Middleware>>> {
+        validate_middleware_references(config)?;
+        let mut middlewares = HashMap::new();
+        for (name, cfg) in &config.middlewares {
+            if cfg.is_shared()
+                || !services_referencing_middleware(config, name).contains(&service_name)
+            {
+                continue;
             }
-            MiddlewareKind::Invite { command_string, uses_allowed, expiry } => {
-                Arc::new(Invite::new(make_ctx()?, command_string.clone(), *uses_allowed, *expiry))
+            let instance_name = per_service_instance_name(name, service_name);
+            if let Some(middleware) = instantiate_middleware(
+                config,
+                &self.cmd_tx,
+                &self.shared,
+                name,
+                &instance_name,
+                cfg,
+            )? {
+                middlewares.insert(instance_name, middleware);
             }
-            MiddlewareKind::Logger {} => Arc::new(Logger {}),
-            MiddlewareKind::MovieShowtimes {
+        }
+        Ok(middlewares)
+    }
+}
+
+fn instantiate_all(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    shared: &SharedState,
+) -> Result<HashMap<String, Arc<dyn Middleware>>> {
+    let mut middlewares = HashMap::new();
+    for (name, cfg) in &config.middlewares {
+        instantiate_named(config, cmd_tx, shared, name, cfg, &mut middlewares)?;
+    }
+    Ok(middlewares)
+}
+
+/// Adds the instances of middleware `name` to `middlewares`: one if it's shared, otherwise one
+/// per service referencing it.
+fn instantiate_named(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    shared: &SharedState,
+    name: &str,
+    cfg: &MiddlewareCfg,
+    middlewares: &mut HashMap<String, Arc<dyn Middleware>>,
+) -> Result<()> {
+    if cfg.is_shared() {
+        if let Some(middleware) = instantiate_middleware(config, cmd_tx, shared, name, name, cfg)? {
+            middlewares.insert(name.to_string(), middleware);
+        }
+        return Ok(());
+    }
+
+    for service_name in services_referencing_middleware(config, name) {
+        let instance_name = per_service_instance_name(name, service_name);
+        if let Some(middleware) =
+            instantiate_middleware(config, cmd_tx, shared, name, &instance_name, cfg)?
+        {
+            middlewares.insert(instance_name, middleware);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every service and room a middleware config names refers to a configured
+/// service, so a typo fails at startup instead of surfacing later as "command sent to
+/// unknown service" warnings. Rooms on Matrix services must be room IDs (`!...`), and
+/// `allowed_commands` may only name known command types.
+pub fn validate_middleware_references(config: &Config) -> Result<()> {
+    let mut names: Vec<&String> = config.middlewares.keys().collect();
+    names.sort();
+    for name in names {
+        for (field, service_id, room_id) in service_references(&config.middlewares[name].kind) {
+            check_service_reference(
+                config,
+                &format!("middleware '{name}'"),
+                field,
                 service_id,
                 room_id,
-                post_on_day_of_week,
-                post_at_time,
-                search_location,
-                search_radius_mi,
-                gracenote_api_key,
-                theater_id_filter,
-                command_string,
-            } => {
-                // Parse day_of_week string to Weekday
-                let weekday = post_on_day_of_week.parse::<chrono::Weekday>()
-                    .map_err(|_| anyhow::anyhow!(
-                        "invalid day_of_week '{}' for middleware '{}'. Valid values: Monday, Tuesday, Wednesday, Thursday, Friday, Saturday, Sunday",
-                        post_on_day_of_week, name
-                    ))?;
-
-                // Parse time string (HH:MM format)
-                let naive_time = chrono::NaiveTime::parse_from_str(post_at_time, "%H:%M")
-                    .map_err(|_| anyhow::anyhow!(
-                        "invalid time format '{}' for middleware '{}'. Expected format: HH:MM (e.g., 18:00)",
-                        post_at_time, name
-                    ))?;
-
-                Arc::new(MovieShowtimes::new(
-                    make_ctx()?,
-                    service_id.clone(),
-                    room_id.clone(),
-                    weekday,
-                    naive_time,
-                    *search_location,
-                    *search_radius_mi,
-                    gracenote_api_key.clone(),
-                    theater_id_filter.clone(),
-                    command_string.clone(),
-                ))
+            )?;
+        }
+        for kind in config.middlewares[name].allowed_commands.iter().flatten() {
+            if !COMMAND_KINDS.contains(&kind.as_str()) {
+                bail!(
+                    "middleware '{name}': unknown command type '{kind}' in allowed_commands (expected one of {})",
+                    COMMAND_KINDS.join(", ")
+                );
             }
-            MiddlewareKind::AttendanceRelay {
-                source_service_id,
-                source_room_id,
-                dest_service_id,
-                dest_room_id,
-                session_start_message,
-                session_end_message,
-                session_ended_edit_message,
-            } => Arc::new(AttendanceRelay::new(
+        }
+    }
+
+    // The bus's lifecycle announcements point at rooms the same way
+    if let Some(announcements) = &config.lifecycle_announcements {
+        let mut destinations: Vec<_> = announcements.destinations.iter().collect();
+        destinations.sort_by_key(|(name, _)| *name);
+        for (name, dest) in destinations {
+            check_service_reference(
+                config,
+                &format!("lifecycle announcement destination '{name}'"),
+                "service_id",
+                &dest.service_id,
+                Some(&dest.room_id),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn check_service_reference(
+    config: &Config,
+    owner: &str,
+    field: &str,
+    service_id: &str,
+    room_id: Option<&str>,
+) -> Result<()> {
+    let Some(service_cfg) = config.services.get(service_id) else {
+        bail!("{owner}: {field} '{service_id}' is not a configured service");
+    };
+    let Some(room_id) = room_id else {
+        return Ok(());
+    };
+    if room_id.trim().is_empty() {
+        bail!("{owner}: room for {field} '{service_id}' is empty");
+    }
+    if matches!(service_cfg.kind, ServiceKind::Matrix { .. }) && !room_id.starts_with('!') {
+        bail!(
+            "{owner}: '{room_id}' is not a Matrix room ID (expected '!...') for {field} '{service_id}'"
+        );
+    }
+    Ok(())
+}
+
+/// `(field name, service ID, room ID)` for each service a middleware config refers to.
+fn service_references(kind: &MiddlewareKind) -> Vec<(&'static str, &str, Option<&str>)> {
+    match kind {
+        MiddlewareKind::MovieShowtimes { service_id, room_id, .. }
+        | MiddlewareKind::WeeklyGathering { service_id, room_id, .. } => {
+            vec![("service_id", service_id.as_str(), Some(room_id.as_str()))]
+        }
+        MiddlewareKind::AttendanceRelay {
+            source_service_id,
+            source_room_id,
+            dest_service_id,
+            dest_room_id,
+            ..
+        }
+        | MiddlewareKind::ChatRelay {
+            source_service_id,
+            source_room_id,
+            dest_service_id,
+            dest_room_id,
+            ..
+        } => vec![
+            ("source_service_id", source_service_id.as_str(), source_room_id.as_deref()),
+            ("dest_service_id", dest_service_id.as_str(), Some(dest_room_id.as_str())),
+        ],
+        MiddlewareKind::UpdateNotifier { service_id, room_id, .. } => {
+            vec![("service_id", service_id.as_str(), room_id.as_deref())]
+        }
+        MiddlewareKind::VoiceSessions {
+            source_service_id,
+            report_service_id,
+            report_room_id,
+            ..
+        } => {
+            vec![
+                ("source_service_id", source_service_id.as_str(), None),
+                ("report_service_id", report_service_id.as_str(), Some(report_room_id.as_str())),
+            ]
+        }
+        MiddlewareKind::Trivia { rooms, .. } => rooms
+            .values()
+            .map(|room| ("rooms service_id", room.service_id.as_str(), Some(room.room_id.as_str())))
+            .collect(),
+        MiddlewareKind::InactivityPruner { rooms, report_service_id, report_room_id, .. } => rooms
+            .values()
+            .map(|room| ("rooms service_id", room.service_id.as_str(), Some(room.room_id.as_str())))
+            .chain(
+                report_service_id
+                    .as_deref()
+                    .map(|service_id| ("report_service_id", service_id, report_room_id.as_deref())),
+            )
+            .collect(),
+        MiddlewareKind::Router { rules } => rules
+            .values()
+            .flat_map(|rule| {
+                let source = rule.source_service_id.as_deref().map(|source_service_id| {
+                    ("rule source_service_id", source_service_id, rule.source_room_id.as_deref())
+                });
+                let dest = ("rule service_id", rule.service_id.as_str(), rule.room_id.as_deref());
+                source.into_iter().chain(std::iter::once(dest))
+            })
+            .collect(),
+        MiddlewareKind::GameStatus {
+            alert_service_id: Some(service_id), alert_room_id, ..
+        }
+        | MiddlewareKind::ImpersonationGuard {
+            alert_service_id: Some(service_id),
+            alert_room_id,
+            ..
+        } => {
+            vec![("alert_service_id", service_id.as_str(), alert_room_id.as_deref())]
+        }
+        MiddlewareKind::StreamAnnounce { service_id, room_id, .. }
+        | MiddlewareKind::ReleaseTracker { service_id, room_id, .. } => {
+            vec![("service_id", service_id.as_str(), Some(room_id.as_str()))]
+        }
+        MiddlewareKind::Who { service_id, .. } => vec![("service_id", service_id.as_str(), None)],
+        MiddlewareKind::VerificationApproval { service_id, .. } => {
+            vec![("service_id", service_id.as_str(), None)]
+        }
+        MiddlewareKind::DmRelay { source_service_id, dest_service_id, .. } => vec![
+            ("source_service_id", source_service_id.as_str(), None),
+            ("dest_service_id", dest_service_id.as_str(), None),
+        ],
+        MiddlewareKind::StatusTopic { service_id, room_id, voice_service_id, .. } => vec![
+            ("service_id", service_id.as_str(), Some(room_id.as_str())),
+            ("voice_service_id", voice_service_id.as_str(), None),
+        ],
+        MiddlewareKind::EzStreamAnnounce { destinations, .. } => destinations
+            .values()
+            .map(|dest| {
+                ("destination service_id", dest.service_id.as_str(), Some(dest.room_id.as_str()))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Key under which a non-shared middleware's instance for `service_name` is registered.
+pub fn per_service_instance_name(middleware_name: &str, service_name: &str) -> String {
+    format!("{middleware_name}@{service_name}")
+}
+
+/// Names of the services whose pipelines (including the global one and per-room ones) reference
+/// `middleware_name`.
+fn services_referencing_middleware<'a>(config: &'a Config, middleware_name: &str) -> Vec<&'a str> {
+    let in_global = config
+        .global_middleware
+        .as_deref()
+        .unwrap_or_default()
+        .iter()
+        .any(|n| n == middleware_name);
+
+    config
+        .services
+        .iter()
+        .filter(|(_, service_cfg)| {
+            in_global
+                || std::iter::once(&service_cfg.middleware)
+                    .chain(service_cfg.rooms.values().map(|room| &room.middleware))
+                    .flat_map(|names| names.as_deref().unwrap_or_default())
+                    .any(|n| n == middleware_name)
+        })
+        .map(|(service_name, _)| service_name.as_str())
+        .collect()
+}
+
+// Middlewares send events from their own tasks, which can afford to wait a little for room
+const EVENT_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::WaitFor(Duration::from_secs(1));
+
+// Handles every middleware's context shares
+struct SharedState {
+    services: ServiceDirectory,
+    preferences: PreferenceStore,
+    subscriptions: SubscriptionStore,
+    room_settings: RoomSettingsStore,
+    bridge_map: BridgeMap,
+    recent_relays: RecentRelays,
+    roster: Roster,
+    media: MediaStore,
+    paste: Paster,
+    http: HttpClient,
+    evt_tx: Option<EventSender>,
+    scheduler: SchedulerHandle,
+}
+
+impl SharedState {
+    fn new(
+        config: &Config,
+        services: &HashMap<ServiceId, Arc<dyn Service>>,
+        roster: &Roster,
+        http: HttpClient,
+        evt_tx: Option<EventSender>,
+    ) -> Result<Self> {
+        let media = MediaStore::from_config(config)?;
+        Ok(Self {
+            services: ServiceDirectory::from_services(services),
+            preferences: PreferenceStore::load(&config.data_directory)?,
+            subscriptions: SubscriptionStore::load(&config.data_directory)?,
+            room_settings: RoomSettingsStore::load(&config.data_directory)?,
+            bridge_map: BridgeMap::load(&config.data_directory)?,
+            recent_relays: RecentRelays::default(),
+            roster: roster.clone(),
+            paste: Paster::from_config(config, media.clone(), http.clone()),
+            media,
+            http,
+            evt_tx,
+            scheduler: SchedulerHandle::default(),
+        })
+    }
+
+    /// A context for middleware instance `instance_name`, opening (or creating) its store file.
+    fn context(
+        &self,
+        config: &Config,
+        cmd_tx: &Sender<Command>,
+        instance_name: &str,
+        quiet_hours: Option<QuietHours>,
+    ) -> Result<MiddlewareContext> {
+        let store_path = config.data_directory.join(format!("{instance_name}.store.json"));
+        let store = Arc::new(PersistentStore::load(store_path)?);
+        Ok(MiddlewareContext {
+            cmd_tx: CommandSender::new(cmd_tx.clone(), instance_name),
+            evt_tx: self.evt_tx.clone(),
+            store,
+            services: self.services.clone(),
+            preferences: self.preferences.clone(),
+            subscriptions: self.subscriptions.clone(),
+            room_settings: self.room_settings.clone(),
+            bridge_map: self.bridge_map.clone(),
+            recent_relays: self.recent_relays.clone(),
+            roster: self.roster.clone(),
+            media: self.media.clone(),
+            paste: self.paste.clone(),
+            http: self.http.clone(),
+            scheduler: self.scheduler,
+            quiet_hours,
+        })
+    }
+}
+
+/// Builds a single middleware instance. `instance_name` names its store file, which keeps
+/// per-service instances of the same middleware from sharing state on disk.
+fn instantiate_middleware(
+    config: &Config,
+    cmd_tx: &Sender<Command>,
+    shared: &SharedState,
+    name: &str,
+    instance_name: &str,
+    cfg: &MiddlewareCfg,
+) -> Result<Option<Arc<dyn Middleware>>> {
+    // Lazily build a MiddlewareContext for this middleware. Calling make_ctx()
+    // opens (or creates) the middleware's dedicated store file on disk. Only
+    // middlewares that actually need the context call this.
+    let quiet_hours = cfg
+        .quiet_hours
+        .as_ref()
+        .or(config.quiet_hours.as_ref())
+        .map(QuietHours::from_config)
+        .transpose()
+        .with_context(|| format!("invalid quiet_hours for middleware '{name}'"))?;
+    let make_ctx = || shared.context(config, cmd_tx, instance_name, quiet_hours);
+
+    let middleware: Arc<dyn Middleware> = match &cfg.kind {
+        MiddlewareKind::Echo { command_string } => {
+            Arc::new(Echo::new(make_ctx()?, command_string.clone()))
+        }
+        MiddlewareKind::Invite { command_string, uses_allowed, expiry, message_expiry } => {
+            Arc::new(Invite::new(
+                make_ctx()?,
+                command_string.clone(),
+                *uses_allowed,
+                *expiry,
+                *message_expiry,
+            ))
+        }
+        MiddlewareKind::Logger {} => Arc::new(Logger {}),
+        MiddlewareKind::BusAdmin { command_string, admin_user_ids } => Arc::new(BusAdmin::new(
+            make_ctx()?,
+            command_string.clone(),
+            admin_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::Chaos { command_string, admin_user_ids } => {
+            if !cfg!(debug_assertions) {
+                bail!("middleware '{name}': chaos is only available in debug builds");
+            }
+            Arc::new(Chaos::new(
+                make_ctx()?,
+                command_string.clone(),
+                admin_user_ids.clone().unwrap_or_default(),
+            ))
+        }
+        MiddlewareKind::Audit { command_string, admin_user_ids } => Arc::new(Audit::new(
+            make_ctx()?,
+            command_string.clone(),
+            admin_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::Pipeline { command_string, admin_user_ids } => Arc::new(Pipeline::new(
+            make_ctx()?,
+            command_string.clone(),
+            admin_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::Reload { command_string, admin_user_ids } => Arc::new(Reload::new(
+            make_ctx()?,
+            command_string.clone(),
+            admin_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::Tap { command_string, admin_user_ids } => Arc::new(Tap::new(
+            make_ctx()?,
+            command_string.clone(),
+            admin_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::FeatureFlags { command_string, admin_user_ids } => {
+            Arc::new(FeatureFlags::new(
                 make_ctx()?,
-                AttendanceRelayConfig {
+                command_string.clone(),
+                admin_user_ids.clone().unwrap_or_default(),
+            ))
+        }
+        MiddlewareKind::Prefs { command_string } => {
+            Arc::new(Prefs::new(make_ctx()?, command_string.clone()))
+        }
+        MiddlewareKind::Settings { command_string, moderator_user_ids } => Arc::new(Settings::new(
+            make_ctx()?,
+            command_string.clone(),
+            moderator_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::Ping { command_string } => {
+            Arc::new(Ping::new(make_ctx()?, command_string.clone()))
+        }
+        MiddlewareKind::Who { command_string, service_id } => {
+            Arc::new(Who::new(make_ctx()?, command_string.clone(), ServiceId(service_id.clone())))
+        }
+        MiddlewareKind::VerificationApproval { service_id, user_id } => Arc::new(
+            VerificationApproval::new(make_ctx()?, ServiceId(service_id.clone()), user_id.clone()),
+        ),
+        MiddlewareKind::DmRelay { source_service_id, dest_service_id, dest_user_id } => {
+            Arc::new(DmRelay::new(
+                make_ctx()?,
+                ServiceId(source_service_id.clone()),
+                ServiceId(dest_service_id.clone()),
+                dest_user_id.clone(),
+            ))
+        }
+        MiddlewareKind::StatusTopic {
+            service_id,
+            room_id,
+            voice_service_id,
+            template,
+            refresh_interval,
+            min_interval,
+        } => {
+            if refresh_interval.is_zero() {
+                bail!("middleware '{name}': refresh_interval must be greater than zero");
+            }
+            Arc::new(StatusTopic::new(
+                make_ctx()?,
+                StatusTopicConfig {
+                    service_id: ServiceId(service_id.clone()),
+                    room_id: room_id.clone(),
+                    voice_service_id: ServiceId(voice_service_id.clone()),
+                    template: template
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_TOPIC_TEMPLATE.to_string()),
+                    refresh_interval: *refresh_interval,
+                    min_interval: *min_interval,
+                },
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::MovieShowtimes {
+            service_id,
+            room_id,
+            post_on_day_of_week,
+            post_at_time,
+            search_location,
+            search_radius_mi,
+            gracenote_api_key,
+            theater_id_filter,
+            command_string,
+            timezone,
+        } => {
+            // Parse day_of_week string to Weekday
+            let weekday = post_on_day_of_week.parse::<chrono::Weekday>()
+                .map_err(|_| anyhow::anyhow!(
+                    "invalid day_of_week '{}' for middleware '{}'. Valid values: Monday, Tuesday, Wednesday, Thursday, Friday, Saturday, Sunday",
+                    post_on_day_of_week, name
+                ))?;
+
+            // Parse time string (HH:MM format)
+            let naive_time = chrono::NaiveTime::parse_from_str(post_at_time, "%H:%M")
+                .map_err(|_| anyhow::anyhow!(
+                    "invalid time format '{}' for middleware '{}'. Expected format: HH:MM (e.g., 18:00)",
+                    post_at_time, name
+                ))?;
+            let timezone = resolve_time_zone(timezone.as_deref())
+                .with_context(|| format!("invalid timezone for middleware '{name}'"))?;
+
+            Arc::new(MovieShowtimes::new(
+                make_ctx()?,
+                service_id.clone(),
+                room_id.clone(),
+                weekday,
+                naive_time,
+                *search_location,
+                *search_radius_mi,
+                gracenote_api_key.clone(),
+                theater_id_filter.clone(),
+                command_string.clone(),
+                timezone,
+            ))
+        }
+        MiddlewareKind::AttendanceRelay {
+            source_service_id,
+            source_room_id,
+            dest_service_id,
+            dest_room_id,
+            session_start_message,
+            session_end_message,
+            session_ended_edit_message,
+        } => Arc::new(AttendanceRelay::new(
+            make_ctx()?,
+            AttendanceRelayConfig {
+                source_service_id: source_service_id.clone(),
+                source_room_id: source_room_id.clone(),
+                dest_service_id: dest_service_id.clone(),
+                dest_room_id: dest_room_id.clone(),
+                session_start_message: session_start_message.clone(),
+                session_end_message: session_end_message.clone(),
+                session_ended_edit_message: session_ended_edit_message.clone(),
+            },
+        )),
+        MiddlewareKind::VoiceSessions {
+            source_service_id,
+            report_service_id,
+            report_room_id,
+            summary_time,
+            timezone,
+        } => {
+            let summary_time = match summary_time {
+                Some(time) => chrono::NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| {
+                    anyhow::anyhow!(
+                        "invalid summary_time format '{}' for middleware '{}'. Expected format: HH:MM (e.g., 09:00)",
+                        time,
+                        name
+                    )
+                })?,
+                None => chrono::NaiveTime::from_hms_opt(9, 0, 0).unwrap_or_default(),
+            };
+            let timezone = resolve_time_zone(timezone.as_deref())
+                .with_context(|| format!("invalid timezone for middleware '{name}'"))?;
+            let log_path = config.data_directory.join(format!("{instance_name}.sessions.sqlite3"));
+            let log = SessionLog::open(&log_path).with_context(|| {
+                format!("failed to open voice session log at {}", log_path.display())
+            })?;
+
+            Arc::new(VoiceSessions::new(
+                make_ctx()?,
+                VoiceSessionsConfig {
                     source_service_id: source_service_id.clone(),
-                    source_room_id: source_room_id.clone(),
-                    dest_service_id: dest_service_id.clone(),
-                    dest_room_id: dest_room_id.clone(),
-                    session_start_message: session_start_message.clone(),
-                    session_end_message: session_end_message.clone(),
-                    session_ended_edit_message: session_ended_edit_message.clone(),
+                    report_service_id: report_service_id.clone(),
+                    report_room_id: report_room_id.clone(),
+                    summary_time,
+                    timezone,
                 },
-            )),
-            MiddlewareKind::ChatRelay {
-                source_service_id,
-                source_room_id,
-                dest_service_id,
-                dest_room_id,
-                prefix_tag,
-                thumbnail_max_width,
-                thumbnail_max_height,
-                thumbnail_jpeg_quality,
-            } => Arc::new(ChatRelay::new(
+                log,
+            ))
+        }
+        MiddlewareKind::ChatRelay {
+            source_service_id,
+            source_room_id,
+            dest_service_id,
+            dest_room_id,
+            prefix_tag,
+            thumbnail_max_width,
+            thumbnail_max_height,
+            thumbnail_jpeg_quality,
+            digest_window,
+            catch_up,
+            catch_up_limit,
+            loop_window,
+        } => {
+            if digest_window.is_some_and(|window| window.is_zero()) {
+                bail!("middleware '{name}': digest_window must be greater than zero");
+            }
+            Arc::new(ChatRelay::new(
                 make_ctx()?,
                 ChatRelayConfig {
                     source_service_id: source_service_id.clone(),
@@ -147,109 +895,637 @@ pub fn instantiate_middleware_from_config(
                     thumbnail_max_width: *thumbnail_max_width,
                     thumbnail_max_height: *thumbnail_max_height,
                     thumbnail_jpeg_quality: *thumbnail_jpeg_quality,
+                    digest_window: *digest_window,
+                    loop_window: *loop_window,
+                    catch_up: match catch_up {
+                        CatchUpMode::Skip => CatchUp::Skip,
+                        CatchUpMode::Relay => CatchUp::Relay {
+                            limit: catch_up_limit.unwrap_or(DEFAULT_CATCH_UP_LIMIT),
+                        },
+                        CatchUpMode::Summarize => CatchUp::Summarize,
+                    },
                 },
-            )),
-            MiddlewareKind::EzStreamAnnounce {
-                websocket_url,
-                stream_url_template,
-                start_message_template,
-                end_message_template,
-                destinations,
-            } => {
-                use crate::middlewares::ezstream_announce::DestinationConfig;
-
-                let dest_configs: Vec<DestinationConfig> = destinations
-                    .values()
-                    .map(|d| DestinationConfig {
-                        service_id: d.service_id.clone(),
-                        room_id: d.room_id.clone(),
-                    })
-                    .collect();
-
-                Arc::new(EzStreamAnnounce::new(
-                    make_ctx()?,
-                    websocket_url.clone(),
-                    stream_url_template.clone(),
-                    start_message_template.clone(),
-                    end_message_template.clone(),
-                    dest_configs,
-                ))
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::EzStreamAnnounce {
+            websocket_url,
+            stream_url_template,
+            start_message_template,
+            end_message_template,
+            destinations,
+        } => {
+            use crate::middlewares::ezstream_announce::DestinationConfig;
+
+            let dest_configs: Vec<DestinationConfig> = destinations
+                .values()
+                .map(|d| DestinationConfig {
+                    service_id: d.service_id.clone(),
+                    room_id: d.room_id.clone(),
+                })
+                .collect();
+
+            Arc::new(EzStreamAnnounce::new(
+                make_ctx()?,
+                websocket_url.clone(),
+                stream_url_template.clone(),
+                start_message_template.clone(),
+                end_message_template.clone(),
+                dest_configs,
+            ))
+        }
+        MiddlewareKind::WeeklyGathering {
+            service_id,
+            room_id,
+            event_day_of_week,
+            event_time,
+            announce_minutes_before,
+            finalize_minutes_before,
+            reaction_virtual,
+            reaction_in_person,
+            reaction_host,
+            announcement_message,
+            finalization_virtual_message,
+            finalization_in_person_message,
+            finalization_no_votes_message,
+            households,
+            timezone,
+        } => {
+            // Parse day_of_week string to Weekday
+            let weekday = event_day_of_week.parse::<chrono::Weekday>()
+                .map_err(|_| anyhow::anyhow!(
+                    "invalid event_day_of_week '{}' for middleware '{}'. Valid values: Monday, Tuesday, Wednesday, Thursday, Friday, Saturday, Sunday",
+                    event_day_of_week, name
+                ))?;
+
+            // Parse time string (HH:MM format)
+            let naive_time = chrono::NaiveTime::parse_from_str(event_time, "%H:%M")
+                .map_err(|_| anyhow::anyhow!(
+                    "invalid event_time format '{}' for middleware '{}'. Expected format: HH:MM (e.g., 19:00)",
+                    event_time, name
+                ))?;
+            let timezone = resolve_time_zone(timezone.as_deref())
+                .with_context(|| format!("invalid timezone for middleware '{name}'"))?;
+
+            let runtime_households: Vec<Household> = households
+                .values()
+                .map(|h: &HouseholdCfg| Household {
+                    name: h.name.clone(),
+                    members: h
+                        .members
+                        .split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect(),
+                })
+                .collect();
+
+            Arc::new(WeeklyGathering::new(
+                make_ctx()?,
+                WeeklyGatheringConfig {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    event_day_of_week: weekday,
+                    event_time: naive_time,
+                    announce_minutes_before: *announce_minutes_before,
+                    finalize_minutes_before: *finalize_minutes_before,
+                    reaction_virtual: reaction_virtual.clone(),
+                    reaction_in_person: reaction_in_person.clone(),
+                    reaction_host: reaction_host.clone(),
+                    announcement_message: announcement_message.clone(),
+                    finalization_virtual_message: finalization_virtual_message.clone(),
+                    finalization_in_person_message: finalization_in_person_message.clone(),
+                    finalization_no_votes_message: finalization_no_votes_message.clone(),
+                    households: runtime_households,
+                    timezone,
+                },
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::UpdateNotifier {
+            service_id,
+            room_id,
+            admin_user_id,
+            repository,
+            check_interval,
+        } => {
+            let destination = match (room_id, admin_user_id) {
+                (Some(room_id), None) => UpdateDestination::Room(room_id.clone()),
+                (None, Some(user_id)) => UpdateDestination::DirectMessage(user_id.clone()),
+                _ => bail!("middleware '{name}': set exactly one of room_id or admin_user_id"),
+            };
+            Arc::new(UpdateNotifier::new(
+                make_ctx()?,
+                UpdateNotifierConfig {
+                    service_id: service_id.clone(),
+                    destination,
+                    repository: repository.clone(),
+                    check_interval: *check_interval,
+                    running_version: RUNNING_VERSION.map(str::to_string),
+                },
+            ))
+        }
+        MiddlewareKind::ScheduledBackup {
+            interval,
+            directory,
+            keep,
+            include_matrix_store,
+            upload_url,
+            upload_token,
+        } => {
+            if interval.is_zero() {
+                bail!("middleware '{name}': interval must be greater than zero");
             }
-            MiddlewareKind::WeeklyGathering {
-                service_id,
-                room_id,
-                event_day_of_week,
-                event_time,
-                announce_minutes_before,
-                finalize_minutes_before,
-                reaction_virtual,
-                reaction_in_person,
-                reaction_host,
-                announcement_message,
-                finalization_virtual_message,
-                finalization_in_person_message,
-                finalization_no_votes_message,
-                households,
-            } => {
-                // Parse day_of_week string to Weekday
-                let weekday = event_day_of_week.parse::<chrono::Weekday>()
-                    .map_err(|_| anyhow::anyhow!(
-                        "invalid event_day_of_week '{}' for middleware '{}'. Valid values: Monday, Tuesday, Wednesday, Thursday, Friday, Saturday, Sunday",
-                        event_day_of_week, name
-                    ))?;
-
-                // Parse time string (HH:MM format)
-                let naive_time = chrono::NaiveTime::parse_from_str(event_time, "%H:%M")
-                    .map_err(|_| anyhow::anyhow!(
-                        "invalid event_time format '{}' for middleware '{}'. Expected format: HH:MM (e.g., 19:00)",
-                        event_time, name
-                    ))?;
-
-                let runtime_households: Vec<Household> = households
-                    .values()
-                    .map(|h: &HouseholdCfg| Household {
-                        name: h.name.clone(),
-                        members: h
-                            .members
-                            .split(',')
-                            .map(|s| s.trim().to_string())
-                            .filter(|s| !s.is_empty())
-                            .collect(),
+            if *keep == 0 {
+                bail!("middleware '{name}': keep must be at least 1");
+            }
+            Arc::new(ScheduledBackup::new(
+                make_ctx()?,
+                ScheduledBackupConfig {
+                    data_directory: config.data_directory.clone(),
+                    directory: directory
+                        .clone()
+                        .unwrap_or_else(|| config.data_directory.join("backups")),
+                    interval: *interval,
+                    keep: *keep,
+                    include_matrix_store: *include_matrix_store,
+                    upload_url: upload_url.clone(),
+                    upload_token: upload_token.clone(),
+                },
+            ))
+        }
+        MiddlewareKind::Router { rules } => {
+            let mut names: Vec<&String> = rules.keys().collect();
+            names.sort();
+            let rules = names
+                .into_iter()
+                .map(|rule_name| route_rule(rule_name, &rules[rule_name]))
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("invalid rule for middleware '{name}'"))?;
+            Arc::new(Router::new(make_ctx()?, rules))
+        }
+        MiddlewareKind::GameStatus {
+            command_string,
+            servers,
+            poll_interval,
+            alert_service_id,
+            alert_room_id,
+        } => {
+            if poll_interval.is_zero() {
+                bail!("middleware '{name}': poll_interval must be greater than zero");
+            }
+            let alerts = match (alert_service_id, alert_room_id) {
+                (Some(service_id), Some(room_id)) => Some(AlertDestination {
+                    service_id: ServiceId(service_id.clone()),
+                    room_id: room_id.clone(),
+                }),
+                (None, None) => None,
+                _ => bail!(
+                    "middleware '{name}': set both or neither of alert_service_id and alert_room_id"
+                ),
+            };
+            let mut names: Vec<&String> = servers.keys().collect();
+            names.sort();
+            if alerts.is_none()
+                && let Some(server) = names.iter().find(|server| servers[**server].watch)
+            {
+                bail!(
+                    "middleware '{name}': server '{server}' is watched but no alert_room_id is set"
+                );
+            }
+            let servers = names
+                .into_iter()
+                .map(|server_name| {
+                    let cfg = &servers[server_name];
+                    GameServer {
+                        name: server_name.clone(),
+                        protocol: match cfg.protocol {
+                            GameProtocolCfg::A2s => GameProtocol::A2s,
+                            GameProtocolCfg::Minecraft => GameProtocol::Minecraft,
+                        },
+                        address: cfg.address.clone(),
+                        watch: cfg.watch,
+                    }
+                })
+                .collect();
+            Arc::new(GameStatus::new(
+                make_ctx()?,
+                command_string.clone(),
+                servers,
+                *poll_interval,
+                alerts,
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::StreamAnnounce {
+            service_id,
+            room_id,
+            channels,
+            twitch_client_id,
+            twitch_client_secret,
+            youtube_api_key,
+            poll_interval,
+            renotify_after,
+        } => {
+            if poll_interval.is_zero() {
+                bail!("middleware '{name}': poll_interval must be greater than zero");
+            }
+            let twitch = match (twitch_client_id, twitch_client_secret) {
+                (Some(client_id), Some(client_secret)) => Some(TwitchCredentials {
+                    client_id: client_id.clone(),
+                    client_secret: client_secret.clone(),
+                }),
+                (None, None) => None,
+                _ => bail!(
+                    "middleware '{name}': set both or neither of twitch_client_id and twitch_client_secret"
+                ),
+            };
+            let mut names: Vec<&String> = channels.keys().collect();
+            names.sort();
+            let channels = names
+                .into_iter()
+                .map(|channel_name| {
+                    let cfg = &channels[channel_name];
+                    let platform = match cfg.platform {
+                        StreamPlatformCfg::Twitch if twitch.is_none() => bail!(
+                            "channel '{channel_name}' is on Twitch but no twitch_client_id is set"
+                        ),
+                        StreamPlatformCfg::Youtube if youtube_api_key.is_none() => bail!(
+                            "channel '{channel_name}' is on YouTube but no youtube_api_key is set"
+                        ),
+                        StreamPlatformCfg::Twitch => StreamPlatform::Twitch,
+                        StreamPlatformCfg::Youtube => StreamPlatform::YouTube,
+                    };
+                    Ok(StreamChannel {
+                        name: channel_name.clone(),
+                        platform,
+                        channel: cfg.channel.clone(),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("invalid channel for middleware '{name}'"))?;
+            Arc::new(StreamAnnounce::new(
+                make_ctx()?,
+                StreamAnnounceConfig {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    channels,
+                    twitch,
+                    youtube_api_key: youtube_api_key.clone(),
+                    poll_interval: *poll_interval,
+                    renotify_after: *renotify_after,
+                },
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::TicketBridge { command_string, repositories, allowed_user_ids } => {
+            let mut names: Vec<&String> = repositories.keys().collect();
+            names.sort();
+            let repos = names
+                .into_iter()
+                .map(|repo_name| {
+                    let cfg = &repositories[repo_name];
+                    let forge = match (cfg.forge, &cfg.base_url) {
+                        (ForgeCfg::Github, _) => Forge::GitHub,
+                        (ForgeCfg::Gitea, Some(base_url)) => {
+                            Forge::Gitea { base_url: base_url.clone() }
+                        }
+                        (ForgeCfg::Gitea, None) => {
+                            bail!("repository '{repo_name}' is on Gitea but has no base_url")
+                        }
+                    };
+                    Ok(TicketRepo {
+                        forge,
+                        repository: cfg.repository.clone(),
+                        token: cfg.token.clone(),
+                        room_id: cfg.room_id.clone(),
                     })
-                    .collect();
-
-                Arc::new(WeeklyGathering::new(
-                    make_ctx()?,
-                    WeeklyGatheringConfig {
-                        service_id: service_id.clone(),
-                        room_id: room_id.clone(),
-                        event_day_of_week: weekday,
-                        event_time: naive_time,
-                        announce_minutes_before: *announce_minutes_before,
-                        finalize_minutes_before: *finalize_minutes_before,
-                        reaction_virtual: reaction_virtual.clone(),
-                        reaction_in_person: reaction_in_person.clone(),
-                        reaction_host: reaction_host.clone(),
-                        announcement_message: announcement_message.clone(),
-                        finalization_virtual_message: finalization_virtual_message.clone(),
-                        finalization_in_person_message: finalization_in_person_message.clone(),
-                        finalization_no_votes_message: finalization_no_votes_message.clone(),
-                        households: runtime_households,
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("invalid repository for middleware '{name}'"))?;
+            Arc::new(TicketBridge::new(
+                make_ctx()?,
+                command_string.clone(),
+                repos,
+                allowed_user_ids.clone().unwrap_or_default(),
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::ReleaseTracker { service_id, room_id, packages, poll_interval } => {
+            if poll_interval.is_zero() {
+                bail!("middleware '{name}': poll_interval must be greater than zero");
+            }
+            let mut names: Vec<&String> = packages.keys().collect();
+            names.sort();
+            let packages = names
+                .into_iter()
+                .map(|package_name| {
+                    let cfg = &packages[package_name];
+                    TrackedPackage {
+                        name: package_name.clone(),
+                        source: match cfg.source {
+                            PackageSourceCfg::CratesIo => PackageSource::CratesIo,
+                            PackageSourceCfg::DockerHub => PackageSource::DockerHub,
+                            PackageSourceCfg::Github => PackageSource::GitHub,
+                        },
+                        package: cfg.package.clone(),
+                        include_prereleases: cfg.prereleases,
+                    }
+                })
+                .collect();
+            Arc::new(ReleaseTracker::new(
+                make_ctx()?,
+                ReleaseTrackerConfig {
+                    service_id: service_id.clone(),
+                    room_id: room_id.clone(),
+                    packages,
+                    poll_interval: *poll_interval,
+                },
+            ))
+        }
+        MiddlewareKind::VoteKick {
+            command_string,
+            threshold,
+            vote_duration,
+            cooldown,
+            admin_user_ids,
+        } => {
+            if *threshold == 0 {
+                bail!("middleware '{name}': threshold must be at least 1");
+            }
+            Arc::new(VoteKick::new(
+                make_ctx()?,
+                command_string.clone(),
+                VoteKickConfig {
+                    settings: VoteKickSettings {
+                        threshold: *threshold,
+                        vote_duration: *vote_duration,
                     },
-                ))
+                    cooldown: *cooldown,
+                    admin_user_ids: admin_user_ids.clone().unwrap_or_default(),
+                },
+            ))
+        }
+        MiddlewareKind::ImpersonationGuard {
+            protected_names,
+            exempt_user_ids,
+            similarity,
+            action,
+            alert_service_id,
+            alert_room_id,
+        } => {
+            if *similarity <= 0.0 || *similarity > 1.0 {
+                bail!("middleware '{name}': similarity must be above 0 and at most 1");
             }
-            MiddlewareKind::Unknown => {
-                warn!(middleware_name=%name, "unknown middleware kind, skipping");
-                continue;
+            let alerts = match (alert_service_id, alert_room_id) {
+                (Some(service_id), Some(room_id)) => Some(AlertDestination {
+                    service_id: ServiceId(service_id.clone()),
+                    room_id: room_id.clone(),
+                }),
+                (None, None) => None,
+                _ => bail!(
+                    "middleware '{name}': set both or neither of alert_service_id and alert_room_id"
+                ),
+            };
+            let action = match action {
+                ImpersonationActionCfg::Alert => ImpersonationAction::Alert,
+                ImpersonationActionCfg::Kick => ImpersonationAction::Kick,
+            };
+            if action == ImpersonationAction::Alert && alerts.is_none() {
+                bail!("middleware '{name}': action is alert but no alert_room_id is set");
             }
-        };
-        middlewares.insert(name.clone(), middleware);
-    }
+            Arc::new(ImpersonationGuard::new(
+                make_ctx()?,
+                ImpersonationGuardConfig {
+                    protected_names: protected_names.clone().unwrap_or_default(),
+                    exempt_user_ids: exempt_user_ids.clone().unwrap_or_default(),
+                    similarity: *similarity,
+                    action,
+                    alerts,
+                },
+            ))
+        }
+        MiddlewareKind::AutoResponder { command_string, timezone } => {
+            let timezone = resolve_time_zone(timezone.as_deref())
+                .with_context(|| format!("invalid timezone for middleware '{name}'"))?;
+            Arc::new(AutoResponder::new(make_ctx()?, command_string.clone(), timezone))
+        }
+        MiddlewareKind::Faq { command_string, editor_user_ids } => Arc::new(Faq::new(
+            make_ctx()?,
+            command_string.clone(),
+            editor_user_ids.clone().unwrap_or_default(),
+        )),
+        MiddlewareKind::Picker { command_string, list_command_string } => {
+            Arc::new(Picker::new(make_ctx()?, command_string.clone(), list_command_string.clone()))
+        }
+        MiddlewareKind::InactivityPruner {
+            rooms,
+            inactive_after,
+            check_interval,
+            action,
+            report_service_id,
+            report_room_id,
+            exempt_user_ids,
+        } => {
+            if rooms.is_empty() {
+                bail!("middleware '{name}': no rooms to prune");
+            }
+            if check_interval.is_zero() {
+                bail!("middleware '{name}': check_interval must be more than 0");
+            }
+            let reports = match (report_service_id, report_room_id) {
+                (Some(service_id), Some(room_id)) => Some(AlertDestination {
+                    service_id: ServiceId(service_id.clone()),
+                    room_id: room_id.clone(),
+                }),
+                (None, None) => None,
+                _ => bail!(
+                    "middleware '{name}': set both or neither of report_service_id and report_room_id"
+                ),
+            };
+            let action = match action {
+                PruneActionCfg::Report => PruneAction::Report,
+                PruneActionCfg::Kick => PruneAction::Kick,
+            };
+            if action == PruneAction::Report && reports.is_none() {
+                bail!("middleware '{name}': action is report but no report_room_id is set");
+            }
+            let mut room_names: Vec<&String> = rooms.keys().collect();
+            room_names.sort();
+            let rooms = room_names
+                .into_iter()
+                .map(|room| PrunedRoom {
+                    service_id: ServiceId(rooms[room].service_id.clone()),
+                    room_id: rooms[room].room_id.clone(),
+                })
+                .collect();
+            Arc::new(InactivityPruner::new(
+                make_ctx()?,
+                InactivityPrunerConfig {
+                    rooms,
+                    inactive_after: *inactive_after,
+                    check_interval: *check_interval,
+                    action,
+                    reports,
+                    exempt_user_ids: exempt_user_ids.clone().unwrap_or_default(),
+                },
+            ))
+        }
+        MiddlewareKind::Subscriptions { command_string, unsubscribe_command_string, topics } => {
+            let topics = topics
+                .as_deref()
+                .map(|topics| {
+                    topics.iter().map(|topic| parse_topic(topic)).collect::<Result<Vec<_>>>()
+                })
+                .transpose()
+                .with_context(|| format!("invalid topics for middleware '{name}'"))?;
+            Arc::new(Subscriptions::new(
+                make_ctx()?,
+                command_string.clone(),
+                unsubscribe_command_string.clone(),
+                topics,
+            ))
+        }
+        #[cfg(feature = "web-middlewares")]
+        MiddlewareKind::Trivia {
+            command_string,
+            rooms,
+            questions_file,
+            category,
+            questions_per_round,
+            answer_time,
+            schedule,
+            timezone,
+        } => {
+            if rooms.is_empty() {
+                bail!("middleware '{name}': no rooms to play trivia in");
+            }
+            if !(1..=MAX_ROUND_QUESTIONS).contains(questions_per_round) {
+                bail!(
+                    "middleware '{name}': questions_per_round must be from 1 to {MAX_ROUND_QUESTIONS}"
+                );
+            }
+            let source = match questions_file {
+                Some(path) => {
+                    let questions = std::fs::read_to_string(path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|toml| parse_question_file(&toml))
+                        .with_context(|| {
+                            format!("middleware '{name}': can't load {}", path.display())
+                        })?;
+                    QuestionSource::Local(questions)
+                }
+                None => QuestionSource::OpenTriviaDb { category: *category },
+            };
+            let schedule = schedule
+                .as_deref()
+                .map(parse_weekday_time)
+                .transpose()
+                .map_err(|e| anyhow::anyhow!("middleware '{name}': invalid schedule: {e}"))?;
+            let timezone = resolve_time_zone(timezone.as_deref())
+                .with_context(|| format!("invalid timezone for middleware '{name}'"))?;
+            let mut names: Vec<&String> = rooms.keys().collect();
+            names.sort();
+            let rooms = names
+                .into_iter()
+                .map(|room| TriviaRoom {
+                    service_id: ServiceId(rooms[room].service_id.clone()),
+                    room_id: rooms[room].room_id.clone(),
+                })
+                .collect();
+            Arc::new(Trivia::new(
+                make_ctx()?,
+                command_string.clone(),
+                TriviaConfig {
+                    rooms,
+                    source,
+                    questions_per_round: *questions_per_round,
+                    answer_time: *answer_time,
+                    schedule,
+                    timezone,
+                },
+            ))
+        }
+        #[cfg(not(feature = "web-middlewares"))]
+        MiddlewareKind::EzStreamAnnounce { .. }
+        | MiddlewareKind::MovieShowtimes { .. }
+        | MiddlewareKind::ReleaseTracker { .. }
+        | MiddlewareKind::StreamAnnounce { .. }
+        | MiddlewareKind::TicketBridge { .. }
+        | MiddlewareKind::Trivia { .. }
+        | MiddlewareKind::UpdateNotifier { .. } => bail!(
+            "middleware '{name}': web API middlewares aren't built in; rebuild with the `web-middlewares` feature"
+        ),
+        MiddlewareKind::WordFilter { words } => {
+            let words = words.clone().unwrap_or_default();
+            Arc::new(
+                WordFilter::new(&words)
+                    .with_context(|| format!("invalid words for middleware '{name}'"))?,
+            )
+        }
+        MiddlewareKind::Unknown => {
+            warn!(middleware_name=%name, "unknown middleware kind, skipping");
+            return Ok(None);
+        }
+    };
+    Ok(Some(middleware))
+}
 
-    Ok(middlewares)
+fn route_rule(name: &str, cfg: &RouteRuleCfg) -> Result<RouteRule> {
+    if let Some(kind) = &cfg.event_kind
+        && !EVENT_KIND_NAMES.contains(&kind.as_str())
+    {
+        bail!(
+            "rule '{name}': unknown event_kind '{kind}' (expected one of {})",
+            EVENT_KIND_NAMES.join(", ")
+        );
+    }
+    let pattern = cfg
+        .pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .with_context(|| format!("rule '{name}': invalid pattern"))?;
+    let topic = cfg
+        .topic
+        .as_deref()
+        .map(parse_topic)
+        .transpose()
+        .with_context(|| format!("rule '{name}': invalid topic"))?;
+    let service_id = cfg.service_id.clone();
+    let destination = match (&cfg.room_id, &cfg.user_id, topic) {
+        (Some(room_id), None, None) => {
+            RouteDestination::Room { service_id, room_id: room_id.clone() }
+        }
+        (None, Some(user_id), None) => {
+            RouteDestination::DirectMessage { service_id, user_id: user_id.clone() }
+        }
+        (None, None, Some(topic)) => RouteDestination::Subscribers { service_id, topic },
+        (Some(room_id), None, Some(topic)) => {
+            RouteDestination::MentionSubscribers { service_id, room_id: room_id.clone(), topic }
+        }
+        _ => bail!(
+            "rule '{name}': set exactly one of room_id or user_id, or topic with or without room_id"
+        ),
+    };
+    Ok(RouteRule {
+        name: name.to_string(),
+        event_kind: cfg.event_kind.clone(),
+        source_service_id: cfg.source_service_id.clone(),
+        source_room_id: cfg.source_room_id.clone(),
+        sender_id: cfg.sender_id.clone(),
+        contains: cfg.contains.clone(),
+        pattern,
+        destination,
+        template: cfg.template.clone().unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+    })
 }
 
-/// Builds a Vec of middleware instances from a list of middleware names
+/// Builds a Vec of middleware instances from a list of middleware names.
+///
+/// Names resolve to the instances in `all_middlewares` as-is, so a shared middleware referenced
+/// by several pipelines is the same `Arc` in each. Use `build_service_pipelines` to resolve
+/// `shared = false` middlewares to their per-service instances.
 pub fn build_middleware_pipeline(
     middleware_names: &[String],
     all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
@@ -267,3 +1543,157 @@ pub fn build_middleware_pipeline(
 
     Ok(pipeline)
 }
+
+/// Builds the full pipeline for every configured service.
+///
+/// Each pipeline is the `global_middleware` list followed by the service's own `middleware`
+/// list. Services with neither get no pipeline entry. Unless `command_dispatch` is
+/// `first_match`, two middlewares in one pipeline registering the same command is an error.
+pub fn build_service_pipelines(
+    config: &Config,
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<HashMap<ServiceId, Vec<Arc<dyn Middleware>>>> {
+    let mut pipelines = HashMap::new();
+    for (service_name, service_cfg) in &config.services {
+        if let Some(pipeline) =
+            service_pipeline(config, service_name, service_cfg, all_middlewares)?
+        {
+            pipelines.insert(ServiceId(service_name.clone()), pipeline);
+        }
+    }
+    Ok(pipelines)
+}
+
+fn service_pipeline(
+    config: &Config,
+    service_name: &str,
+    service_cfg: &ServiceCfg,
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<Option<Vec<Arc<dyn Middleware>>>> {
+    let global_names = config.global_middleware.as_deref().unwrap_or_default();
+    if global_names.is_empty() && service_cfg.middleware.is_none() {
+        return Ok(None);
+    }
+    let service_names = service_cfg.middleware.as_deref().unwrap_or_default();
+    let names = global_names.iter().chain(service_names);
+    build_resolved_pipeline(config, service_name, names, all_middlewares).map(Some)
+}
+
+/// Builds the pipeline of a service that isn't in the config, or replaces a configured one's,
+/// e.g. with `KelvinBotBuilder::pipeline`: the `global_middleware` list followed by `names`.
+pub fn build_pipeline(
+    config: &Config,
+    service_name: &str,
+    names: &[String],
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<Vec<Arc<dyn Middleware>>> {
+    let global_names = config.global_middleware.as_deref().unwrap_or_default();
+    let names = global_names.iter().chain(names);
+    build_resolved_pipeline(config, service_name, names, all_middlewares)
+}
+
+/// Builds the pipelines configured for individual rooms under `services.<name>.rooms`.
+///
+/// A room pipeline is the `global_middleware` list followed by the room's own `middleware`
+/// list, and replaces the service-level pipeline for events from that room. Rooms without an
+/// entry keep using the service-level pipeline.
+pub fn build_room_pipelines(
+    config: &Config,
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<HashMap<ServiceId, HashMap<String, Vec<Arc<dyn Middleware>>>>> {
+    let mut pipelines = HashMap::new();
+    for (service_name, service_cfg) in &config.services {
+        let rooms = room_pipelines(config, service_name, service_cfg, all_middlewares)?;
+        if !rooms.is_empty() {
+            pipelines.insert(ServiceId(service_name.clone()), rooms);
+        }
+    }
+    Ok(pipelines)
+}
+
+fn room_pipelines(
+    config: &Config,
+    service_name: &str,
+    service_cfg: &ServiceCfg,
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<HashMap<String, Vec<Arc<dyn Middleware>>>> {
+    let global_names = config.global_middleware.as_deref().unwrap_or_default();
+    let mut pipelines = HashMap::new();
+    for (room_id, room_cfg) in &service_cfg.rooms {
+        let room_names = room_cfg.middleware.as_deref().unwrap_or_default();
+        let names = global_names.iter().chain(room_names);
+        let pipeline = build_resolved_pipeline(config, service_name, names, all_middlewares)?;
+        pipelines.insert(room_id.clone(), pipeline);
+    }
+    Ok(pipelines)
+}
+
+/// One service's pipelines, built by `build_pipelines_for_service`.
+pub struct ServicePipelines {
+    /// `None` if the service has no service-level pipeline.
+    pub service: Option<Vec<Arc<dyn Middleware>>>,
+    pub rooms: HashMap<String, Vec<Arc<dyn Middleware>>>,
+}
+
+/// The pipelines `build_service_pipelines` and `build_room_pipelines` build for one service,
+/// for a service added at runtime.
+pub fn build_pipelines_for_service(
+    config: &Config,
+    service_name: &str,
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<ServicePipelines> {
+    let Some(service_cfg) = config.services.get(service_name) else {
+        bail!("service '{service_name}' isn't in the config");
+    };
+    Ok(ServicePipelines {
+        service: service_pipeline(config, service_name, service_cfg, all_middlewares)?,
+        rooms: room_pipelines(config, service_name, service_cfg, all_middlewares)?,
+    })
+}
+
+/// Resolves `names` to middleware instances for `service_name`'s pipelines and checks the
+/// result for conflicting commands.
+fn build_resolved_pipeline<'a>(
+    config: &Config,
+    service_name: &str,
+    names: impl Iterator<Item = &'a String>,
+    all_middlewares: &HashMap<String, Arc<dyn Middleware>>,
+) -> Result<Vec<Arc<dyn Middleware>>> {
+    // Non-shared middlewares resolve to the instance created for this service
+    let names: Vec<String> = names
+        .map(|name| match config.middlewares.get(name) {
+            Some(cfg) if !cfg.is_shared() => per_service_instance_name(name, service_name),
+            _ => name.clone(),
+        })
+        .collect();
+    let pipeline = build_middleware_pipeline(&names, all_middlewares)?;
+    if config.command_dispatch == CommandDispatch::Unique {
+        check_command_conflicts(service_name, &names, &pipeline)?;
+    }
+    Ok(pipeline)
+}
+
+/// Fails if two different middlewares in `pipeline` register the same command string.
+fn check_command_conflicts(
+    service_name: &str,
+    names: &[String],
+    pipeline: &[Arc<dyn Middleware>],
+) -> Result<()> {
+    let mut registered: HashMap<&str, usize> = HashMap::new();
+    for (index, mw) in pipeline.iter().enumerate() {
+        for command in mw.command_strings() {
+            match registered.get(command) {
+                Some(&other) if !Arc::ptr_eq(&pipeline[other], mw) => bail!(
+                    "service '{service_name}': middlewares '{}' and '{}' both handle command '{command}'; use distinct command strings or set command_dispatch = \"first_match\"",
+                    names[other],
+                    names[index]
+                ),
+                Some(_) => {}
+                None => {
+                    registered.insert(command, index);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use tracing::{debug, info};
+
+/// One step in a database's schema history, bringing it from the version before to the next.
+///
+/// A database's migrations are a list where the version is the number applied so far, kept in
+/// sqlite's `user_version`. Released migrations are never edited or reordered; a schema change
+/// is a new migration appended to the list.
+pub struct Migration {
+    /// What the step changes, for the log, e.g. "add the links table".
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// A database was written by a newer KelvinBot, whose schema this version doesn't know.
+#[derive(Debug)]
+pub struct SchemaTooNew {
+    /// Which database, e.g. `outbox`.
+    pub database: String,
+    pub version: usize,
+    /// The newest version this KelvinBot knows.
+    pub supported: usize,
+}
+
+impl std::fmt::Display for SchemaTooNew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the {} database is at schema version {}, newer than the {} this version of \
+             KelvinBot knows; run the version that upgraded it, or restore a backup taken \
+             before the upgrade",
+            self.database, self.version, self.supported
+        )
+    }
+}
+
+impl std::error::Error for SchemaTooNew {}
+
+/// The schema version of `conn`: how many of its migrations have been applied.
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version.max(0) as usize)
+}
+
+/// Brings the database `name` up to date, applying the `migrations` it hasn't had yet in order.
+///
+/// Each migration runs in a transaction together with the version bump, so an interrupted
+/// upgrade leaves the database at the last version it fully reached. A database already past
+/// the end of `migrations` is left untouched and reported as [`SchemaTooNew`], rather than
+/// read, and written, by a version that misunderstands it.
+pub fn migrate(conn: &mut Connection, name: &str, migrations: &[Migration]) -> Result<()> {
+    let version = schema_version(conn)?;
+    if version > migrations.len() {
+        return Err(SchemaTooNew {
+            database: name.to_string(),
+            version,
+            supported: migrations.len(),
+        }
+        .into());
+    }
+
+    for (applied, migration) in migrations.iter().enumerate().skip(version) {
+        let next = applied + 1;
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql).with_context(|| {
+            format!(
+                "failed to migrate the {name} database to schema version {next} ({})",
+                migration.description
+            )
+        })?;
+        tx.pragma_update(None, "user_version", next as i64)?;
+        tx.commit()?;
+        // New databases go through every migration; only upgrades are worth noting
+        if version > 0 {
+            info!(database=%name, version=%next, migration=%migration.description, "migrated database");
+        } else {
+            debug!(database=%name, version=%next, migration=%migration.description, "migrated database");
+        }
+    }
+    Ok(())
+}
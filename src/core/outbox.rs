@@ -0,0 +1,283 @@
+use std::{path::Path, sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    bus::Command,
+    event::Provenance,
+    format::BodyFormat,
+    migrations::{self, Migration},
+    service::ServiceId,
+};
+
+/// A fire-and-forget command in a form the outbox can persist.
+///
+/// Commands that carry a response channel are never queued: their caller is waiting on the
+/// outcome and handles failures itself (see `bus::send_with_retry`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum QueuedCommand {
+    DirectMessage {
+        user_id: String,
+        body: String,
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    RoomMessage {
+        room_id: String,
+        body: String,
+        #[serde(default)]
+        format: BodyFormat,
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        #[serde(default)]
+        relayed_from: Option<Provenance>,
+    },
+    ThreadReply {
+        room_id: String,
+        thread_root_id: String,
+        body: String,
+        #[serde(default)]
+        format: BodyFormat,
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    EditMessage {
+        message_id: String,
+        new_body: String,
+        #[serde(default)]
+        format: BodyFormat,
+    },
+    AddReaction {
+        room_id: String,
+        event_id: String,
+        key: String,
+    },
+}
+
+impl QueuedCommand {
+    /// Snapshot of `command` for the outbox, or `None` if it can't be queued.
+    pub fn from_command(command: &Command) -> Option<Self> {
+        match command {
+            Command::SendDirectMessage {
+                user_id,
+                body,
+                response_tx: None,
+                idempotency_key,
+                require_encryption: false,
+                ..
+            } => Some(Self::DirectMessage {
+                user_id: user_id.clone(),
+                body: body.clone(),
+                idempotency_key: idempotency_key.clone(),
+            }),
+            Command::SendRoomMessage {
+                room_id,
+                body,
+                format,
+                response_tx: None,
+                idempotency_key,
+                relayed_from,
+                ..
+            } => Some(Self::RoomMessage {
+                room_id: room_id.clone(),
+                body: body.clone(),
+                format: *format,
+                idempotency_key: idempotency_key.clone(),
+                relayed_from: relayed_from.clone(),
+            }),
+            Command::SendThreadReply {
+                room_id,
+                thread_root_id,
+                body,
+                format,
+                response_tx: None,
+                idempotency_key,
+                ..
+            } => Some(Self::ThreadReply {
+                room_id: room_id.clone(),
+                thread_root_id: thread_root_id.clone(),
+                body: body.clone(),
+                format: *format,
+                idempotency_key: idempotency_key.clone(),
+            }),
+            Command::EditMessage { message_id, new_body, format, .. } => Some(Self::EditMessage {
+                message_id: message_id.clone(),
+                new_body: new_body.clone(),
+                format: *format,
+            }),
+            Command::AddReaction { room_id, event_id, key, .. } => Some(Self::AddReaction {
+                room_id: room_id.clone(),
+                event_id: event_id.clone(),
+                key: key.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn into_command(self, service_id: ServiceId) -> Command {
+        match self {
+            Self::DirectMessage { user_id, body, idempotency_key } => Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body,
+                response_tx: None,
+                origin: None,
+                idempotency_key,
+                expires_after: None,
+                require_encryption: false,
+            },
+            Self::RoomMessage { room_id, body, format, idempotency_key, relayed_from } => {
+                Command::SendRoomMessage {
+                    service_id,
+                    room_id,
+                    body,
+                    format,
+                    response_tx: None,
+                    origin: None,
+                    idempotency_key,
+                    relayed_from,
+                    expires_after: None,
+                }
+            }
+            Self::ThreadReply { room_id, thread_root_id, body, format, idempotency_key } => {
+                Command::SendThreadReply {
+                    service_id,
+                    room_id,
+                    thread_root_id,
+                    body,
+                    format,
+                    response_tx: None,
+                    origin: None,
+                    idempotency_key,
+                }
+            }
+            Self::EditMessage { message_id, new_body, format } => {
+                Command::EditMessage { service_id, message_id, new_body, format, origin: None }
+            }
+            Self::AddReaction { room_id, event_id, key } => {
+                Command::AddReaction { service_id, room_id, event_id, key, origin: None }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedEntry {
+    pub id: i64,
+    pub command: QueuedCommand,
+    pub enqueued_at: DateTime<Utc>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "create the outbox table",
+    sql: "CREATE TABLE IF NOT EXISTS outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            service_id TEXT NOT NULL,
+            command TEXT NOT NULL,
+            enqueued_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS outbox_service_id ON outbox (service_id, id);",
+}];
+
+/// A sqlite-backed queue of commands waiting for their destination service to come back.
+///
+/// Entries are kept in insertion order per service and expire after `ttl`, so a long outage
+/// doesn't flood a room with stale messages once the service reconnects.
+pub struct Outbox {
+    conn: Mutex<Connection>,
+    ttl: Duration,
+}
+
+impl Outbox {
+    /// Open (or create) the outbox database at `path`.
+    pub fn open(path: impl AsRef<Path>, ttl: Duration) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?, ttl)
+    }
+
+    /// Create an outbox that lives only in memory. Useful for testing.
+    pub fn in_memory(ttl: Duration) -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, ttl)
+    }
+
+    fn init(mut conn: Connection, ttl: Duration) -> Result<Self> {
+        migrations::migrate(&mut conn, "outbox", MIGRATIONS)?;
+        Ok(Self { conn: Mutex::new(conn), ttl })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn enqueue(&self, service_id: &ServiceId, command: &QueuedCommand) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO outbox (service_id, command, enqueued_at) VALUES (?1, ?2, ?3)",
+            params![service_id.0, serde_json::to_string(command)?, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `service_id` has undelivered entries. New commands for such a service must be
+    /// queued behind them to keep delivery in order.
+    pub fn has_pending(&self, service_id: &ServiceId) -> Result<bool> {
+        let found = self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM outbox WHERE service_id = ?1 LIMIT 1",
+                params![service_id.0],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Services with at least one undelivered entry.
+    pub fn pending_services(&self) -> Result<Vec<ServiceId>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT DISTINCT service_id FROM outbox")?;
+        let ids = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        Ok(ids.map(|id| id.map(ServiceId)).collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Oldest unexpired entry for `service_id`. Expired entries are discarded first and
+    /// the number discarded is returned alongside.
+    pub fn next(&self, service_id: &ServiceId) -> Result<(Option<QueuedEntry>, usize)> {
+        let conn = self.conn();
+        let cutoff = Utc::now().timestamp() - self.ttl.as_secs() as i64;
+        let expired = conn.execute(
+            "DELETE FROM outbox WHERE service_id = ?1 AND enqueued_at < ?2",
+            params![service_id.0, cutoff],
+        )?;
+
+        let row = conn
+            .query_row(
+                "SELECT id, command, enqueued_at FROM outbox WHERE service_id = ?1
+                 ORDER BY id LIMIT 1",
+                params![service_id.0],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)),
+            )
+            .optional()?;
+
+        let entry = match row {
+            Some((id, command, enqueued_at)) => Some(QueuedEntry {
+                id,
+                command: serde_json::from_str(&command)?,
+                enqueued_at: DateTime::from_timestamp(enqueued_at, 0).unwrap_or_default(),
+            }),
+            None => None,
+        };
+        Ok((entry, expired))
+    }
+
+    pub fn remove(&self, id: i64) -> Result<()> {
+        self.conn().execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
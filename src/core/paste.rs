@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::core::{config::Config, http::HttpClient, media::MediaStore};
+
+// How long an upload to the paste endpoint may take before the message is truncated instead
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TEXT_MIMETYPE: &str = "text/plain; charset=utf-8";
+
+/// Uploads messages too long for their destination, so they can be sent cut short with a link
+/// to the full text instead of just cut short.
+///
+/// Text goes to the configured paste endpoint (`paste.url`), which is sent the raw text in a
+/// `POST` and answers with the URL it's at, like paste.rs or a self-hosted pastebin. Without
+/// one, text is saved in the media store and linked on its HTTP server. When neither is set
+/// up, `fit` falls back to plain truncation.
+#[derive(Clone, Default)]
+pub struct Paster {
+    endpoint: Option<Url>,
+    media: MediaStore,
+    http: HttpClient,
+}
+
+impl Paster {
+    pub fn new(endpoint: Option<Url>, media: MediaStore) -> Self {
+        Self { endpoint, media, http: HttpClient::default() }
+    }
+
+    /// The paster `config` sets up, falling back to `media` when it has no paste endpoint.
+    pub fn from_config(config: &Config, media: MediaStore, http: HttpClient) -> Self {
+        Self { http, ..Self::new(config.paste.as_ref().map(|cfg| cfg.url.clone()), media) }
+    }
+
+    /// Whether `upload` has anywhere to put text.
+    pub fn is_enabled(&self) -> bool {
+        self.endpoint.is_some() || self.media.hosts_links()
+    }
+
+    /// Uploads `text`, returning the link to it.
+    pub async fn upload(&self, text: &str) -> Result<Url> {
+        let Some(endpoint) = &self.endpoint else {
+            let stored = self.media.store(text.as_bytes(), Some(TEXT_MIMETYPE))?;
+            return stored.url.context("the media store has no public_url to link to");
+        };
+        let response = self
+            .http
+            .post(endpoint.clone())
+            .header(reqwest::header::CONTENT_TYPE, TEXT_MIMETYPE)
+            .timeout(UPLOAD_TIMEOUT)
+            .body(text.to_string())
+            .send()
+            .await
+            .context("failed to send paste")?;
+        if !response.status().is_success() {
+            bail!("paste endpoint answered {}", response.status());
+        }
+        let link = response.text().await.context("failed to read paste link")?;
+        Url::parse(link.trim()).with_context(|| format!("paste endpoint answered {link:?}"))
+    }
+
+    /// `body` as it fits in `max_chars` characters: unchanged if it already does, otherwise
+    /// uploaded and cut short with a link to the full text. Truncated without a link when the
+    /// upload fails or there's nowhere to upload to.
+    pub async fn fit(&self, body: String, max_chars: Option<usize>) -> String {
+        let Some(max) = max_chars.filter(|max| body.chars().count() > *max) else {
+            return body;
+        };
+        if !self.is_enabled() {
+            return truncate(&body, max);
+        }
+        match self.upload(&body).await {
+            Ok(link) => {
+                debug!(link=%link, chars=%body.chars().count(), "pasted long message");
+                truncate_with_link(&body, &link, max)
+            }
+            Err(e) => {
+                warn!(error=%e, "failed to paste long message, truncating it");
+                truncate(&body, max)
+            }
+        }
+    }
+}
+
+/// Truncates `body` to at most `max_chars` characters, marking the cut with an ellipsis.
+pub fn truncate(body: &str, max_chars: usize) -> String {
+    if body.chars().count() <= max_chars {
+        return body.to_string();
+    }
+    let mut truncated: String = body.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// `body` cut short so it fits in `max_chars` characters along with a link to `full_text`.
+/// Just the link, if even that doesn't leave room for any of the text.
+pub fn truncate_with_link(body: &str, full_text: &Url, max_chars: usize) -> String {
+    let suffix = format!(" (full text: {full_text})");
+    let room = max_chars.saturating_sub(suffix.chars().count());
+    if room < 2 {
+        return full_text.to_string();
+    }
+    format!("{}{suffix}", truncate(body, room))
+}
@@ -0,0 +1,143 @@
+use std::{collections::BTreeSet, fmt, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::core::{service::ServiceId, time_zone::resolve_time_zone};
+use crate::store::PersistentStore;
+
+/// File in the data directory holding every user's preferences.
+pub const PREFERENCES_STORE_FILE: &str = "user_preferences.store.json";
+
+/// Where a user wants replies to the commands they run in a room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyMode {
+    /// In the room the command came from.
+    #[default]
+    Room,
+    /// In a DM to the user.
+    Dm,
+}
+
+impl fmt::Display for ReplyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyMode::Room => write!(f, "room"),
+            ReplyMode::Dm => write!(f, "dm"),
+        }
+    }
+}
+
+impl FromStr for ReplyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "room" => Ok(ReplyMode::Room),
+            "dm" => Ok(ReplyMode::Dm),
+            _ => Err(anyhow!("unknown reply mode '{s}'. Expected room or dm")),
+        }
+    }
+}
+
+/// One user's settings. Everything is optional; unset fields mean "use the bot's default".
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserPreferences {
+    /// IANA time zone name, e.g. `America/Los_Angeles`.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Language tag, e.g. `en-US`.
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// Notification topics the user doesn't want, usually named after the middleware sending
+    /// them.
+    #[serde(default)]
+    pub opt_outs: BTreeSet<String>,
+    #[serde(default)]
+    pub reply_mode: ReplyMode,
+}
+
+impl UserPreferences {
+    /// The user's time zone, if they set one.
+    pub fn time_zone(&self) -> Option<Tz> {
+        self.timezone.as_deref().and_then(|name| name.parse().ok())
+    }
+
+    pub fn is_opted_out(&self, topic: &str) -> bool {
+        self.opt_outs.contains(&topic.to_ascii_lowercase())
+    }
+}
+
+/// Per-user preferences shared by every middleware (through `MiddlewareContext::preferences`),
+/// so a middleware that needs a user's time zone or wants to respect their opt-outs reads them
+/// here instead of keeping its own per-user settings.
+///
+/// Users are identified per service, since the same person has different IDs on each.
+#[derive(Clone)]
+pub struct PreferenceStore {
+    store: Arc<PersistentStore>,
+    // Serializes read-modify-write updates, which the store alone doesn't
+    update_lock: Arc<Mutex<()>>,
+}
+
+impl PreferenceStore {
+    /// Loads the preferences kept in `data_directory`.
+    pub fn load(data_directory: &Path) -> Result<Self> {
+        let store = PersistentStore::load(data_directory.join(PREFERENCES_STORE_FILE))?;
+        Ok(Self::new(Arc::new(store)))
+    }
+
+    /// A store that never writes to disk. Useful for testing.
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(PersistentStore::in_memory()))
+    }
+
+    fn new(store: Arc<PersistentStore>) -> Self {
+        Self { store, update_lock: Arc::default() }
+    }
+
+    /// The preferences of `user_id` on `service_id`, or the defaults if they never set any.
+    pub async fn get(&self, service_id: &ServiceId, user_id: &str) -> UserPreferences {
+        self.store.get(&key(service_id, user_id)).await.unwrap_or_default()
+    }
+
+    /// Applies `change` to the user's preferences and saves them, returning the result.
+    pub async fn update(
+        &self,
+        service_id: &ServiceId,
+        user_id: &str,
+        change: impl FnOnce(&mut UserPreferences),
+    ) -> Result<UserPreferences> {
+        let _guard = self.update_lock.lock().await;
+        let key = key(service_id, user_id);
+        let mut preferences: UserPreferences = self.store.get(&key).await.unwrap_or_default();
+        change(&mut preferences);
+        self.store.set(&key, &preferences).await?;
+        Ok(preferences)
+    }
+}
+
+fn key(service_id: &ServiceId, user_id: &str) -> String {
+    format!("{service_id}/{user_id}")
+}
+
+/// Checks a time zone given by a user, returning its canonical name.
+pub fn parse_timezone(value: &str) -> Result<String> {
+    Ok(resolve_time_zone(Some(value.trim()))?.name().to_string())
+}
+
+/// Checks a language tag given by a user (`en`, `en-US`, `pt_BR`), normalizing `_` to `-`.
+pub fn parse_locale(value: &str) -> Result<String> {
+    let locale = value.trim().replace('_', "-");
+    let well_formed = !locale.is_empty()
+        && locale.split('-').all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if !well_formed {
+        return Err(anyhow!("invalid locale '{value}'. Expected a language tag (e.g., en-US)"));
+    }
+    Ok(locale)
+}
@@ -0,0 +1,117 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+
+use crate::core::{
+    config::QuietHoursConfig,
+    time_zone::{resolve_local, resolve_time_zone},
+};
+
+/// A daily window during which scheduled posts and relays are held back.
+///
+/// The window may wrap past midnight (`22:00`–`08:00`). A window whose start equals its end is
+/// empty, which lets a middleware opt out of a global window.
+///
+/// With a time zone, the window is in that zone whatever the zone of the times checked against
+/// it; without one, it's in theirs (a scheduled middleware's `timezone`, the host's for relays).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Option<Tz>,
+}
+
+impl QuietHours {
+    pub fn new(start: NaiveTime, end: NaiveTime) -> Self {
+        Self { start, end, timezone: None }
+    }
+
+    /// Places the window in `timezone` instead of the zone of the times checked against it.
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    pub fn from_config(cfg: &QuietHoursConfig) -> Result<Self> {
+        let parse = |value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| {
+                anyhow!("invalid quiet hours time '{value}'. Expected format: HH:MM (e.g., 22:00)")
+            })
+        };
+        let quiet_hours = Self::new(parse(&cfg.start)?, parse(&cfg.end)?);
+        Ok(match &cfg.timezone {
+            Some(name) => quiet_hours.with_timezone(resolve_time_zone(Some(name.as_str()))?),
+            None => quiet_hours,
+        })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    pub fn is_quiet_at<Z: TimeZone>(&self, at: &DateTime<Z>) -> bool {
+        match self.timezone {
+            Some(tz) => self.contains(at.with_timezone(&tz).naive_local().time()),
+            None => self.contains(at.naive_local().time()),
+        }
+    }
+
+    /// The first time the window ends after `at`, whether or not `at` is inside it.
+    pub fn next_end<Z: TimeZone>(&self, at: &DateTime<Z>) -> DateTime<Z> {
+        match self.timezone {
+            Some(tz) => self.end_after(&at.with_timezone(&tz)).with_timezone(&at.timezone()),
+            None => self.end_after(at),
+        }
+    }
+
+    /// The first time the window ends after `at`, in `at`'s zone.
+    fn end_after<Z: TimeZone>(&self, at: &DateTime<Z>) -> DateTime<Z> {
+        let local = at.naive_local();
+        let mut end = local.date().and_time(self.end);
+        if end <= local {
+            end += Duration::days(1);
+        }
+        resolve_local(&at.timezone(), end)
+    }
+
+    /// `at` itself if it falls outside the window, otherwise the moment the window ends.
+    pub fn defer<Z: TimeZone>(&self, at: DateTime<Z>) -> DateTime<Z> {
+        if self.is_quiet_at(&at) { self.next_end(&at) } else { at }
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+/// Parses a window written `HH:MM-HH:MM`, e.g. `22:00-07:00`.
+impl FromStr for QuietHours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s.trim().split_once('-').ok_or_else(|| {
+            anyhow!("invalid quiet hours '{s}'. Expected format: HH:MM-HH:MM (e.g., 22:00-07:00)")
+        })?;
+        Self::from_config(&QuietHoursConfig {
+            start: start.trim().to_string(),
+            end: end.trim().to_string(),
+            timezone: None,
+        })
+    }
+}
+
+/// `at` deferred past `quiet_hours`, if any are configured.
+pub fn defer_past<Z: TimeZone>(quiet_hours: Option<&QuietHours>, at: DateTime<Z>) -> DateTime<Z> {
+    match quiet_hours {
+        Some(quiet_hours) => quiet_hours.defer(at),
+        None => at,
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    io::{self, Write},
+    sync::{LazyLock, RwLock},
+};
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use secrecy::ExposeSecret;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::core::config::{Config, MiddlewareKind, ServiceKind};
+
+/// What a redacted secret is replaced with.
+pub const MASK: &str = "[REDACTED]";
+
+// Secrets shorter than this would mask too much unrelated text
+const MIN_SECRET_LEN: usize = 4;
+
+/// Masks known secrets and anything matching the configured patterns.
+///
+/// Secrets can be added after construction, e.g. registration tokens as they are generated.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    secrets: RwLock<Vec<String>>,
+    patterns: RwLock<Vec<Regex>>,
+}
+
+impl Redactor {
+    pub fn new(secrets: Vec<String>, patterns: Vec<Regex>) -> Self {
+        let redactor = Self { secrets: RwLock::default(), patterns: RwLock::new(patterns) };
+        for secret in secrets {
+            redactor.add_secret(secret);
+        }
+        redactor
+    }
+
+    /// A redactor for the credentials in `config` and its `redaction.patterns`.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let patterns = config
+            .redaction
+            .patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("invalid redaction pattern '{pattern}'"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self::new(config_secrets(config), patterns))
+    }
+
+    pub fn add_secret(&self, secret: impl Into<String>) {
+        let secret = secret.into();
+        if secret.len() < MIN_SECRET_LEN {
+            return;
+        }
+        let mut secrets = self.secrets.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !secrets.contains(&secret) {
+            secrets.push(secret);
+        }
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut redacted = text.to_string();
+        for secret in self.secrets.read().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+            if redacted.contains(secret.as_str()) {
+                redacted = redacted.replace(secret.as_str(), MASK);
+            }
+        }
+        for pattern in self.patterns.read().unwrap_or_else(|poisoned| poisoned.into_inner()).iter()
+        {
+            redacted = pattern.replace_all(&redacted, mask_match).into_owned();
+        }
+        redacted
+    }
+
+    /// Adds `other`'s secrets and patterns to this redactor's.
+    pub fn merge(&self, other: Redactor) {
+        for secret in other.secrets.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            self.add_secret(secret);
+        }
+        self.patterns
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .extend(other.patterns.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()));
+    }
+}
+
+/// Masks the first capture group if the pattern has one, otherwise the whole match.
+fn mask_match(caps: &Captures) -> String {
+    let whole = caps.get(0).expect("group 0 is always present");
+    match caps.get(1) {
+        Some(secret) => {
+            let text = whole.as_str();
+            let start = secret.start() - whole.start();
+            let end = secret.end() - whole.start();
+            format!("{}{MASK}{}", &text[..start], &text[end..])
+        }
+        None => MASK.to_string(),
+    }
+}
+
+/// Passwords, passphrases and API keys from service and middleware configs, and the error
+/// reporting DSN.
+fn config_secrets(config: &Config) -> Vec<String> {
+    let mut secrets = Vec::new();
+    for service in config.services.values() {
+        match &service.kind {
+            ServiceKind::Matrix { password, db_passphrase, .. } => {
+                secrets.push(password.expose_secret().to_string());
+                secrets.push(db_passphrase.expose_secret().to_string());
+            }
+            ServiceKind::Mumble { password, .. } => {
+                secrets.push(password.expose_secret().to_string());
+            }
+            _ => {}
+        }
+    }
+    for middleware in config.middlewares.values() {
+        match &middleware.kind {
+            MiddlewareKind::MovieShowtimes { gracenote_api_key, .. } => {
+                secrets.push(gracenote_api_key.clone());
+            }
+            MiddlewareKind::StreamAnnounce { twitch_client_secret, youtube_api_key, .. } => {
+                secrets.extend(twitch_client_secret.iter().chain(youtube_api_key).cloned());
+            }
+            MiddlewareKind::TicketBridge { repositories, .. } => {
+                secrets.extend(repositories.values().map(|repo| repo.token.clone()));
+            }
+            _ => {}
+        }
+    }
+    if let Some(reporting) = &config.error_reporting {
+        secrets.push(reporting.dsn.expose_secret().to_string());
+    }
+    secrets
+}
+
+// Process-wide, like the tracing subscriber it feeds
+static REDACTOR: LazyLock<Redactor> = LazyLock::new(Redactor::default);
+
+/// Adds `redactor`'s secrets and patterns to those `redact` masks, typically once the config
+/// has been loaded.
+pub fn install(redactor: Redactor) {
+    REDACTOR.merge(redactor);
+}
+
+/// Masks every installed secret and pattern in `text`.
+pub fn redact(text: &str) -> String {
+    REDACTOR.redact(text)
+}
+
+/// Registers a secret learned at runtime (e.g. a registration token), so later log lines and
+/// transcripts mask it.
+pub fn add_secret(secret: impl Into<String>) {
+    REDACTOR.add_secret(secret);
+}
+
+/// A tracing writer that masks secrets in each formatted event before passing it on.
+pub struct RedactingMakeWriter<M> {
+    inner: M,
+}
+
+impl<M> RedactingMakeWriter<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter { inner: self.inner.make_writer(), buf: Vec::new() }
+    }
+}
+
+/// Buffers one formatted event and writes it, redacted, when dropped.
+pub struct RedactingWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for RedactingWriter<W> {
+    fn drop(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let redacted = redact(&String::from_utf8_lossy(&self.buf));
+        let _ = self.inner.write_all(redacted.as_bytes());
+        let _ = self.inner.flush();
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "matrix")]
+use crate::services::matrix::MatrixService;
+#[cfg(feature = "mumble")]
+use crate::services::mumble::MumbleService;
+use crate::{
+    core::{
+        bus::{self, Bus, Command},
+        config::{Config, ServiceKind},
+        event::Event,
+        middleware,
+        roster::Roster,
+        service::{Service, ServiceCapabilities, ServiceId},
+    },
+    services::{dummy::DummyService, loopback::LoopbackService},
+};
+
+// How long the pipelines must go without producing a command before the replay is considered done
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Reads a recorded event log: one JSON-serialized `Event` per line. Blank lines are skipped.
+pub fn read_event_log(reader: impl BufRead) -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .with_context(|| format!("invalid event on line {}", index + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Stands in for a real service during a replay, recording the commands it's asked to send
+/// instead of sending them.
+pub struct CaptureService {
+    id: ServiceId,
+    capabilities: ServiceCapabilities,
+    captured: Arc<Mutex<Vec<String>>>,
+    next_message_id: AtomicU64,
+}
+
+impl CaptureService {
+    pub fn new(
+        id: ServiceId,
+        capabilities: ServiceCapabilities,
+        captured: Arc<Mutex<Vec<String>>>,
+    ) -> Self {
+        Self { id, capabilities, captured, next_message_id: AtomicU64::new(1) }
+    }
+
+    fn message_id(&self) -> String {
+        format!("$replay-{}-{}", self.id, self.next_message_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[async_trait::async_trait]
+impl Service for CaptureService {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        cancel.cancelled().await;
+        Ok(())
+    }
+
+    async fn handle_command(&self, command: Command) -> Result<()> {
+        let line = match command {
+            Command::SendDirectMessage { service_id, user_id, body, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(self.message_id()));
+                }
+                format!("[{service_id}] DM {user_id}: {body}")
+            }
+            Command::SendRoomMessage { service_id, room_id, body, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(self.message_id()));
+                }
+                format!("[{service_id}] {room_id}: {body}")
+            }
+            Command::SendThreadReply {
+                service_id,
+                room_id,
+                thread_root_id,
+                body,
+                response_tx,
+                ..
+            } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(self.message_id()));
+                }
+                format!("[{service_id}] {room_id} (thread {thread_root_id}): {body}")
+            }
+            Command::EditMessage { service_id, message_id, new_body, .. } => {
+                format!("[{service_id}] edit {message_id}: {new_body}")
+            }
+            Command::DeleteMessage { service_id, message_id, .. } => {
+                format!("[{service_id}] delete {message_id}")
+            }
+            Command::GenerateInviteToken { service_id, user_id, response_tx, .. } => {
+                let _ = response_tx.send(Ok("replay-invite-token".to_string()));
+                format!("[{service_id}] invite token for {user_id}")
+            }
+            Command::AddReaction { service_id, room_id, event_id, key, .. } => {
+                format!("[{service_id}] {room_id}: react {key} to {event_id}")
+            }
+            Command::SendRoomImage { service_id, room_id, caption, source_url, .. } => {
+                format!("[{service_id}] {room_id}: image {source_url} ({caption})")
+            }
+            Command::GetRoomState {
+                service_id,
+                room_id,
+                event_type,
+                state_key,
+                response_tx,
+                ..
+            } => {
+                let _ = response_tx.send(Ok(None));
+                format!("[{service_id}] {room_id}: get state {event_type}/{state_key}")
+            }
+            Command::SetRoomState {
+                service_id,
+                room_id,
+                event_type,
+                state_key,
+                content,
+                response_tx,
+                ..
+            } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(self.message_id()));
+                }
+                format!("[{service_id}] {room_id}: set state {event_type}/{state_key} = {content}")
+            }
+            Command::SetRoomTopic { service_id, room_id, topic, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(self.message_id()));
+                }
+                format!("[{service_id}] {room_id}: set topic {topic}")
+            }
+            Command::ApproveKnock { service_id, room_id, user_id, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+                format!("[{service_id}] {room_id}: approve knock from {user_id}")
+            }
+            Command::KickUser { service_id, room_id, user_id, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+                format!("[{service_id}] {room_id}: kick {user_id}")
+            }
+            Command::JoinRoom { service_id, room, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(room.clone()));
+                }
+                format!("[{service_id}] join {room}")
+            }
+            Command::ConfirmVerification { service_id, flow_id, confirm, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+                let answer = if confirm { "confirm" } else { "deny" };
+                format!("[{service_id}] {answer} verification {flow_id}")
+            }
+            Command::Ping { service_id, response_tx, .. } => {
+                let _ = response_tx.send(Ok(Duration::ZERO));
+                format!("[{service_id}] ping")
+            }
+            Command::GetUserList { service_id, response_tx, .. } => {
+                let _ = response_tx.send(Ok(Vec::new()));
+                format!("[{service_id}] user list")
+            }
+            Command::Announce { targets, body, .. } => {
+                format!("[bus] announce to {} targets: {body}", targets.len())
+            }
+            Command::Control(control) => format!("[bus] {control:?}"),
+        };
+        self.captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(line);
+        Ok(())
+    }
+
+    fn capabilities(&self) -> ServiceCapabilities {
+        self.capabilities
+    }
+}
+
+/// Feeds `events` through the pipelines `config` describes, with every configured service
+/// replaced by a `CaptureService` advertising the real service's capabilities. Returns the
+/// commands the middlewares would have sent, in order.
+///
+/// Middlewares get empty stores in a scratch data directory, so a replay never touches the
+/// bot's real state. Scheduled work (digests, showtimes, ...) only shows up if it comes due
+/// while the replay runs.
+pub async fn replay(mut config: Config, events: Vec<Event>) -> Result<Vec<String>> {
+    static REPLAY_COUNT: AtomicU64 = AtomicU64::new(0);
+    let scratch: PathBuf = std::env::temp_dir().join(format!(
+        "kelvin-replay-{}-{}",
+        std::process::id(),
+        REPLAY_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    config.data_directory = scratch.clone();
+
+    let result = replay_in(&config, events).await;
+    let _ = std::fs::remove_dir_all(&scratch);
+    result
+}
+
+async fn replay_in(config: &Config, events: Vec<Event>) -> Result<Vec<String>> {
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let services: HashMap<ServiceId, Arc<dyn Service>> = config
+        .services
+        .iter()
+        .filter(|(_, service_cfg)| service_cfg.enabled)
+        .map(|(name, service_cfg)| {
+            let id = ServiceId(name.clone());
+            let capabilities = match service_cfg.kind {
+                ServiceKind::Dummy { .. } => DummyService::CAPABILITIES,
+                #[cfg(feature = "matrix")]
+                ServiceKind::Matrix { .. } => MatrixService::CAPABILITIES,
+                #[cfg(feature = "mumble")]
+                ServiceKind::Mumble { .. } => MumbleService::CAPABILITIES,
+                ServiceKind::Loopback { .. } => LoopbackService::CAPABILITIES,
+                _ => ServiceCapabilities::default(),
+            };
+            let service = CaptureService::new(id.clone(), capabilities, captured.clone());
+            (id, Arc::new(service) as Arc<dyn Service>)
+        })
+        .collect();
+
+    let (cmd_tx, cmd_rx) = bus::create_command_channel(1024);
+    let (evt_tx, evt_rx) = bus::create_event_channel(1024);
+    let roster = Roster::from_config(&config.roster);
+    let all_middlewares =
+        middleware::instantiate_middleware_with_roster(config, &cmd_tx, &services, &roster)?;
+    let service_middlewares = middleware::build_service_pipelines(config, &all_middlewares)?;
+    let room_middlewares = middleware::build_room_pipelines(config, &all_middlewares)?;
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, service_middlewares, Default::default())
+        .with_room_pipelines(room_middlewares)
+        .with_room_filters(bus::room_filters_from_config(config))
+        .with_middleware_names(all_middlewares)
+        .with_disabled_middlewares(bus::disabled_middlewares_from_config(config))
+        .with_command_dispatch(config.command_dispatch)
+        .with_command_policy(bus::command_policy_from_config(config))
+        .with_read_only_services(bus::read_only_services_from_config(config))
+        .with_roster(roster);
+    let cancel = CancellationToken::new();
+    let bus_task = {
+        let cancel = cancel.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    for event in events {
+        evt_tx.send(event).await?;
+    }
+
+    // Wait until the pipelines stop producing commands
+    let count = || captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len();
+    loop {
+        let before = count();
+        tokio::time::sleep(SETTLE_TIME).await;
+        if count() == before {
+            break;
+        }
+    }
+
+    cancel.cancel();
+    bus_task.await??;
+
+    let captured = captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    Ok(captured)
+}
@@ -0,0 +1,142 @@
+use std::{collections::BTreeMap, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use tokio::sync::Mutex;
+
+use crate::core::{quiet_hours::QuietHours, service::ServiceId};
+use crate::store::PersistentStore;
+
+/// File in the data directory holding every room's settings.
+pub const ROOM_SETTINGS_STORE_FILE: &str = "room_settings.store.json";
+
+/// One room's settings, by name (e.g. `relay.digest`). Values are kept as text and parsed
+/// when read, so any middleware can add settings without a schema change.
+pub type RoomSettings = BTreeMap<String, String>;
+
+/// Per-room settings shared by every middleware (through `MiddlewareContext::room_settings`),
+/// which room moderators change with `!set` instead of the operator editing the config.
+///
+/// Names are lowercase and dotted by the middleware that reads them (`relay.digest`), apart from
+/// settings several middlewares honor (`quiet_hours`). An unset setting means "use the
+/// middleware's configured behavior".
+#[derive(Clone)]
+pub struct RoomSettingsStore {
+    store: Arc<PersistentStore>,
+    // Serializes read-modify-write updates, which the store alone doesn't
+    update_lock: Arc<Mutex<()>>,
+}
+
+impl RoomSettingsStore {
+    /// Loads the settings kept in `data_directory`.
+    pub fn load(data_directory: &Path) -> Result<Self> {
+        let store = PersistentStore::load(data_directory.join(ROOM_SETTINGS_STORE_FILE))?;
+        Ok(Self::new(Arc::new(store)))
+    }
+
+    /// A store that never writes to disk. Useful for testing.
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(PersistentStore::in_memory()))
+    }
+
+    fn new(store: Arc<PersistentStore>) -> Self {
+        Self { store, update_lock: Arc::default() }
+    }
+
+    /// Every setting of `room_id` on `service_id`.
+    pub async fn all(&self, service_id: &ServiceId, room_id: &str) -> RoomSettings {
+        self.store.get(&key(service_id, room_id)).await.unwrap_or_default()
+    }
+
+    /// The setting `name` of the room parsed as `T`, or `None` if it's unset or doesn't parse.
+    pub async fn get<T: FromStr>(
+        &self,
+        service_id: &ServiceId,
+        room_id: &str,
+        name: &str,
+    ) -> Option<T> {
+        let value = self.all(service_id, room_id).await.remove(name)?;
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            tracing::warn!(service_id=%service_id, room_id=%room_id, setting=%name, value=%value, "ignoring room setting that doesn't parse");
+        }
+        parsed
+    }
+
+    /// An on/off setting of the room, or `None` if it's unset.
+    pub async fn flag(&self, service_id: &ServiceId, room_id: &str, name: &str) -> Option<bool> {
+        self.get::<Flag>(service_id, room_id, name).await.map(|Flag(on)| on)
+    }
+
+    /// Sets `name` to `value` for the room, or unsets it when `value` is `None`, returning
+    /// the room's settings afterwards. Values are checked with `parse_setting` first.
+    pub async fn set(
+        &self,
+        service_id: &ServiceId,
+        room_id: &str,
+        name: &str,
+        value: Option<&str>,
+    ) -> Result<RoomSettings> {
+        let name = parse_setting_name(name)?;
+        let value = value.map(|value| parse_setting(&name, value)).transpose()?;
+        let _guard = self.update_lock.lock().await;
+        let key = key(service_id, room_id);
+        let mut settings: RoomSettings = self.store.get(&key).await.unwrap_or_default();
+        match value {
+            Some(value) => settings.insert(name, value),
+            None => settings.remove(&name),
+        };
+        self.store.set(&key, &settings).await?;
+        Ok(settings)
+    }
+}
+
+fn key(service_id: &ServiceId, room_id: &str) -> String {
+    format!("{service_id}/{room_id}")
+}
+
+/// An on/off value, written `on`/`off`, `true`/`false` or `yes`/`no`.
+struct Flag(bool);
+
+impl FromStr for Flag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "on" | "true" | "yes" => Ok(Flag(true)),
+            "off" | "false" | "no" => Ok(Flag(false)),
+            _ => Err(anyhow!("'{s}' isn't on or off")),
+        }
+    }
+}
+
+/// Checks a setting name given by a user, returning it lowercased.
+pub fn parse_setting_name(name: &str) -> Result<String> {
+    let name = name.trim().to_ascii_lowercase();
+    let well_formed = !name.is_empty()
+        && name.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+    if !well_formed {
+        return Err(anyhow!(
+            "invalid setting '{name}'. Expected letters, digits and _, dotted (e.g., relay.digest)"
+        ));
+    }
+    Ok(name)
+}
+
+/// Checks a value given by a user for the setting `name`, returning it as it's stored: on/off
+/// values as `on` or `off`, and `quiet_hours` as `HH:MM-HH:MM`. Anything else is kept as given.
+pub fn parse_setting(name: &str, value: &str) -> Result<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(anyhow!("no value given for '{name}'"));
+    }
+    if name == "quiet_hours" {
+        return Ok(value.parse::<QuietHours>()?.to_string());
+    }
+    Ok(match value.parse::<Flag>() {
+        Ok(Flag(true)) => "on".to_string(),
+        Ok(Flag(false)) => "off".to_string(),
+        Err(_) => value.to_string(),
+    })
+}
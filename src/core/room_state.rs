@@ -0,0 +1,72 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::core::{
+    bus::{Command, CommandSender},
+    service::ServiceId,
+};
+
+/// Prefix every custom state event type the bot reads or writes must start with, so it can't
+/// touch the room's real state (name, power levels, ...).
+pub const ROOM_STATE_NAMESPACE: &str = "org.kelvinbot.";
+
+/// Checks that `event_type` is one of the bot's own, e.g. `org.kelvinbot.attendance`.
+pub fn check_event_type(event_type: &str) -> Result<()> {
+    match event_type.strip_prefix(ROOM_STATE_NAMESPACE) {
+        Some(name) if !name.is_empty() => Ok(()),
+        _ => bail!("room state event type '{event_type}' must start with '{ROOM_STATE_NAMESPACE}'"),
+    }
+}
+
+/// Reads a custom state event from a room, so a middleware can keep bookkeeping (a live
+/// message's ID, per-room settings) in the room itself. `None` if it was never set.
+///
+/// Only services advertising `supports_room_state` can answer; others respond with an error.
+pub async fn get_room_state<T: DeserializeOwned>(
+    cmd_tx: &CommandSender,
+    service_id: &ServiceId,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+) -> Result<Option<T>> {
+    check_event_type(event_type)?;
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::GetRoomState {
+            service_id: service_id.clone(),
+            room_id: room_id.to_string(),
+            event_type: event_type.to_string(),
+            state_key: state_key.to_string(),
+            response_tx,
+            origin: None,
+        })
+        .await?;
+    let content = response_rx.await.map_err(|_| anyhow!("service dropped the request"))??;
+    Ok(content.map(serde_json::from_value).transpose()?)
+}
+
+/// Writes a custom state event to a room, replacing its previous content. Returns the ID of
+/// the new state event.
+pub async fn set_room_state<T: Serialize>(
+    cmd_tx: &CommandSender,
+    service_id: &ServiceId,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+    content: &T,
+) -> Result<String> {
+    check_event_type(event_type)?;
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::SetRoomState {
+            service_id: service_id.clone(),
+            room_id: room_id.to_string(),
+            event_type: event_type.to_string(),
+            state_key: state_key.to_string(),
+            content: serde_json::to_value(content)?,
+            response_tx: Some(response_tx),
+            origin: None,
+        })
+        .await?;
+    response_rx.await.map_err(|_| anyhow!("service dropped the request"))?
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::core::{
+    config::RosterConfig,
+    event::{Event, EventKind},
+    service::ServiceId,
+};
+
+/// A user the bot has seen on a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownUser {
+    pub id: String,
+    /// The most recent display name seen for the user, if any.
+    pub display_name: Option<String>,
+    /// On services that send user lists (e.g. who's connected to Mumble), whether the latest
+    /// one included the user as active. Always true on services that don't.
+    pub is_active: bool,
+}
+
+#[derive(Default)]
+struct ServiceRoster {
+    users: HashMap<String, KnownUser>,
+    // Room ID -> IDs of the users seen in it, with when they were last active there
+    rooms: HashMap<String, BTreeMap<String, DateTime<Utc>>>,
+}
+
+impl ServiceRoster {
+    fn see_user(&mut self, user_id: &str, display_name: Option<&str>) {
+        let user = self.users.entry(user_id.to_string()).or_insert_with(|| KnownUser {
+            id: user_id.to_string(),
+            display_name: None,
+            is_active: true,
+        });
+        if let Some(display_name) = display_name.filter(|name| !name.is_empty()) {
+            user.display_name = Some(display_name.to_string());
+        }
+    }
+
+    fn see_member(&mut self, room_id: &str, user_id: &str, display_name: Option<&str>) {
+        self.see_user(user_id, display_name);
+        self.rooms.entry(room_id.to_string()).or_default().insert(user_id.to_string(), Utc::now());
+    }
+}
+
+/// The rooms, members and display names seen on each service, kept up to date by the bus from
+/// every event so middlewares can look them up instead of each deriving them from raw events.
+///
+/// Tracking is opt-in (`roster.enabled` in the config). A roster that isn't tracking stays
+/// empty, which middlewares relying on it can detect with `is_enabled`. Only what the bot has
+/// seen is known: a room appears once an event arrives from it, and a member once they've
+/// posted or reacted there. The bot's own events are ignored.
+#[derive(Clone, Default)]
+pub struct Roster {
+    services: Option<Arc<RwLock<HashMap<ServiceId, ServiceRoster>>>>,
+}
+
+impl Roster {
+    /// A roster that records the events it's given.
+    pub fn tracking() -> Self {
+        Self { services: Some(Arc::default()) }
+    }
+
+    /// A tracking roster if `cfg` enables it, otherwise one that stays empty.
+    pub fn from_config(cfg: &RosterConfig) -> Self {
+        if cfg.enabled { Self::tracking() } else { Self::default() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.services.is_some()
+    }
+
+    /// Updates the roster from `evt`. Does nothing unless tracking.
+    pub fn record(&self, evt: &Event) {
+        let Some(mut services) = self.write() else {
+            return;
+        };
+        let roster = services.entry(evt.service_id.clone()).or_default();
+        match &evt.kind {
+            EventKind::RoomMessage { is_self: true, .. }
+            | EventKind::RoomImage { is_self: true, .. }
+            | EventKind::ReactionAdded { is_self: true, .. }
+            | EventKind::ReactionRemoved { is_self: true, .. }
+            | EventKind::DirectMessage { is_self: true, .. } => {}
+            EventKind::UserProfileChanged { user } if user.is_self => {}
+            EventKind::VoiceStateChanged { is_self: true, .. } => {}
+            EventKind::RoomMessage { room_id, sender_id, sender_display_name, .. }
+            | EventKind::RoomImage { room_id, sender_id, sender_display_name, .. }
+            | EventKind::ReactionAdded { room_id, sender_id, sender_display_name, .. } => {
+                roster.see_member(room_id, sender_id, sender_display_name.as_deref());
+            }
+            EventKind::ReactionRemoved { room_id, sender_id, .. } => {
+                roster.see_member(room_id, sender_id, None);
+            }
+            EventKind::DirectMessage { sender_id, sender_display_name, .. } => {
+                roster.see_user(sender_id, sender_display_name.as_deref());
+            }
+            // Whoever knocked isn't a member until they're let in
+            EventKind::Knock { .. } => {}
+            EventKind::UserProfileChanged { user } => {
+                roster.see_user(&user.id, Some(user.display_name.as_str()));
+            }
+            // Listeners aren't in the room they hear, so this says nothing about membership
+            EventKind::VoiceStateChanged { user_id, sender_display_name, .. } => {
+                roster.see_user(user_id, sender_display_name.as_deref());
+            }
+            // Members show up in the new room as they post there
+            EventKind::RoomUpgraded { .. } => {}
+            EventKind::VerificationRequested { .. }
+            | EventKind::ConnectionScheduled { .. }
+            | EventKind::ServiceReady
+            | EventKind::ServiceDegraded { .. }
+            | EventKind::ServiceStopped
+            | EventKind::UserListDiff { .. } => {}
+            EventKind::MissedMessages { room_id, messages, .. } => {
+                for message in messages {
+                    roster.see_member(
+                        room_id,
+                        &message.sender_id,
+                        message.sender_display_name.as_deref(),
+                    );
+                }
+            }
+            EventKind::UserListUpdate { users } => {
+                for user in roster.users.values_mut() {
+                    user.is_active = false;
+                }
+                for user in users.iter().filter(|user| !user.is_self) {
+                    roster.see_user(&user.id, Some(user.display_name.as_str()));
+                    if let Some(known) = roster.users.get_mut(&user.id) {
+                        known.is_active = user.is_active;
+                    }
+                }
+            }
+        }
+    }
+
+    /// IDs of the rooms seen on `service_id`, sorted.
+    pub fn rooms(&self, service_id: &ServiceId) -> Vec<String> {
+        let Some(services) = self.read() else {
+            return Vec::new();
+        };
+        let mut rooms: Vec<String> = services
+            .get(service_id)
+            .map(|roster| roster.rooms.keys().cloned().collect())
+            .unwrap_or_default();
+        rooms.sort();
+        rooms
+    }
+
+    /// Users seen in `room_id` on `service_id`, sorted by ID.
+    pub fn members(&self, service_id: &ServiceId, room_id: &str) -> Vec<KnownUser> {
+        let Some(services) = self.read() else {
+            return Vec::new();
+        };
+        let Some(roster) = services.get(service_id) else {
+            return Vec::new();
+        };
+        roster
+            .rooms
+            .get(room_id)
+            .into_iter()
+            .flat_map(|members| members.keys())
+            .filter_map(|user_id| roster.users.get(user_id).cloned())
+            .collect()
+    }
+
+    /// IDs of the users seen in `room_id` on `service_id`, sorted, with when each last posted
+    /// or reacted there.
+    pub fn last_active(
+        &self,
+        service_id: &ServiceId,
+        room_id: &str,
+    ) -> Vec<(String, DateTime<Utc>)> {
+        let Some(services) = self.read() else {
+            return Vec::new();
+        };
+        services
+            .get(service_id)
+            .and_then(|roster| roster.rooms.get(room_id))
+            .map(|members| members.iter().map(|(id, at)| (id.clone(), *at)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Users on `service_id` that are currently active, sorted by ID.
+    pub fn active_users(&self, service_id: &ServiceId) -> Vec<KnownUser> {
+        let Some(services) = self.read() else {
+            return Vec::new();
+        };
+        let mut users: Vec<KnownUser> = services
+            .get(service_id)
+            .map(|roster| roster.users.values().filter(|user| user.is_active).cloned().collect())
+            .unwrap_or_default();
+        users.sort_by(|a, b| a.id.cmp(&b.id));
+        users
+    }
+
+    pub fn user(&self, service_id: &ServiceId, user_id: &str) -> Option<KnownUser> {
+        self.read()?.get(service_id)?.users.get(user_id).cloned()
+    }
+
+    pub fn display_name(&self, service_id: &ServiceId, user_id: &str) -> Option<String> {
+        self.user(service_id, user_id)?.display_name
+    }
+
+    fn read(&self) -> Option<RwLockReadGuard<'_, HashMap<ServiceId, ServiceRoster>>> {
+        let services = self.services.as_ref()?;
+        Some(services.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    fn write(&self) -> Option<RwLockWriteGuard<'_, HashMap<ServiceId, ServiceRoster>>> {
+        let services = self.services.as_ref()?;
+        Some(services.write().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+/// Longest the scheduler sleeps before checking the wall clock again, by default.
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Waits for wall-clock times on behalf of a scheduled middleware.
+///
+/// Timers run on the monotonic clock, so a wait worked out once and slept in one go drifts
+/// from the wall clock whenever it's changed underneath it, e.g. stepped by NTP or set by hand.
+/// The scheduler instead sleeps at most a minute (`RECHECK_INTERVAL`) at a time and checks the
+/// wall clock again, so a time the clock jumps past fires within the interval and a clock set
+/// back doesn't fire early.
+///
+/// It also remembers the last time it fired, and `now` never goes back behind it, so a clock set
+/// back after a post doesn't bring the same occurrence round again.
+///
+/// Schedules are in local wall-clock time, resolved with `resolve_local` across DST changes: a
+/// time skipped when clocks spring forward fires an hour later that day, and a time repeated
+/// when clocks fall back fires once, at its first occurrence.
+pub struct Scheduler {
+    tz: Tz,
+    clock: fn() -> DateTime<Utc>,
+    recheck_interval: Duration,
+    last_fired: Option<DateTime<Utc>>,
+}
+
+impl Scheduler {
+    pub fn new(tz: Tz) -> Self {
+        Self { tz, clock: Utc::now, recheck_interval: RECHECK_INTERVAL, last_fired: None }
+    }
+
+    /// Reads the wall clock from `clock` instead of the system, e.g. to simulate it jumping.
+    pub fn with_clock(mut self, clock: fn() -> DateTime<Utc>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Checks the wall clock every `interval` instead of every `RECHECK_INTERVAL`.
+    pub fn with_recheck_interval(mut self, interval: Duration) -> Self {
+        self.recheck_interval = interval;
+        self
+    }
+
+    /// The time to work out the next occurrence from: the wall clock in the schedule's zone, or
+    /// the last time fired if the clock has since been set back behind it.
+    pub fn now(&self) -> DateTime<Tz> {
+        let now = (self.clock)();
+        let now = self.last_fired.map_or(now, |fired| now.max(fired));
+        now.with_timezone(&self.tz)
+    }
+
+    /// Waits until the wall clock reaches `at`, right away if it already has, and records `at`
+    /// as fired. Nothing is recorded if the wait is dropped first, e.g. by losing a `select!`.
+    pub async fn wait_until<Z: TimeZone>(&mut self, at: &DateTime<Z>) {
+        let at = at.with_timezone(&Utc);
+        while let Ok(remaining) = (at - (self.clock)()).to_std() {
+            if remaining.is_zero() {
+                break;
+            }
+            tokio::time::sleep(remaining.min(self.recheck_interval)).await;
+        }
+        self.last_fired = Some(at);
+    }
+}
+
+/// Makes the `Scheduler`s for a middleware's schedules, so every scheduled middleware reads the
+/// same wall clock and rechecks it as often, and tests can swap the clock in one place.
+#[derive(Clone, Copy)]
+pub struct SchedulerHandle {
+    clock: fn() -> DateTime<Utc>,
+    recheck_interval: Duration,
+}
+
+impl Default for SchedulerHandle {
+    fn default() -> Self {
+        Self { clock: Utc::now, recheck_interval: RECHECK_INTERVAL }
+    }
+}
+
+impl SchedulerHandle {
+    /// Schedulers made from now on read the wall clock from `clock`.
+    pub fn with_clock(mut self, clock: fn() -> DateTime<Utc>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Schedulers made from now on check the wall clock every `interval`.
+    pub fn with_recheck_interval(mut self, interval: Duration) -> Self {
+        self.recheck_interval = interval;
+        self
+    }
+
+    /// A scheduler for a schedule in zone `tz`.
+    pub fn scheduler(&self, tz: Tz) -> Scheduler {
+        Scheduler::new(tz).with_clock(self.clock).with_recheck_interval(self.recheck_interval)
+    }
+}
@@ -1,21 +1,28 @@
-use std::{collections::HashMap, fmt, sync::Arc};
+#[cfg(feature = "matrix")]
+use std::path::{Component, Path, PathBuf};
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{error, info};
 
+#[cfg(feature = "matrix")]
+use crate::services::matrix::{self, MatrixService, MatrixUserId};
+#[cfg(feature = "mumble")]
+use crate::services::mumble::MumbleService;
 use crate::{
     core::{
-        bus::Command,
-        config::{Config, ServiceKind},
-        event::Event,
+        bus::{Command, EventTx},
+        commands::CommandSpec,
+        config::{Config, ServiceCfg, ServiceKind},
+        format::FormatProfile,
+        http::HttpClient,
+        metrics::MetricsRegistry,
     },
     services::{
         dummy::DummyService,
-        matrix::{MatrixService, MatrixUserId},
-        mumble::MumbleService,
+        loopback::{LoopbackService, LoopbackSettings},
     },
 };
 
@@ -29,80 +36,316 @@ impl fmt::Display for ServiceId {
     }
 }
 
+/// What a service can do with the commands it receives.
+///
+/// Middlewares consult these (via `ServiceDirectory`) to degrade gracefully, e.g. posting a new
+/// message instead of editing one, rather than relying on the service to reject the command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServiceCapabilities {
+    pub supports_edit: bool,
+    /// The format message bodies are rendered into before they're sent (see `format::render`).
+    pub format: FormatProfile,
+    pub supports_invite_tokens: bool,
+    pub supports_attachments: bool,
+    /// Whether the service can keep the bot's custom state events (see `room_state`) in rooms.
+    pub supports_room_state: bool,
+    /// Whether the service can delete the bot's messages (`Command::DeleteMessage`).
+    pub supports_delete: bool,
+    /// Longest message body the service accepts, in characters. `None` means no known limit.
+    pub max_message_length: Option<usize>,
+}
+
 #[async_trait::async_trait]
 pub trait Service: Send + Sync {
     async fn run(&self, cancel: CancellationToken) -> Result<()>;
     async fn handle_command(&self, command: Command) -> Result<()>;
+
+    /// Capabilities advertised to middlewares. Defaults to supporting nothing optional.
+    fn capabilities(&self) -> ServiceCapabilities {
+        ServiceCapabilities::default()
+    }
+
+    /// Resolves once the service is connected and can deliver commands. Services that can
+    /// deliver as soon as `run` starts keep the default, which resolves immediately.
+    async fn ready(&self) {}
+
+    /// Registers the commands of the service's middlewares with its native command system
+    /// (e.g. Discord slash commands), called by the bus once the service is ready. Invocations
+    /// must be delivered as ordinary message events whose body is `CommandSpec::message_text`,
+    /// so middlewares don't need to know where a command came from. Services without one keep
+    /// the default, leaving commands to prefix parsing alone.
+    async fn register_commands(&self, _commands: Vec<CommandSpec>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Read-only view of the configured services, shared with every middleware.
+#[derive(Clone, Default)]
+pub struct ServiceDirectory {
+    capabilities: Arc<HashMap<ServiceId, ServiceCapabilities>>,
+}
+
+impl ServiceDirectory {
+    pub fn from_services(services: &HashMap<ServiceId, Arc<dyn Service>>) -> Self {
+        let capabilities =
+            services.iter().map(|(id, svc)| (id.clone(), svc.capabilities())).collect();
+        Self { capabilities: Arc::new(capabilities) }
+    }
+
+    /// Capabilities of `service_id`, or `None` if no such service is configured.
+    pub fn capabilities(&self, service_id: &ServiceId) -> Option<ServiceCapabilities> {
+        self.capabilities.get(service_id).copied()
+    }
+
+    /// Whether `service_id` is known to support a feature. Unknown services are assumed to
+    /// support it, leaving the final say to the service's `handle_command`.
+    pub fn supports(
+        &self,
+        service_id: &ServiceId,
+        feature: impl Fn(&ServiceCapabilities) -> bool,
+    ) -> bool {
+        self.capabilities(service_id).is_none_or(|caps| feature(&caps))
+    }
+}
+
+/// Rejects service configs whose instances would trample each other's state.
+///
+/// Two Matrix services must not share a sqlite store directory, and must not log in as the
+/// same device of the same account: both would fight over the device's sessions and keys.
+/// Running several instances against one account is fine as long as each has its own
+/// `device_id` (and store, which it gets by default).
+#[cfg(feature = "matrix")]
+pub fn validate_service_instances(config: &Config) -> Result<()> {
+    let mut store_paths: HashMap<PathBuf, &str> = HashMap::new();
+    let mut devices: HashMap<(String, &str, &str), &str> = HashMap::new();
+
+    // Disabled services never open their store, so they can't clash with one that does
+    let mut ids: Vec<&String> =
+        config.services.iter().filter(|(_, scfg)| scfg.enabled).map(|(id, _)| id).collect();
+    ids.sort();
+    for id in ids {
+        let ServiceKind::Matrix { homeserver_url, user_id, device_id, store_subdir, .. } =
+            &config.services[id].kind
+        else {
+            continue;
+        };
+
+        if let Some(subdir) = store_subdir {
+            let mut components = Path::new(subdir).components();
+            if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+            {
+                bail!("service '{id}': store_subdir '{subdir}' must be a single directory name");
+            }
+        }
+
+        let path = matrix::store_path(
+            &config.data_directory,
+            &ServiceId(id.clone()),
+            store_subdir.as_deref(),
+        );
+        if let Some(other) = store_paths.insert(path.clone(), id) {
+            bail!(
+                "services '{other}' and '{id}' both use the matrix store at {}; set a distinct store_subdir for each",
+                path.display()
+            );
+        }
+
+        let device = (homeserver_url.to_string(), user_id.as_str(), device_id.as_str());
+        if let Some(other) = devices.insert(device, id) {
+            bail!(
+                "services '{other}' and '{id}' both log in as device '{device_id}' of {user_id}; give each a distinct device_id"
+            );
+        }
+    }
+    Ok(())
 }
 
 /// Instantiates a map of Services based on given config
 pub async fn instantiate_services_from_config(
     config: &Config,
-    evt_tx: &Sender<Event>,
+    evt_tx: &EventTx,
+    metrics: &MetricsRegistry,
 ) -> Result<HashMap<ServiceId, Arc<dyn Service>>> {
+    let http = HttpClient::from_config(&config.http)?;
+    instantiate_services_with_http(config, evt_tx, metrics, &http).await
+}
+
+/// Like `instantiate_services_from_config`, but services make their own HTTP requests (e.g.
+/// Matrix invite tokens) through `http`, which the caller should also give the middlewares.
+pub async fn instantiate_services_with_http(
+    config: &Config,
+    evt_tx: &EventTx,
+    metrics: &MetricsRegistry,
+    http: &HttpClient,
+) -> Result<HashMap<ServiceId, Arc<dyn Service>>> {
+    #[cfg(feature = "matrix")]
+    validate_service_instances(config)?;
+
     let mut services: HashMap<ServiceId, Arc<dyn Service>> = HashMap::new();
     for (id, scfg) in &config.services {
         let service_id = ServiceId(id.clone());
-        match &scfg.kind {
-            ServiceKind::Dummy { interval_ms } => {
-                let svc = Arc::new(DummyService {
-                    id: service_id.clone(),
-                    interval_ms: interval_ms.unwrap_or(1000),
-                    evt_tx: evt_tx.clone(),
-                });
-                services.insert(service_id, svc);
-            }
-            ServiceKind::Matrix {
-                homeserver_url,
-                user_id,
-                password,
-                device_id,
-                db_passphrase,
-                verification_device_id,
-            } => {
-                match MatrixService::create(
-                    service_id.clone(),
-                    homeserver_url.clone(),
-                    MatrixUserId(user_id.clone()),
-                    password.clone(),
-                    device_id.clone(),
-                    evt_tx.clone(),
-                    config.data_directory.clone(),
-                    db_passphrase.clone(),
-                    verification_device_id.clone(),
-                )
-                .await
-                {
-                    Ok(svc) => {
-                        services.insert(service_id, Arc::new(svc));
-                    }
-                    Err(e) => {
-                        error!(id=%id, error=%e, "could not instantiate matrix service");
-                    }
+        if !scfg.enabled {
+            info!(service_id=%service_id, "service disabled in config, skipping");
+            continue;
+        }
+        if let Some(svc) =
+            instantiate_service(config, &service_id, scfg, evt_tx, metrics, http).await?
+        {
+            services.insert(service_id, svc);
+        }
+    }
+    Ok(services)
+}
+
+/// Instantiates the service configured as `service_id`. Returns `None`, having logged why, for
+/// one that couldn't be created (e.g. its Matrix login failed), which the bot starts without.
+async fn instantiate_service(
+    // Only Matrix services keep anything in the data directory
+    #[cfg_attr(not(feature = "matrix"), allow(unused_variables))] config: &Config,
+    service_id: &ServiceId,
+    scfg: &ServiceCfg,
+    evt_tx: &EventTx,
+    metrics: &MetricsRegistry,
+    #[cfg_attr(not(feature = "matrix"), allow(unused_variables))] http: &HttpClient,
+) -> Result<Option<Arc<dyn Service>>> {
+    let id = &service_id.0;
+    let svc: Arc<dyn Service> = match &scfg.kind {
+        ServiceKind::Dummy { interval_ms } => Arc::new(DummyService {
+            id: service_id.clone(),
+            interval_ms: interval_ms.unwrap_or(1000),
+            evt_tx: evt_tx.clone(),
+            metrics: metrics.for_service(service_id),
+        }),
+        #[cfg(feature = "matrix")]
+        ServiceKind::Matrix {
+            homeserver_url,
+            user_id,
+            password,
+            device_id,
+            db_passphrase,
+            verification_device_id,
+            verification_approval,
+            store_subdir,
+        } => {
+            match MatrixService::create(
+                service_id.clone(),
+                homeserver_url.clone(),
+                MatrixUserId(user_id.clone()),
+                password.clone(),
+                device_id.clone(),
+                evt_tx.clone(),
+                matrix::store_path(&config.data_directory, service_id, store_subdir.as_deref()),
+                db_passphrase.clone(),
+                verification_device_id.clone(),
+                verification_approval.unwrap_or(false),
+                metrics.for_service(service_id),
+                http.clone(),
+            )
+            .await
+            {
+                Ok(svc) => Arc::new(svc),
+                Err(e) => {
+                    error!(id=%id, error=%e, "could not instantiate matrix service");
+                    return Ok(None);
                 }
             }
-            ServiceKind::Mumble { hostname, port, username, password, accept_invalid_certs } => {
-                match MumbleService::create(
-                    service_id.clone(),
-                    hostname.clone(),
-                    *port,
-                    username.clone(),
-                    password.clone(),
-                    accept_invalid_certs.unwrap_or(false),
-                    evt_tx.clone(),
-                )
-                .await
-                {
-                    Ok(svc) => {
-                        services.insert(service_id, Arc::new(svc));
-                    }
-                    Err(e) => {
-                        error!(id=%id, error=%e, "could not instantiate mumble service");
-                    }
+        }
+        #[cfg(feature = "mumble")]
+        ServiceKind::Mumble { hostname, port, username, password, accept_invalid_certs } => {
+            match MumbleService::create(
+                service_id.clone(),
+                hostname.clone(),
+                *port,
+                username.clone(),
+                password.clone(),
+                accept_invalid_certs.unwrap_or(false),
+                evt_tx.clone(),
+                metrics.for_service(service_id),
+            )
+            .await
+            {
+                Ok(svc) => Arc::new(svc),
+                Err(e) => {
+                    error!(id=%id, error=%e, "could not instantiate mumble service");
+                    return Ok(None);
                 }
             }
-            _ => error!(id=%id, "unknown service kind, skipping"),
         }
+        #[cfg(not(feature = "matrix"))]
+        ServiceKind::Matrix { .. } => {
+            bail!(
+                "service '{id}': matrix support isn't built in; rebuild with the `matrix` feature"
+            )
+        }
+        #[cfg(not(feature = "mumble"))]
+        ServiceKind::Mumble { .. } => {
+            bail!(
+                "service '{id}': mumble support isn't built in; rebuild with the `mumble` feature"
+            )
+        }
+        ServiceKind::Loopback { users, room_ids, inject_file, transcript_file, poll_interval } => {
+            if poll_interval.is_some_and(|interval| interval.is_zero()) {
+                bail!("service '{id}': poll_interval must be greater than zero");
+            }
+            Arc::new(LoopbackService::new(
+                service_id.clone(),
+                evt_tx.clone(),
+                metrics.for_service(service_id),
+                LoopbackSettings {
+                    users: users.clone().unwrap_or_default(),
+                    rooms: room_ids.clone().unwrap_or_default(),
+                    inject_file: inject_file.clone(),
+                    transcript_file: transcript_file.clone(),
+                    poll_interval: poll_interval.unwrap_or(Duration::from_millis(500)),
+                },
+            ))
+        }
+        _ => {
+            error!(id=%id, "unknown service kind, skipping");
+            return Ok(None);
+        }
+    };
+    Ok(Some(svc))
+}
+
+/// Builds a service from a freshly loaded config, for adding it to the running bus with
+/// `BusControl::AddService` (e.g. another Matrix account) without a restart.
+#[derive(Clone)]
+pub struct ServiceLoader {
+    evt_tx: EventTx,
+    metrics: MetricsRegistry,
+    http: HttpClient,
+    load_config: Arc<dyn Fn() -> Result<Config> + Send + Sync>,
+}
+
+impl ServiceLoader {
+    /// Builds services that emit their events to `evt_tx` and make HTTP requests through
+    /// `http`, from the config `load_config` returns.
+    pub fn new(
+        evt_tx: EventTx,
+        metrics: MetricsRegistry,
+        http: HttpClient,
+        load_config: impl Fn() -> Result<Config> + Send + Sync + 'static,
+    ) -> Self {
+        Self { evt_tx, metrics, http, load_config: Arc::new(load_config) }
+    }
+
+    /// The service configured as `service_id`, along with the config it was built from, which
+    /// its pipelines are built from in turn.
+    pub async fn load(&self, service_id: &ServiceId) -> Result<(Arc<dyn Service>, Config)> {
+        let config = (self.load_config)().context("failed to load config")?;
+        let Some(scfg) = config.services.get(&service_id.0) else {
+            bail!("service '{service_id}' isn't in the config");
+        };
+        if !scfg.enabled {
+            bail!("service '{service_id}' is disabled in the config");
+        }
+        #[cfg(feature = "matrix")]
+        validate_service_instances(&config)?;
+        let service =
+            instantiate_service(&config, service_id, scfg, &self.evt_tx, &self.metrics, &self.http)
+                .await?
+                .with_context(|| format!("could not instantiate service '{service_id}'"))?;
+        Ok((service, config))
     }
-    Ok(services)
 }
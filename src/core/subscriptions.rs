@@ -0,0 +1,169 @@
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::core::service::ServiceId;
+use crate::store::PersistentStore;
+
+/// File in the data directory holding every topic's subscribers.
+pub const SUBSCRIPTIONS_STORE_FILE: &str = "subscriptions.store.json";
+
+// Store key for the subscribers of every topic
+const TOPICS_KEY: &str = "topics";
+
+/// Someone subscribed to a topic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscriber {
+    pub service_id: String,
+    pub user_id: String,
+    /// The name they went by when they subscribed, used to mention them.
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+impl Subscriber {
+    /// How to address them in a room message.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.user_id)
+    }
+}
+
+/// Who's subscribed to which topics (`!subscribe announcements`), shared by every middleware
+/// (through `MiddlewareContext::subscriptions`) so any of them can reach "everyone subscribed
+/// to X" without keeping its own lists.
+///
+/// Topics are lowercase; users are identified per service, since the same person has different
+/// IDs on each.
+#[derive(Clone)]
+pub struct SubscriptionStore {
+    store: Arc<PersistentStore>,
+    // Serializes read-modify-write updates, which the store alone doesn't
+    update_lock: Arc<Mutex<()>>,
+}
+
+impl SubscriptionStore {
+    /// Loads the subscriptions kept in `data_directory`.
+    pub fn load(data_directory: &Path) -> Result<Self> {
+        let store = PersistentStore::load(data_directory.join(SUBSCRIPTIONS_STORE_FILE))?;
+        Ok(Self::new(Arc::new(store)))
+    }
+
+    /// A store that never writes to disk. Useful for testing.
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(PersistentStore::in_memory()))
+    }
+
+    fn new(store: Arc<PersistentStore>) -> Self {
+        Self { store, update_lock: Arc::default() }
+    }
+
+    async fn topics(&self) -> BTreeMap<String, Vec<Subscriber>> {
+        self.store.get(TOPICS_KEY).await.unwrap_or_default()
+    }
+
+    /// Everyone subscribed to `topic`, in the order they subscribed.
+    pub async fn subscribers(&self, topic: &str) -> Vec<Subscriber> {
+        self.topics().await.remove(&topic.to_lowercase()).unwrap_or_default()
+    }
+
+    /// Everyone subscribed to `topic` on `service_id`.
+    pub async fn subscribers_on(&self, topic: &str, service_id: &ServiceId) -> Vec<Subscriber> {
+        let mut subscribers = self.subscribers(topic).await;
+        subscribers.retain(|subscriber| subscriber.service_id == service_id.0);
+        subscribers
+    }
+
+    /// The topics `user_id` on `service_id` is subscribed to, sorted.
+    pub async fn topics_of(&self, service_id: &ServiceId, user_id: &str) -> Vec<String> {
+        self.topics()
+            .await
+            .into_iter()
+            .filter(|(_, subscribers)| {
+                subscribers.iter().any(|s| s.service_id == service_id.0 && s.user_id == user_id)
+            })
+            .map(|(topic, _)| topic)
+            .collect()
+    }
+
+    /// Every topic with at least one subscriber, sorted, with how many it has.
+    pub async fn topic_counts(&self) -> Vec<(String, usize)> {
+        self.topics()
+            .await
+            .into_iter()
+            .filter(|(_, subscribers)| !subscribers.is_empty())
+            .map(|(topic, subscribers)| (topic, subscribers.len()))
+            .collect()
+    }
+
+    /// Subscribes `user_id` on `service_id` to `topic`, updating the name they're mentioned
+    /// by. Returns whether they weren't subscribed already.
+    pub async fn subscribe(
+        &self,
+        topic: &str,
+        service_id: &ServiceId,
+        user_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool> {
+        let _guard = self.update_lock.lock().await;
+        let mut topics = self.topics().await;
+        let subscribers = topics.entry(topic.to_lowercase()).or_default();
+        let subscriber = Subscriber {
+            service_id: service_id.to_string(),
+            user_id: user_id.to_string(),
+            display_name: display_name.map(str::to_string),
+        };
+        let existing = subscribers
+            .iter()
+            .position(|s| s.service_id == subscriber.service_id && s.user_id == user_id);
+        match existing {
+            Some(index) => subscribers[index] = subscriber,
+            None => subscribers.push(subscriber),
+        }
+        self.store.set(TOPICS_KEY, &topics).await?;
+        Ok(existing.is_none())
+    }
+
+    /// Unsubscribes `user_id` on `service_id` from `topic`. Returns whether they were
+    /// subscribed.
+    pub async fn unsubscribe(
+        &self,
+        topic: &str,
+        service_id: &ServiceId,
+        user_id: &str,
+    ) -> Result<bool> {
+        let _guard = self.update_lock.lock().await;
+        let mut topics = self.topics().await;
+        let topic = topic.to_lowercase();
+        let Some(subscribers) = topics.get_mut(&topic) else {
+            return Ok(false);
+        };
+        let before = subscribers.len();
+        subscribers.retain(|s| s.service_id != service_id.0 || s.user_id != user_id);
+        if subscribers.len() == before {
+            return Ok(false);
+        }
+        if subscribers.is_empty() {
+            topics.remove(&topic);
+        }
+        self.store.set(TOPICS_KEY, &topics).await?;
+        Ok(true)
+    }
+}
+
+/// Checks a topic name given by a user, returning it lowercased: one word of letters, digits,
+/// `-` and `_`.
+pub fn parse_topic(value: &str) -> Result<String> {
+    let topic = value.trim().to_lowercase();
+    let well_formed = !topic.is_empty()
+        && topic.len() <= 32
+        && topic.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if !well_formed {
+        return Err(anyhow!(
+            "invalid topic '{}'. Topics are one word of up to 32 letters, digits, - or _",
+            value.trim()
+        ));
+    }
+    Ok(topic)
+}
@@ -0,0 +1,46 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Resolves a configured IANA time zone name (e.g. `America/Los_Angeles`), falling back to the
+/// host's zone when none is configured.
+pub fn resolve_time_zone(name: Option<&str>) -> Result<Tz> {
+    match name {
+        Some(name) => name.parse::<Tz>().map_err(|_| {
+            anyhow!("unknown time zone '{name}'. Expected an IANA name (e.g., America/Los_Angeles)")
+        }),
+        None => Ok(host_time_zone()),
+    }
+}
+
+/// The host's time zone, or UTC if it can't be determined. Containers are usually UTC.
+pub fn host_time_zone() -> Tz {
+    iana_time_zone::get_timezone().ok().and_then(|name| name.parse().ok()).unwrap_or(Tz::UTC)
+}
+
+pub fn now_in(tz: Tz) -> DateTime<Tz> {
+    Utc::now().with_timezone(&tz)
+}
+
+/// Turns a wall-clock time into an instant in `tz`. A time skipped when clocks spring forward
+/// moves an hour later; a time repeated when clocks fall back resolves to its first occurrence.
+pub fn resolve_local<Z: TimeZone>(tz: &Z, local: NaiveDateTime) -> DateTime<Z> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(local + Duration::hours(1))).earliest())
+        .unwrap_or_else(|| tz.from_utc_datetime(&local))
+}
+
+/// The next `weekday` at `time` in `now`'s zone, today included if `time` hasn't passed yet.
+pub fn next_weekly<Z: TimeZone>(
+    now: &DateTime<Z>,
+    weekday: Weekday,
+    time: NaiveTime,
+) -> DateTime<Z> {
+    let local = now.naive_local();
+    let days_ahead =
+        (weekday.num_days_from_monday() + 7 - local.weekday().num_days_from_monday()) % 7;
+    let days_ahead = if days_ahead == 0 && local.time() >= time { 7 } else { days_ahead };
+    let date = local.date() + Duration::days(days_ahead as i64);
+    resolve_local(&now.timezone(), date.and_time(time))
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use crate::core::{
+    event::{Event, EventKind, User},
+    service::ServiceId,
+};
+
+/// The last user list seen from each service, so the bus can send every user list change both
+/// ways: a `UserListDiff` for each `UserListUpdate` snapshot, and a snapshot for each diff.
+///
+/// Services emit whichever is natural for them (Mumble reports joins and leaves as they happen,
+/// a service that polls sends snapshots), and middlewares consume whichever suits them.
+#[derive(Default)]
+pub struct UserLists {
+    lists: HashMap<ServiceId, Vec<User>>,
+}
+
+impl UserLists {
+    /// Records the user list `event` carries or changes, returning the event that describes the
+    /// same change the other way, sent from the same service.
+    ///
+    /// A snapshot yields the diff from the service's previous snapshot (everyone in the first one
+    /// joined), or `None` if nothing changed. A diff yields the list after it. Other events yield
+    /// `None`.
+    pub fn counterpart(&mut self, event: &Event) -> Option<Event> {
+        let kind = match &event.kind {
+            EventKind::UserListUpdate { users } => {
+                let previous = self.lists.insert(event.service_id.clone(), users.clone());
+                let (joined, left, changed) = diff(previous.as_deref().unwrap_or_default(), users);
+                if joined.is_empty() && left.is_empty() && changed.is_empty() {
+                    return None;
+                }
+                EventKind::UserListDiff { joined, left, changed }
+            }
+            EventKind::UserListDiff { joined, left, changed } => {
+                let users = self.lists.entry(event.service_id.clone()).or_default();
+                users.retain(|user| !left.iter().any(|gone| gone.id == user.id));
+                for user in joined.iter().chain(changed) {
+                    match users.iter_mut().find(|known| known.id == user.id) {
+                        Some(known) => *known = user.clone(),
+                        None => users.push(user.clone()),
+                    }
+                }
+                EventKind::UserListUpdate { users: users.clone() }
+            }
+            _ => return None,
+        };
+        Some(Event { service_id: event.service_id.clone(), kind })
+    }
+}
+
+/// The users who joined, left and changed between the lists `before` and `after`, comparing
+/// users by ID.
+pub fn diff(before: &[User], after: &[User]) -> (Vec<User>, Vec<User>, Vec<User>) {
+    let find = |users: &[User], id: &str| users.iter().find(|user| user.id == id).cloned();
+    let mut joined = Vec::new();
+    let mut changed = Vec::new();
+    for user in after {
+        match find(before, &user.id) {
+            None => joined.push(user.clone()),
+            Some(previous) if previous != *user => changed.push(user.clone()),
+            Some(_) => {}
+        }
+    }
+    let left = before.iter().filter(|user| find(after, &user.id).is_none()).cloned().collect();
+    (joined, left, changed)
+}
@@ -0,0 +1,159 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+use tracing::{error, warn};
+
+use crate::core::{
+    alerts,
+    bus::BusAlert,
+    config::{AlertLevel, WatchdogConfig},
+};
+
+/// Progress reports from the bus loop: when it last moved on, and what it has been doing since.
+///
+/// The loop reports `idle` each time round and `step` before anything that awaits a service,
+/// so a stall can be traced to the step it's stuck in.
+#[derive(Clone)]
+pub struct Heartbeat {
+    state: Arc<Mutex<Beat>>,
+}
+
+struct Beat {
+    at: Instant,
+    step: Option<String>,
+    // How long the step is expected to take, e.g. a restart backoff, before it counts as stalled
+    allowance: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(Beat {
+                at: Instant::now(),
+                step: None,
+                allowance: Duration::ZERO,
+            })),
+        }
+    }
+}
+
+impl Heartbeat {
+    fn set(&self, step: Option<String>, allowance: Duration) {
+        let mut beat = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *beat = Beat { at: Instant::now(), step, allowance };
+    }
+
+    /// The loop is waiting for its next event, command or timer.
+    pub fn idle(&self) {
+        self.set(None, Duration::ZERO);
+    }
+
+    /// The loop started `step`, e.g. `dispatching send_room_message to service 'matrix'`.
+    pub fn step(&self, step: impl Into<String>) {
+        self.set(Some(step.into()), Duration::ZERO);
+    }
+
+    /// The loop started `step`, which is expected to take `allowance` (e.g. a restart backoff).
+    pub fn wait(&self, step: impl Into<String>, allowance: Duration) {
+        self.set(Some(step.into()), allowance);
+    }
+
+    /// How long the loop has gone without progress beyond what its step was expected to take,
+    /// and the step it's in, if any.
+    pub fn stalled_for(&self) -> (Duration, Option<String>) {
+        let beat = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        (beat.at.elapsed().saturating_sub(beat.allowance), beat.step.clone())
+    }
+}
+
+/// Stops the watchdog thread when dropped.
+pub struct WatchdogGuard {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+// Time given to log writers before the process aborts
+const ABORT_GRACE: Duration = Duration::from_secs(1);
+
+/// How often the watchdog checks on the loop, and the loop beats while idle.
+pub fn beat_interval(config: &WatchdogConfig) -> Duration {
+    (config.stall_after / 4).max(Duration::from_millis(10))
+}
+
+/// Starts watching `heartbeat`, reporting once the loop has gone `config.stall_after` without
+/// progress: an error log of the step it's stuck in and the runtime's task counts, and a
+/// `BusAlert::BusStalled` on `alerts`. Aborts the process afterwards when `config.abort` is set.
+///
+/// Runs on a thread of its own rather than a task, so it still fires when a blocking call has
+/// tied up the async runtime's workers. A stall is reported once; the watchdog reports again
+/// only after the loop has recovered and stalled anew.
+pub fn start(
+    heartbeat: Heartbeat,
+    config: &WatchdogConfig,
+    alerts: Option<broadcast::Sender<BusAlert>>,
+) -> WatchdogGuard {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let guard = WatchdogGuard { stopped: stopped.clone() };
+    let stall_after = config.stall_after;
+    let abort = config.abort;
+    let runtime = tokio::runtime::Handle::try_current().ok();
+    let check_interval = beat_interval(config);
+
+    let spawned = std::thread::Builder::new().name("bus-watchdog".to_string()).spawn(move || {
+        let mut reported = false;
+        while !stopped.load(Ordering::Relaxed) {
+            std::thread::sleep(check_interval);
+            let (stalled_for, step) = heartbeat.stalled_for();
+            if stalled_for < stall_after {
+                reported = false;
+                continue;
+            }
+            if reported || stopped.load(Ordering::Relaxed) {
+                continue;
+            }
+            reported = true;
+
+            let metrics = runtime.as_ref().map(|runtime| runtime.metrics());
+            error!(
+                stalled_for_ms=%stalled_for.as_millis(),
+                step=%step.as_deref().unwrap_or("waiting for work"),
+                alive_tasks=?metrics.as_ref().map(|metrics| metrics.num_alive_tasks()),
+                runtime_workers=?metrics.as_ref().map(|metrics| metrics.num_workers()),
+                queued_tasks=?metrics.as_ref().map(|metrics| metrics.global_queue_depth()),
+                "bus loop stalled"
+            );
+            alerts::notify(
+                AlertLevel::Critical,
+                format!(
+                    "the bus loop has made no progress for {}s ({}); events and commands are \
+                     held up until it does",
+                    stalled_for.as_secs(),
+                    step.as_deref().unwrap_or("waiting for work")
+                ),
+            );
+            if let Some(alerts) = &alerts {
+                let _ = alerts.send(BusAlert::BusStalled { stalled_for, step });
+            }
+            if abort {
+                error!("aborting so the process can be restarted");
+                std::thread::sleep(ABORT_GRACE);
+                std::process::abort();
+            }
+        }
+    });
+    if let Err(e) = spawned {
+        warn!(error=%e, "failed to start the bus watchdog");
+    }
+    guard
+}
@@ -1,26 +1,105 @@
+pub mod bot;
 pub mod store;
+pub mod testing;
+
+pub use bot::{KelvinBot, KelvinBotBuilder};
 
 pub mod core {
+    pub mod alerts;
+    #[cfg(feature = "proptest")]
+    pub mod arbitrary;
+    pub mod audit;
+    pub mod backup;
+    pub mod bridge_map;
     pub mod bus;
+    pub mod commands;
     pub mod config;
+    pub mod connection_schedule;
+    pub mod conversation;
+    pub mod coordination;
+    pub mod correlation;
+    pub mod error_reporting;
     pub mod event;
+    pub mod format;
+    pub mod http;
+    pub mod idempotency;
+    pub mod logging;
+    pub mod loop_detection;
+    pub mod media;
+    pub mod metrics;
     pub mod middleware;
+    pub mod migrations;
+    pub mod outbox;
+    pub mod paste;
+    pub mod preferences;
+    pub mod quiet_hours;
+    pub mod redact;
+    pub mod replay;
+    pub mod room_settings;
+    pub mod room_state;
+    pub mod roster;
+    pub mod scheduler;
     pub mod service;
+    pub mod subscriptions;
+    pub mod time_zone;
+    pub mod user_list;
+    pub mod watchdog;
 }
 
 pub mod services {
     pub mod dummy;
+    pub mod loopback;
+    #[cfg(feature = "matrix")]
     pub mod matrix;
+    #[cfg(feature = "mumble")]
     pub mod mumble;
 }
 
 pub mod middlewares {
     pub mod attendance_relay;
+    pub mod audit;
+    pub mod auto_responder;
+    pub mod bus_admin;
+    pub mod chaos;
     pub mod chat_relay;
+    pub mod dm_relay;
     pub mod echo;
+    #[cfg(feature = "web-middlewares")]
     pub mod ezstream_announce;
+    pub mod faq;
+    pub mod feature_flags;
+    pub mod game_status;
+    pub mod impersonation_guard;
+    pub mod inactivity_pruner;
     pub mod invite;
     pub mod logger;
+    #[cfg(feature = "web-middlewares")]
     pub mod movie_showtimes;
+    pub mod picker;
+    pub mod ping;
+    pub mod pipeline;
+    pub mod prefs;
+    #[cfg(feature = "web-middlewares")]
+    pub mod release_tracker;
+    pub mod reload;
+    pub mod router;
+    pub mod scheduled_backup;
+    pub mod settings;
+    pub mod status_topic;
+    #[cfg(feature = "web-middlewares")]
+    pub mod stream_announce;
+    pub mod subscriptions;
+    pub mod tap;
+    #[cfg(feature = "web-middlewares")]
+    pub mod ticket_bridge;
+    #[cfg(feature = "web-middlewares")]
+    pub mod trivia;
+    #[cfg(feature = "web-middlewares")]
+    pub mod update_notifier;
+    pub mod verification_approval;
+    pub mod voice_sessions;
+    pub mod vote_kick;
     pub mod weekly_gathering;
+    pub mod who;
+    pub mod word_filter;
 }
@@ -1,71 +1,122 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::{fs::File, io::BufReader, path::PathBuf};
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
-use tracing_subscriber::{EnvFilter, fmt};
 
-use kelvin_bot::core::{bus, config::load_from_env, middleware, service};
+use kelvin_bot::KelvinBot;
+use kelvin_bot::core::{
+    backup::{self, BackupOptions},
+    commands,
+    config::{config_schema, load_from_env},
+    error_reporting, logging,
+    redact::{self, Redactor},
+    replay,
+};
+
+/// What the bot was started to do, besides running.
+enum Task {
+    Replay(PathBuf),
+    Backup { archive: PathBuf, include_matrix_store: bool },
+    Restore(PathBuf),
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    init_tracing();
-
-    info!("starting...");
-
-    info!("loading configuration...");
-    let cfg = load_from_env()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (profile, rest) = match args.as_slice() {
+        ["--profile", name, rest @ ..] => (Some(name.to_string()), rest),
+        rest => (None, rest),
+    };
+    let task = match rest {
+        [] => None,
+        ["replay", path] => Some(Task::Replay(PathBuf::from(path))),
+        ["backup", path] => {
+            Some(Task::Backup { archive: PathBuf::from(path), include_matrix_store: false })
+        }
+        ["backup", path, "--include-matrix-store"] => {
+            Some(Task::Backup { archive: PathBuf::from(path), include_matrix_store: true })
+        }
+        ["restore", path] => Some(Task::Restore(PathBuf::from(path))),
+        ["config", "schema"] if profile.is_none() => {
+            println!("{}", serde_json::to_string_pretty(&config_schema())?);
+            return Ok(());
+        }
+        _ => anyhow::bail!(
+            "usage: kelvin-bot [--profile <name>] [replay <events.jsonl> | backup <backup.tar.gz> \
+             [--include-matrix-store] | restore <backup.tar.gz>] | kelvin-bot config schema"
+        ),
+    };
 
-    // Event channel: many producers (services) -> one consumer (bus)
-    let (cmd_tx, cmd_rx) = bus::create_command_channel(1024);
-    // Command channel: many producers (middleware) -> one consumer (bus)
-    let (evt_tx, evt_rx) = bus::create_event_channel(1024);
+    // Logging is configured too, so nothing is logged until the config has loaded
+    let cfg = load_from_env(profile.as_deref())?;
+    redact::install(Redactor::from_config(&cfg)?);
 
-    info!("instantiating services...");
-    let services = service::instantiate_services_from_config(&cfg, &evt_tx).await?;
+    // Backups and restores only touch the data directory, so they run before anything opens it
+    match &task {
+        Some(Task::Backup { archive, include_matrix_store }) => {
+            let options =
+                BackupOptions { include_matrix_store: *include_matrix_store, ..Default::default() };
+            let summary = backup::create_backup(&cfg.data_directory, archive, &options)?;
+            println!("backed up {} files to {}", summary.files, archive.display());
+            return Ok(());
+        }
+        Some(Task::Restore(archive)) => {
+            let summary = backup::restore_backup(archive, &cfg.data_directory)?;
+            println!(
+                "restored {} files from the backup taken {} to {}",
+                summary.files,
+                summary.manifest.created_at.to_rfc3339(),
+                cfg.data_directory.display()
+            );
+            return Ok(());
+        }
+        _ => {}
+    }
+    commands::install_command_prefixes(&cfg)?;
+    commands::install_command_aliases(&cfg)?;
+    // Held until main returns so pending reports are flushed. Replays run offline against
+    // recorded events, so their failures aren't reported
+    let reporting = match &cfg.error_reporting {
+        Some(reporting_cfg) if task.is_none() => Some(error_reporting::init(reporting_cfg)?),
+        _ => None,
+    };
+    logging::init(&cfg.logging, &cfg.data_directory, reporting.is_some())?;
 
-    info!("instantiating middlewares...");
-    let all_middlewares = middleware::instantiate_middleware_from_config(&cfg, &cmd_tx)?;
+    info!("starting...");
 
-    info!("building service middleware pipelines...");
-    let mut service_middlewares = std::collections::HashMap::new();
-    for (service_name, service_cfg) in &cfg.services {
-        if let Some(ref middleware_list) = service_cfg.middleware {
-            let pipeline =
-                middleware::build_middleware_pipeline(middleware_list, &all_middlewares)?;
-            service_middlewares.insert(service::ServiceId(service_name.clone()), pipeline);
+    if let Some(Task::Replay(path)) = task {
+        info!("replaying {}...", path.display());
+        let file =
+            File::open(&path).with_context(|| format!("could not open {}", path.display()))?;
+        let events = replay::read_event_log(BufReader::new(file))?;
+        for command in replay::replay(cfg, events).await? {
+            println!("{command}");
         }
+        return Ok(());
     }
 
-    // Start bus
+    let bot = KelvinBot::builder()
+        .config(cfg)
+        // `!reload middleware` reads the config again the same way it was read at startup
+        .config_loader(move || load_from_env(profile.as_deref()))
+        .build()
+        .await?;
+
     let cancel_all = CancellationToken::new();
-    let bus_cancel = cancel_all.child_token();
-    let reconnect_config = cfg.reconnection.clone();
-    let bus_task = tokio::spawn({
-        async move {
-            bus::Bus::new(evt_rx, cmd_rx, services, service_middlewares, reconnect_config)
-                .run(bus_cancel)
-                .await
-        }
-    });
+    let bot_task = tokio::spawn(bot.run(cancel_all.clone()));
 
     // Graceful shutdown on Ctrl+C
     tokio::signal::ctrl_c().await?;
     info!("Ctrl+C received; shutting down…");
     cancel_all.cancel();
 
-    // Join bus
-    match bus_task.await {
+    match bot_task.await {
         Ok(Ok(())) => {}
         Ok(Err(e)) => warn!(?e, "bus error"),
-        Err(e) => warn!(?e, "bus task panicked/aborted"),
+        Err(e) => warn!(?e, "bot task panicked/aborted"),
     }
 
     info!("goodbye");
     Ok(())
 }
-
-fn init_tracing() {
-    let filter =
-        EnvFilter::builder().with_default_directive(tracing::Level::WARN.into()).from_env_lossy();
-
-    fmt().with_env_filter(filter).init();
-}
@@ -1,15 +1,17 @@
 use crate::core::{
-    bus::Command,
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    correlation,
     event::{Event, EventKind},
+    format::BodyFormat,
     middleware::{Middleware, MiddlewareContext, Verdict},
-    service::ServiceId,
+    service::{ServiceDirectory, ServiceId},
 };
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{Mutex, mpsc::Sender};
+use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
 pub struct AttendanceRelayConfig {
@@ -23,7 +25,7 @@ pub struct AttendanceRelayConfig {
 }
 
 pub struct AttendanceRelay {
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     source_service_id: String,
     source_room_id: Option<String>,
     dest_service_id: String,
@@ -32,9 +34,12 @@ pub struct AttendanceRelay {
     session_end_message: String,
     session_ended_edit_message: String,
     state: Arc<Mutex<SessionState>>,
+    services: ServiceDirectory,
 }
 
 struct SessionState {
+    // Active users on the source service, user ID -> display name, kept up to date from diffs
+    present: HashMap<String, String>,
     is_session_active: bool,
     active_participants: HashSet<String>,
     all_participants: HashSet<String>,
@@ -46,6 +51,8 @@ struct SessionState {
 struct DestinationConfig {
     service_id: ServiceId,
     room_id: String,
+    // Destinations without edit support get no live participant list updates
+    supports_edit: bool,
 }
 
 #[derive(Clone)]
@@ -58,6 +65,7 @@ struct MessageTemplates {
 impl SessionState {
     fn new() -> Self {
         Self {
+            present: HashMap::new(),
             is_session_active: false,
             active_participants: HashSet::new(),
             all_participants: HashSet::new(),
@@ -79,6 +87,7 @@ impl AttendanceRelay {
             session_end_message: config.session_end_message,
             session_ended_edit_message: config.session_ended_edit_message,
             state: Arc::new(Mutex::new(SessionState::new())),
+            services: ctx.services,
         }
     }
 }
@@ -98,14 +107,16 @@ impl Middleware for AttendanceRelay {
         Ok(())
     }
 
-    fn on_event(&self, event: &Event) -> Result<Verdict> {
+    fn on_event(&self, event: &Arc<Event>) -> Result<Verdict> {
         // Filter: only handle events from our source service
         if event.service_id.0 != self.source_service_id {
             return Ok(Verdict::Continue);
         }
 
-        // Filter: only handle UserListUpdate events
-        let EventKind::UserListUpdate { users } = &event.kind else {
+        // Filter: only handle UserListDiff events. The bus sends one for each change whichever
+        // way the service reports it, and applying diffs by user ID keeps concurrent updates
+        // from overwriting each other with a stale list.
+        let EventKind::UserListDiff { joined, left, changed } = &event.kind else {
             return Ok(Verdict::Continue);
         };
 
@@ -114,24 +125,26 @@ impl Middleware for AttendanceRelay {
         // and source_room_id should be None
         if let Some(ref expected_room_id) = self.source_room_id {
             // Check if this event has a room_id and if it matches
-            // For now, we'll assume UserListUpdate events don't have room filtering
+            // For now, we'll assume user list events don't have room filtering
             // If needed in the future, we can extend the Event struct
             // For services like Mumble, source_room_id will be None so this check is skipped
             let _ = expected_room_id; // Silence unused warning for now
         }
 
-        // Extract non-self active users
-        let current_active: HashSet<String> = users
-            .iter()
-            .filter(|u| !u.is_self && u.is_active)
-            .map(|u| u.display_name.clone())
-            .collect();
+        // Split the diff into non-self active users and everyone who no longer counts
+        let (present, inactive): (Vec<_>, Vec<_>) =
+            joined.iter().chain(changed).partition(|u| !u.is_self && u.is_active);
+        let present: Vec<(String, String)> =
+            present.into_iter().map(|u| (u.id.clone(), u.display_name.clone())).collect();
+        let gone: Vec<String> = inactive.into_iter().chain(left).map(|u| u.id.clone()).collect();
 
         // Clone data for async task
         let state = self.state.clone();
         let cmd_tx = self.cmd_tx.clone();
+        let dest_service_id = ServiceId(self.dest_service_id.clone());
         let destination = DestinationConfig {
-            service_id: ServiceId(self.dest_service_id.clone()),
+            supports_edit: self.services.supports(&dest_service_id, |caps| caps.supports_edit),
+            service_id: dest_service_id,
             room_id: self.dest_room_id.clone(),
         };
         let messages = MessageTemplates {
@@ -141,8 +154,13 @@ impl Middleware for AttendanceRelay {
         };
 
         // Spawn async task to handle state changes
-        tokio::spawn(async move {
+        correlation::spawn(async move {
             let mut state_guard = state.lock().await;
+            for id in &gone {
+                state_guard.present.remove(id);
+            }
+            state_guard.present.extend(present);
+            let current_active: HashSet<String> = state_guard.present.values().cloned().collect();
 
             if let Err(e) = handle_user_list_change(
                 &mut state_guard,
@@ -164,7 +182,7 @@ impl Middleware for AttendanceRelay {
 async fn handle_user_list_change(
     state: &mut SessionState,
     current_active: HashSet<String>,
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     destination: DestinationConfig,
     messages: MessageTemplates,
 ) -> Result<()> {
@@ -216,7 +234,7 @@ async fn handle_user_list_change(
 async fn handle_session_start(
     state: &mut SessionState,
     current_active: HashSet<String>,
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     destination: DestinationConfig,
     session_start_message: &str,
 ) -> Result<()> {
@@ -230,30 +248,26 @@ async fn handle_session_start(
     // Format the initial message
     let body = format_live_message(session_start_message, &state.active_participants);
 
-    // Send initial message with response channel to get message ID
-    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
-
+    // Send initial message and wait for its message ID
     let command = Command::SendRoomMessage {
         service_id: destination.service_id,
         room_id: destination.room_id,
-        body: body.clone(),
-        markdown_body: Some(body),
-        response_tx: Some(response_tx),
+        body,
+        format: BodyFormat::Markdown,
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
     };
 
-    cmd_tx.send(command).await?;
-
-    // Wait for message ID
-    match response_rx.await {
-        Ok(Ok(message_id)) => {
+    match send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+        Ok(message_id) => {
             state.live_message_id = Some(message_id);
             tracing::info!("session start message sent");
         }
-        Ok(Err(e)) => {
-            tracing::error!(error=%e, "failed to send session start message");
-        }
         Err(e) => {
-            tracing::error!(error=%e, "failed to receive message ID");
+            tracing::error!(error=%e, "failed to send session start message");
         }
     }
 
@@ -263,7 +277,7 @@ async fn handle_session_start(
 async fn handle_session_update(
     state: &mut SessionState,
     current_active: HashSet<String>,
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     destination: DestinationConfig,
     session_start_message: &str,
 ) -> Result<()> {
@@ -273,6 +287,11 @@ async fn handle_session_update(
     }
     state.active_participants = current_active.clone();
 
+    if !destination.supports_edit {
+        tracing::debug!("destination does not support edits, skipping live message update");
+        return Ok(());
+    }
+
     // Edit the live message if we have a message ID
     if let Some(message_id) = &state.live_message_id {
         let body = format_live_message(session_start_message, &state.active_participants);
@@ -280,11 +299,12 @@ async fn handle_session_update(
         let command = Command::EditMessage {
             service_id: destination.service_id,
             message_id: message_id.clone(),
-            new_body: body.clone(),
-            new_markdown_body: Some(body),
+            new_body: body,
+            format: BodyFormat::Markdown,
+            origin: None,
         };
 
-        cmd_tx.send(command).await?;
+        send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await?;
         tracing::debug!(
             "updated live message with {} participants",
             state.active_participants.len()
@@ -296,29 +316,25 @@ async fn handle_session_update(
 
         let body = format_live_message(session_start_message, &state.active_participants);
 
-        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
-
         let command = Command::SendRoomMessage {
             service_id: destination.service_id,
             room_id: destination.room_id,
-            body: body.clone(),
-            markdown_body: Some(body),
-            response_tx: Some(response_tx),
+            body,
+            format: BodyFormat::Markdown,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
         };
 
-        cmd_tx.send(command).await?;
-
-        // Wait for message ID
-        match response_rx.await {
-            Ok(Ok(message_id)) => {
+        match send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+            Ok(message_id) => {
                 state.live_message_id = Some(message_id);
                 tracing::info!("session start message sent (retry after initial failure)");
             }
-            Ok(Err(e)) => {
-                tracing::warn!(error=%e, "failed to send session start message (will retry on next update)");
-            }
             Err(e) => {
-                tracing::warn!(error=%e, "failed to receive message ID (will retry on next update)");
+                tracing::warn!(error=%e, "failed to send session start message (will retry on next update)");
             }
         }
     }
@@ -328,7 +344,7 @@ async fn handle_session_update(
 
 async fn handle_session_end(
     state: &mut SessionState,
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     destination: DestinationConfig,
     session_end_message: &str,
     session_ended_edit_message: &str,
@@ -344,17 +360,20 @@ async fn handle_session_end(
     );
 
     // Edit the original message with the configured ended message
-    if let Some(message_id) = &state.live_message_id {
+    if let Some(message_id) = &state.live_message_id
+        && destination.supports_edit
+    {
         let edit_body = session_ended_edit_message.to_string();
 
         let command = Command::EditMessage {
             service_id: destination.service_id.clone(),
             message_id: message_id.clone(),
-            new_body: edit_body.clone(),
-            new_markdown_body: Some(edit_body),
+            new_body: edit_body,
+            format: BodyFormat::Markdown,
+            origin: None,
         };
 
-        cmd_tx.send(command).await?;
+        send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await?;
     }
 
     // Send summary message
@@ -363,12 +382,19 @@ async fn handle_session_end(
     let command = Command::SendRoomMessage {
         service_id: destination.service_id,
         room_id: destination.room_id,
-        body: summary_body.clone(),
-        markdown_body: Some(summary_body),
+        body: summary_body,
+        format: BodyFormat::Markdown,
         response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
     };
 
-    cmd_tx.send(command).await?;
+    // Don't lose the summary if the destination is mid-reconnect; the session is over either way
+    if let Err(e) = send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+        tracing::error!(error=%e, "failed to send session summary");
+    }
 
     // Reset state
     state.is_session_active = false;
@@ -0,0 +1,112 @@
+use crate::core::{
+    bus::{BusControl, Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+// Entries listed when no count is given, and the most one reply will list
+const DEFAULT_COUNT: usize = 10;
+const MAX_COUNT: usize = 50;
+
+/// Lets admins read the bus's command audit trail over DM, e.g. `!audit recent 20`.
+pub struct Audit {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    admin_user_ids: Vec<String>,
+}
+
+impl Audit {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        admin_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Show recent admin actions")
+            .with_subcommand("recent", vec![ArgSpec::optional("count")]);
+        Self { cmd_tx: ctx.cmd_tx, router, admin_user_ids }
+    }
+}
+
+#[async_trait]
+impl Middleware for Audit {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("audit middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("audit middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage { body, user_id, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self || !self.router.matches(body) {
+            return Ok(Verdict::Continue);
+        }
+
+        // Checked before parsing so non-admins don't even get usage replies
+        if !self.admin_user_ids.iter().any(|admin| admin == sender_id) {
+            tracing::info!(sender_id=%sender_id, "ignoring audit command from non-admin");
+            return Ok(Verdict::Continue);
+        }
+
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let limit = match invocation.get("count").map(str::parse::<usize>) {
+            None => DEFAULT_COUNT,
+            Some(Ok(count)) if count > 0 => count.min(MAX_COUNT),
+            Some(_) => {
+                send_reply(evt, "Count must be a positive number".to_string(), &self.cmd_tx);
+                return Ok(Verdict::Continue);
+            }
+        };
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let control = BusControl::RecentAudit { limit, response_tx: Some(response_tx) };
+        self.cmd_tx.spawn_send(Command::Control(control), "audit trail request");
+
+        let cmd_tx = self.cmd_tx.clone();
+        let service_id = evt.service_id.clone();
+        let user_id = user_id.clone();
+        correlation::spawn(async move {
+            let reply = match response_rx.await {
+                Ok(Ok(entries)) => entries,
+                Ok(Err(e)) => format!("Failed: {e}"),
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to receive audit trail");
+                    return;
+                }
+            };
+
+            let command = Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body: reply,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            };
+            cmd_tx.spawn_send(command, "audit reply");
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
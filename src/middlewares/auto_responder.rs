@@ -0,0 +1,235 @@
+use crate::core::{
+    bus::CommandSender,
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    time_zone::now_in,
+};
+use crate::store::PersistentStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+// Store key for the away notes and who's been answered today
+const AWAY_KEY: &str = "away";
+
+/// An away message someone set with `!away`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AwayNote {
+    pub service_id: String,
+    pub user_id: String,
+    /// The name they went by when they set it, which mentions are matched against too.
+    pub display_name: Option<String>,
+    pub note: String,
+    pub since: DateTime<Utc>,
+}
+
+impl AwayNote {
+    fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.user_id)
+    }
+}
+
+/// A sender already told about someone's away note on `date`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Answered {
+    away_service_id: String,
+    away_user_id: String,
+    sender_service_id: String,
+    sender_id: String,
+    date: NaiveDate,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct AwayState {
+    notes: Vec<AwayNote>,
+    answered: Vec<Answered>,
+}
+
+/// Whether `body` mentions `name`: contains it, ignoring case, as a whole word (so `@alice`
+/// and `alice:` count, but `malice` doesn't).
+pub fn mentions(body: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let body = body.to_lowercase();
+    let name = name.to_lowercase();
+    body.match_indices(&name).any(|(start, _)| {
+        let before = body[..start].chars().next_back();
+        let after = body[start + name.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Out-of-office replies: `!away <message>` sets an away message and `!away` on its own clears
+/// it. While it's set, anyone who mentions the user in a room, or in a DM to the bot, gets the
+/// message as a reply, at most once a day per sender. Mentions are matched by user ID and by
+/// display name on every service the bot is on, so bridged messages count too. Away messages
+/// are kept in the store across restarts.
+pub struct AutoResponder {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    router: CommandRouter,
+    /// Days, for answering once a day, are counted in this zone.
+    timezone: Tz,
+    state: Arc<Mutex<AwayState>>,
+}
+
+impl AutoResponder {
+    pub fn new(ctx: MiddlewareContext, command_string: String, timezone: Tz) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Set an away message, or clear it when given none")
+            .with_args(vec![ArgSpec::optional_rest("message")]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            router,
+            timezone,
+            state: Arc::new(Mutex::new(AwayState::default())),
+        }
+    }
+
+    /// Sets or, when `note` is empty, clears `sender_id`'s away message.
+    fn set_away(&self, evt: &Arc<Event>, sender_id: &str, display_name: Option<&str>, note: &str) {
+        let service_id = evt.service_id.to_string();
+        let reply = {
+            let mut state = self.state.lock().unwrap();
+            let existing = state
+                .notes
+                .iter()
+                .position(|away| away.service_id == service_id && away.user_id == sender_id);
+            match (existing, note.is_empty()) {
+                (None, true) => {
+                    send_reply(
+                        evt,
+                        format!(
+                            "You're not away. Usage: {} <message>",
+                            self.router.prefix_on(&evt.service_id)
+                        ),
+                        &self.cmd_tx,
+                    );
+                    return;
+                }
+                (Some(index), true) => {
+                    let away = state.notes.remove(index);
+                    state.answered.retain(|answered| {
+                        answered.away_service_id != away.service_id
+                            || answered.away_user_id != away.user_id
+                    });
+                    "Welcome back! Your away message is cleared".to_string()
+                }
+                (existing, false) => {
+                    let away = AwayNote {
+                        service_id: service_id.clone(),
+                        user_id: sender_id.to_string(),
+                        display_name: display_name.map(str::to_string),
+                        note: note.to_string(),
+                        since: Utc::now(),
+                    };
+                    match existing {
+                        Some(index) => state.notes[index] = away,
+                        None => state.notes.push(away),
+                    }
+                    format!("You're away: {note}. People who mention you will be told")
+                }
+            }
+        };
+        tracing::info!(user_id=%sender_id, away = !note.is_empty(), "away message changed");
+        self.save();
+        send_reply(evt, reply, &self.cmd_tx);
+    }
+
+    /// Replies to `sender_id` with the away messages of whoever `body` mentions, skipping
+    /// those they've already been told about today.
+    fn answer_mentions(&self, evt: &Arc<Event>, sender_id: &str, body: &str) {
+        let sender_service_id = evt.service_id.to_string();
+        let today = now_in(self.timezone).date_naive();
+        let replies: Vec<String> = {
+            let mut state = self.state.lock().unwrap();
+            let AwayState { notes, answered } = &mut *state;
+            answered.retain(|answered| answered.date == today);
+            notes
+                .iter()
+                .filter(|away| away.service_id != sender_service_id || away.user_id != sender_id)
+                .filter(|away| mentions(body, &away.user_id) || mentions(body, away.name()))
+                .filter_map(|away| {
+                    let entry = Answered {
+                        away_service_id: away.service_id.clone(),
+                        away_user_id: away.user_id.clone(),
+                        sender_service_id: sender_service_id.clone(),
+                        sender_id: sender_id.to_string(),
+                        date: today,
+                    };
+                    if answered.contains(&entry) {
+                        return None;
+                    }
+                    answered.push(entry);
+                    Some(format!("{} is away: {}", away.name(), away.note))
+                })
+                .collect()
+        };
+        if replies.is_empty() {
+            return;
+        }
+        self.save();
+        send_reply(evt, replies.join("\n"), &self.cmd_tx);
+    }
+
+    fn save(&self) {
+        let snapshot = self.state.lock().unwrap().clone();
+        let store = self.store.clone();
+        correlation::spawn(async move {
+            if let Err(e) = store.set(AWAY_KEY, &snapshot).await {
+                tracing::warn!(error=%e, "failed to save away messages");
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Middleware for AutoResponder {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if let Some(saved) = self.store.get::<AwayState>(AWAY_KEY).await {
+            *self.state.lock().unwrap() = saved;
+        }
+        tracing::info!("auto_responder middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("auto_responder middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let (EventKind::RoomMessage {
+            body, sender_id, sender_display_name, is_self: false, ..
+        }
+        | EventKind::DirectMessage {
+            body, sender_id, sender_display_name, is_self: false, ..
+        }) = &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+
+        if self.router.matches(body) {
+            if let Some(invocation) = self.router.route(evt, &self.cmd_tx) {
+                let note = invocation.get("message").unwrap_or_default().trim();
+                self.set_away(evt, sender_id, sender_display_name.as_deref(), note);
+            }
+        } else {
+            self.answer_mentions(evt, sender_id, body);
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,142 @@
+use crate::core::{
+    bus::{BusControl, Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec, Invocation},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Lets admins toggle bus runtime controls over DM, e.g. `!bus pause mumble_main`.
+pub struct BusAdmin {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    admin_user_ids: Vec<String>,
+}
+
+impl BusAdmin {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        admin_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Pause, resume or add services and turn middlewares on or off")
+            .with_subcommand("pause", vec![ArgSpec::required("service")])
+            .with_subcommand("resume", vec![ArgSpec::required("service")])
+            .with_subcommand("add", vec![ArgSpec::required("service")])
+            .with_subcommand("disable", vec![ArgSpec::required("middleware")])
+            .with_subcommand("enable", vec![ArgSpec::required("middleware")]);
+        Self { cmd_tx: ctx.cmd_tx, router, admin_user_ids }
+    }
+
+    /// Turns a parsed `<action> <target>` invocation into a control command.
+    fn control_for(
+        invocation: &Invocation,
+        response_tx: tokio::sync::oneshot::Sender<anyhow::Result<String>>,
+    ) -> Option<BusControl> {
+        let response_tx = Some(response_tx);
+        match invocation.subcommand? {
+            "pause" => Some(BusControl::PauseService {
+                service_id: ServiceId(invocation.arg("service").to_string()),
+                response_tx,
+            }),
+            "resume" => Some(BusControl::ResumeService {
+                service_id: ServiceId(invocation.arg("service").to_string()),
+                response_tx,
+            }),
+            "add" => Some(BusControl::AddService {
+                service_id: ServiceId(invocation.arg("service").to_string()),
+                response_tx,
+            }),
+            "disable" => Some(BusControl::DisableMiddleware {
+                name: invocation.arg("middleware").to_string(),
+                response_tx,
+            }),
+            "enable" => Some(BusControl::EnableMiddleware {
+                name: invocation.arg("middleware").to_string(),
+                response_tx,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for BusAdmin {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("bus_admin middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("bus_admin middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage { body, user_id, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self {
+            return Ok(Verdict::Continue);
+        }
+
+        if !self.router.matches(body) {
+            return Ok(Verdict::Continue);
+        }
+
+        // Checked before parsing so non-admins don't even get usage replies
+        if !self.admin_user_ids.iter().any(|admin| admin == sender_id) {
+            tracing::info!(sender_id=%sender_id, "ignoring bus admin command from non-admin");
+            return Ok(Verdict::Continue);
+        }
+
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let Some(control) = Self::control_for(&invocation, response_tx) else {
+            return Ok(Verdict::Continue);
+        };
+
+        self.cmd_tx.spawn_send(Command::Control(control), "bus control command");
+
+        let cmd_tx = self.cmd_tx.clone();
+        let service_id = evt.service_id.clone();
+        let user_id = user_id.clone();
+        correlation::spawn(async move {
+            let reply = match response_rx.await {
+                Ok(Ok(message)) => message,
+                Ok(Err(e)) => format!("Failed: {e}"),
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to receive bus control response");
+                    return;
+                }
+            };
+
+            let command = Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body: reply,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            };
+            cmd_tx.spawn_send(command, "bus admin reply");
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
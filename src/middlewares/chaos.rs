@@ -0,0 +1,143 @@
+use crate::core::{
+    bus::{BusControl, Command, CommandSender, Fault},
+    commands::{ArgSpec, CommandRouter, CommandSpec, Invocation, parse_duration},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Lets admins inject simulated service failures over DM, e.g. `!chaos crash mumble_main`, so
+/// supervision, backoff and the outbox can be exercised without waiting for a real outage.
+/// Only available in debug builds.
+pub struct Chaos {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    admin_user_ids: Vec<String>,
+}
+
+impl Chaos {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        admin_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Inject simulated service failures")
+            .with_subcommand("crash", vec![ArgSpec::required("service")])
+            .with_subcommand(
+                "delay",
+                vec![ArgSpec::required("service"), ArgSpec::required("duration")],
+            )
+            .with_subcommand(
+                "drop",
+                vec![ArgSpec::required("service"), ArgSpec::required("percent")],
+            )
+            .with_subcommand("clear", vec![ArgSpec::required("service")]);
+        Self { cmd_tx: ctx.cmd_tx, router, admin_user_ids }
+    }
+
+    /// The fault a parsed invocation asks for, or why its arguments don't make sense.
+    fn fault_for(invocation: &Invocation) -> Option<Result<Fault, String>> {
+        let fault = match invocation.subcommand? {
+            "crash" => Ok(Fault::Crash),
+            "delay" => parse_duration(invocation.arg("duration")).map(Fault::DelayCommands),
+            "drop" => parse_percent(invocation.arg("percent")).map(Fault::DropEvents),
+            "clear" => Ok(Fault::Clear),
+            _ => return None,
+        };
+        Some(fault)
+    }
+}
+
+/// Parses a whole percentage such as `25` or `25%` into a share from 0.0 to 1.0.
+fn parse_percent(input: &str) -> Result<f64, String> {
+    match input.trim_end_matches('%').parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(f64::from(percent) / 100.0),
+        _ => Err(format!("'{input}' isn't a percentage from 0 to 100")),
+    }
+}
+
+#[async_trait]
+impl Middleware for Chaos {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("chaos middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("chaos middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage { body, user_id, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self || !self.router.matches(body) {
+            return Ok(Verdict::Continue);
+        }
+
+        // Checked before parsing so non-admins don't even get usage replies
+        if !self.admin_user_ids.iter().any(|admin| admin == sender_id) {
+            tracing::info!(sender_id=%sender_id, "ignoring chaos command from non-admin");
+            return Ok(Verdict::Continue);
+        }
+
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let Some(fault) = Self::fault_for(&invocation) else {
+            return Ok(Verdict::Continue);
+        };
+        let service_id = ServiceId(invocation.arg("service").to_string());
+
+        let response_rx = fault.map(|fault| {
+            let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+            let control =
+                BusControl::InjectFault { service_id, fault, response_tx: Some(response_tx) };
+            self.cmd_tx.spawn_send(Command::Control(control), "fault injection request");
+            response_rx
+        });
+
+        let cmd_tx = self.cmd_tx.clone();
+        let reply_service_id = evt.service_id.clone();
+        let user_id = user_id.clone();
+        correlation::spawn(async move {
+            let reply = match response_rx {
+                Ok(response_rx) => match response_rx.await {
+                    Ok(Ok(message)) => message,
+                    Ok(Err(e)) => format!("Failed: {e}"),
+                    Err(e) => {
+                        tracing::error!(error=%e, "failed to receive fault injection response");
+                        return;
+                    }
+                },
+                Err(e) => e,
+            };
+
+            let command = Command::SendDirectMessage {
+                service_id: reply_service_id,
+                user_id,
+                body: reply,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            };
+            cmd_tx.spawn_send(command, "chaos reply");
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
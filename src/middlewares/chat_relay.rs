@@ -1,18 +1,52 @@
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::sync::mpsc::Sender;
+use chrono::Local;
+use regex::Regex;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 
 use crate::core::{
-    bus::Command,
-    event::{Event, EventKind},
+    bridge_map::BridgeMap,
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    correlation,
+    event::{Event, EventKind, MissedMessage, Provenance},
+    format::BodyFormat,
+    http::HttpClient,
+    idempotency::fresh_key,
+    loop_detection::RecentRelays,
+    media::MediaStore,
     middleware::{Middleware, MiddlewareContext, Verdict},
-    service::ServiceId,
+    paste::{self, Paster},
+    quiet_hours::QuietHours,
+    service::{ServiceDirectory, ServiceId},
 };
 
+/// What a relay does with messages its source reports were sent while the bot was offline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CatchUp {
+    /// Drop them.
+    #[default]
+    Skip,
+    /// Relay the most recent `limit`, noting how many earlier ones were left out.
+    Relay { limit: usize },
+    /// Post how many there were.
+    Summarize,
+}
+
+/// Messages relayed when catching up, unless configured otherwise.
+pub const DEFAULT_CATCH_UP_LIMIT: usize = 20;
+
+/// Links longer than this are shortened for destinations that limit message length, when
+/// media storage serves links.
+pub const SHORTEN_LINKS_LONGER_THAN: usize = 40;
+
+// Trailing punctuation is more likely the end of the sentence than part of the link
+static LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"https?://[^\s<>]*[^\s<>.,;:!?)\]'"]"#).unwrap());
+
 pub struct ChatRelayConfig {
     pub source_service_id: String,
     pub source_room_id: Option<String>,
@@ -22,19 +56,43 @@ pub struct ChatRelayConfig {
     pub thumbnail_max_width: u32,
     pub thumbnail_max_height: u32,
     pub thumbnail_jpeg_quality: u8,
+    /// When set, text messages are collected and posted as one digest per window
+    pub digest_window: Option<Duration>,
+    pub catch_up: CatchUp,
+    /// When set, text messages whose content was relayed to the destination room within the
+    /// window are dropped, as another bridge has likely carried them back around
+    pub loop_window: Option<Duration>,
 }
 
 pub struct ChatRelay {
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     source_service_id: String,
-    source_room_id: Option<String>,
+    // Follows the room when it's upgraded
+    source_room_id: Mutex<Option<String>>,
     dest_service_id: String,
     dest_room_id: String,
     prefix_tag: String,
-    http_client: reqwest::Client,
+    http_client: HttpClient,
     thumbnail_max_width: u32,
     thumbnail_max_height: u32,
     thumbnail_jpeg_quality: u8,
+    services: ServiceDirectory,
+    // Hosts images for destinations that can't take attachments and short links for ones that
+    // limit message length, when configured
+    media: MediaStore,
+    // Uploads messages too long for the destination, so they're relayed cut short with a link
+    paste: Paster,
+    // Remembers which message in the destination is the copy of which, so thread replies
+    // land in the copy of their thread
+    bridge_map: BridgeMap,
+    // What every relay sent to each room lately, for spotting messages that loop back
+    recent_relays: RecentRelays,
+    loop_window: Option<Duration>,
+    digest_window: Option<Duration>,
+    catch_up: CatchUp,
+    quiet_hours: Option<QuietHours>,
+    // Formatted lines waiting for the next digest or the end of quiet hours
+    pending_digest: Mutex<Vec<String>>,
 }
 
 impl ChatRelay {
@@ -42,17 +100,54 @@ impl ChatRelay {
         Self {
             cmd_tx: ctx.cmd_tx,
             source_service_id: config.source_service_id,
-            source_room_id: config.source_room_id,
+            source_room_id: Mutex::new(config.source_room_id),
             dest_service_id: config.dest_service_id,
             dest_room_id: config.dest_room_id,
             prefix_tag: config.prefix_tag,
-            http_client: reqwest::Client::new(),
+            http_client: ctx.http,
             thumbnail_max_width: config.thumbnail_max_width,
             thumbnail_max_height: config.thumbnail_max_height,
             thumbnail_jpeg_quality: config.thumbnail_jpeg_quality,
+            services: ctx.services,
+            media: ctx.media,
+            paste: ctx.paste,
+            bridge_map: ctx.bridge_map,
+            recent_relays: ctx.recent_relays,
+            loop_window: config.loop_window,
+            digest_window: config.digest_window,
+            catch_up: config.catch_up,
+            quiet_hours: ctx.quiet_hours,
+            pending_digest: Mutex::new(Vec::new()),
         }
     }
 
+    /// Whether `room_id` is the room to relay from; any room when none is configured.
+    fn is_source_room(&self, room_id: &str) -> bool {
+        self.source_room_id.lock().unwrap().as_deref().is_none_or(|expected| expected == room_id)
+    }
+
+    /// Swaps long links in `body` for short ones when the destination limits message length,
+    /// so they aren't cut off. Links that can't be shortened are left as they are.
+    fn shorten_links(media: &MediaStore, body: String, max_chars: Option<usize>) -> String {
+        if max_chars.is_none() || !media.hosts_links() {
+            return body;
+        }
+        LINK.replace_all(&body, |link: &regex::Captures| {
+            let url = &link[0];
+            if url.chars().count() <= SHORTEN_LINKS_LONGER_THAN {
+                return url.to_string();
+            }
+            match media.shorten(url) {
+                Ok(short) => short.to_string(),
+                Err(e) => {
+                    debug!(error=%e, "failed to shorten link");
+                    url.to_string()
+                }
+            }
+        })
+        .into_owned()
+    }
+
     fn format_relayed_message(
         prefix_tag: &str,
         sender_id: &str,
@@ -63,9 +158,160 @@ impl ChatRelay {
         format!("[{}] {}: {}", prefix_tag, sender_display, body)
     }
 
+    fn is_quiet_now(&self) -> bool {
+        self.quiet_hours.is_some_and(|quiet_hours| quiet_hours.is_quiet_at(&Local::now()))
+    }
+
+    /// Packs `lines` into as few messages as fit within `max_chars`, one line per row. Lines
+    /// too long to fit a message on their own are truncated.
+    fn pack_digest(lines: Vec<String>, max_chars: Option<usize>) -> Vec<Vec<String>> {
+        let mut messages: Vec<Vec<String>> = Vec::new();
+        let mut current_len = 0;
+        for line in lines {
+            let line = match max_chars {
+                Some(max) => paste::truncate(&line, max),
+                None => line,
+            };
+            let line_len = line.chars().count();
+            match messages.last_mut() {
+                // +1 for the newline joining it to the previous line
+                Some(current) if max_chars.is_none_or(|max| current_len + 1 + line_len <= max) => {
+                    current_len += 1 + line_len;
+                    current.push(line);
+                }
+                _ => {
+                    current_len = line_len;
+                    messages.push(vec![line]);
+                }
+            }
+        }
+        messages
+    }
+
+    /// Posts everything collected since the last digest.
+    async fn flush_digest(&self) {
+        let lines = std::mem::take(&mut *self.pending_digest.lock().unwrap());
+        if lines.is_empty() {
+            return;
+        }
+        self.post_lines(lines).await;
+    }
+
+    /// A future posting `lines` to the destination, packed into as few messages as it allows.
+    /// Doesn't borrow `self`, so it can be spawned.
+    fn post_lines(&self, lines: Vec<String>) -> impl Future<Output = ()> + Send + 'static {
+        let cmd_tx = self.cmd_tx.clone();
+        let dest_service_id = ServiceId(self.dest_service_id.clone());
+        let dest_room_id = self.dest_room_id.clone();
+        let max_chars =
+            self.services.capabilities(&dest_service_id).and_then(|caps| caps.max_message_length);
+        let lines: Vec<String> = lines
+            .into_iter()
+            .map(|line| Self::shorten_links(&self.media, line, max_chars))
+            .collect();
+        let paste = self.paste.clone();
+        async move {
+            debug!(lines=%lines.len(), dest_service=%dest_service_id, "posting chat relay digest");
+            // Lines too long for a message of their own are pasted before packing
+            let mut fitted = Vec::with_capacity(lines.len());
+            for line in lines {
+                fitted.push(paste.fit(line, max_chars).await);
+            }
+            for message in Self::pack_digest(fitted, max_chars) {
+                let command = Command::SendRoomMessage {
+                    service_id: dest_service_id.clone(),
+                    room_id: dest_room_id.clone(),
+                    body: message.join("\n"),
+                    format: BodyFormat::Plain,
+                    response_tx: None,
+                    origin: None,
+                    idempotency_key: Some(fresh_key()),
+                    relayed_from: None,
+                    expires_after: None,
+                };
+                if let Err(e) = send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+                    error!(
+                        dest_service=%dest_service_id.0,
+                        dest_room=%dest_room_id,
+                        error=%e,
+                        "failed to send chat relay digest"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Lines to post for messages missed while the bot was offline, per the catch-up setting.
+    /// Messages another bridge brought over from the destination are left out, as when live.
+    fn catch_up_lines(&self, messages: &[MissedMessage], truncated: bool) -> Vec<String> {
+        let messages: Vec<&MissedMessage> = messages
+            .iter()
+            .filter(|message| {
+                message
+                    .relayed_from
+                    .as_ref()
+                    .is_none_or(|from| from.service_id != self.dest_service_id)
+            })
+            .collect();
+        if messages.is_empty() {
+            return Vec::new();
+        }
+        match self.catch_up {
+            CatchUp::Skip => Vec::new(),
+            CatchUp::Summarize => {
+                vec![Self::format_missed_summary(
+                    &self.prefix_tag,
+                    messages.len(),
+                    truncated,
+                    false,
+                )]
+            }
+            CatchUp::Relay { limit } => {
+                let left_out = messages.len().saturating_sub(limit);
+                let mut lines = Vec::new();
+                if left_out > 0 || truncated {
+                    lines.push(Self::format_missed_summary(
+                        &self.prefix_tag,
+                        left_out,
+                        truncated,
+                        true,
+                    ));
+                }
+                lines.extend(messages[left_out..].iter().map(|message| {
+                    Self::format_relayed_message(
+                        &self.prefix_tag,
+                        &message.sender_id,
+                        message.sender_display_name.as_deref(),
+                        &message.body,
+                    )
+                }));
+                lines
+            }
+        }
+    }
+
+    /// E.g. "[Mumble] 37 messages were sent while the bridge was down". `earlier` when the
+    /// most recent ones are relayed after it.
+    fn format_missed_summary(
+        prefix_tag: &str,
+        count: usize,
+        truncated: bool,
+        earlier: bool,
+    ) -> String {
+        let (noun, verb) =
+            if count == 1 && !truncated { ("message", "was") } else { ("messages", "were") };
+        let count = match (truncated, count) {
+            (true, 0) => "Some".to_string(),
+            (true, count) => format!("More than {count}"),
+            (false, count) => count.to_string(),
+        };
+        let earlier = if earlier { " earlier" } else { "" };
+        format!("[{prefix_tag}] {count}{earlier} {noun} {verb} sent while the bridge was down")
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn send_text_fallback(
-        cmd_tx: &Sender<Command>,
+        cmd_tx: &CommandSender,
         dest_service_id: &ServiceId,
         dest_room_id: &str,
         prefix_tag: &str,
@@ -80,19 +326,23 @@ impl ChatRelay {
         let command = Command::SendRoomMessage {
             service_id: dest_service_id.clone(),
             room_id: dest_room_id.to_string(),
-            body: text.clone(),
-            markdown_body: Some(text),
+            body: text,
+            format: BodyFormat::Plain,
             response_tx: None,
+            origin: None,
+            idempotency_key: Some(fresh_key()),
+            relayed_from: None,
+            expires_after: None,
         };
-        if let Err(e) = cmd_tx.send(command).await {
+        if let Err(e) = send_with_retry(cmd_tx, command, &RetryPolicy::default()).await {
             error!(error=%e, "failed to send text fallback for image relay");
         }
     }
 
     #[allow(clippy::too_many_arguments)]
     async fn relay_image(
-        http_client: reqwest::Client,
-        cmd_tx: Sender<Command>,
+        http_client: HttpClient,
+        cmd_tx: CommandSender,
         dest_service_id: ServiceId,
         dest_room_id: String,
         prefix_tag: String,
@@ -234,11 +484,10 @@ impl ChatRelay {
             source_url,
             thumbnail_data,
             thumbnail_mimetype: "image/jpeg".to_string(),
+            origin: None,
         };
 
-        if let Err(e) = cmd_tx.send(command).await {
-            error!(error=%e, "failed to send SendRoomImage command");
-        }
+        cmd_tx.spawn_send(command, "relayed image");
     }
 }
 
@@ -247,18 +496,53 @@ impl Middleware for ChatRelay {
     async fn run(&self, cancel: CancellationToken) -> Result<()> {
         info!(
             source_service=%self.source_service_id,
-            source_room=?self.source_room_id,
+            source_room=?self.source_room_id.lock().unwrap(),
             dest_service=%self.dest_service_id,
             dest_room=%self.dest_room_id,
             prefix_tag=%self.prefix_tag,
+            digest_window=?self.digest_window,
+            loop_window=?self.loop_window,
+            quiet_hours=?self.quiet_hours,
             "chat_relay middleware running..."
         );
-        cancel.cancelled().await;
+        let mut digest_ticker = self
+            .digest_window
+            .map(|window| tokio::time::interval_at(tokio::time::Instant::now() + window, window));
+        loop {
+            let digest_due = async {
+                match digest_ticker.as_mut() {
+                    Some(ticker) => ticker.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let quiet_hours_over = async {
+                match self.quiet_hours {
+                    Some(quiet_hours) => {
+                        let now = Local::now();
+                        let until = (quiet_hours.next_end(&now) - now).to_std().unwrap_or_default();
+                        tokio::time::sleep(until).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = digest_due => {
+                    // Held messages wait for quiet hours to end instead
+                    if !self.is_quiet_now() {
+                        self.flush_digest().await;
+                    }
+                }
+                _ = quiet_hours_over => self.flush_digest().await,
+            }
+        }
+        // Don't drop whatever is still pending
+        self.flush_digest().await;
         info!("chat_relay middleware shutting down...");
         Ok(())
     }
 
-    fn on_event(&self, event: &Event) -> Result<Verdict> {
+    fn on_event(&self, event: &Arc<Event>) -> Result<Verdict> {
         // Filter: only handle events from source service
         if event.service_id.0 != self.source_service_id {
             return Ok(Verdict::Continue);
@@ -271,44 +555,132 @@ impl Middleware for ChatRelay {
                 sender_id,
                 sender_display_name,
                 is_self,
+                relayed_from,
+                thread_root_id,
+                message_id,
                 ..
             } => {
-                if let Some(ref expected_room) = self.source_room_id
-                    && room_id != expected_room
-                {
+                if !self.is_source_room(room_id) {
                     return Ok(Verdict::Continue);
                 }
+                // Also skips what this bot relayed here itself; passing that on again could loop
                 if *is_self {
                     debug!("ignoring message from bot itself");
                     return Ok(Verdict::Continue);
                 }
+                if relayed_from.as_ref().is_some_and(|from| from.service_id == self.dest_service_id)
+                {
+                    debug!("ignoring message relayed from the destination by another bridge");
+                    return Ok(Verdict::Continue);
+                }
+                let dest_service_id = ServiceId(self.dest_service_id.clone());
+                if let Some(window) = self.loop_window
+                    && !self.recent_relays.check_and_record(
+                        &dest_service_id,
+                        &self.dest_room_id,
+                        body,
+                        window,
+                    )
+                {
+                    debug!("ignoring message just relayed to the destination, likely looped back");
+                    return Ok(Verdict::Continue);
+                }
 
-                let formatted_body = Self::format_relayed_message(
-                    &self.prefix_tag,
-                    sender_id,
-                    sender_display_name.as_deref(),
-                    body,
+                if self.digest_window.is_some() || self.is_quiet_now() {
+                    let line = Self::format_relayed_message(
+                        &self.prefix_tag,
+                        sender_id,
+                        sender_display_name.as_deref(),
+                        body,
+                    );
+                    self.pending_digest.lock().unwrap().push(line);
+                    return Ok(Verdict::Continue);
+                }
+
+                // Messages another bridge relayed keep their original provenance
+                let relayed_from = relayed_from.clone().unwrap_or_else(|| Provenance {
+                    service_id: self.source_service_id.clone(),
+                    sender_id: sender_id.clone(),
+                    sender_display_name: sender_display_name.clone(),
+                });
+                let max_chars = self
+                    .services
+                    .capabilities(&dest_service_id)
+                    .and_then(|caps| caps.max_message_length);
+
+                let formatted_body = Self::shorten_links(
+                    &self.media,
+                    Self::format_relayed_message(
+                        &self.prefix_tag,
+                        sender_id,
+                        sender_display_name.as_deref(),
+                        body,
+                    ),
+                    max_chars,
                 );
 
                 let cmd_tx = self.cmd_tx.clone();
-                let dest_service_id = ServiceId(self.dest_service_id.clone());
+                let source_service_id = event.service_id.clone();
                 let dest_room_id = self.dest_room_id.clone();
+                let paste = self.paste.clone();
+                let bridge_map = self.bridge_map.clone();
+                let thread_root_id = thread_root_id.clone();
+                let message_id = message_id.clone();
 
-                tokio::spawn(async move {
-                    let command = Command::SendRoomMessage {
-                        service_id: dest_service_id.clone(),
-                        room_id: dest_room_id.clone(),
-                        body: formatted_body.clone(),
-                        markdown_body: Some(formatted_body),
-                        response_tx: None,
+                correlation::spawn(async move {
+                    // Replies in a thread go to the copy of its root, when it was relayed
+                    let dest_thread_root_id = thread_root_id.and_then(|root_id| {
+                        bridge_map
+                            .counterpart(&source_service_id, &root_id, &dest_service_id)
+                            .unwrap_or_else(|e| {
+                                error!(error=%e, "failed to look up relayed thread root");
+                                None
+                            })
+                    });
+                    let body = paste.fit(formatted_body, max_chars).await;
+                    let command = match dest_thread_root_id {
+                        Some(thread_root_id) => Command::SendThreadReply {
+                            service_id: dest_service_id.clone(),
+                            room_id: dest_room_id.clone(),
+                            thread_root_id,
+                            body,
+                            format: BodyFormat::Plain,
+                            response_tx: None,
+                            origin: None,
+                            idempotency_key: Some(fresh_key()),
+                        },
+                        None => Command::SendRoomMessage {
+                            service_id: dest_service_id.clone(),
+                            room_id: dest_room_id.clone(),
+                            body,
+                            format: BodyFormat::Plain,
+                            response_tx: None,
+                            origin: None,
+                            idempotency_key: Some(fresh_key()),
+                            relayed_from: Some(relayed_from),
+                            expires_after: None,
+                        },
                     };
-                    if let Err(e) = cmd_tx.send(command).await {
-                        error!(
+                    match send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+                        // Services without message IDs respond with an empty one
+                        Ok(dest_id) if !dest_id.is_empty() => {
+                            let Some(message_id) = message_id else { return };
+                            if let Err(e) = bridge_map.record(
+                                &source_service_id,
+                                &message_id,
+                                &dest_service_id,
+                                &dest_id,
+                            ) {
+                                error!(error=%e, "failed to record relayed message ID");
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => error!(
                             dest_service=%dest_service_id.0,
                             dest_room=%dest_room_id,
                             error=%e,
                             "failed to send chat relay command"
-                        );
+                        ),
                     }
                 });
             }
@@ -319,12 +691,11 @@ impl Middleware for ChatRelay {
                 is_self,
                 body,
                 source_url,
+                mimetype,
                 image_data, // Option<Arc<[u8]>> — clone is one atomic increment
                 ..
             } => {
-                if let Some(ref expected_room) = self.source_room_id
-                    && room_id != expected_room
-                {
+                if !self.is_source_room(room_id) {
                     return Ok(Verdict::Continue);
                 }
                 if *is_self {
@@ -336,6 +707,43 @@ impl Middleware for ChatRelay {
                 let cmd_tx = self.cmd_tx.clone();
                 let dest_service_id = ServiceId(self.dest_service_id.clone());
                 let dest_room_id = self.dest_room_id.clone();
+
+                // Destinations that can't take attachments get a text link instead
+                if !self.services.supports(&dest_service_id, |caps| caps.supports_attachments) {
+                    debug!(dest_service=%dest_service_id, "destination lacks attachment support");
+                    let prefix_tag = self.prefix_tag.clone();
+                    let sender_id = sender_id.clone();
+                    let sender_display_name = sender_display_name.clone();
+                    let body = body.clone();
+                    let media = self.media.clone();
+                    let mimetype = mimetype.clone();
+                    let image_data = image_data.clone();
+                    let mut source_url = source_url.clone();
+                    correlation::spawn(async move {
+                        // A copy the bot hosts beats a link only the source's users can open
+                        if let Some(data) = image_data.filter(|_| media.hosts_links()) {
+                            match media.store(&data, mimetype.as_deref()) {
+                                Ok(stored) => {
+                                    source_url = stored.url.map_or(source_url, String::from)
+                                }
+                                Err(e) => error!(error=%e, "failed to store image for relay"),
+                            }
+                        }
+                        Self::send_text_fallback(
+                            &cmd_tx,
+                            &dest_service_id,
+                            &dest_room_id,
+                            &prefix_tag,
+                            &sender_id,
+                            sender_display_name.as_deref(),
+                            &body,
+                            &source_url,
+                        )
+                        .await;
+                    });
+                    return Ok(Verdict::Continue);
+                }
+
                 let prefix_tag = self.prefix_tag.clone();
                 let sender_id = sender_id.clone();
                 let sender_display_name = sender_display_name.clone();
@@ -346,7 +754,7 @@ impl Middleware for ChatRelay {
                 let thumbnail_max_height = self.thumbnail_max_height;
                 let thumbnail_jpeg_quality = self.thumbnail_jpeg_quality;
 
-                tokio::spawn(Self::relay_image(
+                correlation::spawn(Self::relay_image(
                     http_client,
                     cmd_tx,
                     dest_service_id,
@@ -362,6 +770,29 @@ impl Middleware for ChatRelay {
                     thumbnail_jpeg_quality,
                 ));
             }
+            EventKind::MissedMessages { room_id, messages, truncated } => {
+                if !self.is_source_room(room_id) {
+                    return Ok(Verdict::Continue);
+                }
+                let lines = self.catch_up_lines(messages, *truncated);
+                if lines.is_empty() {
+                    debug!(missed=%messages.len(), "not catching up on missed messages");
+                    return Ok(Verdict::Continue);
+                }
+                if self.digest_window.is_some() || self.is_quiet_now() {
+                    self.pending_digest.lock().unwrap().extend(lines);
+                    return Ok(Verdict::Continue);
+                }
+                info!(lines=%lines.len(), "catching up on messages missed while offline");
+                correlation::spawn(self.post_lines(lines));
+            }
+            EventKind::RoomUpgraded { old_room_id, new_room_id } => {
+                let mut source_room_id = self.source_room_id.lock().unwrap();
+                if source_room_id.as_deref() == Some(old_room_id.as_str()) {
+                    info!(old_room=%old_room_id, new_room=%new_room_id, "source room upgraded, following it");
+                    *source_room_id = Some(new_room_id.clone());
+                }
+            }
             _ => {}
         }
 
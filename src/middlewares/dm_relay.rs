@@ -0,0 +1,173 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    commands::send_reply,
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use crate::store::PersistentStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+const SESSIONS_KEY: &str = "sessions";
+
+/// The conversations relayed so far, kept in the store so replies still reach their sender
+/// after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Sessions {
+    // Sender's user ID on the source service -> the name their messages are relayed under
+    names: HashMap<String, String>,
+    // Who a reply goes to when it doesn't name anyone: whoever last wrote
+    current: Option<String>,
+}
+
+impl Sessions {
+    /// The sender a reply is for, and the reply with any leading `@name` removed.
+    fn route<'a>(&self, reply: &'a str) -> Option<(String, &'a str)> {
+        if let Some((name, rest)) = reply.strip_prefix('@').and_then(|r| r.split_once(' ')) {
+            let named = self.names.iter().find(|(user_id, known)| {
+                known.eq_ignore_ascii_case(name) || user_id.eq_ignore_ascii_case(name)
+            });
+            if let Some((user_id, _)) = named {
+                return Some((user_id.clone(), rest.trim_start()));
+            }
+        }
+        Some((self.current.clone()?, reply))
+    }
+}
+
+/// The DM a message from `sender` on `source_service_id` is relayed as.
+pub fn format_relayed_dm(source_service_id: &ServiceId, sender: &str, body: &str) -> String {
+    format!("[{source_service_id}] {sender}: {body}")
+}
+
+/// Bridges DMs across services: a DM to the bot on the source service (e.g. Mumble) is passed
+/// on to a user on another service (e.g. Matrix), prefixed with who sent it, and that user's
+/// DMs back to the bot are relayed to the sender.
+///
+/// A reply goes to whoever wrote last, or to the sender it starts with `@name` for. DMs from
+/// the user starting with `!` are left for commands.
+pub struct DmRelay {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    source_service_id: ServiceId,
+    /// Who DMs are relayed to and whose replies are relayed back.
+    dest_service_id: ServiceId,
+    dest_user_id: String,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl DmRelay {
+    pub fn new(
+        ctx: MiddlewareContext,
+        source_service_id: ServiceId,
+        dest_service_id: ServiceId,
+        dest_user_id: String,
+    ) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            source_service_id,
+            dest_service_id,
+            dest_user_id,
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+        }
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, Sessions> {
+        self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Saves the sessions in the background.
+    fn save(&self, sessions: Sessions) {
+        let store = self.store.clone();
+        correlation::spawn(async move {
+            if let Err(e) = store.set(SESSIONS_KEY, &sessions).await {
+                tracing::warn!(error=%e, "failed to save DM relay sessions");
+            }
+        });
+    }
+
+    fn send_dm(&self, service_id: ServiceId, user_id: String, body: String) {
+        let command = Command::SendDirectMessage {
+            service_id,
+            user_id,
+            body,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            expires_after: None,
+            require_encryption: false,
+        };
+        self.cmd_tx.spawn_send(command, "relayed DM");
+    }
+}
+
+#[async_trait]
+impl Middleware for DmRelay {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if let Some(saved) = self.store.get::<Sessions>(SESSIONS_KEY).await {
+            *self.sessions() = saved;
+        }
+        tracing::info!(
+            source_service_id=%self.source_service_id,
+            dest_service_id=%self.dest_service_id,
+            "dm_relay middleware running..."
+        );
+        cancel.cancelled().await;
+        tracing::info!("dm_relay middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage {
+            user_id, body, sender_id, sender_display_name, is_self, ..
+        } = &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self {
+            return Ok(Verdict::Continue);
+        }
+
+        if evt.service_id == self.source_service_id {
+            let name = sender_display_name.as_deref().unwrap_or(sender_id).to_string();
+            let body = format_relayed_dm(&self.source_service_id, &name, body);
+            let snapshot = {
+                let mut sessions = self.sessions();
+                sessions.names.insert(user_id.clone(), name);
+                sessions.current = Some(user_id.clone());
+                sessions.clone()
+            };
+            self.save(snapshot);
+            tracing::info!(source_service_id=%self.source_service_id, user_id=%user_id, "relaying DM");
+            self.send_dm(self.dest_service_id.clone(), self.dest_user_id.clone(), body);
+        } else if evt.service_id == self.dest_service_id && *sender_id == self.dest_user_id {
+            if body.starts_with('!') {
+                return Ok(Verdict::Continue);
+            }
+            let Some((recipient, reply)) =
+                self.sessions().route(body).map(|(id, reply)| (id, reply.to_string()))
+            else {
+                send_reply(evt, "No conversation to reply to yet".to_string(), &self.cmd_tx);
+                return Ok(Verdict::Continue);
+            };
+            tracing::info!(source_service_id=%self.source_service_id, user_id=%recipient, "relaying DM reply");
+            let snapshot = {
+                let mut sessions = self.sessions();
+                sessions.current = Some(recipient.clone());
+                sessions.clone()
+            };
+            self.save(snapshot);
+            self.send_dm(self.source_service_id.clone(), recipient, reply);
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -1,21 +1,27 @@
 use crate::core::{
-    bus::Command,
+    bus::{Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec},
+    correlation,
     event::{Event, EventKind},
+    format::BodyFormat,
     middleware::{Middleware, MiddlewareContext, Verdict},
 };
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::sync::mpsc::Sender;
+use std::sync::Arc;
 use tokio_util::sync::CancellationToken;
 
 pub struct Echo {
-    cmd_tx: Sender<Command>,
-    command_string: String,
+    cmd_tx: CommandSender,
+    router: CommandRouter,
 }
 
 impl Echo {
     pub fn new(ctx: MiddlewareContext, command_string: String) -> Self {
-        Self { cmd_tx: ctx.cmd_tx, command_string }
+        let router = CommandRouter::new(command_string)
+            .with_description("Repeat a message back")
+            .with_args(vec![ArgSpec::rest("text")]);
+        Self { cmd_tx: ctx.cmd_tx, router }
     }
 }
 
@@ -28,15 +34,34 @@ impl Middleware for Echo {
         Ok(())
     }
 
-    fn on_event(&self, evt: &Event) -> Result<Verdict> {
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
         // Only handle message events
-        let (body, is_self) = match &evt.kind {
-            EventKind::DirectMessage { body, is_self, .. } => (body, *is_self),
-            EventKind::RoomMessage { body, is_self, .. } => (body, *is_self),
+        let is_self = match &evt.kind {
+            EventKind::DirectMessage { is_self, .. } => *is_self,
+            EventKind::RoomMessage { is_self, .. } => *is_self,
             EventKind::UserListUpdate { .. }
+            | EventKind::UserListDiff { .. }
             | EventKind::ReactionAdded { .. }
             | EventKind::ReactionRemoved { .. }
-            | EventKind::RoomImage { .. } => return Ok(Verdict::Continue),
+            | EventKind::RoomImage { .. }
+            | EventKind::Knock { .. }
+            | EventKind::RoomUpgraded { .. }
+            | EventKind::MissedMessages { .. }
+            | EventKind::UserProfileChanged { .. }
+            | EventKind::VoiceStateChanged { .. }
+            | EventKind::VerificationRequested { .. }
+            | EventKind::ConnectionScheduled { .. }
+            | EventKind::ServiceReady
+            | EventKind::ServiceDegraded { .. }
+            | EventKind::ServiceStopped => return Ok(Verdict::Continue),
         };
 
         // Ignore messages from self to prevent infinite recursion
@@ -44,9 +69,8 @@ impl Middleware for Echo {
             return Ok(Verdict::Continue);
         }
 
-        // Build the prefix with a trailing space
-        let prefix = format!("{} ", self.command_string);
-        if let Some(echo_content) = body.strip_prefix(&prefix) {
+        if let Some(invocation) = self.router.route(evt, &self.cmd_tx) {
+            let echo_content = invocation.arg("text");
             // Create a oneshot channel to receive the message ID
             let (response_tx, response_rx) = tokio::sync::oneshot::channel();
 
@@ -57,29 +81,43 @@ impl Middleware for Echo {
                     user_id: user_id.clone(),
                     body: echo_content.to_string(),
                     response_tx: Some(response_tx),
+                    origin: None,
+                    idempotency_key: None,
+                    expires_after: None,
+                    require_encryption: false,
                 },
                 EventKind::RoomMessage { room_id, .. } => Command::SendRoomMessage {
                     service_id: evt.service_id.clone(),
                     room_id: room_id.clone(),
                     body: echo_content.to_string(),
-                    markdown_body: None,
+                    format: BodyFormat::Plain,
                     response_tx: Some(response_tx),
+                    origin: None,
+                    idempotency_key: None,
+                    relayed_from: None,
+                    expires_after: None,
                 },
                 EventKind::UserListUpdate { .. }
+                | EventKind::UserListDiff { .. }
                 | EventKind::ReactionAdded { .. }
                 | EventKind::ReactionRemoved { .. }
-                | EventKind::RoomImage { .. } => unreachable!(),
+                | EventKind::RoomImage { .. }
+                | EventKind::Knock { .. }
+                | EventKind::RoomUpgraded { .. }
+                | EventKind::MissedMessages { .. }
+                | EventKind::UserProfileChanged { .. }
+                | EventKind::VoiceStateChanged { .. }
+                | EventKind::VerificationRequested { .. }
+                | EventKind::ConnectionScheduled { .. }
+                | EventKind::ServiceReady
+                | EventKind::ServiceDegraded { .. }
+                | EventKind::ServiceStopped => unreachable!(),
             };
 
             // Send the command and wait for the message ID
-            let cmd_tx = self.cmd_tx.clone();
+            self.cmd_tx.spawn_send(command, "echo");
             let echo_content_clone = echo_content.to_string();
-            tokio::spawn(async move {
-                if let Err(e) = cmd_tx.send(command).await {
-                    tracing::error!(error=%e, "failed to send echo command");
-                    return;
-                }
-
+            correlation::spawn(async move {
                 // Wait for the message ID response
                 match response_rx.await {
                     Ok(Ok(message_id)) => {
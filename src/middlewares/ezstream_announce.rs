@@ -1,7 +1,9 @@
 use crate::core::{
-    bus::Command,
+    bus::{Command, CommandSender},
     config::ExponentialBackoff,
+    correlation,
     event::Event,
+    format::BodyFormat,
     middleware::{Middleware, MiddlewareContext, Verdict},
     service::ServiceId,
 };
@@ -12,7 +14,7 @@ use futures::{SinkExt, StreamExt};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{Mutex, mpsc::Sender};
+use tokio::sync::Mutex;
 use tokio_tungstenite::{
     connect_async, tungstenite::client::IntoClientRequest, tungstenite::protocol::Message,
 };
@@ -56,7 +58,7 @@ impl StreamState {
 }
 
 pub struct EzStreamAnnounce {
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     websocket_url: String,
     stream_url_template: String,
     start_message_template: String,
@@ -146,8 +148,12 @@ impl EzStreamAnnounce {
                 service_id: ServiceId(dest.service_id.clone()),
                 room_id: dest.room_id.clone(),
                 body: message_body.clone(),
-                markdown_body: Some(message_body.clone()),
+                format: BodyFormat::Markdown,
                 response_tx: Some(response_tx),
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
             };
 
             self.cmd_tx.send(command).await?;
@@ -220,7 +226,8 @@ impl EzStreamAnnounce {
                 service_id: ServiceId(service_id.clone()),
                 message_id,
                 new_body: message_body.clone(),
-                new_markdown_body: Some(message_body.clone()),
+                format: BodyFormat::Markdown,
+                origin: None,
             };
 
             if let Err(e) = self.cmd_tx.send(command).await {
@@ -364,7 +371,7 @@ impl EzStreamAnnounce {
                                         destinations: self.destinations.clone(),
                                         state: self.state.clone(),
                                     };
-                                    tokio::spawn(async move {
+                                    correlation::spawn(async move {
                                         if let Err(e) = self_clone.handle_notification(notification).await {
                                             tracing::error!(error=%e, "failed to handle notification");
                                         }
@@ -415,7 +422,7 @@ impl Middleware for EzStreamAnnounce {
         self.websocket_loop(cancel).await
     }
 
-    fn on_event(&self, _event: &Event) -> Result<Verdict> {
+    fn on_event(&self, _event: &Arc<Event>) -> Result<Verdict> {
         // This middleware doesn't react to events, only to WebSocket notifications
         Ok(Verdict::Continue)
     }
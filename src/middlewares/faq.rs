@@ -0,0 +1,303 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use crate::middlewares::impersonation_guard::name_similarity;
+use crate::store::PersistentStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+const ENTRIES_KEY: &str = "entries";
+
+// Words that start a subcommand, so can't be used as keys
+const RESERVED_KEYS: &[&str] = &["list", "set", "delete", "search"];
+
+// How well an entry must match to show up in a search or as a suggestion, from 0 to 1
+const MIN_SCORE: f64 = 0.5;
+
+const MAX_SEARCH_RESULTS: usize = 5;
+
+/// An answer stored under a key in one room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaqEntry {
+    pub service_id: String,
+    pub room_id: String,
+    /// Lowercase, one word.
+    pub key: String,
+    /// Markdown.
+    pub answer: String,
+    pub updated_by: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// How well `query` matches `entry`, from 0 to 1: the better of how alike the query and key
+/// look, and the share of the query's words found in the key or answer.
+pub fn match_score(query: &str, entry: &FaqEntry) -> f64 {
+    let text = format!("{} {}", entry.key, entry.answer).to_lowercase();
+    let words: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let found = words.iter().filter(|word| text.contains(word.as_str())).count();
+    let word_score = if words.is_empty() { 0.0 } else { found as f64 / words.len() as f64 };
+    name_similarity(query, &entry.key).max(word_score)
+}
+
+/// A knowledge base per room: `!faq <key>` answers with the entry stored under that key, and
+/// `!faq search <words>` finds entries by key and content, forgiving typos in the key. Trusted
+/// users store entries with `!faq set <key> <answer>` and remove them with `!faq delete <key>`.
+/// Answers are Markdown. Entries are kept in the store.
+pub struct Faq {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    router: CommandRouter,
+    /// Who may set and delete entries.
+    editor_user_ids: Vec<String>,
+    entries: Arc<Mutex<Vec<FaqEntry>>>,
+}
+
+impl Faq {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        editor_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Look up, search or edit this room's FAQ")
+            .with_args(vec![ArgSpec::optional_rest("query")]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            router,
+            editor_user_ids,
+            entries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn is_editor(&self, user_id: &str) -> bool {
+        self.editor_user_ids.iter().any(|editor| editor == user_id)
+    }
+
+    /// The room's entries, sorted by key.
+    fn room_entries(&self, evt: &Event, room_id: &str) -> Vec<FaqEntry> {
+        let mut entries: Vec<FaqEntry> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.service_id == evt.service_id.0 && entry.room_id == room_id)
+            .cloned()
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    /// The room's entries matching `query` best first, with scores of at least `MIN_SCORE`.
+    fn search(&self, evt: &Event, room_id: &str, query: &str) -> Vec<FaqEntry> {
+        let mut scored: Vec<(f64, FaqEntry)> = self
+            .room_entries(evt, room_id)
+            .into_iter()
+            .map(|entry| (match_score(query, &entry), entry))
+            .filter(|(score, _)| *score >= MIN_SCORE)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(MAX_SEARCH_RESULTS).map(|(_, entry)| entry).collect()
+    }
+
+    fn lookup(&self, evt: &Event, room_id: &str, key: &str) {
+        let key = key.to_lowercase();
+        let found = self.room_entries(evt, room_id).into_iter().find(|entry| entry.key == key);
+        if let Some(entry) = found {
+            self.send_markdown(evt, room_id, entry.answer);
+            return;
+        }
+        let suggestions: Vec<String> =
+            self.search(evt, room_id, &key).into_iter().map(|entry| entry.key).collect();
+        let reply = if suggestions.is_empty() {
+            format!("Nothing saved under '{key}'")
+        } else {
+            format!("Nothing saved under '{key}'. Did you mean: {}?", suggestions.join(", "))
+        };
+        send_reply(evt, reply, &self.cmd_tx);
+    }
+
+    fn list(&self, evt: &Event, room_id: &str) {
+        let keys: Vec<String> =
+            self.room_entries(evt, room_id).into_iter().map(|entry| entry.key).collect();
+        let reply = if keys.is_empty() {
+            "No FAQ entries here yet".to_string()
+        } else {
+            format!("FAQ entries: {}", keys.join(", "))
+        };
+        send_reply(evt, reply, &self.cmd_tx);
+    }
+
+    fn search_reply(&self, evt: &Event, room_id: &str, query: &str) {
+        if query.is_empty() {
+            send_reply(evt, self.usage(evt), &self.cmd_tx);
+            return;
+        }
+        let results = self.search(evt, room_id, query);
+        if results.is_empty() {
+            send_reply(evt, format!("Nothing matches '{query}'"), &self.cmd_tx);
+            return;
+        }
+        let lines: Vec<String> = results
+            .iter()
+            .map(|entry| {
+                let first_line = entry.answer.lines().next().unwrap_or_default();
+                format!("- **{}**: {first_line}", entry.key)
+            })
+            .collect();
+        self.send_markdown(evt, room_id, lines.join("\n"));
+    }
+
+    /// Handles `!faq set` and `!faq delete`, which only editors may use.
+    fn edit(&self, evt: &Arc<Event>, room_id: &str, sender_id: &str, subcommand: &str, rest: &str) {
+        if !self.is_editor(sender_id) {
+            send_reply(evt, "Only FAQ editors can change entries".to_string(), &self.cmd_tx);
+            return;
+        }
+        let (key, answer) = split_first_word(rest);
+        let key = key.to_lowercase();
+        if key.is_empty() || (subcommand == "set" && answer.is_empty()) {
+            send_reply(evt, self.usage(evt), &self.cmd_tx);
+            return;
+        }
+        if RESERVED_KEYS.contains(&key.as_str()) {
+            send_reply(evt, format!("'{key}' can't be used as a key"), &self.cmd_tx);
+            return;
+        }
+
+        let service_id = evt.service_id.0.as_str();
+        let reply = {
+            let mut entries = self.entries.lock().unwrap();
+            let existing = entries.iter().position(|entry| {
+                entry.service_id == service_id && entry.room_id == room_id && entry.key == key
+            });
+            match (subcommand, existing) {
+                ("delete", Some(index)) => {
+                    entries.remove(index);
+                    format!("Deleted '{key}'")
+                }
+                ("delete", None) => {
+                    send_reply(evt, format!("Nothing saved under '{key}'"), &self.cmd_tx);
+                    return;
+                }
+                (_, existing) => {
+                    let entry = FaqEntry {
+                        service_id: service_id.to_string(),
+                        room_id: room_id.to_string(),
+                        key: key.clone(),
+                        answer: answer.to_string(),
+                        updated_by: sender_id.to_string(),
+                        updated_at: Utc::now(),
+                    };
+                    match existing {
+                        Some(index) => {
+                            entries[index] = entry;
+                            format!("Updated '{key}'")
+                        }
+                        None => {
+                            entries.push(entry);
+                            format!("Saved '{key}'")
+                        }
+                    }
+                }
+            }
+        };
+        tracing::info!(room_id=%room_id, key=%key, editor=%sender_id, action=%subcommand, "faq entry changed");
+
+        let snapshot = self.entries.lock().unwrap().clone();
+        let store = self.store.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let mut reply = reply;
+            if let Err(e) = store.set(ENTRIES_KEY, &snapshot).await {
+                tracing::error!(error=%e, "failed to save faq entries");
+                reply.push_str(" (until restart: failed to save it)");
+            }
+            send_reply(&evt, reply, &cmd_tx);
+        });
+    }
+
+    fn send_markdown(&self, evt: &Event, room_id: &str, body: String) {
+        let command = Command::SendRoomMessage {
+            service_id: evt.service_id.clone(),
+            room_id: room_id.to_string(),
+            body,
+            format: BodyFormat::Markdown,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        };
+        self.cmd_tx.spawn_send(command, "faq answer");
+    }
+
+    fn usage(&self, evt: &Event) -> String {
+        format!(
+            "Usage: {0} <key> | {0} list | {0} search <words> | {0} set <key> <answer> | {0} delete <key>",
+            self.router.prefix_on(&evt.service_id)
+        )
+    }
+}
+
+/// `input`'s first word and the rest, trimmed.
+fn split_first_word(input: &str) -> (&str, &str) {
+    let input = input.trim();
+    input.split_once(char::is_whitespace).map_or((input, ""), |(first, rest)| (first, rest.trim()))
+}
+
+#[async_trait]
+impl Middleware for Faq {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if let Some(saved) = self.store.get::<Vec<FaqEntry>>(ENTRIES_KEY).await {
+            *self.entries.lock().unwrap() = saved;
+        }
+        tracing::info!("faq middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("faq middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::RoomMessage { room_id, body, sender_id, is_self: false, .. } = &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if !self.router.matches(body) {
+            return Ok(Verdict::Continue);
+        }
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let (first, rest) = split_first_word(invocation.get("query").unwrap_or_default());
+        match first {
+            "" | "list" => self.list(evt, room_id),
+            "search" => self.search_reply(evt, room_id, rest),
+            "set" | "delete" => self.edit(evt, room_id, sender_id, first, rest),
+            key => self.lookup(evt, room_id, key),
+        }
+        Ok(Verdict::Continue)
+    }
+}
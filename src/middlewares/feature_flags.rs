@@ -0,0 +1,136 @@
+use crate::core::{
+    bus::{BusControl, Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use crate::store::PersistentStore;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+// Store key for the toggles made at runtime: middleware name -> enabled
+const OVERRIDES_KEY: &str = "overrides";
+
+/// Lets admins turn middlewares on and off over DM, e.g. `!feature off movies`.
+///
+/// Toggles are persisted and reapplied at startup, so they outlast restarts and take precedence
+/// over `enabled` in the config.
+pub struct FeatureFlags {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    router: CommandRouter,
+    admin_user_ids: Vec<String>,
+}
+
+impl FeatureFlags {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        admin_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Turn middlewares on or off")
+            .with_subcommand("on", vec![ArgSpec::required("middleware")])
+            .with_subcommand("off", vec![ArgSpec::required("middleware")]);
+        Self { cmd_tx: ctx.cmd_tx, store: ctx.store, router, admin_user_ids }
+    }
+
+    /// Asks the bus to enable or disable `name`, returning its reply.
+    async fn toggle(cmd_tx: &CommandSender, name: &str, enabled: bool) -> Result<String> {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let name = name.to_string();
+        let response_tx = Some(response_tx);
+        let control = if enabled {
+            BusControl::EnableMiddleware { name, response_tx }
+        } else {
+            BusControl::DisableMiddleware { name, response_tx }
+        };
+        cmd_tx
+            .send(Command::Control(control))
+            .await
+            .map_err(|_| anyhow!("command channel closed"))?;
+        response_rx.await.map_err(|_| anyhow!("the bus dropped the request"))?
+    }
+}
+
+#[async_trait]
+impl Middleware for FeatureFlags {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("feature_flags middleware running...");
+
+        let overrides: HashMap<String, bool> =
+            self.store.get(OVERRIDES_KEY).await.unwrap_or_default();
+        for (name, enabled) in overrides {
+            if let Err(e) = Self::toggle(&self.cmd_tx, &name, enabled).await {
+                tracing::warn!(middleware=%name, enabled, error=%e, "failed to reapply feature flag");
+            }
+        }
+
+        cancel.cancelled().await;
+        tracing::info!("feature_flags middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage { body, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self || !self.router.matches(body) {
+            return Ok(Verdict::Continue);
+        }
+
+        // Checked before parsing so non-admins don't even get usage replies
+        if !self.admin_user_ids.iter().any(|admin| admin == sender_id) {
+            tracing::info!(sender_id=%sender_id, "ignoring feature command from non-admin");
+            return Ok(Verdict::Continue);
+        }
+
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let enabled = invocation.subcommand == Some("on");
+        let name = invocation.arg("middleware").to_string();
+
+        // Turning this middleware off would leave nothing to turn it back on
+        if !enabled && name == self.cmd_tx.origin() {
+            send_reply(evt, format!("'{name}' can't turn itself off"), &self.cmd_tx);
+            return Ok(Verdict::Continue);
+        }
+
+        let cmd_tx = self.cmd_tx.clone();
+        let store = self.store.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let reply = match Self::toggle(&cmd_tx, &name, enabled).await {
+                Ok(message) => {
+                    let mut overrides: HashMap<String, bool> =
+                        store.get(OVERRIDES_KEY).await.unwrap_or_default();
+                    overrides.insert(name, enabled);
+                    match store.set(OVERRIDES_KEY, &overrides).await {
+                        Ok(()) => message,
+                        Err(e) => {
+                            tracing::error!(error=%e, "failed to persist feature flag");
+                            format!("{message} (until restart: failed to save it)")
+                        }
+                    }
+                }
+                Err(e) => format!("Failed: {e}"),
+            };
+            send_reply(&evt, reply, &cmd_tx);
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,389 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    commands::{CommandRouter, CommandSpec, send_reply},
+    event::{Event, EventKind},
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tokio_util::sync::CancellationToken;
+
+/// How long a server gets to answer a poll.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Polls a watched server must fail in a row to count as down, so one lost UDP packet doesn't
+// raise an alert
+const DOWN_AFTER_FAILURES: u32 = 2;
+
+// Largest Minecraft status response read; it can carry the server's icon as base64
+const MAX_MINECRAFT_RESPONSE: usize = 1 << 20;
+
+/// The protocol a game server is polled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameProtocol {
+    /// Valve's A2S_INFO query over UDP, answered by Source engine games and many others.
+    A2s,
+    /// The Minecraft (Java Edition 1.7+) Server List Ping over TCP.
+    Minecraft,
+}
+
+impl GameProtocol {
+    fn default_port(self) -> u16 {
+        match self {
+            GameProtocol::A2s => 27015,
+            GameProtocol::Minecraft => 25565,
+        }
+    }
+}
+
+/// A server to poll, as configured.
+#[derive(Debug, Clone)]
+pub struct GameServer {
+    pub name: String,
+    pub protocol: GameProtocol,
+    /// `host` or `host:port`; the port defaults to the protocol's usual one.
+    pub address: String,
+    /// Whether going down and coming back up are announced in the alert room.
+    pub watch: bool,
+}
+
+/// What a server reported when polled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub players: u32,
+    pub max_players: u32,
+    /// The map (A2S) or game version (Minecraft), when the server says.
+    pub detail: Option<String>,
+}
+
+/// A reply to an A2S_INFO request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum A2sReply {
+    /// The server wants the request repeated with this challenge appended.
+    Challenge([u8; 4]),
+    Info(ServerStatus),
+}
+
+const A2S_HEADER: [u8; 4] = [0xFF; 4];
+const A2S_INFO_REQUEST: &[u8] = b"TSource Engine Query\0";
+
+/// Polls the server at `address` with `protocol`, giving up after `QUERY_TIMEOUT`.
+pub async fn query(protocol: GameProtocol, address: &str) -> Result<ServerStatus> {
+    let (host, port) = split_address(address, protocol.default_port());
+    let poll = async {
+        match protocol {
+            GameProtocol::A2s => query_a2s(&host, port).await,
+            GameProtocol::Minecraft => query_minecraft(&host, port).await,
+        }
+    };
+    tokio::time::timeout(QUERY_TIMEOUT, poll).await.map_err(|_| anyhow!("timed out"))?
+}
+
+/// `address` split into host and port, using `default_port` when it has none.
+fn split_address(address: &str, default_port: u16) -> (String, u16) {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.starts_with('[') => match port.parse() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (address.to_string(), default_port),
+        },
+        _ => (address.to_string(), default_port),
+    }
+}
+
+async fn query_a2s(host: &str, port: u16) -> Result<ServerStatus> {
+    let socket =
+        UdpSocket::bind(if host.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" }).await?;
+    socket.connect((host.trim_matches(['[', ']']), port)).await?;
+    let mut request = [&A2S_HEADER[..], A2S_INFO_REQUEST].concat();
+    let mut buf = vec![0u8; 1400];
+    // Servers that want a challenge answer the first request with one
+    for _ in 0..2 {
+        socket.send(&request).await?;
+        let len = socket.recv(&mut buf).await?;
+        match parse_a2s_reply(&buf[..len])? {
+            A2sReply::Info(status) => return Ok(status),
+            A2sReply::Challenge(challenge) => {
+                request.truncate(A2S_HEADER.len() + A2S_INFO_REQUEST.len());
+                request.extend_from_slice(&challenge);
+            }
+        }
+    }
+    bail!("server kept asking for a challenge")
+}
+
+/// Parses a UDP packet answering an A2S_INFO request.
+pub fn parse_a2s_reply(packet: &[u8]) -> Result<A2sReply> {
+    let body = packet.strip_prefix(&A2S_HEADER[..]).context("not an A2S reply")?;
+    match body.split_first() {
+        Some((b'A', challenge)) => {
+            let challenge = challenge.get(..4).context("truncated challenge")?;
+            Ok(A2sReply::Challenge(challenge.try_into()?))
+        }
+        Some((b'I', info)) => {
+            // Protocol version, then the name, map, folder and game strings
+            let mut rest = info.get(1..).context("truncated info")?;
+            let mut strings = Vec::new();
+            for _ in 0..4 {
+                let end = rest.iter().position(|b| *b == 0).context("truncated info")?;
+                strings.push(String::from_utf8_lossy(&rest[..end]).into_owned());
+                rest = &rest[end + 1..];
+            }
+            // A 2-byte app ID, then player and max player counts
+            let counts = rest.get(2..4).context("truncated info")?;
+            let map = strings.swap_remove(1);
+            Ok(A2sReply::Info(ServerStatus {
+                players: counts[0] as u32,
+                max_players: counts[1] as u32,
+                detail: (!map.is_empty()).then_some(map),
+            }))
+        }
+        _ => bail!("unexpected A2S reply"),
+    }
+}
+
+async fn query_minecraft(host: &str, port: u16) -> Result<ServerStatus> {
+    let mut stream = TcpStream::connect((host.trim_matches(['[', ']']), port)).await?;
+
+    // Handshake (protocol version -1, as clients do when pinging) asking for status, then
+    // the status request
+    let mut handshake = vec![0x00];
+    write_varint(&mut handshake, -1);
+    write_varint(&mut handshake, host.len() as i32);
+    handshake.extend_from_slice(host.as_bytes());
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_varint(&mut handshake, 1);
+    let mut request = Vec::new();
+    write_varint(&mut request, handshake.len() as i32);
+    request.extend_from_slice(&handshake);
+    request.extend_from_slice(&[0x01, 0x00]);
+    stream.write_all(&request).await?;
+
+    let len = read_varint(&mut stream).await?;
+    let len = usize::try_from(len).ok().filter(|len| *len <= MAX_MINECRAFT_RESPONSE);
+    let mut packet = vec![0u8; len.context("status response too large")?];
+    stream.read_exact(&mut packet).await?;
+    let mut packet = packet.as_slice();
+    if read_varint(&mut packet).await? != 0 {
+        bail!("unexpected Minecraft packet");
+    }
+    let json_len = usize::try_from(read_varint(&mut packet).await?)?;
+    let json = packet.get(..json_len).context("truncated status response")?;
+    parse_minecraft_status(&String::from_utf8_lossy(json))
+}
+
+/// Parses the JSON a Minecraft server answers a status request with.
+pub fn parse_minecraft_status(json: &str) -> Result<ServerStatus> {
+    let status: serde_json::Value = serde_json::from_str(json)?;
+    let count = |field: &str| {
+        status["players"][field].as_u64().map(|n| n as u32).context("status has no player counts")
+    };
+    Ok(ServerStatus {
+        players: count("online")?,
+        max_players: count("max")?,
+        detail: status["version"]["name"].as_str().map(str::to_string),
+    })
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+async fn read_varint(reader: &mut (impl AsyncRead + Unpin)) -> Result<i32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = reader.read_u8().await?;
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    bail!("varint too long")
+}
+
+/// A server's name and the outcome of its latest poll, e.g.
+/// `mc: online, 3/20 players (1.21.1)` or `tf2: offline (timed out)`.
+pub fn format_status(name: &str, status: Option<&Result<ServerStatus, String>>) -> String {
+    match status {
+        None => format!("{name}: not checked yet"),
+        Some(Ok(status)) => {
+            let detail = status.detail.as_ref().map(|d| format!(" ({d})")).unwrap_or_default();
+            format!("{name}: online, {}/{} players{detail}", status.players, status.max_players)
+        }
+        Some(Err(e)) => format!("{name}: offline ({e})"),
+    }
+}
+
+/// Where alerts about watched servers go.
+#[derive(Debug, Clone)]
+pub struct AlertDestination {
+    pub service_id: ServiceId,
+    pub room_id: String,
+}
+
+struct Tracked {
+    server: GameServer,
+    last: Option<Result<ServerStatus, String>>,
+    failures: u32,
+    // Set once a down alert went out, so the recovery gets announced too
+    alerted_down: bool,
+}
+
+/// Polls game servers and answers `!servers` with whether each is up and how many people are
+/// playing. Watched servers get an alert in a room when they stop answering for two polls in a
+/// row, and another when they're back.
+pub struct GameStatus {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    poll_interval: Duration,
+    alerts: Option<AlertDestination>,
+    servers: Mutex<Vec<Tracked>>,
+}
+
+impl GameStatus {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        servers: Vec<GameServer>,
+        poll_interval: Duration,
+        alerts: Option<AlertDestination>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Show whether the game servers are up and who's playing");
+        let servers = servers
+            .into_iter()
+            .map(|server| Tracked { server, last: None, failures: 0, alerted_down: false })
+            .collect();
+        Self { cmd_tx: ctx.cmd_tx, router, poll_interval, alerts, servers: Mutex::new(servers) }
+    }
+
+    /// Polls every server, returning the alerts to post.
+    async fn poll(&self) -> Vec<String> {
+        let targets: Vec<GameServer> =
+            self.servers.lock().unwrap().iter().map(|tracked| tracked.server.clone()).collect();
+        let results = futures::future::join_all(
+            targets.iter().map(|server| query(server.protocol, &server.address)),
+        )
+        .await;
+
+        let mut alerts = Vec::new();
+        let mut servers = self.servers.lock().unwrap();
+        for (tracked, result) in servers.iter_mut().zip(results) {
+            let name = &tracked.server.name;
+            match &result {
+                Ok(_) => {
+                    tracked.failures = 0;
+                    if std::mem::take(&mut tracked.alerted_down) {
+                        alerts.push(format!("✅ {name} is back up"));
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!(server=%name, error=%e, "game server poll failed");
+                    tracked.failures += 1;
+                    if tracked.server.watch
+                        && tracked.failures == DOWN_AFTER_FAILURES
+                        && !tracked.alerted_down
+                    {
+                        tracked.alerted_down = true;
+                        alerts.push(format!("⚠️ {name} is down ({e})"));
+                    }
+                }
+            }
+            tracked.last = Some(result.map_err(|e| e.to_string()));
+        }
+        alerts
+    }
+
+    async fn post_alert(&self, body: String) {
+        let Some(alerts) = &self.alerts else {
+            return;
+        };
+        let command = Command::SendRoomMessage {
+            service_id: alerts.service_id.clone(),
+            room_id: alerts.room_id.clone(),
+            body,
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        };
+        if let Err(e) = send_with_retry(&self.cmd_tx, command, &RetryPolicy::default()).await {
+            tracing::error!(error=%e, "failed to post game server alert");
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for GameStatus {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            servers = self.servers.lock().unwrap().len(),
+            poll_interval=?self.poll_interval,
+            "game_status middleware running..."
+        );
+        let mut ticker = tokio::time::interval(self.poll_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    for alert in self.poll().await {
+                        self.post_alert(alert).await;
+                    }
+                }
+            }
+        }
+        tracing::info!("game_status middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let (EventKind::DirectMessage { is_self, .. } | EventKind::RoomMessage { is_self, .. }) =
+            &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self || self.router.route(evt, &self.cmd_tx).is_none() {
+            return Ok(Verdict::Continue);
+        }
+
+        let servers = self.servers.lock().unwrap();
+        let reply = if servers.is_empty() {
+            "No game servers are configured".to_string()
+        } else {
+            servers
+                .iter()
+                .map(|tracked| format_status(&tracked.server.name, tracked.last.as_ref()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        send_reply(evt, reply, &self.cmd_tx);
+        Ok(Verdict::Continue)
+    }
+}
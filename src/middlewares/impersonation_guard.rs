@@ -0,0 +1,280 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    correlation,
+    event::{Event, EventKind, User},
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use crate::middlewares::game_status::AlertDestination;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+/// What the guard does about a name that looks like a protected one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpersonationAction {
+    /// Post to the alert room and leave it to the moderators.
+    Alert,
+    /// Kick the user, then post to the alert room if there is one.
+    Kick,
+}
+
+pub struct ImpersonationGuardConfig {
+    /// Names nobody else may take, on top of the bot's own display names.
+    pub protected_names: Vec<String>,
+    /// Users who really go by a protected name, e.g. the admins, checked against user IDs and
+    /// usernames.
+    pub exempt_user_ids: Vec<String>,
+    /// How similar, from 0 to 1, a name must be to a protected one to be flagged.
+    pub similarity: f64,
+    pub action: ImpersonationAction,
+    pub alerts: Option<AlertDestination>,
+}
+
+// Characters commonly swapped in for the letters they look like: Cyrillic and Greek homoglyphs,
+// digits and symbols. Compared after lowercasing.
+const LOOKALIKES: &[(char, char)] = &[
+    ('а', 'a'),
+    ('е', 'e'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'l'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+    ('к', 'k'),
+    ('м', 'm'),
+    ('т', 't'),
+    ('в', 'b'),
+    ('н', 'h'),
+    ('α', 'a'),
+    ('ε', 'e'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('ι', 'l'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('τ', 't'),
+    ('υ', 'u'),
+    ('0', 'o'),
+    ('1', 'l'),
+    ('3', 'e'),
+    ('4', 'a'),
+    ('5', 's'),
+    ('7', 't'),
+    ('8', 'b'),
+    ('i', 'l'),
+    ('|', 'l'),
+    ('!', 'l'),
+    ('@', 'a'),
+    ('$', 's'),
+];
+
+/// `name` reduced to what it looks like: lowercased, lookalike characters replaced by the
+/// letter they pass for (`i`, `1` and `|` all become `l`, as they do in many fonts), `rn` read
+/// as `m`, and everything but letters and digits dropped, including spaces and invisible
+/// characters.
+pub fn normalize_name(name: &str) -> String {
+    let mapped: String = name
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| LOOKALIKES.iter().find(|(from, _)| *from == c).map_or(c, |(_, to)| *to))
+        .filter(|c| c.is_alphanumeric())
+        .collect();
+    mapped.replace("rn", "m")
+}
+
+/// How alike `a` and `b` look, from 0 (nothing in common) to 1 (indistinguishable once
+/// normalized): one minus their edit distance over the longer one's length.
+pub fn name_similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = normalize_name(a).chars().collect();
+    let b: Vec<char> = normalize_name(b).chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 0.0;
+    }
+    1.0 - edit_distance(&a, &b) as f64 / longest as f64
+}
+
+// Levenshtein distance, keeping one row of the table at a time
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Watches the names people show up with, in user lists, profile changes and the display names
+/// on room messages, for ones confusingly like a protected name: the configured ones and the
+/// bot's own. Bridges make this worse, since a relayed message shows only a name, so names are
+/// compared across services. Each user is flagged once per name they take.
+pub struct ImpersonationGuard {
+    cmd_tx: CommandSender,
+    config: ImpersonationGuardConfig,
+    // The bot's display name on each service, learned from user lists
+    own_names: Mutex<HashMap<ServiceId, String>>,
+    // (service, user, name) already acted on
+    flagged: Mutex<HashSet<(ServiceId, String, String)>>,
+}
+
+impl ImpersonationGuard {
+    pub fn new(ctx: MiddlewareContext, config: ImpersonationGuardConfig) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            config,
+            own_names: Mutex::new(HashMap::new()),
+            flagged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn is_exempt(&self, user_ids: &[&str]) -> bool {
+        self.config.exempt_user_ids.iter().any(|exempt| user_ids.contains(&exempt.as_str()))
+    }
+
+    /// The protected name `name` looks most like, with how similar it is, if it's over the
+    /// threshold.
+    fn closest_protected(&self, name: &str) -> Option<(String, f64)> {
+        let own_names = self.own_names.lock().unwrap();
+        self.config
+            .protected_names
+            .iter()
+            .chain(own_names.values())
+            .map(|protected| (protected.clone(), name_similarity(name, protected)))
+            .filter(|(_, similarity)| *similarity >= self.config.similarity)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Checks `name`, shown by `user_id` on `evt`'s service, acting on it if it's too close to a
+    /// protected name. `room_id` is where to kick them from, when known.
+    fn check(&self, evt: &Event, room_id: Option<&str>, user_id: &str, name: &str) {
+        let Some((protected, similarity)) = self.closest_protected(name) else {
+            return;
+        };
+        let key = (evt.service_id.clone(), user_id.to_string(), name.to_string());
+        if !self.flagged.lock().unwrap().insert(key) {
+            return;
+        }
+        let percent = (similarity * 100.0).round();
+        tracing::warn!(
+            service_id=%evt.service_id, user_id=%user_id, name=%name, protected=%protected, percent,
+            "display name looks like a protected one"
+        );
+
+        let cmd_tx = self.cmd_tx.clone();
+        let alerts = self.config.alerts.clone();
+        let service_id = evt.service_id.clone();
+        let kick = (self.config.action == ImpersonationAction::Kick).then(|| Command::KickUser {
+            service_id: service_id.clone(),
+            room_id: room_id.unwrap_or_default().to_string(),
+            user_id: user_id.to_string(),
+            reason: Some(format!("Display name impersonates {protected}")),
+            response_tx: None,
+            origin: None,
+        });
+        let user_id = user_id.to_string();
+        let summary = format!(
+            "{name} ({user_id}) on {service_id} looks like {protected} ({percent}% similar)"
+        );
+        correlation::spawn(async move {
+            let body = match kick {
+                None => format!("⚠️ {summary}"),
+                Some(kick) => match send_with_retry(&cmd_tx, kick, &RetryPolicy::default()).await {
+                    Ok(_) => format!("🚫 Kicked {summary}"),
+                    Err(e) => {
+                        tracing::warn!(user_id=%user_id, error=%e, "failed to kick impersonator");
+                        format!("⚠️ {summary}, but couldn't kick them: {e}")
+                    }
+                },
+            };
+            let Some(alerts) = alerts else {
+                return;
+            };
+            let command = Command::SendRoomMessage {
+                service_id: alerts.service_id,
+                room_id: alerts.room_id,
+                body,
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            };
+            if let Err(e) = send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+                tracing::error!(error=%e, "failed to send impersonation alert");
+            }
+        });
+    }
+
+    fn check_user(&self, evt: &Event, user: &User) {
+        if user.is_self {
+            if !user.display_name.is_empty() {
+                self.own_names
+                    .lock()
+                    .unwrap()
+                    .insert(evt.service_id.clone(), user.display_name.clone());
+            }
+            return;
+        }
+        if self.is_exempt(&[&user.id, &user.username]) {
+            return;
+        }
+        // Services that identify users by name (Mumble) kick them by it too
+        self.check(evt, None, &user.username, &user.display_name);
+    }
+}
+
+#[async_trait]
+impl Middleware for ImpersonationGuard {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            protected_names = self.config.protected_names.len(),
+            "impersonation_guard middleware running..."
+        );
+        cancel.cancelled().await;
+        tracing::info!("impersonation_guard middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        match &evt.kind {
+            EventKind::UserListUpdate { users } => {
+                // The bot's own name first, so the rest are checked against it
+                for user in users.iter().filter(|user| user.is_self) {
+                    self.check_user(evt, user);
+                }
+                for user in users.iter().filter(|user| !user.is_self) {
+                    self.check_user(evt, user);
+                }
+            }
+            EventKind::UserProfileChanged { user } => self.check_user(evt, user),
+            EventKind::RoomMessage {
+                room_id,
+                sender_id,
+                sender_display_name,
+                is_self: false,
+                ..
+            } => {
+                if !self.is_exempt(&[sender_id]) {
+                    let name = sender_display_name.as_deref().unwrap_or(sender_id);
+                    self.check(evt, Some(room_id), sender_id, name);
+                }
+            }
+            _ => {}
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,250 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    event::Event,
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    roster::Roster,
+    service::ServiceId,
+};
+use crate::middlewares::game_status::AlertDestination;
+use crate::store::PersistentStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+// Store key for when each member of each room was last active, keyed by `service_id/room_id`
+const ACTIVITY_KEY: &str = "activity";
+
+/// What the pruner does about members inactive for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneAction {
+    /// List them in the report room without kicking anyone.
+    Report,
+    /// Kick them, then list who was kicked in the report room if there is one.
+    Kick,
+}
+
+/// A room the pruner looks after. The bot needs to be able to kick people from it.
+#[derive(Debug, Clone)]
+pub struct PrunedRoom {
+    pub service_id: ServiceId,
+    pub room_id: String,
+}
+
+impl PrunedRoom {
+    fn key(&self) -> String {
+        format!("{}/{}", self.service_id, self.room_id)
+    }
+}
+
+pub struct InactivityPrunerConfig {
+    pub rooms: Vec<PrunedRoom>,
+    /// How long a member can go without posting or reacting before they're pruned.
+    pub inactive_after: Duration,
+    pub check_interval: Duration,
+    pub action: PruneAction,
+    pub reports: Option<AlertDestination>,
+    /// Members never pruned, e.g. the admins.
+    pub exempt_user_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Member {
+    last_active: DateTime<Utc>,
+    /// Reported or kicked since they were last active, so it isn't done again.
+    #[serde(default)]
+    pruned: bool,
+}
+
+/// Members of one room found inactive by a check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InactiveMembers {
+    pub room_index: usize,
+    /// User IDs with when each was last active, sorted by ID.
+    pub members: Vec<(String, DateTime<Utc>)>,
+}
+
+/// Prunes members who've gone quiet from rooms the bot administers: every `check_interval`, it
+/// looks up when each member of its rooms last posted or reacted there in the roster, and
+/// members inactive for longer than `inactive_after` are kicked, or with the `report` action
+/// only listed in the report room, as a dry run. Each member is pruned once until they're
+/// active again.
+///
+/// Activity is kept in the store across restarts, since the roster only knows what happened
+/// since the bot started. Members who've never posted or reacted while the bot was watching
+/// aren't known, so aren't pruned. Needs roster tracking (`roster.enabled`).
+pub struct InactivityPruner {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    roster: Roster,
+    config: InactivityPrunerConfig,
+    activity: Mutex<HashMap<String, BTreeMap<String, Member>>>,
+}
+
+impl InactivityPruner {
+    pub fn new(ctx: MiddlewareContext, config: InactivityPrunerConfig) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            roster: ctx.roster,
+            config,
+            activity: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Brings the recorded activity up to date from the roster, then finds the members inactive
+    /// as of `now` that haven't been pruned yet, marking them pruned.
+    pub fn check(&self, now: DateTime<Utc>) -> Vec<InactiveMembers> {
+        let mut activity = self.activity.lock().unwrap();
+        let mut inactive = Vec::new();
+        for (room_index, room) in self.config.rooms.iter().enumerate() {
+            let members = activity.entry(room.key()).or_default();
+            for (user_id, last_active) in self.roster.last_active(&room.service_id, &room.room_id) {
+                let member =
+                    members.entry(user_id).or_insert(Member { last_active, pruned: false });
+                if last_active > member.last_active {
+                    *member = Member { last_active, pruned: false };
+                }
+            }
+            let found: Vec<(String, DateTime<Utc>)> = members
+                .iter_mut()
+                .filter(|(user_id, member)| {
+                    !member.pruned
+                        && (now - member.last_active).to_std().unwrap_or_default()
+                            >= self.config.inactive_after
+                        && !self.config.exempt_user_ids.contains(*user_id)
+                })
+                .map(|(user_id, member)| {
+                    member.pruned = true;
+                    (user_id.clone(), member.last_active)
+                })
+                .collect();
+            if !found.is_empty() {
+                inactive.push(InactiveMembers { room_index, members: found });
+            }
+        }
+        inactive
+    }
+
+    async fn save(&self) {
+        let snapshot = self.activity.lock().unwrap().clone();
+        if let Err(e) = self.store.set(ACTIVITY_KEY, &snapshot).await {
+            tracing::warn!(error=%e, "failed to save member activity");
+        }
+    }
+
+    /// Kicks or reports `inactive`, posting the outcome to the report room.
+    async fn prune(&self, inactive: InactiveMembers) {
+        let room = &self.config.rooms[inactive.room_index];
+        let after = humantime::format_duration(self.config.inactive_after);
+        let listed: Vec<String> = inactive
+            .members
+            .iter()
+            .map(|(user_id, last_active)| {
+                format!("{user_id} (last active {})", last_active.format("%Y-%m-%d"))
+            })
+            .collect();
+        let body = match self.config.action {
+            PruneAction::Report => format!(
+                "🧹 Inactive for over {after} in {} on {}, not kicked: {}",
+                room.room_id,
+                room.service_id,
+                listed.join(", ")
+            ),
+            PruneAction::Kick => {
+                let mut kicked = Vec::new();
+                let mut failed = Vec::new();
+                for (user_id, _) in &inactive.members {
+                    let kick = Command::KickUser {
+                        service_id: room.service_id.clone(),
+                        room_id: room.room_id.clone(),
+                        user_id: user_id.clone(),
+                        reason: Some(format!("Inactive for over {after}")),
+                        response_tx: None,
+                        origin: None,
+                    };
+                    match send_with_retry(&self.cmd_tx, kick, &RetryPolicy::default()).await {
+                        Ok(_) => kicked.push(user_id.clone()),
+                        Err(e) => {
+                            tracing::warn!(user_id=%user_id, error=%e, "failed to kick inactive member");
+                            failed.push(format!("{user_id} ({e})"));
+                        }
+                    }
+                }
+                tracing::info!(room_id=%room.room_id, kicked = kicked.len(), failed = failed.len(), "pruned inactive members");
+                let mut body = format!(
+                    "🧹 Kicked from {} on {} for being inactive over {after}: {}",
+                    room.room_id,
+                    room.service_id,
+                    if kicked.is_empty() { "nobody".to_string() } else { kicked.join(", ") }
+                );
+                if !failed.is_empty() {
+                    body.push_str(&format!("\nCouldn't kick: {}", failed.join(", ")));
+                }
+                body
+            }
+        };
+        let Some(reports) = &self.config.reports else {
+            return;
+        };
+        let command = Command::SendRoomMessage {
+            service_id: reports.service_id.clone(),
+            room_id: reports.room_id.clone(),
+            body,
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        };
+        if let Err(e) = send_with_retry(&self.cmd_tx, command, &RetryPolicy::default()).await {
+            tracing::error!(error=%e, "failed to send inactivity report");
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for InactivityPruner {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if !self.roster.is_enabled() {
+            tracing::warn!("roster tracking is off, so inactivity_pruner won't see any activity");
+        }
+        if let Some(saved) =
+            self.store.get::<HashMap<String, BTreeMap<String, Member>>>(ACTIVITY_KEY).await
+        {
+            *self.activity.lock().unwrap() = saved;
+        }
+        tracing::info!(
+            rooms = self.config.rooms.len(),
+            inactive_after=?self.config.inactive_after,
+            "inactivity_pruner middleware running..."
+        );
+        let mut ticker = tokio::time::interval(self.config.check_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    let inactive = self.check(Utc::now());
+                    self.save().await;
+                    for room in inactive {
+                        self.prune(room).await;
+                    }
+                }
+            }
+        }
+        tracing::info!("inactivity_pruner middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, _evt: &Arc<Event>) -> Result<Verdict> {
+        Ok(Verdict::Continue)
+    }
+}
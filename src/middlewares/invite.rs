@@ -1,19 +1,37 @@
 use crate::core::{
-    bus::Command,
+    bus::{Command, CommandSender, NotEncrypted},
+    commands::{ArgSpec, CommandRouter, CommandSpec, parse_duration, send_reply, split_words},
+    correlation,
     event::{Event, EventKind},
     middleware::{Middleware, MiddlewareContext, Verdict},
+    redact,
 };
 use anyhow::Result;
 use async_trait::async_trait;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 
+const DEFAULT_USES_ALLOWED: u32 = 1;
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const DEFAULT_MESSAGE_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+const NOT_ENCRYPTED_WARNING: &str = "⚠️ Your registration token wasn't sent because this \
+     conversation isn't end-to-end encrypted. Turn on encryption for this DM and ask again.";
+
+/// Generates registration tokens, e.g. `!invite` or `!invite 5 uses 24h`.
+///
+/// The configured `uses_allowed` and `expiry` are the defaults and also the most a user can
+/// ask for.
+///
+/// Tokens are only sent over an end-to-end encrypted DM, and the message is deleted after
+/// `message_expiry` (an hour by default) or when the token expires, whichever comes first.
 pub struct Invite {
-    cmd_tx: Sender<Command>,
-    command_string: String,
+    cmd_tx: CommandSender,
+    router: CommandRouter,
     uses_allowed: Option<u32>,
     expiry: Option<Duration>,
+    message_expiry: Option<Duration>,
 }
 
 impl Invite {
@@ -22,8 +40,50 @@ impl Invite {
         command_string: String,
         uses_allowed: Option<u32>,
         expiry: Option<Duration>,
+        message_expiry: Option<Duration>,
     ) -> Self {
-        Self { cmd_tx: ctx.cmd_tx, command_string, uses_allowed, expiry }
+        let router = CommandRouter::new(command_string)
+            .with_description("Create an invite link")
+            .with_args(vec![ArgSpec::optional_rest("options")]);
+        Self { cmd_tx: ctx.cmd_tx, router, uses_allowed, expiry, message_expiry }
+    }
+
+    /// Parses `[<n> [uses]] [<duration>]`, in either order, into the uses and expiry to request.
+    /// Anything left unspecified falls back to the configured values.
+    fn parse_options(&self, options: &str) -> Result<(Option<u32>, Option<Duration>), String> {
+        let max_uses = self.uses_allowed.unwrap_or(DEFAULT_USES_ALLOWED);
+        let max_expiry = self.expiry.unwrap_or(DEFAULT_EXPIRY);
+        let mut uses = None;
+        let mut expiry = None;
+
+        let mut words = split_words(options).into_iter().peekable();
+        while let Some(word) = words.next() {
+            if let Ok(n) = word.parse::<u32>() {
+                if uses.replace(n).is_some() {
+                    return Err("Specify the number of uses only once.".to_string());
+                }
+                words.next_if(|next| {
+                    next.eq_ignore_ascii_case("uses") || next.eq_ignore_ascii_case("use")
+                });
+            } else if expiry.replace(parse_duration(word)?).is_some() {
+                return Err("Specify the expiry only once.".to_string());
+            }
+        }
+
+        if let Some(n) = uses
+            && !(1..=max_uses).contains(&n)
+        {
+            return Err(format!("Uses must be between 1 and {max_uses}."));
+        }
+        if let Some(requested) = expiry
+            && requested > max_expiry
+        {
+            return Err(format!(
+                "Expiry can be at most {}.",
+                humantime::format_duration(max_expiry)
+            ));
+        }
+        Ok((uses.or(self.uses_allowed), expiry.or(self.expiry)))
     }
 }
 
@@ -36,19 +96,38 @@ impl Middleware for Invite {
         Ok(())
     }
 
-    fn on_event(&self, evt: &Event) -> Result<Verdict> {
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
         match &evt.kind {
             EventKind::UserListUpdate { .. }
+            | EventKind::UserListDiff { .. }
             | EventKind::RoomMessage { .. }
             | EventKind::ReactionAdded { .. }
             | EventKind::ReactionRemoved { .. }
-            | EventKind::RoomImage { .. } => {
+            | EventKind::RoomImage { .. }
+            | EventKind::Knock { .. }
+            | EventKind::RoomUpgraded { .. }
+            | EventKind::MissedMessages { .. }
+            | EventKind::UserProfileChanged { .. }
+            | EventKind::VoiceStateChanged { .. }
+            | EventKind::VerificationRequested { .. }
+            | EventKind::ConnectionScheduled { .. }
+            | EventKind::ServiceReady
+            | EventKind::ServiceDegraded { .. }
+            | EventKind::ServiceStopped => {
                 // Ignore non-DM events
                 return Ok(Verdict::Continue);
             }
-            EventKind::DirectMessage { body, user_id, is_local_user, .. } => {
+            EventKind::DirectMessage { user_id, is_local_user, .. } => {
                 // Check if the message is the invite command
-                if body.trim() == self.command_string {
+                if let Some(invocation) = self.router.route(evt, &self.cmd_tx) {
                     // Only process if user is from the same homeserver/instance
                     if !is_local_user {
                         tracing::info!(
@@ -63,18 +142,30 @@ impl Middleware for Invite {
                             body: "Invite tokens can only be generated for users from this server."
                                 .to_string(),
                             response_tx: None,
+                            origin: None,
+                            idempotency_key: None,
+                            expires_after: None,
+                            require_encryption: false,
                         };
 
-                        let cmd_tx = self.cmd_tx.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = cmd_tx.send(command).await {
-                                tracing::error!(error=%e, "failed to send rejection message");
-                            }
-                        });
+                        self.cmd_tx.spawn_send(command, "rejection message");
 
                         return Ok(Verdict::Continue);
                     }
 
+                    let (uses_allowed, expiry) = match self.parse_options(invocation.arg("options"))
+                    {
+                        Ok(options) => options,
+                        Err(e) => {
+                            send_reply(
+                                evt,
+                                format!("{e}\n{}", self.router.usage_on(&evt.service_id)),
+                                &self.cmd_tx,
+                            );
+                            return Ok(Verdict::Continue);
+                        }
+                    };
+
                     // Create oneshot channel for the response
                     let (response_tx, response_rx) = tokio::sync::oneshot::channel();
 
@@ -82,26 +173,24 @@ impl Middleware for Invite {
                     let command = Command::GenerateInviteToken {
                         service_id: evt.service_id.clone(),
                         user_id: user_id.clone(),
-                        uses_allowed: self.uses_allowed,
-                        expiry: self.expiry,
+                        uses_allowed,
+                        expiry,
                         response_tx,
+                        origin: None,
                     };
 
                     // Send the command and wait for the response
+                    self.cmd_tx.spawn_send(command, "invite token request");
                     let cmd_tx = self.cmd_tx.clone();
                     let service_id = evt.service_id.clone();
                     let user_id_clone = user_id.clone();
-                    let uses_allowed = self.uses_allowed.unwrap_or(1);
-                    let expiry_duration =
-                        self.expiry.unwrap_or(Duration::from_secs(7 * 24 * 60 * 60));
-
-                    tokio::spawn(async move {
-                        // Send the command
-                        if let Err(e) = cmd_tx.send(command).await {
-                            tracing::error!(error=%e, "failed to send generate invite token command");
-                            return;
-                        }
+                    let uses_allowed = uses_allowed.unwrap_or(DEFAULT_USES_ALLOWED);
+                    let expiry_duration = expiry.unwrap_or(DEFAULT_EXPIRY);
+                    // The token is no use once it expires, so neither is the message
+                    let message_expiry =
+                        self.message_expiry.unwrap_or(DEFAULT_MESSAGE_EXPIRY).min(expiry_duration);
 
+                    correlation::spawn(async move {
                         // Wait for the response
                         let result = match response_rx.await {
                             Ok(result) => result,
@@ -111,9 +200,13 @@ impl Middleware for Invite {
                             }
                         };
 
+                        let is_token = result.is_ok();
+
                         // Format the response message
                         let message = match result {
                             Ok(token) => {
+                                // Keep the token out of logs and transcripts, including the reply below
+                                redact::add_secret(&token);
                                 tracing::info!(user_id=%user_id_clone, "token generated successfully");
 
                                 // Calculate expiration time
@@ -146,17 +239,42 @@ impl Middleware for Invite {
                             }
                         };
 
-                        // Send the result back to the user
+                        // Send the result back to the user. A token only goes out over an encrypted
+                        // DM, and is deleted again once they've had time to copy it
+                        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
                         let reply_command = Command::SendDirectMessage {
-                            service_id,
-                            user_id: user_id_clone,
+                            service_id: service_id.clone(),
+                            user_id: user_id_clone.clone(),
                             body: message,
-                            response_tx: None,
+                            response_tx: Some(response_tx),
+                            origin: None,
+                            idempotency_key: None,
+                            expires_after: is_token.then_some(message_expiry),
+                            require_encryption: is_token,
                         };
 
-                        if let Err(e) = cmd_tx.send(reply_command).await {
+                        cmd_tx.spawn_send(reply_command, "invite token response");
+                        let Ok(Err(e)) = response_rx.await else {
+                            return;
+                        };
+                        if e.downcast_ref::<NotEncrypted>().is_none() {
                             tracing::error!(error=%e, "failed to send invite token response");
+                            return;
                         }
+
+                        // Let them know why the token never arrived
+                        tracing::warn!(user_id=%user_id_clone, "DM isn't encrypted, not sending token");
+                        let warning = Command::SendDirectMessage {
+                            service_id,
+                            user_id: user_id_clone,
+                            body: NOT_ENCRYPTED_WARNING.to_string(),
+                            response_tx: None,
+                            origin: None,
+                            idempotency_key: None,
+                            expires_after: None,
+                            require_encryption: false,
+                        };
+                        cmd_tx.spawn_send(warning, "unencrypted DM warning");
                     });
 
                     tracing::info!(user_id=%user_id, "processing invite command");
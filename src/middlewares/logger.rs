@@ -1,9 +1,11 @@
 use crate::core::{
     event::{Event, EventKind},
     middleware::{Middleware, Verdict},
+    redact,
 };
 use anyhow::Result;
 use async_trait::async_trait;
+use std::sync::Arc;
 use tokio_util::sync::CancellationToken;
 
 pub struct Logger;
@@ -17,7 +19,7 @@ impl Middleware for Logger {
         Ok(())
     }
 
-    fn on_event(&self, evt: &Event) -> anyhow::Result<Verdict> {
+    fn on_event(&self, evt: &Arc<Event>) -> anyhow::Result<Verdict> {
         match &evt.kind {
             EventKind::UserListUpdate { users } => {
                 let usernames: Vec<String> = users
@@ -38,7 +40,7 @@ impl Middleware for Logger {
                 );
             }
             _ => {
-                tracing::info!(event=%evt, "inbound event");
+                tracing::info!(event=%redact::redact(&evt.to_string()), "inbound event");
             }
         }
         Ok(Verdict::Continue)
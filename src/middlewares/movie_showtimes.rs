@@ -1,30 +1,25 @@
 use crate::core::{
-    bus::Command,
+    bus::{Command, CommandSender},
+    config::LatLng,
     event::{Event, EventKind},
+    format::BodyFormat,
+    http::HttpClient,
     middleware::{Middleware, MiddlewareContext, Verdict},
+    quiet_hours::{QuietHours, defer_past},
+    scheduler::SchedulerHandle,
     service::ServiceId,
+    time_zone::{next_weekly, now_in},
 };
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use chrono::{
-    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Weekday,
-};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
 use serde::Deserialize;
-use serde_with::{DisplayFromStr, serde_as};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{Mutex, mpsc::Sender};
+use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
-#[serde_as]
-#[derive(Debug, Clone, Copy, Deserialize)]
-pub struct LatLng {
-    #[serde_as(as = "DisplayFromStr")]
-    pub lat: f64,
-    #[serde_as(as = "DisplayFromStr")]
-    pub lng: f64,
-}
-
 // TMS API response structures
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -79,7 +74,7 @@ struct TheaterShowtimes {
 #[derive(Debug, Clone)]
 struct CachedListings {
     listings: Vec<MovieListing>,
-    cached_at: DateTime<Local>,
+    cached_at: DateTime<Tz>,
 }
 
 // Configuration needed for fetching movie data
@@ -91,16 +86,20 @@ struct MovieFetchConfig {
 }
 
 pub struct MovieShowtimes {
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     service_id: String,
     room_id: String,
     post_on_day_of_week: Weekday,
     post_at_time: NaiveTime,
     fetch_config: Arc<MovieFetchConfig>,
+    http: HttpClient,
     cache: Arc<Mutex<Option<CachedListings>>>,
     command_string: String,
     query_tx: tokio::sync::mpsc::Sender<String>,
     query_rx: Arc<Mutex<tokio::sync::mpsc::Receiver<String>>>,
+    quiet_hours: Option<QuietHours>,
+    scheduler: SchedulerHandle,
+    timezone: Tz,
 }
 
 impl MovieShowtimes {
@@ -116,6 +115,7 @@ impl MovieShowtimes {
         gracenote_api_key: String,
         theater_id_filter: Option<Vec<String>>,
         command_string: Option<String>,
+        timezone: Tz,
     ) -> Self {
         let (query_tx, query_rx) = tokio::sync::mpsc::channel(100);
 
@@ -131,47 +131,21 @@ impl MovieShowtimes {
                 gracenote_api_key,
                 theater_id_filter,
             }),
+            http: ctx.http,
             cache: Arc::new(Mutex::new(None)),
             command_string: command_string.unwrap_or_else(|| "!movie".to_string()),
             query_tx,
             query_rx: Arc::new(Mutex::new(query_rx)),
+            quiet_hours: ctx.quiet_hours,
+            scheduler: ctx.scheduler,
+            timezone,
         }
     }
 
-    /// Calculate the next scheduled time based on post_on_day_of_week and post_at_time
-    fn next_scheduled_time(&self) -> chrono::DateTime<Local> {
-        let now = Local::now();
-        let target_time = self.post_at_time;
-
-        // Calculate days until next occurrence
-        let current_weekday = now.weekday();
-        let target_weekday = self.post_on_day_of_week;
-
-        let days_until_target = if current_weekday == target_weekday {
-            // Same day - check if time has passed
-            let now_time = now.time();
-            if now_time < target_time {
-                0 // Today, but later
-            } else {
-                7 // Next week
-            }
-        } else {
-            // Different day - calculate days forward
-            let current_num = current_weekday.number_from_monday();
-            let target_num = target_weekday.number_from_monday();
-
-            if target_num > current_num {
-                target_num - current_num
-            } else {
-                7 - (current_num - target_num)
-            }
-        };
-
-        // Create target datetime
-        let target_date = now.date_naive() + Duration::days(days_until_target as i64);
-        let target_datetime = target_date.and_time(target_time);
-
-        Local.from_local_datetime(&target_datetime).unwrap()
+    /// Calculate the next scheduled time after `now` based on post_on_day_of_week and
+    /// post_at_time
+    fn next_scheduled_time(&self, now: &DateTime<Tz>) -> DateTime<Tz> {
+        next_weekly(now, self.post_on_day_of_week, self.post_at_time)
     }
 
     /// Process API movies into grouped listings, applying theater priority filter
@@ -298,7 +272,7 @@ impl MovieShowtimes {
         tracing::info!("fetching movie showtimes from TMS API");
 
         // Build API request (same as before)
-        let today = Local::now().format("%Y-%m-%d").to_string();
+        let today = now_in(self.timezone).format("%Y-%m-%d").to_string();
         let url = format!(
             "http://data.tmsapi.com/v1.1/movies/showings?api_key={}&lat={}&lng={}&radius={}&units=mi&startDate={}&numDays=7",
             self.fetch_config.gracenote_api_key,
@@ -309,9 +283,8 @@ impl MovieShowtimes {
         );
 
         // Fetch from API
-        let client = reqwest::Client::new();
         let response =
-            client.get(&url).send().await.context("failed to send request to TMS API")?;
+            self.http.get(&url).send().await.context("failed to send request to TMS API")?;
 
         if !response.status().is_success() {
             anyhow::bail!("TMS API returned error: {}", response.status());
@@ -331,7 +304,7 @@ impl MovieShowtimes {
 
     /// Get cached listings or fetch fresh if cache is empty or from a different day
     async fn get_or_fetch_listings(&self) -> Result<CachedListings> {
-        let now = Local::now();
+        let now = now_in(self.timezone);
         let today = now.date_naive();
 
         // Try cache first and check if it's from today
@@ -391,7 +364,7 @@ impl MovieShowtimes {
                 tracing::error!(error=%e, "failed to fetch movie listings");
                 self.send_room_response(
                     "Failed to fetch movie showtimes. Please try again later.".to_string(),
-                    None,
+                    BodyFormat::Plain,
                 )
                 .await;
                 return;
@@ -403,7 +376,7 @@ impl MovieShowtimes {
             Some(movie) => {
                 // Found - send detailed showtimes
                 if let Ok(detail) = Self::format_movie_detail_static(movie, cached.cached_at) {
-                    self.send_room_response(detail.clone(), Some(detail)).await;
+                    self.send_room_response(detail, BodyFormat::Markdown).await;
                 }
             }
             None => {
@@ -413,14 +386,14 @@ impl MovieShowtimes {
                     query
                 );
 
-                self.send_room_response(message, None).await;
+                self.send_room_response(message, BodyFormat::Plain).await;
             }
         }
     }
 
     /// Format a timestamp in relative format (e.g., "Today at 7:40 PM")
-    fn format_relative_time(dt: DateTime<Local>) -> String {
-        let now = Local::now();
+    fn format_relative_time(dt: DateTime<Tz>) -> String {
+        let now = now_in(dt.timezone());
         let date = dt.date_naive();
         let today = now.date_naive();
 
@@ -438,7 +411,7 @@ impl MovieShowtimes {
     /// Format detailed showtimes for a single movie (helper function)
     fn format_movie_detail_static(
         listing: &MovieListing,
-        cached_at: DateTime<Local>,
+        cached_at: DateTime<Tz>,
     ) -> Result<String> {
         let mut message = String::new();
 
@@ -484,13 +457,17 @@ impl MovieShowtimes {
     }
 
     /// Send a response message to the configured room
-    async fn send_room_response(&self, body: String, markdown_body: Option<String>) {
+    async fn send_room_response(&self, body: String, format: BodyFormat) {
         let command = Command::SendRoomMessage {
             service_id: ServiceId(self.service_id.clone()),
             room_id: self.room_id.clone(),
             body,
-            markdown_body,
+            format,
             response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
         };
 
         if let Err(e) = self.cmd_tx.send(command).await {
@@ -515,7 +492,7 @@ impl MovieShowtimes {
             }
         };
 
-        self.send_room_response(message.clone(), Some(message)).await;
+        self.send_room_response(message, BodyFormat::Markdown).await;
     }
 
     /// Send an error message to the room
@@ -524,16 +501,15 @@ impl MovieShowtimes {
             service_id: ServiceId(self.service_id.clone()),
             room_id: self.room_id.clone(),
             body: error_msg,
-            markdown_body: None,
+            format: BodyFormat::Plain,
             response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
         };
 
-        let cmd_tx = self.cmd_tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = cmd_tx.send(command).await {
-                tracing::error!(error=%e, "failed to send error message");
-            }
-        });
+        self.cmd_tx.spawn_send(command, "error message");
     }
 
     /// Post showtimes summary to the configured room
@@ -556,7 +532,8 @@ impl MovieShowtimes {
 
         // Cache the listings with timestamp
         {
-            let cached = CachedListings { listings: listings.clone(), cached_at: Local::now() };
+            let cached =
+                CachedListings { listings: listings.clone(), cached_at: now_in(self.timezone) };
             let mut cache = self.cache.lock().await;
             *cache = Some(cached);
             tracing::debug!("cached {} movie listings", listings.len());
@@ -574,9 +551,13 @@ impl MovieShowtimes {
         let command = Command::SendRoomMessage {
             service_id: ServiceId(self.service_id.clone()),
             room_id: self.room_id.clone(),
-            body: summary.clone(),
-            markdown_body: Some(summary),
+            body: summary,
+            format: BodyFormat::Markdown,
             response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
         };
 
         if let Err(e) = self.cmd_tx.send(command).await {
@@ -591,8 +572,9 @@ impl MovieShowtimes {
 impl Middleware for MovieShowtimes {
     async fn run(&self, cancel: CancellationToken) -> Result<()> {
         let mut query_rx = self.query_rx.lock().await;
-        let mut next_time = self.next_scheduled_time();
-        let mut in_cooldown = false;
+        let mut scheduler = self.scheduler.scheduler(self.timezone);
+        let mut next_time =
+            defer_past(self.quiet_hours.as_ref(), self.next_scheduled_time(&scheduler.now()));
 
         tracing::info!(
             post_on_day_of_week=?self.post_on_day_of_week,
@@ -602,33 +584,23 @@ impl Middleware for MovieShowtimes {
         );
 
         loop {
-            let now = Local::now();
-            let duration_until = if in_cooldown {
-                // Short cooldown to avoid re-posting if time calculation is slightly off
-                std::time::Duration::from_secs(2)
-            } else {
-                (next_time - now).to_std().unwrap_or(std::time::Duration::from_secs(0))
-            };
-
             tokio::select! {
                 _ = cancel.cancelled() => {
                     tracing::info!("movie_showtimes middleware shutting down...");
                     break;
                 }
-                _ = tokio::time::sleep(duration_until) => {
-                    if in_cooldown {
-                        // Cooldown finished, recalculate next scheduled time
-                        in_cooldown = false;
-                        next_time = self.next_scheduled_time();
-                        tracing::info!(
-                            next_scheduled=%next_time.format("%Y-%m-%d %H:%M:%S %Z"),
-                            "next scheduled post"
-                        );
-                    } else {
-                        // Time for scheduled post
-                        self.post_showtimes().await;
-                        in_cooldown = true;
-                    }
+                _ = scheduler.wait_until(&next_time) => {
+                    self.post_showtimes().await;
+                    // The scheduler's now is never before the post just made, so this is next
+                    // week's
+                    next_time = defer_past(
+                        self.quiet_hours.as_ref(),
+                        self.next_scheduled_time(&scheduler.now()),
+                    );
+                    tracing::info!(
+                        next_scheduled=%next_time.format("%Y-%m-%d %H:%M:%S %Z"),
+                        "next scheduled post"
+                    );
                 }
                 Some(query) = query_rx.recv() => {
                     // Process movie query - not blocked by cooldown!
@@ -644,7 +616,11 @@ impl Middleware for MovieShowtimes {
         Ok(())
     }
 
-    fn on_event(&self, evt: &Event) -> Result<Verdict> {
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.command_string.as_str()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
         // Only handle room messages in the configured room
         let (room_id, body, is_self) = match &evt.kind {
             EventKind::RoomMessage { room_id, body, is_self, .. } => (room_id, body, is_self),
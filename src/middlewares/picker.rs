@@ -0,0 +1,189 @@
+use crate::core::{
+    bus::CommandSender,
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use crate::store::PersistentStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::seq::SliceRandom;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+// Store key for every room's list, keyed by `service_id/room_id`
+const LISTS_KEY: &str = "lists";
+
+/// The options in `input`: separated by commas, or by " or " when there are no commas.
+pub fn parse_options(input: &str) -> Vec<String> {
+    let options: Vec<&str> = if input.contains(',') {
+        input.split(',').collect()
+    } else {
+        input.split(" or ").collect()
+    };
+    options
+        .into_iter()
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Settles small decisions: `!pick a, b, c` picks one of the options at random, and `!lunch`
+/// picks from a list kept for the room, managed with `!lunch add <places>`,
+/// `!lunch remove <place>` and `!lunch list`. Lists are kept in the store.
+pub struct Picker {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    pick_router: CommandRouter,
+    list_router: CommandRouter,
+    lists: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl Picker {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        list_command_string: String,
+    ) -> Self {
+        let pick_router = CommandRouter::new(command_string)
+            .with_description("Pick one of a few options, separated by commas")
+            .with_args(vec![ArgSpec::rest("options")]);
+        let list_router = CommandRouter::new(list_command_string)
+            .with_description("Pick from this room's list, or add, remove and list its entries")
+            .with_args(vec![ArgSpec::optional_rest("action")]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            pick_router,
+            list_router,
+            lists: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn pick(&self, evt: &Event, options: &str) {
+        let options = parse_options(options);
+        let reply = if options.len() < 2 {
+            "Give me at least two options, separated by commas".to_string()
+        } else {
+            let choice = options.choose(&mut rand::thread_rng()).expect("options isn't empty");
+            format!("🎲 {choice}")
+        };
+        send_reply(evt, reply, &self.cmd_tx);
+    }
+
+    /// Handles the list command: picks from the room's list, or changes or shows it.
+    fn list_command(&self, evt: &Arc<Event>, room_id: &str, input: &str) {
+        let input = input.trim();
+        let (action, rest) =
+            input.split_once(char::is_whitespace).map_or((input, ""), |(a, rest)| (a, rest.trim()));
+        let key = format!("{}/{room_id}", evt.service_id);
+        let prefix = self.list_router.prefix_on(&evt.service_id);
+        let mut lists = self.lists.lock().unwrap();
+        let reply = match (action, rest) {
+            ("", _) => {
+                match lists.get(&key).and_then(|list| list.choose(&mut rand::thread_rng())) {
+                    Some(choice) => format!("🎲 {choice}"),
+                    None => format!("The list is empty. Add to it with {prefix} add <places>"),
+                }
+            }
+            ("list", "") => match lists.get(&key).filter(|list| !list.is_empty()) {
+                Some(list) => format!("On the list: {}", list.join(", ")),
+                None => format!("The list is empty. Add to it with {prefix} add <places>"),
+            },
+            ("add", rest) if !rest.is_empty() => {
+                let list = lists.entry(key).or_default();
+                let (existing, added): (Vec<String>, Vec<String>) =
+                    parse_options(rest).into_iter().partition(|option| {
+                        list.iter().any(|entry| entry.eq_ignore_ascii_case(option))
+                    });
+                list.extend(added.iter().cloned());
+                let reply = match (added.is_empty(), existing.is_empty()) {
+                    (false, true) => format!("Added {}", added.join(", ")),
+                    (false, false) => format!(
+                        "Added {}; already on the list: {}",
+                        added.join(", "),
+                        existing.join(", ")
+                    ),
+                    (true, _) => format!("Already on the list: {}", existing.join(", ")),
+                };
+                self.save(evt, &lists, reply);
+                return;
+            }
+            ("remove", rest) if !rest.is_empty() => {
+                let list = lists.entry(key).or_default();
+                let Some(index) = list.iter().position(|entry| entry.eq_ignore_ascii_case(rest))
+                else {
+                    drop(lists);
+                    send_reply(evt, format!("{rest} isn't on the list"), &self.cmd_tx);
+                    return;
+                };
+                let removed = list.remove(index);
+                self.save(evt, &lists, format!("Removed {removed}"));
+                return;
+            }
+            _ => format!(
+                "Usage: {prefix} | {prefix} list | {prefix} add <places> | {prefix} remove <place>"
+            ),
+        };
+        drop(lists);
+        send_reply(evt, reply, &self.cmd_tx);
+    }
+
+    /// Saves `lists`, then replies with `reply`.
+    fn save(&self, evt: &Arc<Event>, lists: &HashMap<String, Vec<String>>, reply: String) {
+        let snapshot = lists.clone();
+        let store = self.store.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let mut reply = reply;
+            if let Err(e) = store.set(LISTS_KEY, &snapshot).await {
+                tracing::error!(error=%e, "failed to save picker lists");
+                reply.push_str(" (until restart: failed to save it)");
+            }
+            send_reply(&evt, reply, &cmd_tx);
+        });
+    }
+}
+
+#[async_trait]
+impl Middleware for Picker {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if let Some(saved) = self.store.get::<HashMap<String, Vec<String>>>(LISTS_KEY).await {
+            *self.lists.lock().unwrap() = saved;
+        }
+        tracing::info!("picker middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("picker middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.pick_router.prefix(), self.list_router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.pick_router.spec(), self.list_router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::RoomMessage { room_id, body, is_self: false, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if self.pick_router.matches(body) {
+            if let Some(invocation) = self.pick_router.route(evt, &self.cmd_tx) {
+                self.pick(evt, invocation.arg("options"));
+            }
+        } else if self.list_router.matches(body)
+            && let Some(invocation) = self.list_router.route(evt, &self.cmd_tx)
+        {
+            self.list_command(evt, room_id, invocation.get("action").unwrap_or_default());
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+// How long to wait for a service to answer before reporting it as unresponsive
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers `!ping [service]` with the round trip to a service's server and how long the whole
+/// request took through the bot, so lag can be pinned on one or the other. Without a service,
+/// pings the one the command came from.
+pub struct Ping {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+}
+
+impl Ping {
+    pub fn new(ctx: MiddlewareContext, command_string: String) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Check that the bot and its services are responding")
+            .with_args(vec![ArgSpec::optional("service")]);
+        Self { cmd_tx: ctx.cmd_tx, router }
+    }
+}
+
+/// The reply to a ping of `service_id` that took `total` end to end.
+pub fn format_ping(
+    service_id: &ServiceId,
+    round_trip: Result<Duration>,
+    total: Duration,
+) -> String {
+    match round_trip {
+        Ok(round_trip) => format!(
+            "{service_id}: {} ms to the server, {} ms through the bot",
+            round_trip.as_millis(),
+            total.as_millis()
+        ),
+        Err(e) => format!("{service_id}: ping failed: {e}"),
+    }
+}
+
+#[async_trait]
+impl Middleware for Ping {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("ping middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("ping middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let (EventKind::DirectMessage { is_self, .. } | EventKind::RoomMessage { is_self, .. }) =
+            &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self {
+            return Ok(Verdict::Continue);
+        }
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let service_id = invocation
+            .get("service")
+            .map_or(evt.service_id.clone(), |id| ServiceId(id.to_string()));
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let started = Instant::now();
+        let command = Command::Ping { service_id: service_id.clone(), response_tx, origin: None };
+        self.cmd_tx.spawn_send(command, "ping");
+
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let round_trip = match tokio::time::timeout(PING_TIMEOUT, response_rx).await {
+                Ok(Ok(round_trip)) => round_trip,
+                Ok(Err(_)) => Err(anyhow::anyhow!("no answer")),
+                Err(_) => Err(anyhow::anyhow!("no answer within {}s", PING_TIMEOUT.as_secs())),
+            };
+            send_reply(&evt, format_ping(&service_id, round_trip, started.elapsed()), &cmd_tx);
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
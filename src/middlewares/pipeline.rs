@@ -0,0 +1,107 @@
+use crate::core::{
+    bus::{BusControl, Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Lets admins see the middlewares the current service's events run through over DM, in order
+/// and with how often each returned `Stop`, e.g. `!pipeline` or `!pipeline !lobby:example.com`
+/// for a room with its own pipeline.
+pub struct Pipeline {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    admin_user_ids: Vec<String>,
+}
+
+impl Pipeline {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        admin_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Show the middleware pipeline and how often each step stopped events")
+            .with_args(vec![ArgSpec::optional("room")]);
+        Self { cmd_tx: ctx.cmd_tx, router, admin_user_ids }
+    }
+}
+
+#[async_trait]
+impl Middleware for Pipeline {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("pipeline middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("pipeline middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage { body, user_id, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self || !self.router.matches(body) {
+            return Ok(Verdict::Continue);
+        }
+
+        // Checked before parsing so non-admins don't even get usage replies
+        if !self.admin_user_ids.iter().any(|admin| admin == sender_id) {
+            tracing::info!(sender_id=%sender_id, "ignoring pipeline command from non-admin");
+            return Ok(Verdict::Continue);
+        }
+
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+        let room_id = invocation.get("room").map(str::to_string);
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let control = BusControl::DescribePipeline {
+            service_id: evt.service_id.clone(),
+            room_id,
+            response_tx: Some(response_tx),
+        };
+        self.cmd_tx.spawn_send(Command::Control(control), "pipeline description request");
+
+        let cmd_tx = self.cmd_tx.clone();
+        let service_id = evt.service_id.clone();
+        let user_id = user_id.clone();
+        correlation::spawn(async move {
+            let reply = match response_rx.await {
+                Ok(Ok(description)) => description,
+                Ok(Err(e)) => format!("Failed: {e}"),
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to receive pipeline description");
+                    return;
+                }
+            };
+
+            let command = Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body: reply,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            };
+            cmd_tx.spawn_send(command, "pipeline reply");
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::core::{
+    bus::CommandSender,
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    preferences::{PreferenceStore, ReplyMode, UserPreferences, parse_locale, parse_timezone},
+};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Lets users view and change their own preferences over DM, e.g.
+/// `!prefs set timezone America/Los_Angeles` or `!prefs optout movies`.
+pub struct Prefs {
+    cmd_tx: CommandSender,
+    preferences: PreferenceStore,
+    router: CommandRouter,
+}
+
+impl Prefs {
+    pub fn new(ctx: MiddlewareContext, command_string: String) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Show or change your preferences")
+            .with_subcommand("show", vec![])
+            .with_subcommand("set", vec![ArgSpec::required("setting"), ArgSpec::rest("value")])
+            .with_subcommand("unset", vec![ArgSpec::required("setting")])
+            .with_subcommand("optout", vec![ArgSpec::required("topic")])
+            .with_subcommand("optin", vec![ArgSpec::required("topic")]);
+        Self { cmd_tx: ctx.cmd_tx, preferences: ctx.preferences, router }
+    }
+}
+
+/// A change to one user's preferences, checked before it's applied.
+enum Change {
+    Timezone(Option<String>),
+    Locale(Option<String>),
+    Replies(ReplyMode),
+    OptOut(String),
+    OptIn(String),
+}
+
+impl Change {
+    fn set(setting: &str, value: &str) -> Result<Self> {
+        match setting.to_ascii_lowercase().as_str() {
+            "timezone" => Ok(Change::Timezone(Some(parse_timezone(value)?))),
+            "locale" => Ok(Change::Locale(Some(parse_locale(value)?))),
+            "replies" => Ok(Change::Replies(value.parse()?)),
+            _ => Err(unknown_setting(setting)),
+        }
+    }
+
+    fn unset(setting: &str) -> Result<Self> {
+        match setting.to_ascii_lowercase().as_str() {
+            "timezone" => Ok(Change::Timezone(None)),
+            "locale" => Ok(Change::Locale(None)),
+            "replies" => Ok(Change::Replies(ReplyMode::default())),
+            _ => Err(unknown_setting(setting)),
+        }
+    }
+
+    fn apply(self, preferences: &mut UserPreferences) {
+        match self {
+            Change::Timezone(timezone) => preferences.timezone = timezone,
+            Change::Locale(locale) => preferences.locale = locale,
+            Change::Replies(mode) => preferences.reply_mode = mode,
+            Change::OptOut(topic) => {
+                preferences.opt_outs.insert(topic);
+            }
+            Change::OptIn(topic) => {
+                preferences.opt_outs.remove(&topic);
+            }
+        }
+    }
+}
+
+fn unknown_setting(setting: &str) -> anyhow::Error {
+    anyhow!("unknown setting '{setting}'. Expected timezone, locale or replies")
+}
+
+/// Lists a user's preferences, one per line.
+pub fn format_preferences(preferences: &UserPreferences) -> String {
+    let opt_outs = if preferences.opt_outs.is_empty() {
+        "none".to_string()
+    } else {
+        preferences.opt_outs.iter().cloned().collect::<Vec<_>>().join(", ")
+    };
+    format!(
+        "Your preferences:\ntimezone: {}\nlocale: {}\nreplies: {}\nopted out of: {opt_outs}",
+        preferences.timezone.as_deref().unwrap_or("not set"),
+        preferences.locale.as_deref().unwrap_or("not set"),
+        preferences.reply_mode,
+    )
+}
+
+#[async_trait]
+impl Middleware for Prefs {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("prefs middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("prefs middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::DirectMessage { body, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self {
+            return Ok(Verdict::Continue);
+        }
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+
+        let change = match invocation.subcommand {
+            Some("set") => Change::set(invocation.arg("setting"), invocation.arg("value")),
+            Some("unset") => Change::unset(invocation.arg("setting")),
+            Some("optout") => Ok(Change::OptOut(invocation.arg("topic").to_ascii_lowercase())),
+            Some("optin") => Ok(Change::OptIn(invocation.arg("topic").to_ascii_lowercase())),
+            _ => {
+                let preferences = self.preferences.clone();
+                let cmd_tx = self.cmd_tx.clone();
+                let sender_id = sender_id.clone();
+                let evt = evt.clone();
+                correlation::spawn(async move {
+                    let current = preferences.get(&evt.service_id, &sender_id).await;
+                    send_reply(&evt, format_preferences(&current), &cmd_tx);
+                });
+                return Ok(Verdict::Continue);
+            }
+        };
+        let change = match change {
+            Ok(change) => change,
+            Err(e) => {
+                send_reply(evt, e.to_string(), &self.cmd_tx);
+                return Ok(Verdict::Continue);
+            }
+        };
+
+        let preferences = self.preferences.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let sender_id = sender_id.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let reply = match preferences
+                .update(&evt.service_id, &sender_id, |current| change.apply(current))
+                .await
+            {
+                Ok(updated) => format!("Saved. {}", format_preferences(&updated)),
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to save user preferences");
+                    "Failed to save your preferences".to_string()
+                }
+            };
+            send_reply(&evt, reply, &cmd_tx);
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
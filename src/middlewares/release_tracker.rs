@@ -0,0 +1,301 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    event::Event,
+    format::BodyFormat,
+    http::HttpClient,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use crate::middlewares::update_notifier::parse_version;
+use crate::store::PersistentStore;
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+const SEEN_VERSIONS_KEY: &str = "seen_versions";
+
+/// Where a package is published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageSource {
+    CratesIo,
+    DockerHub,
+    GitHub,
+}
+
+/// A package to watch, as configured.
+#[derive(Debug, Clone)]
+pub struct TrackedPackage {
+    /// The config's name for the package, used in announcements.
+    pub name: String,
+    pub source: PackageSource,
+    /// The crate name, Docker Hub repository (`nginx` or `owner/image`) or GitHub `owner/repo`.
+    pub package: String,
+    /// Whether prereleases (e.g. `2.0.0-rc.1`) are announced too.
+    pub include_prereleases: bool,
+}
+
+/// The newest version of a package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageRelease {
+    pub version: String,
+    pub url: String,
+}
+
+/// Whether a version looks like a prerelease (`1.2.0-beta.1`). Docker tags with a suffix, like
+/// `1.27.0-alpine`, count too, since they're variants rather than the plain release.
+pub fn is_prerelease(version: &str) -> bool {
+    version.contains('-')
+}
+
+/// Whether `latest` should be announced after `previous`: a higher version, or the release of
+/// a version `previous` was a prerelease of. Versions that aren't `x.y.z` only need to differ.
+pub fn is_newer_release(latest: &str, previous: &str) -> bool {
+    match (parse_version(latest), parse_version(previous)) {
+        (Some(latest_version), Some(previous_version)) => {
+            latest_version > previous_version
+                || (latest_version == previous_version
+                    && is_prerelease(previous)
+                    && !is_prerelease(latest))
+        }
+        _ => latest != previous,
+    }
+}
+
+#[derive(Deserialize)]
+struct CrateVersions {
+    versions: Vec<CrateVersion>,
+}
+
+#[derive(Deserialize)]
+struct CrateVersion {
+    num: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// The newest version from a crates.io `crates/{name}` response. Versions are listed newest
+/// first; yanked ones are skipped.
+pub fn latest_crate_version(
+    name: &str,
+    json: &str,
+    include_prereleases: bool,
+) -> Result<Option<PackageRelease>> {
+    let krate: CrateVersions = serde_json::from_str(json)?;
+    Ok(krate
+        .versions
+        .into_iter()
+        .find(|version| !version.yanked && (include_prereleases || !is_prerelease(&version.num)))
+        .map(|version| PackageRelease {
+            url: format!("https://crates.io/crates/{name}/{}", version.num),
+            version: version.num,
+        }))
+}
+
+#[derive(Deserialize)]
+struct DockerTags {
+    results: Vec<DockerTag>,
+}
+
+#[derive(Deserialize)]
+struct DockerTag {
+    name: String,
+}
+
+/// The highest version tag from a Docker Hub `tags` response. Tags that aren't versions, like
+/// `latest`, are skipped.
+pub fn latest_docker_tag(
+    repository: &str,
+    json: &str,
+    include_prereleases: bool,
+) -> Result<Option<PackageRelease>> {
+    let tags: DockerTags = serde_json::from_str(json)?;
+    Ok(tags
+        .results
+        .into_iter()
+        .filter(|tag| include_prereleases || !is_prerelease(&tag.name))
+        .filter_map(|tag| parse_version(&tag.name).map(|version| (version, tag.name)))
+        .max_by(|(a, a_tag), (b, b_tag)| {
+            // Among equal versions, the plain release over a suffixed tag, and `1.27.0` over `1.27`
+            a.cmp(b)
+                .then_with(|| is_prerelease(b_tag).cmp(&is_prerelease(a_tag)))
+                .then_with(|| a_tag.len().cmp(&b_tag.len()))
+        })
+        .map(|(_, tag)| PackageRelease {
+            url: format!(
+                "https://hub.docker.com/r/{}/tags?name={tag}",
+                docker_repository(repository)
+            ),
+            version: tag,
+        }))
+}
+
+/// `repository` with the `library/` namespace official images live in, when it has none.
+fn docker_repository(repository: &str) -> String {
+    if repository.contains('/') { repository.to_string() } else { format!("library/{repository}") }
+}
+
+#[derive(Deserialize)]
+struct GitHubRelease {
+    tag_name: String,
+    html_url: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+}
+
+/// The newest release from a GitHub `releases` response, which lists them newest first.
+/// Releases marked as prereleases count as such whatever their tag.
+pub fn latest_github_release(
+    json: &str,
+    include_prereleases: bool,
+) -> Result<Option<PackageRelease>> {
+    let releases: Vec<GitHubRelease> = serde_json::from_str(json)?;
+    Ok(releases
+        .into_iter()
+        .find(|release| {
+            !release.draft
+                && (include_prereleases
+                    || !(release.prerelease || is_prerelease(&release.tag_name)))
+        })
+        .map(|release| PackageRelease { version: release.tag_name, url: release.html_url }))
+}
+
+/// The announcement for a new version of `name`.
+pub fn format_release(name: &str, release: &PackageRelease, previous: &str) -> String {
+    format!("📦 {name} {} is out (was {previous})\n{}", release.version, release.url)
+}
+
+pub struct ReleaseTrackerConfig {
+    pub service_id: String,
+    pub room_id: String,
+    pub packages: Vec<TrackedPackage>,
+    pub poll_interval: Duration,
+}
+
+/// Watches packages on crates.io, Docker Hub and GitHub releases and posts to a room when one
+/// has a new version. The first version seen of each package is only recorded, so adding a
+/// package doesn't announce its current release.
+pub struct ReleaseTracker {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    config: ReleaseTrackerConfig,
+    http: HttpClient,
+}
+
+impl ReleaseTracker {
+    pub fn new(ctx: MiddlewareContext, config: ReleaseTrackerConfig) -> Self {
+        Self { cmd_tx: ctx.cmd_tx, store: ctx.store, config, http: ctx.http }
+    }
+
+    async fn get(&self, url: &str) -> Result<String> {
+        let response = self
+            .http
+            .get(url)
+            // crates.io rejects requests without one
+            .header(reqwest::header::USER_AGENT, "kelvin-bot")
+            .send_cached()
+            .await
+            .with_context(|| format!("failed to send request to {url}"))?;
+        if !response.status.is_success() {
+            bail!("{url} returned error: {}", response.status);
+        }
+        Ok(response.body)
+    }
+
+    async fn fetch_latest(&self, package: &TrackedPackage) -> Result<Option<PackageRelease>> {
+        let prereleases = package.include_prereleases;
+        match package.source {
+            PackageSource::CratesIo => {
+                let url = format!("https://crates.io/api/v1/crates/{}", package.package);
+                latest_crate_version(&package.package, &self.get(&url).await?, prereleases)
+            }
+            PackageSource::DockerHub => {
+                let url = format!(
+                    "https://hub.docker.com/v2/repositories/{}/tags?page_size=100&ordering=last_updated",
+                    docker_repository(&package.package)
+                );
+                latest_docker_tag(&package.package, &self.get(&url).await?, prereleases)
+            }
+            PackageSource::GitHub => {
+                let url = format!(
+                    "https://api.github.com/repos/{}/releases?per_page=20",
+                    package.package
+                );
+                latest_github_release(&self.get(&url).await?, prereleases)
+            }
+        }
+    }
+
+    async fn check(&self, seen: &mut HashMap<String, String>) {
+        for package in &self.config.packages {
+            let release = match self.fetch_latest(package).await {
+                Ok(Some(release)) => release,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!(package=%package.name, error=%e, "failed to check for new releases");
+                    continue;
+                }
+            };
+            let Some(previous) = seen.get(&package.name) else {
+                tracing::info!(package=%package.name, version=%release.version, "tracking package");
+                seen.insert(package.name.clone(), release.version);
+                continue;
+            };
+            if !is_newer_release(&release.version, previous) {
+                continue;
+            }
+
+            tracing::info!(package=%package.name, version=%release.version, previous=%previous, "new release");
+            let command = Command::SendRoomMessage {
+                service_id: ServiceId(self.config.service_id.clone()),
+                room_id: self.config.room_id.clone(),
+                body: format_release(&package.name, &release, previous),
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            };
+            if let Err(e) = send_with_retry(&self.cmd_tx, command, &RetryPolicy::default()).await {
+                tracing::error!(package=%package.name, error=%e, "failed to announce release");
+                continue;
+            }
+            seen.insert(package.name.clone(), release.version);
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for ReleaseTracker {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            packages = self.config.packages.len(),
+            poll_interval=?self.config.poll_interval,
+            "release_tracker middleware running..."
+        );
+        let mut seen: HashMap<String, String> =
+            self.store.get(SEEN_VERSIONS_KEY).await.unwrap_or_default();
+        let mut ticker = tokio::time::interval(self.config.poll_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    self.check(&mut seen).await;
+                    if let Err(e) = self.store.set(SEEN_VERSIONS_KEY, &seen).await {
+                        tracing::warn!(error=%e, "failed to save seen versions");
+                    }
+                }
+            }
+        }
+        tracing::info!("release_tracker middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, _evt: &Arc<Event>) -> Result<Verdict> {
+        Ok(Verdict::Continue)
+    }
+}
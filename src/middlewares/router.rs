@@ -0,0 +1,256 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    correlation,
+    event::{Event, EventKind},
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    preferences::PreferenceStore,
+    service::ServiceId,
+    subscriptions::SubscriptionStore,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use regex::Regex;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// Message sent by a rule without a template of its own.
+pub const DEFAULT_TEMPLATE: &str = "{{sender}}: {{body}}";
+
+/// Where a rule sends its message.
+#[derive(Debug, Clone)]
+pub enum RouteDestination {
+    Room {
+        service_id: String,
+        room_id: String,
+    },
+    DirectMessage {
+        service_id: String,
+        user_id: String,
+    },
+    /// A DM to everyone on the service subscribed to the topic.
+    Subscribers {
+        service_id: String,
+        topic: String,
+    },
+    /// A room, mentioning everyone on the service subscribed to the topic.
+    MentionSubscribers {
+        service_id: String,
+        room_id: String,
+        topic: String,
+    },
+}
+
+/// A rule's conditions, destination and template. Conditions left as `None` match anything.
+#[derive(Debug, Clone)]
+pub struct RouteRule {
+    pub name: String,
+    pub event_kind: Option<String>,
+    pub source_service_id: Option<String>,
+    pub source_room_id: Option<String>,
+    pub sender_id: Option<String>,
+    /// Matched case-insensitively.
+    pub contains: Option<String>,
+    pub pattern: Option<Regex>,
+    pub destination: RouteDestination,
+    pub template: String,
+}
+
+impl RouteRule {
+    pub fn matches(&self, evt: &Event) -> bool {
+        let body = evt.kind.message_body();
+        self.event_kind.as_deref().is_none_or(|kind| kind == evt.kind.name())
+            && self.source_service_id.as_deref().is_none_or(|id| id == evt.service_id.0)
+            && self.source_room_id.as_deref().is_none_or(|id| Some(id) == evt.kind.room_id())
+            && self.sender_id.as_deref().is_none_or(|id| Some(id) == sender(&evt.kind).0)
+            && self.contains.as_deref().is_none_or(|needle| {
+                body.is_some_and(|body| body.to_lowercase().contains(&needle.to_lowercase()))
+            })
+            && self.pattern.as_ref().is_none_or(|re| body.is_some_and(|body| re.is_match(body)))
+    }
+
+    /// The rule's template filled in from `evt`.
+    pub fn render(&self, evt: &Event) -> String {
+        let (sender_id, sender_display_name) = sender(&evt.kind);
+        let sender_id = sender_id.unwrap_or_default();
+        self.template
+            .replace("{{body}}", evt.kind.message_body().unwrap_or_default())
+            .replace("{{sender}}", sender_display_name.unwrap_or(sender_id))
+            .replace("{{sender_id}}", sender_id)
+            .replace("{{room}}", evt.kind.room_id().unwrap_or_default())
+            .replace("{{service}}", &evt.service_id.0)
+            .replace("{{event_kind}}", evt.kind.name())
+    }
+}
+
+/// Sender ID and display name of events that have a sender. For relayed messages, that's whoever
+/// originally wrote them.
+fn sender(kind: &EventKind) -> (Option<&str>, Option<&str>) {
+    if let Some(from) = kind.relayed_from() {
+        return (Some(&from.sender_id), from.sender_display_name.as_deref());
+    }
+    match kind {
+        EventKind::DirectMessage { sender_id, sender_display_name, .. }
+        | EventKind::RoomMessage { sender_id, sender_display_name, .. }
+        | EventKind::ReactionAdded { sender_id, sender_display_name, .. }
+        | EventKind::RoomImage { sender_id, sender_display_name, .. } => {
+            (Some(sender_id), sender_display_name.as_deref())
+        }
+        EventKind::ReactionRemoved { sender_id, .. } => (Some(sender_id), None),
+        EventKind::Knock { user_id, sender_display_name, .. }
+        | EventKind::VoiceStateChanged { user_id, sender_display_name, .. } => {
+            (Some(user_id), sender_display_name.as_deref())
+        }
+        EventKind::UserProfileChanged { user } => (Some(&user.id), Some(&user.display_name)),
+        EventKind::UserListUpdate { .. }
+        | EventKind::UserListDiff { .. }
+        | EventKind::RoomUpgraded { .. }
+        | EventKind::MissedMessages { .. }
+        | EventKind::VerificationRequested { .. }
+        | EventKind::ConnectionScheduled { .. }
+        | EventKind::ServiceReady
+        | EventKind::ServiceDegraded { .. }
+        | EventKind::ServiceStopped => (None, None),
+    }
+}
+
+/// Sends a message somewhere whenever an event matches one of its configured rules, e.g.
+/// "if a message in the lobby mentions the door, DM the host". Every matching rule fires.
+///
+/// Rules can also reach everyone subscribed to a topic (`!subscribe <topic>`), by DM or by
+/// mentioning them in a room.
+///
+/// The bot's own messages never match, so a rule can't trigger itself; messages it relayed for
+/// someone else do. Users who opted out of this middleware's notifications
+/// (`!prefs optout <name>`) aren't sent DMs. Rules watching a room keep watching it after it's
+/// upgraded.
+pub struct Router {
+    cmd_tx: CommandSender,
+    preferences: PreferenceStore,
+    subscriptions: SubscriptionStore,
+    rules: Mutex<Vec<RouteRule>>,
+}
+
+impl Router {
+    pub fn new(ctx: MiddlewareContext, rules: Vec<RouteRule>) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            preferences: ctx.preferences,
+            subscriptions: ctx.subscriptions,
+            rules: Mutex::new(rules),
+        }
+    }
+
+    /// Points rules watching `old_room_id` on `service_id` at `new_room_id`.
+    fn follow_upgrade(&self, service_id: &ServiceId, old_room_id: &str, new_room_id: &str) {
+        let mut rules = self.rules.lock().unwrap();
+        for rule in rules.iter_mut().filter(|rule| {
+            rule.source_room_id.as_deref() == Some(old_room_id)
+                && rule.source_service_id.as_deref().is_none_or(|id| id == service_id.0)
+        }) {
+            tracing::info!(rule=%rule.name, old_room=%old_room_id, new_room=%new_room_id, "source room upgraded, following it");
+            rule.source_room_id = Some(new_room_id.to_string());
+        }
+    }
+
+    fn send(&self, rule: &RouteRule, body: String) {
+        let cmd_tx = self.cmd_tx.clone();
+        let preferences = self.preferences.clone();
+        let subscriptions = self.subscriptions.clone();
+        let rule_name = rule.name.clone();
+        let destination = rule.destination.clone();
+        correlation::spawn(async move {
+            let commands = match destination {
+                RouteDestination::Room { service_id, room_id } => {
+                    vec![room_message(ServiceId(service_id), room_id, body)]
+                }
+                RouteDestination::DirectMessage { service_id, user_id } => {
+                    vec![direct_message(ServiceId(service_id), user_id, &body)]
+                }
+                RouteDestination::Subscribers { service_id, topic } => {
+                    let service_id = ServiceId(service_id);
+                    subscriptions
+                        .subscribers_on(&topic, &service_id)
+                        .await
+                        .into_iter()
+                        .map(|subscriber| {
+                            direct_message(service_id.clone(), subscriber.user_id, &body)
+                        })
+                        .collect()
+                }
+                RouteDestination::MentionSubscribers { service_id, room_id, topic } => {
+                    let service_id = ServiceId(service_id);
+                    let subscribers = subscriptions.subscribers_on(&topic, &service_id).await;
+                    if subscribers.is_empty() {
+                        tracing::debug!(rule=%rule_name, topic=%topic, "nobody subscribed, not notifying");
+                        return;
+                    }
+                    let names: Vec<&str> = subscribers.iter().map(|s| s.name()).collect();
+                    vec![room_message(service_id, room_id, format!("{}: {body}", names.join(", ")))]
+                }
+            };
+            for command in commands {
+                if let Command::SendDirectMessage { service_id, user_id, .. } = &command
+                    && preferences.get(service_id, user_id).await.is_opted_out(cmd_tx.origin())
+                {
+                    tracing::debug!(rule=%rule_name, user_id=%user_id, "user opted out, not notifying");
+                    continue;
+                }
+                cmd_tx.spawn_send(command, "routed message");
+            }
+        });
+    }
+}
+
+fn room_message(service_id: ServiceId, room_id: String, body: String) -> Command {
+    Command::SendRoomMessage {
+        service_id,
+        room_id,
+        body,
+        format: BodyFormat::Plain,
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    }
+}
+
+fn direct_message(service_id: ServiceId, user_id: String, body: &str) -> Command {
+    Command::SendDirectMessage {
+        service_id,
+        user_id,
+        body: body.to_string(),
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        expires_after: None,
+        require_encryption: false,
+    }
+}
+
+#[async_trait]
+impl Middleware for Router {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        let rule_count = self.rules.lock().unwrap().len();
+        tracing::info!(rules = rule_count, "router middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("router middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        if evt.kind.is_own() {
+            return Ok(Verdict::Continue);
+        }
+        // Rules still match the upgrade event itself, e.g. to tell someone to update the config
+        for rule in self.rules.lock().unwrap().iter().filter(|rule| rule.matches(evt)) {
+            tracing::debug!(rule=%rule.name, event_kind=evt.kind.name(), "routing event");
+            self.send(rule, rule.render(evt));
+        }
+        if let EventKind::RoomUpgraded { old_room_id, new_room_id } = &evt.kind {
+            self.follow_upgrade(&evt.service_id, old_room_id, new_room_id);
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,171 @@
+use crate::core::{
+    alerts,
+    backup::{BackupOptions, create_backup},
+    config::AlertLevel,
+    event::Event,
+    http::HttpClient,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+const BACKUP_PREFIX: &str = "kelvin-backup-";
+const BACKUP_SUFFIX: &str = ".tar.gz";
+
+pub struct ScheduledBackupConfig {
+    pub data_directory: PathBuf,
+    /// Where backups are written; left out of the backups themselves.
+    pub directory: PathBuf,
+    pub interval: Duration,
+    /// How many backups are kept in `directory`, newest first.
+    pub keep: usize,
+    pub include_matrix_store: bool,
+    /// Each backup is `PUT` to this URL joined with its file name.
+    pub upload_url: Option<Url>,
+    /// Sent as a bearer token with uploads.
+    pub upload_token: Option<String>,
+}
+
+/// Backs up the data directory every `interval` with the same snapshot as `kelvin-bot backup`,
+/// keeping the newest `keep` backups and uploading each one when an upload URL is set.
+///
+/// The first backup is taken as soon as the newest one in the directory is older than the
+/// interval, so restarts don't push backups back.
+pub struct ScheduledBackup {
+    config: ScheduledBackupConfig,
+    http: HttpClient,
+}
+
+impl ScheduledBackup {
+    pub fn new(ctx: MiddlewareContext, config: ScheduledBackupConfig) -> Self {
+        Self { config, http: ctx.http }
+    }
+
+    /// Backups in the directory, oldest first. Their names hold when they were taken, so
+    /// sorting by name sorts them by age.
+    pub fn backups(&self) -> Result<Vec<PathBuf>> {
+        let mut backups = Vec::new();
+        let entries = match std::fs::read_dir(&self.config.directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let is_backup = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+                name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX)
+            });
+            if is_backup {
+                backups.push(path);
+            }
+        }
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// Takes a backup named for `now`, removes the ones past `keep`, then uploads it.
+    pub async fn back_up(&self, now: DateTime<Utc>) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.config.directory)
+            .with_context(|| format!("could not create {}", self.config.directory.display()))?;
+        let archive = self
+            .config
+            .directory
+            .join(format!("{BACKUP_PREFIX}{}{BACKUP_SUFFIX}", now.format("%Y%m%dT%H%M%SZ")));
+        let data_directory = self.config.data_directory.clone();
+        let options = BackupOptions {
+            include_matrix_store: self.config.include_matrix_store,
+            exclude: vec![self.config.directory.clone()],
+        };
+        let path = archive.clone();
+        let summary =
+            tokio::task::spawn_blocking(move || create_backup(&data_directory, &path, &options))
+                .await??;
+        tracing::info!(path=%archive.display(), files = summary.files, "backup taken");
+
+        let backups = self.backups()?;
+        for old in backups.iter().take(backups.len().saturating_sub(self.config.keep)) {
+            if let Err(e) = std::fs::remove_file(old) {
+                tracing::warn!(path=%old.display(), error=%e, "failed to remove old backup");
+            }
+        }
+
+        if let Some(upload_url) = &self.config.upload_url {
+            self.upload(upload_url, &archive).await?;
+        }
+        Ok(archive)
+    }
+
+    async fn upload(&self, upload_url: &Url, archive: &Path) -> Result<()> {
+        let file_name = archive.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let url = upload_url.join(file_name).context("invalid upload URL")?;
+        let body = tokio::fs::read(archive).await?;
+        let mut request = self
+            .http
+            .put(url.clone())
+            .header(reqwest::header::USER_AGENT, "kelvin-bot")
+            .header(reqwest::header::CONTENT_TYPE, "application/gzip")
+            .body(body);
+        if let Some(token) = &self.config.upload_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.context("failed to send backup")?;
+        if !response.status().is_success() {
+            bail!("{} answered {}", url.host_str().unwrap_or_default(), response.status());
+        }
+        tracing::info!(url=%url, "backup uploaded");
+        Ok(())
+    }
+
+    /// How long until the next backup is due, going by when the newest one was written.
+    fn next_due(&self) -> Duration {
+        let newest = self.backups().ok().and_then(|backups| backups.last().cloned());
+        let age = newest
+            .and_then(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        match age {
+            Some(age) => self.config.interval.saturating_sub(age),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for ScheduledBackup {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            directory=%self.config.directory.display(),
+            interval=?self.config.interval,
+            "scheduled_backup middleware running..."
+        );
+        let start = tokio::time::Instant::now() + self.next_due();
+        let mut ticker = tokio::time::interval_at(start, self.config.interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    if let Err(e) = self.back_up(Utc::now()).await {
+                        tracing::error!(error=?e, "scheduled backup failed");
+                        alerts::notify(
+                            AlertLevel::Warning,
+                            format!("scheduled backup failed: {e:#}"),
+                        );
+                    }
+                }
+            }
+        }
+        tracing::info!("scheduled_backup middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, _evt: &Arc<Event>) -> Result<Verdict> {
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,115 @@
+use crate::core::{
+    bus::CommandSender,
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    room_settings::{RoomSettings, RoomSettingsStore},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+// Value that unsets a setting, so the middleware's configured behavior applies again
+const DEFAULT_VALUE: &str = "default";
+
+/// Lets room moderators view and change the settings of the room they're in, e.g.
+/// `!set relay.digest on` or `!set quiet_hours 22:00-07:00`. Anyone in the room can view them.
+pub struct Settings {
+    cmd_tx: CommandSender,
+    room_settings: RoomSettingsStore,
+    router: CommandRouter,
+    moderator_user_ids: Vec<String>,
+}
+
+impl Settings {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        moderator_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Show or change this room's settings")
+            .with_args(vec![ArgSpec::optional("setting"), ArgSpec::optional_rest("value")]);
+        Self { cmd_tx: ctx.cmd_tx, room_settings: ctx.room_settings, router, moderator_user_ids }
+    }
+}
+
+/// Lists a room's settings, one per line.
+pub fn format_room_settings(settings: &RoomSettings) -> String {
+    if settings.is_empty() {
+        return "This room has no settings".to_string();
+    }
+    let lines: Vec<_> = settings.iter().map(|(name, value)| format!("{name}: {value}")).collect();
+    format!("Room settings:\n{}", lines.join("\n"))
+}
+
+#[async_trait]
+impl Middleware for Settings {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("settings middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("settings middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::RoomMessage { room_id, sender_id, is_self, .. } = &evt.kind else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self {
+            return Ok(Verdict::Continue);
+        }
+        let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+            return Ok(Verdict::Continue);
+        };
+
+        let setting = invocation.get("setting").map(str::to_string);
+        let value = invocation.get("value").map(str::to_string);
+        if value.is_some()
+            && !self.moderator_user_ids.iter().any(|moderator| moderator == sender_id)
+        {
+            tracing::info!(sender_id=%sender_id, room_id=%room_id, "refusing room setting change from non-moderator");
+            send_reply(evt, "Only room moderators can change settings".to_string(), &self.cmd_tx);
+            return Ok(Verdict::Continue);
+        }
+
+        let room_settings = self.room_settings.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let room_id = room_id.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let service_id = &evt.service_id;
+            let reply = match (setting, value) {
+                (None, _) => format_room_settings(&room_settings.all(service_id, &room_id).await),
+                (Some(setting), None) => {
+                    let setting = setting.to_ascii_lowercase();
+                    match room_settings.all(service_id, &room_id).await.get(&setting) {
+                        Some(value) => format!("{setting}: {value}"),
+                        None => format!("{setting} isn't set in this room"),
+                    }
+                }
+                (Some(setting), Some(value)) => {
+                    let value = (!value.eq_ignore_ascii_case(DEFAULT_VALUE)).then_some(value);
+                    match room_settings.set(service_id, &room_id, &setting, value.as_deref()).await
+                    {
+                        Ok(updated) => format!("Saved. {}", format_room_settings(&updated)),
+                        Err(e) => e.to_string(),
+                    }
+                }
+            };
+            send_reply(&evt, reply, &cmd_tx);
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,184 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    event::Event,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+// How long to wait for the voice service or the room's service to answer before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The topic used when none is configured.
+pub const DEFAULT_TOPIC_TEMPLATE: &str = "🎙 {{voice_count}} in voice — last relay {{last_relay}}";
+
+pub struct StatusTopicConfig {
+    /// The room whose topic is kept up to date.
+    pub service_id: ServiceId,
+    pub room_id: String,
+    /// The voice service whose connected users are counted.
+    pub voice_service_id: ServiceId,
+    /// The topic, with `{{voice_count}}` and `{{last_relay}}` filled in.
+    pub template: String,
+    /// How often the status is worked out again.
+    pub refresh_interval: Duration,
+    /// The least time between two topic changes, however often the status changes.
+    pub min_interval: Duration,
+}
+
+/// Keeps a room's topic showing live status, e.g. "🎙 4 in voice — last relay 2m ago", by
+/// sending `SetRoomTopic` commands.
+///
+/// The status is worked out every `refresh_interval`, but the topic is only changed when the
+/// text does, and at most once per `min_interval`, since every change shows up in the room's
+/// timeline. A change held back by the limit goes out on the first refresh after it lifts.
+pub struct StatusTopic {
+    cmd_tx: CommandSender,
+    config: StatusTopicConfig,
+    /// When a relayed message last came through the pipeline.
+    last_relay: Mutex<Option<Instant>>,
+}
+
+impl StatusTopic {
+    pub fn new(ctx: MiddlewareContext, config: StatusTopicConfig) -> Self {
+        Self { cmd_tx: ctx.cmd_tx, config, last_relay: Mutex::new(None) }
+    }
+
+    /// How many people are connected to the voice service, not counting the bot, or `None` if
+    /// it couldn't be asked.
+    async fn voice_count(&self) -> Option<usize> {
+        let service_id = &self.config.voice_service_id;
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let command =
+            Command::GetUserList { service_id: service_id.clone(), response_tx, origin: None };
+        if let Err(e) = self.cmd_tx.send(command).await {
+            tracing::error!(error=%e, "failed to request user list");
+            return None;
+        }
+        match tokio::time::timeout(RESPONSE_TIMEOUT, response_rx).await {
+            Ok(Ok(Ok(users))) => {
+                Some(users.iter().filter(|connected| !connected.user.is_self).count())
+            }
+            Ok(Ok(Err(e))) => {
+                tracing::warn!(service_id=%service_id, error=%e, "failed to list users");
+                None
+            }
+            Ok(Err(_)) | Err(_) => {
+                tracing::warn!(service_id=%service_id, "no answer to user list request");
+                None
+            }
+        }
+    }
+
+    /// The topic as things stand now.
+    async fn render(&self) -> String {
+        let voice_count = self.voice_count().await;
+        let last_relay = self
+            .last_relay
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .map(|at| at.elapsed());
+        render_status_topic(&self.config.template, voice_count, last_relay)
+    }
+
+    /// Sets the room's topic, returning whether the service changed it.
+    async fn set_topic(&self, topic: &str) -> bool {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let command = Command::SetRoomTopic {
+            service_id: self.config.service_id.clone(),
+            room_id: self.config.room_id.clone(),
+            topic: topic.to_string(),
+            response_tx: Some(response_tx),
+            origin: None,
+        };
+        if let Err(e) = self.cmd_tx.send(command).await {
+            tracing::error!(error=%e, "failed to request topic change");
+            return false;
+        }
+        match tokio::time::timeout(RESPONSE_TIMEOUT, response_rx).await {
+            Ok(Ok(Ok(_))) => true,
+            Ok(Ok(Err(e))) => {
+                tracing::warn!(room_id=%self.config.room_id, error=%e, "failed to set room topic");
+                false
+            }
+            Ok(Err(_)) | Err(_) => {
+                tracing::warn!(room_id=%self.config.room_id, "no answer to topic change");
+                false
+            }
+        }
+    }
+}
+
+/// Fills in `template`'s `{{voice_count}}`, or `?` if it isn't known, and `{{last_relay}}`, how
+/// long ago the last relayed message was (see `format_ago`), or `never`.
+pub fn render_status_topic(
+    template: &str,
+    voice_count: Option<usize>,
+    last_relay: Option<Duration>,
+) -> String {
+    let voice_count = voice_count.map_or_else(|| "?".to_string(), |count| count.to_string());
+    let last_relay = last_relay.map_or_else(|| "never".to_string(), format_ago);
+    template.replace("{{voice_count}}", &voice_count).replace("{{last_relay}}", &last_relay)
+}
+
+/// A rough age to the largest whole unit, e.g. `2m ago` or `3h ago`. Anything under a minute
+/// is `just now`, so the topic doesn't change every refresh.
+pub fn format_ago(elapsed: Duration) -> String {
+    let minutes = elapsed.as_secs() / 60;
+    match minutes {
+        0 => "just now".to_string(),
+        1..60 => format!("{minutes}m ago"),
+        60..1440 => format!("{}h ago", minutes / 60),
+        _ => format!("{}d ago", minutes / 1440),
+    }
+}
+
+#[async_trait]
+impl Middleware for StatusTopic {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            service_id=%self.config.service_id,
+            room_id=%self.config.room_id,
+            "status_topic middleware running..."
+        );
+        let mut ticker = tokio::time::interval(self.config.refresh_interval);
+        let mut current: Option<String> = None;
+        let mut last_set: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    let topic = self.render().await;
+                    if current.as_ref() == Some(&topic) {
+                        continue;
+                    }
+                    if last_set.is_some_and(|at| at.elapsed() < self.config.min_interval) {
+                        tracing::debug!("holding back topic change until the rate limit lifts");
+                        continue;
+                    }
+                    if self.set_topic(&topic).await {
+                        current = Some(topic);
+                        last_set = Some(Instant::now());
+                    }
+                }
+            }
+        }
+        tracing::info!("status_topic middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        if evt.kind.relayed_from().is_some() {
+            *self.last_relay.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                Some(Instant::now());
+        }
+        Ok(Verdict::Continue)
+    }
+}
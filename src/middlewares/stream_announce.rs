@@ -0,0 +1,406 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    event::Event,
+    format::BodyFormat,
+    http::HttpClient,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use crate::store::PersistentStore;
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+const ANNOUNCED_KEY: &str = "announced";
+
+// Twitch takes at most this many logins per streams request
+const TWITCH_LOGINS_PER_REQUEST: usize = 100;
+
+/// Where a channel streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamPlatform {
+    Twitch,
+    YouTube,
+}
+
+/// A channel to watch, as configured.
+#[derive(Debug, Clone)]
+pub struct StreamChannel {
+    /// The config's name for the channel, which keys its announcement history.
+    pub name: String,
+    pub platform: StreamPlatform,
+    /// The Twitch login, or the YouTube channel ID (`UC...`).
+    pub channel: String,
+}
+
+/// A live stream found by a poll.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveStream {
+    /// The platform's ID for this broadcast, which changes each time the channel goes live.
+    pub id: String,
+    pub streamer: String,
+    pub title: String,
+    pub url: String,
+}
+
+/// The last broadcast announced for a channel, and when it was last seen live.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announced {
+    pub stream_id: String,
+    pub last_live: DateTime<Utc>,
+}
+
+/// Decides which live streams get announced. A broadcast is announced once, and a new one isn't
+/// announced while the channel was live within `renotify_after`, so a stream that drops and
+/// restarts (getting a new ID) doesn't post again.
+#[derive(Debug, Clone, Default)]
+pub struct AnnouncementLog {
+    announced: HashMap<String, Announced>,
+}
+
+impl AnnouncementLog {
+    pub fn new(announced: HashMap<String, Announced>) -> Self {
+        Self { announced }
+    }
+
+    /// Records `stream` as live on `channel` at `now`, returning whether to announce it.
+    pub fn observe(
+        &mut self,
+        channel: &str,
+        stream: &LiveStream,
+        now: DateTime<Utc>,
+        renotify_after: Duration,
+    ) -> bool {
+        let announce = match self.announced.get(channel) {
+            None => true,
+            Some(previous) => {
+                previous.stream_id != stream.id
+                    && (now - previous.last_live).to_std().unwrap_or_default() >= renotify_after
+            }
+        };
+        let entry = self
+            .announced
+            .entry(channel.to_string())
+            .or_insert_with(|| Announced { stream_id: stream.id.clone(), last_live: now });
+        entry.last_live = now;
+        if announce {
+            entry.stream_id = stream.id.clone();
+        }
+        announce
+    }
+
+    pub fn announced(&self) -> &HashMap<String, Announced> {
+        &self.announced
+    }
+}
+
+/// The announcement posted when `stream` goes live.
+pub fn format_announcement(stream: &LiveStream) -> String {
+    let title = stream.title.trim();
+    if title.is_empty() {
+        format!("🔴 {} just went live\n{}", stream.streamer, stream.url)
+    } else {
+        format!("🔴 {} just went live: {title}\n{}", stream.streamer, stream.url)
+    }
+}
+
+#[derive(Deserialize)]
+struct TwitchStreams {
+    data: Vec<TwitchStream>,
+}
+
+#[derive(Deserialize)]
+struct TwitchStream {
+    id: String,
+    user_login: String,
+    user_name: String,
+    #[serde(default)]
+    title: String,
+    #[serde(rename = "type", default)]
+    kind: String,
+}
+
+/// Live streams from a Helix `streams` response, keyed by lowercase login.
+pub fn parse_twitch_streams(json: &str) -> Result<HashMap<String, LiveStream>> {
+    let streams: TwitchStreams = serde_json::from_str(json)?;
+    Ok(streams
+        .data
+        .into_iter()
+        .filter(|stream| stream.kind == "live")
+        .map(|stream| {
+            let login = stream.user_login.to_lowercase();
+            let live = LiveStream {
+                id: stream.id,
+                streamer: stream.user_name,
+                title: stream.title,
+                url: format!("https://www.twitch.tv/{login}"),
+            };
+            (login, live)
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct YouTubeSearch {
+    #[serde(default)]
+    items: Vec<YouTubeItem>,
+}
+
+#[derive(Deserialize)]
+struct YouTubeItem {
+    id: YouTubeVideoId,
+    snippet: YouTubeSnippet,
+}
+
+#[derive(Deserialize)]
+struct YouTubeVideoId {
+    #[serde(rename = "videoId")]
+    video_id: String,
+}
+
+#[derive(Deserialize)]
+struct YouTubeSnippet {
+    #[serde(default)]
+    title: String,
+    #[serde(rename = "channelTitle")]
+    channel_title: String,
+}
+
+/// The channel's live broadcast from a Data API `search` response for live videos, if any.
+pub fn parse_youtube_live(json: &str) -> Result<Option<LiveStream>> {
+    let search: YouTubeSearch = serde_json::from_str(json)?;
+    Ok(search.items.into_iter().next().map(|item| LiveStream {
+        url: format!("https://www.youtube.com/watch?v={}", item.id.video_id),
+        id: item.id.video_id,
+        streamer: item.snippet.channel_title,
+        title: item.snippet.title,
+    }))
+}
+
+#[derive(Deserialize)]
+struct TwitchToken {
+    access_token: String,
+}
+
+/// Twitch app credentials, from the Twitch developer console.
+#[derive(Debug, Clone)]
+pub struct TwitchCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+pub struct StreamAnnounceConfig {
+    pub service_id: String,
+    pub room_id: String,
+    pub channels: Vec<StreamChannel>,
+    pub twitch: Option<TwitchCredentials>,
+    pub youtube_api_key: Option<String>,
+    pub poll_interval: Duration,
+    pub renotify_after: Duration,
+}
+
+/// Polls Twitch and YouTube for configured channels and posts to a room when one goes live.
+pub struct StreamAnnounce {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    config: StreamAnnounceConfig,
+    http: HttpClient,
+    // App access token for Helix, fetched on first use and again when Twitch rejects it
+    twitch_token: Mutex<Option<String>>,
+}
+
+impl StreamAnnounce {
+    pub fn new(ctx: MiddlewareContext, config: StreamAnnounceConfig) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            config,
+            http: ctx.http,
+            twitch_token: Mutex::new(None),
+        }
+    }
+
+    async fn fetch_twitch_token(&self, credentials: &TwitchCredentials) -> Result<String> {
+        let cached = self.twitch_token.lock().unwrap().clone();
+        if let Some(token) = cached {
+            return Ok(token);
+        }
+        let response = self
+            .http
+            .post("https://id.twitch.tv/oauth2/token")
+            .form(&[
+                ("client_id", credentials.client_id.as_str()),
+                ("client_secret", credentials.client_secret.as_str()),
+                ("grant_type", "client_credentials"),
+            ])
+            .send()
+            .await
+            .context("failed to send request to Twitch")?;
+        if !response.status().is_success() {
+            bail!("Twitch token request returned error: {}", response.status());
+        }
+        let token: TwitchToken =
+            response.json().await.context("failed to parse Twitch token response")?;
+        *self.twitch_token.lock().unwrap() = Some(token.access_token.clone());
+        Ok(token.access_token)
+    }
+
+    /// Live streams of the given Twitch logins, keyed by lowercase login.
+    async fn fetch_twitch(
+        &self,
+        credentials: &TwitchCredentials,
+        logins: &[&str],
+    ) -> Result<HashMap<String, LiveStream>> {
+        let mut live = HashMap::new();
+        for logins in logins.chunks(TWITCH_LOGINS_PER_REQUEST) {
+            let token = self.fetch_twitch_token(credentials).await?;
+            let query: Vec<(&str, &str)> =
+                logins.iter().map(|login| ("user_login", *login)).collect();
+            let response = self
+                .http
+                .get("https://api.twitch.tv/helix/streams")
+                .query(&query)
+                .header("Client-Id", &credentials.client_id)
+                .bearer_auth(token)
+                .send()
+                .await
+                .context("failed to send request to Twitch")?;
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                // Expired; the next poll fetches a new one
+                self.twitch_token.lock().unwrap().take();
+            }
+            if !response.status().is_success() {
+                bail!("Twitch API returned error: {}", response.status());
+            }
+            live.extend(parse_twitch_streams(&response.text().await?)?);
+        }
+        Ok(live)
+    }
+
+    async fn fetch_youtube(&self, api_key: &str, channel_id: &str) -> Result<Option<LiveStream>> {
+        let response = self
+            .http
+            .get("https://www.googleapis.com/youtube/v3/search")
+            .query(&[
+                ("part", "snippet"),
+                ("channelId", channel_id),
+                ("eventType", "live"),
+                ("type", "video"),
+                ("key", api_key),
+            ])
+            .send()
+            .await
+            .context("failed to send request to YouTube")?;
+        if !response.status().is_success() {
+            bail!("YouTube API returned error: {}", response.status());
+        }
+        parse_youtube_live(&response.text().await?)
+    }
+
+    /// The live stream of each channel that could be checked; channels whose check failed are
+    /// left out rather than treated as offline.
+    async fn poll(&self) -> Vec<(&StreamChannel, LiveStream)> {
+        let mut live = Vec::new();
+
+        let twitch: Vec<&StreamChannel> = self
+            .config
+            .channels
+            .iter()
+            .filter(|channel| channel.platform == StreamPlatform::Twitch)
+            .collect();
+        if let Some(credentials) = &self.config.twitch
+            && !twitch.is_empty()
+        {
+            let logins: Vec<&str> = twitch.iter().map(|channel| channel.channel.as_str()).collect();
+            match self.fetch_twitch(credentials, &logins).await {
+                Ok(mut streams) => live.extend(twitch.into_iter().filter_map(|channel| {
+                    streams.remove(&channel.channel.to_lowercase()).map(|stream| (channel, stream))
+                })),
+                Err(e) => tracing::warn!(error=%e, "failed to check Twitch streams"),
+            }
+        }
+
+        if let Some(api_key) = &self.config.youtube_api_key {
+            for channel in &self.config.channels {
+                if channel.platform != StreamPlatform::YouTube {
+                    continue;
+                }
+                match self.fetch_youtube(api_key, &channel.channel).await {
+                    Ok(Some(stream)) => live.push((channel, stream)),
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::warn!(channel=%channel.name, error=%e, "failed to check YouTube channel")
+                    }
+                }
+            }
+        }
+        live
+    }
+
+    async fn announce(&self, stream: &LiveStream) {
+        let command = Command::SendRoomMessage {
+            service_id: ServiceId(self.config.service_id.clone()),
+            room_id: self.config.room_id.clone(),
+            body: format_announcement(stream),
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: Some(format!("stream_announce:{}", stream.id)),
+            relayed_from: None,
+            expires_after: None,
+        };
+        if let Err(e) = send_with_retry(&self.cmd_tx, command, &RetryPolicy::default()).await {
+            tracing::error!(streamer=%stream.streamer, error=%e, "failed to announce live stream");
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for StreamAnnounce {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            channels = self.config.channels.len(),
+            poll_interval=?self.config.poll_interval,
+            "stream_announce middleware running..."
+        );
+        // Kept across restarts, so a stream that's live when the bot starts isn't announced
+        // again
+        let mut log = AnnouncementLog::new(self.store.get(ANNOUNCED_KEY).await.unwrap_or_default());
+        let mut ticker = tokio::time::interval(self.config.poll_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    let now = Utc::now();
+                    let live = self.poll().await;
+                    for (channel, stream) in &live {
+                        if log.observe(&channel.name, stream, now, self.config.renotify_after) {
+                            tracing::info!(channel=%channel.name, stream_id=%stream.id, "channel went live");
+                            self.announce(stream).await;
+                        }
+                    }
+                    // Saved while anyone is live, so a restart mid-stream remembers when each
+                    // channel was last seen
+                    if !live.is_empty()
+                        && let Err(e) = self.store.set(ANNOUNCED_KEY, log.announced()).await
+                    {
+                        tracing::warn!(error=%e, "failed to save announced streams");
+                    }
+                }
+            }
+        }
+        tracing::info!("stream_announce middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, _evt: &Arc<Event>) -> Result<Verdict> {
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::core::{
+    bus::CommandSender,
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    subscriptions::{SubscriptionStore, parse_topic},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Lets users subscribe to topics, e.g. `!subscribe announcements`, and leave them with
+/// `!unsubscribe announcements`. `!subscribe` on its own lists their subscriptions and the
+/// topics there are. Other middlewares reach a topic's subscribers through
+/// `MiddlewareContext::subscriptions`, e.g. a router rule with a `topic`.
+///
+/// Works in rooms and DMs. Subscriptions are per service, like user IDs.
+pub struct Subscriptions {
+    cmd_tx: CommandSender,
+    subscriptions: SubscriptionStore,
+    subscribe_router: CommandRouter,
+    unsubscribe_router: CommandRouter,
+    /// Topics users may subscribe to, or `None` for any.
+    topics: Option<Vec<String>>,
+}
+
+impl Subscriptions {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        unsubscribe_command_string: String,
+        topics: Option<Vec<String>>,
+    ) -> Self {
+        let subscribe_router = CommandRouter::new(command_string)
+            .with_description("Subscribe to a topic, or list your subscriptions")
+            .with_args(vec![ArgSpec::optional_rest("topic")]);
+        let unsubscribe_router = CommandRouter::new(unsubscribe_command_string)
+            .with_description("Unsubscribe from a topic")
+            .with_args(vec![ArgSpec::rest("topic")]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            subscriptions: ctx.subscriptions,
+            subscribe_router,
+            unsubscribe_router,
+            topics,
+        }
+    }
+
+    /// Checks `topic`, returning it lowercased, or the reply explaining what's wrong with it.
+    fn check_topic(&self, topic: &str) -> Result<String, String> {
+        let topic = parse_topic(topic).map_err(|e| e.to_string())?;
+        match &self.topics {
+            Some(topics) if !topics.contains(&topic) => {
+                Err(format!("Unknown topic '{topic}'. Topics: {}", topics.join(", ")))
+            }
+            _ => Ok(topic),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        evt: &Arc<Event>,
+        sender_id: &str,
+        display_name: Option<&str>,
+        topic: &str,
+    ) {
+        if topic.is_empty() {
+            self.list(evt, sender_id);
+            return;
+        }
+        let topic = match self.check_topic(topic) {
+            Ok(topic) => topic,
+            Err(reply) => {
+                send_reply(evt, reply, &self.cmd_tx);
+                return;
+            }
+        };
+        let subscriptions = self.subscriptions.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        let sender_id = sender_id.to_string();
+        let display_name = display_name.map(str::to_string);
+        let unsubscribe = self.unsubscribe_router.prefix_on(&evt.service_id);
+        correlation::spawn(async move {
+            let result = subscriptions
+                .subscribe(&topic, &evt.service_id, &sender_id, display_name.as_deref())
+                .await;
+            let reply = match result {
+                Ok(true) => {
+                    tracing::info!(user_id=%sender_id, topic=%topic, "subscribed");
+                    format!("Subscribed to {topic}. Leave with {unsubscribe} {topic}")
+                }
+                Ok(false) => format!("You're already subscribed to {topic}"),
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to save subscription");
+                    format!("Couldn't subscribe you to {topic}: {e}")
+                }
+            };
+            send_reply(&evt, reply, &cmd_tx);
+        });
+    }
+
+    fn unsubscribe(&self, evt: &Arc<Event>, sender_id: &str, topic: &str) {
+        let topic = match parse_topic(topic) {
+            Ok(topic) => topic,
+            Err(e) => {
+                send_reply(evt, e.to_string(), &self.cmd_tx);
+                return;
+            }
+        };
+        let subscriptions = self.subscriptions.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        let sender_id = sender_id.to_string();
+        correlation::spawn(async move {
+            let reply = match subscriptions.unsubscribe(&topic, &evt.service_id, &sender_id).await {
+                Ok(true) => {
+                    tracing::info!(user_id=%sender_id, topic=%topic, "unsubscribed");
+                    format!("Unsubscribed from {topic}")
+                }
+                Ok(false) => format!("You're not subscribed to {topic}"),
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to save subscription");
+                    format!("Couldn't unsubscribe you from {topic}: {e}")
+                }
+            };
+            send_reply(&evt, reply, &cmd_tx);
+        });
+    }
+
+    /// Replies with the sender's subscriptions and the topics they could subscribe to.
+    fn list(&self, evt: &Arc<Event>, sender_id: &str) {
+        let subscriptions = self.subscriptions.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        let sender_id = sender_id.to_string();
+        let allowed = self.topics.clone();
+        let subscribe = self.subscribe_router.prefix_on(&evt.service_id);
+        correlation::spawn(async move {
+            let mine = subscriptions.topics_of(&evt.service_id, &sender_id).await;
+            let available: Vec<String> = match allowed {
+                Some(topics) => topics,
+                None => subscriptions
+                    .topic_counts()
+                    .await
+                    .into_iter()
+                    .map(|(topic, count)| format!("{topic} ({count})"))
+                    .collect(),
+            };
+            let mut reply = if mine.is_empty() {
+                "You're not subscribed to anything".to_string()
+            } else {
+                format!("You're subscribed to: {}", mine.join(", "))
+            };
+            if !available.is_empty() {
+                reply.push_str(&format!("\nTopics: {}", available.join(", ")));
+            }
+            reply.push_str(&format!("\nUsage: {subscribe} <topic>"));
+            send_reply(&evt, reply, &cmd_tx);
+        });
+    }
+}
+
+#[async_trait]
+impl Middleware for Subscriptions {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("subscriptions middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("subscriptions middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.subscribe_router.prefix(), self.unsubscribe_router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.subscribe_router.spec(), self.unsubscribe_router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let (EventKind::RoomMessage {
+            body, sender_id, sender_display_name, is_self: false, ..
+        }
+        | EventKind::DirectMessage {
+            body, sender_id, sender_display_name, is_self: false, ..
+        }) = &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if self.subscribe_router.matches(body) {
+            if let Some(invocation) = self.subscribe_router.route(evt, &self.cmd_tx) {
+                let topic = invocation.get("topic").unwrap_or_default().trim();
+                self.subscribe(evt, sender_id, sender_display_name.as_deref(), topic);
+            }
+        } else if self.unsubscribe_router.matches(body)
+            && let Some(invocation) = self.unsubscribe_router.route(evt, &self.cmd_tx)
+        {
+            self.unsubscribe(evt, sender_id, invocation.arg("topic"));
+        }
+        Ok(Verdict::Continue)
+    }
+}
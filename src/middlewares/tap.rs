@@ -0,0 +1,230 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    redact,
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+// How long a tap runs when no duration is given, and the longest one may run
+const DEFAULT_DURATION: Duration = Duration::from_secs(5 * 60);
+const MAX_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// How often tapped events are sent on, unless set with `with_flush_interval`.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Events shown per DM; the rest of a busy interval's events are only counted
+const MAX_EVENTS_PER_FLUSH: usize = 10;
+
+/// One admin watching one service's events.
+struct ActiveTap {
+    service_id: ServiceId,
+    user_id: String,
+    until: Instant,
+    // Redacted events waiting for the next flush, and how many more were left out
+    pending: Vec<String>,
+    skipped: usize,
+}
+
+/// Lets admins watch the events a service receives, e.g. `!tap on 5m` streams a redacted copy
+/// of every event from the service the command was sent on to the admin's DM for five minutes.
+///
+/// Events are sent in batches, a sample of at most 10 every few seconds, so a busy service
+/// can't flood the DM. Only events that reach this middleware are seen, so it belongs first
+/// in `GLOBAL_MIDDLEWARE`. The bot's own events are left out, which also keeps the DMs from
+/// tapping themselves.
+pub struct Tap {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    admin_user_ids: Vec<String>,
+    flush_interval: Duration,
+    taps: Mutex<Vec<ActiveTap>>,
+}
+
+impl Tap {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        admin_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Stream a redacted copy of a service's events to your DM")
+            .with_subcommand("on", vec![ArgSpec::optional("duration")])
+            .with_subcommand("off", vec![]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            router,
+            admin_user_ids,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            taps: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Starts or stops `user_id`'s tap on the event's service, returning the reply.
+    fn command(
+        &self,
+        evt: &Event,
+        user_id: &str,
+        subcommand: &str,
+        duration: Option<&str>,
+    ) -> String {
+        let mut taps = self.taps.lock().unwrap();
+        let existing =
+            taps.iter().position(|tap| tap.service_id == evt.service_id && tap.user_id == user_id);
+        if subcommand == "off" {
+            return match existing {
+                Some(index) => {
+                    taps.remove(index);
+                    tracing::info!(service_id=%evt.service_id, user_id=%user_id, "event tap stopped");
+                    "Tap stopped".to_string()
+                }
+                None => "No tap is running".to_string(),
+            };
+        }
+
+        let duration = match duration.map(humantime::parse_duration) {
+            None => DEFAULT_DURATION,
+            Some(Ok(duration)) if !duration.is_zero() => duration.min(MAX_DURATION),
+            Some(_) => return "Duration must look like 30s, 5m or 1h".to_string(),
+        };
+        let tap = ActiveTap {
+            service_id: evt.service_id.clone(),
+            user_id: user_id.to_string(),
+            until: Instant::now() + duration,
+            pending: Vec::new(),
+            skipped: 0,
+        };
+        match existing {
+            Some(index) => taps[index] = tap,
+            None => taps.push(tap),
+        }
+        tracing::info!(service_id=%evt.service_id, user_id=%user_id, duration=?duration, "event tap started");
+        format!(
+            "Tapping events from {} for {}. Stop with `{} off`",
+            evt.service_id,
+            humantime::format_duration(duration),
+            self.router.prefix_on(&evt.service_id)
+        )
+    }
+
+    /// Sends each tap its pending events, and ends the taps that have run out.
+    async fn flush(&self) {
+        let now = Instant::now();
+        let mut messages = Vec::new();
+        {
+            let mut taps = self.taps.lock().unwrap();
+            for tap in taps.iter_mut() {
+                if tap.pending.is_empty() && tap.skipped == 0 {
+                    continue;
+                }
+                let mut lines = vec![format!("[tap] {}:", tap.service_id)];
+                lines.append(&mut tap.pending);
+                if tap.skipped > 0 {
+                    lines.push(format!("(+{} more not shown)", std::mem::take(&mut tap.skipped)));
+                }
+                messages.push((tap.service_id.clone(), tap.user_id.clone(), lines.join("\n")));
+            }
+            taps.retain(|tap| {
+                let running = tap.until > now;
+                if !running {
+                    let body = format!("[tap] {}: tap ended", tap.service_id);
+                    messages.push((tap.service_id.clone(), tap.user_id.clone(), body));
+                }
+                running
+            });
+        }
+
+        for (service_id, user_id, body) in messages {
+            let command = Command::SendDirectMessage {
+                service_id,
+                user_id,
+                body,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            };
+            if let Err(e) = self.cmd_tx.send(command).await {
+                tracing::error!(error=%e, "failed to send tapped events");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Tap {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("tap middleware running...");
+        let mut ticker = tokio::time::interval(self.flush_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => self.flush().await,
+            }
+        }
+        tracing::info!("tap middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        if evt.kind.is_own() {
+            return Ok(Verdict::Continue);
+        }
+
+        if let EventKind::DirectMessage { body, user_id, sender_id, .. } = &evt.kind
+            && self.router.matches(body)
+        {
+            // Checked before parsing so non-admins don't even get usage replies
+            if !self.admin_user_ids.iter().any(|admin| admin == sender_id) {
+                tracing::info!(sender_id=%sender_id, "ignoring tap command from non-admin");
+                return Ok(Verdict::Continue);
+            }
+            if let Some(invocation) = self.router.route(evt, &self.cmd_tx)
+                && let Some(subcommand) = invocation.subcommand
+            {
+                let reply = self.command(evt, user_id, subcommand, invocation.get("duration"));
+                send_reply(evt, reply, &self.cmd_tx);
+            }
+            return Ok(Verdict::Continue);
+        }
+
+        let mut taps = self.taps.lock().unwrap();
+        let now = Instant::now();
+        let mut watching =
+            taps.iter_mut().filter(|tap| tap.service_id == evt.service_id && tap.until > now);
+        let Some(first) = watching.next() else {
+            return Ok(Verdict::Continue);
+        };
+        let line = redact::redact(&serde_json::to_string(&evt.kind)?);
+        for tap in std::iter::once(first).chain(watching) {
+            if tap.pending.len() < MAX_EVENTS_PER_FLUSH {
+                tap.pending.push(line.clone());
+            } else {
+                tap.skipped += 1;
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -0,0 +1,301 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    format::BodyFormat,
+    http::HttpClient,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+};
+use crate::store::PersistentStore;
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+const THREADS_KEY: &str = "threads";
+
+/// The forge hosting a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    /// A Gitea (or Forgejo) instance at `base_url`, e.g. `https://git.example.com`.
+    Gitea {
+        base_url: String,
+    },
+}
+
+/// A repository issues are filed in, and the room they're filed from.
+#[derive(Debug, Clone)]
+pub struct TicketRepo {
+    pub forge: Forge,
+    /// `owner/name`.
+    pub repository: String,
+    /// API token allowed to create issues and comments.
+    pub token: String,
+    pub room_id: String,
+}
+
+impl TicketRepo {
+    fn api_url(&self, path: &str) -> String {
+        match &self.forge {
+            Forge::GitHub => format!("https://api.github.com/repos/{}/{path}", self.repository),
+            Forge::Gitea { base_url } => format!(
+                "{}/api/v1/repos/{}/{path}",
+                base_url.trim_end_matches('/'),
+                self.repository
+            ),
+        }
+    }
+
+    fn authorization(&self) -> String {
+        match self.forge {
+            Forge::GitHub => format!("Bearer {}", self.token),
+            Forge::Gitea { .. } => format!("token {}", self.token),
+        }
+    }
+}
+
+/// An issue created from chat; GitHub and Gitea answer with the same fields.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CreatedIssue {
+    pub number: u64,
+    pub html_url: String,
+}
+
+/// The issue a chat thread feeds comments into.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct IssueThread {
+    room_id: String,
+    number: u64,
+}
+
+/// The comment posted for a thread reply from `sender`.
+pub fn format_comment(sender: &str, body: &str) -> String {
+    format!("**{sender}** wrote in chat:\n\n{body}")
+}
+
+/// Files issues from chat: `!issue <title>` in a room with a repository creates an issue there
+/// and replies with its link, starting a thread. Replies in that thread are added to the issue
+/// as comments.
+pub struct TicketBridge {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    router: CommandRouter,
+    repos: Vec<TicketRepo>,
+    /// Who may file issues and comment through the bot; everyone when empty.
+    allowed_user_ids: Vec<String>,
+    http: HttpClient,
+    // Thread root message ID -> issue, kept in the store so threads outlive restarts
+    threads: Arc<Mutex<HashMap<String, IssueThread>>>,
+}
+
+impl TicketBridge {
+    pub fn new(
+        ctx: MiddlewareContext,
+        command_string: String,
+        repos: Vec<TicketRepo>,
+        allowed_user_ids: Vec<String>,
+    ) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("File an issue in this room's repository")
+            .with_args(vec![ArgSpec::rest("title")]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            router,
+            repos,
+            allowed_user_ids,
+            http: ctx.http,
+            threads: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn is_allowed(&self, sender_id: &str) -> bool {
+        self.allowed_user_ids.is_empty() || self.allowed_user_ids.iter().any(|id| id == sender_id)
+    }
+
+    fn repo_for_room(&self, room_id: &str) -> Option<&TicketRepo> {
+        self.repos.iter().find(|repo| repo.room_id == room_id)
+    }
+
+    fn file_issue(&self, evt: &Arc<Event>, repo: TicketRepo, title: String, sender: String) {
+        let evt = evt.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let http = self.http.clone();
+        let store = self.store.clone();
+        let threads = self.threads.clone();
+        correlation::spawn(async move {
+            let body = format!("Filed from chat by {sender}.");
+            let issue = match create_issue(&http, &repo, &title, &body).await {
+                Ok(issue) => issue,
+                Err(e) => {
+                    tracing::warn!(repository=%repo.repository, error=%e, "failed to create issue");
+                    send_reply(&evt, format!("Couldn't create the issue: {e}"), &cmd_tx);
+                    return;
+                }
+            };
+            tracing::info!(repository=%repo.repository, number=issue.number, "issue created from chat");
+
+            let command = Command::SendRoomMessage {
+                service_id: evt.service_id.clone(),
+                room_id: repo.room_id.clone(),
+                body: format!(
+                    "Opened #{}: {}\nReply in this thread to add comments",
+                    issue.number, issue.html_url
+                ),
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            };
+            let root_id = match send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+                Ok(root_id) if !root_id.is_empty() => root_id,
+                Ok(_) => return,
+                Err(e) => {
+                    tracing::error!(error=%e, "failed to send issue link");
+                    return;
+                }
+            };
+            let snapshot = {
+                let mut threads = threads.lock().unwrap();
+                threads
+                    .insert(root_id, IssueThread { room_id: repo.room_id, number: issue.number });
+                threads.clone()
+            };
+            if let Err(e) = store.set(THREADS_KEY, &snapshot).await {
+                tracing::warn!(error=%e, "failed to save issue threads");
+            }
+        });
+    }
+
+    fn add_comment(&self, evt: &Arc<Event>, repo: TicketRepo, number: u64, comment: String) {
+        let evt = evt.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let http = self.http.clone();
+        correlation::spawn(async move {
+            if let Err(e) = create_comment(&http, &repo, number, &comment).await {
+                tracing::warn!(repository=%repo.repository, number, error=%e, "failed to add issue comment");
+                send_reply(&evt, format!("Couldn't add that to #{number}: {e}"), &cmd_tx);
+            }
+        });
+    }
+}
+
+async fn post_json(
+    http: &HttpClient,
+    repo: &TicketRepo,
+    path: &str,
+    body: serde_json::Value,
+) -> Result<reqwest::Response> {
+    let response = http
+        .post(repo.api_url(path))
+        .header(reqwest::header::USER_AGENT, "kelvin-bot")
+        .header(reqwest::header::AUTHORIZATION, repo.authorization())
+        .json(&body)
+        .send()
+        .await
+        .context("failed to send request")?;
+    if !response.status().is_success() {
+        bail!("{} answered {}", repo.repository, response.status());
+    }
+    Ok(response)
+}
+
+async fn create_issue(
+    http: &HttpClient,
+    repo: &TicketRepo,
+    title: &str,
+    body: &str,
+) -> Result<CreatedIssue> {
+    let response =
+        post_json(http, repo, "issues", serde_json::json!({ "title": title, "body": body }))
+            .await?;
+    response.json().await.context("failed to parse the created issue")
+}
+
+async fn create_comment(
+    http: &HttpClient,
+    repo: &TicketRepo,
+    number: u64,
+    body: &str,
+) -> Result<()> {
+    let path = format!("issues/{number}/comments");
+    post_json(http, repo, &path, serde_json::json!({ "body": body })).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Middleware for TicketBridge {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        let saved: HashMap<String, IssueThread> =
+            self.store.get(THREADS_KEY).await.unwrap_or_default();
+        self.threads.lock().unwrap().extend(saved);
+        tracing::info!(repositories = self.repos.len(), "ticket_bridge middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("ticket_bridge middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::RoomMessage {
+            room_id,
+            body,
+            sender_id,
+            sender_display_name,
+            is_self: false,
+            thread_root_id,
+            ..
+        } = &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        let sender = sender_display_name.as_deref().unwrap_or(sender_id).to_string();
+
+        if self.router.matches(body) {
+            if !self.is_allowed(sender_id) {
+                send_reply(evt, "You're not allowed to file issues".to_string(), &self.cmd_tx);
+                return Ok(Verdict::Continue);
+            }
+            let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+                return Ok(Verdict::Continue);
+            };
+            let Some(repo) = self.repo_for_room(room_id) else {
+                send_reply(evt, "No repository is set up for this room".to_string(), &self.cmd_tx);
+                return Ok(Verdict::Continue);
+            };
+            let title = invocation.get("title").unwrap_or_default().to_string();
+            self.file_issue(evt, repo.clone(), title, sender);
+            return Ok(Verdict::Continue);
+        }
+
+        let Some(root_id) = thread_root_id else {
+            return Ok(Verdict::Continue);
+        };
+        let Some(thread) = self.threads.lock().unwrap().get(root_id).cloned() else {
+            return Ok(Verdict::Continue);
+        };
+        if thread.room_id != *room_id || !self.is_allowed(sender_id) {
+            return Ok(Verdict::Continue);
+        }
+        let Some(repo) = self.repo_for_room(room_id) else {
+            return Ok(Verdict::Continue);
+        };
+        self.add_comment(evt, repo.clone(), thread.number, format_comment(&sender, body));
+        Ok(Verdict::Continue)
+    }
+}
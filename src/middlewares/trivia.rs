@@ -0,0 +1,573 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    commands::{ArgSpec, CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    format::BodyFormat,
+    http::HttpClient,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    quiet_hours::{QuietHours, defer_past},
+    scheduler::SchedulerHandle,
+    service::ServiceId,
+    time_zone::next_weekly,
+};
+use crate::store::PersistentStore;
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Tz;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+const SCORES_KEY: &str = "scores";
+
+const OPEN_TRIVIA_DB_URL: &str = "https://opentdb.com/api.php";
+
+// Most questions a round can have; Open Trivia DB hands out at most 50 at a time
+pub const MAX_ROUND_QUESTIONS: usize = 50;
+
+// Breather between one question's answer and the next question
+const QUESTION_PAUSE: Duration = Duration::from_secs(3);
+
+const LEADERBOARD_SIZE: usize = 10;
+
+/// A quiz question. Free text unless it has `choices`, in which case the choice's letter is
+/// accepted too.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Question {
+    pub question: String,
+    pub answer: String,
+    /// Other answers counted as correct, e.g. `NYC` for `New York City`.
+    #[serde(default)]
+    pub accept: Vec<String>,
+    /// Options shown with the question, the answer among them.
+    #[serde(default)]
+    pub choices: Vec<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+impl Question {
+    /// The question as posted: its number, category, text and lettered choices.
+    pub fn format(&self, number: usize, total: usize) -> String {
+        let mut text = format!("❓ {number}/{total}");
+        if let Some(category) = &self.category {
+            text.push_str(&format!(" ({category})"));
+        }
+        text.push_str(&format!(": {}", self.question));
+        for (index, choice) in self.choices.iter().enumerate() {
+            text.push_str(&format!("\n{}) {choice}", choice_letter(index)));
+        }
+        text
+    }
+
+    /// Whether `guess` answers the question: the answer or one of the accepted ones, ignoring
+    /// case, punctuation and a leading "the", or the answer's letter on a multiple choice one.
+    pub fn is_correct(&self, guess: &str) -> bool {
+        let guess = normalize_answer(guess);
+        if guess.is_empty() {
+            return false;
+        }
+        if let Some(index) = self.choices.iter().position(|choice| *choice == self.answer)
+            && guess == choice_letter(index).to_ascii_lowercase().to_string()
+        {
+            return true;
+        }
+        std::iter::once(&self.answer)
+            .chain(&self.accept)
+            .any(|answer| normalize_answer(answer) == guess)
+    }
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    if count == 1 { format!("1 {noun}") } else { format!("{count} {noun}s") }
+}
+
+fn choice_letter(index: usize) -> char {
+    (b'A' + (index % 26) as u8) as char
+}
+
+fn normalize_answer(answer: &str) -> String {
+    let answer = answer.trim().to_lowercase();
+    let answer = answer.strip_prefix("the ").unwrap_or(&answer);
+    answer.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+#[derive(Deserialize)]
+struct QuestionFile {
+    questions: Vec<Question>,
+}
+
+/// Parses a local question file: TOML with a `[[questions]]` table per question.
+pub fn parse_question_file(toml: &str) -> Result<Vec<Question>> {
+    let file: QuestionFile = toml::from_str(toml).context("invalid question file")?;
+    if file.questions.is_empty() {
+        bail!("the question file has no questions");
+    }
+    for question in &file.questions {
+        if !question.choices.is_empty() && !question.choices.contains(&question.answer) {
+            bail!("the answer to \"{}\" isn't one of its choices", question.question);
+        }
+    }
+    Ok(file.questions)
+}
+
+#[derive(Deserialize)]
+struct OpenTriviaResponse {
+    response_code: u32,
+    results: Vec<OpenTriviaQuestion>,
+}
+
+#[derive(Deserialize)]
+struct OpenTriviaQuestion {
+    #[serde(rename = "type")]
+    kind: String,
+    category: String,
+    question: String,
+    correct_answer: String,
+    incorrect_answers: Vec<String>,
+}
+
+/// Parses an Open Trivia DB response requested with `encode=base64`, shuffling the choices of
+/// multiple choice questions.
+pub fn parse_open_trivia(json: &str) -> Result<Vec<Question>> {
+    let response: OpenTriviaResponse =
+        serde_json::from_str(json).context("invalid Open Trivia DB response")?;
+    if response.response_code != 0 {
+        bail!("Open Trivia DB answered with code {}", response.response_code);
+    }
+    let decode = |field: &str| -> Result<String> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(field)
+            .context("invalid base64 in Open Trivia DB response")?;
+        String::from_utf8(bytes).context("invalid UTF-8 in Open Trivia DB response")
+    };
+    response
+        .results
+        .into_iter()
+        .map(|result| {
+            let answer = decode(&result.correct_answer)?;
+            let choices = if decode(&result.kind)? == "boolean" {
+                vec!["True".to_string(), "False".to_string()]
+            } else {
+                let mut choices = result
+                    .incorrect_answers
+                    .iter()
+                    .map(|choice| decode(choice))
+                    .collect::<Result<Vec<_>>>()?;
+                choices.push(answer.clone());
+                choices.shuffle(&mut rand::thread_rng());
+                choices
+            };
+            Ok(Question {
+                question: decode(&result.question)?,
+                answer,
+                accept: Vec::new(),
+                choices,
+                category: Some(decode(&result.category)?),
+            })
+        })
+        .collect()
+}
+
+/// Where a `Trivia` middleware's questions come from.
+#[derive(Debug, Clone)]
+pub enum QuestionSource {
+    /// Fetched from Open Trivia DB for each round, from one category (by ID) or any.
+    OpenTriviaDb { category: Option<u32> },
+    /// Loaded from a question file, drawn at random for each round.
+    Local(Vec<Question>),
+}
+
+/// A room a round is played in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriviaRoom {
+    pub service_id: ServiceId,
+    pub room_id: String,
+}
+
+pub struct TriviaConfig {
+    /// Rooms that play together: a round started in one runs in all of them, typically the
+    /// rooms on either side of a chat relay.
+    pub rooms: Vec<TriviaRoom>,
+    pub source: QuestionSource,
+    pub questions_per_round: usize,
+    pub answer_time: Duration,
+    /// A round to start every week on this day and time, in `timezone`.
+    pub schedule: Option<(Weekday, NaiveTime)>,
+    pub timezone: Tz,
+}
+
+/// A player's standing on the leaderboard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerScore {
+    /// The name they last answered under.
+    pub name: String,
+    pub points: u64,
+}
+
+/// The question waiting for an answer, and where to send whoever answers it first.
+struct OpenQuestion {
+    question: Question,
+    winner_tx: oneshot::Sender<(String, String)>,
+}
+
+#[derive(Default)]
+struct RoundState {
+    // Cancels the running round, if there is one
+    running: Option<CancellationToken>,
+    open: Option<OpenQuestion>,
+}
+
+/// Everything a round needs, cloned into the task playing it.
+#[derive(Clone)]
+struct Game {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    http: HttpClient,
+    config: Arc<TriviaConfig>,
+    state: Arc<Mutex<RoundState>>,
+    // Player key (`service_id/user_id` of who wrote the answer) -> score
+    scores: Arc<Mutex<HashMap<String, PlayerScore>>>,
+}
+
+impl Game {
+    /// Starts a round of `count` questions, unless one is already running.
+    fn start_round(&self, count: usize) -> bool {
+        let cancel = CancellationToken::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.running.is_some() {
+                return false;
+            }
+            state.running = Some(cancel.clone());
+        }
+        tracing::info!(questions = count, "trivia round starting");
+        correlation::spawn(self.clone().play_round(count, cancel));
+        true
+    }
+
+    async fn play_round(self, count: usize, cancel: CancellationToken) {
+        let questions = match self.questions(count).await {
+            Ok(questions) => questions,
+            Err(e) => {
+                tracing::warn!(error=%e, "failed to get trivia questions");
+                self.state.lock().unwrap().running = None;
+                self.broadcast(format!("Couldn't get trivia questions: {e}")).await;
+                return;
+            }
+        };
+        let total = questions.len();
+        self.broadcast(format!(
+            "🧠 Trivia time! {}, {} to answer each. First right answer scores",
+            plural(total as u64, "question"),
+            humantime::format_duration(self.config.answer_time)
+        ))
+        .await;
+
+        // Player key -> (name, points this round)
+        let mut round_points: HashMap<String, (String, u64)> = HashMap::new();
+        for (index, question) in questions.into_iter().enumerate() {
+            let (winner_tx, winner_rx) = oneshot::channel();
+            let posted = question.format(index + 1, total);
+            let answer = question.answer.clone();
+            self.state.lock().unwrap().open = Some(OpenQuestion { question, winner_tx });
+            self.broadcast(posted).await;
+
+            let winner = tokio::select! {
+                _ = cancel.cancelled() => break,
+                winner = tokio::time::timeout(self.config.answer_time, winner_rx) => {
+                    winner.ok().and_then(Result::ok)
+                }
+            };
+            self.state.lock().unwrap().open = None;
+            match winner {
+                Some((key, name)) => {
+                    let points = self.award(&key, &name).await;
+                    round_points.entry(key).or_insert_with(|| (name.clone(), 0)).1 += 1;
+                    self.broadcast(format!(
+                        "✅ {name} got it: {answer} ({} in total)",
+                        plural(points, "point")
+                    ))
+                    .await;
+                }
+                None => self.broadcast(format!("⏰ Time's up! The answer was {answer}")).await,
+            }
+
+            if index + 1 < total {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(QUESTION_PAUSE) => {}
+                }
+            }
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.open = None;
+            state.running = None;
+        }
+        if cancel.is_cancelled() {
+            self.broadcast("Trivia stopped".to_string()).await;
+            return;
+        }
+        let mut standings: Vec<(String, u64)> = round_points.into_values().collect();
+        standings.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let summary = if standings.is_empty() {
+            "🏁 Round over! Nobody scored".to_string()
+        } else {
+            let standings: Vec<String> =
+                standings.iter().map(|(name, points)| format!("{name} {points}")).collect();
+            format!("🏁 Round over! {}", standings.join(", "))
+        };
+        self.broadcast(summary).await;
+    }
+
+    async fn questions(&self, count: usize) -> Result<Vec<Question>> {
+        match &self.config.source {
+            QuestionSource::Local(questions) => {
+                Ok(questions.choose_multiple(&mut rand::thread_rng(), count).cloned().collect())
+            }
+            QuestionSource::OpenTriviaDb { category } => {
+                let mut query =
+                    vec![("amount", count.to_string()), ("encode", "base64".to_string())];
+                if let Some(category) = category {
+                    query.push(("category", category.to_string()));
+                }
+                let response = self
+                    .http
+                    .get(OPEN_TRIVIA_DB_URL)
+                    .query(&query)
+                    .send()
+                    .await
+                    .context("failed to reach Open Trivia DB")?;
+                if !response.status().is_success() {
+                    bail!("Open Trivia DB answered {}", response.status());
+                }
+                let body = response.text().await.context("failed to read Open Trivia DB")?;
+                parse_open_trivia(&body)
+            }
+        }
+    }
+
+    /// Gives `key` a point, returning their new total.
+    async fn award(&self, key: &str, name: &str) -> u64 {
+        let (points, snapshot) = {
+            let mut scores = self.scores.lock().unwrap();
+            let score = scores
+                .entry(key.to_string())
+                .or_insert_with(|| PlayerScore { name: name.to_string(), points: 0 });
+            score.name = name.to_string();
+            score.points += 1;
+            (score.points, scores.clone())
+        };
+        if let Err(e) = self.store.set(SCORES_KEY, &snapshot).await {
+            tracing::warn!(error=%e, "failed to save trivia scores");
+        }
+        points
+    }
+
+    /// Posts `body` in every room playing.
+    async fn broadcast(&self, body: String) {
+        for room in &self.config.rooms {
+            let command = Command::SendRoomMessage {
+                service_id: room.service_id.clone(),
+                room_id: room.room_id.clone(),
+                body: body.clone(),
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            };
+            if let Err(e) = send_with_retry(&self.cmd_tx, command, &RetryPolicy::default()).await {
+                tracing::error!(room_id=%room.room_id, error=%e, "failed to post trivia message");
+            }
+        }
+    }
+
+    fn leaderboard(&self) -> String {
+        let mut scores: Vec<PlayerScore> = self.scores.lock().unwrap().values().cloned().collect();
+        if scores.is_empty() {
+            return "Nobody has scored yet".to_string();
+        }
+        scores.sort_by(|a, b| b.points.cmp(&a.points).then_with(|| a.name.cmp(&b.name)));
+        let lines: Vec<String> = scores
+            .iter()
+            .take(LEADERBOARD_SIZE)
+            .enumerate()
+            .map(|(rank, score)| format!("{}. {}: {}", rank + 1, score.name, score.points))
+            .collect();
+        format!("🏆 Trivia leaderboard\n{}", lines.join("\n"))
+    }
+}
+
+/// Quiz rounds: `!trivia` starts a round in the configured rooms, or one can run every week on
+/// a schedule. Each question stays open for the answer time, and the first right answer posted
+/// in any of the rooms scores a point. Points go on a leaderboard (`!trivia top`) kept in the
+/// store, shared by every room playing, so players on both sides of a bridge compete on one.
+/// Answers another bridge relayed count for whoever originally wrote them.
+pub struct Trivia {
+    router: CommandRouter,
+    game: Game,
+    quiet_hours: Option<QuietHours>,
+    scheduler: SchedulerHandle,
+}
+
+impl Trivia {
+    pub fn new(ctx: MiddlewareContext, command_string: String, config: TriviaConfig) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Start a trivia round, stop it, or show the leaderboard")
+            .with_args(vec![ArgSpec::optional("action")]);
+        Self {
+            router,
+            game: Game {
+                cmd_tx: ctx.cmd_tx,
+                store: ctx.store,
+                http: ctx.http,
+                config: Arc::new(config),
+                state: Arc::new(Mutex::new(RoundState::default())),
+                scores: Arc::new(Mutex::new(HashMap::new())),
+            },
+            quiet_hours: ctx.quiet_hours,
+            scheduler: ctx.scheduler,
+        }
+    }
+
+    fn is_playing_room(&self, evt: &Event, room_id: &str) -> bool {
+        self.game
+            .config
+            .rooms
+            .iter()
+            .any(|room| room.service_id == evt.service_id && room.room_id == room_id)
+    }
+
+    fn handle_command(&self, evt: &Event, action: Option<&str>) {
+        let cmd_tx = &self.game.cmd_tx;
+        let count = match action {
+            Some("top" | "scores") => {
+                send_reply(evt, self.game.leaderboard(), cmd_tx);
+                return;
+            }
+            Some("stop") => {
+                let running = self.game.state.lock().unwrap().running.clone();
+                match running {
+                    Some(cancel) => cancel.cancel(),
+                    None => send_reply(evt, "No trivia round is running".to_string(), cmd_tx),
+                }
+                return;
+            }
+            None => self.game.config.questions_per_round,
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) if (1..=MAX_ROUND_QUESTIONS).contains(&count) => count,
+                _ => {
+                    let usage = format!(
+                        "Usage: {0} [questions, up to {MAX_ROUND_QUESTIONS}] | {0} stop | {0} top",
+                        self.router.prefix_on(&evt.service_id)
+                    );
+                    send_reply(evt, usage, cmd_tx);
+                    return;
+                }
+            },
+        };
+        if !self.game.start_round(count) {
+            send_reply(evt, "A trivia round is already running".to_string(), cmd_tx);
+        }
+    }
+
+    fn check_answer(&self, body: &str, player_key: String, name: String) {
+        let mut state = self.game.state.lock().unwrap();
+        if !state.open.as_ref().is_some_and(|open| open.question.is_correct(body)) {
+            return;
+        }
+        if let Some(open) = state.open.take() {
+            let _ = open.winner_tx.send((player_key, name));
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Trivia {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if let Some(saved) = self.game.store.get(SCORES_KEY).await {
+            *self.game.scores.lock().unwrap() = saved;
+        }
+        tracing::info!(rooms = self.game.config.rooms.len(), "trivia middleware running...");
+        let Some((weekday, time)) = self.game.config.schedule else {
+            cancel.cancelled().await;
+            tracing::info!("trivia middleware shutting down...");
+            return Ok(());
+        };
+        let mut scheduler = self.scheduler.scheduler(self.game.config.timezone);
+        loop {
+            let next =
+                defer_past(self.quiet_hours.as_ref(), next_weekly(&scheduler.now(), weekday, time));
+            tracing::debug!(next=%next.format("%Y-%m-%d %H:%M %Z"), "next scheduled trivia round");
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = scheduler.wait_until(&next) => {
+                    if !self.game.start_round(self.game.config.questions_per_round) {
+                        tracing::info!("skipping scheduled trivia round, one is already running");
+                    }
+                }
+            }
+        }
+        tracing::info!("trivia middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let EventKind::RoomMessage {
+            room_id,
+            body,
+            sender_id,
+            sender_display_name,
+            is_self: false,
+            relayed_from,
+            ..
+        } = &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if !self.is_playing_room(evt, room_id) {
+            return Ok(Verdict::Continue);
+        }
+        if self.router.matches(body) {
+            if let Some(invocation) = self.router.route(evt, &self.game.cmd_tx) {
+                self.handle_command(evt, invocation.get("action"));
+            }
+            return Ok(Verdict::Continue);
+        }
+
+        // Answers relayed by another bridge score for whoever wrote them
+        let (player_key, name) = match relayed_from {
+            Some(from) => (
+                format!("{}/{}", from.service_id, from.sender_id),
+                from.sender_display_name.clone().unwrap_or_else(|| from.sender_id.clone()),
+            ),
+            None => (
+                format!("{}/{sender_id}", evt.service_id),
+                sender_display_name.clone().unwrap_or_else(|| sender_id.clone()),
+            ),
+        };
+        self.check_answer(body, player_key, name);
+        Ok(Verdict::Continue)
+    }
+}
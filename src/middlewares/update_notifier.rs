@@ -0,0 +1,229 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    event::Event,
+    format::BodyFormat,
+    http::HttpClient,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    quiet_hours::QuietHours,
+    service::ServiceId,
+    time_zone::{host_time_zone, now_in},
+};
+use crate::store::PersistentStore;
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Version this binary was built as, from the `KELVIN_VERSION` build environment variable
+/// (set by the Docker build to the release tag).
+pub const RUNNING_VERSION: Option<&str> = option_env!("KELVIN_VERSION");
+
+// Give services time to connect before the first check posts anything
+const STARTUP_DELAY: Duration = Duration::from_secs(60);
+const CHANGELOG_EXCERPT_LINES: usize = 10;
+const NOTIFIED_VERSION_KEY: &str = "notified_version";
+
+/// A published GitHub release, as returned by the `releases/latest` endpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub body: Option<String>,
+    pub html_url: String,
+}
+
+/// Where update notifications are sent.
+#[derive(Debug, Clone)]
+pub enum UpdateDestination {
+    Room(String),
+    DirectMessage(String),
+}
+
+pub struct UpdateNotifierConfig {
+    pub service_id: String,
+    pub destination: UpdateDestination,
+    /// GitHub repository to check, as `owner/name`.
+    pub repository: String,
+    pub check_interval: Duration,
+    /// `None` when the version is unknown, which disables checking.
+    pub running_version: Option<String>,
+}
+
+/// Periodically checks the latest GitHub release and says so once when it is newer than the
+/// running version.
+pub struct UpdateNotifier {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    quiet_hours: Option<QuietHours>,
+    config: UpdateNotifierConfig,
+    http: HttpClient,
+}
+
+impl UpdateNotifier {
+    pub fn new(ctx: MiddlewareContext, config: UpdateNotifierConfig) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            quiet_hours: ctx.quiet_hours,
+            config,
+            http: ctx.http,
+        }
+    }
+
+    async fn fetch_latest_release(&self) -> Result<Release> {
+        let url =
+            format!("https://api.github.com/repos/{}/releases/latest", self.config.repository);
+        let response = self
+            .http
+            .get(&url)
+            .header(reqwest::header::USER_AGENT, "kelvin-bot")
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send_cached()
+            .await
+            .context("failed to send request to GitHub")?;
+
+        if !response.status.is_success() {
+            anyhow::bail!("GitHub API returned error: {}", response.status);
+        }
+        response.json().context("failed to parse GitHub release")
+    }
+
+    async fn check_for_update(&self, running_version: &str) -> Result<()> {
+        let release = self.fetch_latest_release().await?;
+        if !is_newer(&release.tag_name, running_version) {
+            tracing::debug!(latest=%release.tag_name, running=%running_version, "up to date");
+            return Ok(());
+        }
+        let notified: Option<String> = self.store.get(NOTIFIED_VERSION_KEY).await;
+        if notified.as_deref() == Some(release.tag_name.as_str()) {
+            return Ok(());
+        }
+
+        tracing::info!(latest=%release.tag_name, running=%running_version, "new version available");
+        let message = format_notification(&release, running_version);
+        let service_id = ServiceId(self.config.service_id.clone());
+        let command = match &self.config.destination {
+            UpdateDestination::Room(room_id) => Command::SendRoomMessage {
+                service_id,
+                room_id: room_id.clone(),
+                body: message,
+                format: BodyFormat::Markdown,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            },
+            UpdateDestination::DirectMessage(user_id) => Command::SendDirectMessage {
+                service_id,
+                user_id: user_id.clone(),
+                body: message,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                expires_after: None,
+                require_encryption: false,
+            },
+        };
+        self.cmd_tx
+            .send(command)
+            .await
+            .map_err(|_| anyhow!("command channel closed, update notification not sent"))?;
+        self.store.set(NOTIFIED_VERSION_KEY, &release.tag_name).await
+    }
+}
+
+#[async_trait]
+impl Middleware for UpdateNotifier {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        let Some(running_version) = self
+            .config
+            .running_version
+            .as_deref()
+            .filter(|version| parse_version(version).is_some())
+        else {
+            tracing::warn!(
+                running=?self.config.running_version,
+                "running version unknown or not a release version, not checking for updates"
+            );
+            cancel.cancelled().await;
+            return Ok(());
+        };
+
+        let timezone = host_time_zone();
+        let mut delay = STARTUP_DELAY;
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {
+                    let now = now_in(timezone);
+                    if let Some(quiet_hours) = &self.quiet_hours
+                        && quiet_hours.is_quiet_at(&now)
+                    {
+                        delay = (quiet_hours.next_end(&now) - now).to_std().unwrap_or_default();
+                        continue;
+                    }
+                    if let Err(e) = self.check_for_update(running_version).await {
+                        tracing::warn!(error=%e, "failed to check for updates");
+                    }
+                    delay = self.config.check_interval;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_event(&self, _evt: &Arc<Event>) -> Result<Verdict> {
+        Ok(Verdict::Continue)
+    }
+}
+
+/// `major.minor.patch` of a tag like `v1.2.3`; missing parts are zero and anything after a `-`
+/// or `+` is ignored.
+pub fn parse_version(tag: &str) -> Option<(u64, u64, u64)> {
+    let version = tag.trim().trim_start_matches(['v', 'V']);
+    let version = version.split(['-', '+']).next()?;
+    let mut parts = version.split('.').map(|part| part.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+/// Whether `latest` is a higher version than `running`. Unparseable versions never are.
+pub fn is_newer(latest: &str, running: &str) -> bool {
+    match (parse_version(latest), parse_version(running)) {
+        (Some(latest), Some(running)) => latest > running,
+        _ => false,
+    }
+}
+
+/// The notification text: the new version, the first lines of its release notes and a link.
+pub fn format_notification(release: &Release, running_version: &str) -> String {
+    let mut message =
+        format!("🆕 KelvinBot {} is available (running {running_version}).", release.tag_name);
+
+    let notes: Vec<&str> = release
+        .body
+        .as_deref()
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    if !notes.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&notes[..notes.len().min(CHANGELOG_EXCERPT_LINES)].join("\n"));
+        if notes.len() > CHANGELOG_EXCERPT_LINES {
+            message.push_str("\n…");
+        }
+    }
+
+    message.push_str(&format!("\n\n{}", release.html_url));
+    message
+}
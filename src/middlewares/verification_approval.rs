@@ -0,0 +1,144 @@
+use crate::core::{
+    bus::{Command, CommandSender},
+    commands::{CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+/// Sends the emoji of a device verification to an admin by DM, so they can compare them with
+/// the ones on the other device without watching the bot's logs, and passes their `!confirm`
+/// or `!deny` back to the service that's waiting on it.
+///
+/// Needs to be in the pipeline of the verifying service, to hear about the verification, and of
+/// the admin's service, to hear their answer. The verifying service can't take part in its own
+/// approval since it isn't connected yet, so the admin is normally on another service.
+pub struct VerificationApproval {
+    cmd_tx: CommandSender,
+    confirm: CommandRouter,
+    deny: CommandRouter,
+    /// Where the admin is reached and answers.
+    service_id: ServiceId,
+    user_id: String,
+    /// The verification waiting on an answer: the verifying service and its flow ID.
+    pending: Mutex<Option<(ServiceId, String)>>,
+}
+
+impl VerificationApproval {
+    pub fn new(ctx: MiddlewareContext, service_id: ServiceId, user_id: String) -> Self {
+        let confirm = CommandRouter::new("!confirm")
+            .with_description("Confirm the emoji of a device verification match");
+        let deny = CommandRouter::new("!deny")
+            .with_description("Cancel a device verification whose emoji don't match");
+        Self { cmd_tx: ctx.cmd_tx, confirm, deny, service_id, user_id, pending: Mutex::new(None) }
+    }
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, Option<(ServiceId, String)>> {
+        self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The DM asking the admin to compare `emoji`.
+pub fn format_verification_request(
+    service_id: &ServiceId,
+    other_device_id: &str,
+    emoji: &[String],
+) -> String {
+    format!(
+        "🔐 {service_id} is verifying its device against {other_device_id}. Do these emoji match \
+         the ones shown there?\n{}\nReply !confirm if they do, or !deny if they don't.",
+        emoji.join(", ")
+    )
+}
+
+#[async_trait]
+impl Middleware for VerificationApproval {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(service_id=%self.service_id, "verification_approval middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("verification_approval middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.confirm.prefix(), self.deny.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.confirm.spec(), self.deny.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        match &evt.kind {
+            EventKind::VerificationRequested { flow_id, other_device_id, emoji } => {
+                tracing::info!(service_id=%evt.service_id, flow_id=%flow_id, "asking admin to compare verification emoji");
+                *self.pending() = Some((evt.service_id.clone(), flow_id.clone()));
+                let command = Command::SendDirectMessage {
+                    service_id: self.service_id.clone(),
+                    user_id: self.user_id.clone(),
+                    body: format_verification_request(&evt.service_id, other_device_id, emoji),
+                    response_tx: None,
+                    origin: None,
+                    idempotency_key: None,
+                    expires_after: None,
+                    require_encryption: false,
+                };
+                self.cmd_tx.spawn_send(command, "verification emoji");
+                Ok(Verdict::Continue)
+            }
+            EventKind::DirectMessage { sender_id, is_self, .. }
+                if !*is_self && evt.service_id == self.service_id && *sender_id == self.user_id =>
+            {
+                let confirm = if self.confirm.route(evt, &self.cmd_tx).is_some() {
+                    true
+                } else if self.deny.route(evt, &self.cmd_tx).is_some() {
+                    false
+                } else {
+                    return Ok(Verdict::Continue);
+                };
+                let Some((service_id, flow_id)) = self.pending().take() else {
+                    send_reply(
+                        evt,
+                        "No verification is waiting for an answer".to_string(),
+                        &self.cmd_tx,
+                    );
+                    return Ok(Verdict::Continue);
+                };
+
+                let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+                let command = Command::ConfirmVerification {
+                    service_id,
+                    flow_id,
+                    confirm,
+                    response_tx: Some(response_tx),
+                    origin: None,
+                };
+                self.cmd_tx.spawn_send(command, "verification answer");
+
+                let cmd_tx = self.cmd_tx.clone();
+                let evt = evt.clone();
+                correlation::spawn(async move {
+                    let reply = match response_rx.await {
+                        Ok(Ok(_)) if confirm => {
+                            "Confirmed; confirm on the other device too".to_string()
+                        }
+                        Ok(Ok(_)) => "Denied; the verification was cancelled".to_string(),
+                        Ok(Err(e)) => format!("Failed: {e}"),
+                        Err(e) => {
+                            tracing::error!(error=%e, "failed to receive verification response");
+                            return;
+                        }
+                    };
+                    send_reply(&evt, reply, &cmd_tx);
+                });
+                Ok(Verdict::Continue)
+            }
+            _ => Ok(Verdict::Continue),
+        }
+    }
+}
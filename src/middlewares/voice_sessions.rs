@@ -0,0 +1,445 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    event::{Event, EventKind, VoiceState},
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    migrations::{self, Migration},
+    quiet_hours::{QuietHours, defer_past},
+    scheduler::SchedulerHandle,
+    service::ServiceId,
+    time_zone::resolve_local,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use rusqlite::{Connection, params};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// One person's part in a voice session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attendance {
+    pub user: String,
+    /// How long they were connected while the session ran.
+    pub present: Duration,
+    /// How long they were talking, going by the service's `Talking` voice state.
+    pub talk: Duration,
+}
+
+/// A finished voice session: from the first person connecting until the last one left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    /// Everyone who was there, by name.
+    pub attendees: Vec<Attendance>,
+}
+
+#[derive(Default)]
+struct Attendee {
+    present_since: Option<DateTime<Utc>>,
+    present: Duration,
+    talking_since: Option<DateTime<Utc>>,
+    talk: Duration,
+}
+
+impl Attendee {
+    /// Closes their open presence and talking spans at `now`.
+    fn leave(&mut self, now: DateTime<Utc>) {
+        if let Some(since) = self.present_since.take() {
+            self.present += (now - since).to_std().unwrap_or_default();
+        }
+        self.stop_talking(now);
+    }
+
+    fn stop_talking(&mut self, now: DateTime<Utc>) {
+        if let Some(since) = self.talking_since.take() {
+            self.talk += (now - since).to_std().unwrap_or_default();
+        }
+    }
+}
+
+struct Session {
+    started_at: DateTime<Utc>,
+    attendees: BTreeMap<String, Attendee>,
+}
+
+/// Follows who is connected and who is talking, and turns each stretch with anyone connected
+/// into a `SessionRecord`.
+#[derive(Default)]
+pub struct SessionTracker {
+    current: Option<Session>,
+}
+
+impl SessionTracker {
+    /// Updates who's connected as of `now`. Starts a session when the first person connects;
+    /// returns it once the last one has left.
+    pub fn users_present(
+        &mut self,
+        users: &HashSet<String>,
+        now: DateTime<Utc>,
+    ) -> Option<SessionRecord> {
+        if users.is_empty() {
+            let mut session = self.current.take()?;
+            for attendee in session.attendees.values_mut() {
+                attendee.leave(now);
+            }
+            let attendees = session
+                .attendees
+                .into_iter()
+                .map(|(user, attendee)| Attendance {
+                    user,
+                    present: attendee.present,
+                    talk: attendee.talk,
+                })
+                .collect();
+            return Some(SessionRecord {
+                started_at: session.started_at,
+                ended_at: now,
+                attendees,
+            });
+        }
+
+        let session = self
+            .current
+            .get_or_insert_with(|| Session { started_at: now, attendees: BTreeMap::new() });
+        for (user, attendee) in session.attendees.iter_mut() {
+            if !users.contains(user) {
+                attendee.leave(now);
+            }
+        }
+        for user in users {
+            let attendee = session.attendees.entry(user.clone()).or_default();
+            attendee.present_since.get_or_insert(now);
+        }
+        None
+    }
+
+    /// Notes `user` starting or stopping talking at `now`. Ignored unless they're connected.
+    pub fn talking(&mut self, user: &str, active: bool, now: DateTime<Utc>) {
+        let Some(attendee) = self
+            .current
+            .as_mut()
+            .and_then(|session| session.attendees.get_mut(user))
+            .filter(|attendee| attendee.present_since.is_some())
+        else {
+            return;
+        };
+        if active {
+            attendee.talking_since.get_or_insert(now);
+        } else {
+            attendee.stop_talking(now);
+        }
+    }
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "create the sessions and attendance tables",
+    sql: "CREATE TABLE IF NOT EXISTS sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            started_at INTEGER NOT NULL,
+            ended_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS sessions_started_at ON sessions (started_at);
+        CREATE TABLE IF NOT EXISTS attendance (
+            session_id INTEGER NOT NULL REFERENCES sessions (id),
+            user TEXT NOT NULL,
+            present_secs INTEGER NOT NULL,
+            talk_secs INTEGER NOT NULL
+        );",
+}];
+
+/// A sqlite-backed record of finished voice sessions, kept for attendance reporting.
+pub struct SessionLog {
+    conn: Mutex<Connection>,
+}
+
+impl SessionLog {
+    /// Open (or create) the session database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Create a session log that lives only in memory. Useful for testing.
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self> {
+        migrations::migrate(&mut conn, "voice sessions", MIGRATIONS)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn record(&self, session: &SessionRecord) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO sessions (started_at, ended_at) VALUES (?1, ?2)",
+            params![session.started_at.timestamp(), session.ended_at.timestamp()],
+        )?;
+        let session_id = tx.last_insert_rowid();
+        for attendance in &session.attendees {
+            tx.execute(
+                "INSERT INTO attendance (session_id, user, present_secs, talk_secs)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    session_id,
+                    attendance.user,
+                    attendance.present.as_secs() as i64,
+                    attendance.talk.as_secs() as i64,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Sessions that started in `[from, to)`, oldest first.
+    pub fn sessions_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<SessionRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, started_at, ended_at FROM sessions
+             WHERE started_at >= ?1 AND started_at < ?2 ORDER BY started_at",
+        )?;
+        let sessions = stmt
+            .query_map(params![from.timestamp(), to.timestamp()], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut attendance_stmt = conn.prepare(
+            "SELECT user, present_secs, talk_secs FROM attendance
+             WHERE session_id = ?1 ORDER BY user",
+        )?;
+        let mut records = Vec::new();
+        for (id, started_at, ended_at) in sessions {
+            let attendees = attendance_stmt
+                .query_map(params![id], |row| {
+                    Ok(Attendance {
+                        user: row.get(0)?,
+                        present: Duration::from_secs(row.get::<_, i64>(1)?.max(0) as u64),
+                        talk: Duration::from_secs(row.get::<_, i64>(2)?.max(0) as u64),
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            records.push(SessionRecord {
+                started_at: DateTime::from_timestamp(started_at, 0).unwrap_or_default(),
+                ended_at: DateTime::from_timestamp(ended_at, 0).unwrap_or_default(),
+                attendees,
+            });
+        }
+        Ok(records)
+    }
+}
+
+/// Totals for `month` (e.g. "March 2026"), one line per person, e.g.
+/// `- alice: 3 sessions, 4h 10m present, 52m talking`, most present first.
+pub fn format_month_summary(month: &str, sessions: &[SessionRecord]) -> String {
+    if sessions.is_empty() {
+        return format!("Voice sessions in {month}: none");
+    }
+    let mut totals: BTreeMap<&str, (usize, Duration, Duration)> = BTreeMap::new();
+    for session in sessions {
+        for attendance in &session.attendees {
+            let total = totals.entry(attendance.user.as_str()).or_default();
+            total.0 += 1;
+            total.1 += attendance.present;
+            total.2 += attendance.talk;
+        }
+    }
+    let mut people: Vec<_> = totals.into_iter().collect();
+    people.sort_by(|a, b| b.1.1.cmp(&a.1.1).then(a.0.cmp(b.0)));
+
+    let total_time: Duration = sessions
+        .iter()
+        .map(|session| (session.ended_at - session.started_at).to_std().unwrap_or_default())
+        .sum();
+    let mut lines = vec![format!(
+        "Voice sessions in {month}: {} sessions, {} in total, {} people",
+        sessions.len(),
+        format_span(total_time),
+        people.len()
+    )];
+    for (user, (count, present, talk)) in people {
+        let sessions = if count == 1 { "session" } else { "sessions" };
+        lines.push(format!(
+            "- {user}: {count} {sessions}, {} present, {} talking",
+            format_span(present),
+            format_span(talk)
+        ));
+    }
+    lines.join("\n")
+}
+
+// Whole minutes, e.g. "4h 10m" or "52m"
+fn format_span(span: Duration) -> String {
+    let minutes = span.as_secs() / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => format!("{minutes}m"),
+        (hours, minutes) => format!("{hours}h {minutes}m"),
+    }
+}
+
+/// The first of the month after `now`'s, at `time`.
+fn next_month_start(now: &DateTime<Tz>, time: NaiveTime) -> DateTime<Tz> {
+    let this_month = NaiveDate::from_ymd_opt(now.year(), now.month(), 1).unwrap_or_default();
+    let next_month = this_month + Months::new(1);
+    resolve_local(&now.timezone(), next_month.and_time(time))
+}
+
+pub struct VoiceSessionsConfig {
+    pub source_service_id: String,
+    pub report_service_id: String,
+    pub report_room_id: String,
+    /// When on the first of each month the previous month's summary is posted.
+    pub summary_time: NaiveTime,
+    pub timezone: Tz,
+}
+
+/// Keeps attendance records of a voice service: who was connected during each session, and
+/// for how long each of them talked. Sessions are recorded in a sqlite database as they end,
+/// and a summary of the month's sessions is posted to a room on the first of the next month.
+///
+/// A session runs from the first person connecting to the last one leaving, like the
+/// attendance relay's. Talk time comes from `Talking` voice states, which Mumble reports.
+pub struct VoiceSessions {
+    cmd_tx: CommandSender,
+    config: VoiceSessionsConfig,
+    quiet_hours: Option<QuietHours>,
+    scheduler: SchedulerHandle,
+    tracker: Mutex<SessionTracker>,
+    log: Arc<SessionLog>,
+}
+
+impl VoiceSessions {
+    pub fn new(ctx: MiddlewareContext, config: VoiceSessionsConfig, log: SessionLog) -> Self {
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            config,
+            quiet_hours: ctx.quiet_hours,
+            scheduler: ctx.scheduler,
+            tracker: Mutex::new(SessionTracker::default()),
+            log: Arc::new(log),
+        }
+    }
+
+    /// Posts the summary of the month before `now`'s.
+    async fn post_summary(&self, now: DateTime<Tz>) {
+        let this_month = NaiveDate::from_ymd_opt(now.year(), now.month(), 1).unwrap_or_default();
+        let last_month = this_month - Months::new(1);
+        let tz = self.config.timezone;
+        let from = resolve_local(&tz, last_month.and_time(NaiveTime::MIN)).with_timezone(&Utc);
+        let to = resolve_local(&tz, this_month.and_time(NaiveTime::MIN)).with_timezone(&Utc);
+        let sessions = match self.log.sessions_between(from, to) {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                tracing::error!(error=%e, "failed to read voice sessions");
+                return;
+            }
+        };
+
+        let body = format_month_summary(&last_month.format("%B %Y").to_string(), &sessions);
+        let command = Command::SendRoomMessage {
+            service_id: ServiceId(self.config.report_service_id.clone()),
+            room_id: self.config.report_room_id.clone(),
+            body,
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        };
+        if let Err(e) = send_with_retry(&self.cmd_tx, command, &RetryPolicy::default()).await {
+            tracing::error!(error=%e, "failed to post voice session summary");
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for VoiceSessions {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(
+            source_service=%self.config.source_service_id,
+            report_service=%self.config.report_service_id,
+            report_room=%self.config.report_room_id,
+            "voice_sessions middleware running..."
+        );
+        let mut scheduler = self.scheduler.scheduler(self.config.timezone);
+        loop {
+            let next = defer_past(
+                self.quiet_hours.as_ref(),
+                next_month_start(&scheduler.now(), self.config.summary_time),
+            );
+            tracing::debug!(next_summary=%next.format("%Y-%m-%d %H:%M"), "waiting to post voice session summary");
+
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = scheduler.wait_until(&next) => self.post_summary(next).await,
+            }
+        }
+        tracing::info!("voice_sessions middleware shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        if evt.service_id.0 != self.config.source_service_id {
+            return Ok(Verdict::Continue);
+        }
+
+        let now = Utc::now();
+        let ended = match &evt.kind {
+            EventKind::UserListUpdate { users } => {
+                let present: HashSet<String> = users
+                    .iter()
+                    .filter(|user| !user.is_self && user.is_active)
+                    .map(|user| user.display_name.clone())
+                    .collect();
+                self.tracker.lock().unwrap().users_present(&present, now)
+            }
+            EventKind::VoiceStateChanged {
+                user_id,
+                sender_display_name,
+                state: VoiceState::Talking,
+                active,
+                is_self: false,
+                ..
+            } => {
+                let user = sender_display_name.as_deref().unwrap_or(user_id);
+                self.tracker.lock().unwrap().talking(user, *active, now);
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(session) = ended {
+            tracing::info!(attendees = session.attendees.len(), "voice session ended");
+            let log = self.log.clone();
+            // Writing to sqlite blocks, so keep it off the pipeline
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = log.record(&session) {
+                    tracing::error!(error=%e, "failed to record voice session");
+                }
+            });
+        }
+        Ok(Verdict::Continue)
+    }
+}
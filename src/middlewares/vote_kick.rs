@@ -0,0 +1,437 @@
+use crate::core::{
+    bus::{Command, CommandSender, RetryPolicy, send_with_retry},
+    commands::{ArgSpec, CommandRouter, CommandSpec, parse_duration, send_reply, split_words},
+    correlation,
+    event::{Event, EventKind},
+    format::BodyFormat,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use crate::store::PersistentStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+// Store key for the settings admins changed at runtime
+const SETTINGS_KEY: &str = "settings";
+
+// Replies counted as a vote in favour, compared case-insensitively
+const YES_VOTES: &[&str] = &["yes", "y", "+1", "👍"];
+
+/// How many votes a kick needs and how long a vote stays open; admins can change both from chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoteKickSettings {
+    /// Votes needed, counting the one from whoever started it.
+    pub threshold: usize,
+    pub vote_duration: Duration,
+}
+
+pub struct VoteKickConfig {
+    pub settings: VoteKickSettings,
+    /// How long after a vote on someone ends before another can be started on them.
+    pub cooldown: Duration,
+    /// Who may change settings and cancel votes; they can't be vote-kicked themselves.
+    pub admin_user_ids: Vec<String>,
+}
+
+/// A vote running in a room.
+struct Vote {
+    id: u64,
+    target: String,
+    /// The announcement, which reactions are counted on once it's been sent.
+    message_id: Option<String>,
+    voters: HashSet<String>,
+}
+
+#[derive(Default)]
+struct VoteState {
+    next_id: u64,
+    // At most one vote per room
+    votes: HashMap<(ServiceId, String), Vote>,
+    // When votes on someone in a room ended, for the cooldown
+    ended: HashMap<(ServiceId, String, String), Instant>,
+}
+
+impl VoteState {
+    /// Removes vote `id` from the room, starting the cooldown on its target.
+    fn end(&mut self, room_key: &(ServiceId, String), id: u64) -> Option<Vote> {
+        if self.votes.get(room_key)?.id != id {
+            return None;
+        }
+        let vote = self.votes.remove(room_key)?;
+        let (service_id, room_id) = room_key.clone();
+        self.ended.insert((service_id, room_id, vote.target.clone()), Instant::now());
+        Some(vote)
+    }
+}
+
+/// Whether `body` is a reply voting in favour.
+pub fn is_yes_vote(body: &str) -> bool {
+    let body = body.trim();
+    YES_VOTES.iter().any(|yes| body.eq_ignore_ascii_case(yes))
+}
+
+/// Lets a room vote someone out: `!votekick <user>` starts a vote, which passes once enough
+/// people reply "yes" or react 👍 to its announcement before it runs out, and the user is then
+/// kicked from the room on the service the vote ran on.
+///
+/// A vote on someone can't be started again until the cooldown has passed since the last one
+/// ended. Admins can't be vote-kicked, and are the only ones who can change the threshold and
+/// vote duration (`!votekick set threshold 4`, `!votekick set duration 3m`) or cancel a running
+/// vote (`!votekick cancel`). Changed settings are persisted.
+pub struct VoteKick {
+    cmd_tx: CommandSender,
+    store: Arc<PersistentStore>,
+    router: CommandRouter,
+    settings: Arc<Mutex<VoteKickSettings>>,
+    cooldown: Duration,
+    admin_user_ids: Vec<String>,
+    state: Arc<Mutex<VoteState>>,
+}
+
+impl VoteKick {
+    pub fn new(ctx: MiddlewareContext, command_string: String, config: VoteKickConfig) -> Self {
+        let router = CommandRouter::new(command_string)
+            .with_description("Start a vote to kick someone from the room")
+            .with_args(vec![ArgSpec::rest("user")]);
+        Self {
+            cmd_tx: ctx.cmd_tx,
+            store: ctx.store,
+            router,
+            settings: Arc::new(Mutex::new(config.settings)),
+            cooldown: config.cooldown,
+            admin_user_ids: config.admin_user_ids,
+            state: Arc::new(Mutex::new(VoteState::default())),
+        }
+    }
+
+    fn is_admin(&self, user_id: &str) -> bool {
+        self.admin_user_ids.iter().any(|admin| admin == user_id)
+    }
+
+    /// Handles `!votekick set ...` and `!votekick cancel`, which only admins may use.
+    fn configure(&self, evt: &Arc<Event>, room_id: &str, sender_id: &str, words: &[&str]) {
+        if !self.is_admin(sender_id) {
+            send_reply(evt, "Only admins can change vote kicks".to_string(), &self.cmd_tx);
+            return;
+        }
+        let reply = match words {
+            ["cancel"] => {
+                let key = (evt.service_id.clone(), room_id.to_string());
+                match self.state.lock().unwrap().votes.remove(&key) {
+                    Some(vote) => format!("Cancelled the vote to kick {}", vote.target),
+                    None => "No vote is running here".to_string(),
+                }
+            }
+            ["set", "threshold", value] => match value.parse::<usize>() {
+                Ok(threshold) if threshold > 0 => {
+                    self.update_settings(evt, |settings| settings.threshold = threshold);
+                    return;
+                }
+                _ => format!("'{value}' isn't a number of votes"),
+            },
+            ["set", "duration", value] => match parse_duration(value) {
+                Ok(duration) => {
+                    self.update_settings(evt, |settings| settings.vote_duration = duration);
+                    return;
+                }
+                Err(e) => e,
+            },
+            _ => self.usage(evt),
+        };
+        send_reply(evt, reply, &self.cmd_tx);
+    }
+
+    /// Applies `change` to the settings, saves them and replies with the result.
+    fn update_settings(&self, evt: &Arc<Event>, change: impl FnOnce(&mut VoteKickSettings)) {
+        let settings = {
+            let mut settings = self.settings.lock().unwrap();
+            change(&mut settings);
+            *settings
+        };
+        let cmd_tx = self.cmd_tx.clone();
+        let store = self.store.clone();
+        let evt = evt.clone();
+        correlation::spawn(async move {
+            let mut reply = format!(
+                "Votes now pass with {} in favour within {}",
+                settings.threshold,
+                humantime::format_duration(settings.vote_duration)
+            );
+            if let Err(e) = store.set(SETTINGS_KEY, &settings).await {
+                tracing::error!(error=%e, "failed to save vote kick settings");
+                reply.push_str(" (until restart: failed to save it)");
+            }
+            send_reply(&evt, reply, &cmd_tx);
+        });
+    }
+
+    /// Starts a vote to kick `target` from `room_id`, with `initiator` voting in favour.
+    fn start_vote(&self, evt: &Arc<Event>, room_id: &str, initiator: &str, target: &str) {
+        if self.is_admin(target) {
+            send_reply(evt, format!("{target} can't be vote-kicked"), &self.cmd_tx);
+            return;
+        }
+        if target == initiator {
+            send_reply(evt, "You can't vote to kick yourself".to_string(), &self.cmd_tx);
+            return;
+        }
+
+        let settings = *self.settings.lock().unwrap();
+        let room_key = (evt.service_id.clone(), room_id.to_string());
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(vote) = state.votes.get(&room_key) {
+                let reply = format!("A vote to kick {} is already running", vote.target);
+                send_reply(evt, reply, &self.cmd_tx);
+                return;
+            }
+            let target_key = (evt.service_id.clone(), room_id.to_string(), target.to_string());
+            if let Some(remaining) = state
+                .ended
+                .get(&target_key)
+                .and_then(|ended| self.cooldown.checked_sub(ended.elapsed()))
+            {
+                let remaining = Duration::from_secs(remaining.as_secs().max(1));
+                let reply = format!(
+                    "Wait {} before voting on {target} again",
+                    humantime::format_duration(remaining)
+                );
+                send_reply(evt, reply, &self.cmd_tx);
+                return;
+            }
+            state.next_id += 1;
+            let id = state.next_id;
+            let vote = Vote {
+                id,
+                target: target.to_string(),
+                message_id: None,
+                voters: HashSet::from([initiator.to_string()]),
+            };
+            state.votes.insert(room_key.clone(), vote);
+            id
+        };
+        tracing::info!(room_id=%room_id, user=%target, initiator=%initiator, "vote kick started");
+
+        // A threshold of one passes on the initiator's vote alone
+        if settings.threshold <= 1 {
+            self.count_vote(&room_key, id, initiator);
+            return;
+        }
+        let announcement = format!(
+            "🗳️ {initiator} started a vote to kick {target}. Reply \"yes\" or react 👍 within {} \
+             to vote in favour; {} votes needed",
+            humantime::format_duration(settings.vote_duration),
+            settings.threshold
+        );
+        correlation::spawn(announce(
+            self.cmd_tx.clone(),
+            self.state.clone(),
+            room_key.clone(),
+            id,
+            announcement,
+        ));
+        correlation::spawn(expire(self.cmd_tx.clone(), self.state.clone(), room_key, id, settings));
+    }
+
+    /// Records `voter`'s vote in the room's vote `id`, kicking its target once it passes.
+    fn count_vote(&self, room_key: &(ServiceId, String), id: u64, voter: &str) {
+        let threshold = self.settings.lock().unwrap().threshold;
+        let passed = {
+            let mut state = self.state.lock().unwrap();
+            let Some(vote) = state.votes.get_mut(room_key).filter(|vote| vote.id == id) else {
+                return;
+            };
+            if voter == vote.target {
+                return;
+            }
+            vote.voters.insert(voter.to_string());
+            if vote.voters.len() < threshold {
+                return;
+            }
+            state.end(room_key, id)
+        };
+        let Some(passed) = passed else {
+            return;
+        };
+
+        let votes = passed.voters.len();
+        let target = passed.target;
+        tracing::info!(user=%target, votes, "vote kick passed");
+        let (service_id, room_id) = room_key.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        correlation::spawn(async move {
+            let command = Command::KickUser {
+                service_id: service_id.clone(),
+                room_id: room_id.clone(),
+                user_id: target.clone(),
+                reason: Some(format!("Vote kicked by {votes} people")),
+                response_tx: None,
+                origin: None,
+            };
+            let body = match send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+                Ok(_) => format!("{target} was kicked by vote ({votes} votes)"),
+                Err(e) => {
+                    tracing::warn!(user=%target, error=%e, "failed to kick user after vote");
+                    format!("The vote passed, but {target} couldn't be kicked: {e}")
+                }
+            };
+            post(&cmd_tx, service_id, room_id, body).await;
+        });
+    }
+
+    /// The room and ID of the vote running in `room_id` on `evt`'s service, if `matches` it.
+    fn running_vote(
+        &self,
+        evt: &Event,
+        room_id: &str,
+        matches: impl FnOnce(&Vote) -> bool,
+    ) -> Option<((ServiceId, String), u64)> {
+        let room_key = (evt.service_id.clone(), room_id.to_string());
+        let id = self.state.lock().unwrap().votes.get(&room_key).filter(|vote| matches(vote))?.id;
+        Some((room_key, id))
+    }
+
+    fn usage(&self, evt: &Event) -> String {
+        format!(
+            "Usage: {0} <user> | {0} set threshold <votes> | {0} set duration <duration> | {0} cancel",
+            self.router.prefix_on(&evt.service_id)
+        )
+    }
+}
+
+/// Posts the announcement for vote `id`, remembering its ID so reactions to it count.
+async fn announce(
+    cmd_tx: CommandSender,
+    state: Arc<Mutex<VoteState>>,
+    (service_id, room_id): (ServiceId, String),
+    id: u64,
+    announcement: String,
+) {
+    let command = Command::SendRoomMessage {
+        service_id,
+        room_id,
+        body: announcement,
+        format: BodyFormat::Plain,
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    };
+    match send_with_retry(&cmd_tx, command, &RetryPolicy::default()).await {
+        Ok(message_id) if !message_id.is_empty() => {
+            if let Some(vote) = state.lock().unwrap().votes.values_mut().find(|vote| vote.id == id)
+            {
+                vote.message_id = Some(message_id);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!(error=%e, "failed to announce vote kick"),
+    }
+}
+
+/// Ends vote `id` as failed once its time is up, unless it passed or was cancelled first.
+async fn expire(
+    cmd_tx: CommandSender,
+    state: Arc<Mutex<VoteState>>,
+    room_key: (ServiceId, String),
+    id: u64,
+    settings: VoteKickSettings,
+) {
+    tokio::time::sleep(settings.vote_duration).await;
+    let Some(vote) = state.lock().unwrap().end(&room_key, id) else {
+        return;
+    };
+    tracing::info!(user=%vote.target, votes = vote.voters.len(), "vote kick failed");
+    let body = format!(
+        "The vote to kick {} failed ({} of {} votes)",
+        vote.target,
+        vote.voters.len(),
+        settings.threshold
+    );
+    let (service_id, room_id) = room_key;
+    post(&cmd_tx, service_id, room_id, body).await;
+}
+
+async fn post(cmd_tx: &CommandSender, service_id: ServiceId, room_id: String, body: String) {
+    let command = Command::SendRoomMessage {
+        service_id,
+        room_id,
+        body,
+        format: BodyFormat::Plain,
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    };
+    if let Err(e) = send_with_retry(cmd_tx, command, &RetryPolicy::default()).await {
+        tracing::error!(error=%e, "failed to post vote kick result");
+    }
+}
+
+#[async_trait]
+impl Middleware for VoteKick {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        if let Some(settings) = self.store.get::<VoteKickSettings>(SETTINGS_KEY).await {
+            *self.settings.lock().unwrap() = settings;
+        }
+        tracing::info!("vote_kick middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("vote_kick middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        match &evt.kind {
+            EventKind::RoomMessage { room_id, body, sender_id, is_self: false, .. } => {
+                if self.router.matches(body) {
+                    let Some(invocation) = self.router.route(evt, &self.cmd_tx) else {
+                        return Ok(Verdict::Continue);
+                    };
+                    let words = split_words(invocation.arg("user"));
+                    match words.as_slice() {
+                        ["cancel"] | ["set", ..] => self.configure(evt, room_id, sender_id, &words),
+                        [target] => self.start_vote(evt, room_id, sender_id, target),
+                        _ => send_reply(evt, self.usage(evt), &self.cmd_tx),
+                    }
+                } else if is_yes_vote(body)
+                    && let Some((room_key, id)) = self.running_vote(evt, room_id, |_| true)
+                {
+                    self.count_vote(&room_key, id, sender_id);
+                }
+            }
+            EventKind::ReactionAdded {
+                room_id,
+                target_event_id,
+                key,
+                sender_id,
+                is_self: false,
+                ..
+            } if key.starts_with('👍') => {
+                if let Some((room_key, id)) = self.running_vote(evt, room_id, |vote| {
+                    vote.message_id.as_deref() == Some(target_event_id.as_str())
+                }) {
+                    self.count_vote(&room_key, id, sender_id);
+                }
+            }
+            _ => {}
+        }
+        Ok(Verdict::Continue)
+    }
+}
@@ -1,17 +1,22 @@
 use crate::core::{
-    bus::Command,
+    bus::{Command, CommandSender},
     event::{Event, EventKind},
+    format::BodyFormat,
     middleware::{Middleware, MiddlewareContext, Verdict},
+    quiet_hours::{QuietHours, defer_past},
+    scheduler::SchedulerHandle,
     service::ServiceId,
+    time_zone::{next_weekly, now_in},
 };
 use crate::store::PersistentStore;
 use anyhow::Result;
 use async_trait::async_trait;
-use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Utc, Weekday};
+use chrono::{DateTime, Duration, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
 use rand::seq::SliceRandom;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{Mutex, mpsc::Sender};
+use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Clone)]
@@ -35,6 +40,8 @@ pub struct WeeklyGatheringConfig {
     pub finalization_in_person_message: String,
     pub finalization_no_votes_message: String,
     pub households: Vec<Household>,
+    /// Zone the event day and time are given in
+    pub timezone: Tz,
 }
 
 #[derive(Debug, Clone)]
@@ -69,17 +76,19 @@ enum ReactionEvent {
 }
 
 pub struct WeeklyGathering {
-    cmd_tx: Sender<Command>,
+    cmd_tx: CommandSender,
     config: WeeklyGatheringConfig,
     state: Arc<Mutex<GatheringState>>,
     store: Arc<PersistentStore>,
     reaction_tx: tokio::sync::mpsc::Sender<ReactionEvent>,
     reaction_rx: Arc<Mutex<tokio::sync::mpsc::Receiver<ReactionEvent>>>,
+    quiet_hours: Option<QuietHours>,
+    scheduler: SchedulerHandle,
 }
 
 impl WeeklyGathering {
     pub fn new(ctx: MiddlewareContext, config: WeeklyGatheringConfig) -> Self {
-        let MiddlewareContext { cmd_tx, store } = ctx;
+        let MiddlewareContext { cmd_tx, store, quiet_hours, scheduler, .. } = ctx;
         let (reaction_tx, reaction_rx) = tokio::sync::mpsc::channel(100);
 
         Self {
@@ -89,43 +98,29 @@ impl WeeklyGathering {
             store,
             reaction_tx,
             reaction_rx: Arc::new(Mutex::new(reaction_rx)),
+            quiet_hours,
+            scheduler,
         }
     }
 
     /// Calculate the next occurrence of the event day/time
-    fn next_event_time(&self) -> DateTime<Local> {
-        let now = Local::now();
-        let target_time = self.config.event_time;
-        let target_weekday = self.config.event_day_of_week;
-
-        let current_weekday = now.weekday();
-        let current_num = current_weekday.number_from_monday();
-        let target_num = target_weekday.number_from_monday();
-
-        let days_until_target = if current_weekday == target_weekday {
-            let now_time = now.time();
-            if now_time < target_time { 0 } else { 7 }
-        } else if target_num > current_num {
-            target_num - current_num
-        } else {
-            7 - (current_num - target_num)
-        };
-
-        let target_date = now.date_naive() + Duration::days(days_until_target as i64);
-        let target_datetime = target_date.and_time(target_time);
-
-        Local.from_local_datetime(&target_datetime).unwrap()
+    fn next_event_time(&self) -> DateTime<Tz> {
+        next_weekly(
+            &now_in(self.config.timezone),
+            self.config.event_day_of_week,
+            self.config.event_time,
+        )
     }
 
     /// Calculate when to post the announcement
-    fn announcement_time(&self) -> DateTime<Local> {
+    fn announcement_time(&self) -> DateTime<Tz> {
         self.next_event_time() - Duration::minutes(self.config.announce_minutes_before as i64)
     }
 
     /// Format event time in a friendly way (e.g., "Today at 7:00pm", "Tomorrow at 7:00pm", "Saturday at 7:00pm")
     fn format_friendly_time(&self) -> String {
         let event_datetime = self.next_event_time();
-        let now = Local::now();
+        let now = now_in(self.config.timezone);
         let today = now.date_naive();
         let event_date = event_datetime.date_naive();
 
@@ -142,7 +137,7 @@ impl WeeklyGathering {
     }
 
     /// Calculate when to finalize and post results
-    fn finalization_time(&self) -> DateTime<Local> {
+    fn finalization_time(&self) -> DateTime<Tz> {
         self.next_event_time() - Duration::minutes(self.config.finalize_minutes_before as i64)
     }
 
@@ -245,9 +240,13 @@ impl WeeklyGathering {
         let command = Command::SendRoomMessage {
             service_id: ServiceId(self.config.service_id.clone()),
             room_id: self.config.room_id.clone(),
-            body: message.clone(),
-            markdown_body: Some(message),
+            body: message,
+            format: BodyFormat::Markdown,
             response_tx: Some(response_tx),
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
         };
 
         self.cmd_tx.send(command).await?;
@@ -267,6 +266,7 @@ impl WeeklyGathering {
                         room_id: self.config.room_id.clone(),
                         event_id: message_id.clone(),
                         key: reaction_key.clone(),
+                        origin: None,
                     };
 
                     if let Err(e) = self.cmd_tx.send(command).await {
@@ -331,9 +331,13 @@ impl WeeklyGathering {
         let command = Command::SendRoomMessage {
             service_id: ServiceId(self.config.service_id.clone()),
             room_id: self.config.room_id.clone(),
-            body: message.clone(),
-            markdown_body: Some(message),
+            body: message,
+            format: BodyFormat::Markdown,
             response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
         };
 
         if let Err(e) = self.cmd_tx.send(command).await {
@@ -499,8 +503,9 @@ impl Middleware for WeeklyGathering {
             "weekly_gathering middleware running"
         );
 
+        let mut scheduler = self.scheduler.scheduler(self.config.timezone);
         loop {
-            let now = Local::now();
+            let now = now_in(self.config.timezone);
             let phase = {
                 let state = self.state.lock().await;
                 state.phase.clone()
@@ -509,7 +514,10 @@ impl Middleware for WeeklyGathering {
             // Calculate next action time based on current phase
             let (next_action_time, action_name) = match &phase {
                 GatheringPhase::Idle => {
-                    let announce_time = self.announcement_time();
+                    // Only the announcement waits out quiet hours; finalization is tied to the
+                    // event itself
+                    let announce_time =
+                        defer_past(self.quiet_hours.as_ref(), self.announcement_time());
                     (announce_time, "announce")
                 }
                 GatheringPhase::Announced { .. } => {
@@ -523,14 +531,14 @@ impl Middleware for WeeklyGathering {
                 }
             };
 
-            let duration_until =
-                (next_action_time - now).to_std().unwrap_or(std::time::Duration::from_secs(1));
+            // An action that's already due (e.g. an announcement that failed) is retried after
+            // a second rather than straight away
+            let next_action_time = next_action_time.max(now + Duration::seconds(1));
 
             tracing::debug!(
                 phase=?phase,
                 next_action=%action_name,
                 next_action_time=%next_action_time.format("%Y-%m-%d %H:%M:%S"),
-                duration_secs=%duration_until.as_secs(),
                 "waiting for next action"
             );
 
@@ -539,7 +547,7 @@ impl Middleware for WeeklyGathering {
                     tracing::info!("weekly_gathering middleware shutting down");
                     break;
                 }
-                _ = tokio::time::sleep(duration_until) => {
+                _ = scheduler.wait_until(&next_action_time) => {
                     match action_name {
                         "announce" => {
                             tracing::info!("posting weekly gathering announcement");
@@ -569,7 +577,7 @@ impl Middleware for WeeklyGathering {
         Ok(())
     }
 
-    fn on_event(&self, evt: &Event) -> Result<Verdict> {
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
         // Only process reaction events from the configured service
         if evt.service_id.0 != self.config.service_id {
             return Ok(Verdict::Continue);
@@ -0,0 +1,112 @@
+use crate::core::{
+    bus::{Command, CommandSender, ConnectedUser},
+    commands::{CommandRouter, CommandSpec, send_reply},
+    correlation,
+    event::{Event, EventKind},
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    service::ServiceId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+// How long to wait for the voice service to answer before giving up
+const USER_LIST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers `!who` with who's currently connected to a voice service, e.g. Mumble, grouped by
+/// channel, so people in a chat room can see whether it's worth hopping in.
+pub struct Who {
+    cmd_tx: CommandSender,
+    router: CommandRouter,
+    /// The voice service to ask.
+    service_id: ServiceId,
+}
+
+impl Who {
+    pub fn new(ctx: MiddlewareContext, command_string: String, service_id: ServiceId) -> Self {
+        let router =
+            CommandRouter::new(command_string).with_description("List who's currently in voice");
+        Self { cmd_tx: ctx.cmd_tx, router, service_id }
+    }
+}
+
+/// The reply listing `users`, e.g. `In voice: alice, bob (Lobby); carol (Games)`. The bot's own
+/// connection isn't listed.
+pub fn format_who(users: &[ConnectedUser]) -> String {
+    let mut channels: BTreeMap<Option<&str>, Vec<&str>> = BTreeMap::new();
+    for connected in users.iter().filter(|connected| !connected.user.is_self) {
+        channels
+            .entry(connected.room_id.as_deref())
+            .or_default()
+            .push(&connected.user.display_name);
+    }
+    if channels.is_empty() {
+        return "Nobody's in voice".to_string();
+    }
+    let listed: Vec<String> = channels
+        .into_iter()
+        .map(|(channel, mut names)| {
+            names.sort_by_key(|name| name.to_lowercase());
+            match channel {
+                Some(channel) => format!("{} ({channel})", names.join(", ")),
+                None => names.join(", "),
+            }
+        })
+        .collect();
+    format!("In voice: {}", listed.join("; "))
+}
+
+#[async_trait]
+impl Middleware for Who {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!(service_id=%self.service_id, "who middleware running...");
+        cancel.cancelled().await;
+        tracing::info!("who middleware shutting down...");
+        Ok(())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        vec![self.router.prefix()]
+    }
+
+    fn commands(&self) -> Vec<CommandSpec> {
+        vec![self.router.spec()]
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let (EventKind::DirectMessage { is_self, .. } | EventKind::RoomMessage { is_self, .. }) =
+            &evt.kind
+        else {
+            return Ok(Verdict::Continue);
+        };
+        if *is_self || self.router.route(evt, &self.cmd_tx).is_none() {
+            return Ok(Verdict::Continue);
+        }
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let command =
+            Command::GetUserList { service_id: self.service_id.clone(), response_tx, origin: None };
+        self.cmd_tx.spawn_send(command, "user list request");
+
+        let cmd_tx = self.cmd_tx.clone();
+        let evt = evt.clone();
+        let service_id = self.service_id.clone();
+        correlation::spawn(async move {
+            let reply = match tokio::time::timeout(USER_LIST_TIMEOUT, response_rx).await {
+                Ok(Ok(Ok(users))) => format_who(&users),
+                Ok(Ok(Err(e))) => {
+                    tracing::warn!(service_id=%service_id, error=%e, "failed to list users");
+                    format!("Couldn't see who's in voice: {e}")
+                }
+                Ok(Err(_)) | Err(_) => {
+                    tracing::warn!(service_id=%service_id, "no answer to user list request");
+                    format!("Couldn't see who's in voice: {service_id} didn't answer")
+                }
+            };
+            send_reply(&evt, reply, &cmd_tx);
+        });
+
+        Ok(Verdict::Continue)
+    }
+}
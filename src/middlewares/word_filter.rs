@@ -0,0 +1,63 @@
+use crate::core::{
+    event::Event,
+    middleware::{Middleware, Verdict},
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use regex::{Captures, Regex};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Masks words in message bodies with asterisks before the middlewares after it see them, so
+/// e.g. a `chatrelay` later in the pipeline forwards the masked text. Words match whole and
+/// ignoring case: filtering `heck` masks `Heck!` but not `check`.
+pub struct WordFilter {
+    // None when no words are configured
+    pattern: Option<Regex>,
+}
+
+impl WordFilter {
+    pub fn new(words: &[String]) -> Result<Self> {
+        let words: Vec<_> = words
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect();
+        if words.is_empty() {
+            return Ok(Self { pattern: None });
+        }
+        let pattern = Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))
+            .context("failed to build word filter pattern")?;
+        Ok(Self { pattern: Some(pattern) })
+    }
+
+    /// `body` with every filtered word masked, or `None` if it has none.
+    pub fn mask(&self, body: &str) -> Option<String> {
+        let pattern = self.pattern.as_ref()?;
+        if !pattern.is_match(body) {
+            return None;
+        }
+        let masked =
+            pattern.replace_all(body, |caps: &Captures| "*".repeat(caps[0].chars().count()));
+        Some(masked.into_owned())
+    }
+}
+
+#[async_trait]
+impl Middleware for WordFilter {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        tracing::info!("word filter running...");
+        cancel.cancelled().await;
+        tracing::info!("word filter shutting down...");
+        Ok(())
+    }
+
+    fn on_event(&self, evt: &Arc<Event>) -> Result<Verdict> {
+        let Some(masked) = evt.kind.message_body().and_then(|body| self.mask(body)) else {
+            return Ok(Verdict::Continue);
+        };
+        tracing::debug!(service_id=%evt.service_id, event_kind=evt.kind.name(), "masking filtered words");
+        Ok(Verdict::rewrite_body(evt, masked))
+    }
+}
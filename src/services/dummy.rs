@@ -1,17 +1,35 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 use crate::core::{
-    bus::Command,
+    bus::{Command, EventTx},
     event::{Event, EventKind},
-    service::{Service, ServiceId},
+    format::FormatProfile,
+    metrics::ServiceMetrics,
+    service::{Service, ServiceCapabilities, ServiceId},
 };
 
 pub struct DummyService {
     pub id: ServiceId,
     pub interval_ms: u64,
-    pub evt_tx: tokio::sync::mpsc::Sender<Event>,
+    pub evt_tx: EventTx,
+    pub metrics: Arc<dyn ServiceMetrics>,
+}
+
+impl DummyService {
+    // The dummy service accepts every command, so advertise everything
+    pub const CAPABILITIES: ServiceCapabilities = ServiceCapabilities {
+        supports_edit: true,
+        format: FormatProfile::Plain,
+        supports_invite_tokens: true,
+        supports_attachments: true,
+        supports_room_state: true,
+        supports_delete: true,
+        max_message_length: None,
+    };
 }
 
 #[async_trait::async_trait]
@@ -35,12 +53,17 @@ impl Service for DummyService {
                             sender_id: "dummy_user".into(),
                             sender_display_name: Some("Dummy User".into()),
                             is_self: false,
+                            mentions_self: false,
+                            relayed_from: None,
+                            thread_root_id: None,
+                            message_id: None,
                         }
                     };
                     if let Err(e) = self.evt_tx.send(msg).await {
                         tracing::error!(?e, "bus event receiver dropped");
                         break;
                     }
+                    self.metrics.message_received();
                 }
             }
         }
@@ -55,16 +78,21 @@ impl Service for DummyService {
                 if let Some(tx) = response_tx {
                     let _ = tx.send(Ok("dummy_message_id_dm".to_string()));
                 }
+                self.metrics.message_sent(std::time::Duration::ZERO);
             }
             Command::SendRoomMessage { room_id, body, response_tx, .. } => {
                 info!(service=%self.id, room_id=%room_id, body=%body, "dummy service: would send room message");
                 if let Some(tx) = response_tx {
                     let _ = tx.send(Ok("dummy_message_id_room".to_string()));
                 }
+                self.metrics.message_sent(std::time::Duration::ZERO);
             }
             Command::EditMessage { message_id, new_body, .. } => {
                 info!(service=%self.id, message_id=%message_id, new_body=%new_body, "dummy service: would edit message");
             }
+            Command::DeleteMessage { message_id, .. } => {
+                info!(service=%self.id, message_id=%message_id, "dummy service: would delete message");
+            }
             Command::GenerateInviteToken { user_id, uses_allowed, expiry, response_tx, .. } => {
                 info!(service=%self.id, user_id=%user_id, uses_allowed=?uses_allowed, expiry=?expiry, "dummy service: generating fake invite token");
                 // Send a fake token response
@@ -76,6 +104,7 @@ impl Service for DummyService {
                 if let Some(tx) = response_tx {
                     let _ = tx.send(Ok("dummy_message_id_thread_reply".to_string()));
                 }
+                self.metrics.message_sent(std::time::Duration::ZERO);
             }
             Command::AddReaction { room_id, event_id, key, .. } => {
                 info!(service=%self.id, room_id=%room_id, event_id=%event_id, key=%key,
@@ -84,7 +113,66 @@ impl Service for DummyService {
             Command::SendRoomImage { room_id, caption, .. } => {
                 info!(service=%self.id, room_id=%room_id, caption=%caption, "dummy service: would send room image");
             }
+            Command::GetRoomState { room_id, event_type, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, event_type=%event_type, "dummy service: no room state to read");
+                let _ = response_tx.send(Ok(None));
+            }
+            Command::SetRoomState { room_id, event_type, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, event_type=%event_type, "dummy service: would set room state");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok("dummy_state_event_id".to_string()));
+                }
+            }
+            Command::SetRoomTopic { room_id, topic, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, topic=%topic, "dummy service: would set room topic");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok("dummy_topic_event_id".to_string()));
+                }
+            }
+            Command::ApproveKnock { room_id, user_id, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, user_id=%user_id, "dummy service: would approve knock");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+            }
+            Command::KickUser { room_id, user_id, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, user_id=%user_id, "dummy service: would kick user");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+            }
+            Command::JoinRoom { room, response_tx, .. } => {
+                info!(service=%self.id, room=%room, "dummy service: would join room");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(room));
+                }
+            }
+            Command::ConfirmVerification { flow_id, response_tx, .. } => {
+                info!(service=%self.id, flow_id=%flow_id, "dummy service: no verification to answer");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow::anyhow!("no verification {flow_id} in progress")));
+                }
+            }
+            Command::Ping { response_tx, .. } => {
+                info!(service=%self.id, "dummy service: no server to ping, answering at once");
+                let _ = response_tx.send(Ok(std::time::Duration::ZERO));
+            }
+            Command::GetUserList { response_tx, .. } => {
+                info!(service=%self.id, "dummy service: nobody connected");
+                let _ = response_tx.send(Ok(Vec::new()));
+            }
+            command @ Command::Announce { .. } => {
+                info!(service=%self.id, "dummy service: announcements are expanded by the bus");
+                command.reject(anyhow::anyhow!("announcements are expanded by the bus"));
+            }
+            Command::Control(control) => {
+                info!(service=%self.id, control=?control, "dummy service: ignoring bus control command");
+            }
         }
         Ok(())
     }
+
+    fn capabilities(&self) -> ServiceCapabilities {
+        Self::CAPABILITIES
+    }
 }
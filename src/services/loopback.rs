@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::core::{
+    bus::{Command, ConnectedUser, EventTx},
+    commands::CommandSpec,
+    event::{Event, EventKind, Provenance, User},
+    format::FormatProfile,
+    idempotency::SeenKeys,
+    metrics::ServiceMetrics,
+    redact,
+    room_state::check_event_type,
+    service::{Service, ServiceCapabilities, ServiceId},
+};
+
+/// How a loopback service is scripted. See `ServiceKind::Loopback`.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackSettings {
+    pub users: Vec<String>,
+    pub rooms: Vec<String>,
+    pub inject_file: Option<PathBuf>,
+    pub transcript_file: Option<PathBuf>,
+    pub poll_interval: Duration,
+}
+
+/// One line of a loopback inject file.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Injection {
+    /// A message in `room`, or in the first configured room when omitted.
+    RoomMessage {
+        #[serde(default)]
+        room: Option<String>,
+        sender: String,
+        body: String,
+        /// Whether the message mentions the bot.
+        #[serde(default)]
+        mentions_self: bool,
+        /// The relay marker another bridge would have attached to the message.
+        #[serde(default)]
+        relayed_from: Option<Provenance>,
+        /// ID of the message starting the thread it's posted in.
+        #[serde(default)]
+        thread: Option<String>,
+        /// The message's own ID, e.g. to start a thread later messages are posted in.
+        #[serde(default)]
+        id: Option<String>,
+    },
+    DirectMessage {
+        sender: String,
+        body: String,
+    },
+    /// A user arrives; emits an updated user list.
+    Join {
+        user: String,
+    },
+    /// A user leaves; emits an updated user list.
+    Leave {
+        user: String,
+    },
+    /// A user asks to join `room`, or the first configured room when omitted.
+    Knock {
+        #[serde(default)]
+        room: Option<String>,
+        user: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// A native invocation of a registered command in `room`, like a Discord slash command,
+    /// e.g. `{"command": "bus", "subcommand": "pause", "args": ["mumble"]}`.
+    Command {
+        #[serde(default)]
+        room: Option<String>,
+        sender: String,
+        command: String,
+        #[serde(default)]
+        subcommand: Option<String>,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// The optional parts of an injected room message.
+#[derive(Default)]
+struct InjectedMessage {
+    mentions_self: bool,
+    relayed_from: Option<Provenance>,
+    thread: Option<String>,
+    id: Option<String>,
+}
+
+/// An in-process service with canned users and rooms, driven by lines appended to a file.
+///
+/// Lets full flows (relays, attendance sessions, commands) run without a chat server. What
+/// the bot sends is logged and, if configured, appended to a transcript file as JSON lines.
+pub struct LoopbackService {
+    id: ServiceId,
+    evt_tx: EventTx,
+    metrics: Arc<dyn ServiceMetrics>,
+    settings: LoopbackSettings,
+    // Users currently present, in join order
+    users: Mutex<Vec<String>>,
+    // How far into the inject file we've read; kept across restarts so nothing replays
+    inject_offset: AtomicU64,
+    next_message_id: AtomicU64,
+    // Custom state events by (room, event type, state key), kept in memory only
+    room_state: Mutex<HashMap<(String, String, String), serde_json::Value>>,
+    // So a message sent twice with the same idempotency key is recorded once
+    seen_keys: SeenKeys,
+    // Commands registered by the bus, which `Injection::Command` invokes by name
+    commands: Mutex<Vec<CommandSpec>>,
+}
+
+impl LoopbackService {
+    // Everything is recorded rather than delivered, so advertise everything
+    pub const CAPABILITIES: ServiceCapabilities = ServiceCapabilities {
+        supports_edit: true,
+        format: FormatProfile::Plain,
+        supports_invite_tokens: true,
+        supports_attachments: true,
+        supports_room_state: true,
+        supports_delete: true,
+        max_message_length: None,
+    };
+
+    pub fn new(
+        id: ServiceId,
+        evt_tx: EventTx,
+        metrics: Arc<dyn ServiceMetrics>,
+        settings: LoopbackSettings,
+    ) -> Self {
+        Self {
+            id,
+            evt_tx,
+            metrics,
+            users: Mutex::new(settings.users.clone()),
+            settings,
+            inject_offset: AtomicU64::new(0),
+            next_message_id: AtomicU64::new(1),
+            room_state: Mutex::default(),
+            seen_keys: SeenKeys::default(),
+            commands: Mutex::default(),
+        }
+    }
+
+    async fn emit(&self, kind: EventKind) -> Result<()> {
+        self.evt_tx
+            .send(Event { service_id: self.id.clone(), kind })
+            .await
+            .map_err(|_| anyhow!("bus event receiver dropped"))
+    }
+
+    fn users(&self) -> Vec<User> {
+        self.users
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|name| User {
+                id: name.clone(),
+                username: name.clone(),
+                display_name: name.clone(),
+                is_active: true,
+                is_self: false,
+                comment: None,
+                avatar: None,
+            })
+            .collect()
+    }
+
+    async fn emit_user_list(&self) -> Result<()> {
+        self.emit(EventKind::UserListUpdate { users: self.users() }).await
+    }
+
+    async fn inject(&self, injection: Injection) -> Result<()> {
+        match injection {
+            Injection::RoomMessage {
+                room,
+                sender,
+                body,
+                mentions_self,
+                relayed_from,
+                thread,
+                id,
+            } => {
+                let message = InjectedMessage { mentions_self, relayed_from, thread, id };
+                self.inject_room_message(room, sender, body, message).await?;
+            }
+            Injection::DirectMessage { sender, body } => {
+                self.emit(EventKind::DirectMessage {
+                    user_id: sender.clone(),
+                    body,
+                    is_local_user: false,
+                    sender_id: sender.clone(),
+                    sender_display_name: Some(sender),
+                    is_self: false,
+                })
+                .await?;
+                self.metrics.message_received();
+            }
+            Injection::Join { user } => {
+                {
+                    let mut users =
+                        self.users.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if users.contains(&user) {
+                        return Ok(());
+                    }
+                    users.push(user);
+                }
+                self.emit_user_list().await?;
+            }
+            Injection::Leave { user } => {
+                {
+                    let mut users =
+                        self.users.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    let before = users.len();
+                    users.retain(|present| *present != user);
+                    if users.len() == before {
+                        return Ok(());
+                    }
+                }
+                self.emit_user_list().await?;
+            }
+            Injection::Knock { room, user, reason } => {
+                let Some(room_id) = room.or_else(|| self.settings.rooms.first().cloned()) else {
+                    warn!(service=%self.id, "loopback: knock names no room and none are configured");
+                    return Ok(());
+                };
+                self.emit(EventKind::Knock {
+                    room_id,
+                    user_id: user.clone(),
+                    sender_display_name: Some(user),
+                    reason,
+                    is_self: false,
+                })
+                .await?;
+            }
+            Injection::Command { room, sender, command, subcommand, args } => {
+                let body = {
+                    let commands =
+                        self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    let Some(spec) = commands.iter().find(|spec| spec.name() == command) else {
+                        warn!(service=%self.id, command=%command, "loopback: ignoring unregistered command");
+                        return Ok(());
+                    };
+                    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                    spec.message_text(subcommand.as_deref(), &args)
+                };
+                self.inject_room_message(room, sender, body, InjectedMessage::default()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn inject_room_message(
+        &self,
+        room: Option<String>,
+        sender: String,
+        body: String,
+        message: InjectedMessage,
+    ) -> Result<()> {
+        let InjectedMessage { mentions_self, relayed_from, thread, id } = message;
+        let Some(room_id) = room.or_else(|| self.settings.rooms.first().cloned()) else {
+            warn!(service=%self.id, "loopback: room message names no room and none are configured");
+            return Ok(());
+        };
+        if !self.settings.rooms.is_empty() && !self.settings.rooms.contains(&room_id) {
+            warn!(service=%self.id, room_id=%room_id, "loopback: ignoring message for unknown room");
+            return Ok(());
+        }
+        self.emit(EventKind::RoomMessage {
+            room_id,
+            body,
+            is_local_user: false,
+            sender_id: sender.clone(),
+            sender_display_name: Some(sender),
+            is_self: false,
+            mentions_self,
+            relayed_from,
+            thread_root_id: thread,
+            message_id: id,
+        })
+        .await?;
+        self.metrics.message_received();
+        Ok(())
+    }
+
+    /// Injects every complete line appended to the inject file since the last poll.
+    async fn poll_inject_file(&self, path: &Path) -> Result<()> {
+        let offset = self.inject_offset.load(Ordering::Relaxed);
+        let Some((lines, consumed)) = read_new_lines(path, offset)? else {
+            // The file was truncated; start over from its beginning
+            self.inject_offset.store(0, Ordering::Relaxed);
+            return Ok(());
+        };
+        self.inject_offset.store(offset + consumed, Ordering::Relaxed);
+
+        for line in lines {
+            match serde_json::from_str::<Injection>(&line) {
+                Ok(injection) => self.inject(injection).await?,
+                Err(e) => {
+                    warn!(service=%self.id, error=%e, line=%line, "loopback: invalid injection")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn message_id(&self) -> String {
+        format!("$loopback-{}", self.next_message_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn record(&self, entry: serde_json::Value) {
+        let entry = redact::redact(&entry.to_string());
+        info!(service=%self.id, sent=%entry, "loopback: bot sent");
+        let Some(path) = &self.settings.transcript_file else { return };
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{entry}"));
+        if let Err(e) = result {
+            warn!(service=%self.id, error=%e, path=%path.display(), "loopback: could not write transcript");
+        }
+    }
+}
+
+/// Complete lines after `offset` and the number of bytes they span, or `None` if the file is
+/// now shorter than `offset`. A missing file has no lines yet.
+fn read_new_lines(path: &Path, offset: u64) -> Result<Option<(Vec<String>, u64)>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some((Vec::new(), 0))),
+        Err(e) => return Err(e.into()),
+    };
+    if file.metadata()?.len() < offset {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut appended = String::new();
+    file.read_to_string(&mut appended)?;
+
+    // Leave a partially written last line for the next poll
+    let Some(end) = appended.rfind('\n') else { return Ok(Some((Vec::new(), 0))) };
+    let lines = appended[..end]
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok(Some((lines, end as u64 + 1)))
+}
+
+#[async_trait::async_trait]
+impl Service for LoopbackService {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        info!(service=%self.id, "loopback service running");
+        self.emit_user_list().await?;
+
+        let mut poll = tokio::time::interval(self.settings.poll_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    info!(service=%self.id, "shutdown requested");
+                    break;
+                }
+                _ = poll.tick(), if self.settings.inject_file.is_some() => {
+                    if let Some(path) = &self.settings.inject_file {
+                        self.poll_inject_file(path).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&self, command: Command) -> Result<()> {
+        let Some(command) = self.seen_keys.screen(command) else {
+            return Ok(());
+        };
+        match command {
+            Command::SendDirectMessage { user_id, body, response_tx, idempotency_key, .. } => {
+                let id = self.message_id();
+                self.record(
+                    json!({ "type": "direct_message", "id": id, "user": user_id, "body": body }),
+                );
+                self.seen_keys.record(idempotency_key.as_deref(), &Ok(id.clone()));
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(id));
+                }
+                self.metrics.message_sent(Duration::ZERO);
+            }
+            Command::SendRoomMessage {
+                room_id,
+                body,
+                format,
+                response_tx,
+                idempotency_key,
+                relayed_from,
+                ..
+            } => {
+                let id = self.message_id();
+                let mut record = json!({
+                    "type": "room_message",
+                    "id": id,
+                    "room": room_id,
+                    "body": body,
+                    "format": format,
+                });
+                if let Some(provenance) = relayed_from {
+                    record["relayed_from"] = json!(provenance);
+                }
+                self.record(record);
+                self.seen_keys.record(idempotency_key.as_deref(), &Ok(id.clone()));
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(id));
+                }
+                self.metrics.message_sent(Duration::ZERO);
+            }
+            Command::SendThreadReply {
+                room_id,
+                thread_root_id,
+                body,
+                format,
+                response_tx,
+                idempotency_key,
+                ..
+            } => {
+                let id = self.message_id();
+                self.record(json!({
+                    "type": "thread_reply",
+                    "id": id,
+                    "room": room_id,
+                    "thread_root": thread_root_id,
+                    "body": body,
+                    "format": format,
+                }));
+                self.seen_keys.record(idempotency_key.as_deref(), &Ok(id.clone()));
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(id));
+                }
+                self.metrics.message_sent(Duration::ZERO);
+            }
+            Command::EditMessage { message_id, new_body, format, .. } => {
+                self.record(
+                    json!({ "type": "edit", "id": message_id, "body": new_body, "format": format }),
+                );
+            }
+            Command::DeleteMessage { message_id, .. } => {
+                self.record(json!({ "type": "delete", "id": message_id }));
+            }
+            Command::GenerateInviteToken { user_id, response_tx, .. } => {
+                self.record(json!({ "type": "invite_token", "user": user_id }));
+                let _ = response_tx.send(Ok("LOOPBACK_TOKEN".to_string()));
+            }
+            Command::AddReaction { room_id, event_id, key, .. } => {
+                self.record(
+                    json!({ "type": "reaction", "room": room_id, "target": event_id, "key": key }),
+                );
+            }
+            Command::SendRoomImage { room_id, caption, source_url, .. } => {
+                self.record(json!({ "type": "image", "room": room_id, "caption": caption, "url": source_url }));
+            }
+            Command::GetRoomState { room_id, event_type, state_key, response_tx, .. } => {
+                let result = check_event_type(&event_type).map(|()| {
+                    self.room_state
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .get(&(room_id, event_type, state_key))
+                        .cloned()
+                });
+                let _ = response_tx.send(result);
+            }
+            Command::SetRoomState {
+                room_id, event_type, state_key, content, response_tx, ..
+            } => {
+                let result = check_event_type(&event_type).map(|()| {
+                    let id = self.message_id();
+                    self.record(json!({
+                        "type": "room_state",
+                        "id": id,
+                        "room": room_id,
+                        "event_type": event_type,
+                        "state_key": state_key,
+                        "content": content,
+                    }));
+                    self.room_state
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .insert((room_id, event_type, state_key), content);
+                    id
+                });
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                }
+            }
+            Command::SetRoomTopic { room_id, topic, response_tx, .. } => {
+                let id = self.message_id();
+                self.record(
+                    json!({ "type": "room_topic", "id": id, "room": room_id, "topic": topic }),
+                );
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(id));
+                }
+            }
+            Command::ApproveKnock { room_id, user_id, response_tx, .. } => {
+                self.record(json!({ "type": "approve_knock", "room": room_id, "user": user_id }));
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+            }
+            Command::KickUser { room_id, user_id, reason, response_tx, .. } => {
+                self.record(json!({
+                    "type": "kick_user",
+                    "room": room_id,
+                    "user": user_id,
+                    "reason": reason,
+                }));
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(String::new()));
+                }
+            }
+            Command::JoinRoom { room, response_tx, .. } => {
+                self.record(json!({ "type": "join_room", "room": room }));
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok(room));
+                }
+            }
+            // Nothing to verify in-process
+            Command::ConfirmVerification { flow_id, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow!("no verification {flow_id} in progress")));
+                }
+            }
+            // Everything is in-process, so the round trip is instant
+            Command::Ping { response_tx, .. } => {
+                self.record(json!({ "type": "ping" }));
+                let _ = response_tx.send(Ok(Duration::ZERO));
+            }
+            Command::GetUserList { response_tx, .. } => {
+                self.record(json!({ "type": "get_user_list" }));
+                let users = self
+                    .users()
+                    .into_iter()
+                    .map(|user| ConnectedUser { user, room_id: None })
+                    .collect();
+                let _ = response_tx.send(Ok(users));
+            }
+            command @ Command::Announce { .. } => {
+                command.reject(anyhow!("announcements are expanded by the bus"));
+            }
+            Command::Control(control) => {
+                info!(service=%self.id, control=?control, "loopback service: ignoring bus control command");
+            }
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> ServiceCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn register_commands(&self, commands: Vec<CommandSpec>) -> Result<()> {
+        let names: Vec<&str> = commands.iter().map(CommandSpec::name).collect();
+        self.record(json!({"type": "register_commands", "commands": names}));
+        *self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = commands;
+        Ok(())
+    }
+}
@@ -1,36 +1,59 @@
-use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Result, bail};
 use matrix_sdk::{
     Client, Room, RoomMemberships, RoomState,
     config::SyncSettings,
-    encryption::{self, EncryptionSettings},
+    deserialized_responses::RawAnySyncOrStrippedState,
+    encryption::{self, EncryptionSettings, verification::SasVerification},
+    room::MessagesOptions,
     ruma::{
-        RoomId, UserId,
+        EventId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, RoomOrAliasId, UserId,
+        api::client::receipt::create_receipt::v3::ReceiptType as SendReceiptType,
         events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, StateEventType, SyncMessageLikeEvent,
             reaction::OriginalSyncReactionEvent,
+            receipt::{ReceiptThread, ReceiptType},
             room::{
                 member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
                 message::{
-                    MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
-                    TextMessageEventContent,
+                    MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
                 },
+                power_levels::{RoomPowerLevels, SyncRoomPowerLevelsEvent},
                 redaction::OriginalSyncRoomRedactionEvent,
+                tombstone::OriginalSyncRoomTombstoneEvent,
             },
         },
+        serde::Raw,
     },
 };
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, oneshot, watch};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 use url::Url;
 
 use crate::core::{
-    bus::Command,
-    event::{Event, EventKind},
-    service::{Service, ServiceId},
+    bus::{
+        Command, EventSender, EventTx, NotEncrypted, OverflowPolicy, PermissionDenied,
+        transient_error,
+    },
+    event::{Event, EventKind, MissedMessage, Provenance},
+    format::{BodyFormat, FormatProfile, render},
+    http::HttpClient,
+    metrics::ServiceMetrics,
+    room_state::check_event_type,
+    service::{Service, ServiceCapabilities, ServiceId},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -48,18 +71,152 @@ struct ReactionInfo {
     key: String,
 }
 
+// Sync handlers must return promptly or the SDK stalls, so only wait briefly on a full bus
+const EVENT_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::WaitFor(Duration::from_millis(500));
+
+// How long verification may take when someone has to confirm the emoji over chat
+const VERIFICATION_APPROVAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Timeline events looked through per room for the last processed one before giving up
+const CATCH_UP_SCAN_LIMIT: usize = 500;
+
+// Upgrades followed when redirecting a command from an old room to its latest replacement
+const MAX_ROOM_UPGRADES_FOLLOWED: usize = 8;
+
+/// Message content field holding the `Provenance` of a relayed message. Clients ignore fields
+/// they don't know, so the marker stays hidden.
+pub const RELAY_MARKER_FIELD: &str = "org.kelvinbot.relayed_from";
+
 pub struct MatrixService {
     id: ServiceId,
     user_id: MatrixUserId,
     password: SecretString,
     device_id: String,
     verification_device_id: Option<String>,
-    evt_tx: tokio::sync::mpsc::Sender<Event>,
+    // Wait for a `ConfirmVerification` command instead of confirming the emoji on our side
+    verification_approval: bool,
+    // The verification waiting on a `ConfirmVerification` command: its flow ID and where the
+    // answer goes
+    pending_verification: Mutex<Option<(String, oneshot::Sender<bool>)>>,
+    evt_tx: EventSender,
     client: Client,
+    // For the Synapse admin API, which the Matrix client doesn't cover
+    http: HttpClient,
     reaction_registry: Arc<Mutex<HashMap<String, ReactionInfo>>>,
+    // Power levels of the rooms moderation was tried in, dropped when they change
+    power_levels: Arc<Mutex<HashMap<OwnedRoomId, RoomPowerLevels>>>,
+    metrics: Arc<dyn ServiceMetrics>,
+    // Set after the first run so later runs are counted as reconnects
+    has_run: AtomicBool,
+    // True once logged in, synced and set up for encryption
+    ready: watch::Sender<bool>,
+}
+
+/// Where a Matrix service keeps its sqlite store: `<data_directory>/matrix/<store_subdir>`,
+/// with `store_subdir` defaulting to the service ID.
+pub fn store_path(
+    data_directory: &Path,
+    service_id: &ServiceId,
+    store_subdir: Option<&str>,
+) -> PathBuf {
+    data_directory.join("matrix").join(store_subdir.unwrap_or(&service_id.0))
+}
+
+/// Whether a message mentions `bot`. Clients that send intentional mentions (`m.mentions`) say
+/// so explicitly; for older ones, fall back to the bot's user ID appearing in the text or in a
+/// pill's link.
+fn mentions_bot(content: &RoomMessageEventContent, bot: &UserId) -> bool {
+    if let Some(mentions) = &content.mentions {
+        return mentions.user_ids.iter().any(|user_id| user_id == bot);
+    }
+    match &content.msgtype {
+        MessageType::Text(text) => {
+            text.body.contains(bot.as_str())
+                || text.formatted.as_ref().is_some_and(|html| html.body.contains(bot.as_str()))
+        }
+        _ => false,
+    }
+}
+
+/// `event` as a missed message, if it's a text message someone other than the bot posted.
+async fn missed_message(
+    room: &Room,
+    event: &AnySyncTimelineEvent,
+    raw: &Raw<AnySyncTimelineEvent>,
+    bot: &UserId,
+) -> Option<MissedMessage> {
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(message),
+    )) = event
+    else {
+        return None;
+    };
+    let MessageType::Text(text) = &message.content.msgtype else {
+        return None;
+    };
+    if message.sender == bot {
+        return None;
+    }
+    let sender_display_name = room
+        .get_member_no_sync(&message.sender)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|member| member.display_name().map(str::to_string));
+    Some(MissedMessage {
+        sender_id: message.sender.to_string(),
+        sender_display_name,
+        body: text.body.clone(),
+        relayed_from: relay_marker(raw),
+    })
+}
+
+/// The relay marker on a received message, if it has a well-formed one.
+fn relay_marker(raw: &Raw<AnySyncTimelineEvent>) -> Option<Provenance> {
+    let content = raw.get_field::<serde_json::Map<String, serde_json::Value>>("content").ok()??;
+    serde_json::from_value(content.get(RELAY_MARKER_FIELD)?.clone()).ok()
+}
+
+/// A text message of `body`, with an HTML rendering alongside it if it's Markdown.
+fn text_content(body: &str, format: BodyFormat) -> RoomMessageEventContent {
+    match format {
+        BodyFormat::Plain => RoomMessageEventContent::text_plain(body),
+        BodyFormat::Markdown => RoomMessageEventContent::text_html(
+            render(body, format, FormatProfile::Plain),
+            render(body, format, FormatProfile::MatrixHtml),
+        ),
+    }
+}
+
+/// `content` as raw JSON with `provenance` attached as the relay marker.
+fn with_relay_marker(
+    content: &RoomMessageEventContent,
+    provenance: &Provenance,
+) -> serde_json::Result<serde_json::Value> {
+    let mut value = serde_json::to_value(content)?;
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert(RELAY_MARKER_FIELD.to_string(), serde_json::to_value(provenance)?);
+    }
+    Ok(value)
+}
+
+/// The transaction ID to send a message with. The homeserver answers a repeated transaction ID
+/// with the event it already created, so an idempotency key dedupes across retries.
+fn transaction_id(idempotency_key: Option<&str>) -> Option<OwnedTransactionId> {
+    idempotency_key.map(OwnedTransactionId::from)
 }
 
 impl MatrixService {
+    pub const CAPABILITIES: ServiceCapabilities = ServiceCapabilities {
+        supports_edit: true,
+        format: FormatProfile::MatrixHtml,
+        supports_invite_tokens: true,
+        supports_attachments: false, // SendRoomImage is not implemented yet
+        supports_room_state: true,
+        supports_delete: true,
+        max_message_length: None,
+    };
+
     #[allow(clippy::too_many_arguments)] // TODO: make this less gross
     pub async fn create(
         id: ServiceId,
@@ -67,15 +224,15 @@ impl MatrixService {
         user_id: MatrixUserId,
         password: SecretString,
         device_id: String,
-        evt_tx: tokio::sync::mpsc::Sender<Event>,
-        data_directory: PathBuf,
+        evt_tx: EventTx,
+        sqlite_path: PathBuf,
         db_passphrase: SecretString,
         verification_device_id: Option<String>,
+        verification_approval: bool,
+        metrics: Arc<dyn ServiceMetrics>,
+        http: HttpClient,
     ) -> Result<Self> {
         // Create storage directory
-        let mut sqlite_path = data_directory.clone();
-        sqlite_path.push("matrix");
-        sqlite_path.push(id.to_string());
         std::fs::create_dir_all(&sqlite_path).expect("Failed to create storage directory");
 
         let client = Client::builder()
@@ -97,9 +254,16 @@ impl MatrixService {
             password,
             device_id,
             verification_device_id,
-            evt_tx,
+            verification_approval,
+            pending_verification: Mutex::new(None),
+            evt_tx: EventSender::new(evt_tx, EVENT_OVERFLOW_POLICY),
             client,
+            http,
             reaction_registry,
+            power_levels: Arc::default(),
+            metrics,
+            has_run: AtomicBool::new(false),
+            ready: watch::channel(false).0,
         })
     }
 
@@ -165,9 +329,14 @@ impl MatrixService {
                 info!("verification request sent, waiting for acceptance...");
 
                 // Wait for the request to transition to "ready" state
-                use tokio::time::{Duration, timeout};
+                use tokio::time::timeout;
 
-                let result = timeout(Duration::from_secs(120), async {
+                let limit = if self.verification_approval {
+                    VERIFICATION_APPROVAL_TIMEOUT
+                } else {
+                    Duration::from_secs(120)
+                };
+                let result = timeout(limit, async {
                     loop {
                         if verification_request.is_ready() {
                             break;
@@ -204,19 +373,31 @@ impl MatrixService {
                     info!("SAS verification accepted, waiting for key agreement...");
 
                     // Wait for emoji/decimal to be ready
-                    loop {
+                    let emoji = loop {
                         if let Some(emoji) = sas.emoji() {
                             info!("Emoji verification codes: {:?}", emoji);
                             info!("Please confirm these emojis match on the other device and accept there");
-                            break;
+                            break emoji;
                         }
                         if sas.is_cancelled() || sas.is_done() {
                             bail!("SAS verification was cancelled");
                         }
                         tokio::time::sleep(Duration::from_millis(500)).await;
+                    };
+
+                    if self.verification_approval {
+                        let emoji = emoji
+                            .iter()
+                            .map(|emoji| format!("{} {}", emoji.symbol, emoji.description))
+                            .collect();
+                        if !self.wait_for_approval(&sas, target_device_id, emoji).await? {
+                            sas.cancel().await?;
+                            bail!("the emoji were denied");
+                        }
+                        info!("emoji confirmed over chat");
                     }
 
-                    // Auto-confirm on this side
+                    // Confirm on this side
                     sas.confirm().await?;
                     info!("confirmed on bot side, waiting for other device to confirm...");
 
@@ -243,7 +424,7 @@ impl MatrixService {
                         bail!("verification failed: {}", e);
                     }
                     Err(_) => {
-                        bail!("verification timed out after 120 seconds");
+                        bail!("verification timed out after {} seconds", limit.as_secs());
                     }
                 }
             } else {
@@ -256,6 +437,43 @@ impl MatrixService {
         Ok(())
     }
 
+    /// Reports the emoji of `sas` as an `EventKind::VerificationRequested` and waits for the
+    /// `Command::ConfirmVerification` answering it, e.g. from an admin over chat. Returns
+    /// whether they were confirmed.
+    async fn wait_for_approval(
+        &self,
+        sas: &SasVerification,
+        other_device_id: &str,
+        emoji: Vec<String>,
+    ) -> Result<bool> {
+        let flow_id = sas.flow_id().to_string();
+        let (answer_tx, answer_rx) = oneshot::channel();
+        *self.pending_verification.lock().await = Some((flow_id.clone(), answer_tx));
+
+        let event = Event {
+            service_id: self.id.clone(),
+            kind: EventKind::VerificationRequested {
+                flow_id,
+                other_device_id: other_device_id.to_string(),
+                emoji,
+            },
+        };
+        if self.evt_tx.send(event).await.is_err() {
+            bail!("could not report the emoji to compare");
+        }
+        info!("waiting for the emoji to be confirmed over chat...");
+
+        let cancelled = async {
+            while !sas.is_cancelled() {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+        tokio::select! {
+            answer = answer_rx => Ok(answer?),
+            _ = cancelled => bail!("SAS verification was cancelled"),
+        }
+    }
+
     async fn find_or_create_dm(&self, user_id: &UserId) -> Result<Room, matrix_sdk::Error> {
         // First, try to find existing DM room
         for room in self.client.rooms() {
@@ -344,11 +562,8 @@ impl MatrixService {
                 + expiry_duration.as_millis() as u64;
         body.insert("expiry_time".to_string(), serde_json::json!(expiry_ms));
 
-        // Create HTTP client
-        let http_client = reqwest::Client::new();
-
         // Call the admin API
-        let response = http_client.post(&url).bearer_auth(access_token).json(&body).send().await?;
+        let response = self.http.post(&url).bearer_auth(access_token).json(&body).send().await?;
 
         if !response.status().is_success() {
             let status = response.status();
@@ -366,6 +581,243 @@ impl MatrixService {
         Ok(token)
     }
 
+    fn joined_room(&self, room_id: &str) -> Result<Room> {
+        let room_id =
+            RoomId::parse(room_id).map_err(|e| anyhow::anyhow!("invalid room ID: {e}"))?;
+        self.current_room(&room_id).ok_or_else(|| anyhow::anyhow!("room not found or not joined"))
+    }
+
+    /// The room to post to in place of `room_id`: the room itself, or the one that replaced it
+    /// if it was upgraded and the bot followed. Lets configs naming the old room keep working.
+    fn current_room(&self, room_id: &RoomId) -> Option<Room> {
+        let mut room = self.client.get_room(room_id)?;
+        // Bounded in case a misbehaving server reports a cycle of upgrades
+        for _ in 0..MAX_ROOM_UPGRADES_FOLLOWED {
+            let Some(successor) = room.successor_room() else {
+                break;
+            };
+            match self.client.get_room(&successor.room_id) {
+                Some(next) if next.state() == RoomState::Joined => room = next,
+                _ => break,
+            }
+        }
+        Some(room)
+    }
+
+    /// Whether `room` is end-to-end encrypted. Unknown counts as not, since it's asked before
+    /// sending secrets.
+    async fn is_encrypted(&self, room: &Room) -> bool {
+        match room.latest_encryption_state().await {
+            Ok(state) => state.is_encrypted(),
+            Err(e) => {
+                warn!(room_id=%room.room_id(), error=%e, "failed to check room encryption");
+                false
+            }
+        }
+    }
+
+    /// The joined room holding `event_id`, found by asking each joined room for it.
+    async fn room_with_event(&self, event_id: &EventId) -> Option<Room> {
+        for room in self.client.rooms() {
+            if room.state() == RoomState::Joined && room.event(event_id, None).await.is_ok() {
+                return Some(room);
+            }
+        }
+        None
+    }
+
+    /// Content of one of the bot's custom state events in a room, as of the last sync.
+    async fn room_state(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        check_event_type(event_type)?;
+        let room = self.joined_room(room_id)?;
+        let content = match room.get_state_event(event_type.into(), state_key).await? {
+            Some(RawAnySyncOrStrippedState::Sync(raw)) => raw.get_field("content")?,
+            Some(RawAnySyncOrStrippedState::Stripped(raw)) => raw.get_field("content")?,
+            None => None,
+        };
+        Ok(content)
+    }
+
+    async fn set_room_state(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        state_key: &str,
+        content: serde_json::Value,
+    ) -> Result<String> {
+        check_event_type(event_type)?;
+        let room = self.joined_room(room_id)?;
+        // Fails for good if the bot lacks the power level, so not marked transient
+        let response = room.send_state_event_raw(event_type, state_key, content).await?;
+        Ok(response.event_id.to_string())
+    }
+
+    /// Reports the messages each joined room got since the last one the bot processed, so
+    /// relays can catch up after downtime. Where it left off is kept as the bot's private read
+    /// receipt, which is moved to the latest message afterwards.
+    async fn catch_up(&self) {
+        for room in self.client.joined_rooms() {
+            if let Err(e) = self.catch_up_room(&room).await {
+                warn!(room_id=%room.room_id(), error=%e, "failed to catch up on missed messages");
+            }
+        }
+    }
+
+    async fn catch_up_room(&self, room: &Room) -> Result<()> {
+        let bot_user_id = self.client.user_id().expect("client should have user_id after login");
+        let Some((last_processed, _)) = room
+            .load_user_receipt(ReceiptType::ReadPrivate, ReceiptThread::Unthreaded, bot_user_id)
+            .await?
+        else {
+            // Nothing processed here yet, so nothing to catch up on
+            return Ok(());
+        };
+        if room.is_direct().await? {
+            return Ok(());
+        }
+
+        let mut messages = Vec::new();
+        let mut latest = None;
+        let mut scanned = 0;
+        let mut from: Option<String> = None;
+        let truncated = 'scan: loop {
+            let page = room.messages(MessagesOptions::backward().from(from.as_deref())).await?;
+            for event in &page.chunk {
+                let raw = event.raw();
+                let Ok(event) = raw.deserialize() else {
+                    continue;
+                };
+                if event.event_id() == &*last_processed {
+                    break 'scan false;
+                }
+                latest.get_or_insert_with(|| event.event_id().to_owned());
+                if let Some(message) = missed_message(room, &event, raw, bot_user_id).await {
+                    messages.push(message);
+                }
+                scanned += 1;
+                if scanned >= CATCH_UP_SCAN_LIMIT {
+                    break 'scan true;
+                }
+            }
+            match page.end {
+                Some(end) => from = Some(end),
+                // Reached the start of the room
+                None => break false,
+            }
+        };
+        let Some(latest) = latest else {
+            return Ok(());
+        };
+
+        if !messages.is_empty() || truncated {
+            messages.reverse();
+            info!(room_id=%room.room_id(), missed=messages.len(), truncated, "reporting missed messages");
+            self.evt_tx
+                .send(Event {
+                    service_id: self.id.clone(),
+                    kind: EventKind::MissedMessages {
+                        room_id: room.room_id().to_string(),
+                        messages,
+                        truncated,
+                    },
+                })
+                .await?;
+        }
+        room.send_single_receipt(SendReceiptType::ReadPrivate, ReceiptThread::Unthreaded, latest)
+            .await?;
+        Ok(())
+    }
+
+    /// Joins `room` (an ID or alias), or knocks on it if it only lets people in on request.
+    /// Returns the room's ID.
+    async fn join_or_knock(&self, room: &str, reason: Option<String>) -> Result<String> {
+        let room = RoomOrAliasId::parse(room)
+            .map_err(|e| anyhow::anyhow!("invalid room ID or alias: {e}"))?;
+        let join_error = match self.client.join_room_by_id_or_alias(&room, &[]).await {
+            Ok(joined) => return Ok(joined.room_id().to_string()),
+            Err(e) => e,
+        };
+        // Knock and restricted rooms refuse a plain join from outsiders, but may take a knock
+        let knocked = match self.client.knock(room.clone(), reason.clone(), Vec::new()).await {
+            Ok(knocked) => knocked,
+            Err(knock_error) => {
+                bail!("failed to join {room} ({join_error}) or knock on it ({knock_error})")
+            }
+        };
+        let room_id = knocked.room_id().to_string();
+        info!(service=%self.id, room_id=%room_id, "knocked on room, waiting to be let in");
+        self.evt_tx
+            .send(Event {
+                service_id: self.id.clone(),
+                kind: EventKind::Knock {
+                    room_id: room_id.clone(),
+                    user_id: self.user_id.0.clone(),
+                    sender_display_name: None,
+                    reason,
+                    is_self: true,
+                },
+            })
+            .await?;
+        Ok(room_id)
+    }
+
+    /// Lets in `user_id`, who knocked on `room_id`, by inviting them.
+    async fn approve_knock(&self, room_id: &str, user_id: &str) -> Result<()> {
+        let room = self.joined_room(room_id)?;
+        let user_id =
+            UserId::parse(user_id).map_err(|e| anyhow::anyhow!("invalid user ID: {e}"))?;
+        room.invite_user_by_id(&user_id).await?;
+        Ok(())
+    }
+
+    /// Replaces the topic of `room_id`, which needs the power level to change it.
+    async fn set_room_topic(&self, room_id: &str, topic: &str) -> Result<String> {
+        let room = self.joined_room(room_id)?;
+        self.require_power(&room, "change the topic", |levels, user_id| {
+            levels.user_can_send_state(user_id, StateEventType::RoomTopic)
+        })
+        .await?;
+        let response = room.set_room_topic(topic).await?;
+        Ok(response.event_id.to_string())
+    }
+
+    /// Kicks `user_id` out of `room_id`, which needs the power level to kick.
+    async fn kick_user(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<()> {
+        let room = self.joined_room(room_id)?;
+        let user_id =
+            UserId::parse(user_id).map_err(|e| anyhow::anyhow!("invalid user ID: {e}"))?;
+        self.require_power(&room, "kick", RoomPowerLevels::user_can_kick).await?;
+        room.kick_user(&user_id, reason).await?;
+        Ok(())
+    }
+
+    /// Checks the bot's power level lets it `action` in `room`, so a moderation command fails
+    /// with a [`PermissionDenied`] saying why rather than whatever the homeserver answers. Each
+    /// room's power levels are cached until they change.
+    async fn require_power(
+        &self,
+        room: &Room,
+        action: &'static str,
+        allowed: fn(&RoomPowerLevels, &UserId) -> bool,
+    ) -> Result<()> {
+        let bot_user_id = self.client.user_id().expect("client should have user_id after login");
+        let mut power_levels = self.power_levels.lock().await;
+        if !power_levels.contains_key(room.room_id()) {
+            let levels = room.power_levels().await?;
+            power_levels.insert(room.room_id().to_owned(), levels);
+        }
+        if allowed(&power_levels[room.room_id()], bot_user_id) {
+            return Ok(());
+        }
+        warn!(room_id=%room.room_id(), action, "bot's power level is too low");
+        Err(anyhow::Error::new(PermissionDenied { room_id: room.room_id().to_string(), action }))
+    }
+
     async fn setup_event_handlers(&self) -> anyhow::Result<()> {
         // Handle room invites
         self.client.add_event_handler(
@@ -415,15 +867,92 @@ impl MatrixService {
                 }
             }
         });
+        // Handle knocks on rooms the bot is in
+        let service_id = self.id.clone();
+        let evt_tx = self.evt_tx.clone();
+        let bot_user_id =
+            self.client.user_id().expect("client should have user_id after login").to_owned();
+        self.client.add_event_handler(move |event: SyncRoomMemberEvent, room: Room| {
+            let service_id = service_id.clone();
+            let evt_tx = evt_tx.clone();
+            let bot_user_id = bot_user_id.clone();
+            async move {
+                let SyncRoomMemberEvent::Original(event) = event else {
+                    return;
+                };
+                if event.content.membership != MembershipState::Knock
+                    || event.state_key == bot_user_id
+                    || room.state() != RoomState::Joined
+                {
+                    return;
+                }
+                info!(room_id=%room.room_id(), user_id=%event.state_key, "received knock");
+                let event = Event {
+                    service_id,
+                    kind: EventKind::Knock {
+                        room_id: room.room_id().to_string(),
+                        user_id: event.state_key.to_string(),
+                        sender_display_name: event.content.displayname.clone(),
+                        reason: event.content.reason.clone(),
+                        is_self: false,
+                    },
+                };
+                let _ = evt_tx.send(event).await;
+            }
+        });
+        // Forget a room's cached power levels once they change
+        let power_levels = self.power_levels.clone();
+        self.client.add_event_handler(move |_event: SyncRoomPowerLevelsEvent, room: Room| {
+            let power_levels = power_levels.clone();
+            async move {
+                power_levels.lock().await.remove(room.room_id());
+            }
+        });
+        // Follow rooms to their replacement when they're upgraded
+        let service_id = self.id.clone();
+        let evt_tx = self.evt_tx.clone();
+        self.client.add_event_handler(
+            move |event: OriginalSyncRoomTombstoneEvent, room: Room, client: Client| {
+                let service_id = service_id.clone();
+                let evt_tx = evt_tx.clone();
+                async move {
+                    if room.state() != RoomState::Joined {
+                        return;
+                    }
+                    let old_room_id = room.room_id().to_owned();
+                    let new_room_id = event.content.replacement_room;
+                    info!(old_room=%old_room_id, new_room=%new_room_id, "room was upgraded, joining its replacement");
+                    // Whoever upgraded the room is in the new one, so their server can let us in
+                    let via = [event.sender.server_name().to_owned()];
+                    if let Err(e) = client.join_room_by_id_or_alias(<&RoomOrAliasId>::from(&*new_room_id), &via).await {
+                        error!(old_room=%old_room_id, new_room=%new_room_id, error=%e, "failed to join replacement room");
+                        return;
+                    }
+                    let event = Event {
+                        service_id,
+                        kind: EventKind::RoomUpgraded {
+                            old_room_id: old_room_id.to_string(),
+                            new_room_id: new_room_id.to_string(),
+                        },
+                    };
+                    let _ = evt_tx.send(event).await;
+                }
+            },
+        );
         // Handle room messages
         let service_id = self.id.clone();
         let evt_tx = self.evt_tx.clone();
+        let metrics = self.metrics.clone();
         let bot_user_id_for_handler =
             self.client.user_id().expect("client should have user_id after login").to_owned();
         self.client.add_event_handler(
-            move |event: OriginalSyncRoomMessageEvent, room: Room, _client: Client| {
+            move |event: OriginalSyncRoomMessageEvent,
+                  room: Room,
+                  _client: Client,
+                  raw: Raw<AnySyncTimelineEvent>| {
                 let service_id = service_id.clone();
                 let evt_tx = evt_tx.clone();
+                let metrics = metrics.clone();
                 let bot_user_id_for_handler = bot_user_id_for_handler.clone();
                 async move {
                     if room.state() != RoomState::Joined {
@@ -449,7 +978,37 @@ impl MatrixService {
 
                     let sender_id = event.sender.to_string();
                     let is_self = event.sender == bot_user_id_for_handler;
+                    let mentions_self = mentions_bot(&event.content, &bot_user_id_for_handler);
+                    let relayed_from = relay_marker(&raw);
+                    let thread_root_id = match &event.content.relates_to {
+                        Some(Relation::Thread(thread)) => Some(thread.event_id.to_string()),
+                        _ => None,
+                    };
+
+                    if matches!(event.content.msgtype, MessageType::Text(_) | MessageType::Image(_))
+                    {
+                        metrics.message_received();
+                    }
+
+                    // Marks where to pick up after downtime (see `catch_up`)
+                    if !is_direct && !is_self {
+                        let room = room.clone();
+                        let event_id = event.event_id.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = room
+                                .send_single_receipt(
+                                    SendReceiptType::ReadPrivate,
+                                    ReceiptThread::Unthreaded,
+                                    event_id,
+                                )
+                                .await
+                            {
+                                debug!(room_id=%room.room_id(), error=%e, "failed to move read receipt");
+                            }
+                        });
+                    }
 
+                    let message_id = event.event_id.to_string();
                     match event.content.msgtype {
                         MessageType::Text(text_content) => match is_direct {
                             true => {
@@ -476,6 +1035,10 @@ impl MatrixService {
                                         sender_id,
                                         sender_display_name,
                                         is_self,
+                                        mentions_self,
+                                        relayed_from,
+                                        thread_root_id,
+                                        message_id: Some(message_id),
                                     },
                                 };
                                 let _ = evt_tx.send(event).await;
@@ -647,6 +1210,11 @@ impl Service for MatrixService {
         info!(service_id=%self.id, homeserver_url=%self.client.homeserver(), user_id=%self.user_id,
             "starting matrix service");
 
+        if self.has_run.swap(true, Ordering::SeqCst) {
+            self.metrics.reconnected();
+        }
+        self.ready.send_replace(false);
+
         // Attempt to authenticate
         match self
             .client
@@ -668,6 +1236,7 @@ impl Service for MatrixService {
         // An initial sync to set up state and so our bot doesn't respond to old messages.
         // This also fetches cross-signing keys from the server.
         self.client.sync_once(SyncSettings::default()).await?;
+        self.catch_up().await;
 
         // Set up event handlers before encryption setup so verification events are processed
         self.setup_event_handlers().await?;
@@ -700,6 +1269,7 @@ impl Service for MatrixService {
         }
 
         info!("encryption setup complete, service ready");
+        self.ready.send_replace(true);
 
         // Clean up any rooms where the bot is the only member
         self.cleanup_empty_rooms().await;
@@ -721,7 +1291,14 @@ impl Service for MatrixService {
 
     async fn handle_command(&self, command: Command) -> Result<()> {
         match command {
-            Command::SendDirectMessage { user_id, body, response_tx, .. } => {
+            Command::SendDirectMessage {
+                user_id,
+                body,
+                response_tx,
+                idempotency_key,
+                require_encryption,
+                ..
+            } => {
                 info!(service=%self.id, user_id=%user_id, body=%body, "sending DM");
 
                 // Parse the user ID
@@ -738,30 +1315,51 @@ impl Service for MatrixService {
 
                 // Find existing or create new DM room
                 let result = match self.find_or_create_dm(&user_id).await {
+                    Ok(room) if require_encryption && !self.is_encrypted(&room).await => {
+                        warn!(user_id=%user_id, room_id=%room.room_id(), "DM room isn't encrypted, not sending DM");
+                        Err(anyhow::Error::new(NotEncrypted))
+                    }
                     Ok(room) => {
                         let content = RoomMessageEventContent::text_plain(&body);
-                        match room.send(content).await {
+                        let send_started = Instant::now();
+                        let mut send = room.send(content);
+                        if let Some(txn_id) = transaction_id(idempotency_key.as_deref()) {
+                            send = send.with_transaction_id(txn_id);
+                        }
+                        match send.await {
                             Ok(response) => {
+                                self.metrics.message_sent(send_started.elapsed());
                                 debug!("DM sent successfully");
                                 Ok(response.event_id.to_string())
                             }
                             Err(e) => {
                                 error!(error=%e, "failed to send DM");
-                                Err(anyhow::anyhow!("failed to send DM: {}", e))
+                                self.metrics.send_failed();
+                                Err(transient_error(format!("failed to send DM: {e}")))
                             }
                         }
                     }
                     Err(e) => {
                         error!(error=%e, user_id=%user_id, "failed to find/create DM room");
-                        Err(anyhow::anyhow!("failed to find/create DM room: {}", e))
+                        Err(transient_error(format!("failed to find/create DM room: {e}")))
                     }
                 };
 
                 if let Some(tx) = response_tx {
                     let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
                 }
             }
-            Command::SendRoomMessage { room_id, body, markdown_body, response_tx, .. } => {
+            Command::SendRoomMessage {
+                room_id,
+                body,
+                format,
+                response_tx,
+                idempotency_key,
+                relayed_from,
+                ..
+            } => {
                 info!(service=%self.id, room_id=%room_id, body=%body, "sending room message");
 
                 // Parse the room ID
@@ -777,23 +1375,40 @@ impl Service for MatrixService {
                 };
 
                 // Get the room and send message
-                let result = if let Some(room) = self.client.get_room(&room_id) {
-                    let content = if let Some(markdown) = markdown_body {
-                        RoomMessageEventContent::new(MessageType::Text(
-                            TextMessageEventContent::markdown(markdown),
-                        ))
-                    } else {
-                        RoomMessageEventContent::text_plain(&body)
+                let result = if let Some(room) = self.current_room(&room_id) {
+                    let content = text_content(&body, format);
+
+                    let txn_id = transaction_id(idempotency_key.as_deref());
+                    let send_started = Instant::now();
+                    let sent = match &relayed_from {
+                        Some(provenance) => match with_relay_marker(&content, provenance) {
+                            Ok(raw) => {
+                                let mut send = room.send_raw("m.room.message", raw);
+                                if let Some(txn_id) = txn_id {
+                                    send = send.with_transaction_id(txn_id);
+                                }
+                                send.await.map(|response| response.event_id)
+                            }
+                            Err(e) => Err(e.into()),
+                        },
+                        None => {
+                            let mut send = room.send(content);
+                            if let Some(txn_id) = txn_id {
+                                send = send.with_transaction_id(txn_id);
+                            }
+                            send.await.map(|response| response.event_id)
+                        }
                     };
-
-                    match room.send(content).await {
-                        Ok(response) => {
+                    match sent {
+                        Ok(event_id) => {
+                            self.metrics.message_sent(send_started.elapsed());
                             debug!("room message sent successfully");
-                            Ok(response.event_id.to_string())
+                            Ok(event_id.to_string())
                         }
                         Err(e) => {
                             error!(error=%e, "failed to send room message");
-                            Err(anyhow::anyhow!("failed to send room message: {}", e))
+                            self.metrics.send_failed();
+                            Err(transient_error(format!("failed to send room message: {e}")))
                         }
                     }
                 } else {
@@ -803,14 +1418,17 @@ impl Service for MatrixService {
 
                 if let Some(tx) = response_tx {
                     let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
                 }
             }
             Command::SendThreadReply {
                 room_id,
                 thread_root_id,
                 body,
-                markdown_body,
+                format,
                 response_tx,
+                idempotency_key,
                 ..
             } => {
                 info!(service=%self.id, room_id=%room_id, thread_root=%thread_root_id, "sending thread reply");
@@ -828,7 +1446,6 @@ impl Service for MatrixService {
                 };
 
                 // Parse the thread root event ID
-                use matrix_sdk::ruma::EventId;
                 let thread_root_event_id = match EventId::parse(&thread_root_id) {
                     Ok(eid) => eid,
                     Err(e) => {
@@ -844,27 +1461,28 @@ impl Service for MatrixService {
                 // Get the room
                 let result = if let Some(room) = self.client.get_room(&room_id) {
                     // Create the message content
-                    let mut content = if let Some(markdown) = markdown_body {
-                        RoomMessageEventContent::new(MessageType::Text(
-                            TextMessageEventContent::markdown(markdown),
-                        ))
-                    } else {
-                        RoomMessageEventContent::text_plain(&body)
-                    };
+                    let mut content = text_content(&body, format);
 
                     // Manually set the thread relation
-                    use matrix_sdk::ruma::events::{relation::Thread, room::message::Relation};
+                    use matrix_sdk::ruma::events::relation::Thread;
                     content.relates_to =
                         Some(Relation::Thread(Thread::without_fallback(thread_root_event_id)));
 
-                    match room.send(content).await {
+                    let send_started = Instant::now();
+                    let mut send = room.send(content);
+                    if let Some(txn_id) = transaction_id(idempotency_key.as_deref()) {
+                        send = send.with_transaction_id(txn_id);
+                    }
+                    match send.await {
                         Ok(response) => {
+                            self.metrics.message_sent(send_started.elapsed());
                             debug!("thread reply sent successfully");
                             Ok(response.event_id.to_string())
                         }
                         Err(e) => {
                             error!(error=%e, "failed to send thread reply");
-                            Err(anyhow::anyhow!("failed to send thread reply: {}", e))
+                            self.metrics.send_failed();
+                            Err(transient_error(format!("failed to send thread reply: {e}")))
                         }
                     }
                 } else {
@@ -874,13 +1492,14 @@ impl Service for MatrixService {
 
                 if let Some(tx) = response_tx {
                     let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
                 }
             }
-            Command::EditMessage { message_id, new_body, new_markdown_body, .. } => {
+            Command::EditMessage { message_id, new_body, format, .. } => {
                 info!(service=%self.id, message_id=%message_id, "editing message");
 
                 // Parse the event ID
-                use matrix_sdk::ruma::EventId;
                 let event_id = match EventId::parse(&message_id) {
                     Ok(eid) => eid,
                     Err(e) => {
@@ -889,29 +1508,9 @@ impl Service for MatrixService {
                     }
                 };
 
-                // Find the room containing this event
-                // We need to search through all joined rooms to find which one contains this event
-                let rooms = self.client.rooms();
-                let mut found_room = None;
-                for room in rooms {
-                    if room.state() == RoomState::Joined {
-                        // Try to get the event from this room
-                        if room.event(&event_id, None).await.is_ok() {
-                            found_room = Some(room);
-                            break;
-                        }
-                    }
-                }
-
-                if let Some(room) = found_room {
+                if let Some(room) = self.room_with_event(&event_id).await {
                     // Create the new message content
-                    let new_content = if let Some(markdown) = new_markdown_body {
-                        RoomMessageEventContent::new(MessageType::Text(
-                            TextMessageEventContent::markdown(markdown),
-                        ))
-                    } else {
-                        RoomMessageEventContent::text_plain(&new_body)
-                    };
+                    let new_content = text_content(&new_body, format);
 
                     // Create edit event using the edit helper
                     use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
@@ -925,6 +1524,7 @@ impl Service for MatrixService {
 
                     if let Err(e) = room.send(edit_event).await {
                         error!(error=%e, "failed to edit message");
+                        return Err(transient_error(format!("failed to edit message: {e}")));
                     } else {
                         debug!("message edited successfully");
                     }
@@ -932,6 +1532,37 @@ impl Service for MatrixService {
                     warn!(message_id=%message_id, "could not find room containing message");
                 }
             }
+            Command::DeleteMessage { message_id, .. } => {
+                info!(service=%self.id, message_id=%message_id, "deleting message");
+                let event_id = match EventId::parse(&message_id) {
+                    Ok(eid) => eid,
+                    Err(e) => {
+                        error!(message_id=%message_id, error=%e, "invalid event ID");
+                        return Ok(());
+                    }
+                };
+                let Some(room) = self.room_with_event(&event_id).await else {
+                    warn!(message_id=%message_id, "could not find room containing message");
+                    return Ok(());
+                };
+                // Redacting someone else's message takes a higher power level than the bot's own
+                let is_own = room
+                    .event(&event_id, None)
+                    .await
+                    .ok()
+                    .and_then(|event| event.raw().get_field::<OwnedUserId>("sender").ok().flatten())
+                    .is_some_and(|sender| Some(sender.as_ref()) == self.client.user_id());
+                let allowed: fn(&RoomPowerLevels, &UserId) -> bool = if is_own {
+                    RoomPowerLevels::user_can_redact_own_event
+                } else {
+                    RoomPowerLevels::user_can_redact_event_of_other
+                };
+                self.require_power(&room, "redact", allowed).await?;
+                if let Err(e) = room.redact(&event_id, None, None).await {
+                    error!(error=%e, "failed to delete message");
+                    return Err(transient_error(format!("failed to delete message: {e}")));
+                }
+            }
             Command::GenerateInviteToken { user_id, uses_allowed, expiry, response_tx, .. } => {
                 info!(service=%self.id, user_id=%user_id, uses_allowed=?uses_allowed, expiry=?expiry, "generating invite token");
 
@@ -940,8 +1571,8 @@ impl Service for MatrixService {
 
                 // Log the result
                 match &result {
-                    Ok(token) => {
-                        info!(token=%token, "registration token generated successfully");
+                    Ok(_) => {
+                        info!(service=%self.id, user_id=%user_id, "registration token generated successfully");
                     }
                     Err(e) => {
                         error!(error=%e, "failed to generate registration token");
@@ -955,6 +1586,119 @@ impl Service for MatrixService {
             Command::SendRoomImage { .. } => {
                 warn!(service=%self.id, "SendRoomImage not implemented for Matrix service");
             }
+            Command::GetRoomState { room_id, event_type, state_key, response_tx, .. } => {
+                debug!(service=%self.id, room_id=%room_id, event_type=%event_type, state_key=%state_key, "reading room state");
+                let result = self.room_state(&room_id, &event_type, &state_key).await;
+                if let Err(e) = &result {
+                    warn!(room_id=%room_id, event_type=%event_type, error=%e, "failed to read room state");
+                }
+                let _ = response_tx.send(result);
+            }
+            Command::SetRoomState {
+                room_id, event_type, state_key, content, response_tx, ..
+            } => {
+                info!(service=%self.id, room_id=%room_id, event_type=%event_type, state_key=%state_key, "writing room state");
+                let result = self.set_room_state(&room_id, &event_type, &state_key, content).await;
+                if let Err(e) = &result {
+                    error!(room_id=%room_id, event_type=%event_type, error=%e, "failed to write room state");
+                }
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
+                }
+            }
+            Command::SetRoomTopic { room_id, topic, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, "setting room topic");
+                let result = self.set_room_topic(&room_id, &topic).await;
+                if let Err(e) = &result {
+                    error!(room_id=%room_id, error=%e, "failed to set room topic");
+                }
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
+                }
+            }
+            Command::ApproveKnock { room_id, user_id, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, user_id=%user_id, "approving knock");
+                let result = self.approve_knock(&room_id, &user_id).await.map(|()| String::new());
+                if let Err(e) = &result {
+                    error!(room_id=%room_id, user_id=%user_id, error=%e, "failed to approve knock");
+                }
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
+                }
+            }
+            Command::KickUser { room_id, user_id, reason, response_tx, .. } => {
+                info!(service=%self.id, room_id=%room_id, user_id=%user_id, "kicking user");
+                let result = self
+                    .kick_user(&room_id, &user_id, reason.as_deref())
+                    .await
+                    .map(|()| String::new());
+                if let Err(e) = &result {
+                    error!(room_id=%room_id, user_id=%user_id, error=%e, "failed to kick user");
+                }
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
+                }
+            }
+            Command::JoinRoom { room, reason, response_tx, .. } => {
+                info!(service=%self.id, room=%room, "joining room");
+                let result = self.join_or_knock(&room, reason).await;
+                if let Err(e) = &result {
+                    error!(room=%room, error=%e, "failed to join room");
+                }
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
+                }
+            }
+            Command::ConfirmVerification { flow_id, confirm, response_tx, .. } => {
+                info!(service=%self.id, flow_id=%flow_id, confirm=%confirm, "answering verification");
+                let pending =
+                    self.pending_verification.lock().await.take_if(|(id, _)| *id == flow_id);
+                let result = match pending {
+                    Some((_, answer_tx)) => {
+                        answer_tx.send(confirm).map(|()| flow_id.clone()).map_err(|_| {
+                            anyhow::anyhow!("verification {flow_id} is no longer in progress")
+                        })
+                    }
+                    None => Err(anyhow::anyhow!("no verification {flow_id} in progress")),
+                };
+                if let Err(e) = &result {
+                    warn!(flow_id=%flow_id, error=%e, "failed to answer verification");
+                }
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                }
+            }
+            Command::GetUserList { response_tx, .. } => {
+                warn!(service=%self.id, "GetUserList not implemented for Matrix service");
+                let _ =
+                    response_tx.send(Err(anyhow::anyhow!("user lists not supported by matrix")));
+            }
+            Command::Ping { response_tx, .. } => {
+                // The cheapest authenticated request there is
+                let started = Instant::now();
+                let result = match self.client.whoami().await {
+                    Ok(_) => {
+                        let round_trip = started.elapsed();
+                        self.metrics.round_trip(round_trip);
+                        Ok(round_trip)
+                    }
+                    Err(e) => {
+                        error!(error=%e, "failed to ping homeserver");
+                        Err(transient_error(format!("failed to ping homeserver: {e}")))
+                    }
+                };
+                let _ = response_tx.send(result);
+            }
             Command::AddReaction { room_id, event_id, key, .. } => {
                 info!(service=%self.id, room_id=%room_id, event_id=%event_id, key=%key, "adding reaction");
 
@@ -968,7 +1712,6 @@ impl Service for MatrixService {
                 };
 
                 // Parse the event ID
-                use matrix_sdk::ruma::EventId;
                 let event_id = match EventId::parse(&event_id) {
                     Ok(eid) => eid,
                     Err(e) => {
@@ -991,13 +1734,30 @@ impl Service for MatrixService {
                         }
                         Err(e) => {
                             error!(error=%e, "failed to add reaction");
+                            return Err(transient_error(format!("failed to add reaction: {e}")));
                         }
                     }
                 } else {
                     warn!(room_id=%room_id, "room not found or not joined");
                 }
             }
+            command @ Command::Announce { .. } => {
+                warn!(service=%self.id, "announcement should be expanded by the bus, not dispatched to a service");
+                command.reject(anyhow::anyhow!("announcements are expanded by the bus"));
+            }
+            Command::Control(_) => {
+                warn!(service=%self.id, "bus control command should not be dispatched to a service");
+            }
         }
         Ok(())
     }
+
+    fn capabilities(&self) -> ServiceCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn ready(&self) {
+        // Only errors if the sender is dropped, which can't happen while `self` is alive
+        let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+    }
 }
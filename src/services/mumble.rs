@@ -1,38 +1,191 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{Result, anyhow};
 use futures::{SinkExt, StreamExt};
 use mumble_protocol_2x::control::msgs::{
-    Authenticate, ChannelState, Ping, ServerSync, TextMessage, UserRemove, UserState, Version,
+    Authenticate, ChannelState, Ping, RequestBlob, ServerSync, TextMessage, UserRemove, UserState,
+    Version,
 };
 use mumble_protocol_2x::control::{ClientControlCodec, ControlPacket};
+use mumble_protocol_2x::voice::{VoicePacket, VoicePacketPayload};
 use mumble_protocol_2x::{Clientbound, Serverbound};
 use secrecy::{ExposeSecret, SecretString};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
 use tokio::sync::mpsc::{self, Sender};
-use tokio::time::{Duration, interval};
+use tokio::sync::{Mutex, oneshot, watch};
+use tokio::time::{Duration, Instant, interval};
 use tokio_native_tls::TlsStream;
 use tokio_util::codec::Framed;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-use crate::core::bus::Command;
-use crate::core::event::{Event, EventKind, User};
-use crate::core::service::{Service, ServiceId};
+use crate::core::bus::{
+    Command, ConnectedUser, EventSender, EventTx, NotEncrypted, OverflowPolicy, transient_error,
+};
+use crate::core::event::{Event, EventKind, User, VoiceState, mentions_name};
+use crate::core::format::{BodyFormat, FormatProfile, render};
+use crate::core::idempotency::SeenKeys;
+use crate::core::metrics::ServiceMetrics;
+use crate::core::service::{Service, ServiceCapabilities, ServiceId};
 
 const VERSION_MAJOR: u16 = 1;
 const VERSION_MINOR: u8 = 5;
 const VERSION_PATCH: u8 = 0;
 
+// Events are emitted from the connection loop, which must keep up with keepalive pings
+const EVENT_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::WaitFor(Duration::from_secs(1));
+
+// Murmur's default `textmessagelength`; servers may configure a different limit
+const MAX_TEXT_MESSAGE_LENGTH: usize = 5000;
+
+// A talker whose audio stops without the packet that ends a transmission (e.g. it was lost, or
+// the codec has no end marker) counts as silent after this long
+const TALKING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A user's comment and avatar (texture), as far as they've arrived.
+#[derive(Clone, Default)]
+struct Profile {
+    comment: Option<String>,
+    avatar: Option<Arc<[u8]>>,
+}
+
+impl Profile {
+    /// Applies the comment and texture in `msg`, if it has them; empty ones clear them.
+    /// Returns whether anything changed.
+    fn update(&mut self, msg: &UserState) -> bool {
+        let mut changed = false;
+        if let Some(comment) = &msg.comment {
+            let comment = Some(comment.clone()).filter(|comment| !comment.is_empty());
+            changed |= self.comment != comment;
+            self.comment = comment;
+        }
+        if let Some(texture) = &msg.texture {
+            let avatar = (!texture.is_empty()).then(|| Arc::from(texture.as_slice()));
+            changed |= self.avatar != avatar;
+            self.avatar = avatar;
+        }
+        changed
+    }
+}
+
+/// The channel a user is in and the voice states of theirs that get reported.
+#[derive(Default)]
+struct Voice {
+    channel_id: u32,
+    priority_speaker: bool,
+    recording: bool,
+    listening: BTreeSet<u32>,
+    // When audio last arrived from the user, while they're talking
+    last_audio: Option<Instant>,
+}
+
+impl Voice {
+    /// Applies the channel and voice states in `msg`. Returns what was switched on or off, with
+    /// the channel each change applies to.
+    fn update(&mut self, msg: &UserState) -> Vec<(VoiceState, u32, bool)> {
+        let mut changes = Vec::new();
+        // Whoever is recording or speaking over everyone does so in the channel they're in
+        if let Some(channel_id) = msg.channel_id
+            && channel_id != self.channel_id
+        {
+            changes.extend(self.in_channel(false));
+            self.channel_id = channel_id;
+            changes.extend(self.in_channel(true));
+        }
+        if let Some(priority_speaker) = msg.priority_speaker
+            && priority_speaker != self.priority_speaker
+        {
+            self.priority_speaker = priority_speaker;
+            changes.push((VoiceState::PrioritySpeaker, self.channel_id, priority_speaker));
+        }
+        if let Some(recording) = msg.recording
+            && recording != self.recording
+        {
+            self.recording = recording;
+            changes.push((VoiceState::Recording, self.channel_id, recording));
+        }
+        for channel_id in &msg.listening_channel_add {
+            if self.listening.insert(*channel_id) {
+                changes.push((VoiceState::Listening, *channel_id, true));
+            }
+        }
+        for channel_id in &msg.listening_channel_remove {
+            if self.listening.remove(channel_id) {
+                changes.push((VoiceState::Listening, *channel_id, false));
+            }
+        }
+        changes
+    }
+
+    /// Notes an audio packet from the user, `end` if it ends their transmission. Returns the
+    /// change if they started or stopped talking.
+    fn audio(&mut self, end: bool) -> Option<(VoiceState, u32, bool)> {
+        let was_talking =
+            if end { self.last_audio.take() } else { self.last_audio.replace(Instant::now()) }
+                .is_some();
+        (was_talking == end).then_some((VoiceState::Talking, self.channel_id, !end))
+    }
+
+    /// Stops the user talking if no audio has arrived from them for `TALKING_TIMEOUT`.
+    fn check_silence(&mut self, now: Instant) -> Option<(VoiceState, u32, bool)> {
+        if now.duration_since(self.last_audio?) < TALKING_TIMEOUT {
+            return None;
+        }
+        self.last_audio = None;
+        Some((VoiceState::Talking, self.channel_id, false))
+    }
+
+    /// Every active state switched off, for when the user leaves.
+    fn all_off(&self) -> Vec<(VoiceState, u32, bool)> {
+        let mut states = self.in_channel(false);
+        states.extend(self.listening.iter().map(|id| (VoiceState::Listening, *id, false)));
+        states
+    }
+
+    /// The states tied to the user's own channel, reported as `active`.
+    fn in_channel(&self, active: bool) -> Vec<(VoiceState, u32, bool)> {
+        [
+            (VoiceState::PrioritySpeaker, self.priority_speaker),
+            (VoiceState::Recording, self.recording),
+            (VoiceState::Talking, self.last_audio.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(state, _)| (state, self.channel_id, active))
+        .collect()
+    }
+}
+
+/// Microseconds since the Unix epoch, which pings carry so the server's echo tells how long the
+/// round trip took.
+fn ping_timestamp() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+fn ping_packet() -> ControlPacket<Serverbound> {
+    let mut ping = Ping::new();
+    ping.set_timestamp(ping_timestamp());
+    ControlPacket::Ping(Box::new(ping))
+}
+
+/// Room ID for a channel: its name, or `channel_<id>` if it hasn't been named yet.
+fn channel_room_id(channel_ids: &HashMap<u32, String>, channel_id: u32) -> String {
+    channel_ids.get(&channel_id).cloned().unwrap_or_else(|| format!("channel_{}", channel_id))
+}
+
 struct MumbleState {
     user_sessions: HashMap<String, u32>,
     session_users: HashMap<u32, String>,
+    profiles: HashMap<u32, Profile>,
+    voices: HashMap<u32, Voice>,
     channel_ids: HashMap<String, u32>,
     id_channels: HashMap<u32, String>,
     own_session_id: Option<u32>,
     initial_sync_complete: bool,
+    // Answered by the next ping to come back
+    ping_waiters: Vec<oneshot::Sender<Result<Duration>>>,
 }
 
 impl MumbleState {
@@ -40,10 +193,13 @@ impl MumbleState {
         Self {
             user_sessions: HashMap::new(),
             session_users: HashMap::new(),
+            profiles: HashMap::new(),
+            voices: HashMap::new(),
             channel_ids: HashMap::new(),
             id_channels: HashMap::new(),
             own_session_id: None,
             initial_sync_complete: false,
+            ping_waiters: Vec::new(),
         }
     }
 }
@@ -55,12 +211,29 @@ pub struct MumbleService {
     username: String,
     password: SecretString,
     accept_invalid_certs: bool,
-    evt_tx: Sender<Event>,
+    evt_tx: EventSender,
     msg_tx: Arc<Mutex<Option<Sender<ControlPacket<Serverbound>>>>>,
     state: Arc<Mutex<MumbleState>>,
+    metrics: Arc<dyn ServiceMetrics>,
+    // Set after the first run so later runs are counted as reconnects
+    has_run: AtomicBool,
+    // True once the server has sent its initial sync
+    ready: watch::Sender<bool>,
+    // Mumble has no way to dedupe sends, so repeated idempotency keys are caught here
+    seen_keys: SeenKeys,
 }
 
 impl MumbleService {
+    pub const CAPABILITIES: ServiceCapabilities = ServiceCapabilities {
+        supports_edit: false,
+        format: FormatProfile::MumbleHtml,
+        supports_invite_tokens: false,
+        supports_attachments: true, // Inline data-URI thumbnails
+        supports_room_state: false,
+        supports_delete: false,
+        max_message_length: Some(MAX_TEXT_MESSAGE_LENGTH),
+    };
+
     pub async fn create(
         id: ServiceId,
         hostname: String,
@@ -68,7 +241,8 @@ impl MumbleService {
         username: String,
         password: SecretString,
         accept_invalid_certs: bool,
-        evt_tx: Sender<Event>,
+        evt_tx: EventTx,
+        metrics: Arc<dyn ServiceMetrics>,
     ) -> Result<Self> {
         if hostname.is_empty() {
             return Err(anyhow!("hostname cannot be empty"));
@@ -85,9 +259,13 @@ impl MumbleService {
             username,
             password,
             accept_invalid_certs,
-            evt_tx,
+            evt_tx: EventSender::new(evt_tx, EVENT_OVERFLOW_POLICY),
             msg_tx: Arc::new(Mutex::new(None)),
             state: Arc::new(Mutex::new(MumbleState::new())),
+            metrics,
+            has_run: AtomicBool::new(false),
+            ready: watch::channel(false).0,
+            seen_keys: SeenKeys::default(),
         })
     }
 
@@ -143,30 +321,121 @@ impl MumbleService {
         info!(session=%msg.session(), "server sync received");
         state.own_session_id = Some(msg.session());
         state.initial_sync_complete = true;
+        self.ready.send_replace(true);
 
         // Emit initial user list
         self.emit_user_list_update(state).await?;
         Ok(())
     }
 
-    async fn handle_user_state(&self, msg: UserState, state: &mut MumbleState) -> Result<()> {
+    /// Returns a request for the user's comment or avatar if the server only sent their hash.
+    async fn handle_user_state(
+        &self,
+        msg: UserState,
+        state: &mut MumbleState,
+    ) -> Result<Option<ControlPacket<Serverbound>>> {
         let session = msg.session();
         let was_new_user = !state.session_users.contains_key(&session);
+        let renamed = msg
+            .name
+            .as_ref()
+            .is_some_and(|name| state.session_users.get(&session).is_some_and(|old| old != name));
 
         if let Some(name) = msg.name.as_ref() {
             debug!(session=%session, username=%name, "user state update");
             state.user_sessions.insert(name.clone(), session);
             state.session_users.insert(session, name.clone());
 
-            // Emit user list update if initial sync is complete and this is a new user
-            if state.initial_sync_complete && was_new_user {
-                self.emit_user_list_update(state).await?;
+            // Announce the user's arrival if initial sync is complete and this is a new user
+            if state.initial_sync_complete
+                && was_new_user
+                && let Some(user) = Self::user(state, session)
+            {
+                self.emit_user_list_diff(vec![user], vec![], vec![]).await?;
             }
         }
 
+        let blob_request = Self::missing_blobs_request(&msg);
+        let profile_changed = state.profiles.entry(session).or_default().update(&msg);
+        // New users' profiles went out with the user list
+        if (profile_changed || renamed)
+            && state.initial_sync_complete
+            && !was_new_user
+            && let Some(user) = Self::user(state, session)
+        {
+            if profile_changed {
+                debug!(session=%session, "user profile changed");
+                let kind = EventKind::UserProfileChanged { user: user.clone() };
+                let event = Event { service_id: self.id.clone(), kind };
+                self.evt_tx.send(event).await?;
+            }
+            self.emit_user_list_diff(vec![], vec![], vec![user]).await?;
+        }
+
+        let changes = state.voices.entry(session).or_default().update(&msg);
+        self.emit_voice_state_changes(state, session, changes).await?;
+
+        Ok(blob_request)
+    }
+
+    async fn emit_voice_state_changes(
+        &self,
+        state: &MumbleState,
+        session: u32,
+        changes: Vec<(VoiceState, u32, bool)>,
+    ) -> Result<()> {
+        let Some(username) = state.session_users.get(&session) else {
+            return Ok(());
+        };
+        for (voice_state, channel_id, active) in changes {
+            let room_id = channel_room_id(&state.id_channels, channel_id);
+            debug!(session=%session, room_id=%room_id, state=voice_state.name(), active, "voice state changed");
+            let event = Event {
+                service_id: self.id.clone(),
+                kind: EventKind::VoiceStateChanged {
+                    room_id,
+                    user_id: username.clone(),
+                    sender_display_name: Some(username.clone()),
+                    state: voice_state,
+                    active,
+                    is_self: state.own_session_id == Some(session),
+                },
+            };
+            self.evt_tx.send(event).await?;
+        }
+        Ok(())
+    }
+
+    /// Reports users whose audio stopped without ending their transmission as no longer talking.
+    async fn end_silent_talkers(&self, state: &mut MumbleState) -> Result<()> {
+        let now = Instant::now();
+        let silent: Vec<(u32, (VoiceState, u32, bool))> = state
+            .voices
+            .iter_mut()
+            .filter_map(|(session, voice)| Some((*session, voice.check_silence(now)?)))
+            .collect();
+        for (session, change) in silent {
+            self.emit_voice_state_changes(state, session, vec![change]).await?;
+        }
         Ok(())
     }
 
+    /// Asks the server for comments and textures too large to come inline, which it only
+    /// sends hashes of. They arrive in a later `UserState`.
+    fn missing_blobs_request(msg: &UserState) -> Option<ControlPacket<Serverbound>> {
+        let mut request = RequestBlob::new();
+        if msg.comment.is_none() && msg.comment_hash.is_some() {
+            request.session_comment.push(msg.session());
+        }
+        if msg.texture.is_none() && msg.texture_hash.is_some() {
+            request.session_texture.push(msg.session());
+        }
+        if request.session_comment.is_empty() && request.session_texture.is_empty() {
+            return None;
+        }
+        Some(ControlPacket::RequestBlob(Box::new(request)))
+    }
+
     fn handle_channel_state(&self, msg: ChannelState, state: &mut MumbleState) {
         let channel_id = msg.channel_id();
 
@@ -181,32 +450,46 @@ impl MumbleService {
         let session = msg.session();
         debug!(session=%session, "user removed");
 
+        // Whatever they were recording or listening to, they aren't anymore
+        if let Some(voice) = state.voices.remove(&session) {
+            self.emit_voice_state_changes(state, session, voice.all_off()).await?;
+        }
+
         // Remove user from tracking
+        let user = Self::user(state, session);
+        state.profiles.remove(&session);
         if let Some(username) = state.session_users.remove(&session) {
             state.user_sessions.remove(&username);
 
-            // Emit user list update if initial sync is complete
-            if state.initial_sync_complete {
-                self.emit_user_list_update(state).await?;
+            // Announce the departure if initial sync is complete
+            if state.initial_sync_complete
+                && let Some(user) = user
+            {
+                self.emit_user_list_diff(vec![], vec![user], vec![]).await?;
             }
         }
 
         Ok(())
     }
 
+    /// The user connected as `session`, with whatever of their profile has arrived.
+    fn user(state: &MumbleState, session: u32) -> Option<User> {
+        let username = state.session_users.get(&session)?;
+        let profile = state.profiles.get(&session).cloned().unwrap_or_default();
+        Some(User {
+            id: session.to_string(),
+            username: username.clone(),
+            display_name: username.clone(),
+            is_active: true,
+            is_self: state.own_session_id == Some(session),
+            comment: profile.comment,
+            avatar: profile.avatar,
+        })
+    }
+
     async fn emit_user_list_update(&self, state: &MumbleState) -> Result<()> {
-        let own_session = state.own_session_id;
-        let users: Vec<User> = state
-            .session_users
-            .iter()
-            .map(|(session_id, username)| User {
-                id: session_id.to_string(),
-                username: username.clone(),
-                display_name: username.clone(),
-                is_active: true,
-                is_self: own_session == Some(*session_id),
-            })
-            .collect();
+        let users: Vec<User> =
+            state.session_users.keys().filter_map(|session| Self::user(state, *session)).collect();
 
         let event =
             Event { service_id: self.id.clone(), kind: EventKind::UserListUpdate { users } };
@@ -215,12 +498,34 @@ impl MumbleService {
         Ok(())
     }
 
+    /// Reports users joining, leaving or changing their name, comment or avatar after the
+    /// initial sync, which the bus turns into a full user list for middlewares that follow
+    /// snapshots.
+    ///
+    /// Channel moves and voice states aren't part of a `User`, so they never show up in
+    /// `changed`: they're reported as `VoiceStateChanged` events, and mute and deafen not at all.
+    async fn emit_user_list_diff(
+        &self,
+        joined: Vec<User>,
+        left: Vec<User>,
+        changed: Vec<User>,
+    ) -> Result<()> {
+        let event = Event {
+            service_id: self.id.clone(),
+            kind: EventKind::UserListDiff { joined, left, changed },
+        };
+
+        self.evt_tx.send(event).await?;
+        Ok(())
+    }
+
     async fn emit_text_message_event(
-        evt_tx: Sender<Event>,
+        evt_tx: EventSender,
         service_id: ServiceId,
         msg: TextMessage,
         sender_name: String,
         is_local_user: bool,
+        own_name: String,
         channel_ids: HashMap<u32, String>,
     ) -> Result<()> {
         let message_text = msg.message();
@@ -244,10 +549,7 @@ impl MumbleService {
             evt_tx.send(event).await?;
         } else if !msg.channel_id.is_empty() {
             let channel_id = msg.channel_id[0];
-            let room_id = channel_ids
-                .get(&channel_id)
-                .cloned()
-                .unwrap_or_else(|| format!("channel_{}", channel_id));
+            let room_id = channel_room_id(&channel_ids, channel_id);
 
             let event = Event {
                 service_id,
@@ -258,6 +560,11 @@ impl MumbleService {
                     sender_id: sender_name.clone(),
                     sender_display_name: Some(sender_name),
                     is_self: is_local_user,
+                    // Mumble has no mentions of its own; people address the bot by name
+                    mentions_self: mentions_name(message_text, &own_name),
+                    relayed_from: None,
+                    thread_root_id: None,
+                    message_id: None,
                 },
             };
             evt_tx.send(event).await?;
@@ -290,6 +597,8 @@ impl MumbleService {
             ControlPacket::TextMessage(_) => {
                 debug!("received text message");
             }
+            // Audio arrives many times a second while anyone talks
+            ControlPacket::UDPTunnel(_) => {}
             _ => {
                 debug!("received other packet: {:?}", packet);
             }
@@ -300,10 +609,7 @@ impl MumbleService {
                 self.handle_server_sync(*msg, state).await?;
                 Ok(None)
             }
-            ControlPacket::UserState(msg) => {
-                self.handle_user_state(*msg, state).await?;
-                Ok(None)
-            }
+            ControlPacket::UserState(msg) => self.handle_user_state(*msg, state).await,
             ControlPacket::UserRemove(msg) => {
                 self.handle_user_remove(*msg, state).await?;
                 Ok(None)
@@ -315,9 +621,19 @@ impl MumbleService {
             ControlPacket::Ping(msg) => {
                 debug!(timestamp=%msg.timestamp(), "received ping (echo from server)");
                 // Don't respond - this is likely our own ping being echoed back
+                let Some(micros) = ping_timestamp().checked_sub(msg.timestamp()) else {
+                    return Ok(None);
+                };
+                let round_trip = Duration::from_micros(micros);
+                debug!(round_trip_ms = round_trip.as_millis() as u64, "server round trip");
+                self.metrics.round_trip(round_trip);
+                for waiter in state.ping_waiters.drain(..) {
+                    let _ = waiter.send(Ok(round_trip));
+                }
                 Ok(None)
             }
             ControlPacket::TextMessage(msg) => {
+                self.metrics.message_received();
                 let evt_tx = self.evt_tx.clone();
                 let id = self.id.clone();
                 let sender_name = state
@@ -327,6 +643,7 @@ impl MumbleService {
                     .unwrap_or_else(|| format!("user_{}", msg.actor()));
                 let is_local_user = state.own_session_id == Some(msg.actor());
                 let channel_ids = state.id_channels.clone();
+                let own_name = self.username.clone();
 
                 tokio::spawn(async move {
                     if let Err(e) = Self::emit_text_message_event(
@@ -335,6 +652,7 @@ impl MumbleService {
                         *msg,
                         sender_name,
                         is_local_user,
+                        own_name,
                         channel_ids,
                     )
                     .await
@@ -344,6 +662,16 @@ impl MumbleService {
                 });
                 Ok(None)
             }
+            // Voice reaches the bot over the control connection since it never sets up UDP
+            ControlPacket::UDPTunnel(voice) => {
+                if let VoicePacket::Audio { session_id, payload, .. } = *voice {
+                    let end = matches!(payload, VoicePacketPayload::Opus(_, true));
+                    let change = state.voices.entry(session_id).or_default().audio(end);
+                    self.emit_voice_state_changes(state, session_id, change.into_iter().collect())
+                        .await?;
+                }
+                Ok(None)
+            }
             _ => {
                 debug!("received unhandled control packet: {:?}", packet);
                 Ok(None)
@@ -357,8 +685,13 @@ impl Service for MumbleService {
     async fn run(&self, cancel: CancellationToken) -> Result<()> {
         info!(id=%self.id, "mumble service starting");
 
+        if self.has_run.swap(true, Ordering::SeqCst) {
+            self.metrics.reconnected();
+        }
+
         // Reset state on each run (important for reconnections after disconnect)
         *self.state.lock().await = MumbleState::new();
+        self.ready.send_replace(false);
 
         let mut stream = self.connect().await?;
         self.authenticate(&mut stream).await?;
@@ -369,6 +702,7 @@ impl Service for MumbleService {
         // Send periodic pings to keep connection alive (every 15 seconds)
         let mut ping_interval = interval(Duration::from_secs(15));
         ping_interval.tick().await; // First tick completes immediately
+        let mut silence_check = interval(TALKING_TIMEOUT);
 
         loop {
             tokio::select! {
@@ -377,21 +711,19 @@ impl Service for MumbleService {
                     break;
                 }
                 _ = ping_interval.tick() => {
-                    // Send ping to keep connection alive
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64;
-
-                    let mut ping = Ping::new();
-                    ping.set_timestamp(timestamp);
-
+                    // Send ping to keep connection alive; the echo also measures latency
                     debug!("sending keepalive ping");
-                    if let Err(e) = stream.send(ControlPacket::Ping(Box::new(ping))).await {
+                    if let Err(e) = stream.send(ping_packet()).await {
                         error!(error=%e, "failed to send keepalive ping");
                         return Err(e.into());
                     }
                 }
+                _ = silence_check.tick() => {
+                    let mut state = self.state.lock().await;
+                    if let Err(e) = self.end_silent_talkers(&mut state).await {
+                        error!(error=%e, "failed to report talkers falling silent");
+                    }
+                }
                 msg = stream.next() => {
                     match msg {
                         Some(Ok(packet)) => {
@@ -435,32 +767,53 @@ impl Service for MumbleService {
     }
 
     async fn handle_command(&self, command: Command) -> Result<()> {
+        let Some(command) = self.seen_keys.screen(command) else {
+            return Ok(());
+        };
         let msg_tx = self.msg_tx.lock().await;
         let Some(tx) = msg_tx.as_ref() else {
-            return Err(anyhow!("mumble service not connected"));
+            command.reject(transient_error("mumble service not connected"));
+            return Err(transient_error("mumble service not connected"));
         };
 
         match command {
-            Command::SendDirectMessage { user_id, body, response_tx, .. } => {
+            Command::SendDirectMessage { response_tx, require_encryption: true, .. } => {
+                warn!("mumble text messages aren't end-to-end encrypted, not sending DM");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow::Error::new(NotEncrypted)));
+                }
+            }
+            Command::SendDirectMessage { user_id, body, response_tx, idempotency_key, .. } => {
                 debug!(user_id=%user_id, "sending direct message");
 
                 let state = self.state.lock().await;
                 let result = match state.user_sessions.get(&user_id) {
                     Some(session_id) => {
                         let mut msg = TextMessage::new();
-                        msg.set_message(body);
+                        // Clients show text messages as HTML
+                        msg.set_message(render(
+                            &body,
+                            BodyFormat::Plain,
+                            FormatProfile::MumbleHtml,
+                        ));
                         msg.session = vec![*session_id];
 
+                        let send_started = Instant::now();
                         match tx.send(ControlPacket::TextMessage(Box::new(msg))).await {
                             Ok(_) => {
+                                self.metrics.message_sent(send_started.elapsed());
                                 // Mumble doesn't provide message IDs, so we return an empty string
                                 Ok(String::new())
                             }
-                            Err(e) => Err(anyhow!("failed to send message: {}", e)),
+                            Err(e) => {
+                                self.metrics.send_failed();
+                                Err(transient_error(format!("failed to send message: {e}")))
+                            }
                         }
                     }
                     None => Err(anyhow!("unknown user: {}", user_id)),
                 };
+                self.seen_keys.record(idempotency_key.as_deref(), &result);
 
                 if let Some(tx) = response_tx {
                     let _ = tx.send(result);
@@ -468,26 +821,39 @@ impl Service for MumbleService {
                     return Err(e);
                 }
             }
-            Command::SendRoomMessage { room_id, body, response_tx, .. } => {
+            Command::SendRoomMessage {
+                room_id,
+                body,
+                format,
+                response_tx,
+                idempotency_key,
+                ..
+            } => {
                 debug!(room_id=%room_id, "sending room message");
 
                 let state = self.state.lock().await;
                 let result = match state.channel_ids.get(&room_id) {
                     Some(channel_id) => {
                         let mut msg = TextMessage::new();
-                        msg.set_message(body);
+                        msg.set_message(render(&body, format, FormatProfile::MumbleHtml));
                         msg.channel_id = vec![*channel_id];
 
+                        let send_started = Instant::now();
                         match tx.send(ControlPacket::TextMessage(Box::new(msg))).await {
                             Ok(_) => {
+                                self.metrics.message_sent(send_started.elapsed());
                                 // Mumble doesn't provide message IDs, so we return an empty string
                                 Ok(String::new())
                             }
-                            Err(e) => Err(anyhow!("failed to send message: {}", e)),
+                            Err(e) => {
+                                self.metrics.send_failed();
+                                Err(transient_error(format!("failed to send message: {e}")))
+                            }
                         }
                     }
                     None => Err(anyhow!("unknown channel: {}", room_id)),
                 };
+                self.seen_keys.record(idempotency_key.as_deref(), &result);
 
                 if let Some(tx) = response_tx {
                     let _ = tx.send(result);
@@ -498,6 +864,9 @@ impl Service for MumbleService {
             Command::EditMessage { .. } => {
                 warn!("mumble does not support editing messages");
             }
+            Command::DeleteMessage { .. } => {
+                warn!("mumble does not support deleting messages");
+            }
             Command::GenerateInviteToken { response_tx, .. } => {
                 warn!("mumble does not support invite token generation");
                 let _ = response_tx.send(Err(anyhow!("not supported by mumble")));
@@ -511,6 +880,90 @@ impl Service for MumbleService {
             Command::AddReaction { .. } => {
                 warn!("mumble does not support reactions");
             }
+            Command::GetRoomState { response_tx, .. } => {
+                warn!("mumble does not support room state");
+                let _ = response_tx.send(Err(anyhow!("room state not supported by mumble")));
+            }
+            Command::SetRoomState { response_tx, .. } => {
+                warn!("mumble does not support room state");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow!("room state not supported by mumble")));
+                }
+            }
+            Command::SetRoomTopic { response_tx, .. } => {
+                warn!("mumble does not support room topics");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow!("room topics not supported by mumble")));
+                }
+            }
+            Command::ApproveKnock { response_tx, .. } => {
+                warn!("mumble does not support knocking");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow!("knocking not supported by mumble")));
+                }
+            }
+            Command::GetUserList { response_tx, .. } => {
+                let state = self.state.lock().await;
+                let mut users: Vec<ConnectedUser> = state
+                    .session_users
+                    .keys()
+                    .filter_map(|session| {
+                        let user = Self::user(&state, *session)?;
+                        let room_id = state
+                            .voices
+                            .get(session)
+                            .map(|voice| channel_room_id(&state.id_channels, voice.channel_id));
+                        Some(ConnectedUser { user, room_id })
+                    })
+                    .collect();
+                users.sort_by(|a, b| a.user.display_name.cmp(&b.user.display_name));
+                let _ = response_tx.send(Ok(users));
+            }
+            Command::Ping { response_tx, .. } => {
+                debug!("sending ping on request");
+                // Registered first so the echo can't beat it
+                self.state.lock().await.ping_waiters.push(response_tx);
+                if let Err(e) = tx.send(ping_packet()).await {
+                    // The waiter is dropped with the state when the connection is reset
+                    error!(error=%e, "failed to send ping");
+                }
+            }
+            // Channels can't be kicked from, so the user is disconnected from the server
+            Command::KickUser { user_id, reason, response_tx, .. } => {
+                debug!(user_id=%user_id, "kicking user");
+                let session = self.state.lock().await.user_sessions.get(&user_id).copied();
+                let result = match session {
+                    Some(session) => {
+                        let mut msg = UserRemove::new();
+                        msg.set_session(session);
+                        if let Some(reason) = reason {
+                            msg.set_reason(reason);
+                        }
+                        tx.send(ControlPacket::UserRemove(Box::new(msg)))
+                            .await
+                            .map(|_| String::new())
+                            .map_err(|e| transient_error(format!("failed to kick user: {e}")))
+                    }
+                    None => Err(anyhow!("unknown user: {}", user_id)),
+                };
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(result);
+                } else if let Err(e) = result {
+                    return Err(e);
+                }
+            }
+            Command::JoinRoom { response_tx, .. } => {
+                warn!("mumble does not support joining rooms on request");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow!("joining rooms not supported by mumble")));
+                }
+            }
+            Command::ConfirmVerification { response_tx, .. } => {
+                warn!("mumble does not support device verification");
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Err(anyhow!("device verification not supported by mumble")));
+                }
+            }
             Command::SendRoomImage {
                 room_id,
                 caption,
@@ -537,9 +990,16 @@ impl Service for MumbleService {
                         msg.set_message(html);
                         msg.channel_id = vec![*channel_id];
 
+                        let send_started = Instant::now();
                         match tx.send(ControlPacket::TextMessage(Box::new(msg))).await {
-                            Ok(_) => Ok(String::new()),
-                            Err(e) => Err(anyhow!("failed to send image message: {}", e)),
+                            Ok(_) => {
+                                self.metrics.message_sent(send_started.elapsed());
+                                Ok(String::new())
+                            }
+                            Err(e) => {
+                                self.metrics.send_failed();
+                                Err(anyhow!("failed to send image message: {}", e))
+                            }
                         }
                     }
                     None => Err(anyhow!("unknown channel: {}", room_id)),
@@ -549,8 +1009,24 @@ impl Service for MumbleService {
                     error!(error=%e, room_id=%room_id, "failed to relay image to mumble");
                 }
             }
+            command @ Command::Announce { .. } => {
+                warn!("announcement should be expanded by the bus, not dispatched to a service");
+                command.reject(anyhow!("announcements are expanded by the bus"));
+            }
+            Command::Control(_) => {
+                warn!("bus control command should not be dispatched to a service");
+            }
         }
 
         Ok(())
     }
+
+    fn capabilities(&self) -> ServiceCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn ready(&self) {
+        // Only errors if the sender is dropped, which can't happen while `self` is alive
+        let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+    }
 }
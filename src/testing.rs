@@ -0,0 +1,277 @@
+//! Building blocks for testing services and middlewares written against this crate without a
+//! live chat server: a controllable service, a recording middleware, and a command channel with
+//! assertion helpers.
+
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::{Mutex, mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::core::{
+    bridge_map::{BridgeMap, DEFAULT_TTL},
+    bus::{Command, CommandSender, EventTx},
+    event::{Event, EventKind},
+    http::HttpClient,
+    loop_detection::RecentRelays,
+    media::MediaStore,
+    middleware::{Middleware, MiddlewareContext, Verdict},
+    paste::Paster,
+    preferences::PreferenceStore,
+    room_settings::RoomSettingsStore,
+    roster::Roster,
+    scheduler::SchedulerHandle,
+    service::{Service, ServiceDirectory, ServiceId},
+    subscriptions::SubscriptionStore,
+};
+use crate::store::PersistentStore;
+
+// How long `CommandCapture` waits for a command before failing the test
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A service that emits room messages on request instead of connecting anywhere.
+///
+/// Send a count to the control channel returned by `new` and the service emits that many
+/// `RoomMessage` events (`room_0`, `room_1`, ...) to the bus.
+#[derive(Debug)]
+pub struct MockService {
+    pub id: ServiceId,
+    pub evt_tx: EventTx,
+    /// Commands to send events (send event count to this channel)
+    pub command_rx: Arc<Mutex<mpsc::Receiver<usize>>>,
+    // Where commands sent to this service go, if anywhere
+    forward_tx: Option<mpsc::Sender<Command>>,
+}
+
+impl MockService {
+    /// Create a new mock service with a command channel for controlling event sending
+    pub fn new(id: ServiceId, evt_tx: EventTx) -> (Self, mpsc::Sender<usize>) {
+        let (cmd_tx, cmd_rx) = mpsc::channel(10);
+
+        let service =
+            MockService { id, evt_tx, command_rx: Arc::new(Mutex::new(cmd_rx)), forward_tx: None };
+
+        (service, cmd_tx)
+    }
+
+    /// Forwards every command the bus dispatches to this service, e.g. to a `CommandCapture`.
+    pub fn forward_commands(mut self, tx: mpsc::Sender<Command>) -> Self {
+        self.forward_tx = Some(tx);
+        self
+    }
+}
+
+#[async_trait]
+impl Service for MockService {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        let mut command_rx = self.command_rx.lock().await;
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    break;
+                }
+                maybe_count = command_rx.recv() => {
+                    let Some(count) = maybe_count else { break };
+
+                    // Send the requested number of events
+                    for i in 0..count {
+                        let event = Event {
+                            service_id: self.id.clone(),
+                            kind: EventKind::RoomMessage {
+                                room_id: format!("room_{i}"),
+                                body: format!("test message {i}"),
+                                is_local_user: false,
+                                sender_id: "test_user".to_string(),
+                                sender_display_name: Some("Test User".to_string()),
+                                is_self: false,
+                                mentions_self: false,
+                                relayed_from: None,
+                                thread_root_id: None,
+                                message_id: None,
+                            },
+                        };
+
+                        if (self.evt_tx.send(event).await).is_err() {
+                            // Channel closed, service should stop
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_command(&self, command: Command) -> Result<()> {
+        tracing::debug!(?command, "mock service received command");
+        if let Some(tx) = &self.forward_tx {
+            tx.send(command).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A middleware that records every event it sees and answers with a fixed verdict.
+pub struct MockMiddleware {
+    verdict: Verdict,
+    commands: Vec<String>,
+    events: StdMutex<Vec<Arc<Event>>>,
+}
+
+impl MockMiddleware {
+    pub fn new(verdict: Verdict) -> Self {
+        Self { verdict, commands: Vec::new(), events: StdMutex::new(Vec::new()) }
+    }
+
+    /// Command strings to report from `command_strings`, for exercising duplicate detection
+    /// and first-match dispatch.
+    pub fn with_commands(mut self, commands: &[&str]) -> Self {
+        self.commands = commands.iter().map(|command| command.to_string()).collect();
+        self
+    }
+
+    /// Events seen so far, in order.
+    pub fn events(&self) -> Vec<Arc<Event>> {
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len()
+    }
+}
+
+#[async_trait]
+impl Middleware for MockMiddleware {
+    async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        cancel.cancelled().await;
+        Ok(())
+    }
+
+    fn on_event(&self, event: &Arc<Event>) -> Result<Verdict> {
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(event.clone());
+        Ok(self.verdict.clone())
+    }
+
+    fn command_strings(&self) -> Vec<&str> {
+        self.commands.iter().map(String::as_str).collect()
+    }
+}
+
+/// A context for constructing a middleware under test, backed by an in-memory store and no
+/// known services. Commands it sends are attributed to `test`.
+pub fn middleware_context(cmd_tx: mpsc::Sender<Command>) -> MiddlewareContext {
+    MiddlewareContext {
+        cmd_tx: CommandSender::new(cmd_tx, "test"),
+        evt_tx: None,
+        store: Arc::new(PersistentStore::in_memory()),
+        services: ServiceDirectory::default(),
+        preferences: PreferenceStore::in_memory(),
+        subscriptions: SubscriptionStore::in_memory(),
+        room_settings: RoomSettingsStore::in_memory(),
+        bridge_map: BridgeMap::in_memory(DEFAULT_TTL).unwrap(),
+        recent_relays: RecentRelays::default(),
+        roster: Roster::default(),
+        media: MediaStore::default(),
+        paste: Paster::default(),
+        http: HttpClient::default(),
+        scheduler: SchedulerHandle::default(),
+        quiet_hours: None,
+    }
+}
+
+/// A command channel whose receiving end is a `CommandCapture`.
+pub fn command_capture(cap: usize) -> (mpsc::Sender<Command>, CommandCapture) {
+    let (tx, rx) = mpsc::channel(cap);
+    (tx, CommandCapture { rx })
+}
+
+/// Receiving end of a command channel, with helpers for asserting what was sent.
+///
+/// The `expect_*` helpers panic (failing the test) if no command arrives within a second or
+/// the next command is of a different kind. Commands expecting a response get `Ok` with a
+/// placeholder message ID, so middlewares awaiting one carry on.
+pub struct CommandCapture {
+    rx: mpsc::Receiver<Command>,
+}
+
+impl CommandCapture {
+    /// The next command sent.
+    pub async fn next(&mut self) -> Command {
+        match tokio::time::timeout(CAPTURE_TIMEOUT, self.rx.recv()).await {
+            Ok(Some(command)) => command,
+            Ok(None) => panic!("command channel closed while waiting for a command"),
+            Err(_) => panic!("no command sent within {CAPTURE_TIMEOUT:?}"),
+        }
+    }
+
+    /// The next command, which must be a room message. Returns its service, room and body.
+    pub async fn expect_room_message(&mut self) -> (ServiceId, String, String) {
+        match self.next().await {
+            Command::SendRoomMessage { service_id, room_id, body, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok("$mock-event-id".to_string()));
+                }
+                (service_id, room_id, body)
+            }
+            other => panic!("expected a room message, got {other:?}"),
+        }
+    }
+
+    /// The next command, which must be a direct message. Returns its service, user and body.
+    pub async fn expect_direct_message(&mut self) -> (ServiceId, String, String) {
+        match self.next().await {
+            Command::SendDirectMessage { service_id, user_id, body, response_tx, .. } => {
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(Ok("$mock-event-id".to_string()));
+                }
+                (service_id, user_id, body)
+            }
+            other => panic!("expected a direct message, got {other:?}"),
+        }
+    }
+
+    /// Panics if a command has been sent and not yet taken.
+    pub fn assert_empty(&mut self) {
+        if let Ok(command) = self.rx.try_recv() {
+            panic!("expected no commands, got {command:?}");
+        }
+    }
+}
+
+/// A room message from another user.
+pub fn room_message(service_id: &str, room_id: &str, sender_id: &str, body: &str) -> Event {
+    Event {
+        service_id: ServiceId(service_id.to_string()),
+        kind: EventKind::RoomMessage {
+            room_id: room_id.to_string(),
+            body: body.to_string(),
+            is_local_user: false,
+            sender_id: sender_id.to_string(),
+            sender_display_name: None,
+            is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
+        },
+    }
+}
+
+/// A direct message from another user.
+pub fn direct_message(service_id: &str, user_id: &str, body: &str) -> Event {
+    Event {
+        service_id: ServiceId(service_id.to_string()),
+        kind: EventKind::DirectMessage {
+            user_id: user_id.to_string(),
+            body: body.to_string(),
+            is_local_user: false,
+            sender_id: user_id.to_string(),
+            sender_display_name: None,
+            is_self: false,
+        },
+    }
+}
@@ -0,0 +1,106 @@
+use kelvin_bot::KelvinBot;
+use kelvin_bot::core::{
+    bus::Command,
+    config::Config,
+    format::BodyFormat,
+    middleware::Middleware,
+    service::{Service, ServiceId},
+};
+use kelvin_bot::middlewares::echo::Echo;
+use kelvin_bot::testing::{MockService, command_capture, room_message};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio_test::assert_ok;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn test_builder_runs_services_and_middlewares_defined_in_code() {
+    let data_directory = TempDir::new().unwrap();
+    let mut config = Config::empty();
+    config.data_directory = data_directory.path().to_path_buf();
+
+    let (capture_tx, mut capture) = command_capture(10);
+    let (evt_tx_out, evt_tx_in) = tokio::sync::oneshot::channel();
+    let bot = KelvinBot::builder()
+        .config(config)
+        .service("chat", move |evt_tx| {
+            let _ = evt_tx_out.send(evt_tx.clone());
+            let (service, _control) = MockService::new(ServiceId("chat".to_string()), evt_tx);
+            Ok(Arc::new(service.forward_commands(capture_tx)) as Arc<dyn Service>)
+        })
+        .middleware("echo", |ctx| {
+            Ok(Arc::new(Echo::new(ctx, "!echo".to_string())) as Arc<dyn Middleware>)
+        })
+        .pipeline("chat", &["echo"])
+        .build()
+        .await
+        .unwrap();
+    let evt_tx = evt_tx_in.await.unwrap();
+    let mut events = bot.subscribe();
+    let cmd_tx = bot.command_sender();
+
+    let cancel = CancellationToken::new();
+    let bot_task = tokio::spawn(bot.run(cancel.clone()));
+
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "!echo hi")).await.unwrap();
+    let (service_id, room_id, body) = capture.expect_room_message().await;
+    assert_eq!((service_id.0.as_str(), room_id.as_str(), body.as_str()), ("chat", "!lobby", "hi"));
+    let tapped =
+        tokio::time::timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap();
+    assert_eq!(tapped.kind.message_body(), Some("!echo hi"));
+
+    // Commands sent from outside the pipeline reach the services too
+    cmd_tx
+        .send(Command::SendRoomMessage {
+            service_id: ServiceId("chat".to_string()),
+            room_id: "!lobby".to_string(),
+            body: "hello from the host program".to_string(),
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(capture.expect_room_message().await.2, "hello from the host program");
+
+    cancel.cancel();
+    assert_ok!(bot_task.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_builder_rejects_names_clashing_with_the_config() {
+    let data_directory = TempDir::new().unwrap();
+    let config_text = format!(
+        r#"
+        data_directory = {:?}
+
+        [services.chat]
+        kind = "dummy"
+
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+        "#,
+        data_directory.path().to_string_lossy()
+    );
+
+    let config: Config = toml::from_str(&config_text).unwrap();
+    let result = KelvinBot::builder()
+        .config(config)
+        .middleware("echo", |ctx| {
+            Ok(Arc::new(Echo::new(ctx, "!echo".to_string())) as Arc<dyn Middleware>)
+        })
+        .build()
+        .await;
+    let error = result.err().expect("a clashing middleware name should fail").to_string();
+    assert!(error.contains("middleware 'echo' is defined both"), "unexpected error: {error}");
+
+    let config: Config = toml::from_str(&config_text).unwrap();
+    let result = KelvinBot::builder().config(config).pipeline("elsewhere", &["echo"]).build().await;
+    let error = result.err().expect("a pipeline for an unknown service should fail").to_string();
+    assert!(error.contains("unknown service 'elsewhere'"), "unexpected error: {error}");
+}
@@ -1,6 +1,10 @@
 use kelvin_bot::core::{
     bus::{create_command_channel, create_event_channel},
-    config::{Config, MiddlewareCfg, MiddlewareKind, ReconnectionConfig, ServiceCfg, ServiceKind},
+    config::{
+        CommandDispatch, Config, MiddlewareCfg, MiddlewareKind, ReconnectionConfig, ServiceCfg,
+        ServiceKind,
+    },
+    metrics::MetricsRegistry,
     middleware::instantiate_middleware_from_config,
     service::instantiate_services_from_config,
 };
@@ -17,7 +21,7 @@ async fn test_unknown_service_kind_handling() {
     let config: Config = toml::from_str(config_str).expect("Failed to parse config");
     let (evt_tx, _evt_rx) = create_event_channel(10);
 
-    let services = instantiate_services_from_config(&config, &evt_tx)
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
         .await
         .expect("Failed to instantiate services");
 
@@ -32,13 +36,35 @@ async fn test_configuration_with_mixed_service_types() {
     // Add a valid dummy service
     services.insert(
         "dummy1".to_string(),
-        ServiceCfg { kind: ServiceKind::Dummy { interval_ms: Some(100) }, middleware: None },
+        ServiceCfg {
+            kind: ServiceKind::Dummy { interval_ms: Some(100) },
+            middleware: None,
+            rooms: HashMap::new(),
+            room_allowlist: None,
+            room_denylist: None,
+            schedule: None,
+            enabled: true,
+            command_prefix: None,
+            read_only: false,
+            startup_timeout: None,
+        },
     );
 
     // Add an unknown service type
     services.insert(
         "unknown1".to_string(),
-        ServiceCfg { kind: ServiceKind::Unknown, middleware: None },
+        ServiceCfg {
+            kind: ServiceKind::Unknown,
+            middleware: None,
+            rooms: HashMap::new(),
+            room_allowlist: None,
+            room_denylist: None,
+            schedule: None,
+            enabled: true,
+            command_prefix: None,
+            read_only: false,
+            startup_timeout: None,
+        },
     );
 
     let config = Config {
@@ -46,12 +72,33 @@ async fn test_configuration_with_mixed_service_types() {
         middlewares: HashMap::new(),
         data_directory: TempDir::new().unwrap().path().to_path_buf(),
         reconnection: ReconnectionConfig::default(),
+        global_middleware: None,
+        outbox: None,
+        coordination: None,
+        command_dispatch: CommandDispatch::default(),
+        quiet_hours: None,
+        lifecycle_announcements: None,
+        panic_guard: Default::default(),
+        middleware_latency_budget: None,
+        event_lag_warning: None,
+        redaction: Default::default(),
+        logging: Default::default(),
+        error_reporting: None,
+        roster: Default::default(),
+        media: None,
+        paste: None,
+        http: Default::default(),
+        aliases: HashMap::new(),
+        audit: None,
+        watchdog: None,
+        operator_alerts: None,
     };
 
     let (evt_tx, _evt_rx) = create_event_channel(10);
-    let instantiated_services = instantiate_services_from_config(&config, &evt_tx)
-        .await
-        .expect("Failed to instantiate services");
+    let instantiated_services =
+        instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
+            .await
+            .expect("Failed to instantiate services");
 
     // Only the valid dummy service should be instantiated
     assert_eq!(instantiated_services.len(), 1);
@@ -97,6 +144,14 @@ async fn test_service_with_middleware_list_configuration() {
         ServiceCfg {
             kind: ServiceKind::Dummy { interval_ms: Some(100) },
             middleware: Some(vec!["echo1".to_string(), "logger1".to_string()]),
+            rooms: HashMap::new(),
+            room_allowlist: None,
+            room_denylist: None,
+            schedule: None,
+            enabled: true,
+            command_prefix: None,
+            read_only: false,
+            startup_timeout: None,
         },
     );
     services.insert(
@@ -104,26 +159,70 @@ async fn test_service_with_middleware_list_configuration() {
         ServiceCfg {
             kind: ServiceKind::Dummy { interval_ms: Some(200) },
             middleware: Some(vec!["logger1".to_string()]),
+            rooms: HashMap::new(),
+            room_allowlist: None,
+            room_denylist: None,
+            schedule: None,
+            enabled: true,
+            command_prefix: None,
+            read_only: false,
+            startup_timeout: None,
         },
     );
 
     let mut middlewares_map = HashMap::new();
     middlewares_map.insert(
         "echo1".to_string(),
-        MiddlewareCfg { kind: MiddlewareKind::Echo { command_string: "!test".to_string() } },
+        MiddlewareCfg {
+            kind: MiddlewareKind::Echo { command_string: "!test".to_string() },
+            shared: None,
+            quiet_hours: None,
+            allowed_commands: None,
+            enabled: true,
+            dry_run: false,
+        },
+    );
+    middlewares_map.insert(
+        "logger1".to_string(),
+        MiddlewareCfg {
+            kind: MiddlewareKind::Logger {},
+            shared: None,
+            quiet_hours: None,
+            allowed_commands: None,
+            enabled: true,
+            dry_run: false,
+        },
     );
-    middlewares_map
-        .insert("logger1".to_string(), MiddlewareCfg { kind: MiddlewareKind::Logger {} });
 
     let config = Config {
         services,
         middlewares: middlewares_map,
         data_directory: TempDir::new().unwrap().path().to_path_buf(),
         reconnection: ReconnectionConfig::default(),
+        global_middleware: None,
+        outbox: None,
+        coordination: None,
+        command_dispatch: CommandDispatch::default(),
+        quiet_hours: None,
+        lifecycle_announcements: None,
+        panic_guard: Default::default(),
+        middleware_latency_budget: None,
+        event_lag_warning: None,
+        redaction: Default::default(),
+        logging: Default::default(),
+        error_reporting: None,
+        roster: Default::default(),
+        media: None,
+        paste: None,
+        http: Default::default(),
+        aliases: HashMap::new(),
+        audit: None,
+        watchdog: None,
+        operator_alerts: None,
     };
 
     let (cmd_tx, _cmd_rx) = create_command_channel(10);
-    let middlewares = instantiate_middleware_from_config(&config, &cmd_tx)
+    let middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &HashMap::new())
         .expect("Failed to instantiate middlewares");
 
     // Verify all middleware instances were created
@@ -0,0 +1,606 @@
+//! End-to-end tests against a real Synapse homeserver and Mumble server, each started in a
+//! Docker container. They exercise `MatrixService` and `MumbleService` the way a deployment
+//! does: logging in, sending and receiving, editing, relaying between the two, and reconnecting
+//! after the server restarts.
+//!
+//! Only built with the `e2e` feature, and ignored unless asked for since they need Docker and
+//! take minutes:
+//!
+//! ```bash
+//! cargo test --features e2e --test integration_tests e2e -- --ignored --test-threads=1
+//! ```
+//!
+//! `KELVIN_E2E_SYNAPSE_IMAGE` and `KELVIN_E2E_MUMBLE_IMAGE` override the images used.
+use kelvin_bot::core::{
+    bus::{Bus, Command, create_command_channel, create_event_channel, create_event_tap},
+    config::{Config, ReconnectionConfig},
+    event::{Event, EventKind},
+    format::BodyFormat,
+    metrics::MetricsRegistry,
+    middleware::{build_service_pipelines, instantiate_middleware_from_config},
+    service::{Service, ServiceId, instantiate_services_from_config},
+};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::process::Command as Process;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_SYNAPSE_IMAGE: &str = "matrixdotorg/synapse:latest";
+// umurmur has no maintained image, so the reference server stands in for it
+const DEFAULT_MUMBLE_IMAGE: &str = "mumblevoip/mumble-server:latest";
+const SERVER_NAME: &str = "localhost";
+const PASSWORD: &str = "correct-horse-battery-staple";
+// Generous, since CI runners pull images and start Synapse from cold
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(180);
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Appended to the generated homeserver.yaml: open registration for the test users, and rate
+// limits high enough that a burst of test traffic isn't throttled
+const SYNAPSE_CONFIG: &str = "
+enable_registration: true
+enable_registration_without_verification: true
+rc_message: {per_second: 1000, burst_count: 1000}
+rc_registration: {per_second: 1000, burst_count: 1000}
+rc_joins:
+  local: {per_second: 1000, burst_count: 1000}
+  remote: {per_second: 1000, burst_count: 1000}
+rc_invites:
+  per_room: {per_second: 1000, burst_count: 1000}
+  per_user: {per_second: 1000, burst_count: 1000}
+rc_login:
+  address: {per_second: 1000, burst_count: 1000}
+  account: {per_second: 1000, burst_count: 1000}
+  failed_attempts: {per_second: 1000, burst_count: 1000}
+";
+
+/// Runs `docker` with `args`, returning its trimmed stdout.
+fn docker(args: &[&str]) -> String {
+    let output = Process::new("docker")
+        .args(args)
+        .output()
+        .expect("failed to run docker; is it installed and on the PATH?");
+    assert!(
+        output.status.success(),
+        "docker {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// A port nothing is listening on, for publishing a container's port at a known address that
+/// survives the container restarting.
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// A running container, removed when dropped so a failed test doesn't leave it behind.
+struct Container {
+    name: String,
+    port: u16,
+}
+
+impl Container {
+    /// Starts `image` with `container_port` published on a free local port, passing `args`
+    /// to `docker run` before the image and `command` after it.
+    fn start(image: &str, container_port: u16, args: &[&str], command: &[&str]) -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let name = format!("kelvin-e2e-{}-{nanos}", std::process::id());
+        let port = free_port();
+        let publish = format!("127.0.0.1:{port}:{container_port}");
+        let mut run =
+            vec!["run", "--detach", "--name", name.as_str(), "--publish", publish.as_str()];
+        run.extend_from_slice(args);
+        run.push(image);
+        run.extend_from_slice(command);
+        docker(&run);
+        Self { name, port }
+    }
+
+    fn restart(&self) {
+        docker(&["restart", self.name.as_str()]);
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Process::new("docker")
+            .args(["rm", "--force", "--volumes", self.name.as_str()])
+            .output();
+    }
+}
+
+/// Retries `attempt` until it returns something or `STARTUP_TIMEOUT` passes.
+async fn wait_for<T, F, Fut>(what: &str, mut attempt: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let deadline = tokio::time::Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if let Some(value) = attempt().await {
+            return value;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "timed out waiting for {what}");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// A Synapse homeserver for `SERVER_NAME`.
+struct Synapse {
+    container: Container,
+    http: reqwest::Client,
+}
+
+impl Synapse {
+    async fn start() -> Self {
+        let image = std::env::var("KELVIN_E2E_SYNAPSE_IMAGE")
+            .unwrap_or_else(|_| DEFAULT_SYNAPSE_IMAGE.to_string());
+        let script = format!(
+            "/start.py generate && cat >> /data/homeserver.yaml <<'EOF'{SYNAPSE_CONFIG}EOF\n\
+             exec /start.py"
+        );
+        let server_name = format!("SYNAPSE_SERVER_NAME={SERVER_NAME}");
+        let container = Container::start(
+            &image,
+            8008,
+            &[
+                "--env",
+                server_name.as_str(),
+                "--env",
+                "SYNAPSE_REPORT_STATS=no",
+                "--entrypoint",
+                "/bin/sh",
+            ],
+            &["-c", &script],
+        );
+        let synapse = Self { container, http: reqwest::Client::new() };
+        let health = format!("{}/health", synapse.url());
+        let (http, health) = (&synapse.http, health.as_str());
+        wait_for("Synapse to start", move || async move {
+            let response = http.get(health).send().await.ok()?;
+            response.status().is_success().then_some(())
+        })
+        .await;
+        synapse
+    }
+
+    fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.container.port)
+    }
+
+    /// Registers `username`, answering the dummy auth stage Synapse asks for.
+    async fn register(&self, username: &str) -> MatrixUser {
+        let url = format!("{}/_matrix/client/v3/register", self.url());
+        let mut request = json!({ "username": username, "password": PASSWORD });
+        let challenge: Value =
+            self.http.post(&url).json(&request).send().await.unwrap().json().await.unwrap();
+        request["auth"] = json!({ "type": "m.login.dummy", "session": challenge["session"] });
+        let response = self.http.post(&url).json(&request).send().await.unwrap();
+        assert!(response.status().is_success(), "failed to register {username}");
+        let registered: Value = response.json().await.unwrap();
+        MatrixUser {
+            http: self.http.clone(),
+            url: self.url(),
+            user_id: registered["user_id"].as_str().unwrap().to_string(),
+            access_token: registered["access_token"].as_str().unwrap().to_string(),
+        }
+    }
+}
+
+/// A user driven through the client-server API, standing in for a person on Matrix.
+struct MatrixUser {
+    http: reqwest::Client,
+    url: String,
+    user_id: String,
+    access_token: String,
+}
+
+impl MatrixUser {
+    fn room_url(&self, room_id: &str, path: &str) -> String {
+        let room_id: String = url::form_urlencoded::byte_serialize(room_id.as_bytes()).collect();
+        format!("{}/_matrix/client/v3/rooms/{room_id}/{path}", self.url)
+    }
+
+    /// Creates an unencrypted room and invites `invitee` to it.
+    async fn create_room(&self, invitee: &str) -> String {
+        let response: Value = self
+            .http
+            .post(format!("{}/_matrix/client/v3/createRoom", self.url))
+            .bearer_auth(&self.access_token)
+            .json(&json!({ "preset": "public_chat", "invite": [invitee] }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        response["room_id"].as_str().expect("room wasn't created").to_string()
+    }
+
+    async fn wait_for_member(&self, room_id: &str, user_id: &str) {
+        let url = self.room_url(room_id, "joined_members");
+        let url = url.as_str();
+        wait_for(&format!("{user_id} to join {room_id}"), move || async move {
+            let response = self.http.get(url).bearer_auth(&self.access_token).send().await.ok()?;
+            let members: Value = response.json().await.ok()?;
+            members["joined"].get(user_id).map(|_| ())
+        })
+        .await;
+    }
+
+    async fn send(&self, room_id: &str, body: &str) {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let response = self
+            .http
+            .put(self.room_url(room_id, &format!("send/m.room.message/{nanos}")))
+            .bearer_auth(&self.access_token)
+            .json(&json!({ "msgtype": "m.text", "body": body }))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success(), "failed to send to {room_id}");
+    }
+
+    /// Waits for a message in the room's recent history matching `matches`.
+    async fn expect_message(&self, room_id: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        let url = self.room_url(room_id, "messages?dir=b&limit=50");
+        tokio::time::timeout(MESSAGE_TIMEOUT, async {
+            loop {
+                let response: Value = self
+                    .http
+                    .get(&url)
+                    .bearer_auth(&self.access_token)
+                    .send()
+                    .await
+                    .unwrap()
+                    .json()
+                    .await
+                    .unwrap();
+                let chunk = response["chunk"].as_array().cloned().unwrap_or_default();
+                if let Some(message) = chunk.into_iter().find(|message| matches(message)) {
+                    return message;
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for a message in {room_id}"))
+    }
+}
+
+/// A Mumble server with open access.
+struct Mumble {
+    container: Container,
+}
+
+impl Mumble {
+    async fn start() -> Self {
+        let image = std::env::var("KELVIN_E2E_MUMBLE_IMAGE")
+            .unwrap_or_else(|_| DEFAULT_MUMBLE_IMAGE.to_string());
+        let mumble = Self { container: Container::start(&image, 64738, &[], &[]) };
+        let address = format!("127.0.0.1:{}", mumble.container.port);
+        let address = address.as_str();
+        wait_for("the Mumble server to start", move || async move {
+            tokio::net::TcpStream::connect(address).await.ok().map(|_| ())
+        })
+        .await;
+        mumble
+    }
+
+    /// Config for a service connecting to this server as `username`.
+    fn service_config(&self, id: &str, username: &str) -> String {
+        format!(
+            r#"
+            [services.{id}]
+            kind = "mumble"
+            hostname = "127.0.0.1"
+            port = "{}"
+            username = "{username}"
+            password = ""
+            accept_invalid_certs = "true"
+            "#,
+            self.container.port
+        )
+    }
+}
+
+/// Config for a Matrix service logging in as `user`.
+fn matrix_service_config(synapse: &Synapse, id: &str, user: &MatrixUser) -> String {
+    format!(
+        r#"
+        [services.{id}]
+        kind = "matrix"
+        homeserver_url = "{}"
+        user_id = "{}"
+        password = "{PASSWORD}"
+        device_id = "KELVINE2E"
+        db_passphrase = "e2e"
+        "#,
+        synapse.url(),
+        user.user_id
+    )
+}
+
+/// The bot, running its services and middlewares on a bus the way `main` does, with a tap to
+/// watch the events they produce.
+struct Bot {
+    cmd_tx: mpsc::Sender<Command>,
+    services: HashMap<ServiceId, Arc<dyn Service>>,
+    tap_rx: broadcast::Receiver<Arc<Event>>,
+    cancel: CancellationToken,
+    _data_directory: TempDir,
+}
+
+impl Bot {
+    async fn start(config_toml: &str) -> Self {
+        let data_directory = TempDir::new().unwrap();
+        let config_toml = format!(
+            "data_directory = {:?}\n{config_toml}",
+            data_directory.path().to_string_lossy()
+        );
+        let config: Config = toml::from_str(&config_toml).expect("Failed to parse config");
+
+        let (cmd_tx, cmd_rx) = create_command_channel(100);
+        let (evt_tx, evt_rx) = create_event_channel(100);
+        let event_tap = create_event_tap(100);
+        let tap_rx = event_tap.subscribe();
+        let services =
+            instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
+                .await
+                .unwrap();
+        let middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &services).unwrap();
+        let pipelines = build_service_pipelines(&config, &middlewares).unwrap();
+        // Retry quickly, so reconnecting after a restart doesn't outlast the test's patience
+        let reconnection = ReconnectionConfig {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+            ..ReconnectionConfig::default()
+        };
+        let mut bus = Bus::new(evt_rx, cmd_rx, services.clone(), pipelines, reconnection)
+            .with_middleware_names(middlewares)
+            .with_event_tap(event_tap);
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move { bus.run(cancel).await }
+        });
+
+        Self { cmd_tx, services, tap_rx, cancel, _data_directory: data_directory }
+    }
+
+    async fn ready(&self, service_id: &str) {
+        let service = &self.services[&ServiceId(service_id.to_string())];
+        tokio::time::timeout(STARTUP_TIMEOUT, service.ready())
+            .await
+            .unwrap_or_else(|_| panic!("{service_id} never became ready"));
+    }
+
+    /// Sends `body` to a room through `service_id`, returning the sent message's ID.
+    async fn send(&self, service_id: &str, room_id: &str, body: &str) -> anyhow::Result<String> {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        self.cmd_tx
+            .send(Command::SendRoomMessage {
+                service_id: ServiceId(service_id.to_string()),
+                room_id: room_id.to_string(),
+                body: body.to_string(),
+                format: BodyFormat::Plain,
+                response_tx: Some(response_tx),
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            })
+            .await?;
+        response_rx.await?
+    }
+
+    /// Waits for a room message on `service_id` whose body contains `text`.
+    async fn expect_room_message(&mut self, service_id: &str, text: &str) -> Arc<Event> {
+        tokio::time::timeout(MESSAGE_TIMEOUT, async {
+            loop {
+                let evt = match self.tap_rx.recv().await {
+                    Ok(evt) => evt,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(e) => panic!("event tap closed: {e}"),
+                };
+                if evt.service_id.0 != service_id {
+                    continue;
+                }
+                if let EventKind::RoomMessage { body, .. } = &evt.kind
+                    && body.contains(text)
+                {
+                    return evt;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for '{text}' on {service_id}"))
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run with --features e2e -- --ignored"]
+async fn test_matrix_service_logs_in_sends_receives_and_edits() {
+    let synapse = Synapse::start().await;
+    let kelvin = synapse.register("kelvin").await;
+    let alice = synapse.register("alice").await;
+    let room_id = alice.create_room(&kelvin.user_id).await;
+
+    let mut bot = Bot::start(&matrix_service_config(&synapse, "matrix", &kelvin)).await;
+    bot.ready("matrix").await;
+    // The service joins rooms it's invited to on its own homeserver
+    alice.wait_for_member(&room_id, &kelvin.user_id).await;
+
+    alice.send(&room_id, "hello kelvin").await;
+    let evt = bot.expect_room_message("matrix", "hello kelvin").await;
+    let EventKind::RoomMessage { room_id: evt_room_id, sender_id, is_self, .. } = &evt.kind else {
+        unreachable!()
+    };
+    assert_eq!(evt_room_id, &room_id);
+    assert_eq!(sender_id, &alice.user_id);
+    assert!(!is_self);
+
+    let event_id = bot.send("matrix", &room_id, "hello alice").await.unwrap();
+    alice
+        .expect_message(&room_id, |message| {
+            message["event_id"] == event_id.as_str() && message["content"]["body"] == "hello alice"
+        })
+        .await;
+
+    bot.cmd_tx
+        .send(Command::EditMessage {
+            service_id: ServiceId("matrix".to_string()),
+            message_id: event_id.clone(),
+            new_body: "hello again, alice".to_string(),
+            format: BodyFormat::Plain,
+            origin: None,
+        })
+        .await
+        .unwrap();
+    alice
+        .expect_message(&room_id, |message| {
+            let content = &message["content"];
+            content["m.relates_to"]["rel_type"] == "m.replace"
+                && content["m.relates_to"]["event_id"] == event_id.as_str()
+                && content["m.new_content"]["body"] == "hello again, alice"
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run with --features e2e -- --ignored"]
+async fn test_mumble_service_sends_and_receives_channel_messages() {
+    let mumble = Mumble::start().await;
+    let config = format!(
+        "{}{}",
+        mumble.service_config("mumble", "Kelvin"),
+        mumble.service_config("mumble_alice", "Alice")
+    );
+
+    let mut bot = Bot::start(&config).await;
+    bot.ready("mumble").await;
+    bot.ready("mumble_alice").await;
+
+    bot.send("mumble_alice", "Root", "hello kelvin").await.unwrap();
+    let evt = bot.expect_room_message("mumble", "hello kelvin").await;
+    let EventKind::RoomMessage { room_id, sender_id, is_self, .. } = &evt.kind else {
+        unreachable!()
+    };
+    assert_eq!(room_id, "Root");
+    assert_eq!(sender_id, "Alice");
+    assert!(!is_self);
+
+    bot.send("mumble", "Root", "hello alice").await.unwrap();
+    bot.expect_room_message("mumble_alice", "hello alice").await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run with --features e2e -- --ignored"]
+async fn test_chat_relay_round_trips_between_matrix_and_mumble() {
+    let (synapse, mumble) = tokio::join!(Synapse::start(), Mumble::start());
+    let kelvin = synapse.register("kelvin").await;
+    let alice = synapse.register("alice").await;
+    let room_id = alice.create_room(&kelvin.user_id).await;
+
+    let config = format!(
+        r#"
+        [middlewares.matrix_to_mumble]
+        kind = "chatrelay"
+        source_service_id = "matrix"
+        source_room_id = "{room_id}"
+        dest_service_id = "mumble"
+        dest_room_id = "Root"
+        prefix_tag = "Matrix"
+
+        [middlewares.mumble_to_matrix]
+        kind = "chatrelay"
+        source_service_id = "mumble"
+        source_room_id = "Root"
+        dest_service_id = "matrix"
+        dest_room_id = "{room_id}"
+        prefix_tag = "Mumble"
+        {}
+        middleware = ["matrix_to_mumble"]
+        {}
+        middleware = ["mumble_to_matrix"]
+        {}
+        "#,
+        matrix_service_config(&synapse, "matrix", &kelvin),
+        mumble.service_config("mumble", "Kelvin"),
+        mumble.service_config("mumble_bob", "Bob")
+    );
+
+    let mut bot = Bot::start(&config).await;
+    for service_id in ["matrix", "mumble", "mumble_bob"] {
+        bot.ready(service_id).await;
+    }
+    alice.wait_for_member(&room_id, &kelvin.user_id).await;
+
+    alice.send(&room_id, "hi from matrix").await;
+    let evt = bot.expect_room_message("mumble_bob", "hi from matrix").await;
+    let EventKind::RoomMessage { body, .. } = &evt.kind else { unreachable!() };
+    assert!(body.starts_with("[Matrix]"), "relayed without its tag: {body}");
+
+    bot.send("mumble_bob", "Root", "hi from mumble").await.unwrap();
+    let message = alice
+        .expect_message(&room_id, |message| {
+            message["content"]["body"].as_str().is_some_and(|body| body.contains("hi from mumble"))
+        })
+        .await;
+    assert_eq!(message["sender"], kelvin.user_id.as_str());
+    let body = message["content"]["body"].as_str().unwrap();
+    assert!(body.starts_with("[Mumble] Bob"), "relayed without its tag: {body}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs Docker; run with --features e2e -- --ignored"]
+async fn test_services_reconnect_after_server_restarts() {
+    let (synapse, mumble) = tokio::join!(Synapse::start(), Mumble::start());
+    let kelvin = synapse.register("kelvin").await;
+    let alice = synapse.register("alice").await;
+    let room_id = alice.create_room(&kelvin.user_id).await;
+
+    let config = format!(
+        "{}{}{}",
+        matrix_service_config(&synapse, "matrix", &kelvin),
+        mumble.service_config("mumble", "Kelvin"),
+        mumble.service_config("mumble_alice", "Alice")
+    );
+    let mut bot = Bot::start(&config).await;
+    for service_id in ["matrix", "mumble", "mumble_alice"] {
+        bot.ready(service_id).await;
+    }
+    alice.wait_for_member(&room_id, &kelvin.user_id).await;
+
+    synapse.container.restart();
+    mumble.container.restart();
+
+    // Sending fails until the services have reconnected, and then goes through
+    let (sender, room_id) = (&bot, room_id.as_str());
+    wait_for("Mumble to reconnect", move || async move {
+        sender.send("mumble_alice", "Root", "back again").await.ok()
+    })
+    .await;
+    bot.expect_room_message("mumble", "back again").await;
+
+    alice.send(room_id, "still there?").await;
+    bot.expect_room_message("matrix", "still there?").await;
+    let sender = &bot;
+    wait_for("Matrix to reconnect", move || async move {
+        sender.send("matrix", room_id, "still here").await.ok()
+    })
+    .await;
+    alice.expect_message(room_id, |message| message["content"]["body"] == "still here").await;
+}
@@ -1,13 +1,36 @@
 use crate::common::MockService;
 use async_trait::async_trait;
 use kelvin_bot::core::{
-    bus::{Bus, create_command_channel, create_event_channel},
-    config::ReconnectionConfig,
-    event::Event,
-    middleware::{Middleware, Verdict},
-    service::ServiceId,
+    alerts::{self, ALERTS_ORIGIN, AlertTarget, OperatorAlerts},
+    audit::AuditLog,
+    bus::{
+        AnnouncementPriority, Bus, BusAlert, BusControl, Command, CommandOrigin, CommandPolicy,
+        CommandSender, Fault, PermissionDenied, RoomFilter, RoomTarget, create_alert_channel,
+        create_command_channel, create_event_channel, create_event_tap, transient_error,
+    },
+    commands::CommandSpec,
+    config::{
+        AlertLevel, AnnouncementDestination, CommandDispatch, Config, LifecycleAnnouncementsConfig,
+        ReconnectionConfig, WatchdogConfig,
+    },
+    coordination::Lease,
+    event::{Event, EventKind, User},
+    format::BodyFormat,
+    http::HttpClient,
+    metrics::{LagStage, MetricsRegistry},
+    middleware::{
+        Middleware, Verdict, build_service_pipelines, instantiate_middleware_with_reloader,
+    },
+    outbox::Outbox,
+    replay::replay,
+    roster::Roster,
+    service::{Service, ServiceCapabilities, ServiceId, ServiceLoader},
 };
-use std::collections::HashMap;
+use kelvin_bot::middlewares::echo::Echo;
+use kelvin_bot::middlewares::logger::Logger;
+use kelvin_bot::testing::{MockMiddleware, command_capture, middleware_context, room_message};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio_test::assert_ok;
@@ -28,7 +51,7 @@ async fn test_end_to_end_event_processing_pipeline() {
             Ok(())
         }
 
-        fn on_event(&self, _event: &Event) -> anyhow::Result<Verdict> {
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
             let mut count = self.count.lock().unwrap();
             *count += 1;
             Ok(Verdict::Continue)
@@ -106,7 +129,7 @@ async fn test_middleware_pipeline_order_and_stopping() {
             Ok(())
         }
 
-        fn on_event(&self, _event: &Event) -> anyhow::Result<Verdict> {
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
             let mut order = self.order.lock().unwrap();
             order.push(self.id);
 
@@ -217,3 +240,2679 @@ async fn test_middleware_pipeline_order_and_stopping() {
         "Third middleware should not execute because second middleware stops the pipeline"
     );
 }
+
+#[tokio::test]
+async fn test_event_tap_observes_all_events() {
+    // A middleware that stops every event, so only the tap can see the full stream
+    struct StoppingMiddleware;
+
+    #[async_trait]
+    impl Middleware for StoppingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            Ok(Verdict::Stop)
+        }
+    }
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let event_tap = create_event_tap(10);
+    let mut tap_rx = event_tap.subscribe();
+
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+
+    let mut services = HashMap::new();
+    services.insert(
+        service_id.clone(),
+        Arc::new(mock_service) as Arc<dyn kelvin_bot::core::service::Service>,
+    );
+
+    let mut service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::new();
+    service_middlewares
+        .insert(service_id.clone(), vec![Arc::new(StoppingMiddleware) as Arc<dyn Middleware>]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_event_tap(event_tap);
+
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    mock_control.send(3).await.expect("Failed to send command to mock service");
+
+    for _ in 0..3 {
+        let evt = tokio::time::timeout(Duration::from_millis(200), tap_rx.recv())
+            .await
+            .expect("Timeout waiting for tapped event")
+            .expect("Tap closed");
+        assert_eq!(evt.service_id, service_id);
+    }
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_bus_control_pauses_services_and_disables_middlewares() {
+    struct CountingMiddleware {
+        count: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Middleware for CountingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            *self.count.lock().unwrap() += 1;
+            Ok(Verdict::Continue)
+        }
+    }
+
+    async fn send_control(
+        cmd_tx: &tokio::sync::mpsc::Sender<Command>,
+        make: impl FnOnce(Option<tokio::sync::oneshot::Sender<anyhow::Result<String>>>) -> BusControl,
+    ) -> anyhow::Result<String> {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        cmd_tx.send(Command::Control(make(Some(response_tx)))).await.unwrap();
+        response_rx.await.unwrap()
+    }
+
+    let counter = Arc::new(Mutex::new(0));
+    let counting_middleware: Arc<dyn Middleware> =
+        Arc::new(CountingMiddleware { count: counter.clone() });
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+
+    let mut services = HashMap::new();
+    services.insert(
+        service_id.clone(),
+        Arc::new(mock_service) as Arc<dyn kelvin_bot::core::service::Service>,
+    );
+
+    let mut service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::new();
+    service_middlewares.insert(service_id.clone(), vec![counting_middleware.clone()]);
+
+    let mut middleware_names: HashMap<String, Arc<dyn Middleware>> = HashMap::new();
+    middleware_names.insert("counter".to_string(), counting_middleware);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_middleware_names(middleware_names);
+
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // Paused service: events are dropped
+    let id = service_id.clone();
+    assert_ok!(
+        send_control(&cmd_tx, |response_tx| BusControl::PauseService {
+            service_id: id,
+            response_tx
+        })
+        .await
+    );
+    mock_control.send(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(*counter.lock().unwrap(), 0);
+
+    let id = service_id.clone();
+    assert_ok!(
+        send_control(&cmd_tx, |response_tx| BusControl::ResumeService {
+            service_id: id,
+            response_tx
+        })
+        .await
+    );
+    mock_control.send(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(*counter.lock().unwrap(), 2);
+
+    // Disabled middleware: skipped in the pipeline
+    assert_ok!(
+        send_control(&cmd_tx, |response_tx| BusControl::DisableMiddleware {
+            name: "counter".to_string(),
+            response_tx
+        })
+        .await
+    );
+    mock_control.send(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(*counter.lock().unwrap(), 2);
+
+    assert_ok!(
+        send_control(&cmd_tx, |response_tx| BusControl::EnableMiddleware {
+            name: "counter".to_string(),
+            response_tx
+        })
+        .await
+    );
+    mock_control.send(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(*counter.lock().unwrap(), 4);
+
+    // Unknown targets are reported back
+    let result = send_control(&cmd_tx, |response_tx| BusControl::DisableMiddleware {
+        name: "nonexistent".to_string(),
+        response_tx,
+    })
+    .await;
+    assert!(result.is_err());
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_outbox_queues_while_service_is_down_and_flushes_in_order() {
+    struct FlakyService {
+        online: AtomicBool,
+        delivered: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Service for FlakyService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, command: Command) -> anyhow::Result<()> {
+            if !self.online.load(Ordering::SeqCst) {
+                return Err(transient_error("service not connected"));
+            }
+            if let Command::SendRoomMessage { body, .. } = command {
+                self.delivered.lock().unwrap().push(body);
+            }
+            Ok(())
+        }
+    }
+
+    fn room_message(service_id: &ServiceId, body: &str) -> Command {
+        Command::SendRoomMessage {
+            service_id: service_id.clone(),
+            room_id: "room".to_string(),
+            body: body.to_string(),
+            format: BodyFormat::Plain,
+            response_tx: None,
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        }
+    }
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let flaky =
+        Arc::new(FlakyService { online: AtomicBool::new(false), delivered: delivered.clone() });
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+
+    let service_id = ServiceId("flaky".to_string());
+    let mut services: HashMap<ServiceId, Arc<dyn Service>> = HashMap::new();
+    services.insert(service_id.clone(), flaky.clone());
+
+    let outbox = Outbox::in_memory(Duration::from_secs(3600)).unwrap();
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_outbox(outbox, Duration::from_secs(3600));
+
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    // Service is down: both sends are queued
+    cmd_tx.send(room_message(&service_id, "first")).await.unwrap();
+    cmd_tx.send(room_message(&service_id, "second")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(delivered.lock().unwrap().is_empty());
+
+    // Service reconnects and emits an event, which triggers the flush
+    flaky.online.store(true, Ordering::SeqCst);
+    evt_tx
+        .send(Event {
+            service_id: service_id.clone(),
+            kind: EventKind::UserListUpdate { users: vec![] },
+        })
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // New sends go straight through once the backlog is empty
+    cmd_tx.send(room_message(&service_id, "third")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(*delivered.lock().unwrap(), vec!["first", "second", "third"]);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_expiring_messages_are_deleted_where_supported() {
+    struct DeletingService {
+        supports_delete: bool,
+        handled: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Service for DeletingService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, command: Command) -> anyhow::Result<()> {
+            let mut handled = self.handled.lock().unwrap();
+            match command {
+                Command::SendRoomMessage { body, response_tx, .. } => {
+                    let message_id = format!("${}", handled.len());
+                    handled.push(format!("send {message_id}: {body}"));
+                    if let Some(tx) = response_tx {
+                        let _ = tx.send(Ok(message_id));
+                    }
+                }
+                Command::DeleteMessage { message_id, .. } => {
+                    handled.push(format!("delete {message_id}"));
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn capabilities(&self) -> ServiceCapabilities {
+            ServiceCapabilities { supports_delete: self.supports_delete, ..Default::default() }
+        }
+    }
+
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let chat = ServiceId("chat".to_string());
+    let voice = ServiceId("voice".to_string());
+    let services: HashMap<ServiceId, Arc<dyn Service>> = HashMap::from([
+        (
+            chat.clone(),
+            Arc::new(DeletingService { supports_delete: true, handled: handled.clone() })
+                as Arc<dyn Service>,
+        ),
+        (
+            voice.clone(),
+            Arc::new(DeletingService { supports_delete: false, handled: handled.clone() })
+                as Arc<dyn Service>,
+        ),
+    ]);
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_audit(AuditLog::in_memory(Duration::from_secs(3600)).unwrap());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let expiring = |service_id: &ServiceId, body: &str, response_tx| Command::SendRoomMessage {
+        service_id: service_id.clone(),
+        room_id: "!lobby".to_string(),
+        body: body.to_string(),
+        format: BodyFormat::Plain,
+        response_tx,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: Some(Duration::from_millis(50)),
+    };
+    // The sender still gets the message's ID
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx.send(expiring(&chat, "token", Some(response_tx))).await.unwrap();
+    assert_eq!(response_rx.await.unwrap().unwrap(), "$0");
+    cmd_tx.send(expiring(&voice, "ping", None)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(*handled.lock().unwrap(), ["send $0: token", "send $1: ping"]);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(*handled.lock().unwrap(), ["send $0: token", "send $1: ping", "delete $0"]);
+
+    // The deletion is dispatched like any other command, so it's in the audit trail
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::Control(BusControl::RecentAudit {
+            limit: 1,
+            response_tx: Some(response_tx),
+        }))
+        .await
+        .unwrap();
+    let report = response_rx.await.unwrap().unwrap();
+    assert!(report.contains("delete_message chat → $0 by unknown: ok"), "{report}");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_permission_denied_moderation_raises_operator_alert() {
+    struct PowerlessService;
+
+    #[async_trait]
+    impl Service for PowerlessService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, command: Command) -> anyhow::Result<()> {
+            match command {
+                Command::KickUser { room_id, response_tx: Some(tx), .. } => {
+                    let _ = tx.send(Err(anyhow::Error::new(PermissionDenied {
+                        room_id,
+                        action: "kick",
+                    })));
+                    Ok(())
+                }
+                Command::DeleteMessage { .. } => Err(anyhow::Error::new(PermissionDenied {
+                    room_id: "!lobby".to_string(),
+                    action: "redact",
+                })),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    let chat = ServiceId("chat".to_string());
+    let services: HashMap<ServiceId, Arc<dyn Service>> =
+        HashMap::from([(chat.clone(), Arc::new(PowerlessService) as Arc<dyn Service>)]);
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let alerts = create_alert_channel(10);
+    let mut alert_rx = alerts.subscribe();
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_alerts(alerts);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    // The middleware still gets the error, and the operator hears about it too
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let kick = Command::KickUser {
+        service_id: chat.clone(),
+        room_id: "!lobby".to_string(),
+        user_id: "@spammer".to_string(),
+        reason: None,
+        response_tx: Some(response_tx),
+        origin: Some(CommandOrigin::new("pruner")),
+    };
+    cmd_tx.send(kick).await.unwrap();
+    let err = response_rx.await.unwrap().unwrap_err();
+    assert!(err.downcast_ref::<PermissionDenied>().is_some(), "{err}");
+    let delete = Command::DeleteMessage {
+        service_id: chat.clone(),
+        message_id: "$1".to_string(),
+        origin: Some(CommandOrigin::new("cleaner")),
+    };
+    cmd_tx.send(delete).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    for (expected_origin, expected_action) in [("pruner", "kick"), ("cleaner", "redact")] {
+        let BusAlert::PermissionDenied { service_id, origin, room_id, action } =
+            alert_rx.try_recv().unwrap()
+        else {
+            panic!("Expected PermissionDenied");
+        };
+        assert_eq!(service_id, chat);
+        assert_eq!(origin, expected_origin);
+        assert_eq!(room_id, "!lobby");
+        assert_eq!(action, expected_action);
+    }
+    assert!(alert_rx.try_recv().is_err());
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_first_match_dispatch_delivers_command_once() {
+    struct CommandCounter {
+        command: &'static str,
+        count: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Middleware for CommandCounter {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            *self.count.lock().unwrap() += 1;
+            Ok(Verdict::Continue)
+        }
+
+        fn command_strings(&self) -> Vec<&str> {
+            vec![self.command]
+        }
+    }
+
+    fn room_message(service_id: &ServiceId, body: &str) -> Event {
+        Event {
+            service_id: service_id.clone(),
+            kind: EventKind::RoomMessage {
+                room_id: "room".to_string(),
+                body: body.to_string(),
+                is_local_user: true,
+                sender_id: "user".to_string(),
+                sender_display_name: None,
+                is_self: false,
+                mentions_self: false,
+                relayed_from: None,
+                thread_root_id: None,
+                message_id: None,
+            },
+        }
+    }
+
+    for (dispatch, expected_second) in
+        [(CommandDispatch::Unique, 2), (CommandDispatch::FirstMatch, 1)]
+    {
+        let first_count = Arc::new(Mutex::new(0));
+        let second_count = Arc::new(Mutex::new(0));
+        let first: Arc<dyn Middleware> =
+            Arc::new(CommandCounter { command: "!ping", count: first_count.clone() });
+        let second: Arc<dyn Middleware> =
+            Arc::new(CommandCounter { command: "!ping", count: second_count.clone() });
+
+        let (_cmd_tx, cmd_rx) = create_command_channel(10);
+        let (evt_tx, evt_rx) = create_event_channel(10);
+        let service_id = ServiceId("test_mock".to_string());
+        let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+
+        let mut services = HashMap::new();
+        services.insert(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>);
+        let mut service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::new();
+        service_middlewares.insert(service_id.clone(), vec![first, second]);
+
+        let mut bus =
+            Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+                .with_command_dispatch(dispatch);
+        let cancel_token = CancellationToken::new();
+        let bus_handle = {
+            let cancel = cancel_token.clone();
+            tokio::spawn(async move { bus.run(cancel).await })
+        };
+
+        // A command invocation and an ordinary message
+        evt_tx.send(room_message(&service_id, "!ping now")).await.unwrap();
+        evt_tx.send(room_message(&service_id, "hello")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(*first_count.lock().unwrap(), 2, "{dispatch:?}");
+        assert_eq!(*second_count.lock().unwrap(), expected_second, "{dispatch:?}");
+
+        cancel_token.cancel();
+        assert_ok!(bus_handle.await.unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_room_pipeline_overrides_service_pipeline() {
+    struct CountingMiddleware {
+        count: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Middleware for CountingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            *self.count.lock().unwrap() += 1;
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let service_count = Arc::new(Mutex::new(0));
+    let room_count = Arc::new(Mutex::new(0));
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+
+    let mut services = HashMap::new();
+    services.insert(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>);
+
+    let mut service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::new();
+    service_middlewares.insert(
+        service_id.clone(),
+        vec![Arc::new(CountingMiddleware { count: service_count.clone() }) as Arc<dyn Middleware>],
+    );
+    let mut rooms: HashMap<String, Vec<Arc<dyn Middleware>>> = HashMap::new();
+    rooms.insert(
+        "ops".to_string(),
+        vec![Arc::new(CountingMiddleware { count: room_count.clone() }) as Arc<dyn Middleware>],
+    );
+    let room_middlewares = HashMap::from([(service_id.clone(), rooms)]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_room_pipelines(room_middlewares);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let message = |room_id: Option<&str>| Event {
+        service_id: service_id.clone(),
+        kind: match room_id {
+            Some(room_id) => EventKind::RoomMessage {
+                room_id: room_id.to_string(),
+                body: "hi".to_string(),
+                is_local_user: true,
+                sender_id: "user".to_string(),
+                sender_display_name: None,
+                is_self: false,
+                mentions_self: false,
+                relayed_from: None,
+                thread_root_id: None,
+                message_id: None,
+            },
+            None => EventKind::DirectMessage {
+                user_id: "user".to_string(),
+                body: "hi".to_string(),
+                is_local_user: true,
+                sender_id: "user".to_string(),
+                sender_display_name: None,
+                is_self: false,
+            },
+        },
+    };
+
+    // The overridden room uses its own pipeline; other rooms and DMs use the service's
+    evt_tx.send(message(Some("ops"))).await.unwrap();
+    evt_tx.send(message(Some("general"))).await.unwrap();
+    evt_tx.send(message(None)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(*room_count.lock().unwrap(), 1);
+    assert_eq!(*service_count.lock().unwrap(), 2);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_middleware_only_in_a_room_pipeline_is_started() {
+    struct StartedMiddleware {
+        started: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Middleware for StartedMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            self.started.store(true, Ordering::SeqCst);
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let started = Arc::new(AtomicBool::new(false));
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+
+    // The service's own pipeline is empty; the middleware is only in the room's
+    let service_middlewares = HashMap::from([(service_id.clone(), Vec::new())]);
+    let room = Arc::new(StartedMiddleware { started: started.clone() }) as Arc<dyn Middleware>;
+    let rooms = HashMap::from([("ops".to_string(), vec![room])]);
+    let room_middlewares = HashMap::from([(service_id.clone(), rooms)]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_room_pipelines(room_middlewares);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(started.load(Ordering::SeqCst), "room-only middleware was never started");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_room_filter_drops_events_before_pipeline() {
+    struct CountingMiddleware {
+        count: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Middleware for CountingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            *self.count.lock().unwrap() += 1;
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let count = Arc::new(Mutex::new(0));
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+
+    let mut services = HashMap::new();
+    services.insert(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>);
+
+    let mut service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::new();
+    service_middlewares.insert(
+        service_id.clone(),
+        vec![Arc::new(CountingMiddleware { count: count.clone() }) as Arc<dyn Middleware>],
+    );
+    let room_filters =
+        HashMap::from([(service_id.clone(), RoomFilter::new(None, vec!["noisy".to_string()]))]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_room_filters(room_filters);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let message = |room_id: &str| Event {
+        service_id: service_id.clone(),
+        kind: EventKind::RoomMessage {
+            room_id: room_id.to_string(),
+            body: "hi".to_string(),
+            is_local_user: true,
+            sender_id: "user".to_string(),
+            sender_display_name: None,
+            is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
+        },
+    };
+
+    evt_tx.send(message("noisy")).await.unwrap();
+    evt_tx.send(message("noisy")).await.unwrap();
+    evt_tx.send(message("general")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(*count.lock().unwrap(), 1);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_roster_is_updated_before_middlewares_see_events() {
+    struct NameLookup {
+        roster: Roster,
+        seen: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    #[async_trait]
+    impl Middleware for NameLookup {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            let name = self.roster.display_name(&event.service_id, "@alice:example.com");
+            self.seen.lock().unwrap().push(name);
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let roster = Roster::tracking();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+
+    let mut services = HashMap::new();
+    services.insert(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>);
+    let service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::from([(
+        service_id.clone(),
+        vec![Arc::new(NameLookup { roster: roster.clone(), seen: seen.clone() })
+            as Arc<dyn Middleware>],
+    )]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_roster(roster.clone());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    evt_tx
+        .send(Event {
+            service_id: service_id.clone(),
+            kind: EventKind::RoomMessage {
+                room_id: "!lobby:example.com".to_string(),
+                body: "hi".to_string(),
+                is_local_user: true,
+                sender_id: "@alice:example.com".to_string(),
+                sender_display_name: Some("Alice".to_string()),
+                is_self: false,
+                mentions_self: false,
+                relayed_from: None,
+                thread_root_id: None,
+                message_id: None,
+            },
+        })
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(*seen.lock().unwrap(), vec![Some("Alice".to_string())]);
+    assert_eq!(roster.rooms(&service_id), vec!["!lobby:example.com".to_string()]);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_lifecycle_announcements_wait_for_ready_and_precede_disconnect() {
+    struct GatedService {
+        ready: tokio::sync::watch::Sender<bool>,
+        stopped: AtomicBool,
+        delivered: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Service for GatedService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            self.stopped.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn handle_command(&self, command: Command) -> anyhow::Result<()> {
+            if self.stopped.load(Ordering::SeqCst) {
+                anyhow::bail!("disconnected");
+            }
+            if let Command::SendRoomMessage { room_id, body, .. } = command {
+                self.delivered.lock().unwrap().push(format!("{room_id}: {body}"));
+            }
+            Ok(())
+        }
+
+        async fn ready(&self) {
+            let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+        }
+    }
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let service = Arc::new(GatedService {
+        ready: tokio::sync::watch::channel(false).0,
+        stopped: AtomicBool::new(false),
+        delivered: delivered.clone(),
+    });
+    let service_id = ServiceId("gated".to_string());
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let services = HashMap::from([(service_id.clone(), service.clone() as Arc<dyn Service>)]);
+    let announcements = LifecycleAnnouncementsConfig {
+        startup_message: Some("back online".to_string()),
+        shutdown_message: Some("going down".to_string()),
+        destinations: HashMap::from([(
+            "lobby".to_string(),
+            AnnouncementDestination {
+                service_id: "gated".to_string(),
+                room_id: "lobby".to_string(),
+            },
+        )]),
+    };
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_lifecycle_announcements(announcements);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    // Nothing is announced until the service reports ready
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(delivered.lock().unwrap().is_empty());
+
+    service.ready.send_replace(true);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(*delivered.lock().unwrap(), vec!["lobby: back online"]);
+
+    // The shutdown message goes out while the service is still connected
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+    assert_eq!(*delivered.lock().unwrap(), vec!["lobby: back online", "lobby: going down"]);
+}
+
+#[tokio::test]
+async fn test_service_stuck_starting_is_restarted_without_holding_up_the_others() {
+    // Never becomes ready, like a Matrix login waiting on a verification nobody confirms
+    struct StuckService {
+        // The token each run was given
+        runs: Mutex<Vec<CancellationToken>>,
+    }
+
+    #[async_trait]
+    impl Service for StuckService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            self.runs.lock().unwrap().push(cancel);
+            std::future::pending().await
+        }
+
+        async fn handle_command(&self, _command: Command) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn ready(&self) {
+            std::future::pending().await
+        }
+    }
+
+    struct OkService {
+        delivered: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Service for OkService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, command: Command) -> anyhow::Result<()> {
+            if let Command::SendRoomMessage { body, .. } = command {
+                self.delivered.lock().unwrap().push(body);
+            }
+            Ok(())
+        }
+    }
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let stuck = Arc::new(StuckService { runs: Mutex::new(Vec::new()) });
+    let stuck_id = ServiceId("stuck".to_string());
+    let ok_id = ServiceId("ok".to_string());
+    let services = HashMap::from([
+        (stuck_id.clone(), stuck.clone() as Arc<dyn Service>),
+        (ok_id.clone(), Arc::new(OkService { delivered: delivered.clone() }) as Arc<dyn Service>),
+    ]);
+    let announcements = LifecycleAnnouncementsConfig {
+        startup_message: Some("back online".to_string()),
+        shutdown_message: None,
+        destinations: HashMap::from([
+            (
+                "lobby".to_string(),
+                AnnouncementDestination {
+                    service_id: "ok".to_string(),
+                    room_id: "lobby".to_string(),
+                },
+            ),
+            (
+                "stuck_lobby".to_string(),
+                AnnouncementDestination {
+                    service_id: "stuck".to_string(),
+                    room_id: "lobby".to_string(),
+                },
+            ),
+        ]),
+    };
+    let reconnection = ReconnectionConfig {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        multiplier: 1.0,
+        jitter_factor: 0.0,
+    };
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), reconnection)
+        .with_lifecycle_announcements(announcements)
+        .with_startup_timeouts(HashMap::from([(stuck_id, Duration::from_millis(50))]));
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    // The ready service's rooms are announced on without waiting for the stuck one
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(*delivered.lock().unwrap(), vec!["back online"]);
+
+    // Past the timeout, the stuck service's attempt is cancelled and started again
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    assert_eq!(*delivered.lock().unwrap(), vec!["back online"]);
+    {
+        let runs = stuck.runs.lock().unwrap();
+        assert!(runs.len() >= 2);
+        assert!(runs[0].is_cancelled());
+    }
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_panicking_middleware_is_isolated_and_disabled_after_limit() {
+    #[derive(Debug)]
+    struct PanickingMiddleware {
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Middleware for PanickingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            panic!("boom");
+        }
+    }
+
+    #[derive(Debug)]
+    struct CountingMiddleware {
+        count: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Middleware for CountingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let panic_calls = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::new(AtomicUsize::new(0));
+    let panicking: Arc<dyn Middleware> =
+        Arc::new(PanickingMiddleware { calls: panic_calls.clone() });
+    let counting: Arc<dyn Middleware> = Arc::new(CountingMiddleware { count: counted.clone() });
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let service_middlewares =
+        HashMap::from([(service_id.clone(), vec![panicking.clone(), counting])]);
+
+    let alerts = create_alert_channel(10);
+    let mut alert_rx = alerts.subscribe();
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_middleware_names(HashMap::from([("flaky".to_string(), panicking)]))
+            .with_panic_limit(Some(2))
+            .with_alerts(alerts);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    mock_control.send(3).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Later middlewares still see every event; the panicking one stops being called at the limit
+    assert_eq!(counted.load(Ordering::SeqCst), 3);
+    assert_eq!(panic_calls.load(Ordering::SeqCst), 2);
+
+    for (expected_count, expected_disabled) in [(1, false), (2, true)] {
+        let BusAlert::MiddlewarePanicked {
+            middleware,
+            event_kind,
+            message,
+            panic_count,
+            disabled,
+            ..
+        } = alert_rx.try_recv().unwrap()
+        else {
+            panic!("Expected MiddlewarePanicked");
+        };
+        assert_eq!(middleware, "flaky");
+        assert_eq!(event_kind, "room_message");
+        assert_eq!(message, "boom");
+        assert_eq!(panic_count, expected_count);
+        assert_eq!(disabled, expected_disabled);
+    }
+    assert!(alert_rx.try_recv().is_err());
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_operator_is_alerted_when_a_middleware_panics() {
+    #[derive(Debug)]
+    struct PanickingMiddleware;
+
+    #[async_trait]
+    impl Middleware for PanickingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            panic!("out of cheese");
+        }
+    }
+
+    // Alerts go out on a channel of their own, so this test sees them apart from the bus's
+    let (alert_tx, mut alert_rx) = create_command_channel(10);
+    alerts::install(OperatorAlerts::new(
+        CommandSender::new(alert_tx, ALERTS_ORIGIN),
+        AlertTarget::Room {
+            service_id: ServiceId("matrix".to_string()),
+            room_id: "!ops:example.com".to_string(),
+        },
+        AlertLevel::Warning,
+        Duration::from_secs(600),
+    ));
+
+    let panicking: Arc<dyn Middleware> = Arc::new(PanickingMiddleware);
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("alerting_mock".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let service_middlewares = HashMap::from([(service_id.clone(), vec![panicking.clone()])]);
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_middleware_names(HashMap::from([("cheese_grater".to_string(), panicking)]))
+            .with_panic_limit(Some(2));
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    mock_control.send(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Other tests' buses may raise alerts through the same installed sink meanwhile
+    let mut bodies = Vec::new();
+    while let Ok(Command::SendRoomMessage { room_id, body, .. }) = alert_rx.try_recv() {
+        assert_eq!(room_id, "!ops:example.com");
+        if body.contains("'cheese_grater'") {
+            bodies.push(body);
+        }
+    }
+    assert_eq!(
+        bodies,
+        vec![
+            "⚠️ middleware 'cheese_grater' panicked handling a room_message event on \
+             'alerting_mock': out of cheese; it was skipped for that event",
+            "🚨 middleware 'cheese_grater' panicked handling a room_message event on \
+             'alerting_mock': out of cheese; it's been disabled until it's re-enabled",
+        ]
+    );
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_slow_middleware_latency_is_recorded_against_budget() {
+    #[derive(Debug)]
+    struct SlowMiddleware;
+
+    #[async_trait]
+    impl Middleware for SlowMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let slow: Arc<dyn Middleware> = Arc::new(SlowMiddleware);
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let service_middlewares = HashMap::from([(service_id.clone(), vec![slow.clone()])]);
+
+    let metrics = MetricsRegistry::default();
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_middleware_names(HashMap::from([("slow".to_string(), slow)]))
+            .with_latency_budget(Duration::from_millis(5))
+            .with_metrics(metrics.clone());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    mock_control.send(2).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let snapshot = metrics.middleware_snapshot();
+    assert_eq!(snapshot["slow"].calls, 2);
+    assert_eq!(snapshot["slow"].over_budget, 2);
+    assert!(snapshot["slow"].max >= Duration::from_millis(20));
+
+    // The second event waited behind the first, and both are aged from when they were emitted
+    let lag = metrics.lag_snapshot();
+    assert_eq!(lag[&LagStage::Queued].events, 2);
+    assert!(lag[&LagStage::Queued].max >= Duration::from_millis(20));
+    assert_eq!(lag[&LagStage::Processed].events, 2);
+    assert!(lag[&LagStage::Processed].max >= Duration::from_millis(40));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_commands_carry_the_correlation_id_of_the_event_that_prompted_them() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+    // Echo sends its reply from a spawned task, so the ID has to survive the hop
+    let echo: Arc<dyn Middleware> =
+        Arc::new(Echo::new(middleware_context(cmd_tx), "!echo".to_string()));
+    let service_middlewares = HashMap::from([(service_id.clone(), vec![echo])]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "!echo one")).await.unwrap();
+    let first = capture.next().await;
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "!echo two")).await.unwrap();
+    let second = capture.next().await;
+
+    assert_eq!(first.origin(), Some("test"));
+    let first_id = first.correlation_id().expect("reply should carry a correlation ID");
+    let second_id = second.correlation_id().expect("reply should carry a correlation ID");
+    assert_ne!(first_id, second_id);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_watchdog_reports_a_bus_loop_stuck_on_a_command() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    // Room for one command; the service hangs forwarding the next until the test reads
+    let (capture_tx, mut capture) = command_capture(1);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let alerts = create_alert_channel(10);
+    let mut alert_rx = alerts.subscribe();
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_alerts(alerts)
+        .with_watchdog(WatchdogConfig { stall_after: Duration::from_millis(200), abort: false });
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    // An idle loop isn't stalled
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(alert_rx.try_recv().is_err());
+
+    for body in ["one", "two"] {
+        cmd_tx
+            .send(Command::SendRoomMessage {
+                service_id: service_id.clone(),
+                room_id: "!lobby".to_string(),
+                body: body.to_string(),
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    let alert = tokio::time::timeout(Duration::from_secs(2), alert_rx.recv())
+        .await
+        .expect("watchdog should report the stall")
+        .unwrap();
+    let BusAlert::BusStalled { stalled_for, step } = alert else {
+        panic!("Expected BusStalled, got {alert:?}");
+    };
+    assert!(stalled_for >= Duration::from_millis(200));
+    assert_eq!(step.as_deref(), Some("dispatching send_room_message to service 'chat'"));
+
+    // Once the service gets going again, so does the loop
+    capture.next().await;
+    capture.next().await;
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_blocked_pipeline_does_not_delay_other_services() {
+    #[derive(Debug)]
+    struct BlockingMiddleware {
+        release: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    #[async_trait]
+    impl Middleware for BlockingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            let _ = self.release.lock().unwrap().recv_timeout(Duration::from_secs(5));
+            Ok(Verdict::Continue)
+        }
+    }
+
+    #[derive(Debug)]
+    struct CountingMiddleware {
+        count: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Middleware for CountingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let (release_tx, release_rx) = std::sync::mpsc::channel();
+    let counted = Arc::new(AtomicUsize::new(0));
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let busy_id = ServiceId("busy".to_string());
+    let quiet_id = ServiceId("quiet".to_string());
+    let (busy_service, busy_control) = MockService::new(busy_id.clone(), evt_tx.clone());
+    let (quiet_service, quiet_control) = MockService::new(quiet_id.clone(), evt_tx.clone());
+    let services = HashMap::from([
+        (busy_id.clone(), Arc::new(busy_service) as Arc<dyn Service>),
+        (quiet_id.clone(), Arc::new(quiet_service) as Arc<dyn Service>),
+    ]);
+    let service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::from([
+        (busy_id, vec![Arc::new(BlockingMiddleware { release: Mutex::new(release_rx) }) as Arc<_>]),
+        (quiet_id, vec![Arc::new(CountingMiddleware { count: counted.clone() }) as Arc<_>]),
+    ]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // The busy service's pipeline is stuck on its first event...
+    busy_control.send(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // ...but the other service's events are still handled
+    quiet_control.send(3).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(counted.load(Ordering::SeqCst), 3);
+
+    release_tx.send(()).unwrap();
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_event_tap_and_pipeline_share_one_event_allocation() {
+    struct RetainingMiddleware {
+        seen: Arc<Mutex<Vec<Arc<Event>>>>,
+    }
+
+    #[async_trait]
+    impl Middleware for RetainingMiddleware {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            self.seen.lock().unwrap().push(event.clone());
+            Ok(Verdict::Continue)
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let event_tap = create_event_tap(10);
+    let mut tap_rx = event_tap.subscribe();
+
+    let service_id = ServiceId("test_mock".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::from([(
+        service_id,
+        vec![Arc::new(RetainingMiddleware { seen: seen.clone() }) as Arc<dyn Middleware>],
+    )]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_event_tap(event_tap);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    mock_control.send(1).await.unwrap();
+    let tapped = tokio::time::timeout(Duration::from_millis(200), tap_rx.recv())
+        .await
+        .expect("Timeout waiting for tapped event")
+        .expect("Tap closed");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.len(), 1);
+    assert!(Arc::ptr_eq(&seen[0], &tapped));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_replay_captures_commands_instead_of_sending_them() {
+    let config = toml::from_str(
+        r#"
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+
+        [services.chat]
+        kind = "dummy"
+        middleware = "echo"
+        "#,
+    )
+    .expect("Failed to parse config");
+
+    let room_message = |body: &str| Event {
+        service_id: ServiceId("chat".to_string()),
+        kind: EventKind::RoomMessage {
+            room_id: "!lobby".to_string(),
+            body: body.to_string(),
+            is_local_user: false,
+            sender_id: "@alice".to_string(),
+            sender_display_name: None,
+            is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
+        },
+    };
+
+    let captured = replay(config, vec![room_message("just chatting"), room_message("!echo hello")])
+        .await
+        .unwrap();
+    assert_eq!(captured, vec!["[chat] !lobby: hello"]);
+}
+
+#[tokio::test]
+async fn test_reloaded_middleware_replaces_the_old_instance() {
+    let data_directory = tempfile::TempDir::new().unwrap();
+    let config_text = Arc::new(Mutex::new(
+        r#"
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+
+        [services.chat]
+        kind = "dummy"
+        middleware = "echo"
+        "#
+        .to_string(),
+    ));
+    let data_path = data_directory.path().to_path_buf();
+    let load_config = {
+        let config_text = config_text.clone();
+        move || -> anyhow::Result<Config> {
+            let mut config: Config = toml::from_str(&config_text.lock().unwrap())?;
+            config.data_directory = data_path.clone();
+            Ok(config)
+        }
+    };
+    let config = load_config().unwrap();
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+    let (middlewares, reloader) = instantiate_middleware_with_reloader(
+        &config,
+        &cmd_tx,
+        &services,
+        &Roster::default(),
+        load_config,
+    )
+    .unwrap();
+    let pipelines = build_service_pipelines(&config, &middlewares).unwrap();
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, pipelines, ReconnectionConfig::default())
+        .with_middleware_names(middlewares)
+        .with_middleware_reloader(reloader);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "!echo hi")).await.unwrap();
+    assert_eq!(capture.expect_room_message().await.2, "hi");
+
+    let reload = |name: &str| {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let control =
+            BusControl::ReloadMiddleware { name: name.to_string(), response_tx: Some(response_tx) };
+        (Command::Control(control), response_rx)
+    };
+    let changed = config_text.lock().unwrap().replace("!echo", "!say");
+    *config_text.lock().unwrap() = changed;
+    let (control, response_rx) = reload("echo");
+    cmd_tx.send(control).await.unwrap();
+    assert_eq!(response_rx.await.unwrap().unwrap(), "middleware 'echo' reloaded");
+
+    // Only the reloaded instance sees events from now on
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "!echo hi")).await.unwrap();
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "!say hello")).await.unwrap();
+    assert_eq!(capture.expect_room_message().await.2, "hello");
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    capture.assert_empty();
+
+    let (control, response_rx) = reload("missing");
+    cmd_tx.send(control).await.unwrap();
+    assert!(response_rx.await.unwrap().is_err());
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_service_added_at_runtime_gets_its_configured_pipeline() {
+    let data_directory = tempfile::TempDir::new().unwrap();
+    let config_text = Arc::new(Mutex::new(
+        r#"
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+        shared = false
+
+        [services.chat]
+        kind = "dummy"
+        middleware = "echo"
+        "#
+        .to_string(),
+    ));
+    let data_path = data_directory.path().to_path_buf();
+    let load_config = {
+        let config_text = config_text.clone();
+        move || -> anyhow::Result<Config> {
+            let mut config: Config = toml::from_str(&config_text.lock().unwrap())?;
+            config.data_directory = data_path.clone();
+            Ok(config)
+        }
+    };
+    let config = load_config().unwrap();
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let (mut middlewares, reloader) = instantiate_middleware_with_reloader(
+        &config,
+        &cmd_tx,
+        &services,
+        &Roster::default(),
+        load_config.clone(),
+    )
+    .unwrap();
+    let watcher = Arc::new(MockMiddleware::new(Verdict::Continue));
+    middlewares.insert("watcher".to_string(), watcher.clone());
+    let pipelines = build_service_pipelines(&config, &middlewares).unwrap();
+    let loader = ServiceLoader::new(
+        evt_tx.clone(),
+        MetricsRegistry::default(),
+        HttpClient::default(),
+        load_config,
+    );
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, pipelines, ReconnectionConfig::default())
+        .with_middleware_names(middlewares)
+        .with_middleware_reloader(reloader)
+        .with_service_loader(loader);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let add = |name: &str| {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let service_id = ServiceId(name.to_string());
+        let control = BusControl::AddService { service_id, response_tx: Some(response_tx) };
+        (Command::Control(control), response_rx)
+    };
+    let (control, response_rx) = add("extra");
+    cmd_tx.send(control).await.unwrap();
+    assert!(response_rx.await.unwrap().unwrap_err().to_string().contains("isn't in the config"));
+
+    config_text.lock().unwrap().push_str(
+        r#"
+        [services.extra]
+        kind = "dummy"
+        interval_ms = 10
+        middleware = ["echo", "watcher"]
+        "#,
+    );
+    let (control, response_rx) = add("extra");
+    cmd_tx.send(control).await.unwrap();
+    assert_eq!(response_rx.await.unwrap().unwrap(), "service 'extra' added");
+
+    // The new service is supervised and its events run through its pipeline
+    let extra = ServiceId("extra".to_string());
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while !watcher.events().iter().any(|evt| evt.service_id == extra) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("events from the added service should reach its pipeline");
+
+    // Non-shared middlewares get an instance of their own for it
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let control = BusControl::DescribePipeline {
+        service_id: extra.clone(),
+        room_id: None,
+        response_tx: Some(response_tx),
+    };
+    cmd_tx.send(Command::Control(control)).await.unwrap();
+    let description = response_rx.await.unwrap().unwrap();
+    assert!(description.contains("echo@extra"), "{description}");
+    assert!(description.contains("watcher"), "{description}");
+
+    let (control, response_rx) = add("extra");
+    cmd_tx.send(control).await.unwrap();
+    assert!(response_rx.await.unwrap().unwrap_err().to_string().contains("already running"));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_audit_trail_records_dispatched_commands_and_controls() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_audit(AuditLog::in_memory(Duration::from_secs(3600)).unwrap());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let room_message = |service: &str| Command::SendRoomMessage {
+        service_id: ServiceId(service.to_string()),
+        room_id: "!lobby".to_string(),
+        body: "hello".to_string(),
+        format: BodyFormat::Plain,
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    };
+    CommandSender::new(cmd_tx.clone(), "echo").send(room_message("chat")).await.unwrap();
+    capture.expect_room_message().await;
+    cmd_tx.send(room_message("nowhere")).await.unwrap();
+    cmd_tx
+        .send(Command::Control(BusControl::PauseService {
+            service_id: service_id.clone(),
+            response_tx: None,
+        }))
+        .await
+        .unwrap();
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::Control(BusControl::RecentAudit {
+            limit: 10,
+            response_tx: Some(response_tx),
+        }))
+        .await
+        .unwrap();
+    let report = response_rx.await.unwrap().unwrap();
+    let lines: Vec<_> = report.lines().collect();
+
+    // Newest first, and reading the trail isn't itself recorded
+    assert_eq!(lines.len(), 3, "unexpected report: {report}");
+    assert!(lines[0].contains("pause_service bus → chat by unknown: ok"), "{}", lines[0]);
+    assert!(
+        lines[1].contains("send_room_message nowhere → !lobby by unknown: failed: unknown service"),
+        "{}",
+        lines[1]
+    );
+    assert!(lines[2].contains("send_room_message chat → !lobby by echo: ok"), "{}", lines[2]);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_describe_pipeline_reports_order_and_stops() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (mock_service, mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+
+    let logger: Arc<dyn Middleware> = Arc::new(MockMiddleware::new(Verdict::Continue));
+    let gate: Arc<dyn Middleware> = Arc::new(MockMiddleware::new(Verdict::Stop));
+    let echo: Arc<dyn Middleware> = Arc::new(MockMiddleware::new(Verdict::Continue));
+    let service_middlewares =
+        HashMap::from([(service_id.clone(), vec![logger.clone(), gate.clone(), echo.clone()])]);
+    let room_middlewares = HashMap::from([(
+        service_id.clone(),
+        HashMap::from([("room_0".to_string(), vec![logger.clone()])]),
+    )]);
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_room_pipelines(room_middlewares)
+            .with_middleware_names(HashMap::from([
+                ("logger".to_string(), logger),
+                ("gate".to_string(), gate),
+                ("echo".to_string(), echo),
+            ]))
+            .with_disabled_middlewares(vec!["echo".to_string()]);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    // One event per room: room_0 has a pipeline of its own, the rest stop at the gate
+    mock_control.send(3).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let describe = |service: &str, room_id: Option<&str>| {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        let control = BusControl::DescribePipeline {
+            service_id: ServiceId(service.to_string()),
+            room_id: room_id.map(str::to_string),
+            response_tx: Some(response_tx),
+        };
+        let cmd_tx = cmd_tx.clone();
+        async move {
+            cmd_tx.send(Command::Control(control)).await.unwrap();
+            response_rx.await.unwrap()
+        }
+    };
+
+    let report = describe("chat", None).await.unwrap();
+    let lines: Vec<_> = report.lines().collect();
+    assert_eq!(lines.len(), 5, "unexpected report: {report}");
+    assert_eq!(lines[0], "Pipeline for chat:");
+    assert_eq!(lines[1], "1. logger: 3 continued, 0 stopped");
+    assert_eq!(lines[2], "2. gate: 0 continued, 2 stopped (last stop 0s ago on room_message)");
+    assert_eq!(lines[3], "3. echo [disabled]: 0 continued, 0 stopped");
+    assert_eq!(lines[4], "Rooms with their own pipeline: room_0");
+
+    let report = describe("chat", Some("room_0")).await.unwrap();
+    assert_eq!(report, "Pipeline for chat in room_0:\n1. logger: 3 continued, 0 stopped");
+
+    assert!(describe("nowhere", None).await.is_err());
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_rewritten_event_reaches_later_middlewares_only() {
+    struct Shouting;
+
+    #[async_trait]
+    impl Middleware for Shouting {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            let mut rewritten = Event::clone(event);
+            let Some(body) = rewritten.kind.message_body_mut() else {
+                return Ok(Verdict::Continue);
+            };
+            *body = body.to_uppercase();
+            Ok(Verdict::Rewrite(Arc::new(rewritten)))
+        }
+    }
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let event_tap = create_event_tap(10);
+    let mut tap_rx = event_tap.subscribe();
+
+    let service_id = ServiceId("chat".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+
+    let before = Arc::new(MockMiddleware::new(Verdict::Continue));
+    let after = Arc::new(MockMiddleware::new(Verdict::Continue));
+    let pipeline: Vec<Arc<dyn Middleware>> =
+        vec![before.clone(), Arc::new(Shouting), after.clone()];
+    let service_middlewares = HashMap::from([(service_id, pipeline)]);
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_event_tap(event_tap);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    evt_tx.send(room_message("chat", "!lobby", "@alice", "hello there")).await.unwrap();
+    let tapped = tokio::time::timeout(Duration::from_millis(200), tap_rx.recv())
+        .await
+        .expect("Timeout waiting for tapped event")
+        .expect("Tap closed");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(tapped.kind.message_body(), Some("hello there"));
+    assert_eq!(before.events()[0].kind.message_body(), Some("hello there"));
+    let seen_after = after.events();
+    assert_eq!(seen_after.len(), 1);
+    assert_eq!(seen_after[0].kind.message_body(), Some("HELLO THERE"));
+    assert_eq!(seen_after[0].kind.room_id(), Some("!lobby"));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_command_policy_denies_commands_a_middleware_may_not_send() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let policy = CommandPolicy::new(HashMap::from([(
+        "echo".to_string(),
+        vec!["send_room_message".to_string()],
+    )]));
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_command_policy(policy)
+        .with_audit(AuditLog::in_memory(Duration::from_secs(3600)).unwrap());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let echo = CommandSender::new(cmd_tx.clone(), "echo");
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    echo.send(Command::GenerateInviteToken {
+        service_id: service_id.clone(),
+        user_id: "@mallory:example.com".to_string(),
+        uses_allowed: None,
+        expiry: None,
+        response_tx,
+        origin: None,
+    })
+    .await
+    .unwrap();
+    let err = response_rx.await.unwrap().unwrap_err().to_string();
+    assert!(err.contains("echo") && err.contains("generate_invite_token"), "{err}");
+
+    // Announcements are checked as a whole, not only message by message
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    echo.send(Command::Announce {
+        targets: vec![(service_id.clone(), RoomTarget::Room("!lobby".to_string()))],
+        body: "maintenance tonight".to_string(),
+        priority: AnnouncementPriority::Notice,
+        response_tx: Some(response_tx),
+        origin: None,
+    })
+    .await
+    .unwrap();
+    let err = response_rx.await.unwrap().unwrap_err().to_string();
+    assert!(err.contains("echo") && err.contains("announce"), "{err}");
+
+    // Commands the middleware is allowed to send still go through
+    echo.send(Command::SendRoomMessage {
+        service_id: service_id.clone(),
+        room_id: "!lobby".to_string(),
+        body: "hello".to_string(),
+        format: BodyFormat::Plain,
+        response_tx: None,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    })
+    .await
+    .unwrap();
+    capture.expect_room_message().await;
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::Control(BusControl::RecentAudit {
+            limit: 10,
+            response_tx: Some(response_tx),
+        }))
+        .await
+        .unwrap();
+    let report = response_rx.await.unwrap().unwrap();
+    assert!(
+        report.contains("generate_invite_token chat → @mallory:example.com by echo: denied"),
+        "{report}"
+    );
+    assert!(report.contains("announce bus → 1 targets by echo: denied"), "{report}");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_dry_run_middleware_commands_are_recorded_not_dispatched() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_dry_run_middlewares(vec!["router".to_string()])
+        .with_audit(AuditLog::in_memory(Duration::from_secs(3600)).unwrap());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let room_message = |body: &str, response_tx| Command::SendRoomMessage {
+        service_id: service_id.clone(),
+        room_id: "!lobby".to_string(),
+        body: body.to_string(),
+        format: BodyFormat::Plain,
+        response_tx,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    };
+    // Per-service instances of a non-shared middleware are covered by its name
+    let router = CommandSender::new(cmd_tx.clone(), "router@chat");
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    router.send(room_message("kicked", Some(response_tx))).await.unwrap();
+    let err = response_rx.await.unwrap().unwrap_err().to_string();
+    assert!(err.contains("router@chat") && err.contains("dry-run"), "{err}");
+
+    // Other middlewares' commands still go through, and only theirs reach the service
+    let echo = CommandSender::new(cmd_tx.clone(), "echo");
+    echo.send(room_message("hello", None)).await.unwrap();
+    let (_, _, body) = capture.expect_room_message().await;
+    assert_eq!(body, "hello");
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::Control(BusControl::RecentAudit {
+            limit: 10,
+            response_tx: Some(response_tx),
+        }))
+        .await
+        .unwrap();
+    let report = response_rx.await.unwrap().unwrap();
+    assert!(report.contains("by router@chat: dry_run"), "{report}");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_read_only_service_drops_commands_that_would_change_it() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let metrics = MetricsRegistry::default();
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_read_only_services(HashSet::from([service_id.clone()]))
+        .with_metrics(metrics.clone());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::SendRoomMessage {
+            service_id: service_id.clone(),
+            room_id: "!lobby".to_string(),
+            body: "hello".to_string(),
+            format: BodyFormat::Plain,
+            response_tx: Some(response_tx),
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        })
+        .await
+        .unwrap();
+    let err = response_rx.await.unwrap().unwrap_err().to_string();
+    assert!(err.contains("chat") && err.contains("read-only"), "{err}");
+    assert_eq!(metrics.snapshot()[&service_id].commands_blocked, 1);
+
+    // Commands that only read state still reach the service
+    let (response_tx, _response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::GetUserList { service_id: service_id.clone(), response_tx, origin: None })
+        .await
+        .unwrap();
+    assert!(matches!(capture.next().await, Command::GetUserList { .. }));
+    capture.assert_empty();
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_announcement_is_posted_to_every_target_and_reported() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let chat = ServiceId("chat".to_string());
+    let voice = ServiceId("voice".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (chat_service, _chat_control) = MockService::new(chat.clone(), evt_tx.clone());
+    let (voice_service, _voice_control) = MockService::new(voice.clone(), evt_tx);
+    let services = HashMap::from([
+        (chat.clone(), Arc::new(chat_service.forward_commands(capture_tx)) as Arc<dyn Service>),
+        (voice.clone(), Arc::new(voice_service) as Arc<dyn Service>),
+    ]);
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_read_only_services(HashSet::from([voice.clone()]))
+        .with_audit(AuditLog::in_memory(Duration::from_secs(3600)).unwrap());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    CommandSender::new(cmd_tx.clone(), "maintenance")
+        .send(Command::Announce {
+            targets: vec![
+                (chat.clone(), RoomTarget::Room("!lobby".to_string())),
+                (chat.clone(), RoomTarget::DirectMessage("@alice".to_string())),
+                (voice.clone(), RoomTarget::Room("root".to_string())),
+            ],
+            body: "Restarting in 5 minutes".to_string(),
+            priority: AnnouncementPriority::Urgent,
+            response_tx: Some(response_tx),
+            origin: None,
+        })
+        .await
+        .unwrap();
+
+    let (_, room_id, body) = capture.expect_room_message().await;
+    assert_eq!((room_id.as_str(), body.as_str()), ("!lobby", "🚨 Restarting in 5 minutes"));
+    let (_, user_id, body) = capture.expect_direct_message().await;
+    assert_eq!((user_id.as_str(), body.as_str()), ("@alice", "🚨 Restarting in 5 minutes"));
+    let report = response_rx.await.unwrap().unwrap();
+    assert_eq!(
+        report, "Posted to 2 of 3 targets\nvoice root: service 'voice' is read-only",
+        "{report}"
+    );
+
+    // Each message is dispatched, and audited, on its own
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::Control(BusControl::RecentAudit {
+            limit: 10,
+            response_tx: Some(response_tx),
+        }))
+        .await
+        .unwrap();
+    let audit = response_rx.await.unwrap().unwrap();
+    assert!(audit.contains("send_room_message chat → !lobby by maintenance: ok"), "{audit}");
+    assert!(audit.contains("send_room_message voice → root by maintenance: read_only"), "{audit}");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_standby_instance_holds_back_commands_until_it_takes_over() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let lease_file = dir.path().join("lease.sqlite3");
+    let ttl = Duration::from_secs(1);
+    let primary = Lease::open(&lease_file, "primary".to_string(), ttl).unwrap();
+    assert!(primary.try_acquire(chrono::Utc::now()).unwrap());
+    let standby = Lease::open(&lease_file, "standby".to_string(), ttl).unwrap();
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_lease(standby);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let room_message = |body: &str, response_tx| Command::SendRoomMessage {
+        service_id: service_id.clone(),
+        room_id: "!lobby".to_string(),
+        body: body.to_string(),
+        format: BodyFormat::Plain,
+        response_tx,
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    };
+    let echo = CommandSender::new(cmd_tx.clone(), "echo");
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    echo.send(room_message("doubled", Some(response_tx))).await.unwrap();
+    let err = response_rx.await.unwrap().unwrap_err().to_string();
+    assert!(err.contains("standby"), "{err}");
+
+    // Once the primary hands over, the standby takes the lease at its next renewal
+    primary.release().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while primary.holder(chrono::Utc::now()).unwrap().as_deref() != Some("standby") {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("standby should take over the lease");
+    echo.send(room_message("hello", None)).await.unwrap();
+    let (_, _, body) = capture.expect_room_message().await;
+    assert_eq!(body, "hello");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+    // Leadership is handed back on shutdown
+    assert_eq!(primary.holder(chrono::Utc::now()).unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_injected_crash_restarts_the_service_after_backoff() {
+    struct CountingService {
+        runs: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Service for CountingService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, _command: Command) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let service = Arc::new(CountingService { runs: AtomicUsize::new(0) });
+    let services = HashMap::from([(service_id.clone(), service.clone() as Arc<dyn Service>)]);
+    let reconnection = ReconnectionConfig {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        multiplier: 1.0,
+        jitter_factor: 0.0,
+    };
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), reconnection);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let inject = |service_id: &str, fault| {
+        let cmd_tx = cmd_tx.clone();
+        let service_id = ServiceId(service_id.to_string());
+        async move {
+            let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+            let control =
+                BusControl::InjectFault { service_id, fault, response_tx: Some(response_tx) };
+            cmd_tx.send(Command::Control(control)).await.unwrap();
+            response_rx.await.unwrap()
+        }
+    };
+    let err = inject("mumble", Fault::Crash).await.unwrap_err().to_string();
+    assert!(err.contains("unknown service"), "{err}");
+
+    inject("chat", Fault::Crash).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while service.runs.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("crashed service should be restarted");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_bus_sends_service_lifecycle_events_through_the_pipeline() {
+    struct IdleService;
+
+    #[async_trait]
+    impl Service for IdleService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, _command: Command) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let services = HashMap::from([(service_id.clone(), Arc::new(IdleService) as Arc<dyn Service>)]);
+    let recorder = Arc::new(MockMiddleware::new(Verdict::Continue));
+    let pipelines =
+        HashMap::from([(service_id.clone(), vec![recorder.clone() as Arc<dyn Middleware>])]);
+    let reconnection = ReconnectionConfig {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        multiplier: 1.0,
+        jitter_factor: 0.0,
+    };
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, pipelines, reconnection);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    let kinds =
+        || -> Vec<EventKind> { recorder.events().iter().map(|evt| evt.kind.clone()).collect() };
+    let wait_for = |count: usize| {
+        let recorder = recorder.clone();
+        tokio::time::timeout(Duration::from_secs(2), async move {
+            while recorder.event_count() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+    };
+
+    wait_for(1).await.expect("service should be reported ready");
+    assert!(matches!(kinds()[..], [EventKind::ServiceReady]), "{:?}", kinds());
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let control = BusControl::InjectFault {
+        service_id: service_id.clone(),
+        fault: Fault::Crash,
+        response_tx: Some(response_tx),
+    };
+    cmd_tx.send(Command::Control(control)).await.unwrap();
+    response_rx.await.unwrap().unwrap();
+
+    wait_for(3).await.expect("service should be reported degraded, then ready again");
+    let kinds = kinds();
+    let [
+        EventKind::ServiceReady,
+        EventKind::ServiceDegraded { error, attempt, retry_in },
+        EventKind::ServiceReady,
+    ] = &kinds[..]
+    else {
+        panic!("unexpected lifecycle events: {kinds:?}");
+    };
+    assert!(error.as_deref().is_some_and(|error| error.contains("simulated crash")), "{error:?}");
+    assert_eq!(*attempt, 1);
+    assert_eq!(*retry_in, Duration::from_millis(10));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_disabled_middleware_commands_are_dropped_until_enabled() {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("chat".to_string());
+    let (capture_tx, mut capture) = command_capture(10);
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx);
+    let services = HashMap::from([(
+        service_id.clone(),
+        Arc::new(mock_service.forward_commands(capture_tx)) as Arc<dyn Service>,
+    )]);
+
+    let chatty: Arc<dyn Middleware> = Arc::new(Logger {});
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_middleware_names(HashMap::from([("chatty".to_string(), chatty)]))
+        .with_disabled_middlewares(vec!["chatty".to_string()]);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let direct_message = |response_tx| Command::SendDirectMessage {
+        service_id: service_id.clone(),
+        user_id: "@admin:example.com".to_string(),
+        body: "hello".to_string(),
+        response_tx,
+        origin: None,
+        idempotency_key: None,
+        expires_after: None,
+        require_encryption: false,
+    };
+    let chatty_tx = CommandSender::new(cmd_tx.clone(), "chatty");
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    chatty_tx.send(direct_message(Some(response_tx))).await.unwrap();
+    let err = response_rx.await.unwrap().unwrap_err().to_string();
+    assert!(err.contains("chatty") && err.contains("disabled"), "{err}");
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    cmd_tx
+        .send(Command::Control(BusControl::EnableMiddleware {
+            name: "chatty".to_string(),
+            response_tx: Some(response_tx),
+        }))
+        .await
+        .unwrap();
+    assert_ok!(response_rx.await.unwrap());
+
+    chatty_tx.send(direct_message(None)).await.unwrap();
+    let (_, _, body) = capture.expect_direct_message().await;
+    assert_eq!(body, "hello");
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_bus_registers_pipeline_commands_once_service_is_ready() {
+    struct Commands(&'static [&'static str]);
+
+    #[async_trait]
+    impl Middleware for Commands {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        fn on_event(&self, _event: &Arc<Event>) -> anyhow::Result<Verdict> {
+            Ok(Verdict::Continue)
+        }
+
+        fn command_strings(&self) -> Vec<&str> {
+            self.0.to_vec()
+        }
+    }
+
+    struct NativeService {
+        registered: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    #[async_trait]
+    impl Service for NativeService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, _command: Command) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn register_commands(&self, commands: Vec<CommandSpec>) -> anyhow::Result<()> {
+            let prefixes = commands.into_iter().map(|command| command.prefix).collect();
+            self.registered.lock().unwrap().push(prefixes);
+            Ok(())
+        }
+    }
+
+    let registered = Arc::new(Mutex::new(Vec::new()));
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+
+    let service_id = ServiceId("native".to_string());
+    let mut services: HashMap<ServiceId, Arc<dyn Service>> = HashMap::new();
+    services.insert(service_id.clone(), Arc::new(NativeService { registered: registered.clone() }));
+
+    let mut service_middlewares: HashMap<ServiceId, Vec<Arc<dyn Middleware>>> = HashMap::new();
+    service_middlewares.insert(
+        service_id.clone(),
+        vec![Arc::new(Commands(&["!echo", "!ping"])), Arc::new(Commands(&[]))],
+    );
+    let room_pipeline: Vec<Arc<dyn Middleware>> = vec![Arc::new(Commands(&["!ping", "!tap"]))];
+    let room_middlewares = HashMap::from([(
+        service_id.clone(),
+        HashMap::from([("lobby".to_string(), room_pipeline)]),
+    )]);
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default())
+            .with_room_middlewares(room_middlewares);
+
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // One registration, with the command both pipelines share listed once
+    assert_eq!(*registered.lock().unwrap(), vec![vec!["!echo", "!ping", "!tap"]]);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_user_list_changes_are_sent_both_as_snapshots_and_as_diffs() {
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            username: id.to_string(),
+            display_name: id.to_string(),
+            is_active: true,
+            is_self: false,
+            comment: None,
+            avatar: None,
+        }
+    }
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let event_tap = create_event_tap(10);
+    let mut tap_rx = event_tap.subscribe();
+
+    let service_id = ServiceId("voice_mock".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_event_tap(event_tap);
+
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let user_list = |kind| Event { service_id: service_id.clone(), kind };
+    evt_tx
+        .send(user_list(EventKind::UserListUpdate { users: vec![user("alice"), user("bob")] }))
+        .await
+        .unwrap();
+    // The same list again changes nothing, so no diff follows it
+    evt_tx
+        .send(user_list(EventKind::UserListUpdate { users: vec![user("alice"), user("bob")] }))
+        .await
+        .unwrap();
+    evt_tx
+        .send(user_list(EventKind::UserListDiff {
+            joined: vec![user("carol")],
+            left: vec![user("alice")],
+            changed: vec![],
+        }))
+        .await
+        .unwrap();
+
+    // The tap also sees the service becoming ready; only the user lists matter here
+    let mut kinds = Vec::new();
+    while kinds.len() < 5 {
+        let evt = tokio::time::timeout(Duration::from_millis(200), tap_rx.recv())
+            .await
+            .expect("Timeout waiting for tapped event")
+            .expect("Tap closed");
+        if matches!(evt.kind, EventKind::UserListUpdate { .. } | EventKind::UserListDiff { .. }) {
+            kinds.push(evt.kind.clone());
+        }
+    }
+    let ids = |users: &[User]| users.iter().map(|u| u.id.clone()).collect::<Vec<_>>();
+
+    assert!(matches!(kinds[0], EventKind::UserListUpdate { .. }));
+    let EventKind::UserListDiff { joined, left, changed } = &kinds[1] else {
+        panic!("Expected a diff after the first snapshot, got {:?}", kinds[1]);
+    };
+    assert_eq!(ids(joined), vec!["alice", "bob"]);
+    assert!(left.is_empty() && changed.is_empty());
+
+    assert!(matches!(kinds[2], EventKind::UserListUpdate { .. }));
+    assert!(matches!(kinds[3], EventKind::UserListDiff { .. }));
+    let EventKind::UserListUpdate { users } = &kinds[4] else {
+        panic!("Expected a snapshot after the diff, got {:?}", kinds[4]);
+    };
+    assert_eq!(ids(users), vec!["bob", "carol"]);
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_user_lists_follow_events_from_paused_services() {
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            username: id.to_string(),
+            display_name: id.to_string(),
+            is_active: true,
+            is_self: false,
+            comment: None,
+            avatar: None,
+        }
+    }
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let event_tap = create_event_tap(10);
+    let mut tap_rx = event_tap.subscribe();
+
+    let service_id = ServiceId("voice_mock".to_string());
+    let (mock_service, _mock_control) = MockService::new(service_id.clone(), evt_tx.clone());
+    let services =
+        HashMap::from([(service_id.clone(), Arc::new(mock_service) as Arc<dyn Service>)]);
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_event_tap(event_tap);
+
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let control =
+        BusControl::PauseService { service_id: service_id.clone(), response_tx: Some(response_tx) };
+    cmd_tx.send(Command::Control(control)).await.unwrap();
+    assert_ok!(response_rx.await.unwrap());
+    let user_list = |kind| Event { service_id: service_id.clone(), kind };
+    evt_tx
+        .send(user_list(EventKind::UserListDiff {
+            joined: vec![user("alice"), user("bob")],
+            left: vec![],
+            changed: vec![],
+        }))
+        .await
+        .unwrap();
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let control = BusControl::ResumeService {
+        service_id: service_id.clone(),
+        response_tx: Some(response_tx),
+    };
+    cmd_tx.send(Command::Control(control)).await.unwrap();
+    assert_ok!(response_rx.await.unwrap());
+    evt_tx
+        .send(user_list(EventKind::UserListUpdate {
+            users: vec![user("alice"), user("bob"), user("carol")],
+        }))
+        .await
+        .unwrap();
+
+    // Only the snapshot sent after resuming gets through, diffed against the dropped join
+    let mut kinds = Vec::new();
+    while kinds.len() < 2 {
+        let evt = tokio::time::timeout(Duration::from_millis(200), tap_rx.recv())
+            .await
+            .expect("Timeout waiting for tapped event")
+            .expect("Tap closed");
+        if matches!(evt.kind, EventKind::UserListUpdate { .. } | EventKind::UserListDiff { .. }) {
+            kinds.push(evt.kind.clone());
+        }
+    }
+    assert!(matches!(kinds[0], EventKind::UserListUpdate { .. }));
+    let EventKind::UserListDiff { joined, left, changed } = &kinds[1] else {
+        panic!("Expected a diff after the snapshot, got {:?}", kinds[1]);
+    };
+    assert_eq!(joined.iter().map(|u| u.id.as_str()).collect::<Vec<_>>(), vec!["carol"]);
+    assert!(left.is_empty() && changed.is_empty());
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
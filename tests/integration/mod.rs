@@ -1,3 +1,8 @@
+pub mod bot;
 pub mod configuration;
+#[cfg(feature = "e2e")]
+pub mod e2e;
 pub mod event_flow;
+#[cfg(feature = "proptest")]
+pub mod properties;
 pub mod service_lifecycle;
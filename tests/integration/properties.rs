@@ -0,0 +1,117 @@
+use kelvin_bot::core::{
+    arbitrary::event_from,
+    bus::{Bus, BusAlert, create_alert_channel, create_command_channel, create_event_channel},
+    config::Config,
+    event::Event,
+    metrics::MetricsRegistry,
+    middleware::{
+        build_service_pipelines, instantiate_middleware_from_config, validate_middleware_references,
+    },
+    service::instantiate_services_from_config,
+};
+use proptest::{collection::vec, prelude::*};
+use std::time::Duration;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio_util::sync::CancellationToken;
+
+// Every middleware that reacts to events without an external connection or schedule
+const CONFIG: &str = r#"
+    global_middleware = "logger"
+
+    [middlewares.logger]
+    kind = "logger"
+
+    [middlewares.echo]
+    kind = "echo"
+    command_string = "!echo"
+
+    [middlewares.invite]
+    kind = "invite"
+    command_string = "!invite"
+
+    [middlewares.admin]
+    kind = "busadmin"
+    command_string = "!admin"
+    admin_user_ids = "@a"
+
+    [middlewares.attendance]
+    kind = "attendancerelay"
+    source_service_id = "voice"
+    dest_service_id = "chat"
+    dest_room_id = "!a"
+    session_start_message = "started"
+    session_end_message = "ended"
+    session_ended_edit_message = "over"
+
+    [middlewares.relay]
+    kind = "chatrelay"
+    source_service_id = "chat"
+    dest_service_id = "voice"
+    dest_room_id = "a"
+    prefix_tag = "[chat]"
+
+    [services.chat]
+    kind = "loopback"
+    middleware = "echo,invite,admin,relay"
+
+    [services.voice]
+    kind = "loopback"
+    middleware = "attendance"
+"#;
+
+/// Runs `events` through the bus and returns every alert it raised.
+async fn run_through_bus(events: Vec<Event>) -> Vec<BusAlert> {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config: Config = toml::from_str(CONFIG).unwrap();
+    config.data_directory = dir.path().to_path_buf();
+    validate_middleware_references(&config).unwrap();
+
+    let (cmd_tx, cmd_rx) = create_command_channel(1024);
+    let (evt_tx, evt_rx) = create_event_channel(1024);
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
+        .await
+        .unwrap();
+    let middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &services).unwrap();
+    let service_middlewares = build_service_pipelines(&config, &middlewares).unwrap();
+    let alerts = create_alert_channel(64);
+    let mut alert_rx = alerts.subscribe();
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, service_middlewares, Default::default())
+        .with_middleware_names(middlewares)
+        .with_alerts(alerts);
+    let cancel = CancellationToken::new();
+    let bus_task = {
+        let cancel = cancel.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    for event in events {
+        evt_tx.send(event).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    cancel.cancel();
+    bus_task.await.unwrap().unwrap();
+
+    let mut raised = Vec::new();
+    loop {
+        match alert_rx.try_recv() {
+            Ok(alert) => raised.push(alert),
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => break,
+        }
+    }
+    raised
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn test_arbitrary_event_sequences_never_panic_middlewares(
+        events in vec(event_from(vec!["chat".to_string(), "voice".to_string()]), 0..24)
+    ) {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let alerts = runtime.block_on(run_through_bus(events));
+        prop_assert!(alerts.is_empty(), "middlewares panicked: {alerts:?}");
+    }
+}
@@ -1,9 +1,18 @@
 use crate::common::{create_multi_service_config, create_test_config};
+use chrono::{Datelike, NaiveTime, Utc};
+use chrono_tz::Tz;
 use kelvin_bot::core::{
-    bus::{Bus, create_command_channel, create_event_channel},
-    config::ReconnectionConfig,
-    middleware::instantiate_middleware_from_config,
-    service::instantiate_services_from_config,
+    bus::{Bus, Command, create_command_channel, create_event_channel},
+    config::{Config, ReconnectionConfig},
+    connection_schedule::ConnectionSchedule,
+    metrics::MetricsRegistry,
+    middleware::{build_service_pipelines, instantiate_middleware_from_config},
+    service::{Service, ServiceId, instantiate_services_from_config},
+};
+use std::collections::HashMap;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
 };
 use std::time::Duration;
 use tokio_test::assert_ok;
@@ -14,7 +23,7 @@ async fn test_service_instantiation_from_config() {
     let config = create_test_config();
     let (evt_tx, _evt_rx) = create_event_channel(10);
 
-    let services = instantiate_services_from_config(&config, &evt_tx)
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
         .await
         .expect("Failed to instantiate services");
 
@@ -27,7 +36,7 @@ async fn test_service_instantiation_with_multiple_services() {
     let config = create_multi_service_config();
     let (evt_tx, _evt_rx) = create_event_channel(10);
 
-    let services = instantiate_services_from_config(&config, &evt_tx)
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
         .await
         .expect("Failed to instantiate services");
 
@@ -41,8 +50,8 @@ async fn test_middleware_instantiation_from_config() {
     let config = create_test_config();
     let (cmd_tx, _cmd_rx) = create_command_channel(10);
 
-    let middlewares =
-        instantiate_middleware_from_config(&config, &cmd_tx).expect("Failed to instantiate");
+    let middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &HashMap::new())
+        .expect("Failed to instantiate");
 
     // With no middlewares defined in config, should be empty
     assert!(middlewares.is_empty());
@@ -54,10 +63,10 @@ async fn test_bus_creation_and_startup() {
     let (cmd_tx, cmd_rx) = create_command_channel(10);
     let (evt_tx, evt_rx) = create_event_channel(10);
 
-    let services = instantiate_services_from_config(&config, &evt_tx)
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
         .await
         .expect("Failed to instantiate services");
-    let _middlewares = instantiate_middleware_from_config(&config, &cmd_tx)
+    let _middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &HashMap::new())
         .expect("Failed to instantiate middlewares");
 
     // No middleware pipelines configured for services in test
@@ -89,10 +98,10 @@ async fn test_cancellation_propagates_to_services() {
     let (cmd_tx, cmd_rx) = create_command_channel(10);
     let (evt_tx, evt_rx) = create_event_channel(10);
 
-    let services = instantiate_services_from_config(&config, &evt_tx)
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
         .await
         .expect("Failed to instantiate services");
-    let _middlewares = instantiate_middleware_from_config(&config, &cmd_tx)
+    let _middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &HashMap::new())
         .expect("Failed to instantiate middlewares");
 
     // No middleware pipelines configured for services in test
@@ -121,3 +130,148 @@ async fn test_cancellation_propagates_to_services() {
         Err(_) => panic!("Bus should shutdown gracefully within timeout"),
     }
 }
+
+#[tokio::test]
+async fn test_bus_leaves_services_outside_their_schedule_disconnected() {
+    struct RecordingService(Arc<AtomicBool>);
+
+    #[async_trait::async_trait]
+    impl Service for RecordingService {
+        async fn run(&self, cancel: CancellationToken) -> anyhow::Result<()> {
+            self.0.store(true, Ordering::SeqCst);
+            cancel.cancelled().await;
+            Ok(())
+        }
+
+        async fn handle_command(&self, _command: Command) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (_cmd_tx, cmd_rx) = create_command_channel(10);
+    let (_evt_tx, evt_rx) = create_event_channel(10);
+    let (scheduled_ran, always_ran) =
+        (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+    let mut services: HashMap<ServiceId, Arc<dyn Service>> = HashMap::new();
+    services.insert(
+        ServiceId("scheduled".to_string()),
+        Arc::new(RecordingService(scheduled_ran.clone())),
+    );
+    services
+        .insert(ServiceId("always".to_string()), Arc::new(RecordingService(always_ran.clone())));
+
+    // Open all day, but neither today nor tomorrow, so the test can't straddle an opening
+    let today = Utc::now().weekday();
+    let days = vec![today.succ().succ(), today.succ().succ().succ()];
+    let midnight = NaiveTime::MIN;
+    let schedules = HashMap::from([(
+        ServiceId("scheduled".to_string()),
+        ConnectionSchedule::new(days, midnight, midnight, Tz::UTC),
+    )]);
+
+    let mut bus = Bus::new(evt_rx, cmd_rx, services, HashMap::new(), ReconnectionConfig::default())
+        .with_connection_schedules(schedules);
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(always_ran.load(Ordering::SeqCst));
+    assert!(!scheduled_ran.load(Ordering::SeqCst));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_loopback_services_drive_attendance_session_lifecycle() {
+    let dir = tempfile::tempdir().unwrap();
+    let inject_file = dir.path().join("voice.jsonl");
+    let transcript_file = dir.path().join("chat.jsonl");
+    let config: Config = toml::from_str(&format!(
+        r#"
+        data_directory = "{data}"
+
+        [middlewares.attendance]
+        kind = "attendancerelay"
+        source_service_id = "voice"
+        dest_service_id = "chat"
+        dest_room_id = "lobby"
+        session_start_message = "Voice chat started"
+        session_end_message = "Voice chat ended"
+        session_ended_edit_message = "(session over)"
+
+        [services.voice]
+        kind = "loopback"
+        middleware = "attendance"
+        inject_file = "{inject}"
+        poll_interval = "10ms"
+
+        [services.chat]
+        kind = "loopback"
+        room_ids = "lobby"
+        transcript_file = "{transcript}"
+        "#,
+        data = dir.path().display(),
+        inject = inject_file.display(),
+        transcript = transcript_file.display(),
+    ))
+    .expect("Failed to parse config");
+
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let (evt_tx, evt_rx) = create_event_channel(10);
+    let services = instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default())
+        .await
+        .expect("Failed to instantiate services");
+    let middlewares = instantiate_middleware_from_config(&config, &cmd_tx, &services)
+        .expect("Failed to instantiate middlewares");
+    let service_middlewares = build_service_pipelines(&config, &middlewares).unwrap();
+
+    let mut bus =
+        Bus::new(evt_rx, cmd_rx, services, service_middlewares, ReconnectionConfig::default());
+    let cancel_token = CancellationToken::new();
+    let bus_handle = {
+        let cancel = cancel_token.clone();
+        tokio::spawn(async move { bus.run(cancel).await })
+    };
+
+    std::fs::write(
+        &inject_file,
+        concat!(
+            r#"{"type": "join", "user": "alice"}"#,
+            "\n",
+            r#"{"type": "leave", "user": "alice"}"#,
+            "\n"
+        ),
+    )
+    .unwrap();
+
+    // Start message, edit of it once the session ends, then the summary
+    let transcript = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let transcript = std::fs::read_to_string(&transcript_file).unwrap_or_default();
+            if transcript.lines().count() >= 3 {
+                return transcript;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("attendance session did not complete");
+    let entries: Vec<serde_json::Value> =
+        transcript.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+
+    assert_eq!(entries[0]["type"], "room_message");
+    assert_eq!(entries[0]["room"], "lobby");
+    assert!(entries[0]["body"].as_str().unwrap().contains("Voice chat started\n\n- alice"));
+    assert_eq!(entries[1]["type"], "edit");
+    assert_eq!(entries[1]["id"], entries[0]["id"]);
+    assert_eq!(entries[1]["body"], "(session over)");
+    assert_eq!(entries[2]["type"], "room_message");
+    assert!(entries[2]["body"].as_str().unwrap().starts_with("Voice chat ended"));
+
+    cancel_token.cancel();
+    assert_ok!(bus_handle.await.unwrap());
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    alerts::{self, ALERTS_ORIGIN, AlertTarget, OperatorAlerts},
+    bus::{Command, CommandSender, create_command_channel},
+    config::{AlertLevel, OperatorAlertsConfig},
+    service::ServiceId,
+};
+
+fn room_alerts(
+    min_level: AlertLevel,
+    repeat_after: Duration,
+) -> (OperatorAlerts, tokio::sync::mpsc::Receiver<Command>) {
+    let (cmd_tx, cmd_rx) = create_command_channel(10);
+    let target = AlertTarget::Room {
+        service_id: ServiceId("matrix".to_string()),
+        room_id: "!ops:example.com".to_string(),
+    };
+    let alerts = OperatorAlerts::new(
+        CommandSender::new(cmd_tx, ALERTS_ORIGIN),
+        target,
+        min_level,
+        repeat_after,
+    );
+    (alerts, cmd_rx)
+}
+
+fn alerts_config(room_id: Option<&str>, user_id: Option<&str>) -> OperatorAlertsConfig {
+    OperatorAlertsConfig {
+        service_id: "matrix".to_string(),
+        room_id: room_id.map(str::to_string),
+        user_id: user_id.map(str::to_string),
+        min_level: AlertLevel::Warning,
+        repeat_after: Duration::from_secs(600),
+    }
+}
+
+#[tokio::test]
+async fn test_alert_is_posted_to_the_operator_room() {
+    let (alerts, mut cmd_rx) = room_alerts(AlertLevel::Warning, Duration::from_secs(600));
+
+    assert!(alerts.notify(AlertLevel::Critical, "service 'mumble' keeps failing"));
+
+    let Command::SendRoomMessage { service_id, room_id, body, origin, .. } =
+        cmd_rx.try_recv().unwrap()
+    else {
+        panic!("Expected SendRoomMessage");
+    };
+    assert_eq!(service_id, ServiceId("matrix".to_string()));
+    assert_eq!(room_id, "!ops:example.com");
+    assert_eq!(body, "🚨 service 'mumble' keeps failing");
+    assert_eq!(origin.unwrap().middleware, ALERTS_ORIGIN);
+}
+
+#[tokio::test]
+async fn test_alert_below_minimum_level_is_not_sent() {
+    let (alerts, mut cmd_rx) = room_alerts(AlertLevel::Critical, Duration::from_secs(600));
+
+    assert!(!alerts.notify(AlertLevel::Warning, "middleware 'echo' panicked"));
+    assert!(cmd_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_identical_alert_is_held_back_until_repeat_after() {
+    let (alerts, mut cmd_rx) = room_alerts(AlertLevel::Info, Duration::from_secs(600));
+
+    assert!(alerts.notify(AlertLevel::Warning, "scheduled backup failed"));
+    assert!(!alerts.notify(AlertLevel::Warning, "scheduled backup failed"));
+    assert!(alerts.notify(AlertLevel::Warning, "reloading middleware 'echo' failed"));
+    assert!(cmd_rx.try_recv().is_ok());
+    assert!(cmd_rx.try_recv().is_ok());
+    assert!(cmd_rx.try_recv().is_err());
+
+    let (alerts, mut cmd_rx) = room_alerts(AlertLevel::Info, Duration::ZERO);
+    assert!(alerts.notify(AlertLevel::Warning, "scheduled backup failed"));
+    assert!(alerts.notify(AlertLevel::Warning, "scheduled backup failed"));
+    assert!(cmd_rx.try_recv().is_ok());
+    assert!(cmd_rx.try_recv().is_ok());
+}
+
+#[tokio::test]
+async fn test_alert_to_a_user_is_sent_as_a_direct_message() {
+    let (cmd_tx, mut cmd_rx) = create_command_channel(10);
+    let alerts =
+        OperatorAlerts::from_config(&alerts_config(None, Some("@admin:example.com")), cmd_tx)
+            .unwrap();
+
+    assert!(alerts.notify(AlertLevel::Warning, "middleware 'echo' panicked"));
+
+    let Command::SendDirectMessage { service_id, user_id, body, .. } = cmd_rx.try_recv().unwrap()
+    else {
+        panic!("Expected SendDirectMessage");
+    };
+    assert_eq!(service_id, ServiceId("matrix".to_string()));
+    assert_eq!(user_id, "@admin:example.com");
+    assert_eq!(body, "⚠️ middleware 'echo' panicked");
+}
+
+#[test]
+fn test_alerts_config_needs_exactly_one_destination() {
+    let (cmd_tx, _cmd_rx) = create_command_channel(10);
+    assert!(OperatorAlerts::from_config(&alerts_config(None, None), cmd_tx.clone()).is_err());
+    assert!(
+        OperatorAlerts::from_config(
+            &alerts_config(Some("!ops:example.com"), Some("@admin:example.com")),
+            cmd_tx.clone()
+        )
+        .is_err()
+    );
+    assert!(
+        OperatorAlerts::from_config(&alerts_config(Some("!ops:example.com"), None), cmd_tx).is_ok()
+    );
+}
+
+#[tokio::test]
+async fn test_notify_sends_through_the_installed_alerts() {
+    let (alerts, mut cmd_rx) = room_alerts(AlertLevel::Info, Duration::from_secs(600));
+    alerts::install(alerts);
+
+    alerts::notify(AlertLevel::Info, "unit test alert through the installed sink");
+
+    // Other tests may raise alerts meanwhile, so look for this one among them
+    let mut bodies = Vec::new();
+    while let Ok(Command::SendRoomMessage { body, .. }) = cmd_rx.try_recv() {
+        bodies.push(body);
+    }
+    assert!(bodies.contains(&"ℹ️ unit test alert through the installed sink".to_string()));
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use kelvin_bot::core::{
+    audit::{AuditEntry, AuditLog, format_entries},
+    bus::{BusControl, Command},
+    service::ServiceId,
+};
+
+fn invite_token_entry(user_id: &str) -> AuditEntry {
+    let (response_tx, _) = tokio::sync::oneshot::channel();
+    let command = Command::GenerateInviteToken {
+        service_id: ServiceId("matrix".to_string()),
+        user_id: user_id.to_string(),
+        uses_allowed: Some(1),
+        expiry: None,
+        response_tx,
+        origin: None,
+    };
+    AuditEntry::for_command(&command, "ok".to_string(), Duration::from_millis(84))
+}
+
+#[test]
+fn test_audit_entry_describes_commands_and_controls() {
+    let entry = invite_token_entry("@bob:example.com");
+    assert_eq!(entry.command, "generate_invite_token");
+    assert_eq!(entry.service_id, "matrix");
+    assert_eq!(entry.target, "@bob:example.com");
+
+    let control = BusControl::DisableMiddleware { name: "echo".to_string(), response_tx: None };
+    let entry = AuditEntry::for_control(&control, "ok".to_string());
+    assert_eq!(entry.command, "disable_middleware");
+    assert_eq!(entry.service_id, "bus");
+    assert_eq!(entry.target, "echo");
+}
+
+#[test]
+fn test_audit_log_returns_recent_entries_newest_first() {
+    let log = AuditLog::in_memory(Duration::from_secs(3600)).unwrap();
+    for user in ["@a", "@b", "@c"] {
+        log.record(&invite_token_entry(user)).unwrap();
+    }
+
+    let recent = log.recent(2).unwrap();
+    let targets: Vec<_> = recent.iter().map(|entry| entry.target.as_str()).collect();
+    assert_eq!(targets, ["@c", "@b"]);
+    assert_eq!(recent[0].outcome, "ok");
+    assert_eq!(recent[0].latency, Duration::from_millis(84));
+    assert_eq!(recent[0].origin, None);
+}
+
+#[test]
+fn test_audit_log_prunes_entries_past_retention() {
+    let log = AuditLog::in_memory(Duration::from_secs(60)).unwrap();
+    let mut stale = invite_token_entry("@stale");
+    stale.at = Utc::now() - chrono::Duration::minutes(5);
+    log.record(&stale).unwrap();
+    log.record(&invite_token_entry("@fresh")).unwrap();
+
+    let recent = log.recent(10).unwrap();
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].target, "@fresh");
+}
+
+#[test]
+fn test_format_entries_lists_one_line_per_entry() {
+    assert_eq!(format_entries(&[]), "No commands recorded");
+
+    let mut entry = invite_token_entry("@bob:example.com");
+    entry.origin = Some("invite".to_string());
+    let formatted = format_entries(&[entry]);
+    assert!(
+        formatted.ends_with("generate_invite_token matrix → @bob:example.com by invite: ok (84ms)"),
+        "unexpected format: {formatted}"
+    );
+}
@@ -0,0 +1,74 @@
+use std::fs;
+
+use kelvin_bot::core::backup::{BackupOptions, create_backup, restore_backup};
+use rusqlite::Connection;
+
+fn seed_data_directory(data: &std::path::Path) {
+    fs::write(data.join("relay.store.json"), r#"{"cursor": 3}"#).unwrap();
+    fs::create_dir_all(data.join("matrix/bot")).unwrap();
+    fs::write(data.join("matrix/bot/session.json"), "{}").unwrap();
+    let outbox = Connection::open(data.join("outbox.sqlite3")).unwrap();
+    outbox.execute_batch("PRAGMA journal_mode = WAL; CREATE TABLE queue (body TEXT);").unwrap();
+    outbox.execute("INSERT INTO queue (body) VALUES ('hello')", []).unwrap();
+}
+
+#[test]
+fn test_backup_round_trips_the_data_directory() {
+    let data = tempfile::tempdir().unwrap();
+    seed_data_directory(data.path());
+    let out = tempfile::tempdir().unwrap();
+    let archive = out.path().join("backup.tar.gz");
+
+    let backup = create_backup(data.path(), &archive, &BackupOptions::default()).unwrap();
+    assert_eq!(backup.files, 2);
+    assert!(!backup.manifest.includes_matrix_store);
+
+    let restored_data = tempfile::tempdir().unwrap();
+    let restored = restore_backup(&archive, restored_data.path()).unwrap();
+    assert_eq!(restored, backup);
+    assert_eq!(
+        fs::read_to_string(restored_data.path().join("relay.store.json")).unwrap(),
+        r#"{"cursor": 3}"#
+    );
+    // Taken while the database was still open in WAL mode, and still holds the row
+    let outbox = Connection::open(restored_data.path().join("outbox.sqlite3")).unwrap();
+    let body: String = outbox.query_row("SELECT body FROM queue", [], |row| row.get(0)).unwrap();
+    assert_eq!(body, "hello");
+    assert!(!restored_data.path().join("matrix").exists());
+}
+
+#[test]
+fn test_backup_includes_the_matrix_store_when_asked() {
+    let data = tempfile::tempdir().unwrap();
+    seed_data_directory(data.path());
+    let archive = data.path().join("backup.tar.gz");
+
+    let options = BackupOptions { include_matrix_store: true, ..Default::default() };
+    let backup = create_backup(data.path(), &archive, &options).unwrap();
+    // The archive sits in the data directory, but isn't backed up into itself
+    assert_eq!(backup.files, 3);
+
+    let restored_data = tempfile::tempdir().unwrap();
+    restore_backup(&archive, restored_data.path()).unwrap();
+    assert!(restored_data.path().join("matrix/bot/session.json").exists());
+    assert!(!restored_data.path().join("backup.tar.gz").exists());
+}
+
+#[test]
+fn test_restore_rejects_tarballs_that_arent_backups() {
+    let out = tempfile::tempdir().unwrap();
+    let archive = out.path().join("other.tar.gz");
+    let file = fs::File::create(&archive).unwrap();
+    let mut builder =
+        tar::Builder::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_cksum();
+    builder.append_data(&mut header, "notes.txt", "hello".as_bytes()).unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    let data = tempfile::tempdir().unwrap();
+    let err = restore_backup(&archive, data.path()).unwrap_err();
+    assert!(err.to_string().contains("isn't a kelvin-bot backup"), "{err}");
+    assert!(!data.path().join("notes.txt").exists());
+}
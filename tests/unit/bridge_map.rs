@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    bridge_map::{BridgeMap, DEFAULT_TTL},
+    service::ServiceId,
+};
+
+fn services() -> (ServiceId, ServiceId, ServiceId) {
+    (
+        ServiceId("matrix".to_string()),
+        ServiceId("discord".to_string()),
+        ServiceId("mumble".to_string()),
+    )
+}
+
+#[test]
+fn test_bridge_map_finds_counterparts_either_way() {
+    let map = BridgeMap::in_memory(DEFAULT_TTL).unwrap();
+    let (matrix, discord, mumble) = services();
+
+    map.record(&matrix, "$root", &discord, "d1").unwrap();
+
+    assert_eq!(map.counterpart(&matrix, "$root", &discord).unwrap().as_deref(), Some("d1"));
+    assert_eq!(map.counterpart(&discord, "d1", &matrix).unwrap().as_deref(), Some("$root"));
+    assert_eq!(map.counterpart(&matrix, "$root", &mumble).unwrap(), None);
+    assert_eq!(map.counterpart(&matrix, "$other", &discord).unwrap(), None);
+}
+
+#[test]
+fn test_bridge_map_lists_every_copy_of_a_message() {
+    let map = BridgeMap::in_memory(DEFAULT_TTL).unwrap();
+    let (matrix, discord, mumble) = services();
+
+    map.record(&matrix, "$root", &discord, "d1").unwrap();
+    map.record(&matrix, "$root", &mumble, "u1").unwrap();
+    // Relaying again replaces the earlier copy
+    map.record(&matrix, "$root", &discord, "d2").unwrap();
+
+    assert_eq!(
+        map.copies(&matrix, "$root").unwrap(),
+        vec![(discord, "d2".to_string()), (mumble, "u1".to_string())]
+    );
+}
+
+#[test]
+fn test_bridge_map_is_kept_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let (matrix, discord, _) = services();
+
+    BridgeMap::load(dir.path()).unwrap().record(&matrix, "$root", &discord, "d1").unwrap();
+
+    let reopened = BridgeMap::load(dir.path()).unwrap();
+    assert_eq!(reopened.counterpart(&discord, "d1", &matrix).unwrap().as_deref(), Some("$root"));
+}
+
+#[test]
+fn test_bridge_map_forgets_expired_mappings() {
+    let map = BridgeMap::in_memory(Duration::from_secs(1)).unwrap();
+    let (matrix, discord, _) = services();
+
+    map.record(&matrix, "$root", &discord, "d1").unwrap();
+    std::thread::sleep(Duration::from_millis(2100));
+
+    assert_eq!(map.counterpart(&matrix, "$root", &discord).unwrap(), None);
+    assert!(map.copies(&matrix, "$root").unwrap().is_empty());
+}
@@ -1,4 +1,16 @@
-use kelvin_bot::core::bus::{create_command_channel, create_event_channel};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use kelvin_bot::core::bus::{
+    AnnouncementPriority, Command, CommandPolicy, CommandSender, EventSender, OverflowPolicy,
+    RetryPolicy, RoomFilter, RoomTarget, command_policy_from_config, create_command_channel,
+    create_event_channel, dry_run_middlewares_from_config, format_announcement, is_retryable,
+    read_only_services_from_config, room_filters_from_config, send_with_retry, transient_error,
+};
+use kelvin_bot::core::config::{Config, ReconnectionConfig};
+use kelvin_bot::core::event::{Event, EventKind};
+use kelvin_bot::core::format::BodyFormat;
+use kelvin_bot::core::service::ServiceId;
 
 #[test]
 fn test_command_channel_creation() {
@@ -21,3 +33,356 @@ fn test_event_channel_creation() {
     // Channel should be created successfully with specified capacity
     // Basic smoke test - if we get here, channel creation worked
 }
+
+fn fast_retry_policy(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        backoff: ReconnectionConfig {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            multiplier: 2.0,
+            jitter_factor: 0.0,
+        },
+    }
+}
+
+fn room_message() -> Command {
+    Command::SendRoomMessage {
+        service_id: ServiceId("matrix".to_string()),
+        room_id: "!room:example.com".to_string(),
+        body: "hello".to_string(),
+        format: BodyFormat::Plain,
+        response_tx: None,
+        origin: None,
+        idempotency_key: Some("relay-1".to_string()),
+        relayed_from: None,
+        expires_after: None,
+    }
+}
+
+#[test]
+fn test_transient_errors_are_retryable() {
+    assert!(is_retryable(&transient_error("service reconnecting")));
+    assert!(!is_retryable(&anyhow::anyhow!("invalid room ID")));
+}
+
+#[tokio::test]
+async fn test_send_with_retry_retries_transient_failures() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "test");
+
+    let responder = tokio::spawn(async move {
+        let mut attempts = 0;
+        while let Some(cmd) = cmd_rx.recv().await {
+            attempts += 1;
+            let Command::SendRoomMessage { body, response_tx: Some(tx), idempotency_key, .. } = cmd
+            else {
+                panic!("Expected SendRoomMessage with a response channel");
+            };
+            assert_eq!(body, "hello");
+            // Every attempt carries the same key, so one that did go through isn't sent twice
+            assert_eq!(idempotency_key.as_deref(), Some("relay-1"));
+            if attempts < 3 {
+                let _ = tx.send(Err(transient_error("not connected")));
+            } else {
+                let _ = tx.send(Ok("event_id".to_string()));
+            }
+        }
+        attempts
+    });
+
+    let result = send_with_retry(&cmd_tx, room_message(), &fast_retry_policy(5)).await;
+    assert_eq!(result.unwrap(), "event_id");
+
+    drop(cmd_tx);
+    assert_eq!(responder.await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn test_send_with_retry_gives_up_after_max_attempts() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "test");
+
+    let responder = tokio::spawn(async move {
+        let mut attempts = 0;
+        while let Some(cmd) = cmd_rx.recv().await {
+            attempts += 1;
+            cmd.reject(transient_error("not connected"));
+        }
+        attempts
+    });
+
+    let result = send_with_retry(&cmd_tx, room_message(), &fast_retry_policy(2)).await;
+    assert!(is_retryable(&result.unwrap_err()));
+
+    drop(cmd_tx);
+    assert_eq!(responder.await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_send_with_retry_does_not_retry_permanent_failures() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "test");
+
+    let responder = tokio::spawn(async move {
+        let mut attempts = 0;
+        while let Some(cmd) = cmd_rx.recv().await {
+            attempts += 1;
+            cmd.reject(anyhow::anyhow!("invalid room ID"));
+        }
+        attempts
+    });
+
+    let result = send_with_retry(&cmd_tx, room_message(), &fast_retry_policy(5)).await;
+    assert!(result.is_err());
+
+    drop(cmd_tx);
+    assert_eq!(responder.await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_send_with_retry_sends_fire_and_forget_commands_once() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "test");
+
+    let command = Command::EditMessage {
+        service_id: ServiceId("matrix".to_string()),
+        message_id: "$event".to_string(),
+        new_body: "edited".to_string(),
+        format: BodyFormat::Plain,
+        origin: None,
+    };
+    let result = send_with_retry(&cmd_tx, command, &fast_retry_policy(5)).await;
+    assert_eq!(result.unwrap(), "");
+
+    assert!(matches!(cmd_rx.try_recv(), Ok(Command::EditMessage { .. })));
+    assert!(cmd_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_command_sender_attributes_commands_to_its_middleware() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "echo@matrix");
+    assert_eq!(cmd_tx.origin(), "echo@matrix");
+
+    cmd_tx.send(room_message()).await.unwrap();
+    let command = cmd_rx.try_recv().unwrap();
+    assert_eq!(command.origin(), Some("echo@matrix"));
+}
+
+#[tokio::test]
+async fn test_spawn_send_sends_in_the_background() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "faq");
+
+    cmd_tx.spawn_send(room_message(), "faq answer");
+
+    let command = tokio::time::timeout(Duration::from_secs(1), cmd_rx.recv()).await.unwrap();
+    assert_eq!(command.unwrap().origin(), Some("faq"));
+}
+
+#[test]
+fn test_command_policy_restricts_listed_middlewares_only() {
+    let policy = CommandPolicy::new(HashMap::from([(
+        "relay".to_string(),
+        vec!["send_room_message".to_string()],
+    )]));
+    let from = |origin: &str, command: Command| {
+        let mut command = command;
+        command.set_origin(origin);
+        command
+    };
+    let reaction = || Command::AddReaction {
+        service_id: ServiceId("matrix".to_string()),
+        room_id: "!room:example.com".to_string(),
+        event_id: "$event".to_string(),
+        key: "👍".to_string(),
+        origin: None,
+    };
+
+    assert!(policy.permits(&from("relay", room_message())));
+    assert!(!policy.permits(&from("relay", reaction())));
+    // Per-service instances follow their middleware's entry
+    assert!(!policy.permits(&from("relay@mumble", reaction())));
+    assert!(policy.permits(&from("echo", reaction())));
+    assert!(policy.permits(&reaction()));
+}
+
+#[test]
+fn test_command_policy_from_config() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.dummy]
+        kind = "dummy"
+
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+        allowed_commands = "send_room_message,send_direct_message"
+
+        [middlewares.logger]
+        kind = "logger"
+        "#,
+    )
+    .unwrap();
+
+    let policy = command_policy_from_config(&config);
+    let mut command = room_message();
+    command.set_origin("echo");
+    assert!(policy.permits(&command));
+    let mut command = Command::EditMessage {
+        service_id: ServiceId("dummy".to_string()),
+        message_id: "$event".to_string(),
+        new_body: "edited".to_string(),
+        format: BodyFormat::Plain,
+        origin: None,
+    };
+    assert!(policy.permits(&command));
+    command.set_origin("echo");
+    assert!(!policy.permits(&command));
+}
+
+#[test]
+fn test_dry_run_middlewares_from_config() {
+    let config: Config = toml::from_str(
+        r#"
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+        dry_run = "true"
+
+        [middlewares.logger]
+        kind = "logger"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(dry_run_middlewares_from_config(&config), vec!["echo".to_string()]);
+}
+
+fn user_list_event() -> Event {
+    Event {
+        service_id: ServiceId("matrix".to_string()),
+        kind: EventKind::UserListUpdate { users: vec![] },
+    }
+}
+
+#[tokio::test]
+async fn test_event_sender_sheds_when_full() {
+    let (evt_tx, mut evt_rx) = create_event_channel(1);
+    let sender = EventSender::new(evt_tx, OverflowPolicy::DropNewest);
+
+    assert!(sender.send(user_list_event()).await.unwrap());
+    assert!(!sender.send(user_list_event()).await.unwrap());
+    assert_eq!(sender.dropped_count(), 1);
+
+    // Clones share the counter
+    let clone = sender.clone();
+    assert!(!clone.send(user_list_event()).await.unwrap());
+    assert_eq!(sender.dropped_count(), 2);
+
+    assert!(evt_rx.try_recv().is_ok());
+    assert!(evt_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_event_sender_waits_for_room() {
+    let (evt_tx, mut evt_rx) = create_event_channel(1);
+    let sender = EventSender::new(evt_tx, OverflowPolicy::WaitFor(Duration::from_secs(1)));
+
+    assert!(sender.send(user_list_event()).await.unwrap());
+    let drain = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        evt_rx.recv().await.unwrap();
+        evt_rx
+    });
+
+    assert!(sender.send(user_list_event()).await.unwrap());
+    assert_eq!(sender.dropped_count(), 0);
+    let mut evt_rx = drain.await.unwrap();
+    assert!(evt_rx.try_recv().is_ok());
+}
+
+#[tokio::test]
+async fn test_event_sender_gives_up_after_waiting() {
+    let (evt_tx, _evt_rx) = create_event_channel(1);
+    let sender = EventSender::new(evt_tx, OverflowPolicy::WaitFor(Duration::from_millis(10)));
+
+    assert!(sender.send(user_list_event()).await.unwrap());
+    assert!(!sender.send(user_list_event()).await.unwrap());
+    assert_eq!(sender.dropped_count(), 1);
+}
+
+#[tokio::test]
+async fn test_event_sender_errors_once_bus_is_gone() {
+    let (evt_tx, evt_rx) = create_event_channel(1);
+    drop(evt_rx);
+    let sender = EventSender::new(evt_tx, OverflowPolicy::DropNewest);
+
+    assert!(sender.send(user_list_event()).await.is_err());
+}
+
+#[test]
+fn test_room_filter_denylist_wins_over_allowlist() {
+    let filter = RoomFilter::new(
+        Some(vec!["!ops:example.org".to_string(), "!noisy:example.org".to_string()]),
+        vec!["!noisy:example.org".to_string()],
+    );
+    assert!(filter.allows("!ops:example.org"));
+    assert!(!filter.allows("!noisy:example.org"));
+    assert!(!filter.allows("!other:example.org"));
+
+    let deny_only = RoomFilter::new(None, vec!["!noisy:example.org".to_string()]);
+    assert!(deny_only.allows("!other:example.org"));
+    assert!(!deny_only.allows("!noisy:example.org"));
+}
+
+#[test]
+fn test_room_filters_from_config_only_covers_filtered_services() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.dummy1]
+        kind = "dummy"
+        room_denylist = "!noisy:example.org,!spam:example.org"
+
+        [services.dummy2]
+        kind = "dummy"
+        "#,
+    )
+    .unwrap();
+
+    let filters = room_filters_from_config(&config);
+    assert_eq!(filters.len(), 1);
+    let filter = &filters[&ServiceId("dummy1".to_string())];
+    assert!(!filter.allows("!spam:example.org"));
+    assert!(filter.allows("!ops:example.org"));
+}
+
+#[test]
+fn test_read_only_services_from_config() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.lurk]
+        kind = "dummy"
+        read_only = "true"
+
+        [services.chat]
+        kind = "dummy"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        read_only_services_from_config(&config),
+        HashSet::from([ServiceId("lurk".to_string())])
+    );
+}
+
+#[test]
+fn test_format_announcement_marks_priority() {
+    assert_eq!(format_announcement(AnnouncementPriority::Info, "New emoji"), "ℹ️ New emoji");
+    assert_eq!(format_announcement(AnnouncementPriority::default(), "Upgrade"), "📢 Upgrade");
+    assert_eq!(format_announcement(AnnouncementPriority::Urgent, "Going down"), "🚨 Going down");
+    assert_eq!(RoomTarget::Room("!ops".to_string()).to_string(), "!ops");
+    assert_eq!(RoomTarget::DirectMessage("@bob".to_string()).to_string(), "DM @bob");
+}
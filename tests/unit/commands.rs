@@ -0,0 +1,285 @@
+use chrono::{NaiveTime, Weekday};
+use kelvin_bot::core::{
+    bus::{Command, CommandSender, create_command_channel},
+    commands::{
+        ArgSpec, CommandRouter, CommandSpec, command_on, command_text, expand_alias,
+        parse_duration, parse_time_of_day, parse_weekday_time, set_command_alias,
+        set_command_prefix, split_words, strip_leading_mention,
+    },
+    event::{Event, EventKind},
+    service::ServiceId,
+};
+use std::time::Duration;
+
+fn bus_router() -> CommandRouter {
+    CommandRouter::new("!bus")
+        .with_subcommand("pause", vec![ArgSpec::required("service")])
+        .with_subcommand("say", vec![ArgSpec::required("room"), ArgSpec::rest("text")])
+        .with_subcommand("status", vec![ArgSpec::optional("service")])
+}
+
+fn room_message(body: &str) -> Event {
+    Event {
+        service_id: ServiceId("test".to_string()),
+        kind: EventKind::RoomMessage {
+            room_id: "!room:example.com".to_string(),
+            body: body.to_string(),
+            is_local_user: true,
+            sender_id: "@user:example.com".to_string(),
+            sender_display_name: None,
+            is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
+        },
+    }
+}
+
+#[test]
+fn test_router_ignores_other_commands() {
+    let router = bus_router();
+    assert!(router.parse("hello").is_none());
+    assert!(router.parse("!business pause x").is_none());
+}
+
+#[test]
+fn test_router_parses_subcommands_and_args() {
+    let router = bus_router();
+
+    let invocation = router.parse("!bus pause mumble").unwrap().unwrap();
+    assert_eq!(invocation.subcommand, Some("pause"));
+    assert_eq!(invocation.arg("service"), "mumble");
+
+    let invocation = router.parse("  !bus say lobby hello   there ").unwrap().unwrap();
+    assert_eq!(invocation.subcommand, Some("say"));
+    assert_eq!(invocation.arg("room"), "lobby");
+    assert_eq!(invocation.arg("text"), "hello   there");
+
+    let invocation = router.parse("!bus status").unwrap().unwrap();
+    assert_eq!(invocation.get("service"), None);
+    let invocation = router.parse("!bus status matrix").unwrap().unwrap();
+    assert_eq!(invocation.get("service"), Some("matrix"));
+}
+
+#[test]
+fn test_router_returns_usage_for_malformed_input() {
+    let router = bus_router();
+    let usage = router.usage();
+    assert_eq!(
+        usage,
+        "Usage: !bus pause <service> | !bus say <room> <text...> | !bus status [service]"
+    );
+
+    for body in ["!bus", "!bus explode", "!bus pause", "!bus pause a b", "!bus say lobby"] {
+        assert_eq!(router.parse(body), Some(Err(usage.clone())), "input: {body}");
+    }
+}
+
+#[test]
+fn test_router_without_subcommands() {
+    let router = CommandRouter::new("!echo").with_args(vec![ArgSpec::rest("text")]);
+    assert_eq!(router.parse("!echo hi there").unwrap().unwrap().arg("text"), "hi there");
+    assert_eq!(router.parse("!echo"), Some(Err("Usage: !echo <text...>".to_string())));
+
+    let bare = CommandRouter::new("!invite");
+    assert!(bare.parse("!invite").unwrap().is_ok());
+    assert!(bare.parse("!invite extra").unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_route_replies_with_usage_where_the_message_came_from() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "bus_admin");
+    let router = bus_router();
+
+    assert!(router.route(&room_message("!bus pause"), &cmd_tx).is_none());
+    let reply = tokio::time::timeout(Duration::from_millis(100), cmd_rx.recv())
+        .await
+        .expect("Timeout waiting for usage reply")
+        .expect("Channel closed");
+    assert_eq!(reply.origin(), Some("bus_admin"));
+    match reply {
+        Command::SendRoomMessage { room_id, body, .. } => {
+            assert_eq!(room_id, "!room:example.com");
+            assert!(body.starts_with("Usage: !bus pause"));
+        }
+        other => panic!("Expected SendRoomMessage, got {other:?}"),
+    }
+
+    assert!(router.route(&room_message("!bus pause mumble"), &cmd_tx).is_some());
+    assert!(router.route(&room_message("unrelated"), &cmd_tx).is_none());
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(cmd_rx.try_recv().is_err());
+}
+
+fn mention(body: &str) -> Event {
+    let mut evt = room_message(body);
+    if let EventKind::RoomMessage { mentions_self, .. } = &mut evt.kind {
+        *mentions_self = true;
+    }
+    evt
+}
+
+#[test]
+fn test_command_text_accepts_commands_after_a_mention() {
+    assert_eq!(command_text(&room_message("!echo hi"), "!echo").as_deref(), Some("!echo hi"));
+    assert_eq!(command_text(&mention("@kelvin echo hi"), "!echo").as_deref(), Some("!echo hi"));
+    assert_eq!(
+        command_text(&mention("Kelvin Bot: !echo hi"), "!echo").as_deref(),
+        Some("!echo hi")
+    );
+    assert_eq!(command_text(&mention("kelvin, echo"), "!echo").as_deref(), Some("!echo"));
+
+    // Without the mention flag, a bare command word is just a word
+    assert_eq!(command_text(&room_message("@kelvin echo hi"), "!echo"), None);
+    assert_eq!(command_text(&mention("@kelvin echoes hi"), "!echo"), None);
+    assert_eq!(command_text(&mention("@kelvin what's up"), "!echo"), None);
+}
+
+#[tokio::test]
+async fn test_service_command_prefix_replaces_the_sigil() {
+    // Prefixes are process-wide, so this service ID is kept to this test
+    let irc = ServiceId("prefixed_irc".to_string());
+    set_command_prefix(irc.clone(), ".");
+    let on_irc = |evt: Event| Event { service_id: irc.clone(), ..evt };
+
+    assert_eq!(command_on(&irc, "!echo"), ".echo");
+    assert_eq!(command_on(&irc, "echo"), "echo");
+    assert_eq!(command_on(&ServiceId("test".to_string()), "!echo"), "!echo");
+    assert_eq!(
+        command_text(&on_irc(room_message(" .echo hi")), "!echo").as_deref(),
+        Some("!echo hi")
+    );
+    assert_eq!(command_text(&on_irc(room_message("!echo hi")), "!echo"), None);
+    assert_eq!(
+        command_text(&on_irc(mention("kelvin: .echo hi")), "!echo").as_deref(),
+        Some("!echo hi")
+    );
+    assert_eq!(command_text(&room_message(".echo hi"), "!echo"), None);
+
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "bus_admin");
+    let router = bus_router();
+    assert_eq!(router.prefix_on(&irc), ".bus");
+    assert!(router.route(&on_irc(room_message(".bus pause mumble")), &cmd_tx).is_some());
+    assert!(router.route(&on_irc(room_message(".bus pause")), &cmd_tx).is_none());
+    match cmd_rx.recv().await.expect("Channel closed") {
+        Command::SendRoomMessage { body, .. } => assert!(body.starts_with("Usage: .bus pause")),
+        other => panic!("Expected SendRoomMessage, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_aliases_stand_for_commands_with_preset_arguments() {
+    // Aliases are process-wide, so these names are kept to this test
+    set_command_alias("aliasgn", "!bus say lobby Game night!");
+    set_command_alias("?aliasst", "!bus status");
+    let irc = ServiceId("aliased_irc".to_string());
+    set_command_prefix(irc.clone(), ".");
+
+    assert_eq!(expand_alias(None, "!aliasgn").as_deref(), Some("!bus say lobby Game night!"));
+    assert_eq!(expand_alias(None, "!aliasgnx"), None);
+    assert_eq!(
+        command_text(&room_message("?aliasst mumble"), "!bus").as_deref(),
+        Some("!bus status mumble")
+    );
+    assert_eq!(command_text(&room_message("?aliasst"), "!echo"), None);
+    assert_eq!(
+        command_text(&mention("kelvin: !aliasgn"), "!bus").as_deref(),
+        Some("!bus say lobby Game night!")
+    );
+    let on_irc = Event { service_id: irc.clone(), ..room_message(".aliasgn") };
+    assert_eq!(command_text(&on_irc, "!bus").as_deref(), Some("!bus say lobby Game night!"));
+
+    let (tx, _cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "bus_admin");
+    let router = bus_router();
+    assert!(router.matches("!aliasgn"));
+    assert!(router.parse("!aliasgn").is_none());
+    let invocation = router.route(&room_message("!aliasgn"), &cmd_tx).expect("alias routes");
+    assert_eq!(invocation.subcommand, Some("say"));
+    assert_eq!(invocation.arg("room"), "lobby");
+    assert_eq!(invocation.arg("text"), "Game night!");
+}
+
+#[test]
+fn test_strip_leading_mention() {
+    assert_eq!(strip_leading_mention("@kelvin:example.com  do it"), "do it");
+    assert_eq!(strip_leading_mention("Kelvin Bot: do it"), "do it");
+    assert_eq!(strip_leading_mention("do it"), "do it");
+    assert_eq!(strip_leading_mention("!bus say lobby: hi"), "!bus say lobby: hi");
+}
+
+#[tokio::test]
+async fn test_route_accepts_mentions() {
+    let (tx, _cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "bus_admin");
+    let invocation = bus_router().route(&mention("@kelvin bus pause mumble"), &cmd_tx).unwrap();
+    assert_eq!(invocation.subcommand, Some("pause"));
+    assert_eq!(invocation.arg("service"), "mumble");
+}
+
+#[test]
+fn test_router_accepts_quoted_words() {
+    let router = bus_router();
+    let invocation = router.parse(r#"!bus say "movie night" see you there"#).unwrap().unwrap();
+    assert_eq!(invocation.arg("room"), "movie night");
+    assert_eq!(invocation.arg("text"), "see you there");
+
+    let invocation = router.parse("!bus pause \u{201C}main mumble\u{201D}").unwrap().unwrap();
+    assert_eq!(invocation.arg("service"), "main mumble");
+
+    assert_eq!(split_words(r#"5 "two words" 24h"#), vec!["5", "two words", "24h"]);
+}
+
+#[test]
+fn test_router_spec_round_trips_native_invocations() {
+    let spec = bus_router().with_description("Manage the bus").spec();
+    assert_eq!(spec.name(), "bus");
+    assert_eq!(spec.description.as_deref(), Some("Manage the bus"));
+    assert_eq!(spec.subcommands.len(), 3);
+
+    // A native invocation becomes the message a user would have typed
+    let text = spec.message_text(Some("say"), &["movie night", "see you there"]);
+    assert_eq!(text, r#"!bus say "movie night" see you there"#);
+    let invocation = bus_router().parse(&text).unwrap().unwrap();
+    assert_eq!(invocation.arg("room"), "movie night");
+    assert_eq!(invocation.arg("text"), "see you there");
+
+    // Omitted optional arguments are left out
+    assert_eq!(spec.message_text(Some("status"), &[""]), "!bus status");
+
+    let bare = CommandSpec::new("!echo");
+    assert_eq!(bare.name(), "echo");
+    assert_eq!(bare.message_text(None, &["hi there"]), "!echo hi there");
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("2h30m"), Ok(Duration::from_secs(9000)));
+    assert_eq!(parse_duration(" 90s "), Ok(Duration::from_secs(90)));
+    assert!(parse_duration("soon").is_err());
+}
+
+#[test]
+fn test_parse_time_of_day() {
+    let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    assert_eq!(parse_time_of_day("19:30"), Ok(hm(19, 30)));
+    assert_eq!(parse_time_of_day("7pm"), Ok(hm(19, 0)));
+    assert_eq!(parse_time_of_day("7:15 AM"), Ok(hm(7, 15)));
+    assert_eq!(parse_time_of_day("12am"), Ok(hm(0, 0)));
+    assert_eq!(parse_time_of_day("12pm"), Ok(hm(12, 0)));
+    for bad in ["13pm", "25:00", "7:60", "noon", ""] {
+        assert!(parse_time_of_day(bad).is_err(), "accepted {bad:?}");
+    }
+}
+
+#[test]
+fn test_parse_weekday_time() {
+    let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    assert_eq!(parse_weekday_time("friday 7pm"), Ok((Weekday::Fri, hm(19, 0))));
+    assert_eq!(parse_weekday_time("Sat 9:15 am"), Ok((Weekday::Sat, hm(9, 15))));
+    assert!(parse_weekday_time("someday 7pm").is_err());
+    assert!(parse_weekday_time("friday").is_err());
+}
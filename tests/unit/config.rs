@@ -1,5 +1,8 @@
 use assert_matches::assert_matches;
-use kelvin_bot::core::config::{Config, ServiceKind};
+use kelvin_bot::core::config::{
+    Config, ServiceKind, config_schema, interpolate_env_in_toml, read_config_file,
+};
+use secrecy::ExposeSecret;
 
 #[test]
 fn test_config_serde_dummy_service() {
@@ -23,6 +26,33 @@ fn test_config_serde_dummy_service() {
     assert_matches!(&dummy2.kind, ServiceKind::Dummy { interval_ms: None });
 }
 
+#[test]
+fn test_config_enabled_defaults_to_true() {
+    let config_str = r#"
+        [services.dummy1]
+        kind = "dummy"
+
+        [services.dummy2]
+        kind = "dummy"
+        enabled = "false"
+
+        [middlewares.echo]
+        kind = "echo"
+        command_string = "!echo"
+        enabled = "false"
+
+        [middlewares.logger]
+        kind = "logger"
+        "#;
+
+    let config: Config = toml::from_str(config_str).expect("Failed to parse config");
+
+    assert!(config.services["dummy1"].enabled);
+    assert!(!config.services["dummy2"].enabled);
+    assert!(!config.middlewares["echo"].enabled);
+    assert!(config.middlewares["logger"].enabled);
+}
+
 #[test]
 fn test_config_unknown_service_type() {
     let config_str = r#"
@@ -36,3 +66,133 @@ fn test_config_unknown_service_type() {
     // Unknown service types should deserialize as Unknown variant
     assert_matches!(&unknown_service.kind, ServiceKind::Unknown);
 }
+
+#[test]
+fn test_config_schema_covers_service_and_middleware_kinds() {
+    let schema = config_schema().to_string();
+
+    for kind in ["dummy", "matrix", "mumble", "loopback", "echo", "chatrelay", "weeklygathering"] {
+        assert!(schema.contains(&format!("\"{kind}\"")), "schema is missing kind {kind}");
+    }
+    for field in ["homeserver_url", "command_string", "thumbnail_max_width", "flush_interval"] {
+        assert!(schema.contains(&format!("\"{field}\"")), "schema is missing field {field}");
+    }
+    // The catch-all variant only exists to tolerate unknown kinds, so it isn't advertised
+    assert!(!schema.contains("\"unknown\""));
+}
+
+fn test_env(name: &str) -> Option<String> {
+    match name {
+        "MATRIX_PASSWORD" => Some("hunter2".to_string()),
+        "HOMESERVER" => Some("matrix.example.com".to_string()),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_interpolate_env_in_toml_substitutes_string_values() {
+    let config_str = r#"
+        [services.matrix]
+        kind = "matrix"
+        homeserver_url = "https://${HOMESERVER}"
+        user_id = "@bot:example.com"
+        password = "${MATRIX_PASSWORD}"
+        device_id = "DEVICE"
+        db_passphrase = "costs $$5"
+        "#;
+
+    let interpolated = interpolate_env_in_toml(config_str, test_env).expect("interpolation failed");
+    let config: Config = toml::from_str(&interpolated).expect("Failed to parse config");
+
+    match &config.services["matrix"].kind {
+        ServiceKind::Matrix { homeserver_url, password, db_passphrase, .. } => {
+            assert_eq!(homeserver_url.as_str(), "https://matrix.example.com/");
+            assert_eq!(password.expose_secret(), "hunter2");
+            assert_eq!(db_passphrase.expose_secret(), "costs $5");
+        }
+        other => panic!("expected matrix service, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_interpolate_env_in_toml_rejects_unset_variables() {
+    let err = interpolate_env_in_toml(r#"token = "${MISSING_TOKEN}""#, test_env).unwrap_err();
+    assert!(err.to_string().contains("MISSING_TOKEN"), "unexpected error: {err}");
+
+    assert!(interpolate_env_in_toml(r#"token = "${UNTERMINATED""#, test_env).is_err());
+}
+
+fn write_config_files(files: &[(&str, &str)]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    for (name, contents) in files {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    dir
+}
+
+fn parse_table(table: toml::Table) -> Config {
+    toml::Value::Table(table).try_into().expect("Failed to parse config")
+}
+
+#[test]
+fn test_read_config_file_resolves_includes_and_profiles() {
+    let dir = write_config_files(&[
+        (
+            "kelvin.toml",
+            r#"
+            include = ["services.toml", "middlewares/*.toml"]
+            data_directory = "/var/lib/kelvin"
+
+            [services.dummy1]
+            interval_ms = "1000"
+
+            [profiles.staging]
+            data_directory = "/tmp/kelvin-staging"
+            services.dummy1.interval_ms = "50"
+            "#,
+        ),
+        (
+            "services.toml",
+            r#"
+            [services.dummy1]
+            kind = "dummy"
+            interval_ms = "5000"
+            "#,
+        ),
+        (
+            "middlewares/echo.toml",
+            "[middlewares.echo]\nkind = \"echo\"\ncommand_string = \"!echo\"",
+        ),
+        ("middlewares/logger.toml", "[middlewares.logger]\nkind = \"logger\""),
+        ("middlewares/notes.txt", "not toml"),
+    ]);
+    let path = dir.path().join("kelvin.toml");
+
+    let base: Config = parse_table(read_config_file(&path, None).unwrap());
+    assert_eq!(base.data_directory, std::path::PathBuf::from("/var/lib/kelvin"));
+    assert_eq!(base.middlewares.len(), 2);
+    // The including file's own values win over its includes
+    assert_matches!(base.services["dummy1"].kind, ServiceKind::Dummy { interval_ms: Some(1000) });
+
+    let staging: Config = parse_table(read_config_file(&path, Some("staging")).unwrap());
+    assert_eq!(staging.data_directory, std::path::PathBuf::from("/tmp/kelvin-staging"));
+    assert_matches!(staging.services["dummy1"].kind, ServiceKind::Dummy { interval_ms: Some(50) });
+    assert_eq!(staging.middlewares.len(), 2);
+}
+
+#[test]
+fn test_read_config_file_rejects_unknown_profile_and_include_cycles() {
+    let dir = write_config_files(&[
+        ("a.toml", "include = [\"b.toml\"]\n[services.dummy1]\nkind = \"dummy\""),
+        ("b.toml", "include = [\"a.toml\"]"),
+        ("plain.toml", "[services.dummy1]\nkind = \"dummy\""),
+    ]);
+
+    let err = read_config_file(&dir.path().join("a.toml"), None).unwrap_err();
+    assert!(err.to_string().contains("includes itself"), "unexpected error: {err}");
+
+    let err = read_config_file(&dir.path().join("plain.toml"), Some("prod")).unwrap_err();
+    assert!(err.to_string().contains("prod"), "unexpected error: {err}");
+}
@@ -0,0 +1,89 @@
+use chrono::{NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use kelvin_bot::core::{
+    bus::connection_schedules_from_config, config::Config, connection_schedule::ConnectionSchedule,
+    service::ServiceId,
+};
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+#[test]
+fn test_connection_schedule_daily_window() {
+    let schedule = ConnectionSchedule::new(vec![], time(18, 0), time(22, 0), Tz::UTC);
+
+    assert!(schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 4, 18, 0, 0).unwrap()));
+    assert!(schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 7, 21, 59, 0).unwrap()));
+    assert!(!schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 4, 22, 0, 0).unwrap()));
+    assert!(!schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 4, 12, 0, 0).unwrap()));
+}
+
+#[test]
+fn test_connection_schedule_window_past_midnight_belongs_to_day_it_opens() {
+    // 2024-03-08 is a Friday
+    let schedule = ConnectionSchedule::new(vec![Weekday::Fri], time(20, 0), time(2, 0), Tz::UTC);
+
+    assert!(schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 8, 23, 0, 0).unwrap()));
+    assert!(schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 9, 1, 0, 0).unwrap()));
+    assert!(!schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 8, 1, 0, 0).unwrap()));
+    assert!(!schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 9, 23, 0, 0).unwrap()));
+
+    // Equal start and end spans the whole day
+    let all_day = ConnectionSchedule::new(vec![Weekday::Sat], time(0, 0), time(0, 0), Tz::UTC);
+    assert!(all_day.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 9, 0, 0, 0).unwrap()));
+    assert!(all_day.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 9, 23, 59, 0).unwrap()));
+    assert!(!all_day.is_open_at(&Utc.with_ymd_and_hms(2024, 3, 10, 0, 0, 0).unwrap()));
+}
+
+#[test]
+fn test_connection_schedule_uses_its_time_zone() {
+    let schedule = ConnectionSchedule::new(
+        vec![],
+        time(18, 0),
+        time(22, 0),
+        "America/New_York".parse().unwrap(),
+    );
+
+    // 19:00 in New York (EST) is midnight UTC
+    assert!(schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 1, 16, 0, 0, 0).unwrap()));
+    assert!(!schedule.is_open_at(&Utc.with_ymd_and_hms(2024, 1, 15, 19, 0, 0).unwrap()));
+}
+
+#[test]
+fn test_connection_schedules_from_config() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.twitch]
+        kind = "dummy"
+        schedule = { days = "Friday,Saturday", start = "20:00", end = "02:00", timezone = "UTC" }
+
+        [services.always]
+        kind = "dummy"
+        "#,
+    )
+    .unwrap();
+
+    let schedules = connection_schedules_from_config(&config).unwrap();
+    assert_eq!(schedules.len(), 1);
+    assert_eq!(
+        schedules[&ServiceId("twitch".to_string())],
+        ConnectionSchedule::new(vec![Weekday::Fri, Weekday::Sat], time(20, 0), time(2, 0), Tz::UTC)
+    );
+}
+
+#[test]
+fn test_connection_schedules_from_config_rejects_bad_values() {
+    for schedule in [
+        r#"{ start = "8pm", end = "02:00" }"#,
+        r#"{ days = "Caturday", start = "20:00", end = "02:00" }"#,
+        r#"{ start = "20:00", end = "02:00", timezone = "Mars/Olympus_Mons" }"#,
+    ] {
+        let config: Config = toml::from_str(&format!(
+            "[services.twitch]\nkind = \"dummy\"\nschedule = {schedule}\n"
+        ))
+        .unwrap();
+        let err = connection_schedules_from_config(&config).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid schedule for service 'twitch'"), "{err:#}");
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    bus::CommandSender,
+    conversation::{ConversationEnded, Conversations},
+    service::ServiceId,
+};
+use kelvin_bot::testing::{command_capture, direct_message, room_message};
+
+#[tokio::test]
+async fn test_conversation_asks_and_receives_replies() {
+    let (cmd_tx, mut capture) = command_capture(10);
+    let cmd_tx = CommandSender::new(cmd_tx, "test");
+    let conversations = Conversations::default();
+
+    let start = direct_message("matrix", "@alice:example.com", "!plan");
+    let mut conversation = conversations.start(&start, &cmd_tx).unwrap();
+    assert!(conversations.is_active(&ServiceId("matrix".to_string()), "@alice:example.com"));
+
+    let flow = tokio::spawn(async move { conversation.ask("What's it called?").await });
+    let (service_id, user_id, prompt) = capture.expect_direct_message().await;
+    assert_eq!(service_id.0, "matrix");
+    assert_eq!(user_id, "@alice:example.com");
+    assert_eq!(prompt, "What's it called?");
+
+    // Only DMs from the same user on the same service are replies
+    assert!(!conversations.deliver(&direct_message("matrix", "@bob:example.com", "Nope")));
+    assert!(!conversations.deliver(&direct_message("mumble", "@alice:example.com", "Nope")));
+    assert!(!conversations.deliver(&room_message("matrix", "!room", "@alice:example.com", "No")));
+    assert!(conversations.deliver(&direct_message("matrix", "@alice:example.com", " Game night ")));
+
+    assert_eq!(flow.await.unwrap(), Ok("Game night".to_string()));
+    assert!(!conversations.is_active(&ServiceId("matrix".to_string()), "@alice:example.com"));
+    assert!(!conversations.deliver(&direct_message("matrix", "@alice:example.com", "Hello?")));
+}
+
+#[tokio::test]
+async fn test_conversation_allows_one_per_user() {
+    let (cmd_tx, _capture) = command_capture(10);
+    let cmd_tx = CommandSender::new(cmd_tx, "test");
+    let conversations = Conversations::default();
+    let start = direct_message("matrix", "@alice:example.com", "!plan");
+
+    let conversation = conversations.start(&start, &cmd_tx).unwrap();
+    assert!(conversations.start(&start, &cmd_tx).is_none());
+    assert!(
+        conversations
+            .start(&direct_message("matrix", "@bob:example.com", "!plan"), &cmd_tx)
+            .is_some()
+    );
+
+    drop(conversation);
+    assert!(conversations.start(&start, &cmd_tx).is_some());
+}
+
+#[tokio::test]
+async fn test_conversation_ends_on_timeout_or_cancel() {
+    let (cmd_tx, _capture) = command_capture(10);
+    let cmd_tx = CommandSender::new(cmd_tx, "test");
+    let conversations = Conversations::new(Duration::from_millis(20));
+    let start = direct_message("matrix", "@alice:example.com", "!plan");
+
+    let mut conversation = conversations.start(&start, &cmd_tx).unwrap();
+    assert_eq!(conversation.reply().await, Err(ConversationEnded::TimedOut));
+
+    conversations.deliver(&direct_message("matrix", "@alice:example.com", "Cancel"));
+    assert_eq!(conversation.reply().await, Err(ConversationEnded::Cancelled));
+}
+
+#[tokio::test]
+async fn test_conversation_choose_asks_until_an_option_matches() {
+    let (cmd_tx, mut capture) = command_capture(10);
+    let cmd_tx = CommandSender::new(cmd_tx, "test");
+    let conversations = Conversations::default();
+    let start = direct_message("matrix", "@alice:example.com", "!plan");
+    let mut conversation = conversations.start(&start, &cmd_tx).unwrap();
+
+    let flow = tokio::spawn(async move {
+        let first = conversation.choose("Where?", &["Online", "In person"]).await;
+        let second = conversation.choose("Where?", &["Online", "In person"]).await;
+        (first, second)
+    });
+
+    let (_, _, prompt) = capture.expect_direct_message().await;
+    assert_eq!(prompt, "Where?\n1. Online\n2. In person");
+    conversations.deliver(&direct_message("matrix", "@alice:example.com", "3"));
+    let (_, _, retry) = capture.expect_direct_message().await;
+    assert!(retry.contains("1 to 2"), "{retry}");
+    conversations.deliver(&direct_message("matrix", "@alice:example.com", "2"));
+
+    capture.expect_direct_message().await;
+    conversations.deliver(&direct_message("matrix", "@alice:example.com", "online"));
+
+    assert_eq!(flow.await.unwrap(), (Ok(1), Ok(0)));
+}
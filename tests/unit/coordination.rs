@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use kelvin_bot::core::coordination::Lease;
+
+fn leases(ttl: Duration) -> (tempfile::TempDir, Lease, Lease) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lease.sqlite3");
+    let first = Lease::open(&path, "first".to_string(), ttl).unwrap();
+    let second = Lease::open(&path, "second".to_string(), ttl).unwrap();
+    (dir, first, second)
+}
+
+#[test]
+fn test_lease_is_held_by_one_instance_at_a_time() {
+    let (_dir, first, second) = leases(Duration::from_secs(30));
+    let now = Utc.with_ymd_and_hms(2026, 3, 1, 12, 0, 0).unwrap();
+
+    assert!(first.try_acquire(now).unwrap());
+    assert!(!second.try_acquire(now).unwrap());
+    // Renewing extends the holder's lease past when it would have run out
+    assert!(first.try_acquire(now + chrono::Duration::seconds(20)).unwrap());
+    assert!(!second.try_acquire(now + chrono::Duration::seconds(40)).unwrap());
+    assert_eq!(
+        second.holder(now + chrono::Duration::seconds(40)).unwrap().as_deref(),
+        Some("first")
+    );
+}
+
+#[test]
+fn test_lease_passes_to_the_standby_once_it_expires_or_is_released() {
+    let (_dir, first, second) = leases(Duration::from_secs(30));
+    let now = Utc.with_ymd_and_hms(2026, 3, 1, 12, 0, 0).unwrap();
+
+    assert!(first.try_acquire(now).unwrap());
+    let expired = now + chrono::Duration::seconds(30);
+    assert!(second.try_acquire(expired).unwrap());
+    assert!(!first.try_acquire(expired).unwrap());
+
+    second.release().unwrap();
+    assert!(first.try_acquire(expired).unwrap());
+    // Releasing someone else's lease does nothing
+    second.release().unwrap();
+    assert_eq!(second.holder(expired).unwrap().as_deref(), Some("first"));
+}
@@ -0,0 +1,55 @@
+use kelvin_bot::core::{
+    bus::{Command, CommandSender, create_command_channel},
+    correlation::{self, CorrelationId},
+    service::ServiceId,
+};
+
+fn delete_message() -> Command {
+    Command::DeleteMessage {
+        service_id: ServiceId("chat".to_string()),
+        message_id: "$event".to_string(),
+        origin: None,
+    }
+}
+
+#[test]
+fn test_correlation_ids_are_unique() {
+    let first = CorrelationId::next();
+    let second = CorrelationId::next();
+    assert_ne!(first, second);
+    assert_ne!(first.to_string(), second.to_string());
+}
+
+#[tokio::test]
+async fn test_spawned_tasks_inherit_the_current_correlation_id() {
+    assert_eq!(CorrelationId::current(), None);
+
+    let id = CorrelationId::next();
+    let handle = id.scope(|| {
+        assert_eq!(CorrelationId::current(), Some(id));
+        correlation::spawn(async { CorrelationId::current() })
+    });
+    assert_eq!(handle.await.unwrap(), Some(id));
+    assert_eq!(CorrelationId::current(), None);
+
+    // Outside of any event there's nothing to inherit
+    assert_eq!(correlation::spawn(async { CorrelationId::current() }).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_commands_sent_while_handling_an_event_carry_its_id() {
+    let (tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(tx, "echo");
+    let id = CorrelationId::next();
+
+    let sender = cmd_tx.clone();
+    id.scope(|| correlation::spawn(async move { sender.send(delete_message()).await.unwrap() }))
+        .await
+        .unwrap();
+    cmd_tx.send(delete_message()).await.unwrap();
+
+    let command = cmd_rx.try_recv().unwrap();
+    assert_eq!(command.origin(), Some("echo"));
+    assert_eq!(command.correlation_id(), Some(id));
+    assert_eq!(cmd_rx.try_recv().unwrap().correlation_id(), None);
+}
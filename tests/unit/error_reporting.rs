@@ -0,0 +1,55 @@
+use kelvin_bot::core::config::Config;
+use kelvin_bot::core::error_reporting::redact_event;
+use kelvin_bot::core::redact::{self, MASK};
+use secrecy::ExposeSecret;
+use sentry::protocol::{Breadcrumb, Event, Value};
+
+#[test]
+fn test_error_reporting_config_from_file() {
+    let config: Config = toml::from_str(
+        r#"
+        [services]
+
+        [error_reporting]
+        dsn = "https://public@sentry.example.com/42"
+        environment = "production"
+        "#,
+    )
+    .unwrap();
+
+    let reporting = config.error_reporting.unwrap();
+    assert_eq!(reporting.dsn.expose_secret(), "https://public@sentry.example.com/42");
+    assert_eq!(reporting.environment.as_deref(), Some("production"));
+
+    let config: Config = toml::from_str("[services]").unwrap();
+    assert!(config.error_reporting.is_none());
+}
+
+#[test]
+fn test_redact_event_masks_secrets_in_messages_fields_and_breadcrumbs() {
+    redact::add_secret("sentry-secret-9012");
+
+    let mut event = Event {
+        message: Some("login failed with sentry-secret-9012".to_string()),
+        ..Default::default()
+    };
+    event.extra.insert(
+        "error".to_string(),
+        Value::from(vec!["token sentry-secret-9012 rejected", "retrying"]),
+    );
+    event.breadcrumbs.values.push(Breadcrumb {
+        message: Some("sent sentry-secret-9012".to_string()),
+        ..Default::default()
+    });
+
+    let event = redact_event(event);
+    assert_eq!(event.message.unwrap(), format!("login failed with {MASK}"));
+    assert_eq!(
+        event.extra["error"],
+        Value::from(vec![format!("token {MASK} rejected"), "retrying".to_string()])
+    );
+    assert_eq!(
+        event.breadcrumbs.values[0].message.as_deref(),
+        Some(format!("sent {MASK}").as_str())
+    );
+}
@@ -1,8 +1,9 @@
 use assert_matches::assert_matches;
 use kelvin_bot::core::{
-    event::{Event, EventKind},
+    event::{Event, EventKind, Provenance, VoiceState, mentions_name},
     service::ServiceId,
 };
+use std::time::Duration;
 
 #[test]
 fn test_event_display_direct_message() {
@@ -36,6 +37,10 @@ fn test_event_display_room_message() {
             sender_id: "@user:example.com".to_string(),
             sender_display_name: Some("User".to_string()),
             is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
         },
     };
 
@@ -46,6 +51,60 @@ fn test_event_display_room_message() {
     assert!(display.contains("Test message"));
 }
 
+#[test]
+fn test_event_display_voice_state_changed() {
+    let event = Event {
+        service_id: ServiceId("mumble".to_string()),
+        kind: EventKind::VoiceStateChanged {
+            room_id: "Lobby".to_string(),
+            user_id: "alice".to_string(),
+            sender_display_name: Some("alice".to_string()),
+            state: VoiceState::Recording,
+            active: true,
+            is_self: false,
+        },
+    };
+
+    assert_eq!(format!("{}", event), "[mumble][Voice+] Lobby: alice recording");
+    assert_eq!(event.kind.name(), "voice_state_changed");
+    assert_eq!(event.kind.room_id(), Some("Lobby"));
+}
+
+#[test]
+fn test_event_display_verification_requested() {
+    let event = Event {
+        service_id: ServiceId("matrix".to_string()),
+        kind: EventKind::VerificationRequested {
+            flow_id: "flow1".to_string(),
+            other_device_id: "ELEMENT".to_string(),
+            emoji: vec!["🐶 Dog".to_string(), "🔑 Key".to_string()],
+        },
+    };
+
+    assert_eq!(format!("{}", event), "[matrix][Verify] ELEMENT: 🐶 Dog, 🔑 Key");
+    assert_eq!(event.kind.name(), "verification_requested");
+    assert!(!event.kind.is_own());
+}
+
+#[test]
+fn test_event_display_service_degraded() {
+    let event = Event {
+        service_id: ServiceId("mumble".to_string()),
+        kind: EventKind::ServiceDegraded {
+            error: Some("connection reset".to_string()),
+            attempt: 2,
+            retry_in: Duration::from_secs(4),
+        },
+    };
+
+    assert_eq!(
+        format!("{}", event),
+        "[mumble][Degraded] attempt 2, retrying in 4s: connection reset"
+    );
+    assert_eq!(event.kind.name(), "service_degraded");
+    assert_eq!(event.kind.room_id(), None);
+}
+
 #[test]
 fn test_event_serialization() {
     let event = Event {
@@ -57,6 +116,10 @@ fn test_event_serialization() {
             sender_id: "@user:example.com".to_string(),
             sender_display_name: Some("User".to_string()),
             is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
         },
     };
 
@@ -70,3 +133,48 @@ fn test_event_serialization() {
     assert_eq!(deserialized.service_id.0, "test_service");
     assert_matches!(deserialized.kind, EventKind::RoomMessage { .. });
 }
+
+#[test]
+fn test_mentions_name() {
+    assert!(mentions_name("@Kelvin play something", "kelvin"));
+    assert!(mentions_name("thanks @kelvin!", "Kelvin"));
+    assert!(mentions_name("kelvin: play something", "Kelvin"));
+    assert!(!mentions_name("@kelvinator hi", "kelvin"));
+    assert!(!mentions_name("kelvin is great", "kelvin"));
+    assert!(!mentions_name("anything", ""));
+}
+
+#[test]
+fn test_room_message_without_mentions_self_deserializes() {
+    let json = r#"{"service_id":"chat","kind":{"RoomMessage":{"room_id":"!lobby","body":"hi","is_local_user":false,"sender_id":"@alice","sender_display_name":null,"is_self":false}}}"#;
+    let event: Event = serde_json::from_str(json).unwrap();
+    assert_matches!(
+        event.kind,
+        EventKind::RoomMessage { mentions_self: false, relayed_from: None, .. }
+    );
+}
+
+#[test]
+fn test_is_own_excludes_messages_relayed_for_others() {
+    let own_message = |relayed_from| EventKind::RoomMessage {
+        room_id: "!lobby".to_string(),
+        body: "[Mumble] Alice: hi".to_string(),
+        is_local_user: true,
+        sender_id: "@kelvin".to_string(),
+        sender_display_name: None,
+        is_self: true,
+        mentions_self: false,
+        relayed_from,
+        thread_root_id: None,
+        message_id: None,
+    };
+
+    assert!(own_message(None).is_own());
+    let relayed = own_message(Some(Provenance {
+        service_id: "mumble".to_string(),
+        sender_id: "alice".to_string(),
+        sender_display_name: Some("Alice".to_string()),
+    }));
+    assert!(!relayed.is_own());
+    assert_eq!(relayed.relayed_from().map(|from| from.sender_id.as_str()), Some("alice"));
+}
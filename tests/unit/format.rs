@@ -0,0 +1,73 @@
+use kelvin_bot::core::format::{BodyFormat, FormatProfile, render};
+
+const MARKDOWN: &str = "**Movie night** is at [the Orpheum](https://example.com), bring `snacks`\n\
+                        - popcorn\n\
+                        - ~~soda~~ juice";
+
+#[test]
+fn test_render_plain_bodies_are_only_escaped_for_html() {
+    let body = "1 < 2 & **not bold**\nsecond line";
+
+    assert_eq!(render(body, BodyFormat::Plain, FormatProfile::Plain), body);
+    assert_eq!(render(body, BodyFormat::Plain, FormatProfile::Irc), body);
+    assert_eq!(
+        render(body, BodyFormat::Plain, FormatProfile::MumbleHtml),
+        "1 &lt; 2 &amp; **not bold**<br>second line"
+    );
+}
+
+#[test]
+fn test_render_markdown_as_matrix_html() {
+    let html = render(MARKDOWN, BodyFormat::Markdown, FormatProfile::MatrixHtml);
+
+    assert!(html.starts_with("<p><strong>Movie night</strong> is at "), "{html}");
+    assert!(html.contains(r#"<a href="https://example.com">the Orpheum</a>"#), "{html}");
+    assert!(html.contains("<li><del>soda</del> juice</li>"), "{html}");
+    // Lines broken where they were written stay broken
+    assert_eq!(
+        render("one\ntwo", BodyFormat::Markdown, FormatProfile::MatrixHtml),
+        "<p>one<br />\ntwo</p>"
+    );
+}
+
+#[test]
+fn test_render_markdown_as_mumble_html() {
+    assert_eq!(
+        render(MARKDOWN, BodyFormat::Markdown, FormatProfile::MumbleHtml),
+        "<b>Movie night</b> is at <a href=\"https://example.com\">the Orpheum</a>, bring \
+         <code>snacks</code><ul><li>popcorn</li><li><s>soda</s> juice</li></ul>"
+    );
+    // Raw HTML is shown, not rendered
+    assert_eq!(
+        render("<img src=x> *hi*", BodyFormat::Markdown, FormatProfile::MumbleHtml),
+        "&lt;img src=x&gt; <i>hi</i>"
+    );
+}
+
+#[test]
+fn test_render_markdown_as_irc() {
+    assert_eq!(
+        render(MARKDOWN, BodyFormat::Markdown, FormatProfile::Irc),
+        "\x02Movie night\x02 is at the Orpheum (https://example.com), bring \x11snacks\x11\n\n\
+         - popcorn\n\
+         - \x1esoda\x1e juice"
+    );
+}
+
+#[test]
+fn test_render_markdown_as_plain_text() {
+    assert_eq!(
+        render(MARKDOWN, BodyFormat::Markdown, FormatProfile::Plain),
+        "Movie night is at the Orpheum (https://example.com), bring snacks\n\n\
+         - popcorn\n\
+         - soda juice"
+    );
+    assert_eq!(
+        render(
+            "# Showtimes\n\nSee <https://example.com>\n\n1. first\n2. second\n\n> quoted",
+            BodyFormat::Markdown,
+            FormatProfile::Plain
+        ),
+        "Showtimes\n\nSee https://example.com\n\n1. first\n2. second\n\n> quoted"
+    );
+}
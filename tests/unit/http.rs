@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    config::Config,
+    http::{HttpClient, parse_rate_limit},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A server answering every request with how many it's had so far, returning its address.
+async fn spawn_counting_server() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut count = 0;
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            count += 1;
+            let body = count.to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    address
+}
+
+#[tokio::test]
+async fn test_send_cached_reuses_responses_within_ttl() {
+    let address = spawn_counting_server().await;
+    let http = HttpClient::default().with_cache(Duration::from_secs(60));
+
+    let first = http.get(format!("{address}/releases")).send_cached().await.unwrap();
+    let second = http.get(format!("{address}/releases")).send_cached().await.unwrap();
+    assert!(first.status.is_success());
+    assert_eq!((first.body.as_str(), second.body.as_str()), ("1", "1"));
+
+    // Other URLs, and requests sent without the cache, reach the server
+    let other = http.get(format!("{address}/tags")).send_cached().await.unwrap();
+    assert_eq!(other.body, "2");
+    let uncached = http.get(format!("{address}/releases")).send().await.unwrap();
+    assert_eq!(uncached.text().await.unwrap(), "3");
+
+    // Without a cache every request goes out
+    let http = HttpClient::default();
+    assert_eq!(http.get(format!("{address}/releases")).send_cached().await.unwrap().body, "4");
+    assert_eq!(http.get(format!("{address}/releases")).send_cached().await.unwrap().body, "5");
+}
+
+#[tokio::test]
+async fn test_send_cached_skips_authorized_requests() {
+    let address = spawn_counting_server().await;
+    let http = HttpClient::default().with_cache(Duration::from_secs(60));
+
+    let authorized = |token: &str| http.get(format!("{address}/releases")).bearer_auth(token);
+    assert_eq!(authorized("alice").send_cached().await.unwrap().body, "1");
+    assert_eq!(authorized("bob").send_cached().await.unwrap().body, "2");
+    // Nor does an anonymous request get what an authorized one fetched
+    assert_eq!(http.get(format!("{address}/releases")).send_cached().await.unwrap().body, "3");
+}
+
+#[tokio::test]
+async fn test_rate_limited_host_spaces_out_requests() {
+    let address = spawn_counting_server().await;
+    // One request every 50ms
+    let http = HttpClient::default().with_rate_limit("127.0.0.1", 1200);
+
+    let started = std::time::Instant::now();
+    for _ in 0..3 {
+        http.get(&address).send().await.unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+}
+
+#[test]
+fn test_parse_rate_limit() {
+    assert_eq!(
+        parse_rate_limit("API.github.com = 30").unwrap(),
+        ("api.github.com".to_string(), 30)
+    );
+    assert!(parse_rate_limit("api.github.com").is_err());
+    assert!(parse_rate_limit("api.github.com=0").is_err());
+    assert!(parse_rate_limit("=30").is_err());
+}
+
+#[test]
+fn test_http_client_from_config() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.chat]
+        kind = "dummy"
+
+        [http]
+        timeout = "30s"
+        rate_limits = "api.github.com=30,crates.io=60"
+        cache_ttl = "5m"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.http.rate_limits.as_ref().map(Vec::len), Some(2));
+    assert!(HttpClient::from_config(&config.http).is_ok());
+
+    let config: Config = toml::from_str(
+        r#"
+        [services.chat]
+        kind = "dummy"
+
+        [http]
+        rate_limits = "api.github.com"
+        "#,
+    )
+    .unwrap();
+    assert!(HttpClient::from_config(&config.http).is_err());
+}
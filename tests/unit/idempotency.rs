@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    bus::Command,
+    format::BodyFormat,
+    idempotency::{SeenKeys, fresh_key},
+    service::ServiceId,
+};
+
+fn room_message(
+    idempotency_key: Option<&str>,
+) -> (Command, tokio::sync::oneshot::Receiver<anyhow::Result<String>>) {
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let command = Command::SendRoomMessage {
+        service_id: ServiceId("mumble".to_string()),
+        room_id: "lobby".to_string(),
+        body: "hello".to_string(),
+        format: BodyFormat::Plain,
+        response_tx: Some(response_tx),
+        origin: None,
+        idempotency_key: idempotency_key.map(str::to_string),
+        relayed_from: None,
+        expires_after: None,
+    };
+    (command, response_rx)
+}
+
+#[tokio::test]
+async fn test_seen_keys_answers_repeats_with_the_first_result() {
+    let seen = SeenKeys::default();
+    let (command, _response_rx) = room_message(Some("relay-1"));
+    let command = seen.screen(command).expect("first send should go through");
+    seen.record(command.idempotency_key(), &Ok("$event".to_string()));
+
+    let (repeat, response_rx) = room_message(Some("relay-1"));
+    assert!(seen.screen(repeat).is_none());
+    assert_eq!(response_rx.await.unwrap().unwrap(), "$event");
+
+    // Other keys, and messages without one, are sent as usual
+    assert!(seen.screen(room_message(Some("relay-2")).0).is_some());
+    assert!(seen.screen(room_message(None).0).is_some());
+}
+
+#[test]
+fn test_seen_keys_forgets_failures_and_expired_keys() {
+    let seen = SeenKeys::default();
+    seen.record(Some("failed"), &Err(anyhow::anyhow!("unknown channel")));
+    assert!(seen.screen(room_message(Some("failed")).0).is_some());
+
+    let seen = SeenKeys::new(Duration::ZERO);
+    seen.record(Some("relay-1"), &Ok(String::new()));
+    assert!(seen.screen(room_message(Some("relay-1")).0).is_some());
+}
+
+#[test]
+fn test_fresh_keys_differ() {
+    assert_ne!(fresh_key(), fresh_key());
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use kelvin_bot::core::config::{Config, LogFormat, LoggingConfig};
+use kelvin_bot::core::logging::filter_directives;
+
+#[test]
+fn test_filter_directives_default_to_warn() {
+    assert_eq!(filter_directives(&LoggingConfig::default(), None).unwrap(), "warn");
+    assert_eq!(filter_directives(&LoggingConfig::default(), Some("  ")).unwrap(), "warn");
+}
+
+#[test]
+fn test_filter_directives_apply_target_levels_then_rust_log() {
+    let cfg = LoggingConfig {
+        level: Some("INFO".to_string()),
+        targets: [
+            ("matrix_sdk".to_string(), "error".to_string()),
+            ("kelvin_bot::core::bus".to_string(), "debug".to_string()),
+        ]
+        .into(),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        filter_directives(&cfg, Some("kelvin_bot=trace")).unwrap(),
+        "info,kelvin_bot::core::bus=debug,matrix_sdk=error,kelvin_bot=trace"
+    );
+}
+
+#[test]
+fn test_filter_directives_reject_invalid_levels() {
+    let cfg = LoggingConfig { level: Some("loud".to_string()), ..Default::default() };
+    assert!(filter_directives(&cfg, None).unwrap_err().to_string().contains("loud"));
+
+    let cfg = LoggingConfig {
+        targets: [("matrix_sdk".to_string(), "verbose".to_string())].into(),
+        ..Default::default()
+    };
+    let err = format!("{:#}", filter_directives(&cfg, None).unwrap_err());
+    assert!(err.contains("matrix_sdk") && err.contains("verbose"), "unexpected error: {err}");
+}
+
+#[test]
+fn test_logging_config_from_file() {
+    let config: Config = toml::from_str(
+        r#"
+        [services]
+
+        [logging]
+        level = "info"
+        format = "json"
+
+        [logging.targets]
+        matrix_sdk = "warn"
+
+        [logging.file]
+        retention = "30d"
+        format = "text"
+        "#,
+    )
+    .unwrap();
+
+    let logging = &config.logging;
+    assert_eq!(logging.format, LogFormat::Json);
+    assert_eq!(logging.targets["matrix_sdk"], "warn");
+    let file = logging.file.as_ref().unwrap();
+    assert_eq!(file.directory, None);
+    assert_eq!(file.prefix, "kelvin.log");
+    assert_eq!(file.retention, Duration::from_secs(30 * 24 * 60 * 60));
+    assert_eq!(file.format, Some(LogFormat::Text));
+}
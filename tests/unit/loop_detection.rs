@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{loop_detection::RecentRelays, service::ServiceId};
+
+const WINDOW: Duration = Duration::from_secs(30);
+
+#[test]
+fn test_recent_relays_spot_content_carried_back_with_prefixes() {
+    let recent = RecentRelays::default();
+    let matrix = ServiceId("matrix".to_string());
+
+    assert!(recent.check_and_record(&matrix, "!lobby", "Anyone up for a game tonight?", WINDOW));
+    // Another bridge passed it on and a third brought it back
+    assert!(!recent.check_and_record(
+        &matrix,
+        "!lobby",
+        "[Discord] relay-bot: [Mumble] Alice: anyone up for a game  tonight",
+        WINDOW
+    ));
+}
+
+#[test]
+fn test_recent_relays_are_kept_per_room() {
+    let recent = RecentRelays::default();
+    let matrix = ServiceId("matrix".to_string());
+    let discord = ServiceId("discord".to_string());
+
+    assert!(recent.check_and_record(&matrix, "!lobby", "Anyone up for a game tonight?", WINDOW));
+    assert!(recent.check_and_record(&matrix, "!other", "Anyone up for a game tonight?", WINDOW));
+    assert!(recent.check_and_record(&discord, "!lobby", "Anyone up for a game tonight?", WINDOW));
+}
+
+#[test]
+fn test_recent_relays_let_short_and_different_messages_through() {
+    let recent = RecentRelays::default();
+    let matrix = ServiceId("matrix".to_string());
+
+    assert!(recent.check_and_record(&matrix, "!lobby", "lol", WINDOW));
+    assert!(recent.check_and_record(&matrix, "!lobby", "lol", WINDOW));
+    assert!(recent.check_and_record(&matrix, "!lobby", "Anyone up for a game tonight?", WINDOW));
+    assert!(recent.check_and_record(&matrix, "!lobby", "Anyone up for a game tomorrow?", WINDOW));
+}
+
+#[test]
+fn test_recent_relays_forget_content_after_the_window() {
+    let recent = RecentRelays::default();
+    let matrix = ServiceId("matrix".to_string());
+    let window = Duration::from_millis(50);
+
+    assert!(recent.check_and_record(&matrix, "!lobby", "Anyone up for a game tonight?", window));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(recent.check_and_record(&matrix, "!lobby", "Anyone up for a game tonight?", window));
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{config::MediaConfig, media::MediaStore};
+
+fn media_config(ttl: Duration) -> MediaConfig {
+    MediaConfig {
+        ttl,
+        listen_address: Some("127.0.0.1:0".parse().unwrap()),
+        public_url: Some("https://bot.example.com/files".parse().unwrap()),
+    }
+}
+
+#[test]
+fn test_media_store_saves_attachments_and_links_to_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let media = MediaStore::open(dir.path(), &media_config(Duration::from_secs(3600))).unwrap();
+    assert!(media.hosts_links());
+
+    let stored = media.store(b"\x89PNG", Some("image/png")).unwrap();
+    assert!(stored.id.ends_with(".png"));
+    assert_eq!(std::fs::read(&stored.path).unwrap(), b"\x89PNG");
+    assert_eq!(
+        stored.url.unwrap().as_str(),
+        format!("https://bot.example.com/files/media/{}", stored.id)
+    );
+    assert_eq!(media.path(&stored.id), Some(stored.path));
+
+    // Names are random, and nothing outside the directory can be asked for
+    assert_ne!(media.store(b"\x89PNG", Some("image/png")).unwrap().id, stored.id);
+    assert_eq!(media.path("../media.png"), None);
+    assert_eq!(media.path("0123abcd.exe"), None);
+}
+
+#[test]
+fn test_media_store_expires_attachments() {
+    let dir = tempfile::tempdir().unwrap();
+    let media = MediaStore::open(dir.path(), &media_config(Duration::ZERO)).unwrap();
+    let stored = media.store(b"data", None).unwrap();
+    assert!(stored.id.ends_with(".bin"));
+    std::thread::sleep(Duration::from_millis(20));
+
+    assert_eq!(media.path(&stored.id), None);
+    assert_eq!(media.prune().unwrap(), 1);
+    assert!(!stored.path.exists());
+}
+
+#[test]
+fn test_media_store_shortens_links() {
+    let dir = tempfile::tempdir().unwrap();
+    let cfg = media_config(Duration::from_secs(3600));
+    let media = MediaStore::open(dir.path(), &cfg).unwrap();
+
+    let short = media.shorten("https://matrix.to/#/!room:matrix.org/$event").unwrap();
+    let code = short.as_str().strip_prefix("https://bot.example.com/files/s/").unwrap();
+    assert_eq!(media.expand(code).as_deref(), Some("https://matrix.to/#/!room:matrix.org/$event"));
+    assert_eq!(media.shorten("https://matrix.to/#/!room:matrix.org/$event").unwrap(), short);
+    assert_ne!(media.shorten("https://example.com/other").unwrap(), short);
+
+    // Other stores on the same directory, e.g. the server's, see the same links
+    let reopened = MediaStore::open(dir.path(), &cfg).unwrap();
+    assert!(reopened.expand(code).is_some());
+    assert_eq!(media.expand("missing"), None);
+    assert!(media.shorten("not a url").is_err());
+}
+
+#[test]
+fn test_media_store_expires_short_links() {
+    let dir = tempfile::tempdir().unwrap();
+    let media = MediaStore::open(dir.path(), &media_config(Duration::ZERO)).unwrap();
+    let short = media.shorten("https://example.com/a/long/path").unwrap();
+    let code = short.path_segments().unwrap().next_back().unwrap().to_string();
+    std::thread::sleep(Duration::from_millis(1100));
+
+    assert_eq!(media.expand(&code), None);
+    assert_eq!(media.prune().unwrap(), 1);
+}
+
+#[test]
+fn test_media_store_without_config_stores_nothing() {
+    let media = MediaStore::default();
+
+    assert!(!media.is_enabled());
+    assert!(!media.hosts_links());
+    assert!(media.store(b"data", None).is_err());
+    assert!(media.shorten("https://example.com").is_err());
+    assert_eq!(media.prune().unwrap(), 0);
+}
+
+#[test]
+fn test_media_store_needs_both_address_and_url_to_serve() {
+    let dir = tempfile::tempdir().unwrap();
+    let cfg = MediaConfig { public_url: None, ..media_config(Duration::from_secs(60)) };
+
+    assert!(MediaStore::open(dir.path(), &cfg).is_err());
+
+    let cfg = MediaConfig { listen_address: None, ..cfg };
+    let media = MediaStore::open(dir.path(), &cfg).unwrap();
+    assert!(media.is_enabled());
+    assert!(!media.hosts_links());
+    assert_eq!(media.store(b"data", None).unwrap().url, None);
+    assert!(media.shorten("https://example.com").is_err());
+}
+
+#[tokio::test]
+async fn test_media_store_serves_attachments_over_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = MediaConfig {
+        listen_address: Some(format!("127.0.0.1:{port}").parse().unwrap()),
+        ..media_config(Duration::from_secs(3600))
+    };
+    let media = MediaStore::open(dir.path(), &cfg).unwrap();
+    let stored = media.store(b"GIF89a", Some("image/gif")).unwrap();
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let server = tokio::spawn({
+        let media = media.clone();
+        let cancel = cancel.clone();
+        async move { media.run(cancel).await }
+    });
+
+    let get = |target: String| async move {
+        let mut stream = loop {
+            match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream.write_all(format!("GET {target} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        anyhow::Ok(String::from_utf8_lossy(&response).into_owned())
+    };
+
+    let response = get(format!("/media/{}", stored.id)).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("Content-Type: image/gif\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nGIF89a"), "{response}");
+
+    let response = get("/media/ffff.gif".to_string()).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+
+    let short = media.shorten("https://example.com/a/long/path").unwrap();
+    let response = get(short.path().trim_start_matches("/files").to_string()).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 302 Found\r\n"), "{response}");
+    assert!(response.contains("Location: https://example.com/a/long/path\r\n"), "{response}");
+
+    let response = get("/s/missing".to_string()).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+
+    cancel.cancel();
+    server.await.unwrap().unwrap();
+}
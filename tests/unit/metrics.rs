@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    metrics::{
+        EventLag, LATENCY_BUCKETS, LagStage, MetricsRegistry, MiddlewareLatency, ServiceCounters,
+        ServiceMetrics, ServiceMetricsSnapshot,
+    },
+    service::ServiceId,
+};
+
+#[test]
+fn test_service_counters_snapshot() {
+    let counters = ServiceCounters::default();
+    assert_eq!(counters.snapshot(), ServiceMetricsSnapshot::default());
+
+    counters.message_received();
+    counters.message_received();
+    counters.message_sent(Duration::from_millis(10));
+    counters.message_sent(Duration::from_millis(30));
+    counters.send_failed();
+    counters.reconnected();
+    counters.round_trip(Duration::from_millis(40));
+    counters.round_trip(Duration::from_millis(20));
+    counters.command_blocked();
+
+    let snapshot = counters.snapshot();
+    assert_eq!(snapshot.messages_received, 2);
+    assert_eq!(snapshot.messages_sent, 2);
+    assert_eq!(snapshot.send_failures, 1);
+    assert_eq!(snapshot.reconnects, 1);
+    assert_eq!(snapshot.mean_send_latency, Some(Duration::from_millis(20)));
+    assert_eq!(snapshot.last_round_trip, Some(Duration::from_millis(20)));
+    assert_eq!(snapshot.mean_round_trip, Some(Duration::from_millis(30)));
+    assert_eq!(snapshot.commands_blocked, 1);
+}
+
+#[test]
+fn test_metrics_registry_shares_counters_per_service() {
+    let registry = MetricsRegistry::default();
+    let matrix = ServiceId("matrix".to_string());
+    let mumble = ServiceId("mumble".to_string());
+
+    registry.for_service(&matrix).message_received();
+    registry.for_service(&matrix).message_received();
+    registry.for_service(&mumble).reconnected();
+
+    let snapshot = registry.snapshot();
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[&matrix].messages_received, 2);
+    assert_eq!(snapshot[&matrix].reconnects, 0);
+    assert_eq!(snapshot[&mumble].reconnects, 1);
+}
+
+#[test]
+fn test_middleware_latency_histogram_buckets() {
+    let latency = MiddlewareLatency::default();
+    latency.record(Duration::from_micros(200), false);
+    latency.record(Duration::from_millis(50), false);
+    latency.record(Duration::from_millis(80), true);
+    latency.record(Duration::from_secs(3), true);
+
+    let snapshot = latency.snapshot();
+    assert_eq!(snapshot.calls, 4);
+    assert_eq!(snapshot.over_budget, 2);
+    assert_eq!(snapshot.max, Duration::from_secs(3));
+    // Bounds are inclusive; anything past the last bound lands in the overflow bucket
+    assert_eq!(snapshot.buckets[0], 1);
+    assert_eq!(snapshot.buckets[4], 1);
+    assert_eq!(snapshot.buckets[5], 1);
+    assert_eq!(snapshot.buckets[LATENCY_BUCKETS.len()], 1);
+    assert_eq!(snapshot.buckets.iter().sum::<u64>(), 4);
+}
+
+#[test]
+fn test_metrics_registry_shares_latency_per_middleware() {
+    let registry = MetricsRegistry::default();
+    registry.for_middleware("echo").record(Duration::from_millis(1), false);
+    registry.for_middleware("echo").record(Duration::from_millis(2), false);
+
+    let snapshot = registry.middleware_snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot["echo"].calls, 2);
+}
+
+#[test]
+fn test_event_lag_percentiles() {
+    let lag = EventLag::default();
+    assert_eq!(lag.snapshot().p50(), None);
+
+    for _ in 0..98 {
+        lag.record(Duration::from_millis(3));
+    }
+    lag.record(Duration::from_millis(200));
+    lag.record(Duration::from_millis(700));
+
+    let snapshot = lag.snapshot();
+    assert_eq!(snapshot.events, 100);
+    // Reported as the bound of the bucket the percentile falls in
+    assert_eq!(snapshot.p50(), Some(Duration::from_millis(5)));
+    assert_eq!(snapshot.p99(), Some(Duration::from_millis(250)));
+    // ...but never more than the largest lag seen
+    assert_eq!(snapshot.percentile(1.0), Some(Duration::from_millis(700)));
+}
+
+#[test]
+fn test_metrics_registry_keeps_lag_per_stage() {
+    let registry = MetricsRegistry::default();
+    registry.for_lag_stage(LagStage::Queued).record(Duration::from_millis(1));
+    registry.for_lag_stage(LagStage::Processed).record(Duration::from_millis(4));
+    registry.for_lag_stage(LagStage::Processed).record(Duration::from_millis(6));
+
+    let snapshot = registry.lag_snapshot();
+    assert_eq!(snapshot[&LagStage::Queued].events, 1);
+    assert_eq!(snapshot[&LagStage::Processed].events, 2);
+    assert_eq!(snapshot[&LagStage::Processed].max, Duration::from_millis(6));
+}
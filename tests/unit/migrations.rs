@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    migrations::{Migration, SchemaTooNew, migrate, schema_version},
+    outbox::Outbox,
+};
+use rusqlite::Connection;
+use tempfile::TempDir;
+
+const NOTES_V1: Migration = Migration {
+    description: "create the notes table",
+    sql: "CREATE TABLE IF NOT EXISTS notes (id INTEGER PRIMARY KEY, body TEXT NOT NULL);",
+};
+
+const NOTES_V2: Migration = Migration {
+    description: "add the pinned column",
+    sql: "ALTER TABLE notes ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;",
+};
+
+fn note_count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn test_migrate_brings_a_new_database_to_the_latest_version() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn, "notes", &[NOTES_V1, NOTES_V2]).unwrap();
+
+    assert_eq!(schema_version(&conn).unwrap(), 2);
+    conn.execute("INSERT INTO notes (body, pinned) VALUES ('hi', 1)", []).unwrap();
+}
+
+#[test]
+fn test_migrate_only_runs_migrations_added_since_the_last_upgrade() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn, "notes", &[NOTES_V1]).unwrap();
+    conn.execute("INSERT INTO notes (body) VALUES ('kept')", []).unwrap();
+
+    migrate(&mut conn, "notes", &[NOTES_V1, NOTES_V2]).unwrap();
+    // Running again must not repeat the ALTER TABLE, which would fail
+    migrate(&mut conn, "notes", &[NOTES_V1, NOTES_V2]).unwrap();
+
+    assert_eq!(schema_version(&conn).unwrap(), 2);
+    let pinned: i64 = conn
+        .query_row("SELECT pinned FROM notes WHERE body = 'kept'", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(pinned, 0);
+}
+
+#[test]
+fn test_migrate_adopts_a_database_created_before_versioning() {
+    // Databases from before migrations have their tables but a user_version of 0
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(NOTES_V1.sql).unwrap();
+    conn.execute("INSERT INTO notes (body) VALUES ('old')", []).unwrap();
+
+    migrate(&mut conn, "notes", &[NOTES_V1, NOTES_V2]).unwrap();
+
+    assert_eq!(schema_version(&conn).unwrap(), 2);
+    assert_eq!(note_count(&conn), 1);
+}
+
+#[test]
+fn test_migrate_refuses_a_database_from_a_newer_version() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn, "notes", &[NOTES_V1, NOTES_V2]).unwrap();
+
+    let error = migrate(&mut conn, "notes", &[NOTES_V1]).unwrap_err();
+    let too_new = error.downcast_ref::<SchemaTooNew>().expect("should report SchemaTooNew");
+    assert_eq!(too_new.database, "notes");
+    assert_eq!(too_new.version, 2);
+    assert_eq!(too_new.supported, 1);
+    assert!(error.to_string().contains("backup"));
+    assert_eq!(schema_version(&conn).unwrap(), 2);
+}
+
+#[test]
+fn test_migrate_rolls_back_a_failed_migration() {
+    let broken = Migration {
+        description: "add a column to a missing table",
+        sql: "ALTER TABLE notes ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+              ALTER TABLE missing ADD COLUMN archived INTEGER;",
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn, "notes", &[NOTES_V1]).unwrap();
+
+    let error = migrate(&mut conn, "notes", &[NOTES_V1, broken]).unwrap_err();
+
+    assert!(error.to_string().contains("schema version 2"));
+    assert_eq!(schema_version(&conn).unwrap(), 1);
+    // The half of the migration that succeeded was rolled back with the rest
+    assert!(conn.prepare("SELECT archived FROM notes").is_err());
+}
+
+#[test]
+fn test_store_refuses_to_open_a_database_from_a_newer_version() {
+    let data_directory = TempDir::new().unwrap();
+    let path = data_directory.path().join("outbox.sqlite3");
+    drop(Outbox::open(&path, Duration::from_secs(60)).unwrap());
+
+    let conn = Connection::open(&path).unwrap();
+    conn.pragma_update(None, "user_version", 99).unwrap();
+    drop(conn);
+
+    let error = Outbox::open(&path, Duration::from_secs(60)).err().expect("should refuse to open");
+    assert!(error.downcast_ref::<SchemaTooNew>().is_some());
+}
@@ -1,6 +1,38 @@
+pub mod alerts;
+pub mod audit;
+pub mod backup;
+pub mod bridge_map;
 pub mod bus;
+pub mod commands;
 pub mod config;
+pub mod connection_schedule;
+pub mod conversation;
+pub mod coordination;
+pub mod correlation;
+pub mod error_reporting;
 pub mod event;
+pub mod format;
+pub mod http;
+pub mod idempotency;
+pub mod logging;
+pub mod loop_detection;
+pub mod media;
+pub mod metrics;
 pub mod middleware;
+pub mod migrations;
+pub mod outbox;
+pub mod paste;
+pub mod preferences;
+pub mod quiet_hours;
+pub mod redact;
+pub mod replay;
+pub mod room_settings;
+pub mod room_state;
+pub mod roster;
+pub mod scheduler;
 pub mod service;
+pub mod subscriptions;
+pub mod testing;
 pub mod thread_reply;
+pub mod time_zone;
+pub mod user_list;
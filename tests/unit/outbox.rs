@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    bus::Command,
+    format::BodyFormat,
+    outbox::{Outbox, QueuedCommand},
+    service::ServiceId,
+};
+
+fn room_message(body: &str) -> QueuedCommand {
+    QueuedCommand::RoomMessage {
+        room_id: "!room:example.com".to_string(),
+        body: body.to_string(),
+        format: BodyFormat::Plain,
+        idempotency_key: None,
+        relayed_from: None,
+    }
+}
+
+#[test]
+fn test_queued_command_round_trips_fire_and_forget_commands() {
+    let command = Command::SendRoomMessage {
+        service_id: ServiceId("matrix".to_string()),
+        room_id: "!room:example.com".to_string(),
+        body: "**hello**".to_string(),
+        format: BodyFormat::Markdown,
+        response_tx: None,
+        origin: None,
+        idempotency_key: Some("relay-1".to_string()),
+        relayed_from: None,
+        expires_after: None,
+    };
+
+    let queued = QueuedCommand::from_command(&command).expect("room message should be queueable");
+    match queued.into_command(ServiceId("matrix".to_string())) {
+        Command::SendRoomMessage {
+            service_id,
+            room_id,
+            body,
+            format,
+            response_tx,
+            idempotency_key,
+            ..
+        } => {
+            assert_eq!(service_id.0, "matrix");
+            assert_eq!(room_id, "!room:example.com");
+            assert_eq!(body, "**hello**");
+            assert_eq!(format, BodyFormat::Markdown);
+            assert!(response_tx.is_none());
+            assert_eq!(idempotency_key.as_deref(), Some("relay-1"));
+        }
+        other => panic!("Expected SendRoomMessage, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_queued_command_skips_commands_awaiting_a_response() {
+    let (response_tx, _response_rx) = tokio::sync::oneshot::channel();
+    let command = Command::SendRoomMessage {
+        service_id: ServiceId("matrix".to_string()),
+        room_id: "!room:example.com".to_string(),
+        body: "hello".to_string(),
+        format: BodyFormat::Plain,
+        response_tx: Some(response_tx),
+        origin: None,
+        idempotency_key: None,
+        relayed_from: None,
+        expires_after: None,
+    };
+
+    assert!(QueuedCommand::from_command(&command).is_none());
+}
+
+#[test]
+fn test_queued_command_reads_entries_queued_without_a_format() {
+    let queued: QueuedCommand = serde_json::from_str(
+        r#"{"kind": "room_message", "room_id": "!room:example.com", "body": "hi", "markdown_body": null}"#,
+    )
+    .unwrap();
+
+    assert_eq!(queued, room_message("hi"));
+}
+
+#[test]
+fn test_outbox_delivers_in_order_per_service() {
+    let outbox = Outbox::in_memory(Duration::from_secs(3600)).unwrap();
+    let matrix = ServiceId("matrix".to_string());
+    let mumble = ServiceId("mumble".to_string());
+
+    outbox.enqueue(&matrix, &room_message("first")).unwrap();
+    outbox.enqueue(&mumble, &room_message("other")).unwrap();
+    outbox.enqueue(&matrix, &room_message("second")).unwrap();
+
+    assert!(outbox.has_pending(&matrix).unwrap());
+    let mut pending = outbox.pending_services().unwrap();
+    pending.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(pending, vec![matrix.clone(), mumble.clone()]);
+
+    let (first, expired) = outbox.next(&matrix).unwrap();
+    let first = first.expect("expected a queued entry");
+    assert_eq!(expired, 0);
+    assert_eq!(first.command, room_message("first"));
+    outbox.remove(first.id).unwrap();
+
+    let (second, _) = outbox.next(&matrix).unwrap();
+    let second = second.expect("expected a queued entry");
+    assert_eq!(second.command, room_message("second"));
+    outbox.remove(second.id).unwrap();
+
+    assert!(!outbox.has_pending(&matrix).unwrap());
+    assert!(outbox.has_pending(&mumble).unwrap());
+}
+
+#[test]
+fn test_outbox_discards_expired_entries() {
+    let outbox = Outbox::in_memory(Duration::ZERO).unwrap();
+    let matrix = ServiceId("matrix".to_string());
+
+    outbox.enqueue(&matrix, &room_message("stale")).unwrap();
+    // Timestamps have second resolution
+    std::thread::sleep(Duration::from_millis(1100));
+
+    let (entry, expired) = outbox.next(&matrix).unwrap();
+    assert!(entry.is_none());
+    assert_eq!(expired, 1);
+    assert!(!outbox.has_pending(&matrix).unwrap());
+}
+
+#[test]
+fn test_outbox_persists_across_reopen() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("outbox.sqlite3");
+    let matrix = ServiceId("matrix".to_string());
+
+    {
+        let outbox = Outbox::open(&path, Duration::from_secs(3600)).unwrap();
+        outbox.enqueue(&matrix, &room_message("survives restart")).unwrap();
+    }
+
+    let outbox = Outbox::open(&path, Duration::from_secs(3600)).unwrap();
+    let (entry, _) = outbox.next(&matrix).unwrap();
+    assert_eq!(entry.unwrap().command, room_message("survives restart"));
+}
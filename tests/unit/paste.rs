@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use kelvin_bot::core::{
+    config::MediaConfig,
+    media::MediaStore,
+    paste::{Paster, truncate, truncate_with_link},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+/// A fake paste endpoint answering every upload with `https://paste.example.com/abc`, returning
+/// its URL and the bodies it received.
+async fn spawn_paste_server() -> (Url, tokio::sync::mpsc::Receiver<String>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let headers_end = loop {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..headers_end]).to_lowercase();
+            let content_length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(0);
+            while request.len() < headers_end + content_length {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+
+            let response = "https://paste.example.com/abc\n";
+            let response = format!(
+                "HTTP/1.1 201 Created\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                response.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let body = String::from_utf8_lossy(&request[headers_end..]).to_string();
+            let _ = tx.send(body).await;
+        }
+    });
+    (url, rx)
+}
+
+#[test]
+fn test_truncate_marks_the_cut() {
+    assert_eq!(truncate("short", 10), "short");
+    assert_eq!(truncate("far too long", 8), "far too…");
+}
+
+#[test]
+fn test_truncate_with_link_fits_within_limit() {
+    let link: Url = "https://paste.example.com/abc".parse().unwrap();
+    let body = "a".repeat(200);
+
+    let fitted = truncate_with_link(&body, &link, 80);
+    assert_eq!(fitted.chars().count(), 80);
+    assert!(fitted.starts_with("aaaa"));
+    assert!(fitted.ends_with("… (full text: https://paste.example.com/abc)"));
+
+    // Too little room for any of the text leaves just the link
+    assert_eq!(truncate_with_link(&body, &link, 30), "https://paste.example.com/abc");
+}
+
+#[tokio::test]
+async fn test_paster_uploads_long_messages_to_endpoint() {
+    let (url, mut uploads) = spawn_paste_server().await;
+    let paster = Paster::new(Some(url), MediaStore::default());
+    assert!(paster.is_enabled());
+
+    // Messages that already fit aren't uploaded
+    assert_eq!(paster.fit("short".to_string(), Some(100)).await, "short");
+    assert_eq!(paster.fit("a".repeat(500), None).await, "a".repeat(500));
+
+    let body = format!("{}END", "word ".repeat(40));
+    let fitted = paster.fit(body.clone(), Some(60)).await;
+    assert_eq!(fitted.chars().count(), 60);
+    assert!(fitted.ends_with(" (full text: https://paste.example.com/abc)"));
+    assert_eq!(uploads.recv().await.unwrap(), body);
+    assert!(uploads.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_paster_falls_back_to_media_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let media = MediaStore::open(
+        dir.path(),
+        &MediaConfig {
+            ttl: Duration::from_secs(3600),
+            listen_address: Some("127.0.0.1:0".parse().unwrap()),
+            public_url: Some("https://bot.example.com".parse().unwrap()),
+        },
+    )
+    .unwrap();
+    let paster = Paster::new(None, media.clone());
+    assert!(paster.is_enabled());
+
+    let body = "x".repeat(300);
+    let link = paster.upload(&body).await.unwrap();
+    let id = link.as_str().strip_prefix("https://bot.example.com/media/").unwrap();
+    assert!(id.ends_with(".txt"));
+    assert_eq!(std::fs::read_to_string(media.path(id).unwrap()).unwrap(), body);
+}
+
+#[tokio::test]
+async fn test_paster_truncates_without_anywhere_to_upload() {
+    let paster = Paster::default();
+    assert!(!paster.is_enabled());
+    assert_eq!(paster.fit("far too long".to_string(), Some(8)).await, "far too…");
+
+    // An endpoint that can't be reached also falls back to truncation
+    let paster = Paster::new(Some("http://127.0.0.1:1/".parse().unwrap()), MediaStore::default());
+    assert_eq!(paster.fit("far too long".to_string(), Some(8)).await, "far too…");
+}
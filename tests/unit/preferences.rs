@@ -0,0 +1,60 @@
+use kelvin_bot::core::{
+    bus::CommandSender,
+    commands::send_preferred_reply,
+    preferences::{PREFERENCES_STORE_FILE, PreferenceStore, ReplyMode, parse_locale},
+    service::ServiceId,
+};
+use kelvin_bot::testing::{command_capture, room_message};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_preferences_persist_per_user_and_service() {
+    let dir = TempDir::new().unwrap();
+    let matrix = ServiceId("matrix".to_string());
+    let mumble = ServiceId("mumble".to_string());
+
+    let preferences = PreferenceStore::load(dir.path()).unwrap();
+    preferences
+        .update(&matrix, "alice", |prefs| {
+            prefs.timezone = Some("Europe/Berlin".to_string());
+            prefs.opt_outs.insert("movies".to_string());
+        })
+        .await
+        .unwrap();
+    assert!(dir.path().join(PREFERENCES_STORE_FILE).exists());
+
+    let reloaded = PreferenceStore::load(dir.path()).unwrap();
+    let alice = reloaded.get(&matrix, "alice").await;
+    assert_eq!(alice.time_zone(), Some(chrono_tz::Europe::Berlin));
+    assert!(alice.is_opted_out("Movies"));
+    assert_eq!(reloaded.get(&mumble, "alice").await, Default::default());
+}
+
+#[tokio::test]
+async fn test_preferred_reply_follows_reply_mode() {
+    let (cmd_tx, mut capture) = command_capture(10);
+    let cmd_tx = CommandSender::new(cmd_tx, "test");
+    let preferences = PreferenceStore::in_memory();
+    let evt = room_message("matrix", "!room", "@alice:example.com", "!movies");
+
+    send_preferred_reply(&evt, "In the room".to_string(), &cmd_tx, &preferences);
+    let (_, room_id, body) = capture.expect_room_message().await;
+    assert_eq!((room_id.as_str(), body.as_str()), ("!room", "In the room"));
+
+    preferences
+        .update(&evt.service_id, "@alice:example.com", |prefs| prefs.reply_mode = ReplyMode::Dm)
+        .await
+        .unwrap();
+    send_preferred_reply(&evt, "In private".to_string(), &cmd_tx, &preferences);
+    let (_, user_id, body) = capture.expect_direct_message().await;
+    assert_eq!((user_id.as_str(), body.as_str()), ("@alice:example.com", "In private"));
+}
+
+#[test]
+fn test_parse_locale() {
+    assert_eq!(parse_locale("en").unwrap(), "en");
+    assert_eq!(parse_locale(" pt_BR ").unwrap(), "pt-BR");
+    assert!(parse_locale("").is_err());
+    assert!(parse_locale("en--US").is_err());
+    assert!(parse_locale("english please").is_err());
+}
@@ -0,0 +1,89 @@
+use chrono::{NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use kelvin_bot::core::config::{Config, QuietHoursConfig};
+use kelvin_bot::core::quiet_hours::QuietHours;
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+#[test]
+fn test_quiet_hours_wrapping_past_midnight() {
+    let quiet = QuietHours::new(time(22, 0), time(8, 0));
+    assert!(quiet.contains(time(22, 0)));
+    assert!(quiet.contains(time(2, 0)));
+    assert!(!quiet.contains(time(8, 0)));
+    assert!(!quiet.contains(time(12, 0)));
+
+    // Equal start and end means no quiet hours at all
+    let empty = QuietHours::new(time(0, 0), time(0, 0));
+    assert!(!empty.contains(time(0, 0)));
+    assert!(!empty.contains(time(12, 0)));
+}
+
+#[test]
+fn test_quiet_hours_defer_moves_to_end_of_window() {
+    let quiet = QuietHours::new(time(22, 0), time(8, 0));
+
+    let late = Utc.with_ymd_and_hms(2024, 3, 4, 23, 30, 0).unwrap();
+    assert_eq!(quiet.defer(late), Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 0).unwrap());
+
+    let early = Utc.with_ymd_and_hms(2024, 3, 4, 2, 0, 0).unwrap();
+    assert_eq!(quiet.defer(early), Utc.with_ymd_and_hms(2024, 3, 4, 8, 0, 0).unwrap());
+
+    let daytime = Utc.with_ymd_and_hms(2024, 3, 4, 12, 0, 0).unwrap();
+    assert_eq!(quiet.defer(daytime), daytime);
+    assert_eq!(quiet.next_end(&daytime), Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 0).unwrap());
+}
+
+#[test]
+fn test_quiet_hours_config_parsing() {
+    let config: Config = toml::from_str(
+        r#"
+        [services]
+
+        [quiet_hours]
+        start = "22:00"
+        end = "08:00"
+
+        [middlewares.logger]
+        kind = "logger"
+        quiet_hours = { start = "7pm", end = "07:00" }
+        "#,
+    )
+    .unwrap();
+
+    let global = QuietHours::from_config(config.quiet_hours.as_ref().unwrap()).unwrap();
+    assert_eq!(global, QuietHours::new(time(22, 0), time(8, 0)));
+
+    let err = QuietHours::from_config(config.middlewares["logger"].quiet_hours.as_ref().unwrap())
+        .unwrap_err();
+    assert!(err.to_string().contains("7pm"), "unexpected error: {err}");
+
+    let cfg =
+        QuietHoursConfig { start: "23:00".to_string(), end: "25:00".to_string(), timezone: None };
+    assert!(QuietHours::from_config(&cfg).is_err());
+}
+
+#[test]
+fn test_quiet_hours_with_a_timezone_apply_in_that_zone() {
+    let cfg = QuietHoursConfig {
+        start: "22:00".to_string(),
+        end: "08:00".to_string(),
+        timezone: Some("America/New_York".to_string()),
+    };
+    let quiet = QuietHours::from_config(&cfg).unwrap();
+
+    // 22:30 in New York is 03:30 UTC the next day, inside the window wherever it's checked from
+    let late = Utc.with_ymd_and_hms(2024, 3, 5, 3, 30, 0).unwrap();
+    assert!(quiet.is_quiet_at(&late));
+    assert!(quiet.is_quiet_at(&late.with_timezone(&Tz::Asia__Tokyo)));
+    assert_eq!(quiet.defer(late), Utc.with_ymd_and_hms(2024, 3, 5, 13, 0, 0).unwrap());
+
+    // Noon UTC is 07:00 in New York, still quiet there though it's daytime in UTC
+    let noon = Utc.with_ymd_and_hms(2024, 3, 5, 12, 0, 0).unwrap();
+    assert!(quiet.is_quiet_at(&noon));
+
+    let cfg = QuietHoursConfig { timezone: Some("Mars/Olympus".to_string()), ..cfg };
+    assert!(QuietHours::from_config(&cfg).is_err());
+}
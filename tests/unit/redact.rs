@@ -0,0 +1,108 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use kelvin_bot::core::config::Config;
+use kelvin_bot::core::redact::{self, MASK, RedactingMakeWriter, Redactor};
+use regex::Regex;
+use tracing_subscriber::fmt::MakeWriter;
+
+#[test]
+fn test_redactor_masks_secrets_and_patterns() {
+    let redactor = Redactor::new(
+        vec!["hunter22".to_string(), "ab".to_string()],
+        vec![Regex::new(r"password=(\S+)").unwrap(), Regex::new(r"sk-[a-z0-9]+").unwrap()],
+    );
+
+    assert_eq!(redactor.redact("login with hunter22"), format!("login with {MASK}"));
+    // Only the capture group is masked when there is one
+    assert_eq!(redactor.redact("password=swordfish ok"), format!("password={MASK} ok"));
+    assert_eq!(redactor.redact("key sk-abc123"), format!("key {MASK}"));
+    // Secrets too short to be meaningful are ignored
+    assert_eq!(redactor.redact("about"), "about");
+
+    redactor.add_secret("token-1234");
+    assert_eq!(redactor.redact("your token is token-1234"), format!("your token is {MASK}"));
+}
+
+#[test]
+fn test_redactor_from_config_masks_credentials() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.matrix]
+        kind = "matrix"
+        homeserver_url = "https://matrix.example.com"
+        user_id = "@bot:example.com"
+        password = "matrix-password"
+        device_id = "DEVICE"
+        db_passphrase = "store-passphrase"
+
+        [middlewares.streams]
+        kind = "streamannounce"
+        service_id = "matrix"
+        room_id = "!lobby:example.com"
+        twitch_client_id = "twitch-client"
+        twitch_client_secret = "twitch-secret"
+        youtube_api_key = "youtube-api-key"
+
+        [redaction]
+        patterns = ["api_key=(\\w+)"]
+        "#,
+    )
+    .unwrap();
+
+    let redactor = Redactor::from_config(&config).unwrap();
+    let redacted = redactor.redact("matrix-password store-passphrase api_key=abc");
+    assert_eq!(redacted, format!("{MASK} {MASK} api_key={MASK}"));
+    let redacted = redactor.redact("twitch-secret youtube-api-key");
+    assert_eq!(redacted, format!("{MASK} {MASK}"));
+}
+
+#[test]
+fn test_redactor_from_config_rejects_invalid_patterns() {
+    let config: Config = toml::from_str(
+        r#"
+        [services]
+
+        [redaction]
+        patterns = ["password=("]
+        "#,
+    )
+    .unwrap();
+
+    let err = Redactor::from_config(&config).unwrap_err().to_string();
+    assert!(err.contains("password=("), "unexpected error: {err}");
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_redacting_writer_masks_runtime_secrets() {
+    redact::add_secret("reg-token-5678");
+    let buffer = SharedBuffer::default();
+    let make_writer = {
+        let buffer = buffer.clone();
+        RedactingMakeWriter::new(move || buffer.clone())
+    };
+
+    {
+        let mut writer = make_writer.make_writer();
+        write!(writer, "INFO token=reg-token-5678 ").unwrap();
+        writeln!(writer, "generated").unwrap();
+        // Nothing reaches the inner writer until the event is complete
+        assert!(buffer.0.lock().unwrap().is_empty());
+    }
+
+    let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(written, format!("INFO token={MASK} generated\n"));
+}
@@ -0,0 +1,24 @@
+use kelvin_bot::core::{event::EventKind, replay::read_event_log};
+
+#[test]
+fn test_read_event_log_parses_one_event_per_line() {
+    let log = concat!(
+        r#"{"service_id":"chat","kind":{"RoomMessage":{"room_id":"!lobby","body":"hi","is_local_user":false,"sender_id":"@alice","sender_display_name":null,"is_self":false}}}"#,
+        "\n\n",
+        r#"{"service_id":"voice","kind":{"UserListUpdate":{"users":[]}}}"#,
+        "\n",
+    );
+
+    let events = read_event_log(log.as_bytes()).unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].service_id.0, "chat");
+    assert_eq!(events[0].kind.message_body(), Some("hi"));
+    assert!(matches!(events[1].kind, EventKind::UserListUpdate { .. }));
+}
+
+#[test]
+fn test_read_event_log_reports_bad_line_number() {
+    let log = "{\"service_id\":\"chat\",\"kind\":{\"UserListUpdate\":{\"users\":[]}}}\nnot json\n";
+    let err = read_event_log(log.as_bytes()).unwrap_err();
+    assert!(err.to_string().contains("line 2"), "unexpected error: {err}");
+}
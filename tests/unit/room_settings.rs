@@ -0,0 +1,49 @@
+use kelvin_bot::core::{
+    quiet_hours::QuietHours,
+    room_settings::{
+        ROOM_SETTINGS_STORE_FILE, RoomSettingsStore, parse_setting, parse_setting_name,
+    },
+    service::ServiceId,
+};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_room_settings_persist_per_room_and_read_typed() {
+    let dir = TempDir::new().unwrap();
+    let matrix = ServiceId("matrix".to_string());
+    let mumble = ServiceId("mumble".to_string());
+
+    let settings = RoomSettingsStore::load(dir.path()).unwrap();
+    settings.set(&matrix, "!lobby", "Relay.Digest", Some("true")).await.unwrap();
+    settings.set(&matrix, "!lobby", "quiet_hours", Some("22:00-07:00")).await.unwrap();
+    settings.set(&matrix, "!lobby", "faq.limit", Some("5")).await.unwrap();
+    assert!(dir.path().join(ROOM_SETTINGS_STORE_FILE).exists());
+
+    let reloaded = RoomSettingsStore::load(dir.path()).unwrap();
+    assert_eq!(reloaded.flag(&matrix, "!lobby", "relay.digest").await, Some(true));
+    assert_eq!(
+        reloaded.get::<QuietHours>(&matrix, "!lobby", "quiet_hours").await.map(|q| q.to_string()),
+        Some("22:00-07:00".to_string())
+    );
+    assert_eq!(reloaded.get::<usize>(&matrix, "!lobby", "faq.limit").await, Some(5));
+    // A value of the wrong type reads as unset
+    assert_eq!(reloaded.flag(&matrix, "!lobby", "faq.limit").await, None);
+    assert_eq!(reloaded.flag(&mumble, "!lobby", "relay.digest").await, None);
+
+    let remaining = reloaded.set(&matrix, "!lobby", "relay.digest", None).await.unwrap();
+    assert_eq!(remaining.keys().collect::<Vec<_>>(), ["faq.limit", "quiet_hours"]);
+}
+
+#[test]
+fn test_parse_setting_normalizes_values() {
+    assert_eq!(parse_setting("relay.digest", "Yes").unwrap(), "on");
+    assert_eq!(parse_setting("relay.digest", " off ").unwrap(), "off");
+    assert_eq!(parse_setting("quiet_hours", "22:00 - 07:30").unwrap(), "22:00-07:30");
+    assert_eq!(parse_setting("greeting", "Welcome!").unwrap(), "Welcome!");
+    assert!(parse_setting("quiet_hours", "late").is_err());
+    assert!(parse_setting("greeting", " ").is_err());
+
+    assert_eq!(parse_setting_name("Relay.Digest").unwrap(), "relay.digest");
+    assert!(parse_setting_name("relay..digest").is_err());
+    assert!(parse_setting_name("relay digest").is_err());
+}
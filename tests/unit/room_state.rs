@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use kelvin_bot::core::{
+    bus::{CommandSender, create_command_channel, create_event_channel},
+    metrics::NoopMetrics,
+    room_state::{check_event_type, get_room_state, set_room_state},
+    service::{Service, ServiceId},
+};
+use kelvin_bot::services::loopback::{LoopbackService, LoopbackSettings};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct LiveMessage {
+    event_id: String,
+}
+
+/// A command sender whose commands are handled by a loopback service.
+fn loopback_sender() -> CommandSender {
+    let (evt_tx, _evt_rx) = create_event_channel(10);
+    let service = LoopbackService::new(
+        ServiceId("loop".to_string()),
+        evt_tx,
+        Arc::new(NoopMetrics),
+        LoopbackSettings::default(),
+    );
+    let (cmd_tx, mut cmd_rx) = create_command_channel(10);
+    tokio::spawn(async move {
+        while let Some(command) = cmd_rx.recv().await {
+            service.handle_command(command).await.unwrap();
+        }
+    });
+    CommandSender::new(cmd_tx, "test")
+}
+
+#[test]
+fn test_check_event_type_requires_the_bot_namespace() {
+    assert!(check_event_type("org.kelvinbot.attendance").is_ok());
+    assert!(check_event_type("m.room.name").is_err());
+    assert!(check_event_type("org.kelvinbot.").is_err());
+}
+
+#[tokio::test]
+async fn test_room_state_round_trips() {
+    let cmd_tx = loopback_sender();
+    let service_id = ServiceId("loop".to_string());
+    let live = LiveMessage { event_id: "$abc".to_string() };
+
+    set_room_state(&cmd_tx, &service_id, "lobby", "org.kelvinbot.attendance", "", &live)
+        .await
+        .unwrap();
+
+    let read: Option<LiveMessage> =
+        get_room_state(&cmd_tx, &service_id, "lobby", "org.kelvinbot.attendance", "")
+            .await
+            .unwrap();
+    assert_eq!(read, Some(live));
+    let other_room: Option<LiveMessage> =
+        get_room_state(&cmd_tx, &service_id, "hall", "org.kelvinbot.attendance", "").await.unwrap();
+    assert_eq!(other_room, None);
+}
+
+#[tokio::test]
+async fn test_room_state_rejects_foreign_event_types() {
+    let (cmd_tx, mut cmd_rx) = create_command_channel(10);
+    let cmd_tx = CommandSender::new(cmd_tx, "test");
+
+    let result = get_room_state::<LiveMessage>(
+        &cmd_tx,
+        &ServiceId("loop".to_string()),
+        "lobby",
+        "m.room.power_levels",
+        "",
+    )
+    .await;
+
+    assert!(result.is_err());
+    assert!(cmd_rx.try_recv().is_err());
+}
@@ -0,0 +1,137 @@
+use kelvin_bot::core::{
+    event::{Event, EventKind, User},
+    roster::{KnownUser, Roster},
+    service::ServiceId,
+};
+use kelvin_bot::testing::{direct_message, room_message};
+
+fn user(id: &str, display_name: &str, is_active: bool) -> User {
+    User {
+        id: id.to_string(),
+        username: id.to_string(),
+        display_name: display_name.to_string(),
+        is_active,
+        is_self: false,
+        comment: None,
+        avatar: None,
+    }
+}
+
+#[test]
+fn test_roster_tracks_rooms_members_and_display_names() {
+    let roster = Roster::tracking();
+    let matrix = ServiceId("matrix".to_string());
+
+    roster.record(&room_message("matrix", "!lobby", "@alice", "hi"));
+    roster.record(&room_message("matrix", "!games", "@bob", "hi"));
+    roster.record(&Event {
+        service_id: matrix.clone(),
+        kind: EventKind::RoomMessage {
+            room_id: "!lobby".to_string(),
+            body: "hello".to_string(),
+            is_local_user: true,
+            sender_id: "@bob".to_string(),
+            sender_display_name: Some("Bob".to_string()),
+            is_self: false,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
+        },
+    });
+    roster.record(&direct_message("matrix", "@carol", "psst"));
+
+    assert_eq!(roster.rooms(&matrix), vec!["!games".to_string(), "!lobby".to_string()]);
+    let lobby: Vec<String> =
+        roster.members(&matrix, "!lobby").into_iter().map(|user| user.id).collect();
+    assert_eq!(lobby, vec!["@alice".to_string(), "@bob".to_string()]);
+    assert_eq!(roster.display_name(&matrix, "@bob").as_deref(), Some("Bob"));
+    assert!(roster.user(&matrix, "@carol").is_some());
+    assert!(roster.rooms(&ServiceId("mumble".to_string())).is_empty());
+}
+
+#[test]
+fn test_roster_tracks_when_members_were_last_active() {
+    let roster = Roster::tracking();
+    let matrix = ServiceId("matrix".to_string());
+
+    roster.record(&room_message("matrix", "!lobby", "@alice", "hi"));
+    roster.record(&room_message("matrix", "!lobby", "@bob", "hi"));
+    let before = roster.last_active(&matrix, "!lobby");
+    roster.record(&room_message("matrix", "!lobby", "@alice", "still here"));
+    let after = roster.last_active(&matrix, "!lobby");
+
+    let ids: Vec<&str> = after.iter().map(|(id, _)| id.as_str()).collect();
+    assert_eq!(ids, ["@alice", "@bob"]);
+    assert!(after[0].1 >= before[0].1);
+    assert_eq!(after[1], before[1]);
+    assert!(roster.last_active(&matrix, "!games").is_empty());
+    assert!(Roster::default().last_active(&matrix, "!lobby").is_empty());
+}
+
+#[test]
+fn test_roster_follows_user_list_updates() {
+    let roster = Roster::tracking();
+    let mumble = ServiceId("mumble".to_string());
+    let update = |users: Vec<User>| Event {
+        service_id: mumble.clone(),
+        kind: EventKind::UserListUpdate { users },
+    };
+
+    roster.record(&update(vec![user("1", "Alice", true), user("2", "Bob", true)]));
+    roster.record(&update(vec![user("2", "Bobby", true)]));
+
+    assert_eq!(
+        roster.active_users(&mumble),
+        vec![KnownUser {
+            id: "2".to_string(),
+            display_name: Some("Bobby".to_string()),
+            is_active: true
+        }]
+    );
+    assert_eq!(roster.user(&mumble, "1").map(|user| user.is_active), Some(false));
+}
+
+#[test]
+fn test_roster_records_profile_changes() {
+    let roster = Roster::tracking();
+    let mumble = ServiceId("mumble".to_string());
+    let changed = |user: User| Event {
+        service_id: mumble.clone(),
+        kind: EventKind::UserProfileChanged { user },
+    };
+
+    roster.record(&changed(user("1", "Alice", true)));
+    roster.record(&changed(user("1", "Alicia", true)));
+    roster.record(&changed(User { is_self: true, ..user("2", "Kelvin", true) }));
+
+    assert_eq!(roster.display_name(&mumble, "1").as_deref(), Some("Alicia"));
+    assert!(roster.user(&mumble, "2").is_none());
+}
+
+#[test]
+fn test_roster_ignores_events_unless_tracking_and_own_messages() {
+    let disabled = Roster::default();
+    disabled.record(&room_message("matrix", "!lobby", "@alice", "hi"));
+    assert!(!disabled.is_enabled());
+    assert!(disabled.rooms(&ServiceId("matrix".to_string())).is_empty());
+
+    let roster = Roster::tracking();
+    let matrix = ServiceId("matrix".to_string());
+    roster.record(&Event {
+        service_id: matrix.clone(),
+        kind: EventKind::RoomMessage {
+            room_id: "!lobby".to_string(),
+            body: "I'm the bot".to_string(),
+            is_local_user: true,
+            sender_id: "@kelvin".to_string(),
+            sender_display_name: None,
+            is_self: true,
+            mentions_self: false,
+            relayed_from: None,
+            thread_root_id: None,
+            message_id: None,
+        },
+    });
+    assert!(roster.user(&matrix, "@kelvin").is_none());
+}
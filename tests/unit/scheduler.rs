@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use kelvin_bot::core::{
+    scheduler::{Scheduler, SchedulerHandle},
+    time_zone::next_weekly,
+};
+
+// Each test gets its own fake wall clock, since tests run in parallel
+static SET_BACK_CLOCK: AtomicI64 = AtomicI64::new(0);
+static JUMPING_CLOCK: AtomicI64 = AtomicI64::new(0);
+static HANDLE_CLOCK: AtomicI64 = AtomicI64::new(0);
+
+fn set_back_clock() -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(SET_BACK_CLOCK.load(Ordering::SeqCst)).unwrap()
+}
+
+fn jumping_clock() -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(JUMPING_CLOCK.load(Ordering::SeqCst)).unwrap()
+}
+
+fn handle_clock() -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(HANDLE_CLOCK.load(Ordering::SeqCst)).unwrap()
+}
+
+#[tokio::test]
+async fn test_scheduler_does_not_fire_twice_when_the_clock_is_set_back() {
+    let six_pm = Utc.with_ymd_and_hms(2024, 6, 7, 22, 0, 0).unwrap();
+    let seven_pm = Utc.with_ymd_and_hms(2024, 6, 7, 23, 0, 0).unwrap();
+    SET_BACK_CLOCK.store(six_pm.timestamp_millis(), Ordering::SeqCst);
+    let mut scheduler = Scheduler::new(Tz::America__New_York).with_clock(set_back_clock);
+    let post_at = NaiveTime::from_hms_opt(19, 0, 0).unwrap();
+
+    let next = next_weekly(&scheduler.now(), Weekday::Fri, post_at);
+    assert_eq!(next, seven_pm);
+    SET_BACK_CLOCK.store(seven_pm.timestamp_millis(), Ordering::SeqCst);
+    scheduler.wait_until(&next).await;
+
+    // The clock is stepped back an hour after the post, but the same post doesn't come round
+    SET_BACK_CLOCK.store(six_pm.timestamp_millis(), Ordering::SeqCst);
+    assert_eq!(scheduler.now(), seven_pm);
+    let next = next_weekly(&scheduler.now(), Weekday::Fri, post_at);
+    assert_eq!(next, Utc.with_ymd_and_hms(2024, 6, 14, 23, 0, 0).unwrap());
+}
+
+#[tokio::test]
+async fn test_scheduler_fires_soon_after_the_clock_jumps_past_the_time() {
+    let start = Utc.with_ymd_and_hms(2024, 6, 7, 12, 0, 0).unwrap();
+    JUMPING_CLOCK.store(start.timestamp_millis(), Ordering::SeqCst);
+    let mut scheduler = Scheduler::new(Tz::UTC)
+        .with_clock(jumping_clock)
+        .with_recheck_interval(Duration::from_millis(20));
+    let at = start + chrono::Duration::hours(6);
+
+    let wait = tokio::spawn(async move { scheduler.wait_until(&at).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!wait.is_finished());
+
+    // NTP steps the clock forward past the scheduled time
+    JUMPING_CLOCK.store((at + chrono::Duration::minutes(1)).timestamp_millis(), Ordering::SeqCst);
+    tokio::time::timeout(Duration::from_secs(1), wait)
+        .await
+        .expect("should fire once the clock is past the time")
+        .unwrap();
+}
+
+#[test]
+fn test_scheduler_handle_makes_schedulers_on_its_clock() {
+    let noon = Utc.with_ymd_and_hms(2024, 6, 7, 12, 0, 0).unwrap();
+    HANDLE_CLOCK.store(noon.timestamp_millis(), Ordering::SeqCst);
+    let handle = SchedulerHandle::default().with_clock(handle_clock);
+
+    assert_eq!(handle.scheduler(Tz::Europe__London).now(), noon);
+    assert_eq!(handle.scheduler(Tz::UTC).now(), noon);
+}
@@ -1,8 +1,19 @@
 use assert_matches::assert_matches;
+use kelvin_bot::core::bus::{Command, create_event_channel};
+use kelvin_bot::core::commands::{ArgSpec, CommandRouter};
+use kelvin_bot::core::config::Config;
 use kelvin_bot::core::event::EventKind;
-use kelvin_bot::core::service::{Service, ServiceId};
+use kelvin_bot::core::format::BodyFormat;
+use kelvin_bot::core::metrics::{MetricsRegistry, NoopMetrics, ServiceMetricsSnapshot};
+use kelvin_bot::core::service::{
+    Service, ServiceDirectory, ServiceId, instantiate_services_from_config,
+    validate_service_instances,
+};
 use kelvin_bot::services::dummy::DummyService;
-use tokio::sync::mpsc;
+use kelvin_bot::services::loopback::{LoopbackService, LoopbackSettings};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio_test::assert_ok;
 use tokio_util::sync::CancellationToken;
 
@@ -21,10 +32,15 @@ fn test_service_id_clone_and_eq() {
 
 #[tokio::test]
 async fn test_dummy_service_creation() {
-    let (evt_tx, _evt_rx) = mpsc::channel(10);
+    let (evt_tx, _evt_rx) = create_event_channel(10);
     let service_id = ServiceId("test_dummy".to_string());
 
-    let dummy_service = DummyService { id: service_id.clone(), interval_ms: 100, evt_tx };
+    let dummy_service = DummyService {
+        id: service_id.clone(),
+        interval_ms: 100,
+        evt_tx,
+        metrics: Arc::new(NoopMetrics),
+    };
 
     assert_eq!(dummy_service.id, service_id);
     assert_eq!(dummy_service.interval_ms, 100);
@@ -32,13 +48,14 @@ async fn test_dummy_service_creation() {
 
 #[tokio::test]
 async fn test_dummy_service_sends_events() {
-    let (evt_tx, mut evt_rx) = mpsc::channel(10);
+    let (evt_tx, mut evt_rx) = create_event_channel(10);
     let service_id = ServiceId("test_dummy".to_string());
 
     let dummy_service = DummyService {
         id: service_id.clone(),
         interval_ms: 50, // Short interval for fast test
         evt_tx,
+        metrics: Arc::new(NoopMetrics),
     };
 
     let cancel_token = CancellationToken::new();
@@ -66,3 +83,418 @@ async fn test_dummy_service_sends_events() {
     cancel_token.cancel();
     assert_ok!(service_handle.await.unwrap());
 }
+
+#[tokio::test]
+async fn test_dummy_service_reports_metrics() {
+    let (evt_tx, mut evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_dummy".to_string());
+    let registry = MetricsRegistry::default();
+
+    let dummy_service = Arc::new(DummyService {
+        id: service_id.clone(),
+        interval_ms: 50,
+        evt_tx,
+        metrics: registry.for_service(&service_id),
+    });
+
+    let cancel_token = CancellationToken::new();
+    let service_handle = {
+        let cancel = cancel_token.clone();
+        let service = dummy_service.clone();
+        tokio::spawn(async move { service.run(cancel).await })
+    };
+
+    tokio::time::timeout(std::time::Duration::from_millis(200), evt_rx.recv())
+        .await
+        .expect("Timeout waiting for event")
+        .expect("Channel closed");
+    cancel_token.cancel();
+    assert_ok!(service_handle.await.unwrap());
+
+    assert_ok!(
+        dummy_service
+            .handle_command(Command::SendRoomMessage {
+                service_id: service_id.clone(),
+                room_id: "1".to_string(),
+                body: "hello".to_string(),
+                format: BodyFormat::Plain,
+                response_tx: None,
+                origin: None,
+                idempotency_key: None,
+                relayed_from: None,
+                expires_after: None,
+            })
+            .await
+    );
+
+    let snapshot = registry.snapshot()[&service_id];
+    assert!(snapshot.messages_received >= 1);
+    assert_eq!(snapshot.messages_sent, 1);
+    assert_eq!(snapshot.send_failures, 0);
+    assert_eq!(snapshot.reconnects, 0);
+    assert_ne!(snapshot, ServiceMetricsSnapshot::default());
+}
+
+#[test]
+fn test_service_directory_reports_capabilities() {
+    let (evt_tx, _evt_rx) = create_event_channel(10);
+    let service_id = ServiceId("test_dummy".to_string());
+    let dummy_service = DummyService {
+        id: service_id.clone(),
+        interval_ms: 100,
+        evt_tx,
+        metrics: Arc::new(NoopMetrics),
+    };
+
+    let mut services: HashMap<ServiceId, Arc<dyn Service>> = HashMap::new();
+    services.insert(service_id.clone(), Arc::new(dummy_service));
+    let directory = ServiceDirectory::from_services(&services);
+
+    let caps = directory.capabilities(&service_id).expect("dummy service should be listed");
+    assert!(caps.supports_edit);
+    assert!(directory.supports(&service_id, |c| c.supports_attachments));
+}
+
+#[test]
+fn test_service_directory_assumes_support_for_unknown_services() {
+    let directory = ServiceDirectory::default();
+    let unknown = ServiceId("unknown".to_string());
+
+    assert!(directory.capabilities(&unknown).is_none());
+    assert!(directory.supports(&unknown, |c| c.supports_edit));
+}
+
+fn matrix_services_config(services: &[(&str, &str, Option<&str>)]) -> Config {
+    let mut config_str = String::from("data_directory = \"/tmp/kelvin\"\n");
+    for (id, device_id, store_subdir) in services {
+        config_str.push_str(&format!(
+            r#"
+            [services.{id}]
+            kind = "matrix"
+            homeserver_url = "https://matrix.example.com"
+            user_id = "@bot:example.com"
+            password = "secret"
+            device_id = "{device_id}"
+            db_passphrase = "passphrase"
+            "#
+        ));
+        if let Some(subdir) = store_subdir {
+            config_str.push_str(&format!("store_subdir = \"{subdir}\"\n"));
+        }
+    }
+    toml::from_str(&config_str).expect("Failed to parse config")
+}
+
+#[test]
+fn test_validate_allows_distinct_matrix_instances_of_one_account() {
+    let config =
+        matrix_services_config(&[("matrix_a", "DEVICEA", None), ("matrix_b", "DEVICEB", None)]);
+    assert_ok!(validate_service_instances(&config));
+}
+
+#[test]
+fn test_validate_rejects_shared_matrix_device() {
+    let config =
+        matrix_services_config(&[("matrix_a", "DEVICE", None), ("matrix_b", "DEVICE", None)]);
+    let err = validate_service_instances(&config).unwrap_err().to_string();
+    assert!(err.contains("device_id"), "unexpected error: {err}");
+}
+
+#[test]
+fn test_validate_rejects_conflicting_store_subdirs() {
+    let config = matrix_services_config(&[
+        ("matrix_a", "DEVICEA", Some("shared")),
+        ("matrix_b", "DEVICEB", Some("shared")),
+    ]);
+    let err = validate_service_instances(&config).unwrap_err().to_string();
+    assert!(err.contains("store_subdir"), "unexpected error: {err}");
+
+    // A subdir that collides with another service's default store is a conflict too
+    let config = matrix_services_config(&[
+        ("matrix_a", "DEVICEA", None),
+        ("matrix_b", "DEVICEB", Some("matrix_a")),
+    ]);
+    assert!(validate_service_instances(&config).is_err());
+}
+
+#[test]
+fn test_validate_rejects_store_subdir_outside_matrix_directory() {
+    for subdir in ["..", "nested/dir", "/abs"] {
+        let config = matrix_services_config(&[("matrix_a", "DEVICEA", Some(subdir))]);
+        assert!(validate_service_instances(&config).is_err(), "accepted store_subdir {subdir}");
+    }
+}
+
+#[tokio::test]
+async fn test_loopback_service_rejects_a_zero_poll_interval() {
+    let config: Config = toml::from_str(
+        r#"
+        [services.loopback]
+        kind = "loopback"
+        poll_interval = "0s"
+        "#,
+    )
+    .expect("Failed to parse config");
+    let (evt_tx, _evt_rx) = create_event_channel(10);
+    let Err(err) =
+        instantiate_services_from_config(&config, &evt_tx, &MetricsRegistry::default()).await
+    else {
+        panic!("accepted a zero poll_interval");
+    };
+    assert!(err.to_string().contains("poll_interval must be greater than zero"), "{err}");
+}
+
+#[tokio::test]
+async fn test_loopback_service_injects_file_lines_and_records_commands() {
+    let dir = tempfile::tempdir().unwrap();
+    let inject_file = dir.path().join("inject.jsonl");
+    let transcript_file = dir.path().join("transcript.jsonl");
+    let (evt_tx, mut evt_rx) = create_event_channel(10);
+    let service = Arc::new(LoopbackService::new(
+        ServiceId("loop".to_string()),
+        evt_tx,
+        Arc::new(NoopMetrics),
+        LoopbackSettings {
+            users: vec!["alice".to_string()],
+            rooms: vec!["lobby".to_string()],
+            inject_file: Some(inject_file.clone()),
+            transcript_file: Some(transcript_file.clone()),
+            poll_interval: Duration::from_millis(10),
+        },
+    ));
+
+    let cancel = CancellationToken::new();
+    let handle = {
+        let (service, cancel) = (service.clone(), cancel.clone());
+        tokio::spawn(async move { service.run(cancel).await })
+    };
+    let mut next_event = async || {
+        tokio::time::timeout(Duration::from_secs(1), evt_rx.recv()).await.unwrap().unwrap()
+    };
+
+    // The canned users are announced up front
+    let event = next_event().await;
+    assert_matches!(&event.kind, EventKind::UserListUpdate { users } if users.len() == 1);
+
+    // A partially written line waits for its newline
+    std::fs::write(
+        &inject_file,
+        concat!(
+            r#"{"type": "room_message", "sender": "alice", "body": "hello"}"#,
+            "\n",
+            r#"{"type": "join", "user": "bob"}"#,
+            "\n",
+            r#"{"type": "leave", "user": "ali"#,
+        ),
+    )
+    .unwrap();
+
+    let event = next_event().await;
+    assert_matches!(
+        &event.kind,
+        EventKind::RoomMessage { room_id, sender_id, body, .. }
+            if room_id == "lobby" && sender_id == "alice" && body == "hello"
+    );
+    let event = next_event().await;
+    assert_matches!(
+        &event.kind,
+        EventKind::UserListUpdate { users }
+            if users.iter().map(|u| u.id.as_str()).collect::<Vec<_>>() == ["alice", "bob"]
+    );
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    service
+        .handle_command(Command::SendRoomMessage {
+            service_id: ServiceId("loop".to_string()),
+            room_id: "lobby".to_string(),
+            body: "hi alice".to_string(),
+            format: BodyFormat::Plain,
+            response_tx: Some(response_tx),
+            origin: None,
+            idempotency_key: None,
+            relayed_from: None,
+            expires_after: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(response_rx.await.unwrap().unwrap(), "$loopback-1");
+
+    let transcript = std::fs::read_to_string(&transcript_file).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(transcript.trim()).unwrap();
+    assert_eq!(entry["room"], "lobby");
+    assert_eq!(entry["body"], "hi alice");
+
+    cancel.cancel();
+    assert_ok!(handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_loopback_service_injects_knocks_and_records_approvals() {
+    let dir = tempfile::tempdir().unwrap();
+    let inject_file = dir.path().join("inject.jsonl");
+    let transcript_file = dir.path().join("transcript.jsonl");
+    let (evt_tx, mut evt_rx) = create_event_channel(10);
+    let service = Arc::new(LoopbackService::new(
+        ServiceId("loop".to_string()),
+        evt_tx,
+        Arc::new(NoopMetrics),
+        LoopbackSettings {
+            users: vec![],
+            rooms: vec!["lobby".to_string()],
+            inject_file: Some(inject_file.clone()),
+            transcript_file: Some(transcript_file.clone()),
+            poll_interval: Duration::from_millis(10),
+        },
+    ));
+
+    let cancel = CancellationToken::new();
+    let handle = {
+        let (service, cancel) = (service.clone(), cancel.clone());
+        tokio::spawn(async move { service.run(cancel).await })
+    };
+    let mut next_event = async || {
+        tokio::time::timeout(Duration::from_secs(1), evt_rx.recv()).await.unwrap().unwrap()
+    };
+    assert_matches!(next_event().await.kind, EventKind::UserListUpdate { .. });
+
+    std::fs::write(
+        &inject_file,
+        concat!(r#"{"type": "knock", "user": "carol", "reason": "it's me"}"#, "\n"),
+    )
+    .unwrap();
+    let event = next_event().await;
+    assert_matches!(
+        &event.kind,
+        EventKind::Knock { room_id, user_id, reason: Some(reason), is_self: false, .. }
+            if room_id == "lobby" && user_id == "carol" && reason == "it's me"
+    );
+    assert_eq!(event.kind.name(), "knock");
+    assert_eq!(event.kind.room_id(), Some("lobby"));
+
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    service
+        .handle_command(Command::ApproveKnock {
+            service_id: ServiceId("loop".to_string()),
+            room_id: "lobby".to_string(),
+            user_id: "carol".to_string(),
+            response_tx: Some(response_tx),
+            origin: None,
+        })
+        .await
+        .unwrap();
+    assert_ok!(response_rx.await.unwrap());
+
+    let transcript = std::fs::read_to_string(&transcript_file).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(transcript.trim()).unwrap();
+    assert_eq!(entry["type"], "approve_knock");
+    assert_eq!(entry["user"], "carol");
+
+    cancel.cancel();
+    assert_ok!(handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_loopback_service_sends_repeated_idempotency_keys_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let transcript_file = dir.path().join("transcript.jsonl");
+    let (evt_tx, _evt_rx) = create_event_channel(10);
+    let service = LoopbackService::new(
+        ServiceId("loop".to_string()),
+        evt_tx,
+        Arc::new(NoopMetrics),
+        LoopbackSettings {
+            rooms: vec!["lobby".to_string()],
+            transcript_file: Some(transcript_file.clone()),
+            ..LoopbackSettings::default()
+        },
+    );
+
+    let send = async |key: &str| {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        service
+            .handle_command(Command::SendRoomMessage {
+                service_id: ServiceId("loop".to_string()),
+                room_id: "lobby".to_string(),
+                body: "hi".to_string(),
+                format: BodyFormat::Plain,
+                response_tx: Some(response_tx),
+                origin: None,
+                idempotency_key: Some(key.to_string()),
+                relayed_from: None,
+                expires_after: None,
+            })
+            .await
+            .unwrap();
+        response_rx.await.unwrap().unwrap()
+    };
+
+    // The repeat gets the first send's message ID instead of a second message
+    assert_eq!(send("relay-1").await, "$loopback-1");
+    assert_eq!(send("relay-1").await, "$loopback-1");
+    assert_eq!(send("relay-2").await, "$loopback-2");
+
+    let transcript = std::fs::read_to_string(&transcript_file).unwrap();
+    assert_eq!(transcript.lines().count(), 2);
+}
+
+#[tokio::test]
+async fn test_loopback_service_delivers_registered_commands_as_messages() {
+    let dir = tempfile::tempdir().unwrap();
+    let inject_file = dir.path().join("inject.jsonl");
+    let transcript_file = dir.path().join("transcript.jsonl");
+    let (evt_tx, mut evt_rx) = create_event_channel(10);
+    let service = Arc::new(LoopbackService::new(
+        ServiceId("loop".to_string()),
+        evt_tx,
+        Arc::new(NoopMetrics),
+        LoopbackSettings {
+            rooms: vec!["lobby".to_string()],
+            inject_file: Some(inject_file.clone()),
+            transcript_file: Some(transcript_file.clone()),
+            poll_interval: Duration::from_millis(10),
+            ..LoopbackSettings::default()
+        },
+    ));
+    let router = CommandRouter::new("!bus")
+        .with_description("Manage the bus")
+        .with_subcommand("pause", vec![ArgSpec::required("service")]);
+    service.register_commands(vec![router.spec()]).await.unwrap();
+
+    let transcript = std::fs::read_to_string(&transcript_file).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(transcript.trim()).unwrap();
+    assert_eq!(entry["type"], "register_commands");
+    assert_eq!(entry["commands"], serde_json::json!(["bus"]));
+
+    let cancel = CancellationToken::new();
+    let handle = {
+        let (service, cancel) = (service.clone(), cancel.clone());
+        tokio::spawn(async move { service.run(cancel).await })
+    };
+    let mut next_event = async || {
+        tokio::time::timeout(Duration::from_secs(1), evt_rx.recv()).await.unwrap().unwrap()
+    };
+    assert_matches!(next_event().await.kind, EventKind::UserListUpdate { .. });
+
+    // Unregistered commands are dropped; registered ones read like typed commands
+    std::fs::write(
+        &inject_file,
+        concat!(
+            r#"{"type": "command", "sender": "alice", "command": "nope"}"#,
+            "\n",
+            r#"{"type": "command", "sender": "alice", "command": "bus", "subcommand": "pause", "args": ["main mumble"]}"#,
+            "\n",
+        ),
+    )
+    .unwrap();
+    let event = next_event().await;
+    assert_matches!(
+        &event.kind,
+        EventKind::RoomMessage { room_id, body, sender_id, .. }
+            if room_id == "lobby" && body == r#"!bus pause "main mumble""# && sender_id == "alice"
+    );
+    let invocation = router.parse(event.kind.message_body().unwrap()).unwrap().unwrap();
+    assert_eq!(invocation.arg("service"), "main mumble");
+
+    cancel.cancel();
+    assert_ok!(handle.await.unwrap());
+}
@@ -0,0 +1,42 @@
+use kelvin_bot::core::{
+    service::ServiceId,
+    subscriptions::{SUBSCRIPTIONS_STORE_FILE, SubscriptionStore, parse_topic},
+};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_subscriptions_persist_per_topic_and_service() {
+    let dir = TempDir::new().unwrap();
+    let matrix = ServiceId("matrix".to_string());
+    let mumble = ServiceId("mumble".to_string());
+
+    let subscriptions = SubscriptionStore::load(dir.path()).unwrap();
+    assert!(subscriptions.subscribe("News", &matrix, "@alice", Some("Alice")).await.unwrap());
+    assert!(!subscriptions.subscribe("news", &matrix, "@alice", Some("Ally")).await.unwrap());
+    assert!(subscriptions.subscribe("news", &mumble, "bob", None).await.unwrap());
+    assert!(subscriptions.subscribe("games", &matrix, "@alice", None).await.unwrap());
+    assert!(dir.path().join(SUBSCRIPTIONS_STORE_FILE).exists());
+
+    let reloaded = SubscriptionStore::load(dir.path()).unwrap();
+    let news = reloaded.subscribers("NEWS").await;
+    assert_eq!(news.iter().map(|s| s.name()).collect::<Vec<_>>(), ["Ally", "bob"]);
+    let on_matrix = reloaded.subscribers_on("news", &matrix).await;
+    assert_eq!(on_matrix.len(), 1);
+    assert_eq!(on_matrix[0].user_id, "@alice");
+    assert_eq!(reloaded.topics_of(&matrix, "@alice").await, ["games", "news"]);
+    assert_eq!(reloaded.topic_counts().await, [("games".to_string(), 1), ("news".to_string(), 2)]);
+
+    assert!(reloaded.unsubscribe("games", &matrix, "@alice").await.unwrap());
+    assert!(!reloaded.unsubscribe("games", &matrix, "@alice").await.unwrap());
+    assert!(!reloaded.unsubscribe("news", &mumble, "@alice").await.unwrap());
+    assert_eq!(reloaded.topic_counts().await, [("news".to_string(), 2)]);
+}
+
+#[test]
+fn test_parse_topic() {
+    assert_eq!(parse_topic(" Announcements ").unwrap(), "announcements");
+    assert_eq!(parse_topic("game-night_2").unwrap(), "game-night_2");
+    assert!(parse_topic("").is_err());
+    assert!(parse_topic("two words").is_err());
+    assert!(parse_topic(&"x".repeat(33)).is_err());
+}
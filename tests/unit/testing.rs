@@ -0,0 +1,37 @@
+use kelvin_bot::core::middleware::{Middleware, Verdict};
+use kelvin_bot::middlewares::echo::Echo;
+use kelvin_bot::testing::{
+    MockMiddleware, command_capture, direct_message, middleware_context, room_message,
+};
+use std::sync::Arc;
+
+#[tokio::test]
+async fn test_command_capture_asserts_middleware_replies() {
+    let (cmd_tx, mut capture) = command_capture(10);
+    let echo = Echo::new(middleware_context(cmd_tx), "!echo".to_string());
+
+    echo.on_event(&Arc::new(room_message("chat", "!lobby", "@alice", "!echo hi"))).unwrap();
+    let (service_id, room_id, body) = capture.expect_room_message().await;
+    assert_eq!(service_id.0, "chat");
+    assert_eq!(room_id, "!lobby");
+    assert_eq!(body, "hi");
+
+    echo.on_event(&Arc::new(direct_message("chat", "@bob", "!echo psst"))).unwrap();
+    let (_, user_id, body) = capture.expect_direct_message().await;
+    assert_eq!(user_id, "@bob");
+    assert_eq!(body, "psst");
+
+    echo.on_event(&Arc::new(room_message("chat", "!lobby", "@alice", "no command"))).unwrap();
+    capture.assert_empty();
+}
+
+#[test]
+fn test_mock_middleware_records_events_and_reports_commands() {
+    let middleware = MockMiddleware::new(Verdict::Stop).with_commands(&["!ping"]);
+    let event = Arc::new(room_message("chat", "!lobby", "@alice", "hello"));
+
+    assert!(matches!(middleware.on_event(&event).unwrap(), Verdict::Stop));
+    assert_eq!(middleware.event_count(), 1);
+    assert!(Arc::ptr_eq(&middleware.events()[0], &event));
+    assert_eq!(middleware.command_strings(), vec!["!ping"]);
+}
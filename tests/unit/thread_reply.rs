@@ -1,13 +1,20 @@
-use kelvin_bot::core::bus::Command;
+use kelvin_bot::core::bus::{Command, create_event_channel};
+use kelvin_bot::core::format::BodyFormat;
+use kelvin_bot::core::metrics::NoopMetrics;
 use kelvin_bot::core::service::{Service, ServiceId};
 use kelvin_bot::services::dummy::DummyService;
-use tokio::sync::mpsc;
+use std::sync::Arc;
 
 #[tokio::test]
 async fn test_dummy_service_handles_thread_reply() {
-    let (evt_tx, _evt_rx) = mpsc::channel(10);
+    let (evt_tx, _evt_rx) = create_event_channel(10);
     let service_id = ServiceId("test_dummy".to_string());
-    let dummy_service = DummyService { id: service_id.clone(), interval_ms: 100, evt_tx };
+    let dummy_service = DummyService {
+        id: service_id.clone(),
+        interval_ms: 100,
+        evt_tx,
+        metrics: Arc::new(NoopMetrics),
+    };
 
     let (response_tx, response_rx) = tokio::sync::oneshot::channel();
     let command = Command::SendThreadReply {
@@ -15,8 +22,10 @@ async fn test_dummy_service_handles_thread_reply() {
         room_id: "!room:example.com".to_string(),
         thread_root_id: "$thread_root:example.com".to_string(),
         body: "Test thread reply".to_string(),
-        markdown_body: None,
+        format: BodyFormat::Plain,
         response_tx: Some(response_tx),
+        origin: None,
+        idempotency_key: None,
     };
 
     // Dummy service should handle the command without error
@@ -31,17 +40,24 @@ async fn test_dummy_service_handles_thread_reply() {
 
 #[tokio::test]
 async fn test_dummy_service_handles_thread_reply_without_response_channel() {
-    let (evt_tx, _evt_rx) = mpsc::channel(10);
+    let (evt_tx, _evt_rx) = create_event_channel(10);
     let service_id = ServiceId("test_dummy".to_string());
-    let dummy_service = DummyService { id: service_id.clone(), interval_ms: 100, evt_tx };
+    let dummy_service = DummyService {
+        id: service_id.clone(),
+        interval_ms: 100,
+        evt_tx,
+        metrics: Arc::new(NoopMetrics),
+    };
 
     let command = Command::SendThreadReply {
         service_id: service_id.clone(),
         room_id: "!room:example.com".to_string(),
         thread_root_id: "$thread_root:example.com".to_string(),
-        body: "Test thread reply".to_string(),
-        markdown_body: Some("**Test** thread reply".to_string()),
+        body: "**Test** thread reply".to_string(),
+        format: BodyFormat::Markdown,
         response_tx: None,
+        origin: None,
+        idempotency_key: None,
     };
 
     // Should handle command even without response channel
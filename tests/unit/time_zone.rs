@@ -0,0 +1,43 @@
+use chrono::{NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use kelvin_bot::core::time_zone::{next_weekly, resolve_time_zone};
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+#[test]
+fn test_resolve_time_zone() {
+    assert_eq!(resolve_time_zone(Some("America/Los_Angeles")).unwrap(), Tz::America__Los_Angeles);
+    let err = resolve_time_zone(Some("Mars/Olympus_Mons")).unwrap_err();
+    assert!(err.to_string().contains("Mars/Olympus_Mons"), "unexpected error: {err}");
+}
+
+#[test]
+fn test_next_weekly_uses_zone_wall_clock() {
+    let tz = Tz::America__Los_Angeles;
+    // Monday 01:00 UTC is still Sunday evening in Los Angeles
+    let now = Utc.with_ymd_and_hms(2024, 6, 3, 1, 0, 0).unwrap().with_timezone(&tz);
+
+    let next = next_weekly(&now, Weekday::Sun, time(19, 0));
+    assert_eq!(next, Utc.with_ymd_and_hms(2024, 6, 3, 2, 0, 0).unwrap());
+
+    // Once the time has passed it's a week out
+    let next = next_weekly(&now, Weekday::Sun, time(17, 0));
+    assert_eq!(next, Utc.with_ymd_and_hms(2024, 6, 10, 0, 0, 0).unwrap());
+}
+
+#[test]
+fn test_next_weekly_handles_dst_transitions() {
+    let tz = Tz::America__New_York;
+    let saturday = Utc.with_ymd_and_hms(2024, 3, 9, 17, 0, 0).unwrap().with_timezone(&tz);
+
+    // 02:30 doesn't exist when clocks spring forward; it runs at 03:30 EDT instead
+    let next = next_weekly(&saturday, Weekday::Sun, time(2, 30));
+    assert_eq!(next, Utc.with_ymd_and_hms(2024, 3, 10, 7, 30, 0).unwrap());
+
+    // 01:30 happens twice when clocks fall back; the first (EDT) one wins
+    let saturday = Utc.with_ymd_and_hms(2024, 11, 2, 16, 0, 0).unwrap().with_timezone(&tz);
+    let next = next_weekly(&saturday, Weekday::Sun, time(1, 30));
+    assert_eq!(next, Utc.with_ymd_and_hms(2024, 11, 3, 5, 30, 0).unwrap());
+}
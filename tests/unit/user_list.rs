@@ -0,0 +1,99 @@
+use kelvin_bot::core::{
+    event::{Event, EventKind, User},
+    service::ServiceId,
+    user_list::{UserLists, diff},
+};
+use kelvin_bot::testing::room_message;
+
+fn user(id: &str, is_active: bool) -> User {
+    User {
+        id: id.to_string(),
+        username: id.to_string(),
+        display_name: id.to_string(),
+        is_active,
+        is_self: false,
+        comment: None,
+        avatar: None,
+    }
+}
+
+fn snapshot(service: &str, users: Vec<User>) -> Event {
+    Event { service_id: ServiceId(service.to_string()), kind: EventKind::UserListUpdate { users } }
+}
+
+fn ids(users: &[User]) -> Vec<&str> {
+    users.iter().map(|user| user.id.as_str()).collect()
+}
+
+#[test]
+fn test_diff_compares_users_by_id() {
+    let before = vec![user("alice", true), user("bob", true), user("carol", true)];
+    let after = vec![user("bob", true), user("carol", false), user("dave", true)];
+
+    let (joined, left, changed) = diff(&before, &after);
+
+    assert_eq!(ids(&joined), vec!["dave"]);
+    assert_eq!(ids(&left), vec!["alice"]);
+    assert_eq!(changed, vec![user("carol", false)]);
+}
+
+#[test]
+fn test_snapshots_become_diffs_from_the_previous_snapshot() {
+    let mut user_lists = UserLists::default();
+
+    let first = user_lists.counterpart(&snapshot("mumble", vec![user("alice", true)])).unwrap();
+    let EventKind::UserListDiff { joined, left, changed } = &first.kind else {
+        panic!("Expected UserListDiff, got {:?}", first.kind);
+    };
+    // Everyone in the first snapshot joined
+    assert_eq!(ids(joined), vec!["alice"]);
+    assert!(left.is_empty() && changed.is_empty());
+    assert_eq!(first.service_id, ServiceId("mumble".to_string()));
+
+    let second = user_lists.counterpart(&snapshot("mumble", vec![user("bob", true)])).unwrap();
+    let EventKind::UserListDiff { joined, left, .. } = &second.kind else {
+        panic!("Expected UserListDiff, got {:?}", second.kind);
+    };
+    assert_eq!(ids(joined), vec!["bob"]);
+    assert_eq!(ids(left), vec!["alice"]);
+}
+
+#[test]
+fn test_unchanged_snapshot_has_no_diff() {
+    let mut user_lists = UserLists::default();
+    user_lists.counterpart(&snapshot("mumble", vec![user("alice", true)]));
+
+    assert!(user_lists.counterpart(&snapshot("mumble", vec![user("alice", true)])).is_none());
+    // Each service's list is its own
+    assert!(user_lists.counterpart(&snapshot("loopback", vec![user("alice", true)])).is_some());
+}
+
+#[test]
+fn test_diffs_become_the_list_after_them() {
+    let mut user_lists = UserLists::default();
+    user_lists.counterpart(&snapshot("mumble", vec![user("alice", true), user("bob", true)]));
+
+    let after = user_lists
+        .counterpart(&Event {
+            service_id: ServiceId("mumble".to_string()),
+            kind: EventKind::UserListDiff {
+                joined: vec![user("carol", true)],
+                left: vec![user("alice", true)],
+                changed: vec![user("bob", false)],
+            },
+        })
+        .unwrap();
+
+    let EventKind::UserListUpdate { users } = &after.kind else {
+        panic!("Expected UserListUpdate, got {:?}", after.kind);
+    };
+    assert_eq!(users, &vec![user("bob", false), user("carol", true)]);
+    // The list the diff led to is what the next snapshot is compared with
+    assert!(user_lists.counterpart(&snapshot("mumble", users.clone())).is_none());
+}
+
+#[test]
+fn test_other_events_have_no_counterpart() {
+    let mut user_lists = UserLists::default();
+    assert!(user_lists.counterpart(&room_message("mumble", "root", "alice", "hi")).is_none());
+}